@@ -17,6 +17,7 @@ use tonic::{Request, Response, Status, Streaming};
 use tracing::{error, info, warn};
 
 use crate::session::{Metadata, Session};
+use crate::web::protocol::{WsDesktop, WsRejection, WsTermination};
 use crate::ServerState;
 
 /// Interval for synchronizing sequence numbers with the client.
@@ -58,6 +59,7 @@ impl SshxService for GrpcServer {
                     encrypted_zeros: request.encrypted_zeros,
                     name: request.name,
                     write_password_hash: request.write_password_hash,
+                    desktop: request.desktop,
                 };
                 self.0.insert(&name, Arc::new(Session::new(metadata)));
             }
@@ -122,6 +124,7 @@ impl SshxService for GrpcServer {
 }
 
 /// Validate the client token for a session.
+#[allow(clippy::result_large_err)]
 fn validate_token(mac: impl Mac, name: &str, token: &str) -> Result<(), Status> {
     if let Ok(token) = BASE64_STANDARD.decode(token) {
         if mac.chain_update(name).verify_slice(&token).is_ok() {
@@ -209,6 +212,38 @@ async fn handle_update(tx: &ServerTx, session: &Session, update: ClientUpdate) -
                 return send_err(tx, format!("close shell: {:?}", err)).await;
             }
         }
+        Some(ClientMessage::RejectedShell(rejected)) => {
+            let rejection = WsRejection {
+                code: rejected.code,
+                message: rejected.message,
+                retry_after: rejected.retry_after,
+            };
+            session.send_shell_rejected(Sid(rejected.id), rejection);
+        }
+        Some(ClientMessage::TerminatedShell(terminated)) => {
+            let termination = WsTermination {
+                reason: terminated.reason,
+                message: terminated.message,
+                time: terminated.time,
+            };
+            session.send_shell_terminated(Sid(terminated.id), termination);
+        }
+        Some(ClientMessage::Desktops(list)) => {
+            let desktops = list
+                .sessions
+                .into_iter()
+                .map(|desktop| WsDesktop {
+                    id: desktop.id,
+                    name: desktop.name,
+                    description: desktop.description,
+                    created_at: desktop.created_at,
+                    idle: desktop.idle,
+                    resolution: desktop.resolution,
+                    attached: desktop.attached,
+                })
+                .collect();
+            session.send_desktops(desktops);
+        }
         Some(ClientMessage::Pong(ts)) => {
             let latency = get_time_ms().saturating_sub(ts);
             session.send_latency_measurement(latency);
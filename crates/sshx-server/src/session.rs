@@ -18,7 +18,7 @@ use tokio_stream::Stream;
 use tracing::{debug, warn};
 
 use crate::utils::Shutdown;
-use crate::web::protocol::{WsServer, WsUser, WsWinsize};
+use crate::web::protocol::{WsDesktop, WsRejection, WsServer, WsTermination, WsUser, WsWinsize};
 
 mod snapshot;
 
@@ -36,6 +36,9 @@ pub struct Metadata {
 
     /// Password for write access to the session.
     pub write_password_hash: Option<Bytes>,
+
+    /// Whether shells show desktops over the desktop channel.
+    pub desktop: bool,
 }
 
 /// In-memory state for a single sshx session.
@@ -368,6 +371,25 @@ impl Session {
         Ok(())
     }
 
+    /// Notify users that the backend refused to start a shell.
+    pub fn send_shell_rejected(&self, id: Sid, rejection: WsRejection) {
+        self.broadcast
+            .send(WsServer::ShellRejected(id, rejection))
+            .ok();
+    }
+
+    /// Notify users that the backend ended the desktop session of a shell.
+    pub fn send_shell_terminated(&self, id: Sid, termination: WsTermination) {
+        self.broadcast
+            .send(WsServer::ShellTerminated(id, termination))
+            .ok();
+    }
+
+    /// Send users the desktop sessions the backend can attach shells to.
+    pub fn send_desktops(&self, desktops: Vec<WsDesktop>) {
+        self.broadcast.send(WsServer::Desktops(desktops)).ok();
+    }
+
     /// Send a measurement of the shell latency.
     pub fn send_latency_measurement(&self, latency: u64) {
         self.broadcast.send(WsServer::ShellLatency(latency)).ok();
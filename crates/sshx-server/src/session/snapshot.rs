@@ -63,6 +63,7 @@ impl Session {
             next_uid: ids.1 .0,
             name: self.metadata().name.clone(),
             write_password_hash: self.metadata().write_password_hash.clone(),
+            desktop: self.metadata().desktop,
         };
         let data = message.encode_to_vec();
         ensure!(data.len() < MAX_SNAPSHOT_SIZE, "snapshot too large");
@@ -78,6 +79,7 @@ impl Session {
             encrypted_zeros: message.encrypted_zeros,
             name: message.name,
             write_password_hash: message.write_password_hash,
+            desktop: message.desktop,
         };
 
         let session = Self::new(metadata);
@@ -43,12 +43,78 @@ pub struct WsUser {
     pub can_write: bool,
 }
 
+/// Structured reason a backend refused to start a shell.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct WsRejection {
+    /// Machine-readable reason, such as `max_sessions` or `rate_limited`.
+    pub code: String,
+    /// Human-readable explanation to show the user.
+    pub message: String,
+    /// Seconds until a retry may succeed, if known.
+    pub retry_after: Option<u32>,
+}
+
+/// Why the backend ended the desktop session of a shell.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct WsTermination {
+    /// Machine-readable reason, such as `idle_timeout` or `admin_kill`.
+    pub reason: String,
+    /// Human-readable explanation to show the user.
+    pub message: String,
+    /// Unix time the session was terminated, in milliseconds.
+    pub time: u64,
+}
+
+/// Desktop session of the backend's user that a new shell can attach to.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct WsDesktop {
+    /// ID of the session, passed back to attach to it.
+    pub id: String,
+    /// Name of the session to show users.
+    pub name: String,
+    /// What the session is for, if the user said.
+    pub description: Option<String>,
+    /// Unix time the session started, in milliseconds.
+    pub created_at: u64,
+    /// Seconds since the last input.
+    pub idle: u64,
+    /// Size of the screen, such as `1920x1080`.
+    pub resolution: String,
+    /// Whether a shell is currently showing the session.
+    pub attached: bool,
+}
+
+/// Parameters a new desktop session is asked to start with, checked by the
+/// backend against what its host allows.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct WsSessionParams {
+    /// Name of a template configured on the backend's host.
+    pub template: Option<String>,
+    /// Program to start on the desktop.
+    pub app: Option<String>,
+    /// Screen size to start with, such as `1920x1080`.
+    pub resolution: Option<String>,
+    /// Whether to forward the desktop's audio.
+    pub audio: bool,
+    /// Locale of the client, such as `de-DE`.
+    #[serde(default)]
+    pub locale: Option<String>,
+    /// IANA time zone of the client, such as `Europe/Berlin`.
+    #[serde(default)]
+    pub timezone: Option<String>,
+}
+
 /// A real-time message sent from the server over WebSocket.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub enum WsServer {
-    /// Initial server message, with the user's ID and session metadata.
-    Hello(Uid, String),
+    /// Initial server message, with the user's ID and session metadata: its
+    /// name and whether its shells show desktops.
+    Hello(Uid, String, bool),
     /// The user's authentication was invalid.
     InvalidAuth(),
     /// A snapshot of all current users in the session.
@@ -61,6 +127,12 @@ pub enum WsServer {
     Chunks(Sid, u64, Vec<Bytes>),
     /// Get a chat message tuple `(uid, name, text)` from the room.
     Hear(Uid, String, String),
+    /// A shell could not be started by the backend, with the reason.
+    ShellRejected(Sid, WsRejection),
+    /// The backend ended the desktop session of a shell, with the reason.
+    ShellTerminated(Sid, WsTermination),
+    /// Desktop sessions of the backend's user, as requested.
+    Desktops(Vec<WsDesktop>),
     /// Forward a latency measurement between the server and backend shell.
     ShellLatency(u64),
     /// Echo back a timestamp, for the the client's own latency measurement.
@@ -84,6 +156,14 @@ pub enum WsClient {
     SetFocus(Option<Sid>),
     /// Create a new shell.
     Create(i32, i32),
+    /// Create a new shell, starting its desktop session with parameters.
+    CreateWith(i32, i32, WsSessionParams),
+    /// Create a new shell showing a running desktop session.
+    Attach(i32, i32, String),
+    /// Request the backend's desktop sessions.
+    ListDesktops(),
+    /// Name a desktop session, with an optional description.
+    RenameDesktop(String, String, Option<String>),
     /// Close a specific shell.
     Close(Sid),
     /// Move a shell window to a new position and focus it.
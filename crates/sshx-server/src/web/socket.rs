@@ -9,7 +9,10 @@ use axum::extract::{
 use axum::response::IntoResponse;
 use bytes::Bytes;
 use futures_util::SinkExt;
-use sshx_core::proto::{server_update::ServerMessage, NewShell, TerminalInput, TerminalSize};
+use sshx_core::proto::{
+    server_update::ServerMessage, ListDesktops, NewShell, RenameDesktop, SessionParams,
+    TerminalInput, TerminalSize,
+};
 use sshx_core::Sid;
 use subtle::ConstantTimeEq;
 use tokio::sync::mpsc;
@@ -94,7 +97,7 @@ async fn handle_socket(socket: &mut WebSocket, session: Arc<Session>) -> Result<
     let metadata = session.metadata();
     let user_id = session.counter().next_uid();
     session.sync_now();
-    send(socket, WsServer::Hello(user_id, metadata.name.clone())).await?;
+    send(socket, WsServer::Hello(user_id, metadata.name.clone(), metadata.desktop)).await?;
 
     let can_write = match recv(socket).await? {
         Some(WsClient::Authenticate(bytes, write_password_bytes)) => {
@@ -181,11 +184,80 @@ async fn handle_socket(socket: &mut WebSocket, session: Arc<Session>) -> Result<
                 }
                 let id = session.counter().next_sid();
                 session.sync_now();
-                let new_shell = NewShell { id: id.0, x, y };
+                let new_shell = NewShell {
+                    id: id.0,
+                    x,
+                    y,
+                    desktop: None,
+                    params: None,
+                };
+                update_tx
+                    .send(ServerMessage::CreateShell(new_shell))
+                    .await?;
+            }
+            WsClient::CreateWith(x, y, params) => {
+                if let Err(e) = session.check_write_permission(user_id) {
+                    send(socket, WsServer::Error(e.to_string())).await?;
+                    continue;
+                }
+                let id = session.counter().next_sid();
+                session.sync_now();
+                let params = SessionParams {
+                    template: params.template,
+                    app: params.app,
+                    resolution: params.resolution,
+                    audio: params.audio,
+                    locale: params.locale,
+                    timezone: params.timezone,
+                };
+                let new_shell = NewShell {
+                    id: id.0,
+                    x,
+                    y,
+                    desktop: None,
+                    params: Some(params),
+                };
+                update_tx
+                    .send(ServerMessage::CreateShell(new_shell))
+                    .await?;
+            }
+            WsClient::Attach(x, y, desktop) => {
+                if let Err(e) = session.check_write_permission(user_id) {
+                    send(socket, WsServer::Error(e.to_string())).await?;
+                    continue;
+                }
+                let id = session.counter().next_sid();
+                session.sync_now();
+                let new_shell = NewShell {
+                    id: id.0,
+                    x,
+                    y,
+                    desktop: Some(desktop),
+                    params: None,
+                };
                 update_tx
                     .send(ServerMessage::CreateShell(new_shell))
                     .await?;
             }
+            WsClient::ListDesktops() => {
+                if let Err(e) = session.check_write_permission(user_id) {
+                    send(socket, WsServer::Error(e.to_string())).await?;
+                    continue;
+                }
+                update_tx
+                    .send(ServerMessage::ListDesktops(ListDesktops {}))
+                    .await?;
+            }
+            WsClient::RenameDesktop(id, name, description) => {
+                if let Err(e) = session.check_write_permission(user_id) {
+                    send(socket, WsServer::Error(e.to_string())).await?;
+                    continue;
+                }
+                let rename = RenameDesktop { id, name, description };
+                update_tx
+                    .send(ServerMessage::RenameDesktop(rename))
+                    .await?;
+            }
             WsClient::Close(id) => {
                 if let Err(e) = session.check_write_permission(user_id) {
                     send(socket, WsServer::Error(e.to_string())).await?;
@@ -12,7 +12,7 @@ use sshx_core::proto::sshx_service_client::SshxServiceClient;
 use sshx_core::{Sid, Uid};
 use sshx_server::{
     state::ServerState,
-    web::protocol::{WsClient, WsServer, WsUser, WsWinsize},
+    web::protocol::{WsClient, WsDesktop, WsRejection, WsServer, WsTermination, WsUser, WsWinsize},
     Server,
 };
 use tokio::net::{TcpListener, TcpStream};
@@ -93,6 +93,9 @@ pub struct ClientSocket {
     pub data: HashMap<Sid, String>,
     pub messages: Vec<(Uid, String, String)>,
     pub errors: Vec<String>,
+    pub rejections: Vec<(Sid, WsRejection)>,
+    pub terminations: Vec<(Sid, WsTermination)>,
+    pub desktops: Vec<WsDesktop>,
 }
 
 impl ClientSocket {
@@ -111,6 +114,9 @@ impl ClientSocket {
             data: HashMap::new(),
             messages: Vec::new(),
             errors: Vec::new(),
+            rejections: Vec::new(),
+            terminations: Vec::new(),
+            desktops: Vec::new(),
         };
         this.authenticate().await;
         Ok(this)
@@ -162,7 +168,7 @@ impl ClientSocket {
         let flush_task = async {
             while let Some(msg) = self.recv().await {
                 match msg {
-                    WsServer::Hello(user_id, _) => self.user_id = user_id,
+                    WsServer::Hello(user_id, _, _) => self.user_id = user_id,
                     WsServer::InvalidAuth() => panic!("invalid authentication"),
                     WsServer::Users(users) => self.users = BTreeMap::from_iter(users),
                     WsServer::UserDiff(id, maybe_user) => {
@@ -187,6 +193,13 @@ impl ClientSocket {
                     WsServer::Hear(id, name, msg) => {
                         self.messages.push((id, name, msg));
                     }
+                    WsServer::ShellRejected(id, rejection) => {
+                        self.rejections.push((id, rejection));
+                    }
+                    WsServer::ShellTerminated(id, termination) => {
+                        self.terminations.push((id, termination));
+                    }
+                    WsServer::Desktops(desktops) => self.desktops = desktops,
                     WsServer::ShellLatency(_) => {}
                     WsServer::Pong(_) => {}
                     WsServer::Error(err) => self.errors.push(err),
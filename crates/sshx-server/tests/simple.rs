@@ -1,6 +1,10 @@
 use anyhow::Result;
 use sshx::encrypt::Encrypt;
-use sshx_core::proto::*;
+use sshx_core::proto::{client_update::ClientMessage, server_update::ServerMessage, *};
+use sshx_core::Sid;
+use sshx_server::web::protocol::{WsClient, WsDesktop, WsRejection, WsTermination};
+use tokio::sync::mpsc;
+use tokio_stream::{wrappers::ReceiverStream, StreamExt};
 
 use crate::common::*;
 
@@ -16,6 +20,7 @@ async fn test_rpc() -> Result<()> {
         encrypted_zeros: Encrypt::new("").zeros().into(),
         name: String::new(),
         write_password_hash: None,
+        desktop: false,
     };
     let resp = client.open(req).await?;
     assert!(!resp.into_inner().name.is_empty());
@@ -32,3 +37,170 @@ async fn test_web_get() -> Result<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_shell_rejected() -> Result<()> {
+    let server = TestServer::new().await;
+    let mut client = server.grpc_client().await;
+
+    let req = OpenRequest {
+        origin: "sshx.io".into(),
+        encrypted_zeros: Encrypt::new("").zeros().into(),
+        name: String::new(),
+        write_password_hash: None,
+        desktop: false,
+    };
+    let resp = client.open(req).await?.into_inner();
+
+    let mut s = ClientSocket::connect(&server.ws_endpoint(&resp.name), "", None).await?;
+    s.flush().await;
+
+    let rejected = ShellRejected {
+        id: 1,
+        code: "max_sessions".into(),
+        message: "You already have 5 desktops open, close one or wait".into(),
+        retry_after: None,
+    };
+    let updates = [
+        ClientMessage::Hello(format!("{},{}", resp.name, resp.token)),
+        ClientMessage::RejectedShell(rejected),
+    ]
+    .map(|message| ClientUpdate {
+        client_message: Some(message),
+    });
+    let _stream = client.channel(tokio_stream::iter(updates)).await?;
+    s.flush().await;
+
+    let rejection = WsRejection {
+        code: "max_sessions".into(),
+        message: "You already have 5 desktops open, close one or wait".into(),
+        retry_after: None,
+    };
+    assert_eq!(s.rejections, vec![(Sid(1), rejection)]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_shell_terminated() -> Result<()> {
+    let server = TestServer::new().await;
+    let mut client = server.grpc_client().await;
+
+    let req = OpenRequest {
+        origin: "sshx.io".into(),
+        encrypted_zeros: Encrypt::new("").zeros().into(),
+        name: String::new(),
+        write_password_hash: None,
+        desktop: false,
+    };
+    let resp = client.open(req).await?.into_inner();
+
+    let mut s = ClientSocket::connect(&server.ws_endpoint(&resp.name), "", None).await?;
+    s.flush().await;
+
+    let terminated = ShellTerminated {
+        id: 2,
+        reason: "idle_timeout".into(),
+        message: "Disconnected: idle timeout".into(),
+        time: 1_700_000_000_000,
+    };
+    let updates = [
+        ClientMessage::Hello(format!("{},{}", resp.name, resp.token)),
+        ClientMessage::TerminatedShell(terminated),
+    ]
+    .map(|message| ClientUpdate {
+        client_message: Some(message),
+    });
+    let _stream = client.channel(tokio_stream::iter(updates)).await?;
+    s.flush().await;
+
+    let termination = WsTermination {
+        reason: "idle_timeout".into(),
+        message: "Disconnected: idle timeout".into(),
+        time: 1_700_000_000_000,
+    };
+    assert_eq!(s.terminations, vec![(Sid(2), termination)]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_list_desktops() -> Result<()> {
+    let server = TestServer::new().await;
+    let mut client = server.grpc_client().await;
+
+    let req = OpenRequest {
+        origin: "sshx.io".into(),
+        encrypted_zeros: Encrypt::new("").zeros().into(),
+        name: String::new(),
+        write_password_hash: None,
+        desktop: false,
+    };
+    let resp = client.open(req).await?.into_inner();
+
+    let mut s = ClientSocket::connect(&server.ws_endpoint(&resp.name), "", None).await?;
+    s.flush().await;
+
+    let (tx, rx) = mpsc::channel(16);
+    let update = |message| ClientUpdate {
+        client_message: Some(message),
+    };
+    tx.send(update(ClientMessage::Hello(format!(
+        "{},{}",
+        resp.name, resp.token
+    ))))
+    .await?;
+    let mut messages = client.channel(ReceiverStream::new(rx)).await?.into_inner();
+
+    s.send(WsClient::ListDesktops()).await;
+    loop {
+        let message = messages.next().await.unwrap()?.server_message;
+        if matches!(message, Some(ServerMessage::ListDesktops(_))) {
+            break;
+        }
+    }
+
+    s.send(WsClient::RenameDesktop(
+        "xpra-3".into(),
+        "Q3 reporting".into(),
+        Some("Quarterly numbers".into()),
+    ))
+    .await;
+    let rename = loop {
+        let message = messages.next().await.unwrap()?.server_message;
+        if let Some(ServerMessage::RenameDesktop(rename)) = message {
+            break rename;
+        }
+    };
+    assert_eq!(rename.id, "xpra-3");
+    assert_eq!(rename.name, "Q3 reporting");
+    assert_eq!(rename.description.as_deref(), Some("Quarterly numbers"));
+
+    let desktop = DesktopSession {
+        id: "xpra-3".into(),
+        name: "Q3 reporting".into(),
+        description: Some("Quarterly numbers".into()),
+        created_at: 1_700_000_000_000,
+        idle: 42,
+        resolution: "1920x1080".into(),
+        attached: false,
+    };
+    tx.send(update(ClientMessage::Desktops(DesktopList {
+        sessions: vec![desktop],
+    })))
+    .await?;
+    s.flush().await;
+
+    let desktop = WsDesktop {
+        id: "xpra-3".into(),
+        name: "Q3 reporting".into(),
+        description: Some("Quarterly numbers".into()),
+        created_at: 1_700_000_000_000,
+        idle: 42,
+        resolution: "1920x1080".into(),
+        attached: false,
+    };
+    assert_eq!(s.desktops, vec![desktop]);
+
+    Ok(())
+}
@@ -31,7 +31,13 @@ async fn test_command() -> Result<()> {
         .context("couldn't find session in server state")?;
 
     let updates = session.update_tx();
-    let new_shell = NewShell { id: 1, x: 0, y: 0 };
+    let new_shell = NewShell {
+        id: 1,
+        x: 0,
+        y: 0,
+        desktop: None,
+        params: None,
+    };
     updates.send(ServerMessage::CreateShell(new_shell)).await?;
 
     let key = controller.encryption_key();
@@ -0,0 +1,225 @@
+//! Throughput and latency of the desktop forwarder's frame path.
+//!
+//! The `encrypt` group compares the copying `Encrypt::segment` with the
+//! in-place `Encrypt::segment_bytes`. The `pipeline` group runs frames
+//! through the same stages as `xpra_task` against a synthetic xpra server
+//! that echoes every WebSocket message back: frames per second of the
+//! forwarder, and the latency it adds to a round trip over the bare
+//! WebSocket.
+//!
+//! Run with `cargo bench -p sshx --bench forwarder`.
+
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::{SinkExt, StreamExt};
+use sshx::encrypt::Encrypt;
+use sshx::xpra_handshake::{
+    ClientHello, ControlMessage, DesktopChannel, Received, RotationPolicy, FRAME_CONTROL, FRAME_DATA,
+};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::Runtime;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{accept_async, connect_async, MaybeTlsStream, WebSocketStream};
+
+/// Frame sizes from small input events up to large screen updates
+const FRAME_SIZES: &[usize] = &[64, 1024, 16 * 1024, 256 * 1024];
+
+/// Stream number of the output of session 1, as used by `xpra_task`
+const OUTPUT_STREAM: u64 = 0x100000001;
+
+/// Stream number of input from the server
+const INPUT_STREAM: u64 = 0x200000000;
+
+type XpraStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Start a synthetic xpra server on a loopback port that echoes every
+/// binary message back to the forwarder.
+async fn xpra_echo() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            // Like xpra, which disables Nagle's algorithm on its sockets
+            stream.set_nodelay(true).unwrap();
+            tokio::spawn(async move {
+                let Ok(ws) = accept_async(stream).await else {
+                    return;
+                };
+                let (mut write, mut read) = ws.split();
+                while let Some(Ok(msg)) = read.next().await {
+                    if msg.is_binary() && write.send(msg).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    addr
+}
+
+async fn connect(addr: SocketAddr) -> XpraStream {
+    connect_async(format!("ws://{addr}/xpra")).await.unwrap().0
+}
+
+/// Channel of a version 2 client without compression.
+fn negotiated_channel() -> DesktopChannel {
+    let mut channel = DesktopChannel::new(RotationPolicy::default(), &[]);
+    let hello = ClientHello { version: 2, ..Default::default() };
+    let mut frame = vec![FRAME_CONTROL];
+    serde_json::to_writer(&mut frame, &ControlMessage::Hello(hello)).unwrap();
+    assert!(matches!(channel.receive(frame.into()), Received::Reply(_)));
+    channel
+}
+
+/// Client input as it arrives from the server: framed and encrypted.
+fn client_frame(encrypt: &Encrypt, size: usize) -> Bytes {
+    let mut frame = vec![b'P'; size + 1];
+    frame[0] = FRAME_DATA;
+    encrypt.segment(INPUT_STREAM, 0, &frame).into()
+}
+
+/// Input path of `xpra_task`: decrypt, unframe and forward to xpra.
+async fn forward_input(
+    encrypt: &Encrypt,
+    channel: &mut DesktopChannel,
+    ws_write: &mut SplitSink<XpraStream, Message>,
+    frame: Bytes,
+) {
+    let data = encrypt.segment_bytes(INPUT_STREAM, 0, frame);
+    let Received::Data(data) = channel.receive(data) else {
+        panic!("expected a data frame");
+    };
+    ws_write.send(Message::Binary(data.into())).await.unwrap();
+}
+
+/// Output path of `xpra_task`: frame and encrypt xpra output for the client.
+async fn forward_output(
+    encrypt: &Encrypt,
+    channel: &mut DesktopChannel,
+    ws_read: &mut SplitStream<XpraStream>,
+    seq: &mut u64,
+) -> Bytes {
+    let payload = ws_read.next().await.unwrap().unwrap().into_data();
+    let frame = channel.send(payload);
+    let len = frame.len() as u64;
+    let data = encrypt.segment_bytes(OUTPUT_STREAM, *seq, frame);
+    *seq += len;
+    data
+}
+
+fn encrypt(c: &mut Criterion) {
+    let encrypt = Encrypt::new("benchmark key");
+    let mut group = c.benchmark_group("encrypt");
+    for &size in FRAME_SIZES {
+        group.throughput(Throughput::Bytes(size as u64));
+
+        // The path before frames were `Bytes`: borrow, copy into the
+        // channel frame, copy again while encrypting.
+        let mut channel = negotiated_channel();
+        group.bench_with_input(BenchmarkId::new("copying", size), &size, |b, &size| {
+            b.iter_batched(
+                || vec![b'P'; size],
+                |frame| {
+                    let framed = channel.send(frame).to_vec();
+                    Bytes::from(encrypt.segment(OUTPUT_STREAM, 0, &framed))
+                },
+                BatchSize::SmallInput,
+            )
+        });
+
+        let mut channel = negotiated_channel();
+        group.bench_with_input(BenchmarkId::new("bytes", size), &size, |b, &size| {
+            b.iter_batched(
+                || vec![b'P'; size],
+                |frame| encrypt.segment_bytes(OUTPUT_STREAM, 0, channel.send(frame)),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn pipeline(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let addr = rt.block_on(xpra_echo());
+    let encrypt = &Encrypt::new("benchmark key");
+
+    let mut group = c.benchmark_group("pipeline");
+    for &size in FRAME_SIZES {
+        let frame = client_frame(encrypt, size);
+
+        // Frames per second with input and output running concurrently,
+        // like a busy session.
+        group.throughput(Throughput::Elements(1));
+        group.bench_with_input(BenchmarkId::new("throughput", size), &frame, |b, frame| {
+            b.to_async(&rt).iter_custom(|iters| async move {
+                let (mut ws_write, mut ws_read) = connect(addr).await.split();
+                let start = Instant::now();
+                let input = tokio::spawn({
+                    let (encrypt, frame) = (encrypt.clone(), frame.clone());
+                    async move {
+                        let mut channel = negotiated_channel();
+                        for _ in 0..iters {
+                            forward_input(&encrypt, &mut channel, &mut ws_write, frame.clone()).await;
+                        }
+                        ws_write
+                    }
+                });
+                let mut channel = negotiated_channel();
+                let mut seq = 0;
+                for _ in 0..iters {
+                    forward_output(encrypt, &mut channel, &mut ws_read, &mut seq).await;
+                }
+                let elapsed = start.elapsed();
+                let _ = input.await.unwrap().close().await;
+                elapsed
+            })
+        });
+
+        // Round trip of one frame through the forwarder, against the bare
+        // WebSocket; the difference is the latency the forwarder adds.
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::new("latency/forwarder", size), &frame, |b, frame| {
+            b.to_async(&rt).iter_custom(|iters| async move {
+                let (mut ws_write, mut ws_read) = connect(addr).await.split();
+                let (mut input, mut output) = (negotiated_channel(), negotiated_channel());
+                let mut seq = 0;
+                let mut elapsed = Duration::ZERO;
+                for _ in 0..iters {
+                    let start = Instant::now();
+                    forward_input(encrypt, &mut input, &mut ws_write, frame.clone()).await;
+                    forward_output(encrypt, &mut output, &mut ws_read, &mut seq).await;
+                    elapsed += start.elapsed();
+                }
+                let _ = ws_write.close().await;
+                elapsed
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("latency/websocket", size), &size, |b, &size| {
+            let payload = Bytes::from(vec![b'P'; size]);
+            b.to_async(&rt).iter_custom(|iters| {
+                let payload = payload.clone();
+                async move {
+                    let (mut ws_write, mut ws_read) = connect(addr).await.split();
+                    let mut elapsed = Duration::ZERO;
+                    for _ in 0..iters {
+                        let start = Instant::now();
+                        ws_write.send(Message::Binary(payload.to_vec())).await.unwrap();
+                        ws_read.next().await.unwrap().unwrap();
+                        elapsed += start.elapsed();
+                    }
+                    let _ = ws_write.close().await;
+                    elapsed
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, encrypt, pipeline);
+criterion_main!(benches);
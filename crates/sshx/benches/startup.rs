@@ -0,0 +1,100 @@
+//! Contention between sessions starting at the same time.
+//!
+//! Every session start takes a display number from its host's pool. The
+//! `display_pool` group starts 50 sessions at once on a multi-threaded
+//! runtime, each taking and returning a number from a pool that is mostly in
+//! use, with the pool's atomic bitset and, for comparison, with the mutex
+//! over a set scanned from the lowest number that it replaced.
+//!
+//! Run with `cargo bench -p sshx --bench startup`.
+
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use sshx::xpra_pool::DisplayPool;
+use tokio::runtime::Runtime;
+use tokio::sync::Mutex;
+
+/// Sessions starting at once
+const STARTS: usize = 50;
+
+/// Display numbers in use before the sessions start
+const IN_USE: u16 = 400;
+
+const MIN_DISPLAY: u16 = 100;
+const MAX_DISPLAY: u16 = 599;
+
+/// The display pool before the bitset: one mutex held over a linear scan.
+#[derive(Clone, Default)]
+struct ScanPool(Arc<Mutex<HashSet<u16>>>);
+
+impl ScanPool {
+    async fn allocate(&self) -> Option<u16> {
+        let mut used = self.0.lock().await;
+        let number = (MIN_DISPLAY..=MAX_DISPLAY).find(|n| !used.contains(n))?;
+        used.insert(number);
+        Some(number)
+    }
+
+    async fn release(&self, number: u16) {
+        self.0.lock().await.remove(&number);
+    }
+}
+
+fn display_pool(c: &mut Criterion) {
+    let rt = tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap();
+    let mut group = c.benchmark_group("display_pool");
+
+    let pool = DisplayPool::with_range(MIN_DISPLAY, MAX_DISPLAY);
+    let _in_use: Vec<_> = rt.block_on(async {
+        let mut leases = Vec::new();
+        for _ in 0..IN_USE {
+            leases.push(pool.allocate().await.unwrap());
+        }
+        leases
+    });
+    group.bench_function("bitset", |b| {
+        b.to_async(&rt).iter(|| starts(&rt, || {
+            let pool = pool.clone();
+            async move {
+                let lease = pool.allocate().await.unwrap();
+                tokio::task::yield_now().await;
+                drop(lease);
+            }
+        }))
+    });
+
+    let scan = ScanPool::default();
+    rt.block_on(async {
+        for _ in 0..IN_USE {
+            scan.allocate().await.unwrap();
+        }
+    });
+    group.bench_function("mutex_scan", |b| {
+        b.to_async(&rt).iter(|| starts(&rt, || {
+            let scan = scan.clone();
+            async move {
+                let number = scan.allocate().await.unwrap();
+                tokio::task::yield_now().await;
+                scan.release(number).await;
+            }
+        }))
+    });
+    group.finish();
+}
+
+/// Run `STARTS` session starts as concurrent tasks and wait for all of them.
+async fn starts<F, Fut>(rt: &Runtime, start: F)
+where
+    F: Fn() -> Fut,
+    Fut: std::future::Future<Output = ()> + Send + 'static,
+{
+    let tasks: Vec<_> = (0..STARTS).map(|_| rt.spawn(start())).collect();
+    for task in tasks {
+        task.await.unwrap();
+    }
+}
+
+criterion_group!(benches, display_pool);
+criterion_main!(benches);
@@ -6,7 +6,8 @@ use std::pin::pin;
 use anyhow::{Context, Result};
 use sshx_core::proto::{
     client_update::ClientMessage, server_update::ServerMessage,
-    sshx_service_client::SshxServiceClient, ClientUpdate, CloseRequest, NewShell, OpenRequest,
+    sshx_service_client::SshxServiceClient, ClientUpdate, CloseRequest, DesktopList, NewShell,
+    OpenRequest, SessionParams,
 };
 use sshx_core::{rand_alphanumeric, Sid};
 use tokio::sync::mpsc;
@@ -18,6 +19,8 @@ use tracing::{debug, error, warn};
 
 use crate::encrypt::Encrypt;
 use crate::runner::{Runner, ShellData};
+#[cfg(feature = "desktop")]
+use crate::xpra_rejection::SessionRejection;
 
 /// Interval for sending empty heartbeat messages to the server.
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(2);
@@ -85,6 +88,7 @@ impl Controller {
             encrypted_zeros: encrypt.zeros().into(),
             name: name.into(),
             write_password_hash,
+            desktop: runner.is_desktop(),
         };
         let mut resp = client.open(req).await?.into_inner();
         resp.url = resp.url + "#" + &encryption_key;
@@ -195,10 +199,15 @@ impl Controller {
 
             match message {
                 ServerMessage::Input(input) => {
-                    let data = self.encrypt.segment(0x200000000, input.offset, &input.data);
+                    let data = if self.runner.is_desktop() {
+                        // Desktops rotate the keys of their input
+                        ShellData::Encrypted(input.offset, input.data)
+                    } else {
+                        ShellData::Data(self.encrypt.segment_bytes(0x200000000, input.offset, input.data))
+                    };
                     if let Some(sender) = self.shells_tx.get(&Sid(input.id)) {
                         // This line applies backpressure if the shell task is overloaded.
-                        sender.send(ShellData::Data(data)).await.ok();
+                        sender.send(data).await.ok();
                     } else {
                         warn!(%input.id, "received data for non-existing shell");
                     }
@@ -207,7 +216,7 @@ impl Controller {
                     let id = Sid(new_shell.id);
                     let center = (new_shell.x, new_shell.y);
                     if !self.shells_tx.contains_key(&id) {
-                        self.spawn_shell_task(id, center);
+                        self.spawn_shell_task(id, center, new_shell.desktop, new_shell.params);
                     } else {
                         warn!(%id, "server asked to create duplicate shell");
                     }
@@ -234,6 +243,19 @@ impl Controller {
                         warn!(%msg.id, "received resize for non-existing shell");
                     }
                 }
+                ServerMessage::ListDesktops(_) => {
+                    let sessions = self.runner.desktops().await;
+                    send_msg(&tx, ClientMessage::Desktops(DesktopList { sessions })).await?;
+                }
+                ServerMessage::RenameDesktop(rename) => {
+                    let description = rename.description.as_deref();
+                    if let Err(err) = self.runner.rename_desktop(&rename.id, &rename.name, description).await {
+                        send_msg(&tx, ClientMessage::Error(err.to_string())).await?;
+                    }
+                    // Answer with the sessions as they are now, renamed or not
+                    let sessions = self.runner.desktops().await;
+                    send_msg(&tx, ClientMessage::Desktops(DesktopList { sessions })).await?;
+                }
                 ServerMessage::Ping(ts) => {
                     // Echo back the timestamp, for stateless latency measurement.
                     send_msg(&tx, ClientMessage::Pong(ts)).await?;
@@ -245,8 +267,15 @@ impl Controller {
         }
     }
 
-    /// Entry point to start a new terminal task on the client.
-    fn spawn_shell_task(&mut self, id: Sid, center: (i32, i32)) {
+    /// Entry point to start a new terminal task on the client, optionally
+    /// showing a running desktop session or starting one with `params`.
+    fn spawn_shell_task(
+        &mut self,
+        id: Sid,
+        center: (i32, i32),
+        desktop: Option<String>,
+        params: Option<SessionParams>,
+    ) {
         let (shell_tx, shell_rx) = mpsc::channel(16);
         let opt = self.shells_tx.insert(id, shell_tx);
         debug_assert!(opt.is_none(), "shell ID cannot be in existing tasks");
@@ -254,18 +283,29 @@ impl Controller {
         let runner = self.runner.clone();
         let encrypt = self.encrypt.clone();
         let output_tx = self.output_tx.clone();
+        let session = self.name.clone();
         tokio::spawn(async move {
             debug!(%id, "spawning new shell");
             let new_shell = NewShell {
                 id: id.0,
                 x: center.0,
                 y: center.1,
+                desktop: desktop.clone(),
+                params: params.clone(),
             };
             if let Err(err) = output_tx.send(ClientMessage::CreatedShell(new_shell)).await {
                 error!(%id, ?err, "failed to send shell creation message");
                 return;
             }
-            if let Err(err) = runner.run(id, encrypt, shell_rx, output_tx.clone()).await {
+            let params = params.unwrap_or_default();
+            let result = runner.run(id, &session, encrypt, desktop, params, shell_rx, output_tx.clone());
+            if let Err(err) = result.await {
+                #[cfg(feature = "desktop")]
+                let err = match err.downcast_ref::<SessionRejection>() {
+                    Some(rejection) => ClientMessage::RejectedShell(rejection.to_proto(id)),
+                    None => ClientMessage::Error(err.to_string()),
+                };
+                #[cfg(not(feature = "desktop"))]
                 let err = ClientMessage::Error(err.to_string());
                 output_tx.send(err).await.ok();
             }
@@ -0,0 +1,281 @@
+//! Desktop session management for embedding in other services.
+//!
+//! The `ssh-desktop` binary drives desktops through the context it creates
+//! at startup; a [`DesktopService`] gives another Rust service the same
+//! operations on a context of its own, with
+//! its own configuration, sessions, metrics and logs:
+//!
+//! ```no_run
+//! # async fn run() -> anyhow::Result<()> {
+//! use sshx::desktop::DesktopService;
+//! use sshx::xpra_config::XpraConfig;
+//! use sshx::xpra_paging::SessionQuery;
+//! use tokio_stream::StreamExt;
+//!
+//! let service = DesktopService::new(XpraConfig::default())?;
+//! service.start();
+//!
+//! let mut events = Box::pin(service.events());
+//! while let Some(event) = events.next().await {
+//!     println!("{:?} {}", event.event_type, event.session_id);
+//!     for session in service.list(&SessionQuery::default()).sessions {
+//!         println!("  {} on :{}", session.session_id, session.display);
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Desktops are shown through shells of the sshx protocol, as for the web
+//! client: a [`DesktopShell`] carries a shell's input and output. Displays
+//! are placed on the hosts of the service's config, the local one taking
+//! numbers from the service's own pool. Maintenance mode and the claims on
+//! app profiles stay global, shared by every service in the process.
+
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use sshx_core::proto::client_update::ClientMessage;
+use sshx_core::proto::SessionParams;
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinHandle;
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
+use tokio_stream::{Stream, StreamExt};
+use tracing::warn;
+
+use crate::encrypt::Encrypt;
+use crate::runner::ShellData;
+use crate::xpra_config::XpraConfig;
+use crate::xpra_context::XpraContext;
+use crate::xpra_labels::Labels;
+use crate::xpra_logger::{SessionEvent, SessionEventType, TerminationReason};
+use crate::xpra_paging::{SessionPage, SessionQuery};
+use crate::xpra_runner;
+use crate::xpra_session_id::ParentShell;
+use crate::xpra_status::{self, SessionStatus};
+
+/// Shell of an sshx session that shows a desktop.
+pub struct DesktopShell {
+    /// sshx session and shell, recorded with the desktops it starts
+    pub parent: ParentShell,
+    /// Encryption of the session's terminal data
+    pub encrypt: Encrypt,
+    /// Input and resizes of the shell, with input passed on still encrypted
+    /// as [`ShellData::Encrypted`] so the desktop can rotate its keys
+    pub input: mpsc::Receiver<ShellData>,
+    /// Messages to the client showing the shell
+    pub output: mpsc::Sender<ClientMessage>,
+}
+
+/// What a new desktop is started with.
+#[derive(Debug, Clone, Default)]
+pub struct SessionRequest {
+    /// User the desktop runs as
+    pub user: String,
+    /// Labels recorded with the session
+    pub labels: Labels,
+    /// Parameters a client asked for, checked against `session_params` of
+    /// the config
+    pub params: SessionParams,
+}
+
+/// A desktop started by [`DesktopService::create_session`].
+#[derive(Debug)]
+pub struct SessionHandle {
+    /// Id of the session
+    pub session_id: String,
+    task: JoinHandle<Result<()>>,
+}
+
+impl SessionHandle {
+    /// Wait for the session to end. Dropping the handle leaves it running.
+    pub async fn wait(self) -> Result<()> {
+        self.task.await.context("desktop session task panicked")?
+    }
+}
+
+/// Desktop sessions of one host instance.
+#[derive(Debug, Clone)]
+pub struct DesktopService {
+    context: Arc<XpraContext>,
+}
+
+impl DesktopService {
+    /// Create a service with its own sessions, writing its logs to the
+    /// config's instance log directory. Nothing runs until [`Self::start`].
+    /// Fails if the config does not [validate](XpraConfig::validate).
+    pub fn new(config: XpraConfig) -> Result<Self> {
+        config.validate()?;
+        Ok(Self::with_context(XpraContext::new(config)))
+    }
+
+    /// Create a service for the sessions of an existing context.
+    pub fn with_context(context: Arc<XpraContext>) -> Self {
+        Self { context }
+    }
+
+    /// Context holding the sessions of the service
+    pub fn context(&self) -> &Arc<XpraContext> {
+        &self.context
+    }
+
+    /// Start terminating idle sessions, periodically logging metrics and
+    /// checking the worker hosts, in the background of the current Tokio
+    /// runtime.
+    pub fn start(&self) {
+        self.context.logger.start_logging(&self.context);
+        self.context.monitor.start();
+        self.context.hosts.start_health_checks();
+    }
+
+    /// Start a desktop shown through `shell`, returning once it is
+    /// registered. Rejections, e.g. by the admission policy or a rate limit,
+    /// are returned as a [`SessionRejection`](crate::xpra_rejection::SessionRejection).
+    pub async fn create_session(&self, shell: DesktopShell, request: SessionRequest) -> Result<SessionHandle> {
+        // Subscribed before starting, so the creation cannot be missed
+        let mut events = self.context.logger.subscribe();
+        let parent = shell.parent.clone();
+        let mut task = tokio::spawn(xpra_runner::start_xpra_session(
+            self.context.clone(),
+            shell.parent,
+            request.user,
+            request.labels,
+            request.params,
+            shell.encrypt,
+            shell.input,
+            shell.output,
+        ));
+        loop {
+            tokio::select! {
+                event = events.recv() => match event {
+                    Ok(event) if event.event_type == SessionEventType::Created && event.parent.as_ref() == Some(&parent) => {
+                        return Ok(SessionHandle { session_id: event.session_id, task });
+                    }
+                    Ok(_) => {}
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        warn!(missed, "Missed session events while starting a desktop");
+                    }
+                    Err(broadcast::error::RecvError::Closed) => anyhow::bail!("session events closed"),
+                },
+                result = &mut task => {
+                    result.context("desktop session task panicked")??;
+                    anyhow::bail!("desktop session ended before it was registered");
+                }
+            }
+        }
+    }
+
+    /// Show a running desktop of `user` through `shell`, returning once the
+    /// desktop lets go of it: when it ends or another shell takes over.
+    pub async fn attach(&self, session_id: &str, user: &str, shell: DesktopShell) -> Result<()> {
+        xpra_runner::attach_xpra_session(
+            self.context.clone(),
+            shell.parent.shell,
+            user.to_string(),
+            session_id.to_string(),
+            shell.encrypt,
+            shell.input,
+            shell.output,
+        )
+        .await
+    }
+
+    /// A page of the running sessions matching `query`.
+    pub fn list(&self, query: &SessionQuery) -> SessionPage<SessionStatus> {
+        self.context.monitor.query_sessions(query, |id, info| xpra_status::session_status(&self.context, id, info))
+    }
+
+    /// Terminate a session for `reason`, returning once its display is
+    /// closed, or false if it is not running.
+    pub async fn terminate(&self, session_id: &str, reason: TerminationReason) -> bool {
+        self.context.monitor.terminate_session(session_id, reason).await
+    }
+
+    /// Session events from now on, as they are written to the history log.
+    /// Events a slow consumer falls too far behind on are skipped.
+    pub fn events(&self) -> impl Stream<Item = SessionEvent> {
+        BroadcastStream::new(self.context.logger.subscribe()).filter_map(|event| match event {
+            Ok(event) => Some(event),
+            Err(BroadcastStreamRecvError::Lagged(missed)) => {
+                warn!(missed, "Skipped session events of a slow subscriber");
+                None
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sshx_core::Sid;
+    use tokio_stream::StreamExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_list_terminate_and_events() {
+        let dir = std::env::temp_dir().join(format!("sshx-desktop-service-{}", std::process::id()));
+        let config = XpraConfig {
+            log_dir: dir.clone(),
+            instance: None,
+            min_display: 700,
+            max_display: 701,
+            base_port: 16000,
+            ..XpraConfig::default()
+        };
+        let service = DesktopService::new(config).unwrap();
+        let mut events = Box::pin(service.events());
+
+        // A `sleep` stands in for xpra
+        let parent = ParentShell { session: "k3xq9v".to_string(), shell: Sid(2) };
+        let (guard, _) = service.context().monitor.register_sleeping("desktop-1", Some(parent), Labels::new()).await;
+        let shutdown = guard.info().shutdown.clone();
+
+        let event = events.next().await.unwrap();
+        assert_eq!((event.event_type, event.session_id.as_str()), (SessionEventType::Created, "desktop-1"));
+        let page = service.list(&SessionQuery { user: Some("alice".to_string()), ..SessionQuery::default() });
+        assert_eq!(page.total, 1);
+        assert_eq!(page.sessions[0].session_id, "desktop-1");
+        // Ports are those of the service's hosts
+        assert_eq!(page.sessions[0].websocket_port, 16000);
+
+        // Terminating returns once the forwarder finished the guard
+        let forwarder = async {
+            shutdown.notified().await;
+            guard.finish().await;
+        };
+        let (terminated, ()) = tokio::join!(service.terminate("desktop-1", TerminationReason::AdminKill), forwarder);
+        assert!(terminated);
+        assert_eq!(service.list(&SessionQuery::default()).total, 0);
+        let event = events.next().await.unwrap();
+        assert_eq!(event.event_type, SessionEventType::Terminated);
+        assert_eq!(event.reason, Some(TerminationReason::AdminKill));
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn test_service_uses_own_config() {
+        let service = DesktopService::new(XpraConfig {
+            min_display: 700,
+            max_display: 701,
+            base_port: 16000,
+            instance: None,
+            ..XpraConfig::default()
+        })
+        .unwrap();
+
+        // The local host is sized by the service's config and shares its pool
+        let hosts = service.context().hosts.hosts();
+        assert_eq!(hosts.len(), 1);
+        assert_eq!(hosts[0].websocket_port(701), 16001);
+        assert_eq!(hosts[0].capacity(), 2);
+        let lease = service.context().display_pool.allocate().await.unwrap();
+        assert_eq!(lease.number(), 700);
+        assert_eq!(hosts[0].active_sessions().await, 1);
+
+        // Display ranges that are empty are refused
+        let config = XpraConfig { min_display: 701, max_display: 700, instance: None, ..XpraConfig::default() };
+        let err = DesktopService::new(config).err().unwrap();
+        assert!(err.to_string().contains("greater than max_display"));
+    }
+}
@@ -1,6 +1,7 @@
 //! Encryption of byte streams based on a random key.
 
 use aes::cipher::{KeyIvInit, StreamCipher, StreamCipherSeek};
+use bytes::Bytes;
 
 type Aes128Ctr64BE = ctr::Ctr64BE<aes::Aes128>;
 
@@ -40,27 +41,81 @@ impl Encrypt {
         zeros.to_vec()
     }
 
+    /// Construct an encryptor from a raw AES key, for streams that switch to
+    /// random keys sent to the other end over time.
+    pub fn from_key(aes_key: [u8; 16]) -> Self {
+        Self { aes_key }
+    }
+
     /// Encrypt a segment of data from a stream.
     ///
     /// Note that in CTR mode, the encryption operation is the same as the
     /// decryption operation.
     pub fn segment(&self, stream_num: u64, offset: u64, data: &[u8]) -> Vec<u8> {
+        let mut buf = data.to_vec();
+        self.apply_keystream(stream_num, offset, &mut buf);
+        buf
+    }
+
+    /// Encrypt a segment of data from a stream in place.
+    ///
+    /// The buffer of `data` is reused when nothing else references it, so
+    /// high-rate streams avoid a copy per segment.
+    pub fn segment_bytes(&self, stream_num: u64, offset: u64, data: Bytes) -> Bytes {
+        let mut buf = Vec::from(data);
+        self.apply_keystream(stream_num, offset, &mut buf);
+        buf.into()
+    }
+
+    fn apply_keystream(&self, stream_num: u64, offset: u64, buf: &mut [u8]) {
         assert_ne!(stream_num, 0, "stream number must be nonzero"); // security check
 
         let mut iv = [0; 16];
         iv[0..8].copy_from_slice(&stream_num.to_be_bytes());
 
         let mut cipher = Aes128Ctr64BE::new(&self.aes_key.into(), &iv.into());
-        let mut buf = data.to_vec();
         cipher.seek(offset);
-        cipher.apply_keystream(&mut buf);
-        buf
+        cipher.apply_keystream(buf);
+    }
+}
+
+/// Keys of a stream that switches keys at offsets.
+#[derive(Clone)]
+pub struct KeySchedule {
+    /// Keys with the offsets they start at, oldest first
+    keys: Vec<(u64, Encrypt)>,
+}
+
+impl KeySchedule {
+    /// Create a schedule using `key` for the whole stream.
+    pub fn new(key: Encrypt) -> Self {
+        Self { keys: vec![(0, key)] }
+    }
+
+    /// Use `key` from `offset` on. Data before it stays readable under the
+    /// previous key, for segments that arrive late; older keys are dropped.
+    pub fn switch(&mut self, offset: u64, key: Encrypt) {
+        let previous = self.keys.pop().expect("schedule has a key");
+        self.keys = vec![previous, (offset, key)];
+    }
+
+    /// Decrypt a segment of the stream with the key of its offset.
+    pub fn segment_bytes(&self, stream_num: u64, offset: u64, data: Bytes) -> Bytes {
+        let (_, key) = self
+            .keys
+            .iter()
+            .rev()
+            .find(|(start, _)| *start <= offset)
+            .unwrap_or(&self.keys[0]);
+        key.segment_bytes(stream_num, offset, data)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Encrypt;
+    use bytes::Bytes;
+
+    use super::{Encrypt, KeySchedule};
 
     #[test]
     fn make_encrypt() {
@@ -93,6 +148,40 @@ mod tests {
         }
     }
 
+    #[test]
+    fn segment_bytes_matches() {
+        let encrypt = Encrypt::new("this is a test key");
+        let data = b"1st block.(16B)|2nd block......|3rd block";
+        let encrypted = encrypt.segment_bytes(1, 5, Bytes::from(data.to_vec()));
+        assert_eq!(encrypted, encrypt.segment(1, 5, data));
+        assert_eq!(encrypt.segment_bytes(1, 5, encrypted), &data[..]);
+    }
+
+    #[test]
+    fn rotation_boundary() {
+        let encrypt = Encrypt::new("this is a test key");
+        let rotated = Encrypt::from_key([7; 16]);
+        assert_ne!(rotated.zeros(), encrypt.zeros());
+
+        // The sender switches keys at offset 11, and the receiver decrypts
+        // each side of the boundary with the matching key
+        let mut stream = encrypt.segment(1, 0, b"hello world");
+        stream.extend(rotated.segment(1, 11, b", rotated!"));
+        let mut schedule = KeySchedule::new(encrypt.clone());
+        schedule.switch(11, rotated.clone());
+        let mut decrypted = schedule.segment_bytes(1, 0, Bytes::from(stream[..11].to_vec())).to_vec();
+        decrypted.extend(schedule.segment_bytes(1, 11, Bytes::from(stream[11..].to_vec())));
+        assert_eq!(decrypted, b"hello world, rotated!");
+
+        // The old key no longer decrypts past the boundary
+        assert_ne!(encrypt.segment(1, 11, &stream[11..]), b", rotated!");
+
+        // Only the key before the latest switch is kept
+        schedule.switch(21, Encrypt::from_key([8; 16]));
+        let early = schedule.segment_bytes(1, 11, Bytes::from(stream[11..].to_vec()));
+        assert_eq!(early, &b", rotated!"[..]);
+    }
+
     #[test]
     #[should_panic]
     fn zero_stream_num() {
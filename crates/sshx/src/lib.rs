@@ -2,6 +2,9 @@
 //!
 //! This crate does not forbid use of unsafe code because it needs to interact
 //! with operating-system APIs to access pseudoterminal (PTY) devices.
+//!
+//! The remote desktop modules, [`desktop`], `status_display` and `xpra_*`,
+//! are only built with the `desktop` feature, which is on by default.
 
 #![deny(unsafe_code)]
 #![warn(missing_docs)]
@@ -10,5 +13,89 @@ pub mod controller;
 pub mod encrypt;
 pub mod runner;
 pub mod terminal;
-pub mod xpra;
-pub mod xpra_runner;
+
+cfg_if::cfg_if! {
+    if #[cfg(feature = "desktop")] {
+        pub mod desktop;
+        pub mod status_display;
+        pub mod xpra;
+        pub mod xpra_admin;
+        pub mod xpra_affinity;
+        pub mod xpra_archive;
+        pub mod xpra_audit;
+        pub mod xpra_auth;
+        pub mod xpra_backend;
+        pub mod xpra_billing;
+        pub mod xpra_branding;
+        pub mod xpra_capabilities;
+        pub mod xpra_capacity;
+        pub mod xpra_chart;
+        pub mod xpra_children;
+        pub mod xpra_cleanup;
+        pub mod xpra_clipboard;
+        pub mod xpra_command;
+        pub mod xpra_config;
+        pub mod xpra_context;
+        pub mod xpra_crash;
+        pub mod xpra_devices;
+        pub mod xpra_doctor;
+        pub mod xpra_drain;
+        pub mod xpra_export;
+        pub mod xpra_gpu;
+        pub mod xpra_handshake;
+        pub mod xpra_headless;
+        pub mod xpra_health;
+        pub mod xpra_hibernate;
+        pub mod xpra_hooks;
+        pub mod xpra_hosts;
+        pub mod xpra_idle;
+        pub mod xpra_inspect;
+        pub mod xpra_labels;
+        pub mod xpra_log_analyzer;
+        pub mod xpra_log_rotation;
+        pub mod xpra_log_schema;
+        pub mod xpra_logger;
+        pub mod xpra_macos;
+        pub mod xpra_maintenance;
+        pub mod xpra_metrics;
+        pub mod xpra_monitor;
+        pub mod xpra_motd;
+        pub mod xpra_netns;
+        pub mod xpra_notify;
+        pub mod xpra_output;
+        pub mod xpra_paging;
+        pub mod xpra_placement;
+        pub mod xpra_policy;
+        pub mod xpra_pool;
+        pub mod xpra_priority;
+        pub mod xpra_profiles;
+        pub mod xpra_protocol;
+        pub mod xpra_proxy;
+        pub mod xpra_publisher;
+        pub mod xpra_quota;
+        pub mod xpra_rate_limit;
+        pub mod xpra_rdp;
+        pub mod xpra_rejection;
+        pub mod xpra_sandbox;
+        pub mod xpra_runner;
+        pub mod xpra_screenshots;
+        pub mod xpra_server_log;
+        pub mod xpra_session_params;
+        pub mod xpra_session_id;
+        pub mod xpra_session_state;
+        pub mod xpra_smartcard;
+        #[cfg(test)]
+        mod xpra_soak;
+        pub mod xpra_status;
+        pub mod xpra_summary;
+        pub mod xpra_systemd;
+        pub mod xpra_termination;
+        pub mod xpra_time_format;
+        pub mod xpra_time_range;
+        pub mod xpra_tls;
+        pub mod xpra_visualizer;
+        pub mod xpra_watchdog;
+        pub mod xpra_watermark;
+        pub mod xpra_xorg;
+    }
+}
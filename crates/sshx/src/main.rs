@@ -7,35 +7,438 @@ use sshx::{controller::Controller, runner::Runner, terminal::get_default_shell};
 use tokio::signal;
 use tracing::error;
 
+cfg_if::cfg_if! {
+    if #[cfg(feature = "desktop")] {
+        use std::path::PathBuf;
+        use std::time::Duration;
+
+        use chrono::{DateTime, Utc};
+        use sshx::{status_display, xpra_billing, xpra_log_analyzer, xpra_status, xpra_summary, xpra_visualizer};
+        use sshx::xpra_admin::{self, AdminRequest, AdminServer};
+        use sshx::xpra_audit::{self, HistoryAudit, RedactReport};
+        use sshx::xpra_chart::ChartStyle;
+        use std::sync::Arc;
+
+        use sshx::xpra_config::XpraConfig;
+        use sshx::xpra_context::XpraContext;
+        use sshx::xpra_doctor;
+        use sshx::xpra_headless;
+        use sshx::xpra_health::{self, HealthReport, HealthServer};
+        use sshx::xpra_labels::{self, Label};
+        use sshx::xpra_log_rotation::LogRotator;
+        use sshx::xpra_logger::SessionEvent;
+        use sshx::xpra_output;
+        use sshx::xpra_paging::{self, SessionQuery};
+        use sshx::xpra_sandbox::{self, SandboxProfile};
+        use sshx::xpra_screenshots::ScreenshotArchive;
+        use sshx::xpra_systemd;
+        use sshx::xpra_time_format::{TimeDisplay, TimeNotation, TimeZoneMode};
+        use sshx::xpra_time_range::{TimeRange, TimeSpec};
+        use sshx::xpra_watchdog;
+        use sshx_core::Sid;
+        use tokio::time;
+        use tracing::warn;
+    }
+}
+
 /// A secure web-based, collaborative terminal.
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
     #[clap(subcommand)]
     command: Command,
+
+    /// Color text output: auto (when stdout is a terminal and NO_COLOR is
+    /// not set), always or never
+    #[cfg(feature = "desktop")]
+    #[clap(long, global = true, default_value = "auto")]
+    color: status_display::ColorMode,
+
+    /// Show times in utc or local time, `time_display.zone` of the config
+    /// by default
+    #[cfg(feature = "desktop")]
+    #[clap(long, global = true)]
+    time_zone: Option<TimeZoneMode>,
+
+    /// Show times as iso dates and times or relative to now ("3h ago"),
+    /// `time_display.notation` of the config by default
+    #[cfg(feature = "desktop")]
+    #[clap(long, global = true)]
+    time_format: Option<TimeNotation>,
 }
 
 #[derive(Parser, Debug)]
 enum Command {
     /// Start a new terminal session
     Start(StartArgs),
-    
+
+    #[cfg(feature = "desktop")]
+    #[clap(flatten)]
+    Desktop(DesktopCommand),
+}
+
+/// Commands managing the remote desktops of a host
+#[cfg(feature = "desktop")]
+#[derive(clap::Subcommand, Debug)]
+enum DesktopCommand {
     /// Show Xpra session status
     Status {
         /// Output format (text/json)
         #[clap(long, default_value = "text")]
         format: String,
         
-        /// Only show active sessions
+        /// Only show active sessions in the text output
         #[clap(long)]
         active_only: bool,
+
+        /// Only show sessions with this label, as KEY=VALUE (repeatable)
+        #[clap(long = "filter")]
+        filters: Vec<Label>,
+
+        /// Only show sessions of this user
+        #[clap(long)]
+        user: Option<String>,
+
+        /// Only show sessions idle at least this long, e.g. 90s, 30m or 1h
+        #[clap(long, value_parser = status_display::parse_duration)]
+        min_idle: Option<u64>,
+
+        /// Sort sessions by idle (longest first), user, or display
+        #[clap(long)]
+        sort: Option<status_display::SortKey>,
+
+        /// Comma-separated columns to show, e.g. id,user,idle
+        #[clap(long, value_delimiter = ',')]
+        columns: Vec<status_display::Column>,
+
+        /// Refresh every INTERVAL (2s if not given), e.g. 5 or 1m, marking
+        /// lines that changed, until interrupted
+        #[clap(
+            long,
+            value_name = "INTERVAL",
+            num_args = 0..=1,
+            default_missing_value = "2",
+            value_parser = status_display::parse_duration,
+        )]
+        watch: Option<u64>,
+
+        /// Show the sessions table in full instead of fitting it to the
+        /// terminal
+        #[clap(long)]
+        wide: bool,
+
+        /// Show only the session counts and idle and running times, by user
+        /// and by team
+        #[clap(long, conflicts_with_all = ["watch", "columns", "sort"])]
+        summary: bool,
+
+        /// Most sessions to list, up to 1000
+        #[clap(long, default_value_t = xpra_paging::MAX_PAGE_SIZE)]
+        limit: usize,
+
+        /// Only list sessions with ids after this one, to continue a listing
+        /// cut off by --limit
+        #[clap(long, value_name = "SESSION_ID")]
+        after: Option<String>,
     },
 
     /// Analyze Xpra logs
     Analyze {
-        /// Analysis period in days
-        #[clap(long, default_value = "7")]
-        days: i64,
+        /// Analysis period in days up to now, 7 unless another period is given
+        #[clap(long, conflicts_with_all = ["since", "last_month"])]
+        days: Option<u64>,
+
+        /// Start of the period, e.g. 7d, yesterday, last monday or 2024-05-01
+        #[clap(long, conflicts_with = "last_month")]
+        since: Option<TimeSpec>,
+
+        /// End of the period, now if not given
+        #[clap(long, requires = "since")]
+        until: Option<TimeSpec>,
+
+        /// Analyze the previous calendar month
+        #[clap(long)]
+        last_month: bool,
+
+        /// Output format (text/json/influx/grafana)
+        #[clap(long, default_value = "text")]
+        format: String,
+
+        /// Only analyze sessions with this label, as KEY=VALUE (repeatable)
+        #[clap(long = "filter")]
+        filters: Vec<Label>,
+
+        /// Number of log files read at once, the number of CPUs by default
+        #[clap(long)]
+        jobs: Option<usize>,
+
+        /// Directory of xpra's own :<display>.log server logs to correlate
+        /// with failed sessions
+        #[clap(long)]
+        xpra_logs: Option<PathBuf>,
+
+        /// Width of the charts of the text report, in columns
+        #[clap(long, default_value_t = 60)]
+        chart_width: usize,
+
+        /// Also read the logs of the period moved to the configured archive
+        #[clap(long)]
+        archived: bool,
+    },
+
+    /// Put the running desktop host into maintenance mode
+    Drain {
+        /// Seconds until remaining sessions are terminated [default: the
+        /// configured drain_deadline]
+        #[clap(long)]
+        deadline: Option<u64>,
+
+        /// Message shown to users in their sessions
+        #[clap(long)]
+        message: Option<String>,
+
+        /// Leave maintenance mode and accept sessions again
+        #[clap(long)]
+        cancel: bool,
+    },
+
+    /// Check whether this desktop host is ready to serve sessions
+    Health {
+        /// Run the checks in this process instead of asking the running host
+        #[clap(long)]
+        local: bool,
+
+        /// Output format (text/json)
+        #[clap(long, default_value = "text")]
+        format: String,
+    },
+
+    /// Check the environment for problems that would break desktop sessions
+    Doctor {
+        /// Output format (text/json)
+        #[clap(long, default_value = "text")]
+        format: String,
+    },
+
+    /// Run a command in the desktop sandbox, as xpra is started with
+    #[clap(hide = true)]
+    Sandbox {
+        /// Sandbox profile (permissive/standard/strict)
+        #[clap(long)]
+        profile: SandboxProfile,
+
+        /// Further path readable in the sandbox
+        #[clap(long)]
+        read: Vec<PathBuf>,
+
+        /// Further path writable in the sandbox
+        #[clap(long)]
+        write: Vec<PathBuf>,
+
+        /// Directory that looks empty in the sandbox
+        #[clap(long)]
+        hide: Vec<PathBuf>,
+
+        /// Path beneath a hidden directory that stays in place
+        #[clap(long)]
+        keep: Vec<PathBuf>,
+
+        /// Command to run, after `--`
+        #[clap(last = true, required = true)]
+        command: Vec<String>,
+    },
+
+    /// Export per-user usage for chargeback
+    Billing {
+        /// Billing month as YYYY-MM (defaults to the previous month)
+        #[clap(long)]
+        month: Option<String>,
+
+        /// Output format (csv/json)
+        #[clap(long, default_value = "csv")]
+        format: String,
+
+        /// Write the report to a file instead of stdout
+        #[clap(long)]
+        output: Option<PathBuf>,
+    },
+
+    /// Drive headless desktop sessions for automation
+    Headless {
+        #[clap(subcommand)]
+        action: HeadlessAction,
+    },
+
+    /// Print everything known about one session as JSON
+    Inspect {
+        session_id: String,
+    },
+
+    /// End every desktop started from an sshx session, and the shells they
+    /// were started from
+    Kill {
+        /// Name of the sshx session
+        session: String,
+
+        /// Only end the desktop started from this shell
+        #[clap(long)]
+        shell: Option<u32>,
+    },
+
+    /// Show a session's xpra output
+    Logs {
+        session_id: String,
+
+        /// Keep printing new output until the session ends
+        #[clap(short, long)]
+        follow: bool,
+
+        /// Only show the last this many lines
+        #[clap(short = 'n', long)]
+        lines: Option<usize>,
+
+        /// Only show output from this time onwards, e.g. 30m, today or last monday
+        #[clap(long)]
+        since: Option<TimeSpec>,
+    },
+
+    /// Show the history events of a session, or of all sessions of a user
+    History {
+        /// Session id or user name
+        key: String,
+
+        /// Only show events from this time onwards, e.g. 7d, yesterday or 2024-05-01
+        #[clap(long)]
+        since: Option<TimeSpec>,
+
+        /// Read the history log in this process instead of asking the running host
+        #[clap(long)]
+        local: bool,
+
+        /// Output format (text/json)
+        #[clap(long, default_value = "text")]
+        format: String,
+    },
+
+    /// Verify, export or redact the history log, or list the compliance
+    /// screenshots
+    Audit {
+        #[clap(subcommand)]
+        action: AuditAction,
+    },
+}
+
+#[cfg(feature = "desktop")]
+#[derive(clap::Subcommand, Debug)]
+enum HeadlessAction {
+    /// Start a headless session and print its ID
+    Launch {
+        /// Program to run in the session (defaults to the window manager)
+        #[clap(long)]
+        program: Option<String>,
+
+        /// Seconds without requests before the session is stopped (0 = never)
+        #[clap(long)]
+        idle_timeout: Option<u64>,
+
+        /// Label to record with the session, as KEY=VALUE (repeatable)
+        #[clap(long = "label")]
+        labels: Vec<Label>,
+    },
+
+    /// Run a shell command on the session's display and exit with its status
+    Exec {
+        session_id: String,
+        shell_command: String,
+
+        /// Start the command in the background instead of waiting for it
+        #[clap(long)]
+        detach: bool,
+    },
+
+    /// Save a PNG screenshot of the session's display in the host's
+    /// screenshot directory, printing where it was written
+    Screenshot {
+        session_id: String,
+        name: String,
+    },
+
+    /// Stop a headless session
+    Stop {
+        session_id: String,
+    },
+}
+
+#[cfg(feature = "desktop")]
+#[derive(clap::Subcommand, Debug)]
+enum AuditAction {
+    /// Check that every line of the history log and its rotated files is an
+    /// event, and that none was changed or removed
+    Verify {
+        /// Output format (text/json)
+        #[clap(long, default_value = "text")]
+        format: String,
+    },
+
+    /// Print the events of the history log and its rotated files as JSON
+    /// lines, oldest first
+    Export {
+        /// Only events from this time onwards, e.g. 7d, yesterday or 2024-05-01
+        #[clap(long)]
+        since: Option<TimeSpec>,
+
+        /// Only events before this time
+        #[clap(long)]
+        until: Option<TimeSpec>,
+
+        /// Only events of this user
+        #[clap(long)]
+        user: Option<String>,
+
+        /// Only events numbered after this one, the last one ingested before
+        #[clap(long)]
+        after_seq: Option<u64>,
+
+        /// Output format: `log` for the lines as logged, `records` for
+        /// events keyed by host and number for billing systems
+        #[clap(long, default_value = "log")]
+        format: String,
+
+        /// Write the events to a file instead of stdout
+        #[clap(long)]
+        output: Option<PathBuf>,
+    },
+
+    /// Replace every event of a user with a tombstone, for a data deletion
+    /// request
+    Redact {
+        /// Name of the user
+        #[clap(long)]
+        user: String,
+
+        /// Rewrite the history log in this process instead of asking the
+        /// running host, only while no host writes it
+        #[clap(long)]
+        local: bool,
+    },
+
+    /// List the screenshots taken of active sessions for compliance
+    Screenshots {
+        /// Only screenshots from this time onwards, e.g. 7d, yesterday or 2024-05-01
+        #[clap(long)]
+        since: Option<TimeSpec>,
+
+        /// Only screenshots before this time
+        #[clap(long)]
+        until: Option<TimeSpec>,
+
+        /// Only screenshots of this user
+        #[clap(long)]
+        user: Option<String>,
+
+        /// Only screenshots of this session
+        #[clap(long)]
+        session: Option<String>,
 
         /// Output format (text/json)
         #[clap(long, default_value = "text")]
@@ -54,13 +457,20 @@ struct StartArgs {
     shell: Option<String>,
 
     /// Start an Xpra session instead of a shell
+    #[cfg(feature = "desktop")]
     #[clap(long)]
     xpra: bool,
 
     /// Window manager to use for Xpra session
+    #[cfg(feature = "desktop")]
     #[clap(long)]
     wm: Option<String>,
 
+    /// Label to record with the Xpra session, as KEY=VALUE (repeatable)
+    #[cfg(feature = "desktop")]
+    #[clap(long = "label")]
+    labels: Vec<Label>,
+
     /// Quiet mode, only prints the URL to stdout.
     #[clap(short, long)]
     quiet: bool,
@@ -113,14 +523,44 @@ fn print_greeting(shell: &str, controller: &Controller) {
     }
 }
 
+/// Start the background services of a desktop host.
+#[cfg(feature = "desktop")]
+fn start_xpra_services(context: &Arc<XpraContext>) -> Result<()> {
+    context.logger.start_logging(context);
+    context.monitor.start();
+    xpra_watchdog::start(context.monitor.clone(), context.config.watchdog.clone());
+    context.maintenance.start();
+    context.headless.start();
+    context.hosts.start_health_checks();
+    context.config.profiles.start_expiry(context.hosts.hosts().to_vec());
+    context.config.screenshots.start(context);
+    if let Some(publisher) = &context.config.publisher {
+        publisher.start(context, context.config.event_source())?;
+    }
+    xpra_billing::UsageExporter::new(context.logger.log_dir().to_path_buf()).start_scheduled_exports();
+    let mut rotator = LogRotator::new(context.logger.log_dir().to_path_buf())
+        .with_logger(context.logger.clone())
+        .with_compression(context.config.log_compression)
+        .with_disk_limits(context.config.log_disk_limits);
+    if let Some(target) = &context.config.archive {
+        rotator = rotator.with_archive(target.clone());
+    }
+    rotator.start_rotation();
+    AdminServer::new(context.clone()).start()?;
+    if let Some(addr) = context.config.health_listen {
+        HealthServer::new(context.clone(), addr).start()?;
+    }
+    Ok(())
+}
+
 #[tokio::main]
-async fn start(args: Args) -> Result<()> {
-    let shell = match args.shell {
-        Some(shell) => shell,
+async fn start(args: &StartArgs, #[cfg(feature = "desktop")] context: &Arc<XpraContext>) -> Result<()> {
+    let shell = match &args.shell {
+        Some(shell) => shell.clone(),
         None => get_default_shell().await,
     };
 
-    let name = args.name.unwrap_or_else(|| {
+    let name = args.name.clone().unwrap_or_else(|| {
         let mut name = whoami::username();
         if let Ok(host) = whoami::fallible::hostname() {
             // Trim domain information like .lan or .local
@@ -131,6 +571,29 @@ async fn start(args: Args) -> Result<()> {
         name
     });
 
+    #[cfg(feature = "desktop")]
+    let runner = if args.xpra {
+        // Surface setup problems before the first session is attempted
+        let report = xpra_doctor::run(context).await;
+        for check in report.checks.iter().filter(|c| !c.ok) {
+            warn!(check = check.name, "{}", check.detail);
+        }
+        if !args.quiet && !report.ready {
+            status_display::display_doctor(&report, "text")?;
+        }
+        // Detect each host's xpra release up front rather than on the first session
+        futures_util::future::join_all(context.hosts.hosts().iter().map(|host| host.capabilities())).await;
+        start_xpra_services(context)?;
+        Runner::Xpra {
+            display: 0,
+            wm: args.wm.clone().unwrap_or_else(|| context.config.window_manager.clone()),
+            labels: xpra_labels::to_labels(&args.labels),
+            context: context.clone(),
+        }
+    } else {
+        Runner::Shell(shell.clone())
+    };
+    #[cfg(not(feature = "desktop"))]
     let runner = Runner::Shell(shell.clone());
     let mut controller = Controller::new(&args.server, &name, runner, args.enable_readers).await?;
     if args.quiet {
@@ -142,20 +605,234 @@ async fn start(args: Args) -> Result<()> {
     } else {
         print_greeting(&shell, &controller);
     }
+    run_until_interrupted(&mut controller).await;
+    controller.close().await?;
+
+    Ok(())
+}
+
+/// Run the session until interrupted, petting the systemd watchdog from the
+/// main loop, so a wedged runtime gets the service restarted.
+#[cfg(feature = "desktop")]
+async fn run_until_interrupted(controller: &mut Controller) {
+    xpra_systemd::notify_ready();
 
+    let mut watchdog = time::interval(
+        xpra_systemd::watchdog_interval().unwrap_or(Duration::from_secs(60)),
+    );
     let exit_signal = signal::ctrl_c();
     tokio::pin!(exit_signal);
+    {
+        let run = controller.run();
+        tokio::pin!(run);
+        loop {
+            tokio::select! {
+                _ = &mut run => unreachable!(),
+                Ok(()) = &mut exit_signal => break,
+                _ = watchdog.tick() => xpra_systemd::notify_watchdog(),
+            }
+        }
+    }
+    xpra_systemd::notify_stopping();
+}
+
+/// Run the session until interrupted.
+#[cfg(not(feature = "desktop"))]
+async fn run_until_interrupted(controller: &mut Controller) {
     tokio::select! {
         _ = controller.run() => unreachable!(),
-        Ok(()) = &mut exit_signal => (),
-    };
-    controller.close().await?;
+        Ok(()) = signal::ctrl_c() => {}
+    }
+}
+
+#[cfg(feature = "desktop")]
+#[tokio::main]
+async fn admin(context: &XpraContext, request: AdminRequest) -> Result<serde_json::Value> {
+    xpra_admin::send_request(&context.config.admin_socket, &request).await
+}
+
+#[cfg(feature = "desktop")]
+#[tokio::main]
+async fn status(context: &XpraContext, query: &SessionQuery) -> Result<xpra_status::XpraStatus> {
+    let request = AdminRequest::Status { query: query.clone() };
+    let status = xpra_admin::send_request(&context.config.admin_socket, &request).await?;
+    Ok(serde_json::from_value(status)?)
+}
+
+#[cfg(feature = "desktop")]
+#[tokio::main]
+async fn session_summary(context: &XpraContext) -> Result<xpra_summary::SessionSummary> {
+    let summary = xpra_admin::send_request(&context.config.admin_socket, &AdminRequest::Summary).await?;
+    Ok(serde_json::from_value(summary)?)
+}
 
+#[cfg(feature = "desktop")]
+#[tokio::main]
+async fn analyze(
+    analyzer: &xpra_log_analyzer::LogAnalyzer,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> Result<xpra_log_analyzer::LogAnalysis> {
+    analyzer.analyze_period(start, end).await
+}
+
+#[cfg(feature = "desktop")]
+#[tokio::main]
+async fn doctor(context: &XpraContext) -> HealthReport {
+    xpra_doctor::run(context).await
+}
+
+#[cfg(feature = "desktop")]
+#[tokio::main]
+async fn health(context: &XpraContext, local: bool) -> Result<HealthReport> {
+    if local {
+        return Ok(xpra_health::self_check(context).await);
+    }
+    let report = xpra_admin::send_request(&context.config.admin_socket, &AdminRequest::Health).await?;
+    Ok(serde_json::from_value(report)?)
+}
+
+#[cfg(feature = "desktop")]
+#[tokio::main]
+async fn logs(context: &XpraContext, request: AdminRequest) -> Result<()> {
+    xpra_admin::stream_request(&context.config.admin_socket, &request, print_log_lines).await
+}
+
+#[cfg(feature = "desktop")]
+#[tokio::main]
+async fn history(context: &XpraContext, key: &str, since: Option<TimeSpec>, local: bool) -> Result<Vec<SessionEvent>> {
+    if local {
+        let since = since.map(|since| since.resolve(Utc::now()));
+        return context.logger.history(key, since).await;
+    }
+    let request = AdminRequest::History { key: key.to_string(), since };
+    let events = xpra_admin::send_request(&context.config.admin_socket, &request).await?;
+    Ok(serde_json::from_value(events)?)
+}
+
+/// Run an audit of the history log, returning the process exit code.
+#[cfg(feature = "desktop")]
+#[tokio::main]
+async fn audit(context: &XpraContext, action: &AuditAction) -> Result<ExitCode> {
+    let mut audit = HistoryAudit::new(context.config.instance_log_dir());
+    if let Some(target) = &context.config.archive {
+        audit = audit.with_archive(target.clone());
+    }
+    match action {
+        AuditAction::Verify { format } => {
+            let report = audit.verify().await?;
+            status_display::display_audit(&report, format)?;
+            if !report.is_intact() {
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+        AuditAction::Export { since, until, user, after_seq, format, output } => {
+            let now = Utc::now();
+            let since = since.map(|since| since.resolve(now));
+            let until = until.map(|until| until.resolve(now));
+            let lines = audit.export(since, until, user.as_deref(), *after_seq).await?;
+            let lines = match format.as_str() {
+                "log" => lines,
+                "records" => {
+                    let source = context.config.event_source();
+                    lines.iter().map(|line| xpra_audit::export_record(line, &source)).collect::<Result<_>>()?
+                }
+                _ => anyhow::bail!("Unsupported format: {}", format),
+            };
+            let mut lines = lines.join("\n");
+            if !lines.is_empty() {
+                lines.push('\n');
+            }
+            match output {
+                Some(path) => std::fs::write(path, lines)?,
+                None => print!("{}", lines),
+            }
+        }
+        AuditAction::Redact { user, local } => {
+            let report: RedactReport = if *local {
+                context.logger.redact_history(user, context.config.archive.as_ref()).await?
+            } else {
+                let request = AdminRequest::Redact { user: user.clone() };
+                serde_json::from_value(xpra_admin::send_request(&context.config.admin_socket, &request).await?)?
+            };
+            println!("Redacted {} events of {} in {} files", report.events, user, report.files);
+        }
+        AuditAction::Screenshots { since, until, user, session, format } => {
+            let now = Utc::now();
+            let archive = ScreenshotArchive::new(context.config.screenshots.dir.clone());
+            let entries = archive
+                .entries(since.map(|since| since.resolve(now)), until.map(|until| until.resolve(now)), user.as_deref(), session.as_deref())
+                .await?;
+            status_display::display_screenshots(&archive, &entries, format)?;
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+#[cfg(feature = "desktop")]
+fn print_log_lines(data: serde_json::Value) -> Result<()> {
+    let output: xpra_output::LogLines = serde_json::from_value(data)?;
+    for line in output.lines {
+        println!("{}", xpra_output::display_line(&line));
+    }
     Ok(())
 }
 
+/// Run a headless session request, returning the process exit code.
+#[cfg(feature = "desktop")]
+fn headless(context: &XpraContext, action: &HeadlessAction) -> Result<ExitCode> {
+    let request = match action {
+        HeadlessAction::Launch { program, idle_timeout, labels } => AdminRequest::Launch {
+            user: whoami::username(),
+            program: program.clone(),
+            idle_timeout_secs: *idle_timeout,
+            labels: xpra_labels::to_labels(labels),
+        },
+        HeadlessAction::Exec { session_id, shell_command, detach } => AdminRequest::Exec {
+            session_id: session_id.clone(),
+            shell_command: shell_command.clone(),
+            detach: *detach,
+        },
+        HeadlessAction::Screenshot { session_id, name } => AdminRequest::Screenshot {
+            session_id: session_id.clone(),
+            name: name.clone(),
+        },
+        HeadlessAction::Stop { session_id } => AdminRequest::Stop {
+            session_id: session_id.clone(),
+        },
+    };
+    let reply = admin(context, request)?;
+
+    match action {
+        HeadlessAction::Launch { .. } => {
+            let session: xpra_headless::HeadlessSession = serde_json::from_value(reply)?;
+            println!("{}", session.session_id);
+        }
+        HeadlessAction::Exec { .. } => {
+            let output: xpra_headless::ExecOutput = serde_json::from_value(reply)?;
+            print!("{}", output.stdout);
+            eprint!("{}", output.stderr);
+            let code = output.exit_code.unwrap_or(1);
+            return Ok(ExitCode::from(u8::try_from(code).unwrap_or(1)));
+        }
+        HeadlessAction::Screenshot { .. } => {
+            let screenshot: xpra_headless::SavedScreenshot = serde_json::from_value(reply)?;
+            println!("{}", screenshot.path.display());
+        }
+        HeadlessAction::Stop { .. } => {}
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
 fn main() -> ExitCode {
+    // Before any thread exists, as it clears the socket activation variables
+    #[cfg(feature = "desktop")]
+    xpra_systemd::init();
     let args = Args::parse();
+    #[cfg(feature = "desktop")]
+    let context = XpraContext::new(XpraConfig::default());
+    #[cfg(feature = "desktop")]
+    apply_display_options(&args, &context);
 
     match &args.command {
         Command::Start(start_args) => {
@@ -165,7 +842,11 @@ fn main() -> ExitCode {
                 .with_writer(std::io::stderr)
                 .init();
 
-            match start(start_args) {
+            #[cfg(feature = "desktop")]
+            let result = start(start_args, &context);
+            #[cfg(not(feature = "desktop"))]
+            let result = start(start_args);
+            match result {
                 Ok(()) => ExitCode::SUCCESS,
                 Err(err) => {
                     error!("{err:?}");
@@ -173,44 +854,294 @@ fn main() -> ExitCode {
                 }
             }
         }
-        Command::Status { format, active_only } => {
-            match xpra_status::get_status().await {
-                Ok(status) => {
-                    if let Err(e) = status_display::display_status(&status, format, *active_only) {
-                        error!("Failed to display status: {}", e);
+        #[cfg(feature = "desktop")]
+        Command::Desktop(command) => run_desktop_command(&context, command),
+    }
+}
+
+/// Apply the color and time options of the desktop commands.
+#[cfg(feature = "desktop")]
+fn apply_display_options(args: &Args, context: &XpraContext) {
+    args.color.apply();
+    let time_display = context.config.time_display;
+    TimeDisplay {
+        zone: args.time_zone.unwrap_or(time_display.zone),
+        notation: args.time_format.unwrap_or(time_display.notation),
+    }
+    .apply();
+}
+
+/// Run a desktop command, returning the process exit code.
+#[cfg(feature = "desktop")]
+fn run_desktop_command(context: &XpraContext, command: &DesktopCommand) -> ExitCode {
+    match command {
+        DesktopCommand::Status { summary: true, format, .. } => {
+            if let Err(e) = session_summary(context).and_then(|summary| status_display::display_summary(&summary, format)) {
+                error!("Failed to display status: {}", e);
+                ExitCode::FAILURE
+            } else {
+                ExitCode::SUCCESS
+            }
+        }
+        DesktopCommand::Status { format, active_only, filters, user, min_idle, sort, columns, watch, wide, limit, after, .. } => {
+            let query = SessionQuery {
+                user: user.clone(),
+                labels: xpra_labels::to_labels(filters),
+                after: after.clone(),
+                limit: Some(*limit),
+                ..SessionQuery::default()
+            };
+            let options = status_display::StatusOptions {
+                active_only: *active_only,
+                user: user.clone(),
+                min_idle: *min_idle,
+                labels: filters.clone(),
+                sort: *sort,
+                columns: columns.clone(),
+                wide: *wide,
+            };
+            let result = match watch {
+                Some(interval) => {
+                    let interval = Duration::from_secs((*interval).max(1));
+                    status_display::watch_status(|| status(context, &query), format, &options, interval)
+                }
+                None => status(context, &query).and_then(|status| status_display::display_status(status, format, &options)),
+            };
+            if let Err(e) = result {
+                error!("Failed to display status: {}", e);
+                ExitCode::FAILURE
+            } else {
+                ExitCode::SUCCESS
+            }
+        }
+        DesktopCommand::Analyze { days, since, until, last_month, format, filters, jobs, xpra_logs, chart_width, archived } => {
+            let now = Utc::now();
+            let range = match (since, last_month) {
+                (_, true) => Ok(TimeRange::last_month(now)),
+                (Some(since), false) => TimeRange::between(*since, *until, now),
+                (None, false) => match days.unwrap_or(7).checked_mul(86400) {
+                    Some(secs) => TimeRange::between(TimeSpec::Ago(secs), None, now),
+                    None => Err(anyhow::anyhow!("--days {} is too large", days.unwrap_or(7))),
+                },
+            };
+            let TimeRange { start, end } = match range {
+                Ok(range) => range,
+                Err(e) => {
+                    error!("{}", e);
+                    return ExitCode::FAILURE;
+                }
+            };
+            
+            let mut analyzer = xpra_log_analyzer::LogAnalyzer::new(
+                context.config.instance_log_dir()
+            ).with_label_filter(filters.clone());
+            if let Some(jobs) = jobs {
+                analyzer = analyzer.with_jobs(*jobs);
+            }
+            if let Some(dir) = xpra_logs {
+                analyzer = analyzer.with_xpra_log_dir(dir.clone());
+            }
+            if *archived {
+                let Some(target) = &context.config.archive else {
+                    error!("No log archive is configured");
+                    return ExitCode::FAILURE;
+                };
+                analyzer = analyzer.with_archive(target.clone());
+            }
+            
+            match analyze(&analyzer, start, end) {
+                Ok(analysis) => {
+                    let chart = ChartStyle { width: *chart_width, ..ChartStyle::default() };
+                    if let Err(e) = xpra_visualizer::display_analysis(&analysis, format, &chart) {
+                        error!("Failed to display analysis: {}", e);
                         ExitCode::FAILURE
                     } else {
                         ExitCode::SUCCESS
                     }
                 }
                 Err(e) => {
-                    error!("Failed to get status: {}", e);
+                    error!("Failed to analyze logs: {}", e);
                     ExitCode::FAILURE
                 }
             }
         }
-        Command::Analyze { days, format } => {
-            let end = Utc::now();
-            let start = end - chrono::Duration::days(*days);
-            
-            let analyzer = xpra_log_analyzer::LogAnalyzer::new(
-                PathBuf::from("/var/log/sshx/xpra")
+        DesktopCommand::Drain { deadline, message, cancel } => {
+            let request = if *cancel {
+                AdminRequest::Undrain
+            } else {
+                AdminRequest::Drain {
+                    deadline_secs: *deadline,
+                    message: message.clone(),
+                }
+            };
+            match admin(context, request) {
+                Ok(status) => {
+                    println!("{}", serde_json::to_string_pretty(&status).unwrap_or_default());
+                    ExitCode::SUCCESS
+                }
+                Err(e) => {
+                    error!("Failed to change drain state: {}", e);
+                    ExitCode::FAILURE
+                }
+            }
+        }
+        DesktopCommand::Health { local, format } => match health(context, *local) {
+            Ok(report) => {
+                if let Err(e) = status_display::display_health(&report, format) {
+                    error!("Failed to display health: {}", e);
+                    ExitCode::FAILURE
+                } else if report.ready {
+                    ExitCode::SUCCESS
+                } else {
+                    ExitCode::FAILURE
+                }
+            }
+            Err(e) => {
+                error!("Failed to run health check: {}", e);
+                ExitCode::FAILURE
+            }
+        },
+        DesktopCommand::Doctor { format } => {
+            let report = doctor(context);
+            if let Err(e) = status_display::display_doctor(&report, format) {
+                error!("Failed to display doctor report: {}", e);
+                ExitCode::FAILURE
+            } else if report.ready {
+                ExitCode::SUCCESS
+            } else {
+                ExitCode::FAILURE
+            }
+        }
+        DesktopCommand::Sandbox { profile, read, write, hide, keep, command } => {
+            tracing_subscriber::fmt()
+                .with_env_filter(std::env::var("RUST_LOG").unwrap_or("warn".into()))
+                .with_writer(std::io::stderr)
+                .init();
+            // Only returns if the command could not be started
+            let hidden = xpra_sandbox::HiddenPaths { hide: hide.clone(), keep: keep.clone() };
+            let e = xpra_sandbox::exec(*profile, read, write, &hidden, command);
+            error!("{e:#}");
+            ExitCode::FAILURE
+        }
+        DesktopCommand::Billing { month, format, output } => {
+            let period = match month {
+                Some(month) => match xpra_billing::BillingPeriod::parse(month) {
+                    Ok(period) => period,
+                    Err(e) => {
+                        error!("{}", e);
+                        return ExitCode::FAILURE;
+                    }
+                },
+                None => xpra_billing::BillingPeriod::previous(Utc::now()),
+            };
+
+            let exporter = xpra_billing::UsageExporter::new(
+                context.config.instance_log_dir()
             );
-            
-            match analyzer.analyze_period(start, end).await {
-                Ok(analysis) => {
-                    if let Err(e) = xpra_visualizer::display_analysis(&analysis, format) {
-                        error!("Failed to display analysis: {}", e);
+
+            let report = exporter
+                .export_period(period)
+                .and_then(|report| report.render(format));
+            match report {
+                Ok(report) => {
+                    let written = match output {
+                        Some(path) => std::fs::write(path, report),
+                        None => {
+                            print!("{}", report);
+                            Ok(())
+                        }
+                    };
+                    if let Err(e) = written {
+                        error!("Failed to write billing report: {}", e);
                         ExitCode::FAILURE
                     } else {
                         ExitCode::SUCCESS
                     }
                 }
                 Err(e) => {
-                    error!("Failed to analyze logs: {}", e);
+                    error!("Failed to export billing report: {}", e);
                     ExitCode::FAILURE
                 }
             }
         }
+        DesktopCommand::Headless { action } => match headless(context, action) {
+            Ok(code) => code,
+            Err(e) => {
+                error!("Headless session request failed: {}", e);
+                ExitCode::FAILURE
+            }
+        },
+        DesktopCommand::Inspect { session_id } => {
+            let request = AdminRequest::Inspect { session_id: session_id.clone() };
+            match admin(context, request) {
+                Ok(inspection) => {
+                    println!("{}", serde_json::to_string_pretty(&inspection).unwrap_or_default());
+                    ExitCode::SUCCESS
+                }
+                Err(e) => {
+                    error!("Failed to inspect session: {}", e);
+                    ExitCode::FAILURE
+                }
+            }
+        }
+        DesktopCommand::Kill { session, shell } => {
+            let request = AdminRequest::Kill { session: session.clone(), shell: shell.map(Sid) };
+            match admin(context, request).and_then(|ids| Ok(serde_json::from_value::<Vec<String>>(ids)?)) {
+                Ok(ids) if ids.is_empty() => {
+                    warn!("No desktops were started from {}", session);
+                    ExitCode::FAILURE
+                }
+                Ok(ids) => {
+                    for id in ids {
+                        println!("{}", id);
+                    }
+                    ExitCode::SUCCESS
+                }
+                Err(e) => {
+                    error!("Failed to kill sessions: {}", e);
+                    ExitCode::FAILURE
+                }
+            }
+        }
+        DesktopCommand::Logs { session_id, follow, lines, since } => {
+            let request = AdminRequest::Logs {
+                session_id: session_id.clone(),
+                lines: *lines,
+                since: *since,
+                follow: *follow,
+            };
+            let result = if *follow {
+                logs(context, request)
+            } else {
+                admin(context, request).and_then(print_log_lines)
+            };
+            if let Err(e) = result {
+                error!("Failed to read session logs: {}", e);
+                ExitCode::FAILURE
+            } else {
+                ExitCode::SUCCESS
+            }
+        }
+        DesktopCommand::History { key, since, local, format } => match history(context, key, *since, *local) {
+            Ok(events) => {
+                if let Err(e) = status_display::display_history(&events, format) {
+                    error!("Failed to display history: {}", e);
+                    ExitCode::FAILURE
+                } else {
+                    ExitCode::SUCCESS
+                }
+            }
+            Err(e) => {
+                error!("Failed to read session history: {}", e);
+                ExitCode::FAILURE
+            }
+        },
+        DesktopCommand::Audit { action } => match audit(context, action) {
+            Ok(code) => code,
+            Err(e) => {
+                error!("Failed to audit the history log: {}", e);
+                ExitCode::FAILURE
+            }
+        },
     }
 }
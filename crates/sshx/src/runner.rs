@@ -1,8 +1,9 @@
 //! Defines tasks that control the behavior of a single shell in the client.
 
 use anyhow::Result;
+use bytes::Bytes;
 use encoding_rs::{CoderResult, UTF_8};
-use sshx_core::proto::{client_update::ClientMessage, TerminalData};
+use sshx_core::proto::{client_update::ClientMessage, DesktopSession, SessionParams, TerminalData};
 use sshx_core::Sid;
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
@@ -11,6 +12,8 @@ use tokio::{
 
 use crate::encrypt::Encrypt;
 use crate::terminal::Terminal;
+#[cfg(feature = "desktop")]
+use crate::xpra_session_id::ParentShell;
 
 const CONTENT_CHUNK_SIZE: usize = 1 << 16; // Send at most this many bytes at a time.
 const CONTENT_ROLLING_BYTES: usize = 8 << 20; // Store at least this much content.
@@ -23,9 +26,16 @@ pub enum Runner {
     Shell(String),
 
     /// Spawns an xpra display server for X11 forwarding.
+    #[cfg(feature = "desktop")]
     Xpra {
+        /// Number of the display to start.
         display: u16,
+        /// Window manager or program to start on it.
         wm: String,
+        /// Labels recorded with the session
+        labels: crate::xpra_labels::Labels,
+        /// Desktop host instance the session runs in
+        context: std::sync::Arc<crate::xpra_context::XpraContext>,
     },
 
     /// Mock runner that only echos its input, useful for testing.
@@ -35,7 +45,10 @@ pub enum Runner {
 /// Internal message routed to shell runners.
 pub enum ShellData {
     /// Sequence of input bytes from the server.
-    Data(Vec<u8>),
+    Data(Bytes),
+    /// Input bytes still encrypted, at their offset in the input stream,
+    /// for desktops that rotate the keys of their input.
+    Encrypted(u64, Bytes),
     /// Information about the server's current sequence number.
     Sync(u64),
     /// Resize the shell to a different number of rows and columns.
@@ -43,26 +56,91 @@ pub enum ShellData {
 }
 
 impl Runner {
-    /// Asynchronous task to run a single shell with process I/O.
+    /// Asynchronous task to run a single shell with process I/O, or to show
+    /// the running desktop session `desktop` in it. New desktops start with
+    /// the parameters the client asked for, linked to the shell `id` of the
+    /// sshx session named `session`.
+    #[allow(clippy::too_many_arguments)]
+    #[cfg_attr(not(feature = "desktop"), allow(unused_variables))]
     pub async fn run(
         &self,
         id: Sid,
+        session: &str,
         encrypt: Encrypt,
+        desktop: Option<String>,
+        params: SessionParams,
         shell_rx: mpsc::Receiver<ShellData>,
         output_tx: mpsc::Sender<ClientMessage>,
     ) -> Result<()> {
-        match self {
-            Self::Shell(shell) => shell_task(id, encrypt, shell, shell_rx, output_tx).await,
-            Self::Xpra { display, wm } => {
-                crate::xpra_runner::start_xpra_session(
+        match (self, desktop) {
+            #[cfg(feature = "desktop")]
+            (Self::Xpra { context, .. }, Some(desktop)) => {
+                crate::xpra_runner::attach_xpra_session(
+                    context.clone(),
                     id,
+                    whoami::username(),
+                    desktop,
                     encrypt,
                     shell_rx,
                     output_tx,
                 ).await
             },
-            Self::Echo => echo_task(id, encrypt, shell_rx, output_tx).await,
+            #[cfg(feature = "desktop")]
+            (Self::Xpra { labels, context, .. }, None) => {
+                crate::xpra_runner::start_xpra_session(
+                    context.clone(),
+                    ParentShell { session: session.to_string(), shell: id },
+                    whoami::username(),
+                    labels.clone(),
+                    params,
+                    encrypt,
+                    shell_rx,
+                    output_tx,
+                ).await
+            },
+            (_, Some(_)) => anyhow::bail!("this session runs no desktops to attach to"),
+            (Self::Shell(shell), None) => shell_task(id, encrypt, shell, shell_rx, output_tx).await,
+            (Self::Echo, None) => echo_task(id, encrypt, shell_rx, output_tx).await,
+        }
+    }
+
+    /// Whether shells show desktops, over the desktop channel.
+    pub fn is_desktop(&self) -> bool {
+        #[cfg(feature = "desktop")]
+        if let Self::Xpra { .. } = self {
+            return true;
+        }
+        false
+    }
+
+    /// Desktop sessions of the user that shells can attach to.
+    pub async fn desktops(&self) -> Vec<DesktopSession> {
+        #[cfg(feature = "desktop")]
+        if let Self::Xpra { context, .. } = self {
+            return crate::xpra_runner::list_desktops(context, &whoami::username()).await;
+        }
+        Vec::new()
+    }
+
+    /// Name one of the user's desktop sessions.
+    #[cfg_attr(not(feature = "desktop"), allow(unused_variables))]
+    pub async fn rename_desktop(
+        &self,
+        desktop: &str,
+        name: &str,
+        description: Option<&str>,
+    ) -> Result<()> {
+        #[cfg(feature = "desktop")]
+        if let Self::Xpra { context, .. } = self {
+            return crate::xpra_runner::rename_desktop(
+                context,
+                &whoami::username(),
+                desktop,
+                name,
+                description,
+            ).await;
         }
+        anyhow::bail!("this session runs no desktops to rename")
     }
 }
 
@@ -113,6 +191,7 @@ async fn shell_task(
                     Some(ShellData::Size(rows, cols)) => {
                         term.set_winsize(rows as u16, cols as u16)?;
                     }
+                    Some(ShellData::Encrypted(..)) => (), // Only sent to desktops
                     None => finished = true, // Server closed this shell.
                 }
             }
@@ -131,7 +210,7 @@ async fn shell_task(
             let data = encrypt.segment(
                 0x100000000 | id.0 as u64, // stream number
                 (content_offset + start) as u64,
-                content[start..end].as_bytes(),
+                &content.as_bytes()[start..end],
             );
             let data = TerminalData {
                 id: id.0,
@@ -184,6 +263,7 @@ async fn echo_task(
             }
             ShellData::Sync(_) => (),
             ShellData::Size(_, _) => (),
+            ShellData::Encrypted(..) => (), // Only sent to desktops
         }
     }
     Ok(())
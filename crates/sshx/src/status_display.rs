@@ -1,44 +1,531 @@
-use std::io::Write;
+//! Terminal and JSON output of the `status` subcommand.
+
+use std::collections::{BTreeMap, HashSet};
+use std::io::{IsTerminal, Write};
+use std::os::fd::AsRawFd;
+use std::str::FromStr;
+use std::time::Duration;
 use anyhow::Result;
+use chrono::Utc;
 use colored::*;
+use tabled::builder::Builder;
 use tabled::{Table, Tabled};
+use crate::xpra_audit::VerifyReport;
+use crate::xpra_chart::Charset;
+use crate::xpra_health::HealthReport;
+use crate::xpra_labels::{self as labels, Label};
+use crate::xpra_logger::SessionEvent;
+use crate::xpra_screenshots::{ScreenshotArchive, ScreenshotEntry};
 use crate::xpra_status::{XpraStatus, SessionStatus};
+use crate::xpra_summary::{GroupSummary, SessionSummary};
+use crate::xpra_time_format::{format_duration, format_time};
+
+#[derive(Tabled)]
+struct HostRow {
+    #[tabled(rename = "Host")]
+    name: String,
+    #[tabled(rename = "Address")]
+    address: String,
+    #[tabled(rename = "Health")]
+    health: String,
+    #[tabled(rename = "Sessions")]
+    sessions: String,
+}
+
+#[derive(Tabled)]
+struct GpuRow {
+    #[tabled(rename = "Host")]
+    host: String,
+    #[tabled(rename = "Device")]
+    device: String,
+    #[tabled(rename = "Sessions")]
+    sessions: String,
+}
 
 #[derive(Tabled)]
-struct SessionRow {
-    #[tabled(rename = "ID")]
+struct EventRow {
+    #[tabled(rename = "Time")]
+    time: String,
+    #[tabled(rename = "Event")]
+    event: String,
+    #[tabled(rename = "Session")]
     session_id: String,
     #[tabled(rename = "User")]
     user: String,
     #[tabled(rename = "Display")]
     display: String,
-    #[tabled(rename = "Port")]
-    port: String,
-    #[tabled(rename = "Idle")]
-    idle: String,
+    #[tabled(rename = "Details")]
+    details: String,
 }
 
-pub fn display_status(status: &XpraStatus, format: &str, active_only: bool) -> Result<()> {
-    match format {
-        "json" => display_json(status)?,
-        "text" => display_text(status, active_only)?,
-        _ => anyhow::bail!("Unsupported format: {}", format),
+#[derive(Tabled)]
+struct ScreenshotRow {
+    #[tabled(rename = "Time")]
+    time: String,
+    #[tabled(rename = "Session")]
+    session_id: String,
+    #[tabled(rename = "User")]
+    user: String,
+    #[tabled(rename = "Display")]
+    display: String,
+    #[tabled(rename = "Screenshot")]
+    path: String,
+}
+
+#[derive(Tabled)]
+struct UsageRow {
+    #[tabled(rename = "User")]
+    user: String,
+    #[tabled(rename = "Today")]
+    daily: String,
+    #[tabled(rename = "This Week")]
+    weekly: String,
+}
+
+/// When text output is colored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColorMode {
+    /// When stdout is a terminal and `NO_COLOR` is not set
+    #[default]
+    Auto,
+    /// Always, even when piped
+    Always,
+    /// Never
+    Never,
+}
+
+impl ColorMode {
+    /// Whether output is colored, given the value of `NO_COLOR` and whether
+    /// stdout is a terminal.
+    fn enabled(self, no_color: Option<&str>, terminal: bool) -> bool {
+        match self {
+            ColorMode::Always => true,
+            ColorMode::Never => false,
+            ColorMode::Auto => terminal && no_color.is_none_or(str::is_empty),
+        }
+    }
+
+    /// Color the text output of every report for the rest of the process, or
+    /// not, according to this mode.
+    pub fn apply(self) {
+        let no_color = std::env::var("NO_COLOR").ok();
+        let terminal = std::io::stdout().is_terminal();
+        colored::control::set_override(self.enabled(no_color.as_deref(), terminal));
     }
-    Ok(())
 }
 
-fn display_json(status: &XpraStatus) -> Result<()> {
-    println!("{}", serde_json::to_string_pretty(status)?);
-    Ok(())
+impl FromStr for ColorMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "auto" => Ok(Self::Auto),
+            "always" => Ok(Self::Always),
+            "never" => Ok(Self::Never),
+            _ => anyhow::bail!("unknown color mode {s:?}, expected auto, always or never"),
+        }
+    }
+}
+
+/// Order of the sessions table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortKey {
+    /// Longest idle first
+    Idle,
+    /// By user name
+    User,
+    /// By display number
+    Display,
+}
+
+impl FromStr for SortKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "idle" => Ok(Self::Idle),
+            "user" => Ok(Self::User),
+            "display" => Ok(Self::Display),
+            _ => anyhow::bail!("unknown sort key {s:?}, expected idle, user or display"),
+        }
+    }
+}
+
+/// Column of the sessions table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Column {
+    /// Session id
+    Id,
+    /// Name the user gave the session
+    Name,
+    /// User owning the session
+    User,
+    /// Host running the display
+    Host,
+    /// Display number
+    Display,
+    /// State of the session
+    State,
+    /// Assigned GPU
+    Gpu,
+    /// CPUs the session is pinned to
+    Cpus,
+    /// WebSocket port
+    Port,
+    /// Idle time
+    Idle,
+    /// Labels of the session
+    Labels,
 }
 
-fn display_text(status: &XpraStatus, active_only: bool) -> Result<()> {
+impl Column {
+    /// Columns hidden on narrow terminals, least important first
+    const HIDE_ORDER: [Column; 8] = [
+        Column::Port,
+        Column::Cpus,
+        Column::Gpu,
+        Column::Labels,
+        Column::Name,
+        Column::Host,
+        Column::Display,
+        Column::State,
+    ];
+
+    /// Every column, in the order they are shown
+    pub const ALL: [Column; 11] = [
+        Column::Id,
+        Column::Name,
+        Column::User,
+        Column::Host,
+        Column::Display,
+        Column::State,
+        Column::Gpu,
+        Column::Cpus,
+        Column::Port,
+        Column::Idle,
+        Column::Labels,
+    ];
+
+    fn header(self) -> &'static str {
+        match self {
+            Column::Id => "ID",
+            Column::Name => "Name",
+            Column::User => "User",
+            Column::Host => "Host",
+            Column::Display => "Display",
+            Column::State => "State",
+            Column::Gpu => "GPU",
+            Column::Cpus => "CPUs",
+            Column::Port => "Port",
+            Column::Idle => "Idle",
+            Column::Labels => "Labels",
+        }
+    }
+
+    /// Narrowest the column is shortened to on narrow terminals, for
+    /// columns that may be shortened.
+    fn min_width(self) -> Option<usize> {
+        match self {
+            Column::Id => Some(12),
+            Column::Name => Some(12),
+            Column::Labels => Some(16),
+            _ => None,
+        }
+    }
+
+    fn value(self, s: &SessionStatus) -> String {
+        match self {
+            Column::Id => s.session_id.clone(),
+            Column::Name => s.name.clone().unwrap_or_else(|| "-".to_string()),
+            Column::User => s.user.clone(),
+            Column::Host => s.host.clone(),
+            Column::Display => format!(":{}", s.display),
+            Column::State if !s.health.is_healthy() => format!("{} ({})", s.state, s.health),
+            Column::State if s.recording => format!("{} (recording)", s.state),
+            Column::State => s.state.to_string(),
+            Column::Gpu => s.gpu.clone().unwrap_or_else(|| "-".to_string()),
+            Column::Cpus => s.cpus.clone().unwrap_or_else(|| "-".to_string()),
+            Column::Port => s.websocket_port.to_string(),
+            Column::Idle => {
+                if s.hibernated {
+                    format!("{} (hibernated)", format_duration(s.idle_time))
+                } else if s.headless {
+                    format!("{} (headless)", format_duration(s.idle_time))
+                } else {
+                    format_duration(s.idle_time)
+                }
+            }
+            Column::Labels => {
+                if s.labels.is_empty() {
+                    "-".to_string()
+                } else {
+                    labels::format_labels(&s.labels)
+                }
+            }
+        }
+    }
+}
+
+impl FromStr for Column {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Column::ALL
+            .into_iter()
+            .find(|c| c.header().eq_ignore_ascii_case(s))
+            .ok_or_else(|| anyhow::anyhow!("unknown column {s:?}, expected one of id, name, user, host, display, state, gpu, cpus, port, idle, labels"))
+    }
+}
+
+/// Which sessions `display_status` shows, and how.
+#[derive(Debug, Default)]
+pub struct StatusOptions {
+    /// Hide sessions idle past the idle timeout from the table; JSON lists
+    /// them all
+    pub active_only: bool,
+    /// Only show sessions of this user
+    pub user: Option<String>,
+    /// Only show sessions idle for at least this many seconds
+    pub min_idle: Option<u64>,
+    /// Only show sessions carrying all of these labels
+    pub labels: Vec<Label>,
+    /// Order of the sessions, as listed when `None`
+    pub sort: Option<SortKey>,
+    /// Columns of the sessions table, all when empty; ignored for JSON
+    pub columns: Vec<Column>,
+    /// Show the sessions table in full, however wide the terminal
+    pub wide: bool,
+}
+
+impl StatusOptions {
+    fn matches(&self, session: &SessionStatus, idle_timeout: Option<u64>) -> bool {
+        idle_timeout.is_none_or(|timeout| session.idle_time < timeout)
+            && self.user.as_ref().is_none_or(|user| &session.user == user)
+            && self.min_idle.is_none_or(|min| session.idle_time >= min)
+            && labels::matches(&session.labels, &self.labels)
+    }
+
+    fn sort(&self, sessions: &mut [SessionStatus]) {
+        match self.sort {
+            Some(SortKey::Idle) => sessions.sort_by_key(|s| std::cmp::Reverse(s.idle_time)),
+            Some(SortKey::User) => sessions.sort_by(|a, b| {
+                a.user.cmp(&b.user).then(b.idle_time.cmp(&a.idle_time))
+            }),
+            Some(SortKey::Display) => sessions.sort_by(|a, b| {
+                a.host.cmp(&b.host).then(a.display.cmp(&b.display))
+            }),
+            None => {}
+        }
+    }
+
+    fn columns(&self) -> &[Column] {
+        if self.columns.is_empty() {
+            &Column::ALL
+        } else {
+            &self.columns
+        }
+    }
+}
+
+/// Width of the terminal on stdout in columns, or of `COLUMNS` when stdout
+/// is not a terminal but that is set; `None` when output is piped.
+#[allow(unsafe_code)]
+pub fn terminal_width() -> Option<usize> {
+    nix::ioctl_read_bad!(ioctl_get_winsize, nix::libc::TIOCGWINSZ, nix::pty::Winsize);
     let stdout = std::io::stdout();
-    let mut out = stdout.lock();
+    if stdout.is_terminal() {
+        let mut winsize = nix::pty::Winsize { ws_row: 0, ws_col: 0, ws_xpixel: 0, ws_ypixel: 0 };
+        // Safety: stdout is a terminal and winsize is a valid Winsize.
+        if unsafe { ioctl_get_winsize(stdout.as_raw_fd(), &mut winsize) }.is_ok() && winsize.ws_col > 0 {
+            return Some(winsize.ws_col as usize);
+        }
+    }
+    std::env::var("COLUMNS").ok()?.parse().ok().filter(|&width| width > 0)
+}
+
+/// Width of a table with columns of `widths`, with tabled's default borders
+/// and padding.
+fn table_width(widths: &[usize]) -> usize {
+    widths.iter().sum::<usize>() + 3 * widths.len() + 1
+}
+
+/// Which of `columns` to show and how wide, for a table of `rows` of cells
+/// to fit in `width`: long session ids and labels are shortened first, then,
+/// if `hide` is set, the least important columns are hidden. The table may
+/// still be wider than `width` when nothing else can give.
+fn fit_columns(columns: &[Column], rows: &[Vec<String>], width: usize, hide: bool) -> Vec<(usize, usize)> {
+    let natural: Vec<usize> = columns
+        .iter()
+        .enumerate()
+        .map(|(i, c)| {
+            rows.iter()
+                .map(|row| row[i].chars().count())
+                .chain([c.header().len()])
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+    let mut shown: Vec<usize> = (0..columns.len()).collect();
+    loop {
+        let mut widths: Vec<usize> = shown.iter().map(|&i| natural[i]).collect();
+        let mut over = table_width(&widths).saturating_sub(width);
+        for (slot, &i) in shown.iter().enumerate() {
+            if let Some(min) = columns[i].min_width() {
+                let cut = over.min(widths[slot].saturating_sub(min));
+                widths[slot] -= cut;
+                over -= cut;
+            }
+        }
+        let hidden = Column::HIDE_ORDER
+            .iter()
+            .find_map(|c| shown.iter().position(|&i| columns[i] == *c));
+        match hidden {
+            Some(slot) if over > 0 && hide => {
+                shown.remove(slot);
+            }
+            _ => return shown.into_iter().zip(widths).collect(),
+        }
+    }
+}
+
+/// Shorten `value` to `width` characters by replacing its middle with an
+/// ellipsis, keeping both ends of ids that share a prefix.
+fn ellipsize(value: &str, width: usize, charset: Charset) -> String {
+    let chars: Vec<char> = value.chars().collect();
+    if chars.len() <= width {
+        return value.to_string();
+    }
+    let ellipsis = match charset {
+        Charset::Unicode => "…",
+        Charset::Ascii => "..",
+    };
+    let keep = width.saturating_sub(ellipsis.chars().count());
+    let head = keep.div_ceil(2);
+    let tail = keep - head;
+    let mut short: String = chars[..head].iter().collect();
+    short.push_str(ellipsis);
+    short.extend(&chars[chars.len() - tail..]);
+    short
+}
+
+/// Parse a duration like `90`, `30m`, `1h` or `2d` into seconds.
+pub fn parse_duration(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, "s"),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|_| anyhow::anyhow!("invalid duration {s:?}, expected e.g. 90s, 30m or 1h"))?;
+    let scale = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        _ => anyhow::bail!("invalid duration unit in {s:?}, expected s, m, h or d"),
+    };
+    number
+        .checked_mul(scale)
+        .ok_or_else(|| anyhow::anyhow!("duration {s:?} is too long"))
+}
+
+/// Print the status of the host as a table (`text`) or as JSON (`json`).
+pub fn display_status(status: XpraStatus, format: &str, options: &StatusOptions) -> Result<()> {
+    print!("{}", render_status(status, format, options, terminal_width())?);
+    Ok(())
+}
+
+/// Clear the terminal and print the status fetched by `fetch` every
+/// `interval`, like `display_status`, until interrupted or fetching fails.
+/// Lines that changed since the previous refresh are marked in the left
+/// margin.
+pub fn watch_status(
+    mut fetch: impl FnMut() -> Result<XpraStatus>,
+    format: &str,
+    options: &StatusOptions,
+    interval: Duration,
+) -> Result<()> {
+    let mut previous: Option<String> = None;
+    loop {
+        // Leave room for the margin marking changes
+        let width = terminal_width().map(|width| width.saturating_sub(2));
+        let current = render_status(fetch()?, format, options, width)?;
+        let stdout = std::io::stdout();
+        let mut out = stdout.lock();
+        // Clear the screen and move to its top left corner
+        write!(out, "\x1b[2J\x1b[H")?;
+        let header = format!(
+            "Every {}s: ssh-desktop status    {}",
+            interval.as_secs(),
+            format_time(Utc::now()),
+        );
+        writeln!(out, "{}", header.dimmed())?;
+        write!(out, "{}", mark_changes(previous.as_deref(), &current))?;
+        out.flush()?;
+        drop(out);
+
+        previous = Some(current);
+        std::thread::sleep(interval);
+    }
+}
+
+/// Indent the lines of `current`, marking those not in `previous`.
+fn mark_changes(previous: Option<&str>, current: &str) -> String {
+    let seen: HashSet<&str> = previous.map(|p| p.lines().collect()).unwrap_or_default();
+    let mut marked = String::with_capacity(current.len() + 2 * current.lines().count());
+    for line in current.lines() {
+        if line.is_empty() {
+            marked.push('\n');
+        } else if previous.is_some() && !seen.contains(line) {
+            marked.push_str(&format!("{} {}\n", ">".yellow().bold(), line));
+        } else {
+            marked.push_str(&format!("  {}\n", line));
+        }
+    }
+    marked
+}
+
+fn render_status(mut status: XpraStatus, format: &str, options: &StatusOptions, width: Option<usize>) -> Result<String> {
+    let idle_timeout = (options.active_only && format != "json").then_some(status.config.idle_timeout);
+    status.sessions.retain(|s| options.matches(s, idle_timeout));
+    options.sort(&mut status.sessions);
+    let mut out = Vec::new();
+    match format {
+        "json" => writeln!(out, "{}", serde_json::to_string_pretty(&status)?)?,
+        "text" => {
+            let width = width.filter(|_| !options.wide);
+            display_text(&mut out, &status, options.columns(), width, options.columns.is_empty())?
+        }
+        _ => anyhow::bail!("Unsupported format: {}", format),
+    }
+    Ok(String::from_utf8(out)?)
+}
+
+/// Write the status as text, fitting the sessions table in `width` columns
+/// if given, hiding columns to fit if `hide` is set.
+fn display_text(
+    out: &mut impl Write,
+    status: &XpraStatus,
+    columns: &[Column],
+    width: Option<usize>,
+    hide: bool,
+) -> Result<()> {
+    if status.drain.draining {
+        writeln!(out, "\n{}", "Maintenance: draining, new sessions are blocked".yellow().bold())?;
+        if let Some(deadline) = status.drain.deadline {
+            writeln!(out, "  Sessions end at: {}", format_time(deadline))?;
+        }
+    }
+
+    if let Some(next) = status.next_maintenance {
+        writeln!(out, "\nNext maintenance window: {}", format_time(next))?;
+    }
 
     // Display configuration
     writeln!(out, "\n{}", "Configuration:".bold())?;
     writeln!(out, "  Window Manager: {}", status.config.window_manager)?;
+    writeln!(out, "  X Server: {}", status.config.x_backend)?;
     writeln!(out, "  Display Range: :{} - :{}", 
         status.config.min_display, status.config.max_display)?;
     writeln!(out, "  Base Port: {}", status.config.base_port)?;
@@ -50,46 +537,385 @@ fn display_text(status: &XpraStatus, active_only: bool) -> Result<()> {
             status.config.max_sessions.to_string() 
         }
     )?;
+    writeln!(out, "  Max Sessions/Host: {}",
+        if status.config.max_total_sessions == 0 {
+            "unlimited".to_string()
+        } else {
+            status.config.max_total_sessions.to_string()
+        }
+    )?;
+    writeln!(out, "  Queue Timeout: {}",
+        if status.config.queue_timeout == 0 {
+            "fail fast".to_string()
+        } else {
+            format!("{}s", status.config.queue_timeout)
+        }
+    )?;
 
     // Display metrics
     writeln!(out, "\n{}", "Metrics:".bold())?;
-    writeln!(out, "  Uptime: {}", status.metrics.uptime.cyan())?;
+    writeln!(out, "  Uptime: {} (since {})", status.metrics.uptime.cyan(), format_time(status.metrics.started_at))?;
     writeln!(out, "  Total Sessions: {}", status.metrics.total_sessions)?;
     writeln!(out, "  Active Sessions: {}", 
         status.metrics.active_sessions.to_string().green())?;
     writeln!(out, "  Failed Sessions: {}", 
         status.metrics.failed_sessions.to_string().red())?;
     writeln!(out, "  Idle Terminations: {}", status.metrics.idle_terminations)?;
+    writeln!(out, "  Rate Limited: {}",
+        status.metrics.rate_limited.to_string().yellow())?;
+    writeln!(out, "  Rejected (Host Full): {}",
+        status.metrics.rejected_full.to_string().yellow())?;
+    writeln!(out, "  Queued Sessions: {}", status.metrics.queued_sessions)?;
+    writeln!(out, "  Clipboard Blocked: {}",
+        status.metrics.clipboard_blocked.to_string().yellow())?;
+    let logging = &status.metrics.logging;
+    let logging_line = format!(
+        "  Logging: {} events, {} bytes written, {} write errors, {} dropped, {} rotations",
+        logging.events_written,
+        logging.bytes_written,
+        logging.write_errors,
+        logging.dropped_events,
+        logging.rotations,
+    );
+    match logging.is_failing() {
+        true => writeln!(out, "{}", logging_line.red())?,
+        false => writeln!(out, "{}", logging_line)?,
+    }
+    let consistency = &status.metrics.consistency;
+    if !consistency.is_consistent() {
+        writeln!(out, "  {}", format!(
+            "Inconsistent: {} active, {} running, {} invalid transitions",
+            consistency.active_sessions,
+            consistency.running_sessions,
+            consistency.invalid_transitions,
+        ).red())?;
+    }
 
     // Display sessions table
-    let sessions: Vec<SessionRow> = status.sessions.iter()
-        .filter(|s| !active_only || s.idle_time < status.config.idle_timeout)
-        .map(|s| SessionRow {
-            session_id: s.session_id.clone(),
-            user: s.user.clone(),
-            display: format!(":{}", s.display),
-            port: s.websocket_port.to_string(),
-            idle: format_idle_time(s.idle_time),
-        })
-        .collect();
-
-    if !sessions.is_empty() {
+    if !status.sessions.is_empty() {
         writeln!(out, "\n{}", "Active Sessions:".bold())?;
-        let table = Table::new(sessions).to_string();
-        writeln!(out, "{}", table)?;
+        let rows: Vec<Vec<String>> = status.sessions
+            .iter()
+            .map(|session| columns.iter().map(|c| c.value(session)).collect())
+            .collect();
+        let fitted = match width {
+            Some(width) => fit_columns(columns, &rows, width, hide),
+            None => columns.iter().enumerate().map(|(i, _)| (i, usize::MAX)).collect(),
+        };
+        let charset = Charset::detect();
+        let mut builder = Builder::default();
+        builder.push_record(fitted.iter().map(|&(i, _)| columns[i].header()));
+        for row in &rows {
+            builder.push_record(fitted.iter().map(|&(i, width)| ellipsize(&row[i], width, charset)));
+        }
+        writeln!(out, "{}", builder.build())?;
+        let hidden = columns.len() - fitted.len();
+        if hidden > 0 {
+            writeln!(out, "{}", format!("  {} columns hidden to fit the terminal, use --wide to show them", hidden).dimmed())?;
+        }
+        if let Some(next) = &status.next_session {
+            let shown = format!("  {} of {} sessions shown, use --after {} for the next page", status.sessions.len(), status.matched_sessions, next);
+            writeln!(out, "{}", shown.dimmed())?;
+        }
     } else {
         writeln!(out, "\n{}", "No active sessions".yellow())?;
     }
 
+    // Display worker hosts when sessions are spread over several
+    if status.hosts.len() > 1 {
+        let hosts: Vec<HostRow> = status.hosts.iter()
+            .map(|h| HostRow {
+                name: h.name.clone(),
+                address: h.address.clone(),
+                health: if h.healthy {
+                    "healthy".green().to_string()
+                } else {
+                    "unhealthy".red().to_string()
+                },
+                sessions: format!("{} / {}", h.active_sessions, h.capacity),
+            })
+            .collect();
+        writeln!(out, "\n{} ({:?} placement)", "Hosts:".bold(), status.config.placement_strategy)?;
+        writeln!(out, "{}", Table::new(hosts))?;
+    }
+
+    // Display GPU sharing when hosts have GPUs
+    let gpus: Vec<GpuRow> = status.hosts.iter()
+        .flat_map(|h| h.gpus.iter().map(move |g| GpuRow {
+            host: h.name.clone(),
+            device: g.device.clone(),
+            sessions: g.sessions.to_string(),
+        }))
+        .collect();
+    if !gpus.is_empty() {
+        writeln!(out, "\n{}", "GPUs:".bold())?;
+        writeln!(out, "{}", Table::new(gpus))?;
+    }
+
+    // Display desktop time usage against budgets
+    let usage: Vec<UsageRow> = status.usage.iter()
+        .map(|u| {
+            let budget = u.budget.clone().unwrap_or_default();
+            UsageRow {
+                user: u.user.clone(),
+                daily: format_usage(u.daily_hours, budget.daily_hours),
+                weekly: format_usage(u.weekly_hours, budget.weekly_hours),
+            }
+        })
+        .collect();
+
+    if !usage.is_empty() {
+        writeln!(out, "\n{}", "Desktop Usage:".bold())?;
+        writeln!(out, "{}", Table::new(usage))?;
+    }
+
     Ok(())
 }
 
-fn format_idle_time(secs: u64) -> String {
-    if secs < 60 {
-        format!("{}s", secs)
-    } else if secs < 3600 {
-        format!("{}m", secs / 60)
-    } else {
-        format!("{}h {}m", secs / 3600, (secs % 3600) / 60)
+/// Prints the readiness checks of the host as `format`, `text` or `json`.
+pub fn display_health(report: &HealthReport, format: &str) -> Result<()> {
+    match format {
+        "json" => println!("{}", serde_json::to_string_pretty(report)?),
+        "text" => {
+            print_checks(report);
+            if report.ready {
+                println!("\n{}", "Host is ready".green().bold());
+            } else {
+                println!("\n{}", "Host is not ready".red().bold());
+            }
+        }
+        _ => anyhow::bail!("Unsupported format: {}", format),
+    }
+    Ok(())
+}
+
+/// Prints the environment checks of the host as `format`, `text` or `json`.
+pub fn display_doctor(report: &HealthReport, format: &str) -> Result<()> {
+    match format {
+        "json" => println!("{}", serde_json::to_string_pretty(report)?),
+        "text" => {
+            println!("\n{}", "Environment:".bold());
+            print_checks(report);
+            let problems = report.checks.iter().filter(|c| !c.ok).count();
+            if problems == 0 {
+                println!("\n{}", "No problems found".green().bold());
+            } else {
+                println!("\n{}", format!("{} problem(s) found", problems).red().bold());
+            }
+        }
+        _ => anyhow::bail!("Unsupported format: {}", format),
+    }
+    Ok(())
+}
+
+/// Prints the session counts by user and team as `format`, `text` or
+/// `json`.
+pub fn display_summary(summary: &SessionSummary, format: &str) -> Result<()> {
+    match format {
+        "json" => println!("{}", serde_json::to_string_pretty(summary)?),
+        "text" => {
+            let total = &summary.total;
+            println!("{}", "Sessions:".bold());
+            println!("  Running: {}", total.sessions.to_string().green());
+            println!("  Idle Past Timeout: {}", total.idle_sessions);
+            println!("  Mean Idle: {}", format_duration(total.mean_idle_secs()));
+            println!("  Mean Running: {}", format_duration(total.mean_duration_secs()));
+            if !summary.by_user.is_empty() {
+                println!("\n{}", "Users:".bold());
+                println!("{}", group_table("User", &summary.by_user));
+            }
+            if !summary.by_team.is_empty() {
+                println!("\n{}", "Teams:".bold());
+                println!("{}", group_table("Team", &summary.by_team));
+            }
+        }
+        _ => anyhow::bail!("Unsupported format: {}", format),
+    }
+    Ok(())
+}
+
+/// Table of session groups, the first column headed `name`.
+fn group_table(name: &str, groups: &BTreeMap<String, GroupSummary>) -> Table {
+    let mut builder = Builder::default();
+    builder.push_record([name, "Sessions", "Idle", "Mean Idle", "Max Idle", "Mean Running"]);
+    for (group, summary) in groups {
+        builder.push_record([
+            if group.is_empty() { "-".to_string() } else { group.clone() },
+            summary.sessions.to_string(),
+            summary.idle_sessions.to_string(),
+            format_duration(summary.mean_idle_secs()),
+            format_duration(summary.max_idle_secs),
+            format_duration(summary.mean_duration_secs()),
+        ]);
+    }
+    builder.build()
+}
+
+/// Prints history events, oldest first, as `format`, `text` or `json`.
+pub fn display_history(events: &[SessionEvent], format: &str) -> Result<()> {
+    match format {
+        "json" => println!("{}", serde_json::to_string_pretty(events)?),
+        "text" if events.is_empty() => println!("No events found"),
+        "text" => {
+            let rows: Vec<EventRow> = events
+                .iter()
+                .map(|event| {
+                    let mut details = Vec::new();
+                    if let Some(state) = event.state {
+                        details.push(format!("state {}", state));
+                    }
+                    if let Some(reason) = event.reason {
+                        details.push(format!("reason {}", reason));
+                    }
+                    if let Some(bundle) = &event.crash_bundle {
+                        details.push(format!("crash bundle {}", bundle.display()));
+                    }
+                    EventRow {
+                        time: format_time(event.timestamp),
+                        event: format!("{:?}", event.event_type),
+                        session_id: event.session_id.clone(),
+                        user: event.user.clone(),
+                        display: match event.display {
+                            0 => "-".to_string(),
+                            display => format!(":{}", display),
+                        },
+                        details: details.join(", "),
+                    }
+                })
+                .collect();
+            println!("{}", Table::new(rows));
+        }
+        _ => anyhow::bail!("Unsupported format: {}", format),
+    }
+    Ok(())
+}
+
+/// Prints the result of verifying the history log as `format`, `text` or
+/// `json`.
+pub fn display_audit(report: &VerifyReport, format: &str) -> Result<()> {
+    match format {
+        "json" => println!("{}", serde_json::to_string_pretty(report)?),
+        "text" => {
+            println!(
+                "Checked {} events in {} files: {} hashed, {} numbered, {} redacted",
+                report.events, report.files, report.hashed, report.numbered, report.redacted,
+            );
+            for issue in &report.issues {
+                println!("  {}:{}: {}", issue.file.display(), issue.line, issue.message);
+            }
+            if report.is_intact() {
+                println!("{}", "History log is intact".green().bold());
+            } else {
+                println!("{}", format!("{} problem(s) found", report.issues.len()).red().bold());
+            }
+        }
+        _ => anyhow::bail!("Unsupported format: {}", format),
+    }
+    Ok(())
+}
+
+/// Prints the screenshots of `archive` as `format`, `text` or `json`.
+pub fn display_screenshots(archive: &ScreenshotArchive, entries: &[ScreenshotEntry], format: &str) -> Result<()> {
+    match format {
+        "json" => println!("{}", serde_json::to_string_pretty(entries)?),
+        "text" if entries.is_empty() => println!("No screenshots found"),
+        "text" => {
+            let rows: Vec<ScreenshotRow> = entries
+                .iter()
+                .map(|entry| ScreenshotRow {
+                    time: format_time(entry.timestamp),
+                    session_id: entry.session_id.clone(),
+                    user: entry.user.clone(),
+                    display: format!("{}:{}", entry.host, entry.display),
+                    path: archive.path(entry).display().to_string(),
+                })
+                .collect();
+            println!("{}", Table::new(rows));
+        }
+        _ => anyhow::bail!("Unsupported format: {}", format),
+    }
+    Ok(())
+}
+
+fn print_checks(report: &HealthReport) {
+    for check in &report.checks {
+        let mark = if check.ok { "PASS".green() } else { "FAIL".red() };
+        println!("  [{}] {:<14} {}", mark, check.name, check.detail);
+    }
+}
+
+fn format_usage(used: f64, limit: Option<f64>) -> String {
+    match limit {
+        Some(limit) if used >= limit => format!("{used:.1}h / {limit:.1}h").red().to_string(),
+        Some(limit) => format!("{used:.1}h / {limit:.1}h"),
+        None => format!("{used:.1}h"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90").unwrap(), 90);
+        assert_eq!(parse_duration("30m").unwrap(), 1800);
+        assert_eq!(parse_duration("1h").unwrap(), 3600);
+        assert_eq!(parse_duration("2d").unwrap(), 172800);
+        assert!(parse_duration("1w").is_err());
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("999999999999999999d").is_err());
+    }
+
+    #[test]
+    fn test_parse_columns() {
+        assert_eq!("idle".parse::<Column>().unwrap(), Column::Idle);
+        assert_eq!("GPU".parse::<Column>().unwrap(), Column::Gpu);
+        assert!("memory".parse::<Column>().is_err());
+        assert_eq!("user".parse::<SortKey>().unwrap(), SortKey::User);
+    }
+
+    #[test]
+    fn test_color_mode() {
+        assert!(ColorMode::Auto.enabled(None, true));
+        assert!(ColorMode::Auto.enabled(Some(""), true));
+        assert!(!ColorMode::Auto.enabled(Some("1"), true));
+        assert!(!ColorMode::Auto.enabled(None, false));
+        assert!(ColorMode::Always.enabled(Some("1"), false));
+        assert!(!ColorMode::Never.enabled(None, true));
+        assert_eq!("never".parse::<ColorMode>().unwrap(), ColorMode::Never);
+        assert!("sometimes".parse::<ColorMode>().is_err());
+    }
+
+    #[test]
+    fn test_fit_columns() {
+        let columns = [Column::Id, Column::User, Column::Port, Column::Idle];
+        let rows = vec![vec!["xpra-0123456789abcdef0123".to_string(), "alice".to_string(), "14600".to_string(), "5m".to_string()]];
+        // Full width: 25 + 5 + 5 + 4 plus borders
+        assert_eq!(fit_columns(&columns, &rows, 52, true), [(0, 25), (1, 5), (2, 5), (3, 4)]);
+        // The id gives way first, down to 12 characters
+        assert_eq!(fit_columns(&columns, &rows, 40, true), [(0, 13), (1, 5), (2, 5), (3, 4)]);
+        // Then the port is hidden, and the rest may still overflow
+        assert_eq!(fit_columns(&columns, &rows, 30, true), [(0, 12), (1, 5), (3, 4)]);
+        // Columns chosen explicitly are kept
+        assert_eq!(fit_columns(&columns, &rows, 30, false).len(), 4);
+    }
+
+    #[test]
+    fn test_ellipsize() {
+        assert_eq!(ellipsize("xpra-0123456789", 20, Charset::Unicode), "xpra-0123456789");
+        assert_eq!(ellipsize("xpra-0123456789", 8, Charset::Unicode), "xpra…789");
+        assert_eq!(ellipsize("xpra-0123456789", 8, Charset::Ascii), "xpr..789");
+    }
+
+    #[test]
+    fn test_mark_changes() {
+        let first = "Active Sessions: 2\nTotal Sessions: 5\n";
+        assert_eq!(mark_changes(None, first), "  Active Sessions: 2\n  Total Sessions: 5\n");
+
+        let marked = mark_changes(Some(first), "Active Sessions: 3\nTotal Sessions: 5\n");
+        let lines: Vec<_> = marked.lines().collect();
+        assert!(!lines[0].starts_with("  ") && lines[0].ends_with(" Active Sessions: 3"));
+        assert_eq!(lines[1], "  Total Sessions: 5");
     }
 }
@@ -1,56 +1,333 @@
-use std::process::{Child, Command};
+//! Xpra display servers, one process per desktop session.
+
+use std::path::PathBuf;
+use std::process::{Child, ExitStatus, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use anyhow::Result;
-use tokio::net::TcpListener;
-use tracing::{debug, error};
+use chrono::{DateTime, Utc};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time;
+use tracing::{debug, error, warn};
+
+use crate::xpra_affinity::CpuSet;
+use crate::xpra_auth::SessionToken;
+use crate::xpra_backend::BackendConfig;
+use crate::xpra_command::{ProgramAllowlist, XpraCommandBuilder};
+use crate::xpra_config::XpraConfig;
+use crate::xpra_context::XpraContext;
+use crate::xpra_devices::Device;
+use crate::xpra_hosts::{HostKind, HostPool, WorkerHost};
+use crate::xpra_netns::SessionNetwork;
+use crate::xpra_pool::{DisplayLease, ExpiryCleanup};
+use crate::xpra_priority::SessionClass;
+use crate::xpra_profiles::AppProfile;
+use crate::xpra_sandbox::HiddenPaths;
+
+/// Checks of whether a killed xpra process exited before giving up on it.
+const REAP_ATTEMPTS: u32 = 50;
+
+/// Time between checks of a killed xpra process.
+const REAP_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Time between checks of whether an xpra process that failed has exited.
+const EXIT_INTERVAL: Duration = Duration::from_millis(100);
 
-const BASE_WS_PORT: u16 = 14500;
-const MAX_DISPLAYS: u16 = 500;
+/// Time between checks of whether a new display accepts connections.
+const READY_INTERVAL: Duration = Duration::from_millis(250);
 
+/// A running xpra server with its display number and WebSocket port.
+#[derive(Debug)]
 pub struct XpraDisplay {
     display: u16,
-    process: Child,
+    /// Lease on the display number, none while hibernated
+    lease: Option<DisplayLease>,
+    process: XpraProcess,
     websocket_port: u16,
+    /// Local port forwarded to the websocket on a tunnelled host
+    tunnel_port: Option<u16>,
+    /// Local port forwarded to the display's smartcard reader, if it has one
+    smartcard_port: Option<u16>,
+    /// Paths out of sight of the desktop's programs
+    hidden: HiddenPaths,
+    host: Arc<WorkerHost>,
+    /// Configuration the display was started with
+    config: Arc<XpraConfig>,
+    /// GPU device assigned from the host's pool
+    gpu: Option<String>,
+    /// CPUs the xpra process is pinned to
+    cpus: Option<CpuSet>,
+    /// Network namespace the xpra process runs in
+    network: Option<SessionNetwork>,
+    /// Token connections to xpra authenticate with, if required
+    token: Option<SessionToken>,
+    /// Window manager or program the display was started with
+    wm: String,
+    /// Environment of the desktop's programs, besides `DISPLAY`
+    env: Vec<String>,
+    /// Session class whose priorities the display runs with
+    class: SessionClass,
+    /// When the xpra process was started
+    started_at: DateTime<Utc>,
+    /// Devices of the client the display may be given
+    devices: Vec<Device>,
+    /// Set by close() once the display's resources were returned
+    closed: bool,
+}
+
+/// Extras of a display started for a client.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DisplayOptions {
+    /// Program started on the desktop besides the window manager
+    pub app: Option<String>,
+    /// Whether the desktop's audio is forwarded
+    pub audio: bool,
+    /// POSIX locale of the desktop's programs, e.g. `de_DE.UTF-8`
+    pub locale: Option<String>,
+    /// Time zone of the desktop's programs, e.g. `Europe/Berlin`
+    pub timezone: Option<String>,
+    /// XKB keyboard layout of the display, e.g. `de`
+    pub keyboard_layout: Option<String>,
+    /// Backend showing the desktop
+    pub backend: BackendConfig,
+    /// Home of the desktop's programs, for sessions of an app
+    pub profile: Option<AppProfile>,
+    /// Devices of the client the desktop may be given
+    pub devices: Vec<Device>,
+}
+
+impl DisplayOptions {
+    /// Environment of the xpra server, inherited by the desktop's programs.
+    fn env(&self) -> Vec<String> {
+        let mut env = Vec::new();
+        if let Some(locale) = &self.locale {
+            env.push(format!("LANG={locale}"));
+        }
+        if let Some(timezone) = &self.timezone {
+            env.push(format!("TZ={timezone}"));
+        }
+        env.extend(self.profile.iter().flat_map(AppProfile::env));
+        env
+    }
+}
+
+/// The xpra process behind a display.
+#[derive(Debug)]
+enum XpraProcess {
+    /// Spawned by us, shared with the cleanup of its display's lease.
+    Child(Arc<Mutex<Child>>),
+    /// Restored by CRIU; not our child, so only known by pid.
+    Restored(u32),
+    /// Checkpointed to the given image directory, with the display released.
+    Hibernated(PathBuf),
 }
 
 impl XpraDisplay {
-    /// Create a new Xpra display with the given number and window manager
-    pub async fn new(wm: &str) -> Result<Self> {
-        // Get display number from pool
-        let display = crate::xpra_pool::DISPLAY_POOL.allocate().await?;
+    /// Create a new Xpra display on the local host of `context` with the given
+    /// window manager
+    pub async fn new(context: &XpraContext, wm: &str) -> Result<Self> {
+        let host = Arc::new(WorkerHost::local(&context.config, context.display_pool.clone()));
+        Self::new_on_host(&context.config, host, wm, SessionClass::Interactive, &DisplayOptions::default()).await
+    }
+
+    /// Create a new Xpra display on the given host with the given window manager,
+    /// running with the priorities `config` sets for the session class
+    pub async fn new_on_host(
+        config: &Arc<XpraConfig>,
+        host: Arc<WorkerHost>,
+        wm: &str,
+        class: SessionClass,
+        options: &DisplayOptions,
+    ) -> Result<Self> {
+        // Lease a display number from the host's pool, returned to it once
+        // xpra is stopped if the session is not bound to it in time
+        let lease = host.pool.allocate().await?;
+        lease.expire_after(config.lease_duration());
+        let number = lease.number();
 
         // Calculate websocket port - each display gets its own port
-        let websocket_port = BASE_WS_PORT + display;
+        let websocket_port = host.websocket_port(number);
 
         // Ensure the port is available
-        let listener = TcpListener::bind(("127.0.0.1", websocket_port)).await?;
-        drop(listener);
-
-        // Start xpra process
-        let process = Command::new("xpra")
-            .args([
-                "start",
-                &format!(":${display}"),
-                &format!("--bind-ws=127.0.0.1:${websocket_port}"),
-                "--start",
-                wm,
-                "--html=on",
-                "--pulseaudio=no",
-                "--daemon=no",
-                "--exit-with-children=yes"
-            ])
-            .spawn()?;
+        if host.kind == HostKind::Local {
+            TcpListener::bind(("127.0.0.1", websocket_port)).await?;
+        }
+        // Xpra bound to a worker's loopback is reached through a forward of
+        // the ssh running it, from a local port free at the time
+        let free_port = || async { std::io::Result::Ok(TcpListener::bind(("127.0.0.1", 0)).await?.local_addr()?.port()) };
+        let tunnel_port = match host.is_tunnelled() {
+            true => Some(free_port().await?),
+            false => None,
+        };
+        // So is the reader of a smartcard, through its Unix socket
+        let smartcard = &config.devices.smartcard;
+        let smartcard_port = match smartcard.available(&host) {
+            true => Some(free_port().await?),
+            false => None,
+        };
+
+        // Start xpra process with arguments its version understands
+        let capabilities = host.capabilities().await;
+        let x_server = crate::xpra_xorg::x_server_command(config, &host, &capabilities).await?;
+
+        // Assign a GPU when the host has any
+        let gpu = host.gpus.allocate().await;
+        if gpu.is_none() && !host.gpus.is_empty() && config.gpu.required {
+            anyhow::bail!("No GPU available on {}", host.name);
+        }
+        let cpus = host.cpus.assign(&host).await;
+        let started_at = Utc::now();
+        let network = match config.network.for_display(number) {
+            Ok(Some(network)) => match network.setup(&host, websocket_port).await {
+                Ok(()) => Some(network),
+                Err(e) => {
+                    release_display(&host, Some((lease, started_at)), gpu, cpus, None).await;
+                    return Err(e);
+                }
+            },
+            Ok(None) => None,
+            Err(e) => {
+                release_display(&host, Some((lease, started_at)), gpu, cpus, None).await;
+                return Err(e);
+            }
+        };
+
+        // Other users' profiles are out of sight of every desktop
+        let hidden = config.profiles.hidden(options.profile.as_ref());
+        let mut env = options.env();
+        env.extend(config.proxy.env());
+        env.extend(smartcard.env(number, &host));
+
+        // Wrap xpra in the priority, pinning and GPU environment, if any
+        let allowlist = ProgramAllowlist::from_config(config);
+        let mut builder = XpraCommandBuilder::new(&capabilities, &allowlist)
+            .prefix(config.priority.for_class(class).command_prefix())
+            .prefix(cpus.iter().flat_map(CpuSet::command_prefix))
+            .env(env.clone())
+            .wrap(options.profile.iter().flat_map(AppProfile::command_prefix))
+            .wrap(config.proxy.command_prefix())
+            .wrap(network.iter().flat_map(SessionNetwork::command_prefix))
+            .wrap(config.sandbox.command_prefix(&host, &hidden))
+            .display(number)
+            .window_manager(wm)
+            .x_server(&x_server)
+            .options(options);
+        // Inside a namespace xpra must listen on the namespace's address
+        builder = match &network {
+            Some(network) => builder.bind(&network.address.to_string(), websocket_port),
+            None => builder.bind(&host.bind_address, websocket_port),
+        };
+        if let Some(device) = &gpu {
+            builder = builder.env(config.gpu.server_env(device)).gpu(&config.gpu, device);
+        }
+        if let Some(tls) = &host.tls {
+            builder = builder.tls(tls);
+        }
+        let token = config.socket_auth.then(SessionToken::generate);
+        if token.is_some() {
+            builder = builder.auth(&host.kind);
+        }
+        let command = match builder.build() {
+            Ok(command) => command,
+            Err(e) => {
+                release_display(&host, Some((lease, started_at)), gpu, cpus, network).await;
+                return Err(e);
+            }
+        };
+
+        let mut forwards: Vec<(u16, String)> = Vec::new();
+        forwards.extend(tunnel_port.map(|local_port| (local_port, host.bind_target(websocket_port))));
+        forwards.extend(smartcard_port.map(|local_port| (local_port, smartcard.socket(number).to_string_lossy().into_owned())));
+        // A tty would echo the token written to ssh into xpra's output
+        let mut cmd = host.command_forwarding(&command[0], &command[1..], &forwards, token.is_none());
+        cmd.stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped());
+        let process = match cmd.spawn() {
+            Ok(mut process) => {
+                // xpra's stdin is closed once the token, if any, is written,
+                // except over ssh -T, where closing it ends the remote xpra
+                if let (Some(token), Some(stdin)) = (&token, process.stdin.as_mut()) {
+                    if let Err(e) = token.write_line(stdin) {
+                        warn!(display = number, "Failed to pass the token to xpra: {}", e);
+                    }
+                }
+                if token.is_none() || host.kind == HostKind::Local {
+                    drop(process.stdin.take());
+                }
+                crate::xpra_output::capture(&mut process, &crate::xpra_output::log_path(config, &host.name, number));
+                Arc::new(Mutex::new(process))
+            }
+            Err(e) => {
+                release_display(&host, Some((lease, started_at)), gpu, cpus, network).await;
+                return Err(e.into());
+            }
+        };
+        // Should the lease expire, xpra is stopped before the number is free
+        if lease.on_expiry(expiry_cleanup(host.clone(), number, started_at, process.clone())).is_err() {
+            stop(&process, number).await;
+            release_display(&host, Some((lease, started_at)), gpu, cpus, network).await;
+            anyhow::bail!("the lease of display :{} expired before xpra started", number);
+        }
 
         debug!(
-            display = display,
+            host = host.name,
+            display = number,
             port = websocket_port,
-            pid = process.id(),
+            gpu = gpu.as_deref(),
+            cpus = cpus.as_ref().map(|c| c.to_string()),
+            pid = process.lock().unwrap().id(),
             "Started new Xpra display"
         );
 
         Ok(Self {
-            display,
-            process,
+            display: number,
+            lease: Some(lease),
+            process: XpraProcess::Child(process),
             websocket_port,
+            tunnel_port,
+            smartcard_port,
+            hidden,
+            host,
+            config: config.clone(),
+            gpu,
+            cpus,
+            network,
+            token,
+            wm: wm.to_string(),
+            env,
+            class,
+            started_at,
+            devices: options.devices.clone(),
+            closed: false,
+        })
+    }
+
+    /// Track an already running process as a display on the given host,
+    /// taking a number from the host's pool. Lets tests stand in a cheap
+    /// process for xpra; the process is killed when the display is closed.
+    /// The display runs with the default configuration.
+    #[cfg(test)]
+    pub(crate) async fn from_process(host: Arc<WorkerHost>, process: Child, wm: &str) -> Result<Self> {
+        let lease = host.pool.allocate().await?;
+        Ok(Self {
+            display: lease.number(),
+            websocket_port: host.websocket_port(lease.number()),
+            lease: Some(lease),
+            process: XpraProcess::Child(Arc::new(Mutex::new(process))),
+            tunnel_port: None,
+            smartcard_port: None,
+            hidden: HiddenPaths::default(),
+            host,
+            config: Arc::default(),
+            gpu: None,
+            cpus: None,
+            network: None,
+            token: None,
+            wm: wm.to_string(),
+            env: Vec::new(),
+            class: SessionClass::Interactive,
+            started_at: Utc::now(),
+            devices: Vec::new(),
+            closed: false,
         })
     }
 
@@ -59,67 +336,499 @@ impl XpraDisplay {
         self.display
     }
 
+    /// Configuration the display was started with
+    pub fn config(&self) -> &XpraConfig {
+        &self.config
+    }
+
     /// Get the websocket port
     pub fn websocket_port(&self) -> u16 {
         self.websocket_port
     }
 
+    /// Get the host running this display
+    pub fn host(&self) -> &Arc<WorkerHost> {
+        &self.host
+    }
+
+    /// Get the devices of the client this display may be given
+    pub fn devices(&self) -> &[Device] {
+        &self.devices
+    }
+
+    /// Get the GPU device assigned to this display, if any
+    pub fn gpu(&self) -> Option<&str> {
+        self.gpu.as_deref()
+    }
+
+    /// Get the CPUs this display is pinned to, if any
+    pub fn cpus(&self) -> Option<&CpuSet> {
+        self.cpus.as_ref()
+    }
+
+    /// Command and arguments that run a program next to the display's xpra
+    /// server, in its network namespace and sandbox
+    pub fn command_prefix(&self) -> Vec<String> {
+        let mut prefix = self.network.as_ref().map(SessionNetwork::command_prefix).unwrap_or_default();
+        prefix.extend(self.config.sandbox.command_prefix(&self.host, &self.hidden));
+        prefix
+    }
+
+    /// Get the token connections to the display authenticate with, if
+    /// required
+    pub fn token(&self) -> Option<&SessionToken> {
+        self.token.as_ref()
+    }
+
+    /// Get the network namespace this display runs in, if isolated
+    pub fn network(&self) -> Option<&SessionNetwork> {
+        self.network.as_ref()
+    }
+
+    /// Address the display's websocket is reached on from this host
+    pub fn connect_address(&self) -> String {
+        match &self.network {
+            _ if self.tunnel_port.is_some() => "127.0.0.1".to_string(),
+            // Remote namespaces are reached through their host's address
+            Some(network) if self.host.kind == HostKind::Local => network.address.to_string(),
+            _ => self.host.address.clone(),
+        }
+    }
+
+    /// Port the display's websocket is reached on from this host
+    pub fn connect_port(&self) -> u16 {
+        self.tunnel_port.unwrap_or(self.websocket_port)
+    }
+
+    /// Port on this host's loopback the display's smartcard reader is
+    /// reached on, if it has one
+    pub fn smartcard_port(&self) -> Option<u16> {
+        self.smartcard_port
+    }
+
+    /// Wait until the display's websocket accepts connections, failing if
+    /// xpra exits or `timeout` passes first
+    pub async fn wait_ready(&mut self, timeout: Duration) -> Result<()> {
+        let address = (self.connect_address(), self.connect_port());
+        let deadline = time::Instant::now() + timeout;
+        loop {
+            if let Some(status) = self.exit_status() {
+                anyhow::bail!("xpra exited with {} before display :{} was ready", status, self.display);
+            }
+            if let Ok(Ok(stream)) = time::timeout(READY_INTERVAL, TcpStream::connect(&address)).await {
+                // ssh accepts on the forwarded port before the worker's xpra
+                // listens, and closes the connection if it does not; xpra
+                // itself waits for the client to speak first
+                let closed = self.tunnel_port.is_some()
+                    && time::timeout(READY_INTERVAL, stream.peek(&mut [0])).await.is_ok();
+                if !closed {
+                    debug!(host = self.host.name, display = self.display, "Xpra display is ready");
+                    return Ok(());
+                }
+            }
+            if time::Instant::now() >= deadline {
+                anyhow::bail!("display :{} did not accept connections within {}s", self.display, timeout.as_secs());
+            }
+            time::sleep(READY_INTERVAL).await;
+        }
+    }
+
+    /// Get the window manager or program the display was started with
+    pub fn wm(&self) -> &str {
+        &self.wm
+    }
+
+    /// Get the environment the desktop's programs run with, besides
+    /// `DISPLAY`
+    pub fn env(&self) -> &[String] {
+        &self.env
+    }
+
+    /// Get the session class the display runs as
+    pub fn class(&self) -> SessionClass {
+        self.class
+    }
+
     /// Check if the Xpra process is still running
     pub fn is_running(&mut self) -> bool {
-        self.process.try_wait().map(|status| status.is_none()).unwrap_or(false)
+        match &mut self.process {
+            XpraProcess::Child(process) => {
+                process.lock().unwrap().try_wait().map(|status| status.is_none()).unwrap_or(false)
+            }
+            XpraProcess::Restored(pid) => std::path::Path::new(&format!("/proc/{}", pid)).exists(),
+            XpraProcess::Hibernated(_) => false,
+        }
+    }
+
+    /// Exit status of an xpra process spawned by us once it exited, `None`
+    /// while it runs or for restored and hibernated displays
+    pub fn exit_status(&mut self) -> Option<ExitStatus> {
+        match &mut self.process {
+            XpraProcess::Child(process) => process.lock().unwrap().try_wait().ok().flatten(),
+            XpraProcess::Restored(_) | XpraProcess::Hibernated(_) => None,
+        }
+    }
+
+    /// Exit status of an xpra process spawned by us, waiting up to `timeout`
+    /// for it to exit; `None` if it still runs then, or for restored and
+    /// hibernated displays
+    pub async fn wait_exit(&mut self, timeout: Duration) -> Option<ExitStatus> {
+        let deadline = time::Instant::now() + timeout;
+        loop {
+            if let Some(status) = self.exit_status() {
+                return Some(status);
+            }
+            if !matches!(self.process, XpraProcess::Child(_)) || time::Instant::now() >= deadline {
+                return None;
+            }
+            time::sleep(EXIT_INTERVAL).await;
+        }
+    }
+
+    /// Whether the display is currently checkpointed to disk
+    pub fn is_hibernated(&self) -> bool {
+        matches!(self.process, XpraProcess::Hibernated(_))
+    }
+
+    /// Checkpoint the xpra process tree into `dir` and free the display.
+    pub async fn hibernate(&mut self, dir: PathBuf) -> Result<()> {
+        let pid = match &self.process {
+            XpraProcess::Child(process) => process.lock().unwrap().id(),
+            XpraProcess::Restored(pid) => *pid,
+            XpraProcess::Hibernated(_) => anyhow::bail!("display is already hibernated"),
+        };
+        // CRIU cannot checkpoint processes holding GPU device handles
+        if self.gpu.is_some() {
+            anyhow::bail!("displays with a GPU cannot be hibernated");
+        }
+        if self.network.is_some() {
+            anyhow::bail!("displays in a network namespace cannot be hibernated");
+        }
+        crate::xpra_hibernate::checkpoint(pid, &dir).await?;
+
+        // CRIU killed the tree after dumping it; reap our child
+        if let XpraProcess::Child(process) = &self.process {
+            let _ = process.lock().unwrap().wait();
+        }
+        self.process = XpraProcess::Hibernated(dir);
+        self.lease = None;
+        Ok(())
+    }
+
+    /// Bind the display's number to the session it now runs, so its lease
+    /// no longer expires. Fails if the display took too long to start and
+    /// its number went back to the pool.
+    pub fn bind(&self) -> Result<()> {
+        self.lease.as_ref().map_or(Ok(()), DisplayLease::bind)
+    }
+
+    /// Stop the xpra process and, once it is gone, return the display
+    /// number, GPU and CPUs to their pools. Every display should end here;
+    /// dropping one returns them from a task of its own, if it can.
+    pub async fn close(mut self) {
+        self.closed = true;
+        let display = match &mut self.process {
+            XpraProcess::Child(process) => {
+                stop(process, self.display).await;
+                self.lease.take().map(|lease| (lease, self.started_at))
+            }
+            XpraProcess::Restored(pid) => {
+                kill_restored(*pid);
+                self.lease.take().map(|lease| (lease, self.started_at))
+            }
+            XpraProcess::Hibernated(dir) => {
+                if let Err(e) = tokio::fs::remove_dir_all(&*dir).await {
+                    error!(display = self.display, error = ?e, "Failed to remove checkpoint images");
+                }
+                // The display was already released when hibernating
+                None
+            }
+        };
+        release_display(&self.host, display, self.gpu.take(), self.cpus.take(), self.network.take()).await;
+        debug!(display = self.display, "Terminated Xpra display");
+    }
+
+    /// Restore a hibernated display on its original display number.
+    pub async fn resume(&mut self) -> Result<()> {
+        let XpraProcess::Hibernated(dir) = &self.process else {
+            return Ok(());
+        };
+        let Some(lease) = self.host.pool.reserve(self.display).await else {
+            anyhow::bail!("display :{} was reused while the session was hibernated", self.display);
+        };
+        // Dropping the lease on failure returns the number again
+        let pid = crate::xpra_hibernate::restore(dir).await?;
+        let _ = tokio::fs::remove_dir_all(dir).await;
+        self.process = XpraProcess::Restored(pid);
+        self.lease = Some(lease);
+        Ok(())
+    }
+}
+
+/// Show a desktop notification to everyone attached to a display on a host
+/// of `hosts`.
+pub async fn notify_display(hosts: &HostPool, host: &str, display: u16, title: &str, body: &str) -> Result<()> {
+    let Some(host) = hosts.get(host) else {
+        anyhow::bail!("unknown desktop host {}", host);
+    };
+    let args = [
+        "control".to_string(),
+        format!(":{}", display),
+        "send-notification".to_string(),
+        "0".to_string(),
+        title.to_string(),
+        body.to_string(),
+        "*".to_string(),
+    ];
+    let status = tokio::process::Command::from(host.command("xpra", &args))
+        .status()
+        .await?;
+    if !status.success() {
+        anyhow::bail!("xpra control exited with {}", status);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+impl XpraDisplay {
+    /// A display on `host` whose process is a `sleep`, with the pid of the
+    /// `sleep`. Shared by the tests of the modules that track displays.
+    pub(crate) async fn sleeping(host: Arc<WorkerHost>) -> (Self, u32) {
+        let process = std::process::Command::new("sleep")
+            .arg("60")
+            .stdin(Stdio::null())
+            .spawn()
+            .unwrap();
+        let pid = process.id();
+        (Self::from_process(host, process, "test").await.unwrap(), pid)
     }
 }
 
 impl Drop for XpraDisplay {
     fn drop(&mut self) {
-        // Return display number to pool
-        tokio::spawn({
-            let pool = crate::xpra_pool::DISPLAY_POOL.clone();
-            let display = self.display;
-            async move {
-                pool.release(display).await;
-            }
-        });
-        // Ensure xpra process is terminated
-        if let Err(e) = self.process.kill() {
-            error!(
-                display = self.display,
-                error = ?e,
-                "Failed to kill Xpra process"
-            );
+        if self.closed {
+            return;
+        }
+        match &mut self.process {
+            XpraProcess::Child(process) => {
+                let mut process = process.lock().unwrap();
+                let _ = process.kill();
+                let _ = process.wait();
+            }
+            XpraProcess::Restored(pid) => kill_restored(*pid),
+            XpraProcess::Hibernated(_) => {}
         }
-        
-        // Wait for process to fully terminate
-        if let Err(e) = self.process.wait() {
+        // The number is only free once the X server's leftovers are gone,
+        // which takes a runtime; without one it stays taken with the GPU
+        // and CPUs
+        let display = self.lease.take().map(|lease| (lease, self.started_at));
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            std::mem::forget(display);
             error!(
+                host = self.host.name,
                 display = self.display,
-                error = ?e,
-                "Failed to wait for Xpra process termination"
+                "Xpra display dropped without being closed, its number, GPU and CPUs stay taken"
             );
+            return;
+        };
+        warn!(host = self.host.name, display = self.display, "Xpra display dropped without being closed");
+        let (host, gpu, cpus, network) = (self.host.clone(), self.gpu.take(), self.cpus.take(), self.network.take());
+        runtime.spawn(async move { release_display(&host, display, gpu, cpus, network).await });
+    }
+}
+
+/// Cleanup of a starting display whose lease expires: stop its xpra and
+/// wait out the X server it ran, so the number is free for the next one.
+fn expiry_cleanup(host: Arc<WorkerHost>, number: u16, started_at: DateTime<Utc>, process: Arc<Mutex<Child>>) -> ExpiryCleanup {
+    Box::new(move || {
+        Box::pin(async move {
+            stop(&process, number).await;
+            crate::xpra_cleanup::clean_display(&host, number, started_at).await;
+        })
+    })
+}
+
+/// Kill an xpra process spawned by us and wait for it to exit.
+async fn stop(process: &Mutex<Child>, number: u16) {
+    // Killing a process already waited for does nothing, so the display
+    // and the cleanup of its lease may both stop it
+    if let Err(e) = process.lock().unwrap().kill() {
+        error!(display = number, error = ?e, "Failed to kill Xpra process");
+    }
+    reap(process, number).await;
+}
+
+/// Return a display, started at the given time, and its GPU and CPUs to
+/// their pools, removing its network namespace.
+async fn release_display(
+    host: &WorkerHost,
+    display: Option<(DisplayLease, DateTime<Utc>)>,
+    gpu: Option<String>,
+    cpus: Option<CpuSet>,
+    network: Option<SessionNetwork>,
+) {
+    if let Some(network) = network {
+        network.teardown(host).await;
+    }
+    // An expired number may already be another display's; leave its files
+    if let Some((lease, started_at)) = display.filter(|(lease, _)| !lease.is_expired()) {
+        // Leftovers of the X server would fail the next xpra on it
+        crate::xpra_cleanup::clean_display(host, lease.number(), started_at).await;
+    }
+    if let Some(device) = gpu {
+        host.gpus.release(&device).await;
+    }
+    if let Some(cpus) = cpus {
+        host.cpus.release(&cpus).await;
+    }
+}
+
+/// Wait for a killed xpra process to exit without blocking the runtime.
+async fn reap(process: &Mutex<Child>, number: u16) {
+    for _ in 0..REAP_ATTEMPTS {
+        let status = process.lock().unwrap().try_wait();
+        match status {
+            Ok(Some(_)) => return,
+            Ok(None) => time::sleep(REAP_INTERVAL).await,
+            Err(e) => {
+                error!(display = number, error = ?e, "Failed to wait for Xpra process termination");
+                return;
+            }
         }
+    }
+    warn!(display = number, pid = process.lock().unwrap().id(), "Xpra process did not exit after being killed");
+}
 
-        debug!(
-            display = self.display,
-            "Terminated Xpra display"
-        );
+#[cfg(unix)]
+fn kill_restored(pid: u32) {
+    use nix::sys::signal::{kill, Signal};
+    use nix::unistd::Pid;
+
+    if let Err(e) = kill(Pid::from_raw(pid as i32), Signal::SIGKILL) {
+        error!(pid, error = ?e, "Failed to kill restored Xpra process");
     }
 }
 
+#[cfg(not(unix))]
+fn kill_restored(_pid: u32) {}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::xpra_pool::DisplayPool;
 
     #[tokio::test]
     async fn test_xpra_display_lifecycle() {
-        let mut display = XpraDisplay::new(1, "gnome-flashback")
+        let context = XpraContext::new(XpraConfig::default());
+        let mut display = XpraDisplay::new(&context, "gnome-flashback")
             .await
             .expect("Failed to create display");
 
-        assert_eq!(display.display(), 1);
-        assert_eq!(display.websocket_port(), BASE_WS_PORT + 1);
+        let host = WorkerHost::local(&context.config, context.display_pool.clone());
+        assert_eq!(display.websocket_port(), host.websocket_port(display.display()));
+        assert!(display.is_running());
+
+        // Closing the display returns its number to the pool
+        display.close().await;
+    }
+
+    #[tokio::test]
+    async fn test_close_releases_display() {
+        let host = Arc::new(WorkerHost::local(&XpraConfig::default(), DisplayPool::new()));
+        let (mut display, _) = XpraDisplay::sleeping(host.clone()).await;
         assert!(display.is_running());
+        assert_eq!(host.pool.allocated_count().await, 1);
 
-        // Display should be cleaned up when dropped
+        display.close().await;
+        assert_eq!(host.pool.allocated_count().await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_drop_releases_display_once_cleaned() {
+        let host = Arc::new(WorkerHost::local(&XpraConfig::default(), DisplayPool::new()));
+        let (display, pid) = XpraDisplay::sleeping(host.clone()).await;
         drop(display);
+        assert!(!std::path::Path::new(&format!("/proc/{pid}")).exists());
+
+        // The number comes back from a task once the display is cleaned up
+        for _ in 0..50 {
+            if host.pool.allocated_count().await == 0 {
+                return;
+            }
+            time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("display number was not released");
+    }
+
+    #[tokio::test]
+    async fn test_hibernate_transitions() {
+        let host = Arc::new(WorkerHost::local(&XpraConfig::default(), DisplayPool::new()));
+        let (mut display, _) = XpraDisplay::sleeping(host.clone()).await;
+        let dir = std::env::temp_dir().join(format!("sshx-xpra-hibernate-{}", std::process::id()));
+
+        // Resuming a running display does nothing
+        display.resume().await.unwrap();
+        assert!(display.is_running() && !display.is_hibernated());
+
+        // Displays holding a GPU are refused and keep running
+        display.gpu = Some("0".to_string());
+        let err = display.hibernate(dir.clone()).await.unwrap_err();
+        assert!(err.to_string().contains("GPU"));
+        assert!(display.is_running() && !display.is_hibernated());
+        assert_eq!(host.pool.allocated_count().await, 1);
+        display.gpu = None;
+
+        // As if checkpointed: the process is gone and the number released
+        if let XpraProcess::Child(process) = &display.process {
+            let mut process = process.lock().unwrap();
+            process.kill().unwrap();
+            process.wait().unwrap();
+        }
+        std::fs::create_dir_all(&dir).unwrap();
+        display.process = XpraProcess::Hibernated(dir.clone());
+        display.lease = None;
+        assert!(display.is_hibernated() && !display.is_running());
+        assert!(display.exit_status().is_none());
+        assert_eq!(host.pool.allocated_count().await, 0);
+
+        let err = display.hibernate(dir.clone()).await.unwrap_err();
+        assert!(err.to_string().contains("already hibernated"));
+
+        // Resuming on a number another display took meanwhile is refused
+        let taken = host.pool.reserve(display.display).await.unwrap();
+        let err = display.resume().await.unwrap_err();
+        assert!(err.to_string().contains("was reused"));
+        assert!(display.is_hibernated() && dir.exists());
+        drop(taken);
+        assert_eq!(host.pool.allocated_count().await, 0);
+
+        // A failed restore stays hibernated and gives the number back
+        assert!(display.resume().await.is_err());
+        assert!(display.is_hibernated());
+        assert_eq!(host.pool.allocated_count().await, 0);
+
+        // Closing a hibernated display removes its images
+        display.close().await;
+        assert!(!dir.exists());
+        assert_eq!(host.pool.allocated_count().await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_wait_ready() {
+        let host = Arc::new(WorkerHost::local(&XpraConfig::default(), DisplayPool::new()));
+        let (mut display, _) = XpraDisplay::sleeping(host.clone()).await;
+        let err = display.wait_ready(Duration::ZERO).await.unwrap_err();
+        assert!(err.to_string().contains("did not accept connections"));
+
+        let _listener = TcpListener::bind(("127.0.0.1", display.websocket_port())).await.unwrap();
+        display.wait_ready(Duration::from_secs(5)).await.unwrap();
+        display.close().await;
+
+        let process = std::process::Command::new("true").spawn().unwrap();
+        let mut display = XpraDisplay::from_process(host.clone(), process, "test").await.unwrap();
+        time::sleep(Duration::from_millis(100)).await;
+        let err = display.wait_ready(Duration::from_secs(5)).await.unwrap_err();
+        assert!(err.to_string().contains("xpra exited"));
+        display.close().await;
     }
 }
@@ -0,0 +1,431 @@
+//! Local admin API for a running desktop host, served over a Unix socket.
+//!
+//! Each connection carries newline-delimited JSON requests, each answered by a
+//! single line of JSON. A `logs` request with `follow` set is the exception:
+//! it is answered by a line for each batch of new output until the session
+//! ends or the client sends anything else or disconnects.
+
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sshx_core::Sid;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::unix::OwnedWriteHalf;
+use tokio::net::{UnixListener, UnixStream};
+use tracing::{debug, error, info};
+
+use crate::xpra_context::XpraContext;
+use crate::xpra_health::self_check;
+use crate::xpra_inspect;
+use crate::xpra_labels::Labels;
+use crate::xpra_logger::TerminationReason;
+use crate::xpra_output::{self, LogLines};
+use crate::xpra_paging::SessionQuery;
+use crate::xpra_status;
+use crate::xpra_time_range::TimeSpec;
+
+/// How often a followed session log is checked for new output.
+const LOG_FOLLOW_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Operation requested over the admin socket.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum AdminRequest {
+    /// Block new sessions and terminate existing ones after a deadline.
+    Drain {
+        /// Seconds until the remaining sessions are terminated, the configured
+        /// `drain_deadline` if not given
+        deadline_secs: Option<u64>,
+        /// Message shown to users of the remaining sessions
+        message: Option<String>,
+    },
+    /// Leave drain mode.
+    Undrain,
+    /// Run the readiness self-check.
+    Health,
+    /// Start a headless session for automation.
+    Launch {
+        /// User the session runs as
+        user: String,
+        /// Program to start on the display, the configured one if not given
+        program: Option<String>,
+        /// Idle timeout of the session, the configured one if not given
+        idle_timeout_secs: Option<u64>,
+        /// Labels attached to the session
+        #[serde(default)]
+        labels: Labels,
+    },
+    /// Run a shell command on a headless session's display.
+    Exec {
+        /// Id of the headless session
+        session_id: String,
+        /// Command run with `sh -c` on the display
+        shell_command: String,
+        /// Return at once instead of waiting for the command's output
+        #[serde(default)]
+        detach: bool,
+    },
+    /// Save a PNG screenshot of a headless session in the screenshot
+    /// directory of this host.
+    Screenshot {
+        /// Id of the headless session
+        session_id: String,
+        /// File name the PNG is saved as
+        name: String,
+    },
+    /// Stop a headless session.
+    Stop {
+        /// Id of the headless session
+        session_id: String,
+    },
+    /// Describe a single session in full.
+    Inspect {
+        /// Id of the session
+        session_id: String,
+    },
+    /// Read a session's xpra output, optionally following new output.
+    Logs {
+        /// Id of the session
+        session_id: String,
+        /// Only the last this many lines
+        lines: Option<usize>,
+        /// Only lines written at or after this time, e.g. `30m` or
+        /// `yesterday`, resolved against the host's clock
+        since: Option<TimeSpec>,
+        /// Keep sending new output until the session ends
+        #[serde(default)]
+        follow: bool,
+    },
+    /// List a page of the running sessions, filtered on the host.
+    Sessions {
+        /// Which sessions, and which page of them
+        #[serde(flatten)]
+        query: SessionQuery,
+    },
+    /// Report the host's status, with a page of its sessions filtered on
+    /// the host.
+    Status {
+        /// Which sessions, and which page of them
+        #[serde(flatten)]
+        query: SessionQuery,
+    },
+    /// Count the running sessions, in total and by user and team.
+    Summary,
+    /// Terminate every session started from an sshx session, which also
+    /// closes the shells they were started from.
+    Kill {
+        /// Name of the sshx session
+        session: String,
+        /// Only the session started from this shell of it
+        shell: Option<Sid>,
+    },
+    /// Read the history events of a session or of all sessions of a user.
+    History {
+        /// Id of the session or name of the user
+        key: String,
+        /// Only events at or after this time, e.g. `7d` or `yesterday`,
+        /// resolved against the host's clock
+        since: Option<TimeSpec>,
+    },
+    /// Replace every history event of a user with a tombstone, for a data
+    /// deletion request.
+    Redact {
+        /// Name of the user
+        user: String,
+    },
+}
+
+/// Reply to an admin request.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdminResponse {
+    /// Whether the request succeeded
+    pub ok: bool,
+    /// Why the request failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Result of the request, null if it has none
+    #[serde(default)]
+    pub data: serde_json::Value,
+}
+
+impl AdminResponse {
+    fn ok(data: impl Serialize) -> Self {
+        match serde_json::to_value(data) {
+            Ok(data) => Self { ok: true, error: None, data },
+            Err(e) => Self::error(e.to_string()),
+        }
+    }
+
+    fn error(message: String) -> Self {
+        Self { ok: false, error: Some(message), data: serde_json::Value::Null }
+    }
+}
+
+/// Serves the admin API of a host on a Unix socket.
+pub struct AdminServer {
+    context: Arc<XpraContext>,
+    socket_path: PathBuf,
+}
+
+impl AdminServer {
+    /// Create a server of the host of `context`, listening on its
+    /// `admin_socket`
+    pub fn new(context: Arc<XpraContext>) -> Self {
+        let socket_path = context.config.admin_socket.clone();
+        Self { context, socket_path }
+    }
+
+    /// Bind the socket, or take it from systemd socket activation under the
+    /// name `admin`, and serve requests in the background.
+    pub fn start(&self) -> Result<()> {
+        let listener = match crate::xpra_systemd::take_unix_listener("admin") {
+            Some(listener) => {
+                info!("Admin API listening on socket-activated socket");
+                UnixListener::from_std(listener)?
+            }
+            None => self.bind()?,
+        };
+
+        let context = self.context.clone();
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        let context = context.clone();
+                        tokio::spawn(async move {
+                            if let Err(e) = serve_connection(&context, stream).await {
+                                debug!("Admin connection closed with error: {}", e);
+                            }
+                        });
+                    }
+                    Err(e) => error!("Failed to accept admin connection: {}", e),
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn bind(&self) -> Result<UnixListener> {
+        if let Some(parent) = self.socket_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        // A leftover socket from a previous run would make bind() fail.
+        let _ = std::fs::remove_file(&self.socket_path);
+        let listener = UnixListener::bind(&self.socket_path)
+            .with_context(|| format!("failed to bind {}", self.socket_path.display()))?;
+        std::fs::set_permissions(&self.socket_path, std::fs::Permissions::from_mode(0o600))?;
+        info!(path = %self.socket_path.display(), "Admin API listening");
+        Ok(listener)
+    }
+}
+
+async fn serve_connection(context: &XpraContext, stream: UnixStream) -> Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+    while let Some(line) = lines.next_line().await? {
+        let response = match serde_json::from_str::<AdminRequest>(&line) {
+            Ok(AdminRequest::Logs { session_id, lines: count, since, follow: true }) => {
+                let since = since.map(|since| since.resolve(Utc::now()));
+                // Stop following as soon as the client sends anything or
+                // hangs up
+                tokio::select! {
+                    result = follow_logs(context, &mut write, &session_id, count, since) => result?,
+                    _ = lines.next_line() => {}
+                }
+                return Ok(());
+            }
+            Ok(request) => handle_request(context, request).await,
+            Err(e) => AdminResponse::error(format!("invalid request: {e}")),
+        };
+        write_response(&mut write, &response).await?;
+    }
+    Ok(())
+}
+
+async fn write_response(write: &mut OwnedWriteHalf, response: &AdminResponse) -> Result<()> {
+    let mut out = serde_json::to_vec(response)?;
+    out.push(b'\n');
+    write.write_all(&out).await?;
+    Ok(())
+}
+
+/// Stream a session's xpra output until the session ends.
+async fn follow_logs(
+    context: &XpraContext,
+    write: &mut OwnedWriteHalf,
+    session_id: &str,
+    count: Option<usize>,
+    since: Option<DateTime<Utc>>,
+) -> Result<()> {
+    let path = match xpra_output::session_log_path(context, session_id).await {
+        Ok(path) => path,
+        Err(e) => return write_response(write, &reply::<()>(Err(e))).await,
+    };
+    let (lines, mut offset) = match xpra_output::read_tail(&path, count, since).await {
+        Ok(tail) => tail,
+        Err(e) => return write_response(write, &reply::<()>(Err(e))).await,
+    };
+    write_response(write, &AdminResponse::ok(LogLines { lines })).await?;
+
+    let mut interval = tokio::time::interval(LOG_FOLLOW_INTERVAL);
+    loop {
+        interval.tick().await;
+        let running = context.monitor.get_session(session_id).await.is_some();
+        let (lines, next) = xpra_output::read_new(&path, offset).await?;
+        offset = next;
+        if !lines.is_empty() {
+            write_response(write, &AdminResponse::ok(LogLines { lines })).await?;
+        }
+        if !running {
+            return Ok(());
+        }
+    }
+}
+
+async fn handle_request(context: &XpraContext, request: AdminRequest) -> AdminResponse {
+    debug!(?request, "Handling admin request");
+    match request {
+        AdminRequest::Drain { deadline_secs, message } => {
+            let deadline = Duration::from_secs(deadline_secs.unwrap_or(context.config.drain_deadline));
+            AdminResponse::ok(context.drain.start(deadline, message).await)
+        }
+        AdminRequest::Undrain => AdminResponse::ok(context.drain.stop().await),
+        AdminRequest::Health => AdminResponse::ok(self_check(context).await),
+        AdminRequest::Launch { user, program, idle_timeout_secs, labels } => {
+            reply(context.headless.launch(user, program, idle_timeout_secs, labels).await)
+        }
+        AdminRequest::Exec { session_id, shell_command, detach } => {
+            reply(context.headless.exec(&session_id, &shell_command, detach).await)
+        }
+        AdminRequest::Screenshot { session_id, name } => reply(context.headless.screenshot(&session_id, &name).await),
+        AdminRequest::Stop { session_id } => reply(context.headless.stop(&session_id).await),
+        AdminRequest::Inspect { session_id } => reply(xpra_inspect::inspect(context, &session_id).await),
+        AdminRequest::Logs { session_id, lines, since, .. } => {
+            let since = since.map(|since| since.resolve(Utc::now()));
+            let path = match xpra_output::session_log_path(context, &session_id).await {
+                Ok(path) => path,
+                Err(e) => return reply::<()>(Err(e)),
+            };
+            reply(xpra_output::read_tail(&path, lines, since).await.map(|(lines, _)| LogLines { lines }))
+        }
+        AdminRequest::Sessions { query } => {
+            AdminResponse::ok(context.monitor.query_sessions(&query, |id, info| xpra_status::session_status(context, id, info)))
+        }
+        AdminRequest::Status { query } => AdminResponse::ok(xpra_status::get_status(context, &query).await),
+        AdminRequest::Summary => AdminResponse::ok(context.monitor.summary()),
+        AdminRequest::Kill { session, shell } => AdminResponse::ok(
+            context.monitor.terminate_spawned(&session, shell, TerminationReason::AdminKill).await,
+        ),
+        AdminRequest::History { key, since } => {
+            let since = since.map(|since| since.resolve(Utc::now()));
+            reply(context.logger.history(&key, since).await)
+        }
+        AdminRequest::Redact { user } => reply(context.logger.redact_history(&user, context.config.archive.as_ref()).await),
+    }
+}
+
+fn reply<T: Serialize>(result: Result<T>) -> AdminResponse {
+    match result {
+        Ok(data) => AdminResponse::ok(data),
+        Err(e) => AdminResponse::error(format!("{e:#}")),
+    }
+}
+
+/// Send a single request to a running host's admin socket.
+pub async fn send_request(socket_path: &Path, request: &AdminRequest) -> Result<serde_json::Value> {
+    let stream = UnixStream::connect(socket_path)
+        .await
+        .with_context(|| format!("failed to connect to {}", socket_path.display()))?;
+    let (read, mut write) = stream.into_split();
+
+    let mut line = serde_json::to_vec(request)?;
+    line.push(b'\n');
+    write.write_all(&line).await?;
+
+    let reply = BufReader::new(read)
+        .lines()
+        .next_line()
+        .await?
+        .context("admin socket closed without a reply")?;
+    let response: AdminResponse = serde_json::from_str(&reply)?;
+    if !response.ok {
+        anyhow::bail!(response.error.unwrap_or_else(|| "unknown admin error".into()));
+    }
+    Ok(response.data)
+}
+
+/// Send a request answered by a stream of replies, such as following a
+/// session log, passing each reply's data to `on_data` until the host
+/// closes the connection.
+pub async fn stream_request(
+    socket_path: &Path,
+    request: &AdminRequest,
+    mut on_data: impl FnMut(serde_json::Value) -> Result<()>,
+) -> Result<()> {
+    let stream = UnixStream::connect(socket_path)
+        .await
+        .with_context(|| format!("failed to connect to {}", socket_path.display()))?;
+    let (read, mut write) = stream.into_split();
+
+    let mut line = serde_json::to_vec(request)?;
+    line.push(b'\n');
+    write.write_all(&line).await?;
+
+    let mut replies = BufReader::new(read).lines();
+    while let Some(reply) = replies.next_line().await? {
+        let response: AdminResponse = serde_json::from_str(&reply)?;
+        if !response.ok {
+            anyhow::bail!(response.error.unwrap_or_else(|| "unknown admin error".into()));
+        }
+        on_data(response.data)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sessions_request() {
+        let json = r#"{"command":"sessions","user":"alice","labels":{"team":"ml"},"limit":20}"#;
+        let AdminRequest::Sessions { query } = serde_json::from_str(json).unwrap() else {
+            panic!("not a sessions request");
+        };
+        assert_eq!(query.user.as_deref(), Some("alice"));
+        assert_eq!(query.labels.get("team").map(String::as_str), Some("ml"));
+        assert_eq!(query.page_size(), 20);
+        assert_eq!(query.after, None);
+    }
+
+    #[test]
+    fn test_status_request() {
+        let json = r#"{"command":"status","user":"alice","after":"xpra-7","limit":5}"#;
+        let AdminRequest::Status { query } = serde_json::from_str(json).unwrap() else {
+            panic!("not a status request");
+        };
+        assert_eq!(query.user.as_deref(), Some("alice"));
+        assert_eq!(query.after.as_deref(), Some("xpra-7"));
+        assert_eq!(query.page_size(), 5);
+    }
+
+    #[test]
+    fn test_summary_request() {
+        let request = serde_json::to_string(&AdminRequest::Summary).unwrap();
+        assert_eq!(request, r#"{"command":"summary"}"#);
+        assert!(matches!(serde_json::from_str(&request).unwrap(), AdminRequest::Summary));
+    }
+
+    #[test]
+    fn test_kill_request() {
+        let json = r#"{"command":"kill","session":"k3xq9v","shell":4}"#;
+        let AdminRequest::Kill { session, shell } = serde_json::from_str(json).unwrap() else {
+            panic!("not a kill request");
+        };
+        assert_eq!((session.as_str(), shell), ("k3xq9v", Some(Sid(4))));
+    }
+}
@@ -0,0 +1,212 @@
+//! Pinning of desktop sessions to CPU sets or NUMA nodes, so interactive
+//! sessions on large multi-socket hosts don't migrate between sockets.
+
+use std::collections::HashMap;
+use std::fmt;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::{debug, info, warn};
+
+use crate::xpra_hosts::WorkerHost;
+
+/// Lists each NUMA node with its CPUs, one `node: cpulist` line per node.
+const NUMA_NODES_SCRIPT: &str =
+    r#"for n in /sys/devices/system/node/node[0-9]*; do echo "${n##*node}: $(cat "$n/cpulist")"; done"#;
+
+/// How sessions are pinned to CPUs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AffinityMode {
+    /// Sessions may run on any CPU.
+    #[default]
+    Off,
+    /// Sessions take turns over the host's NUMA nodes, binding both CPUs and
+    /// memory to the node.
+    Numa,
+    /// Sessions take turns over the configured `cpu_sets`.
+    CpuSets,
+}
+
+/// CPU pinning of sessions, off by default.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AffinityConfig {
+    /// How sessions are assigned CPUs
+    #[serde(default)]
+    pub mode: AffinityMode,
+
+    /// CPU lists in `taskset` syntax, e.g. `0-7` or `0-3,16-19`
+    #[serde(default)]
+    pub cpu_sets: Vec<String>,
+}
+
+/// CPUs a session is pinned to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CpuSet {
+    /// CPU list in `taskset` syntax
+    pub cpus: String,
+    /// NUMA node the CPUs belong to, when pinned by node
+    pub numa_node: Option<u32>,
+}
+
+impl CpuSet {
+    /// Command and arguments that run a program pinned to this set.
+    pub fn command_prefix(&self) -> Vec<String> {
+        match self.numa_node {
+            Some(node) => vec![
+                "numactl".to_string(),
+                format!("--cpunodebind={}", node),
+                format!("--membind={}", node),
+            ],
+            None => vec!["taskset".to_string(), "-c".to_string(), self.cpus.clone()],
+        }
+    }
+}
+
+impl fmt::Display for CpuSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.numa_node {
+            Some(node) => write!(f, "node{} ({})", node, self.cpus),
+            None => f.write_str(&self.cpus),
+        }
+    }
+}
+
+/// Parse the output of [`NUMA_NODES_SCRIPT`].
+fn parse_numa_nodes(output: &str) -> Vec<CpuSet> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let (node, cpus) = line.split_once(':')?;
+            let cpus = cpus.trim();
+            if cpus.is_empty() {
+                return None;
+            }
+            Some(CpuSet {
+                cpus: cpus.to_string(),
+                numa_node: Some(node.trim().parse().ok()?),
+            })
+        })
+        .collect()
+}
+
+async fn detect_numa_nodes(host: &WorkerHost) -> Result<Vec<CpuSet>> {
+    let args = ["-c".to_string(), NUMA_NODES_SCRIPT.to_string()];
+    let output = tokio::process::Command::from(host.command("sh", &args))
+        .output()
+        .await
+        .context("failed to list NUMA nodes")?;
+    let nodes = parse_numa_nodes(&String::from_utf8_lossy(&output.stdout));
+    if nodes.is_empty() {
+        anyhow::bail!("no NUMA nodes found in /sys/devices/system/node");
+    }
+    Ok(nodes)
+}
+
+/// Spreads sessions over a host's CPU sets.
+#[derive(Debug)]
+pub struct CpuPool {
+    mode: AffinityMode,
+    /// Detected on first use in NUMA mode
+    sets: Mutex<Option<Vec<CpuSet>>>,
+    used: Mutex<HashMap<String, u32>>,
+}
+
+impl CpuPool {
+    /// Create a pool of the CPU sets `config` asks for
+    pub fn new(config: &AffinityConfig) -> Self {
+        let sets = match config.mode {
+            AffinityMode::Numa => None,
+            _ => Some(
+                config
+                    .cpu_sets
+                    .iter()
+                    .map(|cpus| CpuSet { cpus: cpus.clone(), numa_node: None })
+                    .collect(),
+            ),
+        };
+        Self {
+            mode: config.mode,
+            sets: Mutex::new(sets),
+            used: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Pin a new session on `host` to the set with the fewest sessions,
+    /// taking sets in order on ties.
+    pub async fn assign(&self, host: &WorkerHost) -> Option<CpuSet> {
+        if self.mode == AffinityMode::Off {
+            return None;
+        }
+        let sets = {
+            let mut sets = self.sets.lock().await;
+            if sets.is_none() {
+                *sets = Some(match detect_numa_nodes(host).await {
+                    Ok(nodes) => {
+                        info!(host = host.name, nodes = nodes.len(), "Detected NUMA nodes");
+                        nodes
+                    }
+                    Err(e) => {
+                        warn!(host = host.name, "Sessions will not be pinned: {}", e);
+                        Vec::new()
+                    }
+                });
+            }
+            sets.clone().unwrap_or_default()
+        };
+
+        let mut used = self.used.lock().await;
+        let set = sets
+            .into_iter()
+            .min_by_key(|set| used.get(&set.cpus).copied().unwrap_or(0))?;
+        *used.entry(set.cpus.clone()).or_default() += 1;
+        debug!(host = host.name, cpus = %set, "Pinned session to CPUs");
+        Some(set)
+    }
+
+    /// Give a CPU set back when its session ends
+    pub async fn release(&self, set: &CpuSet) {
+        let mut used = self.used.lock().await;
+        match used.get_mut(&set.cpus) {
+            Some(count) if *count > 0 => *count -= 1,
+            _ => warn!(cpus = %set, "Attempted to release unassigned CPU set"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::xpra_config::XpraConfig;
+    use crate::xpra_pool::DisplayPool;
+
+    #[test]
+    fn test_parse_numa_nodes() {
+        let nodes = parse_numa_nodes("0: 0-15,32-47\n1: 16-31,48-63\n2: \n");
+        assert_eq!(nodes.len(), 2);
+        assert_eq!(nodes[1], CpuSet { cpus: "16-31,48-63".to_string(), numa_node: Some(1) });
+        assert_eq!(nodes[1].command_prefix(), vec!["numactl", "--cpunodebind=1", "--membind=1"]);
+        assert_eq!(nodes[1].to_string(), "node1 (16-31,48-63)");
+    }
+
+    #[tokio::test]
+    async fn test_assign_cpu_sets() {
+        let host = WorkerHost::local(&XpraConfig::default(), DisplayPool::new());
+        let config = AffinityConfig {
+            mode: AffinityMode::CpuSets,
+            cpu_sets: vec!["0-7".to_string(), "8-15".to_string()],
+        };
+        let pool = CpuPool::new(&config);
+
+        let first = pool.assign(&host).await.unwrap();
+        let second = pool.assign(&host).await.unwrap();
+        assert_eq!((first.cpus.as_str(), second.cpus.as_str()), ("0-7", "8-15"));
+        assert_eq!(second.command_prefix(), vec!["taskset", "-c", "8-15"]);
+
+        pool.release(&second).await;
+        assert_eq!(pool.assign(&host).await, Some(second));
+
+        let off = CpuPool::new(&AffinityConfig::default());
+        assert_eq!(off.assign(&host).await, None);
+    }
+}
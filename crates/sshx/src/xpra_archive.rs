@@ -0,0 +1,411 @@
+//! Archival of rotated logs past their retention to a directory or an
+//! S3-compatible object store, instead of deleting them.
+//!
+//! Each archived file is recorded in `archive-index.jsonl` in the log
+//! directory with the times it covers, so the analyzer can read back the
+//! files of a period without listing the archive.
+
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncWriteExt;
+use tracing::{debug, info};
+
+use crate::xpra_log_analyzer::read_log_file;
+
+/// Index of the archived logs, in the log directory
+pub const INDEX_FILE: &str = "archive-index.jsonl";
+
+/// Where aged logs are archived.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ArchiveTarget {
+    /// A directory, e.g. on a larger or network-mounted disk
+    Dir {
+        /// Directory the logs are moved to
+        path: PathBuf,
+    },
+    /// A bucket of an S3-compatible object store. Credentials are read from
+    /// `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and, for temporary ones,
+    /// `AWS_SESSION_TOKEN`.
+    S3(S3Config),
+}
+
+/// Bucket of an S3-compatible object store.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct S3Config {
+    /// Base URL of the store, like `https://s3.eu-west-1.amazonaws.com` or
+    /// `http://minio.internal:9000`
+    pub endpoint: String,
+    /// Bucket, addressed path-style
+    pub bucket: String,
+    /// Prefix of the object keys, like `sshx/host-1/`
+    #[serde(default)]
+    pub prefix: String,
+    /// Region requests are signed for
+    #[serde(default = "default_region")]
+    pub region: String,
+}
+
+fn default_region() -> String { "us-east-1".to_string() }
+
+/// An archived log file, as recorded in the index.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ArchivedLog {
+    /// File name, like `history.log.20240501_120000.gz`
+    pub name: String,
+    /// Time of the first entry, none for an empty file
+    pub first: Option<DateTime<Utc>>,
+    /// When the file was rotated, after its last entry
+    pub rotated: DateTime<Utc>,
+    /// Path of the archived file, or `s3://<bucket>/<key>`
+    pub location: String,
+}
+
+impl ArchivedLog {
+    /// Whether the file holds entries between `start` and `end`.
+    pub fn overlaps(&self, start: DateTime<Utc>, end: DateTime<Utc>) -> bool {
+        self.rotated >= start && self.first.is_some_and(|first| first <= end)
+    }
+}
+
+/// Move the log at `path`, rotated at `rotated`, to `target` and record it in
+/// the index of `log_dir`. The local file is only removed once it is stored.
+///
+/// Archiving a file again, e.g. after it could not be removed, stores it over
+/// the previous copy and keeps its existing index entry.
+pub async fn archive(
+    target: &ArchiveTarget,
+    log_dir: &Path,
+    path: &Path,
+    rotated: DateTime<Utc>,
+) -> Result<ArchivedLog> {
+    let name = path
+        .file_name()
+        .context("log file without a name")?
+        .to_string_lossy()
+        .into_owned();
+    let first = first_timestamp(path)?;
+
+    let location = match target {
+        ArchiveTarget::Dir { path: dir } => {
+            tokio::fs::create_dir_all(dir).await?;
+            // Copied rather than renamed, as the archive is usually on
+            // another file system
+            let archived = dir.join(&name);
+            tokio::fs::copy(path, &archived).await?;
+            archived.to_string_lossy().into_owned()
+        }
+        ArchiveTarget::S3(config) => {
+            let key = format!("{}{}", config.prefix, name);
+            let body = tokio::fs::read(path).await?;
+            S3Client::new(config)?.put(&config.bucket, &key, body).await?;
+            format!("s3://{}/{}", config.bucket, key)
+        }
+    };
+
+    let entry = ArchivedLog { name, first, rotated, location };
+    let indexed = read_index(log_dir)?
+        .iter()
+        .any(|other| other.name == entry.name && other.location == entry.location);
+    if indexed {
+        debug!(location = %entry.location, "Log file already in the archive index");
+    } else {
+        let mut line = serde_json::to_string(&entry)?;
+        line.push('\n');
+        let mut index = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(log_dir.join(INDEX_FILE))
+            .await?;
+        index.write_all(line.as_bytes()).await?;
+        index.sync_data().await?;
+    }
+    tokio::fs::remove_file(path).await?;
+
+    info!(path = %path.display(), location = %entry.location, "Archived old log file");
+    Ok(entry)
+}
+
+/// Time of the first entry of a log file.
+fn first_timestamp(path: &Path) -> Result<Option<DateTime<Utc>>> {
+    #[derive(Deserialize)]
+    struct Entry {
+        timestamp: DateTime<Utc>,
+    }
+
+    let content = read_log_file(path)?;
+    let Some(line) = content.lines().find(|line| !line.trim().is_empty()) else {
+        return Ok(None);
+    };
+    let entry: Entry = serde_json::from_str(line)
+        .with_context(|| format!("no timestamp in the first line of {}", path.display()))?;
+    Ok(Some(entry.timestamp))
+}
+
+/// Archived logs recorded in the index of `log_dir`, oldest first.
+pub fn read_index(log_dir: &Path) -> Result<Vec<ArchivedLog>> {
+    let content = match std::fs::read_to_string(log_dir.join(INDEX_FILE)) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut entries = content
+        .lines()
+        .map(serde_json::from_str)
+        .collect::<Result<Vec<ArchivedLog>, _>>()?;
+    entries.sort_by_key(|entry| entry.rotated);
+    Ok(entries)
+}
+
+/// Local paths of the archived logs of `log_dir` holding entries between
+/// `start` and `end`. Logs in an object store are downloaded to `scratch`.
+pub async fn fetch(
+    target: &ArchiveTarget,
+    log_dir: &Path,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    scratch: &Path,
+) -> Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for entry in read_index(log_dir)?.into_iter().filter(|entry| entry.overlaps(start, end)) {
+        paths.push(fetch_entry(target, &entry, scratch).await?);
+    }
+    Ok(paths)
+}
+
+/// Local path of the archived log `entry`, downloaded to `scratch` if it is
+/// in an object store.
+pub async fn fetch_entry(target: &ArchiveTarget, entry: &ArchivedLog, scratch: &Path) -> Result<PathBuf> {
+    let Some((config, bucket, key)) = object(target, entry)? else {
+        return Ok(PathBuf::from(&entry.location));
+    };
+    debug!(bucket, key, "Fetching archived log");
+    let body = S3Client::new(config)?.get(bucket, key).await?;
+    tokio::fs::create_dir_all(scratch).await?;
+    let local = scratch.join(&entry.name);
+    tokio::fs::write(&local, body).await?;
+    Ok(local)
+}
+
+/// Store `local`, the copy of the archived log `entry` that
+/// [`fetch_entry`] returned, back in the archive after it was changed.
+/// Logs archived to a directory were changed in place.
+pub async fn store_entry(target: &ArchiveTarget, entry: &ArchivedLog, local: &Path) -> Result<()> {
+    if let Some((config, bucket, key)) = object(target, entry)? {
+        debug!(bucket, key, "Storing archived log back");
+        S3Client::new(config)?.put(bucket, key, tokio::fs::read(local).await?).await?;
+    }
+    Ok(())
+}
+
+/// Bucket and key of the archived log `entry` with the configuration of
+/// its object store, if it is in one.
+fn object<'a>(target: &'a ArchiveTarget, entry: &'a ArchivedLog) -> Result<Option<(&'a S3Config, &'a str, &'a str)>> {
+    let Some(object) = entry.location.strip_prefix("s3://") else {
+        return Ok(None);
+    };
+    let ArchiveTarget::S3(config) = target else {
+        anyhow::bail!("{} is archived in an object store, but no S3 archive is configured", entry.name);
+    };
+    let (bucket, key) = object
+        .split_once('/')
+        .with_context(|| format!("invalid archive location {:?}", entry.location))?;
+    Ok(Some((config, bucket, key)))
+}
+
+/// Keys requests to an object store are signed with.
+struct Credentials {
+    access_key: String,
+    secret_key: String,
+    session_token: Option<String>,
+}
+
+impl Credentials {
+    fn from_env() -> Result<Self> {
+        Ok(Self {
+            access_key: std::env::var("AWS_ACCESS_KEY_ID").context("AWS_ACCESS_KEY_ID is not set")?,
+            secret_key: std::env::var("AWS_SECRET_ACCESS_KEY").context("AWS_SECRET_ACCESS_KEY is not set")?,
+            session_token: std::env::var("AWS_SESSION_TOKEN").ok(),
+        })
+    }
+}
+
+/// Just enough of an S3 client to store and read back objects.
+struct S3Client<'a> {
+    config: &'a S3Config,
+    credentials: Credentials,
+    http: reqwest::Client,
+}
+
+impl<'a> S3Client<'a> {
+    fn new(config: &'a S3Config) -> Result<Self> {
+        Ok(Self { config, credentials: Credentials::from_env()?, http: reqwest::Client::new() })
+    }
+
+    async fn put(&self, bucket: &str, key: &str, body: Vec<u8>) -> Result<()> {
+        self.send(reqwest::Method::PUT, bucket, key, body).await?;
+        Ok(())
+    }
+
+    async fn get(&self, bucket: &str, key: &str) -> Result<Vec<u8>> {
+        let response = self.send(reqwest::Method::GET, bucket, key, Vec::new()).await?;
+        Ok(response.bytes().await?.to_vec())
+    }
+
+    async fn send(&self, method: reqwest::Method, bucket: &str, key: &str, body: Vec<u8>) -> Result<reqwest::Response> {
+        let endpoint = self.config.endpoint.trim_end_matches('/');
+        let url = reqwest::Url::parse(&format!("{}/{}/{}", endpoint, bucket, uri_encode(key)))?;
+        let host = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            (None, _) => anyhow::bail!("no host in S3 endpoint {:?}", self.config.endpoint),
+        };
+        let payload_hash = hex(&Sha256::digest(&body));
+        let headers = sign(
+            &self.credentials,
+            &self.config.region,
+            method.as_str(),
+            &host,
+            url.path(),
+            &payload_hash,
+            Utc::now(),
+        );
+
+        let mut request = self.http.request(method.clone(), url).body(body);
+        for (name, value) in headers {
+            request = request.header(name, value);
+        }
+        let response = request.send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            let detail = response.text().await.unwrap_or_default();
+            anyhow::bail!("{} s3://{}/{} failed with {}: {}", method, bucket, key, status, detail.trim());
+        }
+        Ok(response)
+    }
+}
+
+/// Headers signing a request without query string for AWS Signature
+/// Version 4, including the `x-amz-*` headers they cover.
+fn sign(
+    credentials: &Credentials,
+    region: &str,
+    method: &str,
+    host: &str,
+    path: &str,
+    payload_hash: &str,
+    now: DateTime<Utc>,
+) -> Vec<(String, String)> {
+    let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+    let date = now.format("%Y%m%d").to_string();
+
+    let mut headers = vec![
+        ("host".to_string(), host.to_string()),
+        ("x-amz-content-sha256".to_string(), payload_hash.to_string()),
+        ("x-amz-date".to_string(), amz_date.clone()),
+    ];
+    if let Some(token) = &credentials.session_token {
+        headers.push(("x-amz-security-token".to_string(), token.clone()));
+    }
+    let canonical_headers: String = headers.iter().map(|(name, value)| format!("{}:{}\n", name, value)).collect();
+    let signed_headers = headers.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>().join(";");
+    let canonical_request = format!("{method}\n{path}\n\n{canonical_headers}\n{signed_headers}\n{payload_hash}");
+
+    let scope = format!("{date}/{region}/s3/aws4_request");
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{amz_date}\n{scope}\n{}",
+        hex(&Sha256::digest(canonical_request.as_bytes())),
+    );
+    let key = signing_key(&credentials.secret_key, &date, region, "s3");
+    let signature = hex(&hmac(&key, string_to_sign.as_bytes()));
+
+    headers.retain(|(name, _)| name != "host");
+    headers.push((
+        "authorization".to_string(),
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, Signature={signature}",
+            credentials.access_key,
+        ),
+    ));
+    headers
+}
+
+fn signing_key(secret_key: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    let key = hmac(format!("AWS4{secret_key}").as_bytes(), date.as_bytes());
+    let key = hmac(&key, region.as_bytes());
+    let key = hmac(&key, service.as_bytes());
+    hmac(&key, b"aws4_request")
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Percent-encode an object key as S3 expects in paths, keeping `/`.
+fn uri_encode(key: &str) -> String {
+    key.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => (byte as char).to_string(),
+            _ => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signing_key() {
+        // Example from the AWS Signature Version 4 documentation
+        let key = signing_key("wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY", "20120215", "us-east-1", "iam");
+        assert_eq!(hex(&key), "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d");
+        assert_eq!(uri_encode("logs/history.log 1"), "logs/history.log%201");
+    }
+
+    #[tokio::test]
+    async fn test_archive_to_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let (log_dir, archive_dir) = (dir.join("logs"), dir.join("archive"));
+        std::fs::create_dir_all(&log_dir).unwrap();
+        let path = log_dir.join("history.log.20240501_120000");
+        std::fs::write(&path, [
+            r#"{"timestamp":"2024-05-01T10:00:00Z","event_type":"Created","session_id":"a","user":"alice","display":100}"#,
+            r#"{"timestamp":"2024-05-01T11:00:00Z","event_type":"Terminated","session_id":"a","user":"alice","display":100}"#,
+        ].join("\n")).unwrap();
+
+        let target = ArchiveTarget::Dir { path: archive_dir.clone() };
+        let rotated = "2024-05-01T12:00:00Z".parse().unwrap();
+        let entry = archive(&target, &log_dir, &path, rotated).await.unwrap();
+        assert!(!path.exists());
+        assert_eq!(entry.first, Some("2024-05-01T10:00:00Z".parse().unwrap()));
+        assert_eq!(read_index(&log_dir).unwrap(), std::slice::from_ref(&entry));
+
+        // A file left behind after archiving is not indexed twice
+        std::fs::copy(archive_dir.join("history.log.20240501_120000"), &path).unwrap();
+        let again = archive(&target, &log_dir, &path, rotated).await.unwrap();
+        assert!(!path.exists());
+        assert_eq!(again.location, entry.location);
+        assert_eq!(read_index(&log_dir).unwrap(), [entry]);
+
+        let scratch = dir.join("scratch");
+        let fetch_period = |start: &str, end: &str| {
+            let (start, end) = (start.parse().unwrap(), end.parse().unwrap());
+            fetch(&target, &log_dir, start, end, &scratch)
+        };
+        let archived = fetch_period("2024-05-01T11:30:00Z", "2024-05-02T00:00:00Z").await.unwrap();
+        assert_eq!(archived, [archive_dir.join("history.log.20240501_120000")]);
+        assert!(fetch_period("2024-05-01T12:30:00Z", "2024-05-02T00:00:00Z").await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,667 @@
+//! Verification, export and redaction of the history log.
+//!
+//! Every line of the history log carries a `hash` chaining it to the line
+//! before it: the SHA-256 of the previous line's hash, a newline, and the
+//! hex SHA-256 of the line without its hash. The chain runs on across
+//! rotated and archived files, so a line changed or removed in any of them
+//! breaks it at that point.
+//!
+//! Redacting a user for a data deletion request replaces each of their
+//! events with a tombstone: the event without the user, labels, names or
+//! parent shell, with `redacted` set to the SHA-256 of the original line and
+//! keeping its hash. The tombstone stays in the chain, which verifies
+//! through it from the digest it commits to.
+//!
+//! Events also carry a `seq`, numbering the events of the host's history
+//! log from 1 without reuse. The last number is kept in `history.seq` next
+//! to the log, so it survives restarts and pruned logs; should that file be
+//! lost, numbering continues from the last line of the log. Billing and
+//! accounting systems ingesting the log tell missed events by a gap in the
+//! numbers and events ingested twice by a number they already have, and
+//! `ssh-desktop audit export --format records` keys every event by its host
+//! and number for them.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+
+use crate::xpra_archive::{self, ArchiveTarget};
+use crate::xpra_labels::Labels;
+use crate::xpra_log_analyzer::{log_files, read_log_file, with_archived, LogAnalyzer};
+use crate::xpra_log_schema::{self, LogSchema};
+use crate::xpra_logger::SessionEvent;
+
+/// Name of the history log in the log directory.
+const HISTORY_LOG: &str = "history.log";
+
+/// Name of the file keeping the last event number, in the log directory.
+const SEQUENCE_FILE: &str = "history.seq";
+
+/// Marks of a history line besides the event itself.
+#[derive(Debug, Default, Deserialize)]
+struct Marks {
+    /// Digest of the event a tombstone replaced
+    #[serde(default)]
+    redacted: Option<String>,
+}
+
+/// Hex SHA-256 of `data`.
+fn sha256(data: &str) -> String {
+    Sha256::digest(data.as_bytes()).iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Hash of the history line with `digest`, the [`sha256`] of the line
+/// without its own hash, following the line hashed to `prev`.
+fn chain_hash(prev: &str, digest: &str) -> String {
+    sha256(&format!("{prev}\n{digest}"))
+}
+
+/// Split a history line into the line without its hash and the hash, if it
+/// has one.
+fn split_hash(line: &str) -> Option<(String, &str)> {
+    let (body, hash) = line.strip_suffix("\"}")?.rsplit_once(",\"hash\":\"")?;
+    let valid = hash.len() == 64 && hash.bytes().all(|byte| byte.is_ascii_hexdigit());
+    valid.then(|| (format!("{body}}}"), hash))
+}
+
+/// Add `field` as the last field of the JSON object `line`.
+fn append_field(line: &str, field: &str) -> String {
+    let body = line.strip_suffix('}').unwrap_or(line);
+    format!("{body},{field}}}")
+}
+
+/// Serialize `event` as a history line following the line hashed to
+/// `prev`, returning the line and its hash.
+pub(crate) fn seal(prev: &str, event: &SessionEvent) -> Result<(String, String)> {
+    let body = xpra_log_schema::to_line(event)?;
+    let hash = chain_hash(prev, &sha256(&body));
+    let line = append_field(&body, &format!("\"hash\":\"{hash}\""));
+    Ok((line, hash))
+}
+
+/// Last line of the history log in `log_dir`, rotated files included.
+fn last_line(log_dir: &Path) -> Result<Option<String>> {
+    for path in log_files(log_dir, HISTORY_LOG)?.iter().rev() {
+        if let Some(line) = read_log_file(path)?.lines().last() {
+            return Ok(Some(line.to_string()));
+        }
+    }
+    Ok(None)
+}
+
+/// Hash of the last line of the history log in `log_dir`, for the next line
+/// to follow. Empty if there is none, or it has no hash.
+pub(crate) fn last_hash(log_dir: &Path) -> String {
+    match last_line(log_dir) {
+        Ok(line) => line.as_deref().and_then(split_hash).map(|(_, hash)| hash.to_string()).unwrap_or_default(),
+        Err(e) => {
+            warn!(log_dir = %log_dir.display(), "Cannot read the history log, starting a new hash chain: {:#}", e);
+            String::new()
+        }
+    }
+}
+
+/// Number of the last event logged in `log_dir`, for the next event to
+/// follow: the highest of the sequence file and the last line of the
+/// history log, 0 if neither has one.
+pub(crate) fn last_seq(log_dir: &Path) -> u64 {
+    let saved = std::fs::read_to_string(log_dir.join(SEQUENCE_FILE)).ok().and_then(|seq| seq.trim().parse().ok());
+    let logged = match last_line(log_dir) {
+        Ok(line) => line.and_then(|line| LogSchema::History.parse::<SessionEvent>(&line).ok()).and_then(|event| event.seq),
+        Err(e) => {
+            warn!(log_dir = %log_dir.display(), "Cannot read the history log for the last event number: {:#}", e);
+            None
+        }
+    };
+    saved.max(logged).unwrap_or(0)
+}
+
+/// Keep `seq` as the number of the last event logged in `log_dir`, on disk
+/// by the time this returns. Blocks on the file system.
+pub(crate) fn save_seq(log_dir: &Path, seq: u64) -> io::Result<()> {
+    let path = log_dir.join(SEQUENCE_FILE);
+    let partial_path = log_dir.join(format!("{SEQUENCE_FILE}.partial"));
+    std::fs::create_dir_all(log_dir)?;
+    let mut file = File::create(&partial_path)?;
+    writeln!(file, "{seq}")?;
+    file.sync_all()?;
+    std::fs::rename(&partial_path, path)?;
+    // The rename only survives a crash once the directory is synced
+    File::open(log_dir)?.sync_all()
+}
+
+/// The history line `line` as a record for billing and accounting systems:
+/// the event with the `source` that logged it and an `event_id` unique
+/// across sources, `<source>:<seq>`, or `<source>:<hash>` for events logged
+/// before they were numbered.
+pub fn export_record(line: &str, source: &str) -> Result<String> {
+    let Value::Object(mut fields) = serde_json::from_str(line)? else {
+        anyhow::bail!("history line is not a JSON object");
+    };
+    let key = match (fields.get("seq").and_then(Value::as_u64), split_hash(line)) {
+        (Some(seq), _) => seq.to_string(),
+        (None, Some((_, hash))) => hash.to_string(),
+        (None, None) => anyhow::bail!("history line has neither a number nor a hash"),
+    };
+    let mut record = Map::new();
+    record.insert("event_id".to_string(), Value::from(format!("{source}:{key}")));
+    record.insert("source".to_string(), Value::from(source));
+    record.append(&mut fields);
+    Ok(serde_json::to_string(&record)?)
+}
+
+/// Tombstone replacing `line` if it is an event of `user` not yet redacted.
+/// Lines that are no events are left for [`HistoryAudit::verify`] to report.
+pub(crate) fn redact_line(line: &str, user: &str) -> Option<String> {
+    let event: SessionEvent = LogSchema::History.parse(line).ok()?;
+    let marks: Marks = serde_json::from_str(line).ok()?;
+    if event.user != user || marks.redacted.is_some() {
+        return None;
+    }
+    let body = split_hash(line).map_or_else(|| line.to_string(), |(body, _)| body);
+    let tombstone = SessionEvent {
+        timestamp: event.timestamp,
+        seq: event.seq,
+        event_type: event.event_type,
+        session_id: event.session_id,
+        user: String::new(),
+        display: event.display,
+        labels: Labels::new(),
+        state: event.state,
+        reason: event.reason,
+        crash_bundle: None,
+        name: None,
+        description: None,
+        parent: None,
+        device: event.device,
+        traffic: event.traffic,
+        host: event.host,
+    };
+    let digest = format!("\"redacted\":\"{}\"", sha256(&body));
+    let mut tombstone = append_field(&xpra_log_schema::to_line(&tombstone).ok()?, &digest);
+    if let Some((_, hash)) = split_hash(line) {
+        tombstone = append_field(&tombstone, &format!("\"hash\":\"{hash}\""));
+    }
+    Some(tombstone)
+}
+
+/// Write `content` over the log file at `path`, compressed like its
+/// extension says. It is written under a hidden name first and renamed over
+/// the file, so readers see either the old or the new content.
+fn rewrite_log_file(path: &Path, content: &str) -> Result<()> {
+    let file_name = path.file_name().ok_or_else(|| anyhow::anyhow!("log file without a name"))?;
+    let partial_path = path.with_file_name(format!(".{}.redacted", file_name.to_string_lossy()));
+
+    let output = BufWriter::new(File::create(&partial_path)?);
+    let output = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("gz") => {
+            let mut encoder = flate2::write::GzEncoder::new(output, flate2::Compression::default());
+            encoder.write_all(content.as_bytes())?;
+            encoder.finish()?
+        }
+        Some("zst") => {
+            let mut encoder = zstd::stream::write::Encoder::new(output, 0)?;
+            encoder.write_all(content.as_bytes())?;
+            encoder.finish()?
+        }
+        _ => {
+            let mut output = output;
+            output.write_all(content.as_bytes())?;
+            output
+        }
+    };
+    output.into_inner().map_err(io::IntoInnerError::into_error)?.sync_all()?;
+    std::fs::rename(&partial_path, path)?;
+    Ok(())
+}
+
+/// Replace every event of `user` in the history log in `log_dir` and its
+/// rotated files with a tombstone. Whoever writes the history log must not
+/// write or rotate it meanwhile.
+pub(crate) fn redact_files(log_dir: &Path, user: &str) -> Result<RedactReport> {
+    let mut report = RedactReport::default();
+    for path in log_files(log_dir, HISTORY_LOG)? {
+        report.add(redact_file(&path, user)?);
+    }
+    Ok(report)
+}
+
+/// Replace every event of `user` in the history logs of `log_dir` archived
+/// to `target` with a tombstone, storing the files changed back.
+pub(crate) async fn redact_archived(target: &ArchiveTarget, log_dir: &Path, user: &str) -> Result<RedactReport> {
+    let scratch = tempfile::tempdir()?;
+    let mut report = RedactReport::default();
+    let entries = xpra_archive::read_index(log_dir)?;
+    for entry in entries.iter().filter(|entry| entry.name.starts_with(HISTORY_LOG)) {
+        let path = xpra_archive::fetch_entry(target, entry, scratch.path()).await?;
+        let redacted = {
+            let (path, user) = (path.clone(), user.to_string());
+            tokio::task::spawn_blocking(move || redact_file(&path, &user)).await??
+        };
+        if redacted > 0 {
+            xpra_archive::store_entry(target, entry, &path).await?;
+        }
+        report.add(redacted);
+    }
+    Ok(report)
+}
+
+/// Replace every event of `user` in the history log file at `path` with a
+/// tombstone, returning how many were.
+fn redact_file(path: &Path, user: &str) -> Result<usize> {
+    let mut redacted = 0;
+    let mut content = String::new();
+    for line in read_log_file(path)?.lines() {
+        match redact_line(line, user) {
+            Some(tombstone) => {
+                content.push_str(&tombstone);
+                redacted += 1;
+            }
+            None => content.push_str(line),
+        }
+        content.push('\n');
+    }
+    if redacted > 0 {
+        rewrite_log_file(path, &content)?;
+        info!(path = %path.display(), redacted, "Redacted history events");
+    }
+    Ok(redacted)
+}
+
+/// Result of redacting a user from the history log.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct RedactReport {
+    /// Number of files rewritten
+    pub files: usize,
+    /// Number of events replaced with tombstones
+    pub events: usize,
+}
+
+impl RedactReport {
+    /// Count a file with `redacted` events replaced, if any were.
+    fn add(&mut self, redacted: usize) {
+        self.files += usize::from(redacted > 0);
+        self.events += redacted;
+    }
+
+    /// Count the files and events of `other` too.
+    pub fn merge(&mut self, other: RedactReport) {
+        self.files += other.files;
+        self.events += other.events;
+    }
+}
+
+/// Problem found in a line of the history log.
+#[derive(Debug, Serialize)]
+pub struct AuditIssue {
+    /// File holding the line
+    pub file: PathBuf,
+    /// Number of the line in the file, from 1
+    pub line: usize,
+    /// What is wrong with it
+    pub message: String,
+}
+
+/// Result of verifying the history log.
+#[derive(Debug, Default, Serialize)]
+pub struct VerifyReport {
+    /// Number of files read, rotated ones included
+    pub files: usize,
+    /// Number of lines read
+    pub events: usize,
+    /// Lines carrying a hash
+    pub hashed: usize,
+    /// Lines carrying an event number
+    pub numbered: usize,
+    /// Tombstones of redacted events
+    pub redacted: usize,
+    /// Problems found, in the order of the log
+    pub issues: Vec<AuditIssue>,
+}
+
+impl VerifyReport {
+    /// Whether no problem was found
+    pub fn is_intact(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+/// Reads the history log in a directory, and its rotated and archived
+/// files, for an audit.
+pub struct HistoryAudit {
+    log_dir: PathBuf,
+    analyzer: LogAnalyzer,
+    archive: Option<ArchiveTarget>,
+}
+
+impl HistoryAudit {
+    /// Create an audit of the history log in `log_dir`.
+    pub fn new(log_dir: PathBuf) -> Self {
+        Self { analyzer: LogAnalyzer::new(log_dir.clone()), log_dir, archive: None }
+    }
+
+    /// Also read the files of the history log archived to `target`.
+    pub fn with_archive(mut self, target: ArchiveTarget) -> Self {
+        self.archive = Some(target);
+        self
+    }
+
+    /// Files of the history log, oldest first, with the archived ones
+    /// downloaded to `scratch` if they are in an object store.
+    async fn files(&self, scratch: &Path) -> Result<Vec<PathBuf>> {
+        let mut archived = Vec::new();
+        if let Some(target) = &self.archive {
+            for entry in xpra_archive::read_index(&self.log_dir)? {
+                if entry.name.starts_with(HISTORY_LOG) {
+                    archived.push(xpra_archive::fetch_entry(target, &entry, scratch).await?);
+                }
+            }
+        }
+        Ok(with_archived(log_files(&self.log_dir, HISTORY_LOG)?, &archived, HISTORY_LOG))
+    }
+
+    /// Check that every line of the history log is an event, that the hash
+    /// chain is unbroken and that event numbers only go up. The first hashed
+    /// line read starts the chain, as the files before it may have been
+    /// pruned, and lines logged before the log was hashed are only accepted
+    /// before the first hashed one. Gaps in the numbers are no problem of
+    /// the log: they are left by events lost in a crash.
+    pub async fn verify(&self) -> Result<VerifyReport> {
+        let scratch = tempfile::tempdir()?;
+        let files = self.files(scratch.path()).await?;
+        let contents = self.analyzer.read_files(files.clone(), read_log_file).await?;
+
+        let mut report = VerifyReport { files: files.len(), ..VerifyReport::default() };
+        // Hash of the previous line, if known
+        let mut prev: Option<String> = None;
+        let mut hashed_seen = false;
+        // Number of the previous numbered line
+        let mut last_seq: Option<u64> = None;
+        for (path, content) in files.iter().zip(contents) {
+            for (index, line) in content.lines().enumerate() {
+                let mut issue = |message: String| {
+                    report.issues.push(AuditIssue { file: path.clone(), line: index + 1, message });
+                };
+                match LogSchema::History.parse::<SessionEvent>(line) {
+                    Ok(SessionEvent { seq: Some(seq), .. }) => {
+                        if let Some(last) = last_seq.filter(|last| *last >= seq) {
+                            issue(format!("event number {seq} follows {last}: the event was logged twice or out of order"));
+                        }
+                        report.numbered += 1;
+                        last_seq = Some(seq);
+                    }
+                    Ok(_) => {}
+                    Err(e) => issue(format!("not a history event: {e}")),
+                }
+                let marks: Marks = serde_json::from_str(line).unwrap_or_default();
+                match split_hash(line) {
+                    None if hashed_seen => issue("line has no hash".to_string()),
+                    None => prev = Some(String::new()),
+                    Some((body, hash)) => {
+                        // Tombstones commit to the digest of the line they replaced
+                        let digest = marks.redacted.clone().unwrap_or_else(|| sha256(&body));
+                        if prev.as_deref().is_some_and(|prev| chain_hash(prev, &digest) != hash) {
+                            issue("hash does not match: the line was changed, or one before it removed".to_string());
+                        }
+                        report.hashed += 1;
+                        report.redacted += usize::from(marks.redacted.is_some());
+                        hashed_seen = true;
+                        prev = Some(hash.to_string());
+                    }
+                }
+                report.events += 1;
+            }
+        }
+        Ok(report)
+    }
+
+    /// Lines of the history log from `since` and before `until`, of `user`
+    /// and numbered after `after_seq` if given, as they were logged and
+    /// oldest first. Lines that are no events are skipped with a warning,
+    /// for [`Self::verify`] to report.
+    pub async fn export(
+        &self,
+        since: Option<DateTime<Utc>>,
+        until: Option<DateTime<Utc>>,
+        user: Option<&str>,
+        after_seq: Option<u64>,
+    ) -> Result<Vec<String>> {
+        let scratch = tempfile::tempdir()?;
+        let files = self.files(scratch.path()).await?;
+        let user = user.map(str::to_string);
+        let parsed = self.analyzer.read_files(files, move |path| {
+            let mut lines = Vec::new();
+            for (index, line) in read_log_file(path)?.lines().enumerate() {
+                let event: SessionEvent = match LogSchema::History.parse(line) {
+                    Ok(event) => event,
+                    Err(e) => {
+                        warn!(path = %path.display(), line = index + 1, "Skipping unreadable history event: {:#}", e);
+                        continue;
+                    }
+                };
+                let in_period = since.is_none_or(|since| event.timestamp >= since)
+                    && until.is_none_or(|until| event.timestamp < until);
+                let after = after_seq.is_none_or(|after| event.seq.is_some_and(|seq| seq > after));
+                if in_period && after && user.as_ref().is_none_or(|user| event.user == *user) {
+                    lines.push((event.timestamp, line.to_string()));
+                }
+            }
+            Ok(lines)
+        }).await?;
+        // Files are read oldest first, so lines logged at the same time keep
+        // their order
+        let mut lines: Vec<(DateTime<Utc>, String)> = parsed.into_iter().flatten().collect();
+        lines.sort_by_key(|(timestamp, _)| *timestamp);
+        Ok(lines.into_iter().map(|(_, line)| line).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::xpra_logger::SessionEventType;
+
+    fn event(minute: u32, user: &str) -> SessionEvent {
+        SessionEvent {
+            timestamp: format!("2024-05-01T10:{minute:02}:00Z").parse().unwrap(),
+            seq: Some(u64::from(minute) + 1),
+            event_type: SessionEventType::Created,
+            session_id: format!("s{minute}"),
+            user: user.to_string(),
+            display: 10,
+            labels: Labels::new(),
+            state: None,
+            reason: None,
+            crash_bundle: None,
+            name: None,
+            description: None,
+            parent: None,
+            device: None,
+            traffic: None,
+            host: None,
+        }
+    }
+
+    /// Write `events` as a hash chain, split into a rotated and the current
+    /// history log.
+    fn write_chain(dir: &Path, events: &[SessionEvent]) {
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir_all(dir).unwrap();
+        let mut prev = String::new();
+        let mut lines = Vec::new();
+        for event in events {
+            let (line, hash) = seal(&prev, event).unwrap();
+            lines.push(line + "\n");
+            prev = hash;
+        }
+        let (rotated, current) = lines.split_at(lines.len() / 2);
+        std::fs::write(dir.join("history.log.20240501_100000"), rotated.concat()).unwrap();
+        std::fs::write(dir.join("history.log"), current.concat()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_verify_detects_tampering() {
+        let dir = std::env::temp_dir().join(format!("sshx-audit-verify-{}", std::process::id()));
+        let events: Vec<SessionEvent> = (0..6).map(|minute| event(minute, "alice")).collect();
+        write_chain(&dir, &events);
+        assert_eq!(last_hash(&dir).len(), 64);
+
+        let audit = HistoryAudit::new(dir.clone());
+        let report = audit.verify().await.unwrap();
+        assert!(report.is_intact(), "{:?}", report.issues);
+        assert_eq!((report.files, report.events, report.hashed), (2, 6, 6));
+
+        // Change a user in the rotated file and remove a line of the current
+        let path = dir.join("history.log.20240501_100000");
+        let rotated = std::fs::read_to_string(&path).unwrap();
+        let mut rotated: Vec<String> = rotated.lines().map(str::to_string).collect();
+        rotated[1] = rotated[1].replace("\"user\":\"alice\"", "\"user\":\"mallory\"");
+        std::fs::write(&path, rotated.join("\n") + "\n").unwrap();
+        let path = dir.join("history.log");
+        let current = std::fs::read_to_string(&path).unwrap();
+        let current: Vec<&str> = current.lines().collect();
+        std::fs::write(&path, format!("{}\n{}\n", current[0], current[2])).unwrap();
+
+        let report = audit.verify().await.unwrap();
+        let issues: Vec<(&str, usize)> = report
+            .issues
+            .iter()
+            .map(|issue| (issue.file.file_name().unwrap().to_str().unwrap(), issue.line))
+            .collect();
+        assert_eq!(issues, [("history.log.20240501_100000", 2), ("history.log", 2)]);
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn test_redact_keeps_chain() {
+        let dir = std::env::temp_dir().join(format!("sshx-audit-redact-{}", std::process::id()));
+        let mut events: Vec<SessionEvent> =
+            (0..6).map(|minute| event(minute, if minute % 2 == 0 { "alice" } else { "bob" })).collect();
+        events[2].labels.insert("team".to_string(), "ml".to_string());
+        write_chain(&dir, &events);
+
+        let report = redact_files(&dir, "alice").unwrap();
+        assert_eq!((report.files, report.events), (2, 3));
+        // Redacting again finds nothing left
+        assert_eq!(redact_files(&dir, "alice").unwrap().events, 0);
+
+        let audit = HistoryAudit::new(dir.clone());
+        let report = audit.verify().await.unwrap();
+        assert!(report.is_intact(), "{:?}", report.issues);
+        assert_eq!((report.hashed, report.redacted), (6, 3));
+
+        let lines = audit.export(None, None, None, None).await.unwrap();
+        assert_eq!(lines.len(), 6);
+        assert!(!lines.iter().any(|line| line.contains("alice") || line.contains("team")));
+        let tombstone: serde_json::Value = serde_json::from_str(&lines[2]).unwrap();
+        assert_eq!(tombstone["session_id"], "s2");
+        assert_eq!(tombstone["user"], "");
+        assert_eq!(tombstone["redacted"].as_str().map(str::len), Some(64));
+        assert_eq!(tombstone["seq"], 3);
+
+        // A tombstone must commit to the line it replaced
+        let path = dir.join("history.log");
+        let current = std::fs::read_to_string(&path).unwrap();
+        let current_tombstone: serde_json::Value = serde_json::from_str(&lines[4]).unwrap();
+        let digest = current_tombstone["redacted"].as_str().unwrap();
+        std::fs::write(&path, current.replace(digest, &"0".repeat(64))).unwrap();
+        let report = audit.verify().await.unwrap();
+        assert_eq!(report.issues.len(), 1, "{:?}", report.issues);
+        std::fs::write(&path, current.replace(&format!("\"redacted\":\"{digest}\""), "\"redacted\":true")).unwrap();
+        assert_eq!(audit.verify().await.unwrap().issues.len(), 1);
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn test_archived_files() {
+        let dir = std::env::temp_dir().join(format!("sshx-audit-archived-{}", std::process::id()));
+        let log_dir = dir.join("logs");
+        let events: Vec<SessionEvent> = (0..6).map(|minute| event(minute, "alice")).collect();
+        write_chain(&log_dir, &events);
+        let target = ArchiveTarget::Dir { path: dir.join("archive") };
+        let rotated = log_dir.join("history.log.20240501_100000");
+        xpra_archive::archive(&target, &log_dir, &rotated, Utc::now()).await.unwrap();
+
+        let audit = HistoryAudit::new(log_dir.clone()).with_archive(target.clone());
+        let report = audit.verify().await.unwrap();
+        assert!(report.is_intact(), "{:?}", report.issues);
+        assert_eq!((report.files, report.events), (2, 6));
+        assert_eq!(audit.export(None, None, None, None).await.unwrap().len(), 6);
+
+        let report = redact_archived(&target, &log_dir, "alice").await.unwrap();
+        assert_eq!((report.files, report.events), (1, 3));
+        let archived = std::fs::read_to_string(dir.join("archive/history.log.20240501_100000")).unwrap();
+        assert!(!archived.contains("alice"));
+        assert!(audit.verify().await.unwrap().is_intact());
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn test_export_filters_and_merges() {
+        let dir = std::env::temp_dir().join(format!("sshx-audit-export-{}", std::process::id()));
+        let events: Vec<SessionEvent> =
+            (0..6).map(|minute| event(minute, if minute < 4 { "alice" } else { "bob" })).collect();
+        write_chain(&dir, &events);
+
+        let audit = HistoryAudit::new(dir.clone());
+        let since = "2024-05-01T10:01:00Z".parse().ok();
+        let until = "2024-05-01T10:05:00Z".parse().ok();
+        let sessions = |lines: Vec<String>| -> Vec<String> {
+            lines
+                .iter()
+                .map(|line| serde_json::from_str::<SessionEvent>(line).unwrap().session_id)
+                .collect()
+        };
+        assert_eq!(sessions(audit.export(since, until, None, None).await.unwrap()), ["s1", "s2", "s3", "s4"]);
+        assert_eq!(sessions(audit.export(since, until, Some("alice"), None).await.unwrap()), ["s1", "s2", "s3"]);
+        assert_eq!(sessions(audit.export(None, None, None, Some(4)).await.unwrap()), ["s4", "s5"]);
+        // Lines that are no events are skipped
+        let path = dir.join("history.log");
+        let current = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, format!("{{\"timestamp\":\n{current}")).unwrap();
+        assert_eq!(sessions(audit.export(None, None, None, Some(4)).await.unwrap()), ["s4", "s5"]);
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn test_numbered_events() {
+        let dir = std::env::temp_dir().join(format!("sshx-audit-numbered-{}", std::process::id()));
+        let mut events: Vec<SessionEvent> = (0..4).map(|minute| event(minute, "alice")).collect();
+        // An event logged twice
+        events[3].seq = Some(3);
+        write_chain(&dir, &events);
+        assert_eq!(last_seq(&dir), 3);
+        // The sequence file is ahead of a log missing its last events
+        save_seq(&dir, 7).unwrap();
+        assert_eq!(last_seq(&dir), 7);
+        assert!(!dir.join(format!("{SEQUENCE_FILE}.partial")).exists());
+
+        let audit = HistoryAudit::new(dir.clone());
+        let report = audit.verify().await.unwrap();
+        assert_eq!(report.numbered, 4);
+        let issues: Vec<(usize, &str)> = report.issues.iter().map(|issue| (issue.line, issue.message.as_str())).collect();
+        assert_eq!(issues, [(2, "event number 3 follows 3: the event was logged twice or out of order")]);
+
+        let lines = audit.export(None, None, None, Some(1)).await.unwrap();
+        let record: Value = serde_json::from_str(&export_record(&lines[0], "worker1").unwrap()).unwrap();
+        assert_eq!((record["event_id"].as_str(), record["source"].as_str()), (Some("worker1:2"), Some("worker1")));
+        assert_eq!(record["session_id"], "s1");
+        // Events logged before they were numbered are keyed by their hash
+        let (unnumbered, hash) = seal("", &SessionEvent { seq: None, ..event(9, "bob") }).unwrap();
+        let record: Value = serde_json::from_str(&export_record(&unnumbered, "worker1").unwrap()).unwrap();
+        assert_eq!(record["event_id"], format!("worker1:{hash}"));
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
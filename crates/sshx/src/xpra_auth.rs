@@ -0,0 +1,259 @@
+//! Token authentication of the forwarder to xpra.
+//!
+//! A display's websocket listens on a TCP port any local user can connect
+//! to. With `socket_auth` set, xpra only accepts connections answering its
+//! challenge with a random token generated for the display. The token is
+//! handed to xpra on its standard input, never on a command line, is only
+//! held in memory, and is overwritten once the display is gone. The window
+//! manager and app are started without it in their environment.
+//!
+//! The web client does not know the token, so the forwarder answers for it:
+//! it keeps the `hello` the client opens a connection with and, when xpra
+//! answers that with a `challenge`, sends the hello again with the response
+//! added, keeping the challenge from the client.
+
+use std::fmt;
+use std::io::Write;
+use std::sync::atomic::{compiler_fence, Ordering};
+use anyhow::{Context, Result};
+use hmac::{Hmac, Mac};
+use sha2::{Sha256, Sha512};
+use sshx_core::rand_alphanumeric;
+
+use crate::xpra_protocol::{add_to_hello, packet_len, Challenge};
+
+/// Variable of xpra's environment holding the token.
+pub const TOKEN_VAR: &str = "SSHX_XPRA_TOKEN";
+
+/// Characters in a token.
+const TOKEN_LEN: usize = 32;
+
+/// Shortest salt of the client; xpra refuses shorter ones.
+const MIN_SALT_LEN: usize = 32;
+
+/// Secret a display's xpra accepts connections with.
+#[derive(Clone)]
+pub struct SessionToken(Vec<u8>);
+
+impl SessionToken {
+    /// A new random token.
+    pub fn generate() -> Self {
+        Self(rand_alphanumeric(TOKEN_LEN).into_bytes())
+    }
+
+    /// Command and arguments that read a token from standard input into
+    /// the environment of a program.
+    pub fn command_prefix() -> Vec<String> {
+        vec![
+            "sh".to_string(),
+            "-c".to_string(),
+            format!("IFS= read -r {TOKEN_VAR}; export {TOKEN_VAR}; exec \"$@\""),
+            "sh".to_string(),
+        ]
+    }
+
+    /// Like [`Self::command_prefix`], for a program run by `ssh -T`. Without
+    /// a tty the program gets no SIGHUP when ssh is killed, so it is
+    /// terminated once standard input closes, and the tty cannot echo the
+    /// token into the program's output.
+    pub fn ssh_command_prefix() -> Vec<String> {
+        vec![
+            "sh".to_string(),
+            "-c".to_string(),
+            format!(
+                "exec 3<&0; IFS= read -r {TOKEN_VAR} <&3; export {TOKEN_VAR}; \"$@\" & pid=$!; unset {TOKEN_VAR}; \
+                 {{ cat <&3 >/dev/null 2>&1; kill $pid; }} & wait $pid; status=$?; kill $! 2>/dev/null; exit $status"
+            ),
+            "sh".to_string(),
+        ]
+    }
+
+    /// Start of a command line running a program without the token in its
+    /// environment.
+    pub fn unset_prefix() -> String {
+        format!("env -u {TOKEN_VAR} ")
+    }
+
+    /// Write the token as a line, for [`Self::command_prefix`] to read.
+    pub fn write_line(&self, mut out: impl Write) -> std::io::Result<()> {
+        out.write_all(&self.0)?;
+        out.write_all(b"\n")
+    }
+}
+
+impl Drop for SessionToken {
+    fn drop(&mut self) {
+        self.0.fill(0);
+        // Keep the overwrite from being optimized away with the buffer
+        compiler_fence(Ordering::SeqCst);
+    }
+}
+
+impl fmt::Debug for SessionToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SessionToken(..)")
+    }
+}
+
+/// Login of one client connection to a display requiring a token.
+#[derive(Debug)]
+pub struct XpraLogin {
+    token: SessionToken,
+    /// First packet of the client, or as much of it as arrived
+    hello: Vec<u8>,
+    answered: bool,
+}
+
+impl XpraLogin {
+    /// Log clients in with `token`.
+    pub fn new(token: SessionToken) -> Self {
+        Self { token, hello: Vec::new(), answered: false }
+    }
+
+    /// Start a new connection to xpra.
+    pub fn reset(&mut self) {
+        self.hello.clear();
+        self.answered = false;
+    }
+
+    /// Keep what the client sends until its first packet, its hello, is
+    /// complete.
+    pub fn client_data(&mut self, data: &[u8]) {
+        let wanted = packet_len(&self.hello).unwrap_or(usize::MAX);
+        if self.hello.len() < wanted {
+            let n = (wanted - self.hello.len()).min(data.len());
+            self.hello.extend_from_slice(&data[..n]);
+        }
+    }
+
+    /// The client's hello with the response to `challenge`, to send to
+    /// xpra in the client's place.
+    pub fn answer(&mut self, challenge: &Challenge) -> Result<Vec<u8>> {
+        if self.answered {
+            anyhow::bail!("xpra did not accept the display's token");
+        }
+        let Some(len) = packet_len(&self.hello).filter(|len| self.hello.len() >= *len) else {
+            anyhow::bail!("xpra sent a challenge before the client's hello");
+        };
+        let client_salt = rand_alphanumeric(challenge.salt.len().max(MIN_SALT_LEN)).into_bytes();
+        let salt = digest(&challenge.salt_digest, &client_salt, &challenge.salt)?;
+        let response = digest(&challenge.digest, &self.token.0, &salt)?;
+        let entries: [(&str, &[u8]); 2] = [("challenge_response", &response), ("challenge_client_salt", &client_salt)];
+        let hello = add_to_hello(&self.hello[..len], &entries).context("the client's hello cannot be read")?;
+        self.answered = true;
+        Ok(hello)
+    }
+}
+
+/// xpra's digest `name` of `message` under `key`.
+fn digest(name: &str, key: &[u8], message: &[u8]) -> Result<Vec<u8>> {
+    match name {
+        // The message is cut or padded with zeros to the length of the key
+        "xor" => Ok(key.iter().enumerate().map(|(i, byte)| byte ^ message.get(i).copied().unwrap_or(0)).collect()),
+        "hmac+sha256" => Ok(hmac_hex::<Hmac<Sha256>>(key, message)),
+        "hmac+sha512" => Ok(hmac_hex::<Hmac<Sha512>>(key, message)),
+        _ => anyhow::bail!("xpra asked for the unsupported digest {name:?}"),
+    }
+}
+
+fn hmac_hex<M: Mac + hmac::digest::KeyInit>(key: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut mac = <M as Mac>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(message);
+    let hex: String = mac.finalize().into_bytes().iter().map(|byte| format!("{byte:02x}")).collect();
+    hex.into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_digest() {
+        // RFC 4231 test case 2
+        let expected = b"5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843";
+        assert_eq!(digest("hmac+sha256", b"Jefe", b"what do ya want for nothing?").unwrap(), expected);
+        assert_eq!(digest("xor", b"ab", b"\x01\x02\x03").unwrap(), b"``");
+        assert_eq!(digest("xor", b"ab", b"").unwrap(), b"ab");
+        assert!(digest("des", b"key", b"salt").is_err());
+    }
+
+    #[test]
+    fn test_answer() {
+        let token = SessionToken(b"secret".to_vec());
+        assert_eq!(format!("{token:?}"), "SessionToken(..)");
+        let mut login = XpraLogin::new(token);
+        let challenge = Challenge { salt: vec![b's'; 64], digest: "hmac+sha256".to_string(), salt_digest: "xor".to_string() };
+        assert!(login.answer(&challenge).is_err());
+
+        // A bencoded hello, arriving in pieces, followed by other packets
+        let payload = b"l5:hellod7:versioni6eee";
+        let mut hello = vec![b'P', 0, 0, 0];
+        hello.extend((payload.len() as u32).to_be_bytes());
+        hello.extend_from_slice(payload);
+        login.client_data(&hello[..5]);
+        login.client_data(&[&hello[5..], b"Pmore"].concat());
+        let answer = login.answer(&challenge).unwrap();
+        let text = String::from_utf8_lossy(&answer);
+        assert!(text.contains("7:versioni6e18:challenge_response64:"));
+        assert!(text.contains("21:challenge_client_salt64:"));
+        assert_eq!(packet_len(&answer), Some(answer.len()));
+
+        // A second challenge means the token was refused
+        assert!(login.answer(&challenge).unwrap_err().to_string().contains("did not accept"));
+        login.reset();
+        login.client_data(&hello);
+        assert!(login.answer(&challenge).is_ok());
+    }
+
+    #[test]
+    fn test_command_prefix() {
+        let mut prefix = SessionToken::command_prefix();
+        prefix.extend(["sh".to_string(), "-c".to_string(), format!("echo ${TOKEN_VAR}")]);
+        let mut child = std::process::Command::new(&prefix[0])
+            .args(&prefix[1..])
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        SessionToken(b"secret".to_vec()).write_line(child.stdin.take().unwrap()).unwrap();
+        let output = child.wait_with_output().unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "secret\n");
+    }
+
+    #[test]
+    fn test_ssh_command_prefix() {
+        let path = std::env::temp_dir().join(format!("sshx-auth-{}.log", std::process::id()));
+        let wm = SessionToken::unset_prefix() + "sh -c 'echo wm has ${SSHX_XPRA_TOKEN:-no} token'";
+        let mut prefix = SessionToken::ssh_command_prefix();
+        prefix.extend([
+            "sh".to_string(),
+            "-c".to_string(),
+            format!("echo xpra has ${{#{TOKEN_VAR}}} characters; {wm}; exec sleep 30"),
+        ]);
+        let mut child = std::process::Command::new(&prefix[0])
+            .args(&prefix[1..])
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        crate::xpra_output::capture(&mut child, &path);
+        let token = SessionToken::generate();
+        token.write_line(child.stdin.as_mut().unwrap()).unwrap();
+
+        // The program runs until standard input closes, as ssh would on exit
+        let read_log = || std::fs::read_to_string(&path).unwrap_or_default();
+        while !read_log().contains("wm has") {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert!(child.try_wait().unwrap().is_none());
+        drop(child.stdin.take());
+        child.wait().unwrap();
+
+        let log = read_log();
+        assert!(log.contains("xpra has 32 characters"), "{log}");
+        assert!(log.contains("wm has no token"), "{log}");
+        assert!(!log.contains(&*String::from_utf8_lossy(&token.0)), "{log}");
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,215 @@
+//! Backends showing the desktops.
+//!
+//! Every desktop is an xpra display, so the sessions of all backends share
+//! placement, limits and quotas, the forwarder and its encryption, the logs
+//! and the analyzer. The backend decides what runs on the display: the
+//! window manager the session was started with for [`XpraBackend`], or the
+//! client of a remote desktop protocol showing another machine, like the
+//! [`RdpBackend`] of Windows hosts and the [`MacosBackend`] of Macs.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::xpra::DisplayOptions;
+use crate::xpra_command::ProgramAllowlist;
+use crate::xpra_hosts::shell_quote;
+use crate::xpra_macos::{MacosBackend, MacosConfig};
+use crate::xpra_rdp::{RdpBackend, RdpConfig};
+
+/// What the display of a desktop runs.
+pub trait DesktopBackend: fmt::Debug + Send + Sync {
+    /// Name of the backend, as labelled on its sessions
+    fn name(&self) -> &'static str;
+
+    /// Command line xpra runs as the desktop, in place of the window manager
+    /// `wm` the session was started with, if the backend can start the
+    /// desktop with `options`.
+    fn desktop_program(&self, wm: &str, options: &DisplayOptions, allowlist: &ProgramAllowlist) -> Result<String>;
+
+    /// Whether the session ends when the desktop program exits, rather than
+    /// xpra keeping the display up without it
+    fn ends_with_program(&self) -> bool {
+        false
+    }
+
+    /// Address of the machine the desktop shows, none for desktops of the
+    /// display itself
+    fn target(&self) -> Option<&str> {
+        None
+    }
+
+    /// Whether the machine has a single screen, which every desktop of it
+    /// would show whoever started it, so it has one desktop at a time
+    fn exclusive(&self) -> bool {
+        false
+    }
+}
+
+lazy_static::lazy_static! {
+    /// Users of the targets of exclusive backends, by target
+    static ref CLAIMED_TARGETS: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
+}
+
+/// Take `target` for a desktop of `user`, or the user whose desktop already
+/// shows it.
+pub fn claim_target(target: &str, user: &str) -> Result<TargetClaim, String> {
+    let mut claimed = CLAIMED_TARGETS.lock().unwrap();
+    if let Some(holder) = claimed.get(target) {
+        return Err(holder.clone());
+    }
+    claimed.insert(target.to_string(), user.to_string());
+    Ok(TargetClaim { target: target.to_string() })
+}
+
+/// A target taken by a desktop, free again once dropped.
+#[derive(Debug)]
+pub struct TargetClaim {
+    target: String,
+}
+
+impl Drop for TargetClaim {
+    fn drop(&mut self) {
+        CLAIMED_TARGETS.lock().unwrap().remove(&self.target);
+    }
+}
+
+/// Desktops of the window manager a session is started with.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct XpraBackend;
+
+impl DesktopBackend for XpraBackend {
+    fn name(&self) -> &'static str {
+        "xpra"
+    }
+
+    fn desktop_program(&self, wm: &str, options: &DisplayOptions, allowlist: &ProgramAllowlist) -> Result<String> {
+        allowlist.check(wm, "window manager")?;
+        if let Some(app) = &options.app {
+            allowlist.check(app, "app")?;
+        }
+        Ok(wm.to_string())
+    }
+}
+
+/// Backend a desktop is started with.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum BackendConfig {
+    /// A Linux desktop of the session's window manager
+    #[default]
+    Xpra,
+    /// A Windows host shown through an RDP client
+    Rdp(RdpConfig),
+    /// A Mac shown through its screen sharing
+    Macos(MacosConfig),
+}
+
+impl BackendConfig {
+    /// The backend starting desktops as configured.
+    pub fn backend(&self) -> Box<dyn DesktopBackend> {
+        match self {
+            BackendConfig::Xpra => Box::new(XpraBackend),
+            BackendConfig::Rdp(config) => Box::new(RdpBackend::new(config.clone())),
+            BackendConfig::Macos(config) => Box::new(MacosBackend::new(config.clone())),
+        }
+    }
+}
+
+/// Check that the address of a remote desktop is a host name or address,
+/// with a port if any, and cannot be taken as an option of the client.
+pub(crate) fn check_target(target: &str, backend: &str) -> Result<()> {
+    let valid = !target.is_empty()
+        && !target.starts_with('-')
+        && target.chars().all(|c| c.is_ascii_alphanumeric() || "-_.:[]".contains(c));
+    if !valid {
+        anyhow::bail!("{backend} target {target:?} is not a host with an optional port");
+    }
+    Ok(())
+}
+
+/// Command line of a remote desktop client, quoted for the shell xpra runs
+/// the desktop with.
+pub(crate) fn command_line(client: &str, args: &[String]) -> String {
+    let mut command = vec![shell_quote(client)];
+    command.extend(args.iter().map(|arg| shell_quote(arg)));
+    command.join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::xpra_config::XpraConfig;
+
+    #[test]
+    fn test_backend_selection() {
+        let backend = |json: &str| serde_json::from_str::<BackendConfig>(json).unwrap().backend();
+
+        let xpra = BackendConfig::default().backend();
+        assert_eq!((xpra.name(), xpra.target(), xpra.exclusive()), ("xpra", None, false));
+        let rdp = backend(r#"{"type":"rdp","target":"win-1:3389"}"#);
+        assert_eq!((rdp.name(), rdp.target(), rdp.exclusive()), ("rdp", Some("win-1:3389"), false));
+        let macos = backend(r#"{"type":"macos","target":"mac-1"}"#);
+        assert_eq!((macos.name(), macos.target(), macos.exclusive()), ("macos", Some("mac-1"), true));
+        assert!(serde_json::from_str::<BackendConfig>(r#"{"type":"vnc"}"#).is_err());
+    }
+
+    #[test]
+    fn test_claim_target() {
+        let claim = claim_target("mac-test", "alice").unwrap();
+        // Neither the same user nor another can show the screen twice
+        assert_eq!(claim_target("mac-test", "alice").unwrap_err(), "alice");
+        assert_eq!(claim_target("mac-test", "bob").unwrap_err(), "alice");
+        let other = claim_target("mac-other", "bob").unwrap();
+        drop(claim);
+        assert!(claim_target("mac-test", "bob").is_ok());
+        drop(other);
+    }
+
+    #[test]
+    fn test_xpra_backend_checks_allowlist() {
+        let config = XpraConfig {
+            window_manager: "gnome-flashback".to_string(),
+            allowed_programs: vec!["firefox".to_string()],
+            ..XpraConfig::default()
+        };
+        let allowlist = ProgramAllowlist::from_config(&config);
+        let app = |app: &str| DisplayOptions { app: Some(app.to_string()), ..DisplayOptions::default() };
+
+        let program = XpraBackend.desktop_program("gnome-flashback", &app("firefox"), &allowlist).unwrap();
+        assert_eq!(program, "gnome-flashback");
+        let err = XpraBackend.desktop_program("xterm", &DisplayOptions::default(), &allowlist).unwrap_err();
+        assert!(err.to_string().contains("window manager \"xterm\" is not allowed"));
+        let err = XpraBackend.desktop_program("gnome-flashback", &app("gimp"), &allowlist).unwrap_err();
+        assert!(err.to_string().contains("app \"gimp\" is not allowed"));
+    }
+
+    #[test]
+    fn test_rdp_command_line() {
+        // The window manager is replaced and not checked against the allowlist
+        let backend = BackendConfig::Rdp(serde_json::from_str(r#"{"target":"win-1:3389"}"#).unwrap()).backend();
+        let program = backend.desktop_program("gnome-flashback", &DisplayOptions::default(), &ProgramAllowlist::default());
+        assert_eq!(
+            program.unwrap(),
+            "xfreerdp /v:win-1:3389 /f /dynamic-resolution /sec:tls -authentication +clipboard"
+        );
+    }
+
+    #[test]
+    fn test_check_target() {
+        for target in ["win-1", "win-1.corp:3389", "10.0.0.5", "[fe80::1]:3389"] {
+            assert!(check_target(target, "RDP").is_ok(), "{target}");
+        }
+        for target in ["", "-p", "win-1 /p:x", "win-1;id", "$(id)"] {
+            assert!(check_target(target, "RDP").is_err(), "{target}");
+        }
+    }
+
+    #[test]
+    fn test_command_line_quotes_arguments() {
+        let args = ["/v:win-1".to_string(), "/t:Alice's desktop".to_string()];
+        assert_eq!(command_line("xfreerdp", &args), "xfreerdp /v:win-1 '/t:Alice'\\''s desktop'");
+    }
+}
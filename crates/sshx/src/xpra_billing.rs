@@ -0,0 +1,386 @@
+//! Billing records of the desktop time used, by user and cost center.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Datelike, Duration, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::time;
+use tracing::{error, info};
+
+use crate::xpra_log_analyzer::read_log_lines;
+use crate::xpra_log_schema::LogSchema;
+use crate::xpra_logger::{SessionEvent, SessionEventType};
+
+/// Start and end of a session
+type Interval = (DateTime<Utc>, DateTime<Utc>);
+
+/// Calendar month used as a billing period.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BillingPeriod {
+    /// Year of the month
+    pub year: i32,
+    /// Month, from 1 to 12
+    pub month: u32,
+}
+
+impl BillingPeriod {
+    /// Parse a `YYYY-MM` string.
+    pub fn parse(s: &str) -> Result<Self> {
+        let date = NaiveDate::parse_from_str(&format!("{s}-01"), "%Y-%m-%d")
+            .with_context(|| format!("invalid billing month {s:?}, expected YYYY-MM"))?;
+        Ok(Self { year: date.year(), month: date.month() })
+    }
+
+    /// The month before the one containing `now`.
+    pub fn previous(now: DateTime<Utc>) -> Self {
+        let first = now.date_naive().with_day(1).unwrap();
+        let prev = first - Duration::days(1);
+        Self { year: prev.year(), month: prev.month() }
+    }
+
+    /// First instant of the month, in UTC
+    pub fn start(&self) -> DateTime<Utc> {
+        NaiveDate::from_ymd_opt(self.year, self.month, 1)
+            .unwrap()
+            .and_hms_opt(0, 0, 0)
+            .unwrap()
+            .and_utc()
+    }
+
+    /// First instant of the next month, in UTC
+    pub fn end(&self) -> DateTime<Utc> {
+        let (year, month) = if self.month == 12 { (self.year + 1, 1) } else { (self.year, self.month + 1) };
+        Self { year, month }.start()
+    }
+
+    /// The month as `YYYY-MM`
+    pub fn label(&self) -> String {
+        format!("{:04}-{:02}", self.year, self.month)
+    }
+}
+
+/// Chargeback totals for one user over a billing period.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct UserBilling {
+    /// User billed
+    pub user: String,
+    /// Sessions running at some point in the period
+    pub sessions: u32,
+    /// Hours of the period the user's sessions ran, summed over sessions
+    pub session_hours: f64,
+    /// Most sessions of the user running at once
+    pub peak_concurrency: u32,
+    /// Bytes sent from clients to the user's desktops
+    pub bytes_in: u64,
+    /// Bytes sent from the user's desktops to clients
+    pub bytes_out: u64,
+}
+
+/// Usage report for a billing period.
+#[derive(Debug, Clone, Serialize)]
+pub struct BillingReport {
+    /// Start of the billing period
+    pub period_start: DateTime<Utc>,
+    /// End of the billing period
+    pub period_end: DateTime<Utc>,
+    /// When the report was made
+    pub generated_at: DateTime<Utc>,
+    /// Totals per user, by user name
+    pub users: Vec<UserBilling>,
+}
+
+impl BillingReport {
+    /// Render the report as CSV, one row per user.
+    pub fn to_csv(&self) -> String {
+        let mut out = String::from(
+            "period_start,period_end,user,sessions,session_hours,peak_concurrency,bytes_in,bytes_out\n",
+        );
+        for u in &self.users {
+            out.push_str(&format!(
+                "{},{},{},{},{:.3},{},{},{}\n",
+                self.period_start.to_rfc3339(),
+                self.period_end.to_rfc3339(),
+                csv_field(&u.user),
+                u.sessions,
+                u.session_hours,
+                u.peak_concurrency,
+                u.bytes_in,
+                u.bytes_out,
+            ));
+        }
+        out
+    }
+
+    /// Render the report in the given format (csv/json).
+    pub fn render(&self, format: &str) -> Result<String> {
+        match format {
+            "csv" => Ok(self.to_csv()),
+            "json" => Ok(serde_json::to_string_pretty(self)?),
+            _ => anyhow::bail!("Unsupported format: {}", format),
+        }
+    }
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct MetricsLine {
+    timestamp: DateTime<Utc>,
+    #[serde(default)]
+    sessions: Vec<SessionLine>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SessionLine {
+    session_id: String,
+    user: String,
+    #[serde(default)]
+    bytes_in: u64,
+    #[serde(default)]
+    bytes_out: u64,
+}
+
+#[derive(Debug, Default)]
+struct SessionUsage {
+    user: String,
+    start: Option<DateTime<Utc>>,
+    end: Option<DateTime<Utc>>,
+    last_seen: Option<DateTime<Utc>>,
+    bytes_before: (u64, u64),
+    bytes_until_end: (u64, u64),
+}
+
+/// Aggregates history and metrics logs into chargeback reports.
+pub struct UsageExporter {
+    log_dir: PathBuf,
+}
+
+impl UsageExporter {
+    /// Create an exporter reading the logs in `log_dir`
+    pub fn new(log_dir: PathBuf) -> Self {
+        Self { log_dir }
+    }
+
+    /// Build a usage report for `[start, end)`.
+    pub fn export(&self, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<BillingReport> {
+        let mut sessions: HashMap<String, SessionUsage> = HashMap::new();
+
+        for line in read_log_lines(&self.log_dir, "history.log")? {
+            let Ok(event) = LogSchema::History.parse::<SessionEvent>(&line) else {
+                continue;
+            };
+            let usage = sessions.entry(event.session_id.clone()).or_default();
+            usage.user = event.user;
+            match event.event_type {
+                SessionEventType::Created => usage.start = Some(event.timestamp),
+                SessionEventType::Terminated
+                | SessionEventType::IdleTimeout
+                | SessionEventType::Failed => {
+                    usage.end = Some(event.timestamp);
+                    // Final totals of the session, counted like a last
+                    // metrics snapshot taken when it ended
+                    if let Some(traffic) = event.traffic.filter(|_| event.timestamp < end) {
+                        let bytes = if event.timestamp < start {
+                            &mut usage.bytes_before
+                        } else {
+                            &mut usage.bytes_until_end
+                        };
+                        bytes.0 = bytes.0.max(traffic.bytes_in);
+                        bytes.1 = bytes.1.max(traffic.bytes_out);
+                    }
+                }
+                SessionEventType::RateLimited
+                | SessionEventType::PolicyDenied
+                | SessionEventType::Hibernated
+                | SessionEventType::Resumed
+                | SessionEventType::StateChanged
+                | SessionEventType::Renamed
+                | SessionEventType::DeviceAttached
+                | SessionEventType::DeviceDetached
+                | SessionEventType::RecordingAcknowledged => {}
+            }
+        }
+
+        for line in read_log_lines(&self.log_dir, "metrics.log")? {
+            let Ok(entry) = LogSchema::Metrics.parse::<MetricsLine>(&line) else {
+                continue;
+            };
+            if entry.timestamp >= end {
+                continue;
+            }
+            for s in entry.sessions {
+                let usage = sessions.entry(s.session_id).or_default();
+                if usage.user.is_empty() {
+                    usage.user = s.user;
+                }
+                usage.last_seen = usage.last_seen.max(Some(entry.timestamp));
+                let bytes = if entry.timestamp < start {
+                    &mut usage.bytes_before
+                } else {
+                    &mut usage.bytes_until_end
+                };
+                bytes.0 = bytes.0.max(s.bytes_in);
+                bytes.1 = bytes.1.max(s.bytes_out);
+            }
+        }
+
+        let now = Utc::now();
+        let mut users: HashMap<String, UserBilling> = HashMap::new();
+        let mut intervals: HashMap<String, Vec<Interval>> = HashMap::new();
+
+        for usage in sessions.into_values() {
+            let Some(session_start) = usage.start else {
+                continue;
+            };
+            // Sessions without a termination event are charged until they
+            // were last seen alive, or until the end of the period.
+            let session_end = usage.end
+                .or(usage.last_seen)
+                .unwrap_or_else(|| end.min(now));
+            let from = session_start.max(start);
+            let to = session_end.min(end);
+            if to <= from {
+                continue;
+            }
+
+            let billing = users.entry(usage.user.clone()).or_insert_with(|| UserBilling {
+                user: usage.user.clone(),
+                ..Default::default()
+            });
+            billing.sessions += 1;
+            billing.session_hours += (to - from).num_seconds() as f64 / 3600.0;
+            billing.bytes_in += usage.bytes_until_end.0.saturating_sub(usage.bytes_before.0);
+            billing.bytes_out += usage.bytes_until_end.1.saturating_sub(usage.bytes_before.1);
+            intervals.entry(usage.user).or_default().push((from, to));
+        }
+
+        for (user, intervals) in intervals {
+            if let Some(billing) = users.get_mut(&user) {
+                billing.peak_concurrency = peak_concurrency(&intervals);
+            }
+        }
+
+        let mut users: Vec<UserBilling> = users.into_values().collect();
+        users.sort_by(|a, b| a.user.cmp(&b.user));
+
+        Ok(BillingReport {
+            period_start: start,
+            period_end: end,
+            generated_at: now,
+            users,
+        })
+    }
+
+    /// Build the report for a calendar month.
+    pub fn export_period(&self, period: BillingPeriod) -> Result<BillingReport> {
+        self.export(period.start(), period.end())
+    }
+
+    /// Write CSV and JSON reports for the previous month into
+    /// `<log_dir>/billing` once per month, skipping months already exported.
+    pub fn start_scheduled_exports(&self) {
+        let log_dir = self.log_dir.clone();
+        tokio::spawn(async move {
+            let mut interval = time::interval(time::Duration::from_secs(3600)); // Check hourly
+            loop {
+                interval.tick().await;
+                let log_dir = log_dir.clone();
+                let result = tokio::task::spawn_blocking(move || {
+                    UsageExporter::new(log_dir).write_previous_month()
+                }).await;
+                match result {
+                    Ok(Ok(())) => (),
+                    Ok(Err(e)) => error!("Failed to export billing report: {}", e),
+                    Err(e) => error!("Billing export task panicked: {}", e),
+                }
+            }
+        });
+    }
+
+    fn write_previous_month(&self) -> Result<()> {
+        let period = BillingPeriod::previous(Utc::now());
+        let dir = self.log_dir.join("billing");
+        let csv_path = dir.join(format!("usage-{}.csv", period.label()));
+        if csv_path.exists() {
+            return Ok(());
+        }
+
+        fs::create_dir_all(&dir)?;
+        let report = self.export_period(period)?;
+        fs::write(dir.join(format!("usage-{}.json", period.label())), report.render("json")?)?;
+        fs::write(&csv_path, report.to_csv())?;
+
+        info!(period = period.label(), path = %csv_path.display(), "Exported billing report");
+        Ok(())
+    }
+}
+
+fn peak_concurrency(intervals: &[Interval]) -> u32 {
+    let mut edges: Vec<(DateTime<Utc>, i32)> = intervals.iter()
+        .flat_map(|(from, to)| [(*from, 1), (*to, -1)])
+        .collect();
+    // Ends sort before starts at the same instant, so back-to-back sessions
+    // don't count as concurrent.
+    edges.sort();
+
+    let mut current = 0i32;
+    let mut peak = 0i32;
+    for (_, delta) in edges {
+        current += delta;
+        peak = peak.max(current);
+    }
+    peak as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn test_billing_period_bounds() {
+        let period = BillingPeriod::parse("2024-12").unwrap();
+        assert_eq!(period.start(), Utc.with_ymd_and_hms(2024, 12, 1, 0, 0, 0).unwrap());
+        assert_eq!(period.end(), Utc.with_ymd_and_hms(2025, 1, 1, 0, 0, 0).unwrap());
+
+        let now = Utc.with_ymd_and_hms(2025, 1, 15, 8, 0, 0).unwrap();
+        assert_eq!(BillingPeriod::previous(now), period);
+    }
+
+    #[test]
+    fn test_peak_concurrency() {
+        let t = |h| Utc.with_ymd_and_hms(2024, 5, 1, h, 0, 0).unwrap();
+        assert_eq!(peak_concurrency(&[(t(1), t(3)), (t(2), t(4)), (t(3), t(5))]), 2);
+        assert_eq!(peak_concurrency(&[(t(1), t(2)), (t(2), t(3))]), 1);
+    }
+
+    #[test]
+    fn test_traffic_after_last_snapshot_is_billed() {
+        let dir = std::env::temp_dir().join(format!("sshx-billing-traffic-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("history.log"), [
+            r#"{"schema_version":2,"timestamp":"2024-05-01T10:00:00Z","event_type":"Created","session_id":"a","user":"alice","display":100}"#,
+            r#"{"schema_version":2,"timestamp":"2024-05-01T10:07:00Z","event_type":"Terminated","session_id":"a","user":"alice","display":100,"traffic":{"bytes_in":300,"bytes_out":9000}}"#,
+        ].join("\n")).unwrap();
+        // The last snapshot was taken two minutes before the session ended
+        std::fs::write(dir.join("metrics.log"), [
+            r#"{"schema_version":2,"timestamp":"2024-05-01T10:05:00Z","metrics":{"total_sessions":1,"active_sessions":1,"failed_sessions":0,"idle_terminations":0},"sessions":[{"session_id":"a","user":"alice","display":100,"idle_seconds":0,"bytes_in":100,"bytes_out":4000}]}"#,
+        ].join("\n")).unwrap();
+
+        let period = BillingPeriod::parse("2024-05").unwrap();
+        let report = UsageExporter::new(dir.clone()).export(period.start(), period.end()).unwrap();
+        assert_eq!(report.users.len(), 1);
+        assert_eq!((report.users[0].bytes_in, report.users[0].bytes_out), (300, 9000));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
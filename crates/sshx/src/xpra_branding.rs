@@ -0,0 +1,171 @@
+//! Look of new desktops: wallpaper, GTK theme and a branding overlay.
+//!
+//! A bare window manager looks nothing like a company's standard desktop.
+//! The `branding` settings are applied by programs started on each desktop
+//! next to the configured `children`, run once when the desktop starts: the
+//! wallpaper, with the overlay image composited onto one of its corners, is
+//! set on the root window with feh and, for desktops drawing their own
+//! background, through gsettings; the GTK theme is set through gsettings
+//! and, under Xfce, xfconf.
+
+use serde::{Deserialize, Serialize};
+
+use crate::xpra_children::{ChildProgram, RestartPolicy};
+use crate::xpra_hosts::shell_quote;
+
+/// Color behind an overlay when no wallpaper is configured.
+const BACKGROUND: &str = "#1e1e1e";
+
+/// Pixels between an overlay and the edges of the screen.
+const OVERLAY_MARGIN: u32 = 24;
+
+/// Corner of the screen an overlay is placed in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverlayPosition {
+    /// Top left corner
+    TopLeft,
+    /// Top right corner
+    TopRight,
+    /// Bottom left corner
+    BottomLeft,
+    /// Bottom right corner
+    #[default]
+    BottomRight,
+}
+
+impl OverlayPosition {
+    /// ImageMagick gravity placing an image in the corner
+    fn gravity(&self) -> &'static str {
+        match self {
+            OverlayPosition::TopLeft => "NorthWest",
+            OverlayPosition::TopRight => "NorthEast",
+            OverlayPosition::BottomLeft => "SouthWest",
+            OverlayPosition::BottomRight => "SouthEast",
+        }
+    }
+}
+
+/// Look applied to every new desktop.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BrandingConfig {
+    /// Image shown as the desktop background, on the desktop's host
+    #[serde(default)]
+    pub wallpaper: Option<String>,
+
+    /// Name of the GTK theme applications use
+    #[serde(default)]
+    pub gtk_theme: Option<String>,
+
+    /// Image, e.g. a logo, placed over the background
+    #[serde(default)]
+    pub overlay: Option<String>,
+
+    /// Corner the overlay is placed in
+    #[serde(default)]
+    pub overlay_position: OverlayPosition,
+}
+
+impl BrandingConfig {
+    /// Programs applying the branding to a display whose screen is at most
+    /// `max_resolution`.
+    pub fn programs(&self, max_resolution: &str) -> Vec<ChildProgram> {
+        let mut programs = Vec::new();
+        if let Some(command) = self.background_command(max_resolution) {
+            programs.push(ChildProgram { name: "wallpaper".to_string(), command, restart: RestartPolicy::Never });
+        }
+        if let Some(theme) = &self.gtk_theme {
+            let theme = shell_quote(theme);
+            let command = format!(
+                "if command -v gsettings >/dev/null; then \
+                 gsettings set org.gnome.desktop.interface gtk-theme {theme}; fi; \
+                 if command -v xfconf-query >/dev/null; then \
+                 xfconf-query -c xsettings -p /Net/ThemeName -s {theme}; fi"
+            );
+            programs.push(ChildProgram { name: "gtk-theme".to_string(), command, restart: RestartPolicy::Never });
+        }
+        programs
+    }
+
+    /// Programs the configured branding needs on a host.
+    pub fn required_programs(&self) -> Vec<&'static str> {
+        let mut required = Vec::new();
+        if self.wallpaper.is_some() || self.overlay.is_some() {
+            required.push("feh");
+        }
+        if self.overlay.is_some() {
+            required.push("convert");
+        }
+        required
+    }
+
+    fn background_command(&self, max_resolution: &str) -> Option<String> {
+        let image = match (&self.wallpaper, &self.overlay) {
+            (None, None) => return None,
+            (Some(wallpaper), None) => shell_quote(wallpaper),
+            (wallpaper, Some(overlay)) => {
+                let background = match wallpaper {
+                    Some(wallpaper) => format!("{} -resize {max_resolution}^", shell_quote(wallpaper)),
+                    None => format!("-size {max_resolution} xc:'{BACKGROUND}'"),
+                };
+                // A private directory of its own, as gsettings reads the
+                // image again later
+                let branded = "\"$dir/branding.png\"";
+                return Some(format!(
+                    "dir=$(mktemp -d) && convert {background} {} -gravity {} \
+                     -geometry +{OVERLAY_MARGIN}+{OVERLAY_MARGIN} -composite {branded} && {}",
+                    shell_quote(overlay),
+                    self.overlay_position.gravity(),
+                    set_background(branded),
+                ));
+            }
+        };
+        Some(set_background(&image))
+    }
+}
+
+/// Command setting the quoted `image` as the desktop background.
+fn set_background(image: &str) -> String {
+    format!(
+        "feh --no-fehbg --bg-fill {image}; \
+         if command -v gsettings >/dev/null; then \
+         gsettings set org.gnome.desktop.background picture-uri \"file://$(realpath {image})\"; fi"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_programs() {
+        assert!(BrandingConfig::default().programs("1920x1080").is_empty());
+
+        let branding = BrandingConfig {
+            wallpaper: Some("/usr/share/backgrounds/acme.png".to_string()),
+            gtk_theme: Some("Adwaita-dark".to_string()),
+            ..BrandingConfig::default()
+        };
+        let programs = branding.programs("1920x1080");
+        assert_eq!(programs.len(), 2);
+        assert!(programs[0].command.starts_with("feh --no-fehbg --bg-fill /usr/share/backgrounds/acme.png;"));
+        assert!(programs[1].command.contains("gtk-theme Adwaita-dark"));
+        assert!(programs.iter().all(|p| p.restart == RestartPolicy::Never));
+        assert_eq!(branding.required_programs(), ["feh"]);
+
+        let branding = BrandingConfig {
+            overlay: Some("/etc/acme/logo.png".to_string()),
+            overlay_position: OverlayPosition::TopRight,
+            ..BrandingConfig::default()
+        };
+        let programs = branding.programs("1920x1080");
+        assert_eq!(programs.len(), 1);
+        assert!(programs[0].command.starts_with(
+            "dir=$(mktemp -d) && convert -size 1920x1080 xc:'#1e1e1e' /etc/acme/logo.png -gravity NorthEast"
+        ));
+        assert!(programs[0].command.contains("-composite \"$dir/branding.png\" && feh"));
+        assert!(programs[0].command.contains("--bg-fill \"$dir/branding.png\""));
+        assert!(!programs[0].command.contains("/tmp/"));
+        assert_eq!(branding.required_programs(), ["feh", "convert"]);
+    }
+}
@@ -0,0 +1,335 @@
+//! Detection of the installed xpra release and the command line features it
+//! supports, so sessions start with arguments the host's xpra understands.
+
+use std::collections::HashSet;
+use std::fmt;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use tracing::warn;
+
+use crate::xpra::DisplayOptions;
+use crate::xpra_devices::Device;
+use crate::xpra_hosts::WorkerHost;
+use crate::xpra_tls::TlsConfig;
+use crate::xpra_xorg::DUMMY_DRIVER_PATTERNS;
+
+/// Where the HTML5 client is installed, in order of preference.
+const HTML5_ROOTS: &[&str] = &["/usr/share/xpra/www", "/usr/local/share/xpra/www"];
+
+/// An xpra release number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct XpraVersion {
+    /// Major version
+    pub major: u32,
+    /// Minor version
+    pub minor: u32,
+    /// Patch version
+    pub patch: u32,
+}
+
+impl XpraVersion {
+    /// Oldest release whose command line options we support.
+    pub const MINIMUM: Self = Self::new(3, 0, 0);
+
+    /// Release assumed when detection fails.
+    const ASSUMED: Self = Self::new(6, 0, 0);
+
+    /// A release with the given numbers
+    pub const fn new(major: u32, minor: u32, patch: u32) -> Self {
+        Self { major, minor, patch }
+    }
+
+    /// Parse the output of `xpra --version`, e.g. `xpra v6.0.2-r0`.
+    pub fn parse(output: &str) -> Option<Self> {
+        let version = output
+            .split_whitespace()
+            .map(|word| word.trim_start_matches('v'))
+            .find(|word| word.starts_with(|c: char| c.is_ascii_digit()))?;
+        let version = version.split('-').next()?;
+        let mut parts = version.split('.').map(|p| p.parse::<u32>().ok());
+        let major = parts.next()??;
+        let minor = parts.next().flatten().unwrap_or(0);
+        let patch = parts.next().flatten().unwrap_or(0);
+        Some(Self::new(major, minor, patch))
+    }
+}
+
+impl fmt::Display for XpraVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// Features of an xpra installation that affect how it is driven.
+#[derive(Debug, Clone, Serialize)]
+pub struct XpraCapabilities {
+    /// Installed release, or the assumed one if detection failed
+    pub version: XpraVersion,
+    /// `--bind-ws` is available; older releases and builds without
+    /// websocket support serve it on `--bind-tcp` when the HTML5 client is
+    /// enabled
+    pub bind_ws: bool,
+    /// `--webcam` is available; builds without webcam support lack it
+    pub webcam: bool,
+    /// Installed HTML5 client outside the location xpra looks in by default
+    pub html_root: Option<String>,
+    /// The Xorg dummy driver is installed, so Xdummy can be used
+    pub dummy_driver: bool,
+}
+
+impl XpraCapabilities {
+    /// Capabilities implied by a release number alone, for when the options
+    /// of the installed build cannot be listed.
+    pub fn for_version(version: XpraVersion) -> Self {
+        Self {
+            version,
+            bind_ws: version >= XpraVersion::new(2, 1, 0),
+            webcam: true,
+            html_root: None,
+            dummy_driver: false,
+        }
+    }
+
+    /// Probe the xpra installed on a host.
+    pub async fn detect(host: &WorkerHost) -> Result<Self> {
+        let output = tokio::process::Command::from(host.command("xpra", &["--version".to_string()]))
+            .output()
+            .await
+            .context("failed to run xpra --version")?;
+        let text = String::from_utf8_lossy(&output.stdout);
+        let version = XpraVersion::parse(&text)
+            .with_context(|| format!("could not parse xpra version from {:?}", text.trim()))?;
+
+        let mut capabilities = Self::for_version(version);
+        // Distributions build xpra with or without optional parts, so the
+        // release alone does not tell which options exist
+        match listed_options(host).await {
+            Ok(options) => {
+                capabilities.bind_ws = options.contains("--bind-ws");
+                capabilities.webcam = options.contains("--webcam");
+            }
+            Err(e) => warn!(host = host.name, "Failed to list xpra options, going by its version: {:#}", e),
+        }
+        // xpra 4+ ships the HTML5 client separately, which may land under
+        // /usr/local when installed from source
+        for (i, root) in HTML5_ROOTS.iter().enumerate() {
+            if dir_exists(host, root).await {
+                capabilities.html_root = (i > 0).then(|| root.to_string());
+                break;
+            }
+        }
+        capabilities.dummy_driver = any_exists(host, DUMMY_DRIVER_PATTERNS).await;
+        Ok(capabilities)
+    }
+
+    /// Arguments for `xpra` to start a display, short of where it serves
+    /// websockets, which [`Self::bind_args`] gives.
+    pub fn start_args(&self, display: u16, wm: &str, x_server: &str, options: &DisplayOptions) -> Vec<String> {
+        let html = match &self.html_root {
+            Some(root) => format!("--html={}", root),
+            None => "--html=on".to_string(),
+        };
+        let audio = if options.audio { "yes" } else { "no" };
+        let mut args = vec![
+            "start".to_string(),
+            format!(":{}", display),
+            html,
+            format!("--xvfb={}", x_server),
+            format!("--pulseaudio={}", audio),
+            "--daemon=no".to_string(),
+        ];
+        if options.backend.backend().ends_with_program() {
+            // --exit-with-children only tracks commands started as children
+            args.push(format!("--start-child={}", wm));
+            args.push("--exit-with-children=yes".to_string());
+        } else {
+            // xpra keeps the display when the window manager exits, and the
+            // watchdog starts it again
+            args.push(format!("--start={}", wm));
+        }
+        if let Some(app) = &options.app {
+            args.push(format!("--start={}", app));
+        }
+        if let Some(layout) = &options.keyboard_layout {
+            args.push(format!("--keyboard-layout={}", layout));
+        }
+        // xpra would take the webcam of any client that offers it
+        if self.webcam {
+            let webcam = if options.devices.contains(&Device::Webcam) { "yes" } else { "no" };
+            args.push(format!("--webcam={}", webcam));
+        }
+        args
+    }
+}
+
+impl XpraCapabilities {
+    /// Arguments for `xpra` to require connections to its websocket to
+    /// authenticate with the password in the environment variable `var`.
+    pub fn auth_args(&self, var: &str, tls: bool) -> Vec<String> {
+        vec![format!("--{}-auth=env:name={}", self.socket(tls), var)]
+    }
+
+    /// Arguments for `xpra` to serve its websocket on `port`, over TLS
+    /// with the certificates of `tls` if given.
+    pub fn bind_args(&self, bind_address: &str, port: u16, tls: Option<&TlsConfig>) -> Vec<String> {
+        let mut args = vec![format!("--bind-{}={}:{}", self.socket(tls.is_some()), bind_address, port)];
+        if let Some(tls) = tls {
+            args.extend(tls.xpra_args());
+        }
+        args
+    }
+
+    /// Kind of socket the websocket is served on, as named in xpra's
+    /// `--bind-*` and `--*-auth` options.
+    fn socket(&self, tls: bool) -> &'static str {
+        match (self.bind_ws, tls) {
+            (true, false) => "ws",
+            (true, true) => "wss",
+            (false, false) => "tcp",
+            (false, true) => "ssl",
+        }
+    }
+}
+
+impl Default for XpraCapabilities {
+    fn default() -> Self {
+        Self::for_version(XpraVersion::ASSUMED)
+    }
+}
+
+/// Options the xpra installed on the host lists in its help.
+async fn listed_options(host: &WorkerHost) -> Result<HashSet<String>> {
+    let output = tokio::process::Command::from(host.command("xpra", &["--help".to_string()]))
+        .output()
+        .await
+        .context("failed to run xpra --help")?;
+    let options = parse_options(&String::from_utf8_lossy(&output.stdout));
+    if options.is_empty() {
+        anyhow::bail!("xpra --help listed no options");
+    }
+    Ok(options)
+}
+
+/// Option names in `xpra --help` output, e.g. `--bind-ws` from
+/// `  --bind-ws=BIND_WS  Listen for connections over Websocket.`
+fn parse_options(help: &str) -> HashSet<String> {
+    help.split_whitespace()
+        .filter(|word| word.starts_with("--") && word.len() > 2)
+        .map(|word| word.split(['=', ',', '[']).next().unwrap_or(word).to_string())
+        .collect()
+}
+
+/// Whether any path matching one of the glob patterns exists on the host.
+async fn any_exists(host: &WorkerHost, patterns: &[&str]) -> bool {
+    // Patterns are expanded by the shell, so they must not be quoted
+    let script = format!("ls -d {} 2>/dev/null", patterns.join(" "));
+    tokio::process::Command::from(host.command("sh", &["-c".to_string(), script]))
+        .output()
+        .await
+        .is_ok_and(|output| !output.stdout.is_empty())
+}
+
+async fn dir_exists(host: &WorkerHost, path: &str) -> bool {
+    let args = ["-d".to_string(), path.to_string()];
+    tokio::process::Command::from(host.command("test", &args))
+        .status()
+        .await
+        .is_ok_and(|status| status.success())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::xpra_backend::BackendConfig;
+
+    #[test]
+    fn test_parse_version() {
+        assert_eq!(XpraVersion::parse("xpra v6.0.2-r34872 (g1e5dbb7c0a)"), Some(XpraVersion::new(6, 0, 2)));
+        assert_eq!(XpraVersion::parse("xpra v3.1\n"), Some(XpraVersion::new(3, 1, 0)));
+        assert_eq!(XpraVersion::parse("xpra 4.4.6"), Some(XpraVersion::new(4, 4, 6)));
+        assert_eq!(XpraVersion::parse("command not found"), None);
+        assert!(XpraVersion::new(2, 10, 0) < XpraVersion::MINIMUM);
+    }
+
+    #[test]
+    fn test_parse_options() {
+        let help = "Usage:\n  xpra start [DISPLAY]\n\nOptions:\n  --version  show program's version number and exit\n  \
+                    --bind-ws=BIND_WS  Listen for connections over Websocket.\n  \
+                    --webcam=WEBCAM  Webcam forwarding, can be used to specify a device.\n  \
+                    --start-child=START_CHILD  program to spawn as a child process\n";
+        let options = parse_options(help);
+        assert!(options.contains("--bind-ws"));
+        assert!(options.contains("--webcam"));
+        assert!(options.contains("--start-child"));
+        assert!(!options.contains("--bind-wss"));
+        assert!(parse_options("command not found").is_empty());
+    }
+
+    #[test]
+    fn test_start_args() {
+        let options = DisplayOptions::default();
+        let capabilities = XpraCapabilities::default();
+        let args = capabilities.start_args(100, "xfce4-session", "Xvfb", &options);
+        assert_eq!(capabilities.bind_args("127.0.0.1", 14500, None), ["--bind-ws=127.0.0.1:14500"]);
+        assert!(args.contains(&"--start=xfce4-session".to_string()));
+        assert!(!args.iter().any(|arg| arg.starts_with("--exit-with-children")));
+        assert!(args.contains(&"--html=on".to_string()));
+        assert!(args.contains(&"--xvfb=Xvfb".to_string()));
+        assert!(args.contains(&"--pulseaudio=no".to_string()));
+        assert!(args.contains(&"--webcam=no".to_string()));
+
+        let mut old = XpraCapabilities::for_version(XpraVersion::new(2, 0, 5));
+        old.html_root = Some("/usr/local/share/xpra/www".to_string());
+        let options = DisplayOptions {
+            app: Some("firefox".to_string()),
+            audio: true,
+            locale: Some("en_CH.UTF-8".to_string()),
+            timezone: None,
+            keyboard_layout: Some("ch(fr)".to_string()),
+            backend: BackendConfig::Xpra,
+            profile: None,
+            devices: vec![Device::Webcam],
+        };
+        let args = old.start_args(100, "openbox", "Xvfb", &options);
+        assert_eq!(old.bind_args("0.0.0.0", 14500, None), ["--bind-tcp=0.0.0.0:14500"]);
+        assert!(args.contains(&"--html=/usr/local/share/xpra/www".to_string()));
+        assert!(args.contains(&"--start=firefox".to_string()));
+        assert!(args.contains(&"--pulseaudio=yes".to_string()));
+        // The layout is never guessed from the locale's region
+        assert!(args.contains(&"--keyboard-layout=ch(fr)".to_string()));
+        assert!(!XpraCapabilities::default()
+            .start_args(100, "openbox", "Xvfb", &DisplayOptions { locale: options.locale.clone(), ..DisplayOptions::default() })
+            .iter()
+            .any(|arg| arg.starts_with("--keyboard-layout")));
+        assert!(args.contains(&"--webcam=yes".to_string()));
+
+        // A remote desktop ends when its client exits
+        let rdp = DisplayOptions { backend: BackendConfig::Rdp(serde_json::from_str(r#"{"target": "win-1"}"#).unwrap()), ..DisplayOptions::default() };
+        let args = capabilities.start_args(100, "xfreerdp /v:win-1", "Xvfb", &rdp);
+        assert!(args.contains(&"--start-child=xfreerdp /v:win-1".to_string()));
+        assert!(args.contains(&"--exit-with-children=yes".to_string()));
+
+        // A build without webcam support does not know the option
+        old.webcam = false;
+        assert!(!old.start_args(100, "openbox", "Xvfb", &options).iter().any(|arg| arg.starts_with("--webcam")));
+    }
+
+    #[test]
+    fn test_tls_args() {
+        let tls = TlsConfig {
+            ca_cert: "ca.pem".into(),
+            client_cert: "forwarder.pem".into(),
+            client_key: "forwarder.key".into(),
+            server_cert: "/etc/xpra/worker.pem".into(),
+            server_key: "/etc/xpra/worker.key".into(),
+            client_ca: "/etc/xpra/ca.pem".into(),
+            server_name: None,
+        };
+        let args = XpraCapabilities::default().bind_args("10.0.0.2", 14500, Some(&tls));
+        assert_eq!(args[0], "--bind-wss=10.0.0.2:14500");
+        assert!(args.contains(&"--ssl-client-verify-mode=required".to_string()));
+        let old = XpraCapabilities::for_version(XpraVersion::new(2, 0, 5));
+        assert_eq!(old.bind_args("10.0.0.2", 14500, Some(&tls))[0], "--bind-ssl=10.0.0.2:14500");
+        assert_eq!(XpraCapabilities::default().auth_args("TOKEN", true), ["--wss-auth=env:name=TOKEN"]);
+    }
+}
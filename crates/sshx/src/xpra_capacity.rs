@@ -0,0 +1,193 @@
+//! Limit on the sessions running at once, with a queue for the starts over it.
+
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::Result;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time;
+use tracing::{debug, warn};
+
+use crate::xpra_metrics::XpraMetrics;
+use crate::xpra_rejection::{RejectionCode, SessionRejection};
+
+/// Slot in the host-wide session cap, released when dropped.
+#[derive(Debug)]
+pub struct SessionSlot {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+/// Entry in the wait queue, counted in the metrics until dropped so that a
+/// cancelled wait does not leave the queue depth behind.
+struct QueueEntry {
+    metrics: Arc<XpraMetrics>,
+}
+
+impl QueueEntry {
+    fn new(metrics: &Arc<XpraMetrics>) -> Self {
+        metrics.session_queued();
+        Self { metrics: metrics.clone() }
+    }
+}
+
+impl Drop for QueueEntry {
+    fn drop(&mut self) {
+        self.metrics.session_dequeued();
+    }
+}
+
+/// Host-wide cap on concurrent sessions with an optional wait queue.
+#[derive(Debug, Clone)]
+pub struct SessionCapacity {
+    slots: Option<Arc<Semaphore>>,
+    queue_timeout: Option<Duration>,
+    metrics: Arc<XpraMetrics>,
+}
+
+impl SessionCapacity {
+    /// Create a new cap of `max_sessions` (0 = unlimited), waiting up to
+    /// `queue_timeout` for a free slot when the host is full and counting
+    /// rejections and waits in `metrics`.
+    pub fn new(
+        max_sessions: u32,
+        queue_timeout: Option<Duration>,
+        metrics: Arc<XpraMetrics>,
+    ) -> Self {
+        Self {
+            slots: (max_sessions > 0).then(|| Arc::new(Semaphore::new(max_sessions as usize))),
+            queue_timeout,
+            metrics,
+        }
+    }
+
+    /// Acquire a slot, either failing fast or queueing until the deadline.
+    pub async fn acquire(&self) -> Result<SessionSlot> {
+        let Some(slots) = &self.slots else {
+            return Ok(SessionSlot { _permit: None });
+        };
+
+        if let Ok(permit) = slots.clone().try_acquire_owned() {
+            return Ok(SessionSlot { _permit: Some(permit) });
+        }
+
+        let Some(timeout) = self.queue_timeout else {
+            self.metrics.session_rejected_full();
+            return Err(SessionRejection::new(
+                RejectionCode::Capacity,
+                "Host is at maximum Xpra session capacity, try again later",
+            )
+            .into());
+        };
+
+        debug!(timeout = ?timeout, "Host full, queueing Xpra session start");
+        let entry = QueueEntry::new(&self.metrics);
+        let result = time::timeout(timeout, slots.clone().acquire_owned()).await;
+        drop(entry);
+
+        match result {
+            Ok(Ok(permit)) => Ok(SessionSlot { _permit: Some(permit) }),
+            Ok(Err(_)) => anyhow::bail!("Xpra session capacity has been shut down"),
+            Err(_) => {
+                self.metrics.session_rejected_full();
+                warn!(timeout = ?timeout, "Timed out waiting for a free Xpra session slot");
+                Err(SessionRejection::new(
+                    RejectionCode::Capacity,
+                    format!(
+                        "Host is at maximum Xpra session capacity, no slot freed up within {}s",
+                        timeout.as_secs()
+                    ),
+                )
+                .into())
+            }
+        }
+    }
+
+    /// Number of free slots, or `None` when the cap is disabled.
+    pub fn available(&self) -> Option<usize> {
+        self.slots.as_ref().map(|s| s.available_permits())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_limit_without_queue() {
+        let capacity = SessionCapacity::new(2, None, Arc::default());
+        assert_eq!(capacity.available(), Some(2));
+
+        let first = capacity.acquire().await.unwrap();
+        let _second = capacity.acquire().await.unwrap();
+        assert_eq!(capacity.available(), Some(0));
+        let err = capacity.acquire().await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<SessionRejection>().map(|r| r.code),
+            Some(RejectionCode::Capacity)
+        );
+
+        drop(first);
+        assert_eq!(capacity.available(), Some(1));
+        assert!(capacity.acquire().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_unlimited() {
+        let capacity = SessionCapacity::new(0, None, Arc::default());
+        assert_eq!(capacity.available(), None);
+        let mut slots = Vec::new();
+        for _ in 0..10 {
+            slots.push(capacity.acquire().await.unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_queue_waits_for_free_slot() {
+        let capacity = SessionCapacity::new(1, Some(Duration::from_secs(5)), Arc::default());
+        let slot = capacity.acquire().await.unwrap();
+
+        let waiting = tokio::spawn({
+            let capacity = capacity.clone();
+            async move { capacity.acquire().await.map(|_| ()) }
+        });
+        time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished());
+        assert_eq!(capacity.metrics.get_metrics().queued_sessions, 1);
+
+        drop(slot);
+        waiting.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_queue_timeout() {
+        let capacity = SessionCapacity::new(1, Some(Duration::from_millis(50)), Arc::default());
+        let _slot = capacity.acquire().await.unwrap();
+
+        let err = capacity.acquire().await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<SessionRejection>().map(|r| r.code),
+            Some(RejectionCode::Capacity)
+        );
+        let metrics = capacity.metrics.get_metrics();
+        assert_eq!((metrics.queued_sessions, metrics.rejected_full), (0, 1));
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_wait_leaves_queue() {
+        let capacity = SessionCapacity::new(1, Some(Duration::from_secs(5)), Arc::default());
+        let _slot = capacity.acquire().await.unwrap();
+
+        let waiting = tokio::spawn({
+            let capacity = capacity.clone();
+            async move { capacity.acquire().await.map(|_| ()) }
+        });
+        time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(capacity.metrics.get_metrics().queued_sessions, 1);
+
+        // Dropping the waiting start must take it off the queue
+        waiting.abort();
+        assert!(waiting.await.unwrap_err().is_cancelled());
+        assert_eq!(capacity.metrics.get_metrics().queued_sessions, 0);
+        assert_eq!(capacity.metrics.get_metrics().invalid_transitions, 0);
+        assert_eq!(capacity.available(), Some(0));
+    }
+}
@@ -0,0 +1,145 @@
+//! Bar charts and sparklines for text reports, drawn with Unicode block
+//! characters or, on terminals without UTF-8, with plain ASCII.
+
+/// Characters charts are drawn with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Charset {
+    /// Block characters, with eighths of a column for bars
+    Unicode,
+    /// `#` bars and an ASCII ramp for sparklines
+    Ascii,
+}
+
+impl Charset {
+    /// Unicode if the locale, from `LC_ALL`, `LC_CTYPE` or `LANG`, uses
+    /// UTF-8, otherwise ASCII.
+    pub fn detect() -> Self {
+        let locale = ["LC_ALL", "LC_CTYPE", "LANG"]
+            .iter()
+            .filter_map(|name| std::env::var(name).ok())
+            .find(|value| !value.is_empty())
+            .unwrap_or_default()
+            .to_lowercase();
+        if locale.contains("utf-8") || locale.contains("utf8") {
+            Charset::Unicode
+        } else {
+            Charset::Ascii
+        }
+    }
+
+    fn eighths(&self) -> &'static [char] {
+        match self {
+            Charset::Unicode => &['▏', '▎', '▍', '▌', '▋', '▊', '▉', '█'],
+            Charset::Ascii => &['#'],
+        }
+    }
+
+    fn ramp(&self) -> &'static [char] {
+        match self {
+            Charset::Unicode => &['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'],
+            Charset::Ascii => &['_', '.', '-', '=', '+', '*', '#'],
+        }
+    }
+}
+
+/// Width and characters of the charts in a report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChartStyle {
+    /// Columns of the longest bar or of a sparkline
+    pub width: usize,
+    /// Characters to draw with
+    pub charset: Charset,
+}
+
+impl Default for ChartStyle {
+    fn default() -> Self {
+        Self { width: 60, charset: Charset::detect() }
+    }
+}
+
+impl ChartStyle {
+    /// A bar for `value` on a scale where `max` fills the width. Nonzero
+    /// values always get some bar.
+    pub fn bar(&self, value: u64, max: u64) -> String {
+        if value == 0 || max == 0 {
+            return String::new();
+        }
+        let steps = self.charset.eighths();
+        let units = steps.len() as u64;
+        let total = (value.min(max) * self.width as u64 * units).div_ceil(max).max(1);
+        let mut bar: String = std::iter::repeat_n(steps[steps.len() - 1], (total / units) as usize).collect();
+        if total % units > 0 {
+            bar.push(steps[(total % units) as usize - 1]);
+        }
+        bar
+    }
+
+    /// One line per `(label, value)`: the label, the value and its bar, with
+    /// bars scaled to the largest value.
+    pub fn bar_chart(&self, rows: &[(String, u64)]) -> Vec<String> {
+        let max = rows.iter().map(|(_, value)| *value).max().unwrap_or(0);
+        let label_width = rows.iter().map(|(label, _)| label.chars().count()).max().unwrap_or(0);
+        let value_width = max.to_string().len().max(5);
+        rows.iter()
+            .map(|(label, value)| {
+                let line = format!("{label:<label_width$} {value:>value_width$} {}", self.bar(*value, max));
+                line.trim_end().to_string()
+            })
+            .collect()
+    }
+
+    /// A sparkline of `values`, one column per value, from the lowest step
+    /// for zero to the highest for the largest value. Values beyond the width
+    /// are merged into columns by their maximum, so peaks are kept.
+    pub fn sparkline(&self, values: &[u64]) -> String {
+        let width = self.width.max(1);
+        let columns: Vec<u64> = if values.len() > width {
+            (0..width)
+                .map(|column| {
+                    let from = column * values.len() / width;
+                    let to = ((column + 1) * values.len() / width).max(from + 1);
+                    values[from..to].iter().copied().max().unwrap_or(0)
+                })
+                .collect()
+        } else {
+            values.to_vec()
+        };
+        let ramp = self.charset.ramp();
+        let max = columns.iter().copied().max().unwrap_or(0).max(1);
+        let top = (ramp.len() - 1) as u64;
+        columns
+            .iter()
+            .map(|value| ramp[(value * top).div_ceil(max) as usize])
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bars() {
+        let unicode = ChartStyle { width: 4, charset: Charset::Unicode };
+        assert_eq!(unicode.bar(8, 8), "████");
+        assert_eq!(unicode.bar(5, 8), "██▌");
+        assert_eq!(unicode.bar(1, 1000), "▏");
+        assert_eq!(unicode.bar(0, 8), "");
+
+        let ascii = ChartStyle { width: 4, charset: Charset::Ascii };
+        assert_eq!(ascii.bar(5, 8), "###");
+        let chart = ascii.bar_chart(&[("00:00".to_string(), 2), ("01:00".to_string(), 0)]);
+        assert_eq!(chart, ["00:00     2 ####", "01:00     0"]);
+    }
+
+    #[test]
+    fn test_sparkline() {
+        let unicode = ChartStyle { width: 10, charset: Charset::Unicode };
+        assert_eq!(unicode.sparkline(&[0, 1, 7, 0]), "▁▂█▁");
+
+        // Merged by maximum, so the single peak survives
+        let ascii = ChartStyle { width: 3, charset: Charset::Ascii };
+        assert_eq!(ascii.sparkline(&[0, 0, 0, 6, 0, 0]), "_#_");
+        assert_eq!(ascii.sparkline(&[]), "");
+    }
+}
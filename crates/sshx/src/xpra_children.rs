@@ -0,0 +1,409 @@
+//! Programs started on a desktop next to its window manager.
+//!
+//! The window manager is started by xpra itself and the session ends with
+//! it. Panels, docks and autostart applications listed under `children` are
+//! started by us once the display is up, each in its own task that restarts
+//! the program according to its restart policy, and are stopped with the
+//! session. They run in the display's network namespace and sandbox, if
+//! it has them, with its environment and GPU. A window manager the watchdog
+//! starts again after it died is run the same way, as one more program.
+//! Their state is shown by `ssh-desktop inspect`. Programs whose outcome
+//! the forwarder waits for, like the recording notice, are run once on
+//! their own instead.
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time;
+use tracing::{debug, info, warn};
+
+use crate::xpra::XpraDisplay;
+use crate::xpra_gpu::GpuConfig;
+use crate::xpra_hosts::WorkerHost;
+
+/// Name of the window manager among the programs of a desktop, once the
+/// watchdog started it again.
+pub const WINDOW_MANAGER: &str = "window manager";
+
+/// Delay before the first restart of a program.
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest delay between restarts of a program that keeps exiting.
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(60);
+
+/// Time a program has to run for its restart delay to start over.
+const STABLE_RUN: Duration = Duration::from_secs(60);
+
+/// When a program is started again after it exits.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RestartPolicy {
+    /// Never, the program runs once
+    Never,
+    /// When it exits with an error or is killed
+    #[default]
+    OnFailure,
+    /// Whenever it exits
+    Always,
+}
+
+impl RestartPolicy {
+    /// Whether a program that exited, `failed` or not, is started again
+    pub fn restarts(&self, failed: bool) -> bool {
+        match self {
+            RestartPolicy::Never => false,
+            RestartPolicy::OnFailure => failed,
+            RestartPolicy::Always => true,
+        }
+    }
+}
+
+/// A program started on every desktop.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChildProgram {
+    /// Name shown for the program
+    pub name: String,
+    /// Command line, run by `sh -c` with `DISPLAY` set to the desktop
+    pub command: String,
+    /// When the program is started again
+    #[serde(default)]
+    pub restart: RestartPolicy,
+}
+
+/// State of a program on one desktop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChildState {
+    /// The program runs
+    Running,
+    /// The program exited and waits to be started again
+    Restarting,
+    /// The program exited successfully and is not restarted
+    Exited,
+    /// The program failed and is not restarted
+    Failed,
+    /// The program was stopped with the session
+    Stopped,
+}
+
+impl fmt::Display for ChildState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ChildState::Running => "running",
+            ChildState::Restarting => "restarting",
+            ChildState::Exited => "exited",
+            ChildState::Failed => "failed",
+            ChildState::Stopped => "stopped",
+        })
+    }
+}
+
+/// What is known about a program on one desktop.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChildStatus {
+    /// Name of the program
+    pub name: String,
+    /// Command line of the program
+    pub command: String,
+    /// When the program is started again
+    pub restart: RestartPolicy,
+    /// State of the program
+    pub state: ChildState,
+    /// Pid of the running program, on our host
+    pub pid: Option<u32>,
+    /// Times the program was started again
+    pub restarts: u32,
+    /// Exit code of the last run, `None` if killed by a signal or still
+    /// on its first run
+    pub exit_code: Option<i32>,
+}
+
+/// The programs of a session, shared between its supervisor, the monitor and
+/// the watchdog.
+#[derive(Debug, Clone, Default)]
+pub struct SessionChildren(Arc<Mutex<Children>>);
+
+#[derive(Debug, Default)]
+struct Children {
+    statuses: Vec<ChildStatus>,
+    /// How programs are run on the display, while its supervisor runs
+    launcher: Option<Launcher>,
+    tasks: JoinSet<()>,
+}
+
+impl SessionChildren {
+    /// Status of every program, in configured order.
+    pub fn statuses(&self) -> Vec<ChildStatus> {
+        self.0.lock().unwrap().statuses.clone()
+    }
+
+    /// Whether the program named `name` runs or is about to be restarted.
+    pub fn is_running(&self, name: &str) -> bool {
+        self.0.lock().unwrap().statuses.iter().any(|status| {
+            status.name == name && matches!(status.state, ChildState::Running | ChildState::Restarting)
+        })
+    }
+
+    /// Start `program` on the display next to the others, in place of an
+    /// exited program of the same name. Fails once the supervisor stopped
+    /// the display's programs, e.g. as the session ended.
+    pub fn start(&self, program: ChildProgram) -> Result<()> {
+        let mut children = self.0.lock().unwrap();
+        let Some(launcher) = children.launcher.clone() else {
+            anyhow::bail!("the programs of the display are stopped");
+        };
+        let status = ChildStatus::new(&program);
+        let index = match children.statuses.iter().position(|status| status.name == program.name) {
+            Some(index) => {
+                let previous = &mut children.statuses[index];
+                if matches!(previous.state, ChildState::Running | ChildState::Restarting) {
+                    anyhow::bail!("{} is already running", program.name);
+                }
+                let restarts = previous.restarts + 1;
+                *previous = ChildStatus { restarts, ..status };
+                index
+            }
+            None => {
+                children.statuses.push(status);
+                children.statuses.len() - 1
+            }
+        };
+        children.tasks.spawn(supervise(launcher, program, index, self.clone()));
+        Ok(())
+    }
+
+    fn update(&self, index: usize, f: impl FnOnce(&mut ChildStatus)) {
+        if let Some(status) = self.0.lock().unwrap().statuses.get_mut(index) {
+            f(status);
+        }
+    }
+}
+
+impl ChildStatus {
+    fn new(program: &ChildProgram) -> Self {
+        Self {
+            name: program.name.clone(),
+            command: program.command.clone(),
+            restart: program.restart,
+            state: ChildState::Restarting,
+            pid: None,
+            restarts: 0,
+            exit_code: None,
+        }
+    }
+}
+
+/// Runs the programs of one desktop until dropped.
+#[derive(Debug)]
+pub struct ChildSupervisor {
+    children: SessionChildren,
+}
+
+impl ChildSupervisor {
+    /// Start `programs` on a display, reporting their state to `children`.
+    pub fn start(display: &XpraDisplay, programs: &[ChildProgram], children: SessionChildren) -> Self {
+        let launcher = Launcher::new(display);
+        let mut shared = children.0.lock().unwrap();
+        shared.statuses = programs.iter().map(ChildStatus::new).collect();
+        shared.launcher = Some(launcher.clone());
+        for (index, program) in programs.iter().enumerate() {
+            shared.tasks.spawn(supervise(launcher.clone(), program.clone(), index, children.clone()));
+        }
+        drop(shared);
+        Self { children }
+    }
+}
+
+/// What running a program on a display takes, as the display's programs
+/// are run.
+#[derive(Debug, Clone)]
+struct Launcher {
+    host: Arc<WorkerHost>,
+    number: u16,
+    /// Network namespace and sandbox of the display
+    prefix: Vec<String>,
+    env: Vec<String>,
+    gpu: Option<(GpuConfig, String)>,
+}
+
+impl Launcher {
+    fn new(display: &XpraDisplay) -> Self {
+        Self {
+            host: display.host().clone(),
+            number: display.display(),
+            prefix: display.command_prefix(),
+            env: display.env().to_vec(),
+            gpu: display.gpu().map(|device| (display.config().gpu.clone(), device.to_string())),
+        }
+    }
+
+    /// Command line running `program` on the display.
+    fn args(&self, program: &ChildProgram) -> Vec<String> {
+        let command = match &self.gpu {
+            Some((config, device)) => config.wrap_command(device, &program.command),
+            None => program.command.clone(),
+        };
+        let mut args = self.prefix.clone();
+        args.push("env".to_string());
+        args.push(format!("DISPLAY=:{}", self.number));
+        args.extend(self.env.iter().cloned());
+        args.extend(["sh".to_string(), "-c".to_string(), command]);
+        args
+    }
+}
+
+/// Run `program` once on a display, never restarting it. The task tells
+/// whether it exited successfully; aborting it kills the program.
+pub fn run_once(display: &XpraDisplay, program: &ChildProgram) -> JoinHandle<bool> {
+    let (host, number) = (display.host().clone(), display.display());
+    let args = Launcher::new(display).args(program);
+    let name = program.name.clone();
+    tokio::spawn(async move {
+        let mut command = tokio::process::Command::from(host.command(&args[0], &args[1..]));
+        command.kill_on_drop(true);
+        match command.status().await {
+            Ok(exit) => {
+                info!(display = number, exit_code = exit.code(), "Desktop program {} exited", name);
+                exit.success()
+            }
+            Err(e) => {
+                warn!(display = number, "Failed to run desktop program {}: {}", name, e);
+                false
+            }
+        }
+    })
+}
+
+impl Drop for ChildSupervisor {
+    fn drop(&mut self) {
+        let mut children = self.children.0.lock().unwrap();
+        // Aborting a task drops its process, which kills it
+        children.launcher = None;
+        children.tasks.abort_all();
+        for status in children.statuses.iter_mut() {
+            if matches!(status.state, ChildState::Running | ChildState::Restarting) {
+                status.state = ChildState::Stopped;
+                status.pid = None;
+            }
+        }
+    }
+}
+
+/// Run one program, starting it again as its restart policy says.
+async fn supervise(launcher: Launcher, program: ChildProgram, index: usize, children: SessionChildren) {
+    let (host, number) = (&launcher.host, launcher.number);
+    let args = launcher.args(&program);
+    let mut delay = RESTART_DELAY;
+    loop {
+        let started = Instant::now();
+        let mut command = tokio::process::Command::from(host.command(&args[0], &args[1..]));
+        command.kill_on_drop(true);
+        let (failed, exit_code) = match command.spawn() {
+            Ok(mut process) => {
+                let pid = process.id();
+                children.update(index, |status| {
+                    status.state = ChildState::Running;
+                    status.pid = pid;
+                });
+                debug!(display = number, pid, "Started desktop program {}", program.name);
+                match process.wait().await {
+                    Ok(exit) => (!exit.success(), exit.code()),
+                    Err(e) => {
+                        warn!(display = number, "Failed to wait for desktop program {}: {}", program.name, e);
+                        (true, None)
+                    }
+                }
+            }
+            Err(e) => {
+                warn!(display = number, "Failed to start desktop program {}: {}", program.name, e);
+                (true, None)
+            }
+        };
+
+        let restart = program.restart.restarts(failed);
+        children.update(index, |status| {
+            status.pid = None;
+            status.exit_code = exit_code;
+            status.state = match (restart, failed) {
+                (true, _) => ChildState::Restarting,
+                (false, true) => ChildState::Failed,
+                (false, false) => ChildState::Exited,
+            };
+        });
+        if !restart {
+            info!(display = number, exit_code, "Desktop program {} exited", program.name);
+            return;
+        }
+
+        // Back off from programs that keep exiting right away
+        if started.elapsed() >= STABLE_RUN {
+            delay = RESTART_DELAY;
+        }
+        debug!(display = number, exit_code, "Restarting desktop program {} in {:?}", program.name, delay);
+        time::sleep(delay).await;
+        delay = (delay * 2).min(MAX_RESTART_DELAY);
+        children.update(index, |status| status.restarts += 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_restart_policy() {
+        assert!(!RestartPolicy::Never.restarts(true));
+        assert!(RestartPolicy::OnFailure.restarts(true));
+        assert!(!RestartPolicy::OnFailure.restarts(false));
+        assert!(RestartPolicy::Always.restarts(false));
+
+        let program: ChildProgram = serde_json::from_str(r#"{"name": "panel", "command": "tint2"}"#).unwrap();
+        assert_eq!(program.restart, RestartPolicy::OnFailure);
+        let program: ChildProgram =
+            serde_json::from_str(r#"{"name": "notes", "command": "xpad", "restart": "always"}"#).unwrap();
+        assert_eq!(program.restart, RestartPolicy::Always);
+    }
+
+    #[tokio::test]
+    async fn test_start_program() {
+        use crate::xpra_config::XpraConfig;
+        use crate::xpra_pool::DisplayPool;
+
+        let children = SessionChildren::default();
+        let program = |command: &str| ChildProgram {
+            name: WINDOW_MANAGER.to_string(),
+            command: command.to_string(),
+            restart: RestartPolicy::Never,
+        };
+        assert!(children.start(program("true")).is_err());
+
+        children.0.lock().unwrap().launcher = Some(Launcher {
+            host: Arc::new(WorkerHost::local(&XpraConfig::default(), DisplayPool::new())),
+            number: 100,
+            prefix: Vec::new(),
+            env: vec!["TZ=Europe/Berlin".to_string()],
+            gpu: None,
+        });
+        children.start(program("[ \"$TZ\" = Europe/Berlin ] && sleep 30")).unwrap();
+        time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(children.statuses()[0].state, ChildState::Running);
+        assert!(children.start(program("true")).is_err());
+
+        // A program that exited is replaced by the next one of its name
+        children.0.lock().unwrap().tasks.abort_all();
+        children.update(0, |status| status.state = ChildState::Stopped);
+        children.start(program("exit 3")).unwrap();
+        for _ in 0..50 {
+            if !children.is_running(WINDOW_MANAGER) {
+                break;
+            }
+            time::sleep(Duration::from_millis(100)).await;
+        }
+        let statuses = children.statuses();
+        assert_eq!(statuses.len(), 1);
+        assert_eq!((statuses[0].state, statuses[0].exit_code, statuses[0].restarts), (ChildState::Failed, Some(3), 1));
+    }
+}
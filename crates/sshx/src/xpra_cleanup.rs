@@ -0,0 +1,263 @@
+//! Removal of the X server files a display leaves behind.
+//!
+//! An X server claims its display with a lock file, `/tmp/.X<N>-lock`
+//! holding its pid, and listens on `/tmp/.X11-unix/X<N>`. When xpra is
+//! killed its X server may exit without removing them, or keep running as
+//! an orphan, and the next xpra started on that number then fails. Before a
+//! display number goes back to its pool, [`clean_display`] waits for the X
+//! server to go away, stopping it if it outlives xpra, and removes the files
+//! it left. Only files of our user written since the display was started
+//! count as ours; others, and the X servers holding them, are left alone.
+//! Since pids are reused, an X server is known by its pid together with its
+//! start time, and a lock file whose pid started after the file was written
+//! belongs to a process that exited.
+
+use std::time::Duration;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use tokio::time;
+use tracing::{debug, info, warn};
+
+use crate::xpra_hosts::WorkerHost;
+
+/// Directory holding the X server lock files and socket directory.
+const X_TMP_DIR: &str = "/tmp";
+
+/// Times the display is checked before giving up on it.
+const CHECK_ATTEMPTS: u32 = 6;
+
+/// Check after which an X server still running is asked to stop.
+const STOP_AFTER: u32 = 3;
+
+/// Time between checks of a display.
+const CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Seconds files may predate the display's start and still count as ours,
+/// for clock differences with remote hosts.
+const CLOCK_SLACK: i64 = 5;
+
+/// Shell function printing the start time of process `$1` in clock ticks
+/// since boot, the 22nd field of its `/proc/<pid>/stat`. The command name
+/// before it may hold spaces, so the fields are counted after its `)`.
+const START_TICKS_FN: &str =
+    r#"start_ticks() { sed 's/.*) //' "/proc/$1/stat" 2>/dev/null | cut -d' ' -f20; }"#;
+
+/// What was found for a display's X server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Leftovers {
+    /// The X server is gone; this many of its files were removed
+    Removed(u32),
+    /// Our X server with this pid, started this many clock ticks after
+    /// boot, still runs
+    Running { pid: u32, start: u64 },
+    /// The display is used by, or its files belong to, another X server
+    Foreign,
+}
+
+/// Wait for the X server of a display started at `started` to go away and
+/// remove the lock file and socket it left behind.
+pub async fn clean_display(host: &WorkerHost, number: u16, started: DateTime<Utc>) {
+    let since = started.timestamp() - CLOCK_SLACK;
+    for attempt in 1..=CHECK_ATTEMPTS {
+        match check_display(host, number, since).await {
+            Ok(Leftovers::Removed(0)) => return,
+            Ok(Leftovers::Removed(removed)) => {
+                info!(host = host.name, display = number, removed, "Removed stale X server files");
+                return;
+            }
+            Ok(Leftovers::Foreign) => {
+                warn!(host = host.name, display = number, "Display is held by files or an X server we did not start");
+                return;
+            }
+            Ok(Leftovers::Running { pid, .. }) if attempt == CHECK_ATTEMPTS => {
+                warn!(host = host.name, display = number, pid, "X server outlived its xpra server and did not stop");
+            }
+            Ok(Leftovers::Running { pid, start }) => {
+                debug!(host = host.name, display = number, pid, "Waiting for X server to exit");
+                if attempt == STOP_AFTER {
+                    stop_x_server(host, pid, start).await;
+                }
+                time::sleep(CHECK_INTERVAL).await;
+            }
+            Err(e) => {
+                warn!(host = host.name, display = number, "Failed to clean up X server files: {:#}", e);
+                return;
+            }
+        }
+    }
+}
+
+async fn check_display(host: &WorkerHost, number: u16, since: i64) -> Result<Leftovers> {
+    let args = ["-c".to_string(), cleanup_script(X_TMP_DIR, number, since)];
+    let output = tokio::process::Command::from(host.command("sh", &args))
+        .output()
+        .await
+        .context("failed to run sh")?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    parse_leftovers(&stdout).with_context(|| format!("unexpected cleanup output {:?}", stdout.trim()))
+}
+
+/// Script printing whether the X server of display `number` still runs or,
+/// if it is gone, removing its files and printing how many there were.
+/// Files are ours if owned by our user and modified at or after `since`, in
+/// seconds since the epoch. The pid in the lock file only counts as its X
+/// server if that process started before the file was last written.
+fn cleanup_script(tmp: &str, number: u16, since: i64) -> String {
+    format!(
+        r#"lock={tmp}/.X{number}-lock
+socket={tmp}/.X11-unix/X{number}
+ours() {{ [ -O "$1" ] && [ "$(stat -c %Y "$1")" -ge {since} ]; }}
+{START_TICKS_FN}
+if [ -e "$lock" ]; then
+  pid=$(tr -dc 0-9 < "$lock")
+  ticks=$(start_ticks "$pid")
+  if [ -n "$pid" ] && [ -n "$ticks" ]; then
+    boot=$(awk '/^btime/ {{ print $2 }}' /proc/stat)
+    started=$((boot + ticks / $(getconf CLK_TCK)))
+    if [ "$started" -le $(($(stat -c %Y "$lock") + 1)) ]; then
+      if ours "$lock"; then echo "running $pid $ticks"; else echo foreign; fi
+      exit 0
+    fi
+  fi
+fi
+removed=0
+for file in "$lock" "$socket"; do
+  [ -e "$file" ] || continue
+  ours "$file" || {{ echo foreign; exit 0; }}
+  rm -f "$file" && removed=$((removed + 1))
+done
+echo "removed $removed"
+"#
+    )
+}
+
+fn parse_leftovers(output: &str) -> Option<Leftovers> {
+    let mut words = output.split_whitespace();
+    match (words.next()?, words.next()) {
+        ("removed", Some(count)) => count.parse().ok().map(Leftovers::Removed),
+        ("running", Some(pid)) => Some(Leftovers::Running {
+            pid: pid.parse().ok()?,
+            start: words.next()?.parse().ok()?,
+        }),
+        ("foreign", None) => Some(Leftovers::Foreign),
+        _ => None,
+    }
+}
+
+/// Script sending `SIGTERM` to process `pid` only if it is still the one
+/// started at `start` ticks, printing "stopped" if so and "exited" if that
+/// process is gone.
+fn stop_script(pid: u32, start: u64) -> String {
+    format!(
+        r#"{START_TICKS_FN}
+if [ "$(start_ticks {pid})" = "{start}" ]; then
+  kill -TERM {pid} && echo stopped
+else
+  echo exited
+fi
+"#
+    )
+}
+
+async fn stop_x_server(host: &WorkerHost, pid: u32, start: u64) {
+    let args = ["-c".to_string(), stop_script(pid, start)];
+    match tokio::process::Command::from(host.command("sh", &args)).output().await {
+        Ok(output) => match String::from_utf8_lossy(&output.stdout).trim() {
+            "stopped" => info!(host = host.name, pid, "Stopped X server left behind by xpra"),
+            "exited" => debug!(host = host.name, pid, "X server exited before it was stopped"),
+            _ => warn!(host = host.name, pid, "Failed to stop X server: {}", String::from_utf8_lossy(&output.stderr).trim()),
+        },
+        Err(e) => warn!(host = host.name, pid, "Failed to stop X server: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(tmp: &std::path::Path, number: u16, since: i64) -> Option<Leftovers> {
+        let output = std::process::Command::new("sh")
+            .args(["-c", &cleanup_script(tmp.to_str().unwrap(), number, since)])
+            .output()
+            .unwrap();
+        parse_leftovers(&String::from_utf8_lossy(&output.stdout))
+    }
+
+    #[test]
+    fn test_cleanup_script() {
+        let tmp = std::env::temp_dir().join(format!("sshx-cleanup-{}", std::process::id()));
+        std::fs::create_dir_all(tmp.join(".X11-unix")).unwrap();
+
+        let since = Utc::now().timestamp() - CLOCK_SLACK;
+
+        // Nothing to do for an unused display
+        assert_eq!(run(&tmp, 100, since), Some(Leftovers::Removed(0)));
+
+        // A live X server is waited for, unless it predates the display
+        std::fs::write(tmp.join(".X100-lock"), format!("{:>10}\n", std::process::id())).unwrap();
+        std::fs::write(tmp.join(".X11-unix/X100"), "").unwrap();
+        let pid = std::process::id();
+        assert!(matches!(run(&tmp, 100, since), Some(Leftovers::Running { pid: p, .. }) if p == pid));
+        assert_eq!(run(&tmp, 100, since + 3600), Some(Leftovers::Foreign));
+
+        // A pid that started after its lock file was written was reused
+        let hour_ago = std::time::SystemTime::now() - Duration::from_secs(3600);
+        std::fs::File::options()
+            .write(true)
+            .open(tmp.join(".X100-lock"))
+            .unwrap()
+            .set_modified(hour_ago)
+            .unwrap();
+        assert_eq!(run(&tmp, 100, since - 7200), Some(Leftovers::Removed(2)));
+        std::fs::write(tmp.join(".X100-lock"), format!("{:>10}\n", pid)).unwrap();
+        std::fs::write(tmp.join(".X11-unix/X100"), "").unwrap();
+
+        // Files of a dead one are removed
+        std::fs::write(tmp.join(".X100-lock"), format!("{:>10}\n", u32::MAX / 2)).unwrap();
+        assert_eq!(run(&tmp, 100, since + 3600), Some(Leftovers::Foreign));
+        assert_eq!(run(&tmp, 100, since), Some(Leftovers::Removed(2)));
+        assert!(!tmp.join(".X100-lock").exists());
+        assert!(!tmp.join(".X11-unix/X100").exists());
+
+        std::fs::remove_dir_all(&tmp).unwrap();
+    }
+
+    #[test]
+    fn test_stop_script() {
+        let mut child = std::process::Command::new("sleep").arg("60").spawn().unwrap();
+        let start_ticks = |pid: u32| {
+            let output = std::process::Command::new("sh")
+                .args(["-c", &format!("{START_TICKS_FN}\nstart_ticks {pid}")])
+                .output()
+                .unwrap();
+            String::from_utf8_lossy(&output.stdout).trim().parse::<u64>().unwrap()
+        };
+        let stop = |pid: u32, start: u64| {
+            let output = std::process::Command::new("sh")
+                .args(["-c", &stop_script(pid, start)])
+                .output()
+                .unwrap();
+            String::from_utf8_lossy(&output.stdout).trim().to_string()
+        };
+
+        // A process with the same pid but another start time is left alone
+        let start = start_ticks(child.id());
+        assert_eq!(stop(child.id(), start + 1), "exited");
+        assert!(child.try_wait().unwrap().is_none());
+
+        assert_eq!(stop(child.id(), start), "stopped");
+        assert!(!child.wait().unwrap().success());
+    }
+
+    #[test]
+    fn test_parse_leftovers() {
+        assert_eq!(parse_leftovers("removed 2\n"), Some(Leftovers::Removed(2)));
+        assert_eq!(
+            parse_leftovers("running 4242 123\n"),
+            Some(Leftovers::Running { pid: 4242, start: 123 })
+        );
+        assert_eq!(parse_leftovers("running 4242\n"), None);
+        assert_eq!(parse_leftovers("foreign\n"), Some(Leftovers::Foreign));
+        assert_eq!(parse_leftovers("sh: tr: not found\n"), None);
+    }
+}
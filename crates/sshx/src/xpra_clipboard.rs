@@ -0,0 +1,344 @@
+//! Filtering of the clipboard contents passing between a desktop and its
+//! client.
+//!
+//! Clipboard policies are keyed by user name, `@group`, or `*` for everyone,
+//! like the time budgets. A policy limits the size of the contents copied
+//! in either direction, lets through only the types it allows, and blocks
+//! contents matching any of its patterns, such as card numbers. The
+//! forwarder reads the `clipboard-token` and `clipboard-contents` packets
+//! of xpra, with the raw chunks they come with, and drops the blocked ones
+//! whole: the other side sees nothing was copied. Contents that cannot be
+//! read are blocked whenever a size or pattern has to be checked.
+//!
+//! Every blocked transfer is logged and counted in the metrics.
+
+use std::fmt;
+use regex::Regex;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::xpra_protocol::{clipboard_packet, packet_len, ClipboardPacket, ClipboardTransfer};
+
+/// What may be copied to and from the desktops of a user.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ClipboardPolicy {
+    /// Largest contents let through, in bytes
+    #[serde(default)]
+    pub max_size: Option<usize>,
+
+    /// Types of the contents let through, e.g. `text/plain` or `image/*`;
+    /// any if empty
+    #[serde(default)]
+    pub mime_types: Vec<String>,
+
+    /// Regular expressions of the contents blocked
+    #[serde(default)]
+    pub block_patterns: Vec<BlockPattern>,
+}
+
+/// Regular expression of clipboard contents to block, compiled when the
+/// configuration is read.
+#[derive(Debug, Clone)]
+pub struct BlockPattern(Regex);
+
+impl Serialize for BlockPattern {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.0.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for BlockPattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let pattern = String::deserialize(deserializer)?;
+        Regex::new(&pattern).map(BlockPattern).map_err(serde::de::Error::custom)
+    }
+}
+
+impl ClipboardPolicy {
+    /// Why `transfer` is blocked, if it is.
+    fn check(&self, transfer: &ClipboardTransfer) -> Option<BlockReason> {
+        if transfer.data.is_none() {
+            return Some(BlockReason::Unreadable);
+        }
+        if let Some(max_size) = self.max_size.filter(|max| transfer.size > *max) {
+            return Some(BlockReason::TooLarge(max_size));
+        }
+        let mime_type = mime_type(&transfer.target);
+        if !self.mime_types.is_empty() && !self.mime_types.iter().any(|allowed| type_allowed(allowed, mime_type)) {
+            return Some(BlockReason::Type(mime_type.to_string()));
+        }
+        let text = String::from_utf8_lossy(transfer.data.as_deref().unwrap_or_default());
+        let pattern = self.block_patterns.iter().find(|pattern| pattern.0.is_match(&text))?;
+        Some(BlockReason::Pattern(pattern.0.as_str().to_string()))
+    }
+}
+
+/// MIME type of the clipboard target `target`; X's text targets are
+/// `text/plain`.
+fn mime_type(target: &str) -> &str {
+    match target {
+        "UTF8_STRING" | "STRING" | "TEXT" | "COMPOUND_TEXT" => "text/plain",
+        _ => target.split(';').next().unwrap_or(target).trim(),
+    }
+}
+
+/// Whether the allowlist entry `allowed`, a type or `type/*`, allows
+/// `mime_type`.
+fn type_allowed(allowed: &str, mime_type: &str) -> bool {
+    match allowed.strip_suffix("/*") {
+        Some(prefix) => mime_type.split('/').next() == Some(prefix),
+        None => allowed.eq_ignore_ascii_case(mime_type),
+    }
+}
+
+/// Side the clipboard contents are copied to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClipboardDirection {
+    /// From the client to the desktop
+    ToDesktop,
+    /// From the desktop to the client
+    ToClient,
+}
+
+impl fmt::Display for ClipboardDirection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClipboardDirection::ToDesktop => write!(f, "to desktop"),
+            ClipboardDirection::ToClient => write!(f, "to client"),
+        }
+    }
+}
+
+/// Why a clipboard transfer was blocked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockReason {
+    /// The contents are larger than the given size
+    TooLarge(usize),
+    /// The contents are of a type not allowed
+    Type(String),
+    /// The contents match the given pattern
+    Pattern(String),
+    /// The contents could not be read to be checked
+    Unreadable,
+}
+
+impl fmt::Display for BlockReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockReason::TooLarge(max_size) => write!(f, "larger than {max_size} bytes"),
+            BlockReason::Type(mime_type) => write!(f, "type {mime_type} not allowed"),
+            BlockReason::Pattern(pattern) => write!(f, "matches {pattern}"),
+            BlockReason::Unreadable => write!(f, "contents unreadable"),
+        }
+    }
+}
+
+/// A clipboard transfer dropped from the stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClipboardBlock {
+    /// Side the contents were copied to
+    pub direction: ClipboardDirection,
+    /// Clipboard target of the contents, empty if unknown
+    pub target: String,
+    /// Size of the contents in bytes
+    pub size: usize,
+    /// Why it was blocked
+    pub reason: BlockReason,
+}
+
+/// xpra packets of one direction, split from the websocket messages.
+#[derive(Debug, Default)]
+struct PacketStream {
+    /// Start of a packet continued in the next message
+    buffer: Vec<u8>,
+    /// Raw chunks held until the packet they belong to
+    chunks: Vec<Vec<u8>>,
+    /// Set when the stream stopped looking like xpra packets; nothing is
+    /// let through after
+    lost: bool,
+}
+
+/// Applies a user's clipboard policy to both directions of a connection to
+/// xpra.
+#[derive(Debug)]
+pub struct ClipboardFilter {
+    policy: ClipboardPolicy,
+    to_desktop: PacketStream,
+    to_client: PacketStream,
+}
+
+impl ClipboardFilter {
+    /// Filter a connection with `policy`.
+    pub fn new(policy: ClipboardPolicy) -> Self {
+        Self { policy, to_desktop: PacketStream::default(), to_client: PacketStream::default() }
+    }
+
+    /// Start filtering a new connection to xpra.
+    pub fn reset(&mut self) {
+        self.to_desktop = PacketStream::default();
+        self.to_client = PacketStream::default();
+    }
+
+    /// Filter the next message sent `direction`, returning the whole
+    /// packets it completes that are let through, and the transfers
+    /// blocked. Packets that cannot be read are blocked too, as they may
+    /// carry the clipboard.
+    pub fn push(&mut self, direction: ClipboardDirection, data: Vec<u8>) -> (Vec<u8>, Vec<ClipboardBlock>) {
+        let stream = match direction {
+            ClipboardDirection::ToDesktop => &mut self.to_desktop,
+            ClipboardDirection::ToClient => &mut self.to_client,
+        };
+        if stream.lost {
+            return (Vec::new(), Vec::new());
+        }
+        let data = match stream.buffer.is_empty() {
+            true => data,
+            false => {
+                let mut buffer = std::mem::take(&mut stream.buffer);
+                buffer.extend_from_slice(&data);
+                buffer
+            }
+        };
+        let mut passed = Vec::with_capacity(data.len());
+        let mut blocked = Vec::new();
+        let mut pos = 0;
+        while pos < data.len() {
+            let rest = &data[pos..];
+            if rest[0] != b'P' {
+                stream.lost = true;
+                stream.chunks.clear();
+                let reason = BlockReason::Unreadable;
+                blocked.push(ClipboardBlock { direction, target: String::new(), size: rest.len(), reason });
+                return (passed, blocked);
+            }
+            let Some(len) = packet_len(rest).filter(|len| *len <= rest.len()) else {
+                break;
+            };
+            let packet = &rest[..len];
+            pos += len;
+            if packet[3] > 0 {
+                stream.chunks.push(packet.to_vec());
+                continue;
+            }
+            let chunks = std::mem::take(&mut stream.chunks);
+            let block = match clipboard_packet(packet, &chunks) {
+                ClipboardPacket::Other => None,
+                ClipboardPacket::Transfer(transfer) => self.policy.check(&transfer).map(|reason| ClipboardBlock {
+                    direction,
+                    target: transfer.target,
+                    size: transfer.size,
+                    reason,
+                }),
+                ClipboardPacket::Unreadable => Some(ClipboardBlock {
+                    direction,
+                    target: String::new(),
+                    size: packet.len() + chunks.iter().map(Vec::len).sum::<usize>(),
+                    reason: BlockReason::Unreadable,
+                }),
+            };
+            match block {
+                Some(block) => blocked.push(block),
+                None => {
+                    passed.extend(chunks.into_iter().flatten());
+                    passed.extend_from_slice(packet);
+                }
+            }
+        }
+        stream.buffer = data[pos..].to_vec();
+        (passed, blocked)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(index: u8, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![b'P', 0, 0, index];
+        packet.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    /// A `clipboard-token` packet copying `data` as `target`.
+    fn token(target: &str, data: &str) -> Vec<u8> {
+        let payload = format!(
+            "l15:clipboard-token9:CLIPBOARDl{len_t}:{target}e{len_t}:{target}{len_t}:{target}i8e5:bytes{}:{data}i1ei0ei0ee",
+            data.len(),
+            len_t = target.len(),
+        );
+        packet(0, payload.as_bytes())
+    }
+
+    fn policy(json: &str) -> ClipboardPolicy {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn test_check() {
+        let transfer = |target: &str, data: &[u8]| ClipboardTransfer {
+            target: target.to_string(),
+            size: data.len(),
+            data: Some(data.to_vec()),
+        };
+        let policy = policy(r#"{"max_size": 8, "mime_types": ["text/plain", "image/*"], "block_patterns": ["\\b(?:\\d[ -]?){13,16}\\b"]}"#);
+        assert_eq!(policy.check(&transfer("UTF8_STRING", b"hello")), None);
+        assert_eq!(policy.check(&transfer("image/png", b"png")), None);
+        assert_eq!(policy.check(&transfer("UTF8_STRING", b"hello world")), Some(BlockReason::TooLarge(8)));
+        assert_eq!(policy.check(&transfer("text/html", b"<b>")), Some(BlockReason::Type("text/html".to_string())));
+        let policy = ClipboardPolicy { max_size: None, ..policy };
+        let card = policy.check(&transfer("text/plain;charset=utf-8", b"card 4111 1111 1111 1111"));
+        assert!(matches!(card, Some(BlockReason::Pattern(_))));
+        let unread = ClipboardTransfer { data: None, ..transfer("UTF8_STRING", b"") };
+        assert_eq!(policy.check(&unread), Some(BlockReason::Unreadable));
+        // Contents that are not read are blocked whatever the policy
+        assert_eq!(ClipboardPolicy::default().check(&unread), Some(BlockReason::Unreadable));
+
+        assert!(serde_json::from_str::<ClipboardPolicy>(r#"{"block_patterns": ["("]}"#).is_err());
+    }
+
+    #[test]
+    fn test_filter() {
+        let mut filter = ClipboardFilter::new(policy(r#"{"block_patterns": ["secret"]}"#));
+        let hello = packet(0, b"l5:helloe");
+        let allowed = token("UTF8_STRING", "hello");
+        let secret = token("UTF8_STRING", "top secret");
+
+        let message = [hello.clone(), secret.clone(), allowed.clone()].concat();
+        let (passed, blocked) = filter.push(ClipboardDirection::ToClient, message);
+        assert_eq!(passed, [hello.clone(), allowed.clone()].concat());
+        assert_eq!(blocked.len(), 1);
+        assert_eq!(blocked[0].direction, ClipboardDirection::ToClient);
+        assert_eq!(blocked[0].reason, BlockReason::Pattern("secret".to_string()));
+
+        // Packets split across messages are passed once whole
+        let (first, second) = secret.split_at(20);
+        assert_eq!(filter.push(ClipboardDirection::ToDesktop, first.to_vec()), (Vec::new(), Vec::new()));
+        let (passed, blocked) = filter.push(ClipboardDirection::ToDesktop, second.to_vec());
+        assert!(passed.is_empty());
+        assert_eq!(blocked.len(), 1);
+
+        // Chunks are held for their packet and dropped with it
+        let chunk = packet(6, b"secret");
+        let contents = packet(0, b"l18:clipboard-contentsi3e9:CLIPBOARD11:UTF8_STRINGi8e5:bytesi0ee");
+        let (passed, blocked) = filter.push(ClipboardDirection::ToDesktop, chunk.clone());
+        assert!(passed.is_empty() && blocked.is_empty());
+        let (passed, blocked) = filter.push(ClipboardDirection::ToDesktop, [contents, hello.clone()].concat());
+        assert_eq!((passed, blocked.len()), (hello.clone(), 1));
+        let (passed, _) = filter.push(ClipboardDirection::ToDesktop, [chunk.clone(), hello.clone()].concat());
+        assert_eq!(passed, [chunk, hello.clone()].concat());
+
+        // Packets that cannot be read are blocked
+        let mut encrypted = allowed.clone();
+        encrypted[1] = 0x02;
+        let (passed, blocked) = filter.push(ClipboardDirection::ToClient, [encrypted, hello.clone()].concat());
+        assert_eq!(passed, hello);
+        assert_eq!(blocked[0].reason, BlockReason::Unreadable);
+
+        // Nothing passes once the stream stops looking like xpra packets
+        let (passed, blocked) = filter.push(ClipboardDirection::ToClient, [hello.clone(), b"GET /".to_vec()].concat());
+        assert_eq!((passed, blocked.len()), (hello.clone(), 1));
+        assert_eq!(filter.push(ClipboardDirection::ToClient, allowed), (Vec::new(), Vec::new()));
+        filter.reset();
+        assert_eq!(filter.push(ClipboardDirection::ToClient, hello.clone()).0, hello);
+    }
+}
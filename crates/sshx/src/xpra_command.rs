@@ -0,0 +1,327 @@
+//! Checked construction of the command line starting an xpra server.
+//!
+//! The window manager and app a display starts with come from the config,
+//! but also from clients picking templates, from the admission policy and
+//! from headless launches. [`XpraCommandBuilder`] assembles the command from
+//! typed parts and refuses to build it unless the display number, port and
+//! bind address are valid, the window manager and app are on the
+//! [`ProgramAllowlist`], and no part holds characters that could be taken as
+//! a further argument or line by xpra, the shell or ssh.
+
+use std::collections::BTreeSet;
+use std::net::IpAddr;
+use anyhow::Result;
+
+use crate::xpra::DisplayOptions;
+use crate::xpra_auth::{SessionToken, TOKEN_VAR};
+use crate::xpra_capabilities::XpraCapabilities;
+use crate::xpra_config::XpraConfig;
+use crate::xpra_gpu::GpuConfig;
+use crate::xpra_hosts::HostKind;
+use crate::xpra_tls::TlsConfig;
+
+/// Lowest port a display's websocket may listen on.
+const MIN_PORT: u16 = 1024;
+
+/// Window managers and programs displays may be started with.
+#[derive(Debug, Clone, Default)]
+pub struct ProgramAllowlist {
+    programs: BTreeSet<String>,
+}
+
+impl ProgramAllowlist {
+    /// The programs the config names: the window manager, the session
+    /// templates and apps, and `allowed_programs`.
+    pub fn from_config(config: &XpraConfig) -> Self {
+        let mut programs = BTreeSet::new();
+        programs.insert(config.window_manager.clone());
+        programs.extend(config.session_params.templates.values().cloned());
+        programs.extend(config.session_params.apps.iter().cloned());
+        programs.extend(config.allowed_programs.iter().cloned());
+        Self { programs }
+    }
+
+    /// Check that `program` may be started, as what it is `used` for.
+    pub fn check(&self, program: &str, used: &str) -> Result<()> {
+        check_argument(program, used)?;
+        if !self.programs.contains(program) {
+            anyhow::bail!("{used} {program:?} is not allowed; add it to allowed_programs to start it");
+        }
+        Ok(())
+    }
+}
+
+/// Command line of an xpra server, checked as it is built.
+#[derive(Debug)]
+pub struct XpraCommandBuilder<'a> {
+    capabilities: &'a XpraCapabilities,
+    allowlist: &'a ProgramAllowlist,
+    prefix: Vec<String>,
+    env: Vec<String>,
+    wrappers: Vec<String>,
+    display: Option<u16>,
+    bind: Option<(String, u16)>,
+    window_manager: Option<String>,
+    x_server: Option<String>,
+    options: DisplayOptions,
+    gpu: Option<(&'a GpuConfig, String)>,
+    /// Set if connections authenticate, true if xpra runs over SSH
+    auth: Option<bool>,
+    tls: Option<&'a TlsConfig>,
+}
+
+impl<'a> XpraCommandBuilder<'a> {
+    /// Start building a command for an xpra with `capabilities`, starting
+    /// only programs on `allowlist`.
+    pub fn new(capabilities: &'a XpraCapabilities, allowlist: &'a ProgramAllowlist) -> Self {
+        Self {
+            capabilities,
+            allowlist,
+            prefix: Vec::new(),
+            env: Vec::new(),
+            wrappers: Vec::new(),
+            display: None,
+            bind: None,
+            window_manager: None,
+            x_server: None,
+            options: DisplayOptions::default(),
+            gpu: None,
+            auth: None,
+            tls: None,
+        }
+    }
+
+    /// Run the command under a program like `nice`, before its environment
+    /// is set.
+    pub fn prefix(mut self, args: impl IntoIterator<Item = String>) -> Self {
+        self.prefix.extend(args);
+        self
+    }
+
+    /// Set `NAME=value` variables for xpra and the programs it starts.
+    pub fn env(mut self, vars: impl IntoIterator<Item = String>) -> Self {
+        self.env.extend(vars);
+        self
+    }
+
+    /// Run xpra through a program like `ip netns exec`, with its
+    /// environment set.
+    pub fn wrap(mut self, args: impl IntoIterator<Item = String>) -> Self {
+        self.wrappers.extend(args);
+        self
+    }
+
+    /// Display number of the X server.
+    pub fn display(mut self, number: u16) -> Self {
+        self.display = Some(number);
+        self
+    }
+
+    /// Address and port the websocket listens on.
+    pub fn bind(mut self, address: &str, port: u16) -> Self {
+        self.bind = Some((address.to_string(), port));
+        self
+    }
+
+    /// Window manager, or program, the session lasts as long as.
+    pub fn window_manager(mut self, wm: &str) -> Self {
+        self.window_manager = Some(wm.to_string());
+        self
+    }
+
+    /// Command line of the virtual X server.
+    pub fn x_server(mut self, x_server: &str) -> Self {
+        self.x_server = Some(x_server.to_string());
+        self
+    }
+
+    /// App, audio and locale the display is started with.
+    pub fn options(mut self, options: &DisplayOptions) -> Self {
+        self.options = options.clone();
+        self
+    }
+
+    /// Render the window manager and app on a GPU device.
+    pub fn gpu(mut self, config: &'a GpuConfig, device: &str) -> Self {
+        self.gpu = Some((config, device.to_string()));
+        self
+    }
+
+    /// Require connections to authenticate with a token, which xpra reads
+    /// from its standard input, of `ssh -T` on hosts of `kind` reached over
+    /// SSH.
+    pub fn auth(mut self, kind: &HostKind) -> Self {
+        self.auth = Some(matches!(kind, HostKind::Ssh { .. }));
+        self
+    }
+
+    /// Serve the websocket over TLS, only to clients with a certificate
+    /// `tls` trusts.
+    pub fn tls(mut self, tls: &'a TlsConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// The command and its arguments, if every part is valid.
+    pub fn build(self) -> Result<Vec<String>> {
+        let Some(number) = self.display else {
+            anyhow::bail!("no display number given");
+        };
+        if number == 0 {
+            anyhow::bail!("display :0 is reserved for the host's own display");
+        }
+        let Some((address, port)) = &self.bind else {
+            anyhow::bail!("no address to bind to given");
+        };
+        if address.parse::<IpAddr>().is_err() {
+            anyhow::bail!("bind address {address:?} is not an IP address");
+        }
+        if *port < MIN_PORT {
+            anyhow::bail!("port {port} is below {MIN_PORT}");
+        }
+        let Some(wm) = &self.window_manager else {
+            anyhow::bail!("no window manager given");
+        };
+        let mut options = self.options.clone();
+        let wm = options.backend.backend().desktop_program(wm, &options, self.allowlist)?;
+        check_argument(&wm, "desktop program")?;
+        let Some(x_server) = &self.x_server else {
+            anyhow::bail!("no X server given");
+        };
+        check_argument(x_server, "X server")?;
+        if let Some(tls) = self.tls {
+            for path in tls.worker_paths() {
+                check_argument(&path.to_string_lossy(), "TLS file")?;
+            }
+        }
+        for var in &self.env {
+            check_env(var)?;
+        }
+        // Wrappers may be scripts spanning lines, but a NUL would end them
+        if self.prefix.iter().chain(&self.wrappers).any(|arg| arg.contains('\0')) {
+            anyhow::bail!("a wrapper of xpra contains a NUL character");
+        }
+
+        // Programs are checked before they are wrapped to run on the GPU
+        let mut wm = match &self.gpu {
+            Some((config, device)) => {
+                options.app = options.app.map(|app| config.wrap_command(device, &app));
+                config.wrap_command(device, &wm)
+            }
+            None => wm,
+        };
+        // Only xpra itself needs the token
+        if self.auth.is_some() {
+            options.app = options.app.map(|app| SessionToken::unset_prefix() + &app);
+            wm = SessionToken::unset_prefix() + &wm;
+        }
+        let mut command = self.prefix;
+        if !self.env.is_empty() {
+            command.push("env".to_string());
+            command.extend(self.env);
+        }
+        match self.auth {
+            Some(true) => command.extend(SessionToken::ssh_command_prefix()),
+            Some(false) => command.extend(SessionToken::command_prefix()),
+            None => {}
+        }
+        command.extend(self.wrappers);
+        command.push("xpra".to_string());
+        command.extend(self.capabilities.start_args(number, &wm, x_server, &options));
+        command.extend(self.capabilities.bind_args(address, *port, self.tls));
+        if let Some((config, device)) = &self.gpu {
+            command.extend(config.xpra_args(device));
+        }
+        if self.auth.is_some() {
+            command.extend(self.capabilities.auth_args(TOKEN_VAR, self.tls.is_some()));
+        }
+        Ok(command)
+    }
+}
+
+/// Check that an argument is not empty and holds no control characters,
+/// which could end a line or the argument early.
+fn check_argument(arg: &str, used: &str) -> Result<()> {
+    if arg.trim().is_empty() {
+        anyhow::bail!("{used} is empty");
+    }
+    if arg.chars().any(char::is_control) {
+        anyhow::bail!("{used} {arg:?} contains control characters");
+    }
+    Ok(())
+}
+
+/// Check that a variable is `NAME=value` with a shell-style name.
+fn check_env(var: &str) -> Result<()> {
+    let valid_name = |name: &str| {
+        name.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    };
+    match var.split_once('=') {
+        Some((name, value)) if valid_name(name) => {
+            if value.chars().any(char::is_control) {
+                anyhow::bail!("variable {name} contains control characters");
+            }
+            Ok(())
+        }
+        _ => anyhow::bail!("{var:?} is not a NAME=value environment variable"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allowlist() -> ProgramAllowlist {
+        let config = XpraConfig {
+            window_manager: "xfce4-session".to_string(),
+            allowed_programs: vec!["firefox".to_string()],
+            ..XpraConfig::default()
+        };
+        ProgramAllowlist::from_config(&config)
+    }
+
+    fn builder<'a>(capabilities: &'a XpraCapabilities, allowlist: &'a ProgramAllowlist) -> XpraCommandBuilder<'a> {
+        XpraCommandBuilder::new(capabilities, allowlist)
+            .display(100)
+            .bind("127.0.0.1", 14500)
+            .window_manager("xfce4-session")
+            .x_server("Xvfb +extension GLX")
+    }
+
+    #[test]
+    fn test_build() {
+        let (capabilities, allowlist) = (XpraCapabilities::default(), allowlist());
+        let options = DisplayOptions { app: Some("firefox".to_string()), ..DisplayOptions::default() };
+        let command = builder(&capabilities, &allowlist)
+            .prefix(["nice".to_string(), "-n".to_string(), "5".to_string()])
+            .env(["TZ=Europe/Berlin".to_string()])
+            .wrap(["ip".to_string(), "netns".to_string(), "exec".to_string(), "sshx-100".to_string()])
+            .options(&options)
+            .build()
+            .unwrap();
+        assert_eq!(command[..10], ["nice", "-n", "5", "env", "TZ=Europe/Berlin", "ip", "netns", "exec", "sshx-100", "xpra"]);
+        assert!(command.contains(&":100".to_string()));
+        assert!(command.contains(&"--start=xfce4-session".to_string()));
+        assert!(command.contains(&"--start=firefox".to_string()));
+        let command = builder(&capabilities, &allowlist).options(&options).auth(&HostKind::Local).build().unwrap();
+        assert_eq!(command[..2], ["sh", "-c"]);
+        assert_eq!(command.last().unwrap(), "--ws-auth=env:name=SSHX_XPRA_TOKEN");
+        assert!(command.contains(&"--start=env -u SSHX_XPRA_TOKEN xfce4-session".to_string()));
+        assert!(command.contains(&"--start=env -u SSHX_XPRA_TOKEN firefox".to_string()));
+    }
+
+    #[test]
+    fn test_refused() {
+        let (capabilities, allowlist) = (XpraCapabilities::default(), allowlist());
+        let refused = |builder: XpraCommandBuilder| builder.build().unwrap_err().to_string();
+        assert!(refused(builder(&capabilities, &allowlist).display(0)).contains("reserved"));
+        assert!(refused(builder(&capabilities, &allowlist).bind("0.0.0.0 --daemon=yes", 14500)).contains("IP address"));
+        assert!(refused(builder(&capabilities, &allowlist).bind("0.0.0.0", 80)).contains("below"));
+        assert!(refused(builder(&capabilities, &allowlist).window_manager("xterm -e rm -rf ~")).contains("not allowed"));
+        let options = DisplayOptions { app: Some("firefox\n--daemon=yes".to_string()), ..DisplayOptions::default() };
+        assert!(refused(builder(&capabilities, &allowlist).options(&options)).contains("control characters"));
+        assert!(refused(builder(&capabilities, &allowlist).env(["LD_PRELOAD =x".to_string()])).contains("NAME=value"));
+        assert!(refused(builder(&capabilities, &allowlist).env(["TZ=a\nb".to_string()])).contains("control characters"));
+    }
+}
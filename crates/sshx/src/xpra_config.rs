@@ -1,6 +1,47 @@
+//! Configuration of the Xpra desktop sessions started on this host.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::time::Duration;
+use anyhow::Result;
 use serde::{Deserialize, Serialize};
 
+use crate::xpra_affinity::AffinityConfig;
+use crate::xpra_archive::ArchiveTarget;
+use crate::xpra_branding::BrandingConfig;
+use crate::xpra_children::ChildProgram;
+use crate::xpra_clipboard::ClipboardPolicy;
+use crate::xpra_crash::CrashConfig;
+use crate::xpra_devices::DeviceConfig;
+use crate::xpra_gpu::GpuConfig;
+use crate::xpra_handshake::RotationPolicy;
+use crate::xpra_headless::HeadlessConfig;
+use crate::xpra_hibernate::IdlePolicy;
+use crate::xpra_hooks::HooksConfig;
+use crate::xpra_hosts::HostConfig;
+use crate::xpra_idle::IdleSource;
+use crate::xpra_log_rotation::{DiskLimits, LogCompression};
+use crate::xpra_maintenance::MaintenanceWindow;
+use crate::xpra_motd::MotdConfig;
+use crate::xpra_netns::NetworkConfig;
+use crate::xpra_placement::PlacementStrategy;
+use crate::xpra_policy::AdmissionPolicyConfig;
+use crate::xpra_priority::PriorityConfig;
+use crate::xpra_profiles::ProfileConfig;
+use crate::xpra_proxy::ProxyConfig;
+use crate::xpra_publisher::PublisherConfig;
+use crate::xpra_quota::TimeBudget;
+use crate::xpra_sandbox::SandboxConfig;
+use crate::xpra_screenshots::ScreenshotConfig;
+use crate::xpra_session_id::SessionIdScheme;
+use crate::xpra_session_params::SessionParamsConfig;
+use crate::xpra_time_format::TimeDisplay;
+use crate::xpra_watchdog::WatchdogConfig;
+use crate::xpra_watermark::WatermarkConfig;
+use crate::xpra_xorg::XBackend;
+
+/// Settings for starting, limiting, and cleaning up Xpra sessions.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct XpraConfig {
     /// Minimum display number to allocate
@@ -19,21 +60,319 @@ pub struct XpraConfig {
     #[serde(default = "default_window_manager")]
     pub window_manager: String,
 
+    /// XKB keyboard layout of the desktops, e.g. `de` or `ch(fr)`; xpra
+    /// follows the keyboard of the client when not set
+    #[serde(default)]
+    pub keyboard_layout: Option<String>,
+
+    /// Further window managers and programs displays may be started with,
+    /// e.g. by the admission policy or headless launches
+    #[serde(default)]
+    pub allowed_programs: Vec<String>,
+
+    /// Require connections to each display's xpra to authenticate with a
+    /// token only the forwarder knows
+    #[serde(default)]
+    pub socket_auth: bool,
+
+    /// Programs started on every desktop next to the window manager
+    #[serde(default)]
+    pub children: Vec<ChildProgram>,
+
+    /// Message shown on each new desktop
+    #[serde(default)]
+    pub motd: MotdConfig,
+
+    /// Wallpaper, theme and overlay of new desktops
+    #[serde(default)]
+    pub branding: BrandingConfig,
+
+    /// Watermark naming the user on the desktops of some users
+    #[serde(default)]
+    pub watermark: WatermarkConfig,
+
+    /// Proxy and extra CA certificates for the programs on the desktops
+    #[serde(default)]
+    pub proxy: ProxyConfig,
+
+    /// Where and for how long the browser profiles of app sessions are kept
+    #[serde(default)]
+    pub profiles: ProfileConfig,
+
+    /// Devices of the client forwarded to desktops, by user
+    #[serde(default)]
+    pub devices: DeviceConfig,
+
+    /// Clipboard policies keyed by user name, `@group`, or `*` for everyone
+    #[serde(default)]
+    pub clipboard: HashMap<String, ClipboardPolicy>,
+
+    /// Network namespaces and egress rules isolating the desktops
+    #[serde(default)]
+    pub network: NetworkConfig,
+
+    /// Seccomp and Landlock sandbox of the xpra process tree
+    #[serde(default)]
+    pub sandbox: SandboxConfig,
+
+    /// Virtual X server used for displays
+    #[serde(default)]
+    pub x_backend: XBackend,
+
+    /// Largest screen size a display can be resized to, as WIDTHxHEIGHT
+    #[serde(default = "default_max_resolution")]
+    pub max_resolution: String,
+
+    /// Existing xorg.conf to use with Xdummy instead of the generated one
+    #[serde(default)]
+    pub xorg_conf: Option<PathBuf>,
+
+    /// GPU devices and hardware acceleration for displays
+    #[serde(default)]
+    pub gpu: GpuConfig,
+
+    /// Pinning of sessions to CPU sets or NUMA nodes
+    #[serde(default)]
+    pub cpu_affinity: AffinityConfig,
+
+    /// nice, ionice and OOM score settings per session class
+    #[serde(default)]
+    pub priority: PriorityConfig,
+
+    /// Seconds a new display has to start accepting connections
+    #[serde(default = "default_startup_timeout")]
+    pub startup_timeout: u64,
+
+    /// Seconds a starting session's display number is leased for; if the
+    /// session is not running by then, the number returns to the pool
+    #[serde(default = "default_lease_timeout")]
+    pub lease_timeout: u64,
+
+    /// Seconds each attempt to connect to a display's websocket may take
+    #[serde(default = "default_connect_timeout")]
+    pub connect_timeout: u64,
+
+    /// Attempts to connect to a display's websocket before the session fails
+    #[serde(default = "default_connect_attempts")]
+    pub connect_attempts: u32,
+
+    /// Seconds xpra may send nothing to an attached client before the
+    /// session fails (0 = no timeout)
+    #[serde(default)]
+    pub read_timeout: u64,
+
+    /// Seconds a terminated session's forwarder has to stop before it is
+    /// aborted and its xpra killed
+    #[serde(default = "default_stop_timeout")]
+    pub stop_timeout: u64,
+
     /// Session idle timeout in seconds (0 = no timeout)
     #[serde(default = "default_idle_timeout")]
     pub idle_timeout: u64,
 
+    /// What to do with sessions idle past the timeout (experimental `hibernate`
+    /// requires the `criu` feature)
+    #[serde(default)]
+    pub idle_policy: IdlePolicy,
+
+    /// Signal deciding that a session is idle: input through its channel,
+    /// or input seen by its X server (`x_input`, needs xprintidle)
+    #[serde(default)]
+    pub idle_source: IdleSource,
+
+    /// Checks of each display's window manager and root window
+    #[serde(default)]
+    pub watchdog: WatchdogConfig,
+
+    /// Directory for checkpoint images of hibernated sessions
+    #[serde(default = "default_hibernate_dir")]
+    pub hibernate_dir: PathBuf,
+
+    /// Seconds a session stays hibernated before it is terminated (0 = no limit)
+    #[serde(default = "default_hibernate_ttl")]
+    pub hibernate_ttl: u64,
+
+    /// Where bundles of crashed xpra processes go and how many are kept
+    #[serde(default)]
+    pub crash: CrashConfig,
+
+    /// How times are shown in text reports, unless overridden on the
+    /// command line
+    #[serde(default)]
+    pub time_display: TimeDisplay,
+
+    /// Idle and exec timeouts for headless automation sessions
+    #[serde(default)]
+    pub headless: HeadlessConfig,
+
+    /// Commands run before and after sessions start and after they stop
+    #[serde(default)]
+    pub hooks: HooksConfig,
+
     /// Maximum sessions per user (0 = unlimited)
     #[serde(default = "default_max_sessions")]
     pub max_sessions: u32,
+
+    /// External command deciding whether sessions may start
+    #[serde(default)]
+    pub admission_policy: AdmissionPolicyConfig,
+
+    /// Templates, apps, screen sizes and audio clients may start sessions with
+    #[serde(default)]
+    pub session_params: SessionParamsConfig,
+
+    /// Session starts allowed per user per minute (0 = unlimited)
+    #[serde(default = "default_user_rate_limit")]
+    pub user_rate_limit: u32,
+
+    /// Burst of session starts allowed per user before rate limiting kicks in
+    #[serde(default = "default_user_rate_burst")]
+    pub user_rate_burst: u32,
+
+    /// Session starts allowed host-wide per minute (0 = unlimited)
+    #[serde(default = "default_global_rate_limit")]
+    pub global_rate_limit: u32,
+
+    /// Burst of session starts allowed host-wide before rate limiting kicks in
+    #[serde(default = "default_global_rate_burst")]
+    pub global_rate_burst: u32,
+
+    /// Maximum concurrent sessions on this host (0 = unlimited)
+    #[serde(default = "default_max_total_sessions")]
+    pub max_total_sessions: u32,
+
+    /// Seconds to wait for a free slot when the host is full (0 = fail fast)
+    #[serde(default = "default_queue_timeout")]
+    pub queue_timeout: u64,
+
+    /// Desktop hour budgets keyed by user name, `@group`, or `*` for everyone
+    #[serde(default)]
+    pub time_budgets: HashMap<String, TimeBudget>,
+
+    /// Refuse new sessions once a budget is exhausted (otherwise only warn)
+    #[serde(default = "default_enforce_time_budgets")]
+    pub enforce_time_budgets: bool,
+
+    /// Seconds between starting a drain and terminating remaining sessions
+    #[serde(default = "default_drain_deadline")]
+    pub drain_deadline: u64,
+
+    /// Seconds before the drain deadline to send a final warning
+    #[serde(default = "default_drain_warning")]
+    pub drain_warning: u64,
+
+    /// Message shown to users when the host is draining
+    #[serde(default = "default_drain_message")]
+    pub drain_message: String,
+
+    /// Seconds after which the key of a desktop stream is rotated (0 = never)
+    #[serde(default = "default_key_rotation_interval")]
+    pub key_rotation_interval: u64,
+
+    /// Bytes sent on a desktop stream after which its key is rotated (0 = never)
+    #[serde(default = "default_key_rotation_bytes")]
+    pub key_rotation_bytes: u64,
+
+    /// Directory for the metrics, history and session logs
+    /// (defaults to `SSHX_XPRA_LOG_DIR`, or /var/log/sshx/xpra)
+    #[serde(default = "default_log_dir")]
+    pub log_dir: PathBuf,
+
+    /// Name of this instance; its logs go to a subdirectory of `log_dir`
+    /// with this name (defaults to `SSHX_XPRA_INSTANCE`)
+    #[serde(default = "default_instance")]
+    pub instance: Option<String>,
+
+    /// Where rotated logs go once they are 30 days old, to be read back by
+    /// `analyze --archived` (deleted if unset)
+    #[serde(default)]
+    pub archive: Option<ArchiveTarget>,
+
+    /// Screenshots of the active sessions kept for compliance
+    #[serde(default)]
+    pub screenshots: ScreenshotConfig,
+
+    /// Message broker the session events, metrics and alerts are published
+    /// to, for fleet-wide dashboards (none if unset)
+    #[serde(default)]
+    pub publisher: Option<PublisherConfig>,
+
+    /// Format and level rotated logs are compressed with
+    #[serde(default)]
+    pub log_compression: LogCompression,
+
+    /// Free space and log size limits past which logs are rotated and
+    /// pruned early
+    #[serde(default)]
+    pub log_disk_limits: DiskLimits,
+
+    /// Unix socket for the local admin API
+    #[serde(default = "default_admin_socket")]
+    pub admin_socket: PathBuf,
+
+    /// How the ids of new sessions are made
+    #[serde(default)]
+    pub session_ids: SessionIdScheme,
+
+    /// Address for the `/healthz`, `/readyz` and `/metrics` HTTP endpoints
+    /// (disabled if unset)
+    #[serde(default)]
+    pub health_listen: Option<SocketAddr>,
+
+    /// Label whose values group sessions into teams in `status --summary`
+    /// and `/metrics`; sessions are not grouped by team if null
+    #[serde(default = "default_team_label")]
+    pub team_label: Option<String>,
+
+    /// Recurring windows during which the host is drained automatically
+    #[serde(default)]
+    pub maintenance_windows: Vec<MaintenanceWindow>,
+
+    /// Worker hosts to place sessions on; sessions run locally when empty
+    #[serde(default)]
+    pub hosts: Vec<HostConfig>,
+
+    /// How new sessions are spread over the worker hosts
+    #[serde(default)]
+    pub placement_strategy: PlacementStrategy,
 }
 
 fn default_min_display() -> u16 { 100 }
 fn default_max_display() -> u16 { 599 }
 fn default_base_port() -> u16 { 14500 }
 fn default_window_manager() -> String { "gnome-flashback".to_string() }
+fn default_max_resolution() -> String { "3840x2160".to_string() }
+fn default_startup_timeout() -> u64 { 30 }
+fn default_lease_timeout() -> u64 { 300 } // 5 minutes
+fn default_connect_timeout() -> u64 { 5 }
+fn default_connect_attempts() -> u32 { 10 }
+fn default_stop_timeout() -> u64 { 10 }
 fn default_idle_timeout() -> u64 { 3600 } // 1 hour
+fn default_hibernate_dir() -> PathBuf { PathBuf::from("/var/lib/sshx/xpra-hibernate") }
+fn default_hibernate_ttl() -> u64 { 86400 } // 1 day
 fn default_max_sessions() -> u32 { 5 }
+fn default_user_rate_limit() -> u32 { 6 }
+fn default_user_rate_burst() -> u32 { 3 }
+fn default_global_rate_limit() -> u32 { 60 }
+fn default_global_rate_burst() -> u32 { 10 }
+fn default_max_total_sessions() -> u32 { 0 }
+fn default_queue_timeout() -> u64 { 0 }
+fn default_enforce_time_budgets() -> bool { true }
+fn default_drain_deadline() -> u64 { 900 } // 15 minutes
+fn default_drain_warning() -> u64 { 300 } // 5 minutes
+fn default_drain_message() -> String {
+    "This host is going down for maintenance, please save your work.".to_string()
+}
+fn default_key_rotation_interval() -> u64 { 3600 } // 1 hour
+fn default_key_rotation_bytes() -> u64 { 1 << 30 } // 1 GiB
+fn default_log_dir() -> PathBuf {
+    std::env::var_os("SSHX_XPRA_LOG_DIR").map_or_else(|| PathBuf::from("/var/log/sshx/xpra"), PathBuf::from)
+}
+fn default_instance() -> Option<String> {
+    std::env::var("SSHX_XPRA_INSTANCE").ok().filter(|name| !name.is_empty())
+}
+fn default_admin_socket() -> PathBuf { PathBuf::from("/run/sshx/xpra-admin.sock") }
+fn default_team_label() -> Option<String> { Some("team".to_string()) }
 
 impl Default for XpraConfig {
     fn default() -> Self {
@@ -42,13 +381,103 @@ impl Default for XpraConfig {
             max_display: default_max_display(),
             base_port: default_base_port(),
             window_manager: default_window_manager(),
+            keyboard_layout: None,
+            allowed_programs: Vec::new(),
+            socket_auth: false,
+            children: Vec::new(),
+            motd: MotdConfig::default(),
+            branding: BrandingConfig::default(),
+            watermark: WatermarkConfig::default(),
+            proxy: ProxyConfig::default(),
+            profiles: ProfileConfig::default(),
+            devices: DeviceConfig::default(),
+            clipboard: HashMap::new(),
+            network: NetworkConfig::default(),
+            sandbox: SandboxConfig::default(),
+            x_backend: XBackend::default(),
+            max_resolution: default_max_resolution(),
+            xorg_conf: None,
+            gpu: GpuConfig::default(),
+            cpu_affinity: AffinityConfig::default(),
+            priority: PriorityConfig::default(),
+            startup_timeout: default_startup_timeout(),
+            lease_timeout: default_lease_timeout(),
+            connect_timeout: default_connect_timeout(),
+            connect_attempts: default_connect_attempts(),
+            read_timeout: 0,
+            stop_timeout: default_stop_timeout(),
             idle_timeout: default_idle_timeout(),
+            idle_policy: IdlePolicy::default(),
+            idle_source: IdleSource::default(),
+            watchdog: WatchdogConfig::default(),
+            hibernate_dir: default_hibernate_dir(),
+            hibernate_ttl: default_hibernate_ttl(),
+            crash: CrashConfig::default(),
+            time_display: TimeDisplay::default(),
+            headless: HeadlessConfig::default(),
+            hooks: HooksConfig::default(),
             max_sessions: default_max_sessions(),
+            admission_policy: AdmissionPolicyConfig::default(),
+            session_params: SessionParamsConfig::default(),
+            user_rate_limit: default_user_rate_limit(),
+            user_rate_burst: default_user_rate_burst(),
+            global_rate_limit: default_global_rate_limit(),
+            global_rate_burst: default_global_rate_burst(),
+            max_total_sessions: default_max_total_sessions(),
+            queue_timeout: default_queue_timeout(),
+            time_budgets: HashMap::new(),
+            enforce_time_budgets: default_enforce_time_budgets(),
+            drain_deadline: default_drain_deadline(),
+            drain_warning: default_drain_warning(),
+            drain_message: default_drain_message(),
+            key_rotation_interval: default_key_rotation_interval(),
+            key_rotation_bytes: default_key_rotation_bytes(),
+            log_dir: default_log_dir(),
+            instance: default_instance(),
+            archive: None,
+            screenshots: ScreenshotConfig::default(),
+            publisher: None,
+            log_compression: LogCompression::default(),
+            log_disk_limits: DiskLimits::default(),
+            admin_socket: default_admin_socket(),
+            session_ids: SessionIdScheme::default(),
+            health_listen: None,
+            team_label: default_team_label(),
+            maintenance_windows: Vec::new(),
+            hosts: Vec::new(),
+            placement_strategy: PlacementStrategy::default(),
         }
     }
 }
 
 impl XpraConfig {
+    /// Longest a new display may take to accept connections
+    pub fn startup_duration(&self) -> Duration {
+        Duration::from_secs(self.startup_timeout)
+    }
+
+    /// Longest a starting session's display number stays leased, never
+    /// shorter than the display may take to start
+    pub fn lease_duration(&self) -> Duration {
+        Duration::from_secs(self.lease_timeout.max(self.startup_timeout))
+    }
+
+    /// Longest an attempt to connect to a display's websocket may take
+    pub fn connect_duration(&self) -> Duration {
+        Duration::from_secs(self.connect_timeout)
+    }
+
+    /// Longest xpra may stay silent towards an attached client, if limited
+    pub fn read_duration(&self) -> Option<Duration> {
+        (self.read_timeout > 0).then(|| Duration::from_secs(self.read_timeout))
+    }
+
+    /// Longest a terminated session's forwarder may take to stop
+    pub fn stop_duration(&self) -> Duration {
+        Duration::from_secs(self.stop_timeout)
+    }
+
+    /// Idle timeout after which sessions are terminated, if any.
     pub fn idle_duration(&self) -> Option<Duration> {
         if self.idle_timeout == 0 {
             None
@@ -57,12 +486,92 @@ impl XpraConfig {
         }
     }
 
+    /// Longest a session stays hibernated, if limited
+    pub fn hibernate_ttl_duration(&self) -> Option<Duration> {
+        (self.hibernate_ttl > 0).then(|| Duration::from_secs(self.hibernate_ttl))
+    }
+
+    /// Longest a session start waits for a free slot, if limited
+    pub fn queue_duration(&self) -> Option<Duration> {
+        if self.queue_timeout == 0 {
+            None
+        } else {
+            Some(Duration::from_secs(self.queue_timeout))
+        }
+    }
+
+    /// When the keys of desktop streams are rotated
+    pub fn key_rotation(&self) -> RotationPolicy {
+        RotationPolicy {
+            interval: (self.key_rotation_interval > 0)
+                .then(|| Duration::from_secs(self.key_rotation_interval)),
+            bytes: (self.key_rotation_bytes > 0).then_some(self.key_rotation_bytes),
+        }
+    }
+
+    /// Log directory of this instance
+    pub fn instance_log_dir(&self) -> PathBuf {
+        match &self.instance {
+            Some(instance) => self.log_dir.join(instance),
+            None => self.log_dir.clone(),
+        }
+    }
+
+    /// Name of the host and instance whose history log this is, keying its
+    /// numbered events in exports.
+    pub fn event_source(&self) -> String {
+        let host = whoami::fallible::hostname().unwrap_or_else(|_| "localhost".to_string());
+        match &self.instance {
+            Some(instance) => format!("{host}/{instance}"),
+            None => host,
+        }
+    }
+
+    /// Check settings that cannot work together, before any session starts.
+    pub fn validate(&self) -> Result<()> {
+        if self.min_display > self.max_display {
+            anyhow::bail!(
+                "min_display {} is greater than max_display {}",
+                self.min_display,
+                self.max_display
+            );
+        }
+        if self.base_port.checked_add(self.max_display - self.min_display).is_none() {
+            anyhow::bail!(
+                "base_port {} leaves no WebSocket port for displays up to {}",
+                self.base_port,
+                self.max_display
+            );
+        }
+        for host in &self.hosts {
+            let address: std::net::IpAddr = host.bind_address.parse().map_err(|_| {
+                anyhow::anyhow!("bind_address {:?} of host {} is not an IP address", host.bind_address, host.name)
+            })?;
+            // Off loopback anyone on the worker's network could reach xpra
+            if !address.is_loopback() {
+                if host.tls.is_none() && !self.socket_auth {
+                    anyhow::bail!(
+                        "host {} binds xpra to {} without tls or socket_auth",
+                        host.name,
+                        host.bind_address
+                    );
+                }
+                if host.address.is_empty() {
+                    anyhow::bail!("host {} binds xpra to {} but has no address", host.name, host.bind_address);
+                }
+            }
+            // A reader's sockets are only the display's own under an account
+            // no other host runs desktops as
+            let shared = self.hosts.iter().filter(|other| other.ssh_target == host.ssh_target).count() > 1;
+            if self.devices.smartcard.enabled && shared {
+                anyhow::bail!("hosts share the account {} of host {}, which smartcard readers need to themselves", host.ssh_target, host.name);
+            }
+        }
+        Ok(())
+    }
+
+    /// WebSocket port of the given display number.
     pub fn websocket_port(&self, display: u16) -> u16 {
         self.base_port + (display - self.min_display)
     }
 }
-
-// Global config instance
-lazy_static::lazy_static! {
-    pub static ref CONFIG: XpraConfig = XpraConfig::default();
-}
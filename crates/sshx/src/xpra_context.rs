@@ -0,0 +1,175 @@
+//! Shared state of a desktop host instance: its configuration, metrics,
+//! event logger, session monitor, hosts, local display pool, session ids,
+//! the limits on starting sessions, its drain and maintenance state and its
+//! headless sessions.
+//!
+//! A context is constructed once and passed around as an `Arc`, so several
+//! isolated instances, e.g. in tests, can run in one process. The binary
+//! creates its context in `main` and hands it down.
+
+use std::sync::Arc;
+use tracing::error;
+
+use crate::xpra_capacity::SessionCapacity;
+use crate::xpra_config::XpraConfig;
+use crate::xpra_drain::DrainController;
+use crate::xpra_headless::HeadlessManager;
+use crate::xpra_hosts::HostPool;
+use crate::xpra_logger::XpraLogger;
+use crate::xpra_maintenance::MaintenanceScheduler;
+use crate::xpra_metrics::XpraMetrics;
+use crate::xpra_monitor::SessionMonitor;
+use crate::xpra_pool::DisplayPool;
+use crate::xpra_quota::UsageAccounting;
+use crate::xpra_rate_limit::SessionRateLimiter;
+use crate::xpra_session_id::SessionIdFactory;
+
+/// Shared state of a desktop host instance.
+#[derive(Debug)]
+pub struct XpraContext {
+    /// Configuration the host was started with
+    pub config: Arc<XpraConfig>,
+    /// Session and log counters
+    pub metrics: Arc<XpraMetrics>,
+    /// Writer of the metrics and history logs
+    pub logger: XpraLogger,
+    /// Sessions running on the host
+    pub monitor: SessionMonitor,
+    /// Hosts sessions are placed on, the local one taking display numbers
+    /// from `display_pool`
+    pub hosts: HostPool,
+    /// Display numbers of the local host
+    pub display_pool: DisplayPool,
+    /// Makes the ids of new sessions
+    pub session_ids: SessionIdFactory,
+    /// Rate limits on starting sessions
+    pub rate_limiter: SessionRateLimiter,
+    /// Cap on the sessions running at once
+    pub capacity: SessionCapacity,
+    /// Desktop time used by each user, checked against the time budgets
+    pub usage: UsageAccounting,
+    /// Whether the host refuses new sessions for maintenance
+    pub drain: DrainController,
+    /// Maintenance windows, draining the host with `drain`
+    pub maintenance: MaintenanceScheduler,
+    /// Headless sessions started through the admin API
+    pub headless: HeadlessManager,
+}
+
+impl XpraContext {
+    /// Create a context writing its logs to the config's instance log
+    /// directory.
+    pub fn new(config: XpraConfig) -> Arc<Self> {
+        let config = Arc::new(config);
+        let metrics = Arc::new(XpraMetrics::new());
+        let usage = UsageAccounting::from_history(&config.instance_log_dir())
+            .with_budgets(config.time_budgets.clone());
+        let logger = XpraLogger::with_metrics(config.instance_log_dir(), metrics.logging().clone())
+            .with_usage(usage.clone());
+        let display_pool = DisplayPool::with_range(config.min_display, config.max_display);
+        let hosts = HostPool::new(&config, display_pool.clone());
+        let monitor = SessionMonitor::new(config.clone(), logger.clone(), metrics.clone()).with_hosts(hosts.clone());
+        let session_ids = SessionIdFactory::new(config.session_ids);
+        let rate_limiter = SessionRateLimiter::new(
+            config.user_rate_limit,
+            config.user_rate_burst,
+            config.global_rate_limit,
+            config.global_rate_burst,
+        );
+        let capacity = SessionCapacity::new(
+            config.max_total_sessions,
+            config.queue_duration(),
+            metrics.clone(),
+        );
+        let drain = DrainController::new(config.clone(), monitor.clone(), hosts.clone());
+        let maintenance = MaintenanceScheduler::new(&config.maintenance_windows, drain.clone()).unwrap_or_else(|e| {
+            error!("Invalid maintenance window configuration: {}", e);
+            MaintenanceScheduler::new(&[], drain.clone()).unwrap()
+        });
+        Arc::new_cyclic(|context| Self {
+            config,
+            metrics,
+            logger,
+            monitor,
+            hosts,
+            display_pool,
+            session_ids,
+            rate_limiter,
+            capacity,
+            usage,
+            drain,
+            maintenance,
+            headless: HeadlessManager::new(context.clone()),
+        })
+    }
+}
+
+#[cfg(test)]
+impl XpraContext {
+    /// A context logging to a directory of its own, named after `name`.
+    pub(crate) fn for_test(name: &str) -> Arc<Self> {
+        let dir = std::env::temp_dir().join(format!("sshx-{}-{}", name, std::process::id()));
+        Self::new(XpraConfig { log_dir: dir, instance: None, ..XpraConfig::default() })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use chrono::Utc;
+
+    use super::*;
+    use crate::xpra_health;
+    use crate::xpra_maintenance::MaintenanceWindow;
+    use crate::xpra_paging::SessionQuery;
+    use crate::xpra_status;
+
+    #[tokio::test]
+    async fn test_contexts_are_isolated() {
+        let dir = std::env::temp_dir().join(format!("sshx-context-{}", std::process::id()));
+        let config = |instance: &str| XpraConfig {
+            log_dir: dir.clone(),
+            instance: Some(instance.to_string()),
+            ..XpraConfig::default()
+        };
+        let window = MaintenanceWindow {
+            schedule: "0 3 * * *".to_string(),
+            duration_minutes: 60,
+            warn_minutes: 0,
+            message: None,
+        };
+        let first = XpraContext::new(XpraConfig { maintenance_windows: vec![window], ..config("first") });
+        let second = XpraContext::new(config("second"));
+
+        first.metrics.session_started("xpra-1");
+        let _lease = first.display_pool.allocate().await.unwrap();
+        assert_eq!(first.metrics.get_metrics().active_sessions, 1);
+        assert_eq!(second.metrics.get_metrics().active_sessions, 0);
+        assert_eq!(second.display_pool.allocated_count().await, 0);
+        assert_eq!(first.logger.log_dir(), dir.join("first"));
+        assert_eq!(second.logger.log_dir(), dir.join("second"));
+
+        // Draining one host leaves the other accepting sessions
+        first.drain.start(Duration::from_secs(600), None).await;
+        assert!(!second.drain.is_draining().await);
+        let status = xpra_status::get_status(&first, &SessionQuery::default()).await;
+        assert!(status.drain.draining && status.next_maintenance.is_some());
+        let status = xpra_status::get_status(&second, &SessionQuery::default()).await;
+        assert!(!status.drain.draining && status.next_maintenance.is_none());
+        assert_eq!(status.metrics.active_sessions, 0);
+        let drain_check = |report: xpra_health::HealthReport| report.checks.into_iter().find(|c| c.name == "drain").unwrap().ok;
+        assert!(!drain_check(xpra_health::self_check(&first).await));
+        assert!(drain_check(xpra_health::self_check(&second).await));
+        assert!(first.maintenance.next_window(Utc::now()).is_some());
+
+        // Each context has its own headless sessions, tied to it
+        let err = second.headless.stop("headless-1").await.unwrap_err();
+        assert_eq!(err.to_string(), "no headless session headless-1");
+        let headless = first.headless.clone();
+        drop(first);
+        let err = headless.launch("alice".to_string(), None, None, Default::default()).await.unwrap_err();
+        assert_eq!(err.to_string(), "the desktop host is shutting down");
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
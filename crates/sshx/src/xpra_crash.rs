@@ -0,0 +1,289 @@
+//! Crash bundles of xpra processes that exited with an error, so a crash can
+//! be looked into after the display was reused.
+//!
+//! Each bundle is a directory `<YYYYmmdd_HHMMSS>-<session id>` in the crash
+//! directory holding `crash.json` with the exit status and the host's core
+//! pattern, `xpra.log` with the last lines of the session's xpra output and
+//! `dmesg.log` with the end of the host's kernel log. Bundles past the
+//! configured age or count are removed, oldest first, after each crash.
+//! Bundles hold the session's output and the host's kernel log, so they are
+//! only readable by the service's user.
+
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+use std::time::Duration;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::time;
+use tracing::{info, warn};
+
+use crate::xpra::XpraDisplay;
+use crate::xpra_hosts::WorkerHost;
+
+/// Lines of xpra output kept in a bundle
+const LOG_LINES: usize = 200;
+/// Lines of the kernel log kept in a bundle
+const DMESG_LINES: usize = 50;
+/// How long commands gathering a bundle may run on the host
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a failed xpra process is given to exit before no bundle is
+/// collected for it
+const EXIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where crash bundles go and how many are kept.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CrashConfig {
+    /// Directory of crash bundles
+    #[serde(default = "default_dir")]
+    pub dir: PathBuf,
+
+    /// Most bundles kept, newest first (0 = no bundles are collected)
+    #[serde(default = "default_max_bundles")]
+    pub max_bundles: usize,
+
+    /// Days a bundle is kept (0 = no age limit)
+    #[serde(default = "default_max_age_days")]
+    pub max_age_days: u64,
+}
+
+fn default_dir() -> PathBuf { PathBuf::from("/var/lib/sshx/xpra-crashes") }
+fn default_max_bundles() -> usize { 50 }
+fn default_max_age_days() -> u64 { 30 }
+
+impl Default for CrashConfig {
+    fn default() -> Self {
+        Self {
+            dir: default_dir(),
+            max_bundles: default_max_bundles(),
+            max_age_days: default_max_age_days(),
+        }
+    }
+}
+
+/// The crashed session, as written to `crash.json` of its bundle.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CrashInfo {
+    /// Id of the session
+    pub session_id: String,
+    /// User owning the session
+    pub user: String,
+    /// Host running the display
+    pub host: String,
+    /// Display number of the session
+    pub display: u16,
+    /// When the crash was collected
+    pub time: DateTime<Utc>,
+    /// Exit code of xpra, or `None` if it was killed by a signal
+    pub exit_code: Option<i32>,
+    /// Signal that killed xpra, if any
+    pub signal: Option<i32>,
+    /// The host's `kernel.core_pattern`, if it could be read
+    pub core_pattern: Option<String>,
+}
+
+impl CrashInfo {
+    /// A crash of the session's xpra with exit `status`.
+    pub fn new(session_id: &str, user: &str, host: &str, display: u16, status: ExitStatus) -> Self {
+        Self {
+            session_id: session_id.to_string(),
+            user: user.to_string(),
+            host: host.to_string(),
+            display,
+            time: Utc::now(),
+            exit_code: status.code(),
+            signal: status.signal(),
+            core_pattern: None,
+        }
+    }
+}
+
+/// Collect a bundle for `info` from the xpra output in `log_path` and the
+/// kernel of `host`, then prune old bundles. Returns the bundle directory.
+pub async fn collect(config: &CrashConfig, host: &WorkerHost, log_path: &Path, mut info: CrashInfo) -> Result<PathBuf> {
+    if config.max_bundles == 0 {
+        anyhow::bail!("crash bundles are disabled");
+    }
+    let name = format!("{}-{}", info.time.format("%Y%m%d_%H%M%S"), info.session_id);
+    let bundle = config.dir.join(name);
+    tokio::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&bundle)
+        .await
+        .with_context(|| format!("failed to create {}", bundle.display()))?;
+
+    let log = match crate::xpra_output::read_tail(log_path, Some(LOG_LINES), None).await {
+        Ok((lines, _)) => lines.join("\n") + "\n",
+        Err(e) => format!("xpra output unavailable: {e:#}\n"),
+    };
+    write_private(&bundle.join("xpra.log"), log.as_bytes()).await?;
+
+    let dmesg = match host_output(host, "dmesg", &[]).await {
+        Ok(output) => last_lines(&output, DMESG_LINES),
+        Err(e) => format!("dmesg unavailable: {e:#}\n"),
+    };
+    write_private(&bundle.join("dmesg.log"), dmesg.as_bytes()).await?;
+
+    let core_pattern = host_output(host, "cat", &["/proc/sys/kernel/core_pattern".to_string()]).await;
+    info.core_pattern = core_pattern.ok().map(|pattern| pattern.trim().to_string());
+    write_private(&bundle.join("crash.json"), &serde_json::to_vec_pretty(&info)?).await?;
+    info!(session_id = info.session_id, bundle = %bundle.display(), "Collected xpra crash bundle");
+
+    if let Err(e) = prune(config, Utc::now()).await {
+        warn!("Failed to prune crash bundles: {:#}", e);
+    }
+    Ok(bundle)
+}
+
+/// Collect a bundle for a session whose xpra failed, once it exited and
+/// before its display is released and the output log reused. Returns `None`,
+/// with a warning if collecting failed, when xpra does not exit in time,
+/// exited cleanly, or bundles are disabled.
+pub async fn collect_for(config: &CrashConfig, display: &mut XpraDisplay, session_id: &str, user: &str) -> Option<PathBuf> {
+    if config.max_bundles == 0 {
+        return None;
+    }
+    let Some(status) = display.wait_exit(EXIT_TIMEOUT).await else {
+        warn!(session_id, "xpra did not exit, no crash bundle is collected");
+        return None;
+    };
+    if status.success() {
+        return None;
+    }
+    let host = display.host().clone();
+    let info = CrashInfo::new(session_id, user, &host.name, display.display(), status);
+    let log_path = crate::xpra_output::log_path(display.config(), &host.name, display.display());
+    match collect(config, &host, &log_path, info).await {
+        Ok(bundle) => Some(bundle),
+        Err(e) => {
+            warn!(session_id, "Failed to collect xpra crash bundle: {:#}", e);
+            None
+        }
+    }
+}
+
+/// Write a file of a bundle, readable only by its owner.
+async fn write_private(path: &Path, contents: &[u8]) -> Result<()> {
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .await
+        .with_context(|| format!("failed to create {}", path.display()))?;
+    file.write_all(contents).await?;
+    file.flush().await?;
+    Ok(())
+}
+
+/// Standard output of `program` run on `host`.
+async fn host_output(host: &WorkerHost, program: &str, args: &[String]) -> Result<String> {
+    let mut cmd = tokio::process::Command::from(host.command(program, args));
+    cmd.kill_on_drop(true);
+    let output = time::timeout(COMMAND_TIMEOUT, cmd.output())
+        .await
+        .with_context(|| format!("{program} timed out"))??;
+    if !output.status.success() {
+        anyhow::bail!("{program} exited with {}: {}", output.status, String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+fn last_lines(text: &str, count: usize) -> String {
+    let lines: Vec<&str> = text.lines().collect();
+    let skip = lines.len().saturating_sub(count);
+    lines[skip..].iter().map(|line| format!("{line}\n")).collect()
+}
+
+/// Remove bundles older than the age limit, then the oldest past the count
+/// limit. Bundles are ordered by the time their name starts with.
+pub async fn prune(config: &CrashConfig, now: DateTime<Utc>) -> Result<()> {
+    let mut entries = match tokio::fs::read_dir(&config.dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut bundles = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        if entry.file_type().await?.is_dir() {
+            bundles.push(entry.path());
+        }
+    }
+    bundles.sort();
+
+    let cutoff = (config.max_age_days > 0)
+        .then(|| (now - chrono::Duration::days(config.max_age_days as i64)).format("%Y%m%d_%H%M%S").to_string());
+    let excess = bundles.len().saturating_sub(config.max_bundles);
+    for (index, bundle) in bundles.iter().enumerate() {
+        let name = bundle.file_name().unwrap_or_default().to_string_lossy();
+        let expired = cutoff.as_ref().is_some_and(|cutoff| name.get(..15).is_some_and(|time| time < cutoff.as_str()));
+        if index < excess || expired {
+            tokio::fs::remove_dir_all(bundle)
+                .await
+                .with_context(|| format!("failed to remove {}", bundle.display()))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::xpra_config::XpraConfig;
+    use crate::xpra_pool::DisplayPool;
+
+    #[tokio::test]
+    async fn test_collect_bundle() {
+        let dir = std::env::temp_dir().join(format!("sshx-crash-{}", std::process::id()));
+        let config = CrashConfig { dir: dir.join("crashes"), ..CrashConfig::default() };
+        let log_path = dir.join("local-100.log");
+        std::fs::create_dir_all(&dir).unwrap();
+        let log: Vec<String> = (0..250).map(|i| format!("2024-05-15T10:00:00.000Z line {i}")).collect();
+        std::fs::write(&log_path, log.join("\n") + "\n").unwrap();
+
+        let status = ExitStatus::from_raw(3 << 8);
+        let info = CrashInfo::new("xpra-1", "alice", "local", 100, status);
+        let bundle = collect(&config, &WorkerHost::local(&XpraConfig::default(), DisplayPool::new()), &log_path, info).await.unwrap();
+
+        let info: CrashInfo = serde_json::from_slice(&std::fs::read(bundle.join("crash.json")).unwrap()).unwrap();
+        assert_eq!(info.exit_code, Some(3));
+        assert_eq!(info.signal, None);
+        let log = std::fs::read_to_string(bundle.join("xpra.log")).unwrap();
+        assert_eq!(log.lines().count(), LOG_LINES);
+        assert!(log.ends_with("line 249\n"));
+        assert!(bundle.join("dmesg.log").exists());
+        for name in ["crash.json", "xpra.log", "dmesg.log"] {
+            let mode = std::fs::metadata(bundle.join(name)).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        assert_eq!(std::fs::metadata(&bundle).unwrap().permissions().mode() & 0o777, 0o700);
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn test_prune() {
+        let dir = std::env::temp_dir().join(format!("sshx-crash-prune-{}", std::process::id()));
+        let config = CrashConfig { dir: dir.clone(), max_bundles: 2, max_age_days: 7 };
+        for name in ["20240501_100000-a", "20240512_100000-b", "20240513_100000-c", "20240514_100000-d"] {
+            std::fs::create_dir_all(dir.join(name)).unwrap();
+        }
+
+        prune(&config, Utc.with_ymd_and_hms(2024, 5, 15, 0, 0, 0).unwrap()).await.unwrap();
+        let mut left: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        left.sort();
+        assert_eq!(left, ["20240513_100000-c", "20240514_100000-d"]);
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
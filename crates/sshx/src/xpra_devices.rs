@@ -0,0 +1,199 @@
+//! Forwarding of the client's devices to a desktop.
+//!
+//! xpra can show the webcam of the client to the desktop's programs as a
+//! video device, so the desktop can join video calls, and deployments with
+//! virtual readers let the client's smartcards through, see
+//! [`crate::xpra_smartcard`]. Devices are only forwarded for the users and
+//! groups the policy lists, and only once the user agreed to it: the
+//! forwarder asks through a notification when the channel is negotiated,
+//! and holds back the client's requests to attach a device until the client
+//! answers with a [`ControlMessage::DeviceConsent`].
+//! Every device attached, and every smartcard detached, is logged to the
+//! history log.
+//!
+//! [`ControlMessage::DeviceConsent`]: crate::xpra_handshake::ControlMessage::DeviceConsent
+
+use std::fmt;
+use std::process::Stdio;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::xpra_hosts::WorkerHost;
+use crate::xpra_notify::Notification;
+use crate::xpra_quota::user_keys;
+use crate::xpra_smartcard::SmartcardConfig;
+
+/// Id of the notification asking for consent, out of the range of the ids
+/// xpra gives the desktop's notifications.
+pub const CONSENT_NOTIFICATION_ID: u64 = u64::MAX;
+
+/// Device of the client forwarded to a desktop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Device {
+    /// The client's webcam, shown to the desktop as a video device
+    Webcam,
+    /// A smartcard or security key of the client, in the display's reader
+    Smartcard,
+}
+
+impl Device {
+    /// Type of the xpra packet the client sends to attach the device, none
+    /// for devices attached outside of xpra.
+    pub fn attach_packet(&self) -> Option<&'static str> {
+        match self {
+            Device::Webcam => Some("webcam-start"),
+            Device::Smartcard => None,
+        }
+    }
+
+    /// Device the client attaches with a packet of type `packet`, if any.
+    pub fn attached_by(packet: &str) -> Option<Device> {
+        [Device::Webcam].into_iter().find(|device| device.attach_packet() == Some(packet))
+    }
+
+    /// Let xpra on a display attach the device, or stop it from doing so.
+    /// xpra of sessions that may forward a webcam starts able to take it,
+    /// so it is turned off until the user consents.
+    pub async fn set_enabled(&self, host: &WorkerHost, display: u16, enabled: bool) -> Result<()> {
+        let Some(feature) = self.xpra_feature() else {
+            return Ok(());
+        };
+        let state = if enabled { "on" } else { "off" };
+        let args = ["control", &format!(":{display}"), "toggle-feature", feature, state].map(String::from);
+        let status = tokio::process::Command::from(host.command("xpra", &args))
+            .stdin(Stdio::null())
+            .status()
+            .await?;
+        if !status.success() {
+            anyhow::bail!("xpra control toggle-feature exited with {}", status);
+        }
+        Ok(())
+    }
+
+    /// Feature of `xpra control toggle-feature` attaching the device, none
+    /// for devices attached outside of xpra.
+    fn xpra_feature(&self) -> Option<&'static str> {
+        match self {
+            Device::Webcam => Some("webcam"),
+            Device::Smartcard => None,
+        }
+    }
+}
+
+impl fmt::Display for Device {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Device::Webcam => write!(f, "webcam"),
+            Device::Smartcard => write!(f, "smartcard"),
+        }
+    }
+}
+
+/// Which users may forward which devices, and how they are asked.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceConfig {
+    /// Users whose webcam is forwarded: user names, `@group`, or `*` for
+    /// everyone. Nobody's by default.
+    #[serde(default)]
+    pub webcam: Vec<String>,
+
+    /// Virtual readers of the client's smartcards, for everyone if enabled
+    #[serde(default)]
+    pub smartcard: SmartcardConfig,
+
+    /// Message shown to users before they agree to forward a device
+    #[serde(default = "default_consent_message")]
+    pub consent_message: String,
+}
+
+fn default_consent_message() -> String {
+    "This desktop can use your devices. Their use is logged; allow it only if you need it.".to_string()
+}
+
+impl Default for DeviceConfig {
+    fn default() -> Self {
+        Self {
+            webcam: Vec::new(),
+            smartcard: SmartcardConfig::default(),
+            consent_message: default_consent_message(),
+        }
+    }
+}
+
+impl DeviceConfig {
+    /// Devices of `user` that may be forwarded to a desktop on `host`.
+    pub async fn allowed(&self, user: &str, host: &WorkerHost) -> Vec<Device> {
+        let mut devices = Vec::new();
+        if listed(&self.webcam, user).await {
+            devices.push(Device::Webcam);
+        }
+        if self.smartcard.available(host) {
+            devices.push(Device::Smartcard);
+        }
+        devices
+    }
+
+    /// Notification asking the user to agree to forwarding `devices`.
+    pub fn consent_notification(&self, devices: &[Device]) -> Notification {
+        let devices: Vec<String> = devices.iter().map(Device::to_string).collect();
+        Notification {
+            id: CONSENT_NOTIFICATION_ID,
+            replaces: 0,
+            app_name: "ssh-desktop".to_string(),
+            summary: format!("Allow forwarding your {}?", devices.join(", ")),
+            body: self.consent_message.clone(),
+            expire_timeout: 0,
+        }
+    }
+}
+
+/// Whether `user` is in `entries` of user names, `@group` and `*`.
+pub(crate) async fn listed(entries: &[String], user: &str) -> bool {
+    if entries.is_empty() {
+        return false;
+    }
+    let groups = entries.iter().any(|entry| entry.starts_with('@'));
+    user_keys(user, groups).await.iter().any(|key| entries.contains(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::xpra_config::XpraConfig;
+    use crate::xpra_hosts::HostConfig;
+    use crate::xpra_pool::DisplayPool;
+
+    #[tokio::test]
+    async fn test_allowed() {
+        let xpra = XpraConfig::default();
+        let local = WorkerHost::local(&xpra, DisplayPool::new());
+        let config = DeviceConfig { webcam: vec!["alice".to_string()], ..DeviceConfig::default() };
+        assert_eq!(config.allowed("alice", &local).await, vec![Device::Webcam]);
+        assert!(config.allowed("bob", &local).await.is_empty());
+        assert!(DeviceConfig::default().allowed("alice", &local).await.is_empty());
+
+        let config = DeviceConfig { webcam: vec!["*".to_string()], ..DeviceConfig::default() };
+        assert_eq!(config.allowed("bob", &local).await, vec![Device::Webcam]);
+        let smartcard = SmartcardConfig { enabled: true, ..SmartcardConfig::default() };
+        let config = DeviceConfig { smartcard, ..config };
+        // Smartcards only go to hosts running the one display
+        assert_eq!(config.allowed("bob", &local).await, vec![Device::Webcam]);
+        let host: HostConfig = serde_json::from_str(r#"{"name": "a", "ssh_target": "desk@a", "max_sessions": 1}"#).unwrap();
+        let dedicated = WorkerHost::remote(&host, &xpra);
+        assert_eq!(config.allowed("bob", &dedicated).await, vec![Device::Webcam, Device::Smartcard]);
+        // Groups of users that do not exist resolve to none
+        let config = DeviceConfig { webcam: vec!["@video".to_string()], ..DeviceConfig::default() };
+        assert!(config.allowed("no-such-user-sshx", &local).await.is_empty());
+    }
+
+    #[test]
+    fn test_consent_notification() {
+        let notification = DeviceConfig::default().consent_notification(&[Device::Webcam]);
+        assert_eq!(notification.id, CONSENT_NOTIFICATION_ID);
+        assert_eq!(notification.summary, "Allow forwarding your webcam?");
+        assert_eq!(notification.expire_timeout, 0);
+        assert_eq!(Device::attached_by("webcam-start"), Some(Device::Webcam));
+        assert_eq!(Device::attached_by("webcam-frame"), None);
+    }
+}
@@ -0,0 +1,433 @@
+//! Startup environment checks with actionable diagnostics.
+//!
+//! Unlike the readiness checks in `xpra_health`, which answer "can a session
+//! start right now", the doctor looks for setup problems that would make every
+//! session fail, and says how to fix them.
+
+use std::path::{Path, PathBuf};
+
+use crate::xpra_admin::{self, AdminRequest};
+use crate::xpra_capabilities::XpraVersion;
+use crate::xpra_config::XpraConfig;
+use crate::xpra_context::XpraContext;
+use crate::xpra_health::{check_log_dir, CheckResult, HealthReport};
+use crate::xpra_idle::{IdleSource, IDLE_PROGRAM};
+use crate::xpra_motd::MotdStyle;
+use crate::xpra_paging::{SessionPage, SessionQuery};
+use crate::xpra_sandbox::SandboxProfile;
+use crate::xpra_status::SessionStatus;
+use crate::xpra_xorg::{parse_resolution, XBackend, DUMMY_DRIVER_PATTERNS};
+
+/// Window managers known to work, tried in order when the configured one is
+/// missing.
+const KNOWN_WINDOW_MANAGERS: &[&str] = &[
+    "gnome-flashback",
+    "startxfce4",
+    "mate-session",
+    "startlxde",
+    "openbox",
+    "fluxbox",
+    "icewm",
+];
+
+/// Run every environment check of the host of `context`.
+pub async fn run(context: &XpraContext) -> HealthReport {
+    let config = &context.config;
+    HealthReport::new(vec![
+        check_xpra().await,
+        check_window_manager(config),
+        check_remote_desktops(config),
+        check_smartcard(context),
+        check_log_dir(context.logger.log_dir()).await,
+        check_display_range(Path::new("/tmp/.X11-unix"), config.min_display, config.max_display),
+        check_ports(config, &running_host_ports(config).await),
+        check_x_backend(config),
+        check_idle_source(config),
+        check_motd(config),
+        check_branding(config),
+        check_watermark(config),
+        check_proxy(config),
+        check_network(config),
+        check_sandbox(config),
+    ])
+}
+
+async fn check_xpra() -> CheckResult {
+    let output = match tokio::process::Command::new("xpra").arg("--version").output().await {
+        Ok(output) => output,
+        Err(_) => {
+            return CheckResult::fail(
+                "xpra",
+                "xpra not found in PATH, install it from your distribution or https://xpra.org",
+            )
+        }
+    };
+    let text = String::from_utf8_lossy(&output.stdout);
+    let Some(version) = XpraVersion::parse(&text) else {
+        return CheckResult::fail("xpra", format!("could not parse xpra version from {:?}", text.trim()));
+    };
+    if version < XpraVersion::MINIMUM {
+        return CheckResult::fail(
+            "xpra",
+            format!("xpra {} is too old, version {} or newer is required", version, XpraVersion::MINIMUM),
+        );
+    }
+
+    // The HTML5 client is packaged separately on some distributions
+    let html5 = ["/usr/share/xpra/www", "/usr/local/share/xpra/www"]
+        .iter()
+        .any(|p| Path::new(p).is_dir());
+    if !html5 {
+        return CheckResult::fail(
+            "xpra",
+            format!("xpra {} is installed but the HTML5 client is missing, install xpra-html5", version),
+        );
+    }
+    CheckResult::pass("xpra", format!("xpra {} with HTML5 client", version))
+}
+
+fn check_window_manager(config: &XpraConfig) -> CheckResult {
+    let path = std::env::var("PATH").unwrap_or_default();
+    let configured = &config.window_manager;
+    if find_in_path(configured, &path).is_some() {
+        return CheckResult::pass("window_manager", configured.clone());
+    }
+    match KNOWN_WINDOW_MANAGERS.iter().find(|wm| find_in_path(wm, &path).is_some()) {
+        Some(wm) => CheckResult::fail(
+            "window_manager",
+            format!("{configured} not found, set window_manager to {wm:?} or install {configured}"),
+        ),
+        None => CheckResult::fail(
+            "window_manager",
+            format!("{configured} not found and no other window manager is installed, install e.g. xfce4"),
+        ),
+    }
+}
+
+fn check_remote_desktops(config: &XpraConfig) -> CheckResult {
+    let params = &config.session_params;
+    let path = std::env::var("PATH").unwrap_or_default();
+    let clients = params
+        .rdp
+        .values()
+        .map(|config| (&config.client, "freerdp2-x11 or freerdp", "RDP"))
+        .chain(params.macos.values().map(|config| (&config.client, "tigervnc-viewer", "macOS")));
+    for (client, package, templates) in clients {
+        if find_in_path(client, &path).is_none() {
+            return CheckResult::fail(
+                "remote_desktops",
+                format!("{client} not found, install {package} for the {templates} templates"),
+            );
+        }
+    }
+    match (params.rdp.len(), params.macos.len()) {
+        (0, 0) => CheckResult::pass("remote_desktops", "none"),
+        (windows, macs) => CheckResult::pass("remote_desktops", format!("{windows} Windows hosts, {macs} Macs")),
+    }
+}
+
+fn check_smartcard(context: &XpraContext) -> CheckResult {
+    let smartcard = &context.config.devices.smartcard;
+    if !smartcard.enabled {
+        return CheckResult::pass("smartcard", "disabled");
+    }
+    // Readers run on workers whose account runs no other desktop, which
+    // need pcscd, socat, unshare and the vpcd driver installed
+    let hosts: Vec<&str> = context.hosts.hosts().iter().filter(|host| smartcard.available(host)).map(|host| host.name.as_str()).collect();
+    if hosts.is_empty() {
+        return CheckResult::fail("smartcard", "no host runs a single display under its own account, cards are not forwarded");
+    }
+    CheckResult::pass("smartcard", format!("readers on {}", hosts.join(", ")))
+}
+
+/// Look up an executable in a `PATH`-style list of directories.
+fn find_in_path(program: &str, path: &str) -> Option<PathBuf> {
+    // The configured window manager may include arguments
+    let program = program.split_whitespace().next()?;
+    if program.contains('/') {
+        return Path::new(program).is_file().then(|| PathBuf::from(program));
+    }
+    std::env::split_paths(path)
+        .map(|dir| dir.join(program))
+        .find(|candidate| candidate.is_file())
+}
+
+/// Count displays in the range already taken by X servers not managed by us.
+fn check_display_range(socket_dir: &Path, min: u16, max: u16) -> CheckResult {
+    if min > max {
+        return CheckResult::fail(
+            "display_range",
+            format!("min_display {min} is greater than max_display {max}, swap them"),
+        );
+    }
+    let taken: Vec<u16> = (min..=max)
+        .filter(|n| socket_dir.join(format!("X{n}")).exists())
+        .collect();
+    let total = (max - min) as usize + 1;
+    if taken.len() == total {
+        CheckResult::fail(
+            "display_range",
+            format!("all displays :{min}-:{max} are in use, stop stale X servers or widen the range"),
+        )
+    } else if taken.is_empty() {
+        CheckResult::pass("display_range", format!(":{min}-:{max}, {total} displays free"))
+    } else {
+        CheckResult::pass(
+            "display_range",
+            format!(
+                ":{min}-:{max}, {} of {total} free (in use by other X servers: {})",
+                total - taken.len(),
+                summarize(&taken),
+            ),
+        )
+    }
+}
+
+/// WebSocket ports of the local sessions of the host running on this
+/// machine, which are in use by design. Empty if no host is running.
+async fn running_host_ports(config: &XpraConfig) -> Vec<u16> {
+    let mut ports = Vec::new();
+    let mut query = SessionQuery { host: Some("local".to_string()), ..SessionQuery::default() };
+    loop {
+        let request = AdminRequest::Sessions { query: query.clone() };
+        let Ok(page) = xpra_admin::send_request(&config.admin_socket, &request).await else {
+            break;
+        };
+        let Ok(page) = serde_json::from_value::<SessionPage<SessionStatus>>(page) else {
+            break;
+        };
+        ports.extend(page.sessions.iter().map(|session| session.websocket_port));
+        match page.next {
+            Some(next) => query.after = Some(next),
+            None => break,
+        }
+    }
+    ports
+}
+
+/// Check the WebSocket ports of the display range are free, apart from
+/// `held`, the ports of the running host's own sessions.
+fn check_ports(config: &XpraConfig, held: &[u16]) -> CheckResult {
+    if let Err(e) = config.validate() {
+        return CheckResult::fail("ports", format!("{e}"));
+    }
+    let first = config.websocket_port(config.min_display);
+    let last = config.websocket_port(config.max_display);
+    let busy: Vec<u16> = (first..=last)
+        .filter(|port| !held.contains(port))
+        .filter(|&port| std::net::TcpListener::bind(("127.0.0.1", port)).is_err())
+        .collect();
+    if busy.is_empty() {
+        CheckResult::pass("ports", format!("websocket ports {first}-{last} available"))
+    } else {
+        CheckResult::fail(
+            "ports",
+            format!(
+                "websocket ports in use by other processes: {}, sessions on those displays will fail; change base_port",
+                summarize(&busy),
+            ),
+        )
+    }
+}
+
+fn check_x_backend(config: &XpraConfig) -> CheckResult {
+    if let Err(e) = parse_resolution(&config.max_resolution) {
+        return CheckResult::fail("x_server", format!("{e}, fix max_resolution"));
+    }
+    let found = DUMMY_DRIVER_PATTERNS
+        .iter()
+        .filter_map(|pattern| glob::glob(pattern).ok())
+        .flatten()
+        .flatten()
+        .next();
+    match (config.x_backend, found) {
+        (XBackend::Xvfb, _) => CheckResult::pass("x_server", "Xvfb"),
+        (_, Some(path)) => CheckResult::pass("x_server", format!("Xdummy ({})", path.display())),
+        (XBackend::Auto, None) => CheckResult::pass(
+            "x_server",
+            "Xvfb (Xorg dummy driver not found; install it for resizable displays)",
+        ),
+        (XBackend::Xdummy, None) => CheckResult::fail(
+            "x_server",
+            "Xorg dummy driver not found, install xserver-xorg-video-dummy (Debian/Ubuntu) or \
+             xorg-x11-drv-dummy (Fedora/RHEL), or set x_backend to \"xvfb\"",
+        ),
+    }
+}
+
+fn check_idle_source(config: &XpraConfig) -> CheckResult {
+    if config.idle_source == IdleSource::Channel {
+        return CheckResult::pass("idle_source", "channel input");
+    }
+    let path = std::env::var("PATH").unwrap_or_default();
+    match find_in_path(IDLE_PROGRAM, &path) {
+        Some(program) => CheckResult::pass("idle_source", format!("X input ({})", program.display())),
+        None => CheckResult::fail(
+            "idle_source",
+            format!(
+                "{IDLE_PROGRAM} not found, install it or set idle_source to \"channel\"; \
+                 until then channel input decides when sessions are idle"
+            ),
+        ),
+    }
+}
+
+fn check_motd(config: &XpraConfig) -> CheckResult {
+    let path = std::env::var("PATH").unwrap_or_default();
+    let missing = |programs: &[&'static str]| -> Vec<&'static str> {
+        programs.iter().copied().filter(|p| find_in_path(p, &path).is_none()).collect()
+    };
+    match config.motd.style {
+        MotdStyle::Off => CheckResult::pass("motd", "off"),
+        MotdStyle::Dialog if missing(&["zenity", "xmessage"]).len() == 2 => CheckResult::fail(
+            "motd",
+            "neither zenity nor xmessage found, install one to show the message of the day",
+        ),
+        MotdStyle::Dialog => CheckResult::pass("motd", "dialog"),
+        MotdStyle::Wallpaper => match missing(&["convert", "feh"])[..] {
+            [] => CheckResult::pass("motd", "wallpaper"),
+            ref programs => CheckResult::fail(
+                "motd",
+                format!("{} not found, install it to show the message of the day", programs.join(" and ")),
+            ),
+        },
+    }
+}
+
+fn check_branding(config: &XpraConfig) -> CheckResult {
+    let branding = &config.branding;
+    let path = std::env::var("PATH").unwrap_or_default();
+    let missing: Vec<&str> = branding
+        .required_programs()
+        .into_iter()
+        .filter(|p| find_in_path(p, &path).is_none())
+        .collect();
+    if !missing.is_empty() {
+        return CheckResult::fail(
+            "branding",
+            format!("{} not found, install it to apply the wallpaper and overlay", missing.join(" and ")),
+        );
+    }
+    let images = [&branding.wallpaper, &branding.overlay];
+    if let Some(image) = images.into_iter().flatten().find(|image| !Path::new(image).is_file()) {
+        return CheckResult::fail("branding", format!("{image} does not exist"));
+    }
+    match (&branding.wallpaper, &branding.overlay, &branding.gtk_theme) {
+        (None, None, None) => CheckResult::pass("branding", "none"),
+        _ => CheckResult::pass("branding", "configured"),
+    }
+}
+
+fn check_watermark(config: &XpraConfig) -> CheckResult {
+    if config.watermark.users.is_empty() {
+        return CheckResult::pass("watermark", "off");
+    }
+    let path = std::env::var("PATH").unwrap_or_default();
+    if find_in_path("aosd_cat", &path).is_none() {
+        return CheckResult::fail("watermark", "aosd_cat not found, install aosd-cat to draw the watermark");
+    }
+    if find_in_path("xcompmgr", &path).is_none() {
+        return CheckResult::fail("watermark", "xcompmgr not found, install xcompmgr to blend the watermark");
+    }
+    CheckResult::pass("watermark", format!("for {}", config.watermark.users.join(", ")))
+}
+
+fn check_proxy(config: &XpraConfig) -> CheckResult {
+    let proxy = &config.proxy;
+    if let Some(cert) = proxy.ca_certificates.iter().find(|cert| !cert.is_file()) {
+        return CheckResult::fail("proxy", format!("{} does not exist", cert.display()));
+    }
+    let path = std::env::var("PATH").unwrap_or_default();
+    if !proxy.ca_certificates.is_empty() && find_in_path("certutil", &path).is_none() {
+        return CheckResult::fail(
+            "proxy",
+            "certutil not found, install it (libnss3-tools or nss-tools) for browsers to trust the CA certificates",
+        );
+    }
+    match (proxy.https_proxy.as_ref().or(proxy.http_proxy.as_ref()), proxy.ca_certificates.len()) {
+        (None, 0) => CheckResult::pass("proxy", "none"),
+        (None, certs) => CheckResult::pass("proxy", format!("{certs} CA certificates")),
+        (Some(url), certs) => CheckResult::pass("proxy", format!("{url} with {certs} CA certificates")),
+    }
+}
+
+fn check_network(config: &XpraConfig) -> CheckResult {
+    let network = &config.network;
+    if !network.isolate {
+        return CheckResult::pass("network", "not isolated");
+    }
+    if let Err(e) = network.for_display(config.min_display) {
+        return CheckResult::fail("network", format!("{e:#}"));
+    }
+    let path = std::env::var("PATH").unwrap_or_default();
+    let missing: Vec<&str> = ["ip", "nft"].into_iter().filter(|p| find_in_path(p, &path).is_none()).collect();
+    if !missing.is_empty() {
+        return CheckResult::fail(
+            "network",
+            format!("{} not found, install iproute2 and nftables to isolate desktops", missing.join(" and ")),
+        );
+    }
+    CheckResult::pass("network", format!("isolated, {} egress rules", network.egress.len()))
+}
+
+fn check_sandbox(config: &XpraConfig) -> CheckResult {
+    let profile = config.sandbox.profile;
+    if profile == SandboxProfile::Off {
+        return CheckResult::pass("sandbox", "off");
+    }
+    // Landlock only restricts the filesystem for the standard and strict
+    // profiles, and is skipped where the kernel does not enable it
+    let landlock = std::fs::read_to_string("/sys/kernel/security/lsm").is_ok_and(|lsm| lsm.contains("landlock"));
+    if profile >= SandboxProfile::Standard && !landlock {
+        return CheckResult::pass("sandbox", format!("{profile}, syscalls only: Landlock is not enabled in this kernel"));
+    }
+    CheckResult::pass("sandbox", profile.to_string())
+}
+
+/// Shorten a list of numbers for display, e.g. `100, 101, 102 and 7 more`.
+fn summarize(values: &[u16]) -> String {
+    let shown: Vec<String> = values.iter().take(3).map(|v| v.to_string()).collect();
+    match values.len() {
+        n if n > 3 => format!("{} and {} more", shown.join(", "), n - 3),
+        _ => shown.join(", "),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display_range() {
+        let dir = std::env::temp_dir().join(format!("sshx-doctor-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("X100"), b"").unwrap();
+
+        let check = check_display_range(&dir, 100, 101);
+        assert!(check.ok);
+        assert!(check.detail.contains("1 of 2 free"));
+
+        std::fs::write(dir.join("X101"), b"").unwrap();
+        assert!(!check_display_range(&dir, 100, 101).ok);
+        assert!(!check_display_range(&dir, 101, 100).ok);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_ports() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let config = XpraConfig { min_display: 100, max_display: 100, base_port: port, ..XpraConfig::default() };
+
+        // Ports of the running host's sessions are not in the way
+        assert!(!check_ports(&config, &[]).ok);
+        assert!(check_ports(&config, &[port]).ok);
+    }
+
+    #[test]
+    fn test_summarize() {
+        assert_eq!(summarize(&[1, 2]), "1, 2");
+        assert_eq!(summarize(&[1, 2, 3, 4, 5]), "1, 2, 3 and 2 more");
+    }
+}
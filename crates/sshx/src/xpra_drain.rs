@@ -0,0 +1,256 @@
+//! Draining a host: refusing new sessions while the running ones finish.
+
+use std::sync::Arc;
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tokio::time;
+use tracing::{info, warn};
+
+use crate::xpra::notify_display;
+use crate::xpra_config::XpraConfig;
+use crate::xpra_hosts::HostPool;
+use crate::xpra_logger::TerminationReason;
+use crate::xpra_monitor::SessionMonitor;
+use crate::xpra_session_state::SessionState;
+
+/// Drain state of the host, as shown in status output.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DrainStatus {
+    /// Whether the host refuses new sessions
+    pub draining: bool,
+    /// When the drain began
+    pub started_at: Option<DateTime<Utc>>,
+    /// When the remaining sessions are terminated, if ever
+    pub deadline: Option<DateTime<Utc>>,
+    /// Message shown to users of the remaining sessions
+    pub message: Option<String>,
+}
+
+#[derive(Debug, Default)]
+struct DrainState {
+    status: DrainStatus,
+    /// Bumped on every drain/undrain so stale timers can tell they are stale.
+    generation: u64,
+}
+
+/// Puts the host into maintenance mode: blocks new sessions, warns users in
+/// existing sessions, and terminates them once the deadline passes.
+#[derive(Debug, Clone)]
+pub struct DrainController {
+    state: Arc<Mutex<DrainState>>,
+    config: Arc<XpraConfig>,
+    monitor: SessionMonitor,
+    hosts: HostPool,
+}
+
+impl DrainController {
+    /// Create a controller of the sessions of `monitor`, with the host not
+    /// draining
+    pub fn new(config: Arc<XpraConfig>, monitor: SessionMonitor, hosts: HostPool) -> Self {
+        Self { state: Arc::default(), config, monitor, hosts }
+    }
+
+    /// Configuration of the host, with the drain defaults
+    pub(crate) fn config(&self) -> &XpraConfig {
+        &self.config
+    }
+
+    /// Start draining, terminating remaining sessions after `deadline`.
+    pub async fn start(&self, deadline: Duration, message: Option<String>) -> DrainStatus {
+        let now = Utc::now();
+        let deadline_at = now + chrono::Duration::from_std(deadline).unwrap_or_default();
+        let message = message.unwrap_or_else(|| self.config.drain_message.clone());
+
+        let (generation, status) = {
+            let mut state = self.state.lock().await;
+            state.generation += 1;
+            state.status = DrainStatus {
+                draining: true,
+                started_at: Some(now),
+                deadline: Some(deadline_at),
+                message: Some(message.clone()),
+            };
+            (state.generation, state.status.clone())
+        };
+        info!(deadline = %deadline_at, "Draining host for maintenance");
+        self.move_sessions(&[SessionState::Starting, SessionState::Active, SessionState::Detached], SessionState::Draining)
+            .await;
+
+        let controller = self.clone();
+        tokio::spawn(async move {
+            controller.warn_sessions(&message, deadline_at).await;
+
+            let warning = Duration::from_secs(controller.config.drain_warning);
+            if deadline > warning && !warning.is_zero() {
+                time::sleep(deadline - warning).await;
+                if !controller.is_current(generation).await {
+                    return;
+                }
+                controller.warn_sessions(&message, deadline_at).await;
+                time::sleep(warning).await;
+            } else {
+                time::sleep(deadline).await;
+            }
+
+            if controller.is_current(generation).await {
+                controller.terminate_sessions().await;
+            }
+        });
+
+        status
+    }
+
+    /// Leave maintenance mode and accept new sessions again.
+    pub async fn stop(&self) -> DrainStatus {
+        self.stop_if(|_| true).await.unwrap_or_default()
+    }
+
+    /// Leave maintenance mode if the current drain is the one started at
+    /// `started_at`, leaving a drain started since then alone.
+    pub async fn stop_started_at(&self, started_at: DateTime<Utc>) -> Option<DrainStatus> {
+        self.stop_if(|status| status.started_at == Some(started_at)).await
+    }
+
+    async fn stop_if(&self, matches: impl FnOnce(&DrainStatus) -> bool) -> Option<DrainStatus> {
+        let status = {
+            let mut state = self.state.lock().await;
+            if !matches(&state.status) {
+                return None;
+            }
+            state.generation += 1;
+            state.status = DrainStatus::default();
+            state.status.clone()
+        };
+        info!("Host drain cancelled, accepting new sessions");
+        self.move_sessions(&[SessionState::Draining], SessionState::Active).await;
+        Some(status)
+    }
+
+    /// Whether new sessions should currently be refused.
+    pub async fn is_draining(&self) -> bool {
+        self.state.lock().await.status.draining
+    }
+
+    /// Current drain state of the host
+    pub async fn status(&self) -> DrainStatus {
+        self.state.lock().await.status.clone()
+    }
+
+    async fn is_current(&self, generation: u64) -> bool {
+        let state = self.state.lock().await;
+        state.generation == generation && state.status.draining
+    }
+
+    async fn warn_sessions(&self, message: &str, deadline: DateTime<Utc>) {
+        let body = format!(
+            "{} Your session will be closed at {}.",
+            message,
+            deadline.format("%H:%M UTC"),
+        );
+        self.notify_sessions("Scheduled maintenance", &body).await;
+    }
+
+    /// Show a notification on the display of every running session.
+    pub(crate) async fn notify_sessions(&self, title: &str, body: &str) {
+        for (host, number) in self.monitor.collect_sessions(|_, info| Some((info.host.clone(), info.display))) {
+            if let Err(e) = notify_display(&self.hosts, &host, number, title, body).await {
+                warn!(display = number, "Failed to send {:?} notification: {}", title, e);
+            }
+        }
+    }
+
+    async fn terminate_sessions(&self) {
+        let sessions = self.monitor.session_ids();
+        info!(count = sessions.len(), "Drain deadline reached, terminating sessions");
+        for session_id in &sessions {
+            self.monitor.terminate_session(session_id, TerminationReason::Drain).await;
+        }
+    }
+
+    /// Move the sessions in any of the `from` states to `to`.
+    async fn move_sessions(&self, from: &[SessionState], to: SessionState) {
+        let sessions = self.monitor.collect_sessions(|id, info| {
+            from.contains(&info.lifecycle.state()).then(|| id.to_string())
+        });
+        for session_id in sessions {
+            self.monitor.set_state(&session_id, to).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::xpra_context::XpraContext;
+
+    #[tokio::test]
+    async fn test_start_sets_deadline_and_message() {
+        let context = XpraContext::for_test("drain-start");
+        let drain = &context.drain;
+        assert!(!drain.is_draining().await);
+
+        let status = drain.start(Duration::from_secs(600), None).await;
+        assert!(status.draining);
+        assert!(drain.is_draining().await);
+        let started_at = status.started_at.unwrap();
+        assert_eq!(status.deadline, Some(started_at + chrono::Duration::seconds(600)));
+        assert_eq!(status.message.as_deref(), Some(context.config.drain_message.as_str()));
+
+        let status = drain.start(Duration::from_secs(60), Some("Kernel update".into())).await;
+        assert_eq!(status.message.as_deref(), Some("Kernel update"));
+        assert_eq!(drain.status().await.deadline, status.deadline);
+    }
+
+    #[tokio::test]
+    async fn test_stop_resets_state_and_invalidates_timers() {
+        let context = XpraContext::for_test("drain-stop");
+        let drain = &context.drain;
+        drain.start(Duration::from_secs(600), None).await;
+        let generation = drain.state.lock().await.generation;
+        assert!(drain.is_current(generation).await);
+
+        let status = drain.stop().await;
+        assert!(!status.draining);
+        assert!(status.started_at.is_none() && status.deadline.is_none() && status.message.is_none());
+        assert!(!drain.is_draining().await);
+        // The timer of the cancelled drain must not terminate sessions
+        assert!(!drain.is_current(generation).await);
+
+        // Nor one of a drain replaced by a new one
+        drain.start(Duration::from_secs(600), None).await;
+        assert!(!drain.is_current(generation).await);
+        let generation = drain.state.lock().await.generation;
+        assert!(drain.is_current(generation).await);
+    }
+
+    #[tokio::test]
+    async fn test_stop_started_at_leaves_newer_drain() {
+        let context = XpraContext::for_test("drain-started-at");
+        let drain = &context.drain;
+        let first = drain.start(Duration::from_secs(600), None).await.started_at.unwrap();
+        time::sleep(Duration::from_millis(5)).await;
+        let second = drain.start(Duration::from_secs(600), None).await.started_at.unwrap();
+
+        assert!(drain.stop_started_at(first).await.is_none());
+        assert!(drain.is_draining().await);
+        assert!(drain.stop_started_at(second).await.is_some());
+        assert!(!drain.is_draining().await);
+    }
+
+    #[tokio::test]
+    async fn test_host_stays_draining_past_deadline() {
+        let context = XpraContext::for_test("drain-deadline");
+        let drain = &context.drain;
+        drain.start(Duration::ZERO, None).await;
+        let generation = drain.state.lock().await.generation;
+
+        // The host stays draining past the deadline until the drain is stopped
+        time::sleep(Duration::from_millis(50)).await;
+        assert!(drain.is_draining().await);
+        assert!(drain.is_current(generation).await);
+        drain.stop().await;
+        assert!(!drain.is_draining().await);
+    }
+}
@@ -0,0 +1,169 @@
+//! Exports of a log analysis for time series databases and dashboards.
+//!
+//! The hourly timeline and the per-user totals are written either as InfluxDB
+//! line protocol or as the response of a Grafana simple JSON datasource.
+
+use std::fmt::Write;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::xpra_log_analyzer::{LogAnalysis, TimelinePoint};
+
+/// Escape a tag value or measurement name for the line protocol.
+fn escape_tag(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, ',' | '=' | ' ' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// The analysis as InfluxDB line protocol, with nanosecond timestamps:
+/// `xpra_sessions` for each hour of the timeline and `xpra_user_sessions` for
+/// each user at the end of the period.
+pub fn influx_lines(analysis: &LogAnalysis) -> String {
+    let mut out = String::new();
+    for point in &analysis.timeline {
+        let _ = writeln!(
+            out,
+            "xpra_sessions started={}i,concurrent={}i {}",
+            point.sessions_started,
+            point.max_concurrent,
+            point.time.timestamp_nanos_opt().unwrap_or_default(),
+        );
+    }
+
+    let end = analysis.period.end.timestamp_nanos_opt().unwrap_or_default();
+    let mut users: Vec<_> = analysis.user_stats.iter().collect();
+    users.sort_by(|a, b| a.0.cmp(b.0));
+    for (user, stats) in users {
+        let _ = writeln!(
+            out,
+            "xpra_user_sessions,user={} sessions={}i,total_duration_secs={}i,avg_duration_secs={}i,idle_terminations={}i {}",
+            escape_tag(user),
+            stats.total_sessions,
+            stats.total_duration.num_seconds(),
+            stats.avg_session_duration.num_seconds(),
+            stats.idle_terminations,
+            end,
+        );
+    }
+    out
+}
+
+/// A time series of a Grafana simple JSON datasource.
+#[derive(Debug, Serialize)]
+struct TimeSeries {
+    target: &'static str,
+    /// Pairs of value and Unix time in milliseconds
+    datapoints: Vec<(u32, i64)>,
+}
+
+/// The analysis as the response of a Grafana simple JSON datasource query:
+/// the `sessions_started` and `max_concurrent` time series of the timeline,
+/// then a table of the users.
+pub fn grafana_json(analysis: &LogAnalysis) -> Value {
+    let series = |target, value: fn(&TimelinePoint) -> u32| TimeSeries {
+        target,
+        datapoints: analysis
+            .timeline
+            .iter()
+            .map(|point| (value(point), point.time.timestamp_millis()))
+            .collect(),
+    };
+
+    let mut users: Vec<_> = analysis.user_stats.iter().collect();
+    users.sort_by(|a, b| a.0.cmp(b.0));
+    let rows: Vec<Value> = users
+        .into_iter()
+        .map(|(user, stats)| {
+            json!([
+                user,
+                stats.total_sessions,
+                stats.total_duration.num_seconds(),
+                stats.avg_session_duration.num_seconds(),
+                stats.idle_terminations,
+            ])
+        })
+        .collect();
+
+    json!([
+        series("sessions_started", |point| point.sessions_started),
+        series("max_concurrent", |point| point.max_concurrent),
+        {
+            "type": "table",
+            "columns": [
+                { "text": "user", "type": "string" },
+                { "text": "sessions", "type": "number" },
+                { "text": "total_duration_secs", "type": "number" },
+                { "text": "avg_duration_secs", "type": "number" },
+                { "text": "idle_terminations", "type": "number" },
+            ],
+            "rows": rows,
+        },
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashMap};
+    use chrono::{Duration, TimeZone, Utc};
+
+    use super::*;
+    use crate::xpra_log_analyzer::{AnalysisPeriod, SessionStats, UserStats};
+
+    fn analysis() -> LogAnalysis {
+        let start = Utc.with_ymd_and_hms(2024, 5, 1, 10, 0, 0).unwrap();
+        let user = UserStats {
+            total_sessions: 2,
+            total_duration: Duration::hours(3),
+            avg_session_duration: Duration::minutes(90),
+            idle_terminations: 1,
+        };
+        LogAnalysis {
+            period: AnalysisPeriod { start, end: start + Duration::hours(2) },
+            session_stats: SessionStats {
+                total_sessions: 2,
+                avg_duration: Duration::minutes(90),
+                max_concurrent: 2,
+                idle_terminations: 1,
+                failed_sessions: 0,
+                carried_over: 0,
+                open_at_end: 0,
+                termination_reasons: HashMap::new(),
+            },
+            user_stats: HashMap::from([("ann smith".to_string(), user)]),
+            hourly_distribution: Vec::new(),
+            timeline: vec![
+                TimelinePoint { time: start, sessions_started: 2, max_concurrent: 2 },
+                TimelinePoint { time: start + Duration::hours(1), sessions_started: 0, max_concurrent: 1 },
+            ],
+            server_issues: HashMap::new(),
+            failures: Vec::new(),
+            spawned: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_influx_lines() {
+        let lines = influx_lines(&analysis());
+        let lines: Vec<_> = lines.lines().collect();
+        assert_eq!(lines, [
+            "xpra_sessions started=2i,concurrent=2i 1714557600000000000",
+            "xpra_sessions started=0i,concurrent=1i 1714561200000000000",
+            "xpra_user_sessions,user=ann\\ smith sessions=2i,total_duration_secs=10800i,\
+             avg_duration_secs=5400i,idle_terminations=1i 1714564800000000000",
+        ]);
+    }
+
+    #[test]
+    fn test_grafana_json() {
+        let value = grafana_json(&analysis());
+        assert_eq!(value[0]["target"], "sessions_started");
+        assert_eq!(value[1]["datapoints"][1], json!([1, 1714561200000i64]));
+        assert_eq!(value[2]["rows"][0], json!(["ann smith", 2, 10800, 5400, 1]));
+    }
+}
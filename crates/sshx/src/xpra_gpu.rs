@@ -0,0 +1,210 @@
+//! Optional GPU acceleration: assignment of GPU devices to sessions and the
+//! VirtualGL/NVENC settings used to start their displays.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::{debug, warn};
+
+/// GPU settings applied to every host with GPU devices.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GpuConfig {
+    /// GPU devices on the local host, e.g. `/dev/dri/renderD128` or `/dev/nvidia0`
+    #[serde(default)]
+    pub devices: Vec<String>,
+
+    /// Maximum sessions sharing one device
+    #[serde(default = "default_sessions_per_gpu")]
+    pub sessions_per_gpu: u32,
+
+    /// Run the window manager under `vglrun` for hardware OpenGL
+    #[serde(default)]
+    pub virtualgl: bool,
+
+    /// Prefer the NVENC hardware video encoder
+    #[serde(default)]
+    pub nvenc: bool,
+
+    /// Refuse sessions when no device is free instead of starting them
+    /// without a GPU
+    #[serde(default)]
+    pub required: bool,
+}
+
+fn default_sessions_per_gpu() -> u32 { 4 }
+
+impl Default for GpuConfig {
+    fn default() -> Self {
+        Self {
+            devices: Vec::new(),
+            sessions_per_gpu: default_sessions_per_gpu(),
+            virtualgl: false,
+            nvenc: false,
+            required: false,
+        }
+    }
+}
+
+impl GpuConfig {
+    /// The window manager command, wrapped to render through `device`.
+    pub fn wrap_command(&self, device: &str, wm: &str) -> String {
+        if self.virtualgl {
+            format!("vglrun -d {} {}", vgl_display(device), wm)
+        } else {
+            wm.to_string()
+        }
+    }
+
+    /// Environment for the xpra server using `device`.
+    pub fn server_env(&self, device: &str) -> Vec<String> {
+        match nvidia_index(device) {
+            Some(index) => vec![format!("CUDA_VISIBLE_DEVICES={}", index)],
+            None => Vec::new(),
+        }
+    }
+
+    /// Extra xpra arguments for a display using `device`.
+    pub fn xpra_args(&self, device: &str) -> Vec<String> {
+        let mut args = Vec::new();
+        if self.nvenc {
+            args.push("--video-encoders=nvenc,all".to_string());
+        }
+        if let Some(index) = dri_index(device) {
+            args.push(format!("--env=DRI_PRIME={}", index));
+        }
+        args
+    }
+}
+
+/// Index of an NVIDIA device node like `/dev/nvidia1`.
+fn nvidia_index(device: &str) -> Option<u32> {
+    device.strip_prefix("/dev/nvidia")?.parse().ok()
+}
+
+/// Index of a DRM device node, `/dev/dri/card1` or its render node
+/// `/dev/dri/renderD129`, as `DRI_PRIME` takes it.
+fn dri_index(device: &str) -> Option<u32> {
+    let name = device.strip_prefix("/dev/dri/")?;
+    if let Some(index) = name.strip_prefix("card") {
+        return index.parse().ok();
+    }
+    // Render nodes are numbered from 128 in the order of the cards
+    name.strip_prefix("renderD")?.parse::<u32>().ok()?.checked_sub(128)
+}
+
+/// What `vglrun -d` takes for `device`: the DRM card of a DRM device and
+/// the EGL device of an NVIDIA one, for VirtualGL's EGL back end. Anything
+/// else, like an X display `:0`, is passed as given.
+fn vgl_display(device: &str) -> String {
+    if let Some(index) = dri_index(device) {
+        format!("/dev/dri/card{index}")
+    } else if let Some(index) = nvidia_index(device) {
+        format!("egl{index}")
+    } else {
+        device.to_string()
+    }
+}
+
+/// Number of sessions using a GPU, for status output.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GpuUsage {
+    /// Device path of the GPU
+    pub device: String,
+    /// Sessions using the GPU
+    pub sessions: u32,
+}
+
+/// Hands out GPU devices to sessions, spreading sessions over devices.
+#[derive(Debug, Clone)]
+pub struct GpuPool {
+    devices: Vec<String>,
+    sessions_per_gpu: u32,
+    used: Arc<Mutex<HashMap<String, u32>>>,
+}
+
+impl GpuPool {
+    /// Create a pool of `devices`, each shared by at most `sessions_per_gpu` sessions (0 = unlimited)
+    pub fn new(devices: Vec<String>, sessions_per_gpu: u32) -> Self {
+        Self {
+            devices,
+            sessions_per_gpu,
+            used: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Whether the pool has no devices
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty()
+    }
+
+    /// Assign the least used device with room for another session.
+    pub async fn allocate(&self) -> Option<String> {
+        let mut used = self.used.lock().await;
+        let device = self
+            .devices
+            .iter()
+            .map(|d| (d, used.get(d).copied().unwrap_or(0)))
+            .filter(|&(_, count)| self.sessions_per_gpu == 0 || count < self.sessions_per_gpu)
+            .min_by_key(|&(_, count)| count)
+            .map(|(d, _)| d.clone())?;
+        *used.entry(device.clone()).or_default() += 1;
+        debug!(device, "Assigned GPU to session");
+        Some(device)
+    }
+
+    /// Give a device back when its session ends
+    pub async fn release(&self, device: &str) {
+        let mut used = self.used.lock().await;
+        match used.get_mut(device) {
+            Some(count) if *count > 0 => *count -= 1,
+            _ => warn!(device, "Attempted to release unassigned GPU"),
+        }
+    }
+
+    /// Sessions using each device
+    pub async fn usage(&self) -> Vec<GpuUsage> {
+        let used = self.used.lock().await;
+        self.devices
+            .iter()
+            .map(|d| GpuUsage {
+                device: d.clone(),
+                sessions: used.get(d).copied().unwrap_or(0),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_gpu_allocation() {
+        let pool = GpuPool::new(vec!["/dev/nvidia0".into(), "/dev/nvidia1".into()], 2);
+
+        let a = pool.allocate().await.unwrap();
+        let b = pool.allocate().await.unwrap();
+        assert_ne!(a, b);
+        pool.allocate().await.unwrap();
+        pool.allocate().await.unwrap();
+        assert_eq!(pool.allocate().await, None);
+
+        pool.release(&a).await;
+        assert_eq!(pool.allocate().await, Some(a));
+    }
+
+    #[test]
+    fn test_gpu_settings() {
+        let config = GpuConfig { virtualgl: true, nvenc: true, ..Default::default() };
+        assert_eq!(config.wrap_command("/dev/dri/card0", "xfce4-session"), "vglrun -d /dev/dri/card0 xfce4-session");
+        assert_eq!(config.wrap_command("/dev/dri/renderD129", "openbox"), "vglrun -d /dev/dri/card1 openbox");
+        assert_eq!(config.wrap_command("/dev/nvidia1", "openbox"), "vglrun -d egl1 openbox");
+        assert_eq!(config.wrap_command(":0", "openbox"), "vglrun -d :0 openbox");
+        assert!(config.xpra_args("/dev/dri/renderD129").contains(&"--env=DRI_PRIME=1".to_string()));
+        assert!(!config.xpra_args("/dev/nvidia0").iter().any(|arg| arg.contains("DRI_PRIME")));
+        assert_eq!(config.server_env("/dev/nvidia1"), vec!["CUDA_VISIBLE_DEVICES=1".to_string()]);
+        assert!(config.server_env("/dev/dri/renderD128").is_empty());
+        assert!(config.xpra_args("/dev/nvidia0").contains(&"--video-encoders=nvenc,all".to_string()));
+    }
+}
@@ -0,0 +1,656 @@
+//! Versioned hello exchange at the start of a desktop channel, letting the
+//! forwarder adapt to what each web client supports.
+//!
+//! A client that wants to negotiate sends a [`ClientHello`] as its first
+//! frame. From then on, frames in both directions start with a type byte:
+//! [`FRAME_DATA`] for xpra traffic or [`FRAME_CONTROL`] for a JSON
+//! [`ControlMessage`]. xpra packets start with `P`, so a hello cannot be
+//! mistaken for one. Clients that send no hello get the unframed stream they
+//! always did.
+//!
+//! From version 2, stream keys are rotated in both directions: a
+//! [`ControlMessage::Rekey`] gives random keys for the next epoch, under
+//! which the stream to the client continues at once, and the client answers
+//! with a [`ControlMessage::InputRekey`] marking where its input switches.
+//!
+//! Clients showing notifications may forward the devices they list, as far
+//! as the session allows; see [`crate::xpra_devices`].
+
+use std::io::Write;
+use std::time::{Duration, Instant};
+use bytes::Bytes;
+use chrono::Utc;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::encrypt::Encrypt;
+use crate::xpra_devices::{Device, DeviceConfig};
+use crate::xpra_notify::{Notification, NotificationEvent};
+use crate::xpra_protocol::{self, XpraPacket};
+use crate::xpra_smartcard::SmartcardInput;
+
+/// Newest channel protocol version the forwarder speaks.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// First version rotating stream keys.
+const REKEY_VERSION: u32 = 2;
+
+/// Frame type byte of xpra traffic.
+pub const FRAME_DATA: u8 = 0;
+/// Frame type byte of control messages.
+pub const FRAME_CONTROL: u8 = 1;
+
+/// Picture encodings offered to clients, in order of preference.
+const ENCODINGS: &[&str] = &["webp", "jpeg", "png", "rgb", "h264", "vp8"];
+
+/// Compression methods for frames to the client, in order of preference.
+const COMPRESSION: &[&str] = &["deflate"];
+
+/// Shortest heartbeat interval a client may ask for.
+const MIN_HEARTBEAT: Duration = Duration::from_secs(5);
+
+/// Features announced by a web client.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ClientHello {
+    /// Newest protocol version the client speaks
+    pub version: u32,
+    /// xpra picture encodings the client can decode
+    #[serde(default)]
+    pub encodings: Vec<String>,
+    /// Compression methods the client can inflate
+    #[serde(default)]
+    pub compression: Vec<String>,
+    /// The client sends its size in pixels through resize messages
+    #[serde(default)]
+    pub resize: bool,
+    /// Seconds between heartbeats the client wants, if any
+    #[serde(default)]
+    pub heartbeat_interval: Option<u64>,
+    /// The client shows desktop notifications sent as control messages
+    #[serde(default)]
+    pub notifications: bool,
+    /// Devices the client can forward to the desktop
+    #[serde(default)]
+    pub devices: Vec<Device>,
+}
+
+/// Settings agreed for a channel.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChannelParams {
+    /// Protocol version of the channel
+    pub version: u32,
+    /// Picture encodings both sides support
+    pub encodings: Vec<String>,
+    /// Compression of the stream, if any
+    pub compression: Option<String>,
+    /// The client sends its size in pixels
+    pub resize: bool,
+    /// Seconds between heartbeats, if any
+    pub heartbeat_interval: Option<u64>,
+    /// Desktop notifications are sent as control messages
+    #[serde(default)]
+    pub notifications: bool,
+    /// Devices the client may forward once the user consents
+    #[serde(default)]
+    pub devices: Vec<Device>,
+}
+
+/// Message on the control stream of a desktop channel.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ControlMessage {
+    /// Features of the client, its first message on the channel
+    Hello(ClientHello),
+    /// Answer to a hello with the agreed settings
+    Welcome(ChannelParams),
+    /// Sent at the agreed interval so clients can tell a stalled channel
+    /// from an idle desktop
+    Heartbeat {
+        /// Unix time the heartbeat was sent at, in milliseconds
+        time: i64,
+    },
+    /// The stream to the client continues from `offset` under `key`, and
+    /// the client's input is to switch to `input_key`
+    Rekey {
+        /// Key epoch the stream continues under
+        epoch: u64,
+        /// Stream offset the new key starts at
+        offset: u64,
+        /// Raw AES key of the stream to the client
+        key: Vec<u8>,
+        /// Raw AES key of the client's input
+        input_key: Vec<u8>,
+    },
+    /// The client's input continues under the input key of `epoch` right
+    /// after this frame, which starts at input `offset`
+    InputRekey {
+        /// Key epoch the input continues under
+        epoch: u64,
+        /// Input offset this frame starts at
+        offset: u64,
+    },
+    /// A notification was raised or updated on the desktop
+    Notification(Notification),
+    /// A notification of the desktop was closed
+    NotificationClosed {
+        /// Id of the notification
+        id: u64,
+    },
+    /// The user agreed to forward the device to the desktop
+    DeviceConsent {
+        /// Device agreed to
+        device: Device,
+    },
+    /// A smartcard was attached to the client, for the reader of the desktop
+    SmartcardAttach,
+    /// A frame between the reader of the desktop and the client's card
+    Smartcard {
+        /// Command of the reader, or the card's answer
+        data: Vec<u8>,
+    },
+    /// The smartcard was removed from the client
+    SmartcardDetach,
+}
+
+/// When the key of a stream to the client is rotated; whichever limit is
+/// reached first triggers a rotation.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RotationPolicy {
+    /// Rotate after this much time
+    pub interval: Option<Duration>,
+    /// Rotate after this many bytes
+    pub bytes: Option<u64>,
+}
+
+impl ClientHello {
+    /// Agree on settings, or `None` if the client only speaks versions
+    /// newer protocols have dropped.
+    pub fn negotiate(&self) -> Option<ChannelParams> {
+        let version = self.version.min(PROTOCOL_VERSION);
+        if version == 0 {
+            return None;
+        }
+        let encodings = ENCODINGS
+            .iter()
+            .filter(|e| self.encodings.is_empty() || self.encodings.iter().any(|c| c == *e))
+            .map(|e| e.to_string())
+            .collect();
+        let compression = COMPRESSION
+            .iter()
+            .find(|method| self.compression.iter().any(|c| c == *method))
+            .map(|method| method.to_string());
+        let heartbeat_interval = self
+            .heartbeat_interval
+            .filter(|&secs| secs > 0)
+            .map(|secs| secs.max(MIN_HEARTBEAT.as_secs()));
+        Some(ChannelParams {
+            version,
+            encodings,
+            compression,
+            resize: self.resize,
+            heartbeat_interval,
+            notifications: self.notifications,
+            // Consent is asked through a notification
+            devices: if self.notifications { self.devices.clone() } else { Vec::new() },
+        })
+    }
+}
+
+/// What a frame from the client turned out to be.
+#[derive(Debug, PartialEq)]
+pub enum Received {
+    /// Traffic for xpra
+    Data(Bytes),
+    /// Traffic for xpra attaching a device of the client
+    Attach(Device, Bytes),
+    /// Input of the client for the reader of the desktop
+    Smartcard(SmartcardInput),
+    /// The user agreed to forward the device
+    Consent(Device),
+    /// The client's input continues under this raw key from the offset
+    InputRekey(u64, [u8; 16]),
+    /// The channel was negotiated; send this frame back
+    Reply(Bytes),
+    /// Nothing to forward
+    Ignored,
+}
+
+/// Framing state of one desktop channel.
+#[derive(Debug)]
+pub struct DesktopChannel {
+    params: Option<ChannelParams>,
+    first_frame: bool,
+    rotation: RotationPolicy,
+    epoch: u64,
+    rotated_at: Instant,
+    bytes_since_rotation: u64,
+    /// Input key handed out with the latest rekey, until the client uses it
+    input_key: Option<(u64, [u8; 16])>,
+    /// Devices the session may forward
+    devices: Vec<Device>,
+    /// Devices the user agreed to forward
+    consented: Vec<Device>,
+}
+
+impl DesktopChannel {
+    /// Create a channel that has not seen a hello yet, of a session that
+    /// may forward `devices`
+    pub fn new(rotation: RotationPolicy, devices: &[Device]) -> Self {
+        Self {
+            params: None,
+            first_frame: true,
+            rotation,
+            epoch: 0,
+            rotated_at: Instant::now(),
+            bytes_since_rotation: 0,
+            input_key: None,
+            devices: devices.to_vec(),
+            consented: Vec::new(),
+        }
+    }
+
+    /// Agreed settings, or `None` for a client without a hello.
+    pub fn params(&self) -> Option<&ChannelParams> {
+        self.params.as_ref()
+    }
+
+    /// Whether resize messages carry the client's size in pixels.
+    pub fn resize(&self) -> bool {
+        self.params.as_ref().is_some_and(|p| p.resize)
+    }
+
+    /// Interval of heartbeats to the client, if agreed
+    pub fn heartbeat_interval(&self) -> Option<Duration> {
+        self.params.as_ref()?.heartbeat_interval.map(Duration::from_secs)
+    }
+
+    /// Handle a frame from the client.
+    pub fn receive(&mut self, frame: Bytes) -> Received {
+        let first_frame = std::mem::take(&mut self.first_frame);
+        if self.params.is_some() {
+            return match frame.split_first() {
+                Some((&FRAME_DATA, _)) => self.receive_data(frame.slice(1..)),
+                Some((&FRAME_CONTROL, message)) => {
+                    let input = match serde_json::from_slice(message) {
+                        Ok(ControlMessage::InputRekey { epoch, offset }) => {
+                            return match self.input_key.take_if(|(pending, _)| *pending == epoch) {
+                                Some((_, key)) => Received::InputRekey(offset + frame.len() as u64, key),
+                                None => {
+                                    warn!(epoch, "Ignoring input rekey to an unknown epoch");
+                                    Received::Ignored
+                                }
+                            };
+                        }
+                        Ok(ControlMessage::DeviceConsent { device }) => {
+                            return match self.consent(device) {
+                                true => Received::Consent(device),
+                                false => Received::Ignored,
+                            };
+                        }
+                        Ok(ControlMessage::SmartcardAttach) => SmartcardInput::Attach,
+                        Ok(ControlMessage::Smartcard { data }) => SmartcardInput::Data(data),
+                        Ok(ControlMessage::SmartcardDetach) => SmartcardInput::Detach,
+                        _ => {
+                            debug!(message = %String::from_utf8_lossy(message), "Ignoring desktop channel control message");
+                            return Received::Ignored;
+                        }
+                    };
+                    if !self.consented.contains(&Device::Smartcard) {
+                        debug!("Ignoring smartcard input without consent");
+                        return Received::Ignored;
+                    }
+                    Received::Smartcard(input)
+                }
+                _ => Received::Ignored,
+            };
+        }
+        if first_frame && frame.first() == Some(&FRAME_CONTROL) {
+            if let Ok(ControlMessage::Hello(hello)) = serde_json::from_slice(&frame[1..]) {
+                if let Some(mut params) = hello.negotiate() {
+                    params.devices.retain(|device| self.devices.contains(device));
+                    debug!(?params, "Negotiated desktop channel");
+                    self.params = Some(params.clone());
+                    return Received::Reply(control_frame(&ControlMessage::Welcome(params)));
+                }
+                // Leave the client on the unframed stream
+                return Received::Ignored;
+            }
+        }
+        // Clients without a hello cannot consent to any device
+        self.receive_data(frame)
+    }
+
+    /// Devices the client may forward once the user consents.
+    fn devices(&self) -> &[Device] {
+        self.params.as_ref().map_or(&[], |p| &p.devices)
+    }
+
+    /// Record that the user agreed to forward `device`, returning whether
+    /// the client may and had not agreed yet.
+    fn consent(&mut self, device: Device) -> bool {
+        if !self.devices().contains(&device) || self.consented.contains(&device) {
+            return false;
+        }
+        debug!(%device, "User consented to forwarding a device");
+        self.consented.push(device);
+        true
+    }
+
+    /// Traffic for xpra, held back if it attaches a device the user did
+    /// not agree to, or if the session may forward a device and the
+    /// traffic's packets cannot be read to tell.
+    fn receive_data(&self, data: Bytes) -> Received {
+        // xpra of sessions without devices takes none from the client
+        if self.devices.is_empty() {
+            return Received::Data(data);
+        }
+        let Some(types) = xpra_protocol::packet_types(&data) else {
+            warn!(len = data.len(), "Dropping client traffic whose packets cannot be read");
+            return Received::Ignored;
+        };
+        let mut attached = None;
+        for device in types.iter().filter_map(|packet| Device::attached_by(packet)) {
+            if !self.consented.contains(&device) {
+                warn!(%device, "Dropping a device attached without consent");
+                return Received::Ignored;
+            }
+            attached = Some(device);
+        }
+        match attached {
+            Some(device) => Received::Attach(device, data),
+            None => Received::Data(data),
+        }
+    }
+
+    /// Control frame asking the user to agree to forwarding the devices the
+    /// client may forward, if any.
+    pub fn consent_request(&self, config: &DeviceConfig) -> Option<Bytes> {
+        let devices = self.devices();
+        if devices.is_empty() {
+            return None;
+        }
+        Some(control_frame(&ControlMessage::Notification(config.consent_notification(devices))))
+    }
+
+    /// Control frame passing a command of the desktop's reader to the
+    /// client's card.
+    pub fn smartcard(&self, data: Vec<u8>) -> Bytes {
+        control_frame(&ControlMessage::Smartcard { data })
+    }
+
+    /// Whether the stream key is due for rotation.
+    pub fn rekey_due(&self) -> bool {
+        if self.params.as_ref().is_none_or(|p| p.version < REKEY_VERSION) {
+            return false;
+        }
+        self.rotation.interval.is_some_and(|interval| self.rotated_at.elapsed() >= interval)
+            || self.rotation.bytes.is_some_and(|bytes| self.bytes_since_rotation >= bytes)
+    }
+
+    /// Move to the next key epoch with fresh random keys, returning the key
+    /// of the stream to the client with the frame announcing them. The frame
+    /// itself goes out under the old key at `offset`; the new key applies
+    /// from right after it.
+    pub fn rekey(&mut self, offset: u64) -> (Encrypt, Bytes) {
+        self.epoch += 1;
+        self.rotated_at = Instant::now();
+        self.bytes_since_rotation = 0;
+        let key: [u8; 16] = rand::thread_rng().gen();
+        let input_key: [u8; 16] = rand::thread_rng().gen();
+        // A client that never switched keeps its input key until it does
+        self.input_key = Some((self.epoch, input_key));
+        // The announced offset depends on the frame's own length, which
+        // only varies with the number of digits in it
+        let mut next = offset;
+        loop {
+            let frame = control_frame(&ControlMessage::Rekey {
+                epoch: self.epoch,
+                offset: next,
+                key: key.to_vec(),
+                input_key: input_key.to_vec(),
+            });
+            let end = offset + frame.len() as u64;
+            if end == next {
+                return (Encrypt::from_key(key), frame);
+            }
+            next = end;
+        }
+    }
+
+    /// Frame xpra output for the client. Unframed output keeps its buffer.
+    pub fn send(&mut self, data: Vec<u8>) -> Bytes {
+        self.bytes_since_rotation += data.len() as u64;
+        let Some(params) = &self.params else {
+            return data.into();
+        };
+        let mut frame = Vec::with_capacity(data.len() + 1);
+        frame.push(FRAME_DATA);
+        match params.compression.as_deref() {
+            Some("deflate") => {
+                let mut encoder = DeflateEncoder::new(frame, Compression::fast());
+                // Writing to a Vec cannot fail
+                encoder.write_all(&data).unwrap();
+                frame = encoder.finish().unwrap();
+            }
+            _ => frame.extend_from_slice(&data),
+        }
+        frame.into()
+    }
+
+    /// Control frames for the notifications raised or closed in xpra
+    /// packets, if the client asked for them.
+    pub fn notifications(&self, packets: &[XpraPacket]) -> Vec<Bytes> {
+        if self.params.as_ref().is_none_or(|p| !p.notifications) {
+            return Vec::new();
+        }
+        packets
+            .iter()
+            .filter_map(|packet| match packet {
+                XpraPacket::Notification(NotificationEvent::Show(notification)) => {
+                    Some(control_frame(&ControlMessage::Notification(notification.clone())))
+                }
+                XpraPacket::Notification(NotificationEvent::Close(id)) => {
+                    Some(control_frame(&ControlMessage::NotificationClosed { id: *id }))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Heartbeat frame, if the client asked for heartbeats.
+    pub fn heartbeat(&self) -> Option<Bytes> {
+        self.heartbeat_interval()?;
+        Some(control_frame(&ControlMessage::Heartbeat { time: Utc::now().timestamp_millis() }))
+    }
+}
+
+fn control_frame(message: &ControlMessage) -> Bytes {
+    let mut frame = vec![FRAME_CONTROL];
+    serde_json::to_writer(&mut frame, message).expect("control messages serialize");
+    frame.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use flate2::read::DeflateDecoder;
+
+    use super::*;
+    use crate::encrypt::KeySchedule;
+
+    #[test]
+    fn test_negotiate() {
+        let hello = ClientHello {
+            version: 7,
+            encodings: vec!["jpeg".to_string(), "vp9".to_string()],
+            compression: vec!["zstd".to_string(), "deflate".to_string()],
+            resize: true,
+            heartbeat_interval: Some(1),
+            notifications: false,
+            devices: vec![Device::Webcam],
+        };
+        let params = hello.negotiate().unwrap();
+        assert_eq!(params.version, PROTOCOL_VERSION);
+        assert_eq!(params.encodings, vec!["jpeg".to_string()]);
+        assert_eq!(params.compression.as_deref(), Some("deflate"));
+        assert_eq!(params.heartbeat_interval, Some(5));
+        // Without notifications there is no asking for consent
+        assert!(params.devices.is_empty());
+
+        assert!(ClientHello::default().negotiate().is_none());
+    }
+
+    #[test]
+    fn test_legacy_client() {
+        let mut channel = DesktopChannel::new(RotationPolicy::default(), &[]);
+        let frame = Bytes::from_static(b"PJ\0\0");
+        assert_eq!(channel.receive(frame.clone()), Received::Data(frame));
+        // A hello is only recognized as the first frame
+        let hello = control_frame(&ControlMessage::Hello(ClientHello { version: 1, ..Default::default() }));
+        assert_eq!(channel.receive(hello.clone()), Received::Data(hello));
+        assert_eq!(channel.send(b"xpra".to_vec()), &b"xpra"[..]);
+        assert_eq!(channel.heartbeat(), None);
+    }
+
+    #[test]
+    fn test_negotiated_client() {
+        let mut channel = DesktopChannel::new(RotationPolicy::default(), &[]);
+        let hello = ClientHello {
+            version: 1,
+            compression: vec!["deflate".to_string()],
+            heartbeat_interval: Some(30),
+            ..Default::default()
+        };
+        let Received::Reply(reply) = channel.receive(control_frame(&ControlMessage::Hello(hello))) else {
+            panic!("expected a welcome");
+        };
+        assert_eq!(reply[0], FRAME_CONTROL);
+        let welcome: ControlMessage = serde_json::from_slice(&reply[1..]).unwrap();
+        assert!(matches!(welcome, ControlMessage::Welcome(params) if params.encodings.len() == ENCODINGS.len()));
+
+        assert_eq!(channel.receive(Bytes::from_static(b"\0PJ")), Received::Data(Bytes::from_static(b"PJ")));
+        assert_eq!(channel.receive(Bytes::from_static(b"\x01{}")), Received::Ignored);
+
+        let frame = channel.send(b"xpra output".to_vec());
+        assert_eq!(frame[0], FRAME_DATA);
+        let mut data = Vec::new();
+        DeflateDecoder::new(&frame[1..]).read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"xpra output");
+        assert_eq!(channel.heartbeat().unwrap()[0], FRAME_CONTROL);
+    }
+
+    #[test]
+    fn test_device_consent() {
+        let mut channel = DesktopChannel::new(RotationPolicy::default(), &[Device::Webcam]);
+        let hello = ClientHello { version: 2, notifications: true, devices: vec![Device::Webcam], ..Default::default() };
+        channel.receive(control_frame(&ControlMessage::Hello(hello)));
+        let request = channel.consent_request(&DeviceConfig::default()).unwrap();
+        assert!(matches!(serde_json::from_slice(&request[1..]).unwrap(), ControlMessage::Notification(_)));
+
+        // bencoded ["webcam-start", 0]
+        let mut attach = b"\0P\0\0\0".to_vec();
+        attach.extend(20u32.to_be_bytes());
+        attach.extend_from_slice(b"l12:webcam-starti0ee");
+        let attach = Bytes::from(attach);
+        assert_eq!(channel.receive(attach.clone()), Received::Ignored);
+        let consent = control_frame(&ControlMessage::DeviceConsent { device: Device::Webcam });
+        assert_eq!(channel.receive(consent.clone()), Received::Consent(Device::Webcam));
+        assert_eq!(channel.receive(consent), Received::Ignored);
+        assert_eq!(channel.receive(attach.clone()), Received::Attach(Device::Webcam, attach.slice(1..)));
+        // Packets that cannot be read are dropped
+        assert_eq!(channel.receive(Bytes::from_static(b"\0PJ")), Received::Ignored);
+        let mut input = b"\0P\0\0\0".to_vec();
+        input.extend(19u32.to_be_bytes());
+        input.extend_from_slice(b"l11:pointer-posi0ee");
+        let input = Bytes::from(input);
+        assert_eq!(channel.receive(input.clone()), Received::Data(input.slice(1..)));
+
+        // Clients that cannot be asked never attach a device
+        let mut legacy = DesktopChannel::new(RotationPolicy::default(), &[Device::Webcam]);
+        assert_eq!(legacy.receive(attach.slice(1..)), Received::Ignored);
+        assert_eq!(legacy.receive(input.slice(1..)), Received::Data(input.slice(1..)));
+        let mut silent = DesktopChannel::new(RotationPolicy::default(), &[Device::Webcam]);
+        let hello = ClientHello { version: 2, devices: vec![Device::Webcam], ..Default::default() };
+        silent.receive(control_frame(&ControlMessage::Hello(hello)));
+        assert_eq!(silent.receive(control_frame(&ControlMessage::DeviceConsent { device: Device::Webcam })), Received::Ignored);
+        assert_eq!(silent.receive(attach.clone()), Received::Ignored);
+        // Smartcards need a consent of their own
+        let smartcard = control_frame(&ControlMessage::Smartcard { data: vec![0x90, 0] });
+        assert_eq!(channel.receive(smartcard.clone()), Received::Ignored);
+        let mut channel = DesktopChannel::new(RotationPolicy::default(), &[Device::Smartcard]);
+        let hello = ClientHello { version: 2, notifications: true, devices: vec![Device::Smartcard], ..Default::default() };
+        channel.receive(control_frame(&ControlMessage::Hello(hello)));
+        channel.receive(control_frame(&ControlMessage::DeviceConsent { device: Device::Smartcard }));
+        let expected = Received::Smartcard(SmartcardInput::Data(vec![0x90, 0]));
+        assert_eq!(channel.receive(smartcard), expected);
+
+        // Sessions not allowed a device never ask for it
+        let mut channel = DesktopChannel::new(RotationPolicy::default(), &[]);
+        let hello = ClientHello { version: 2, notifications: true, devices: vec![Device::Webcam], ..Default::default() };
+        channel.receive(control_frame(&ControlMessage::Hello(hello)));
+        assert!(channel.consent_request(&DeviceConfig::default()).is_none());
+    }
+
+    #[test]
+    fn test_rekey() {
+        let key = Encrypt::new("this is a test key");
+        let rotation = RotationPolicy { interval: None, bytes: Some(16) };
+        let mut channel = DesktopChannel::new(rotation, &[]);
+        let hello = ClientHello { version: 2, ..Default::default() };
+        channel.receive(control_frame(&ControlMessage::Hello(hello)));
+        assert!(!channel.rekey_due());
+
+        // Sender: a data frame, the rekey announcement, then a data frame
+        // under the new key
+        let first = channel.send(b"before the boundary".to_vec());
+        assert!(channel.rekey_due());
+        let mut stream = key.segment(1, 0, &first);
+        let (rotated, rekey) = channel.rekey(stream.len() as u64);
+        assert!(!channel.rekey_due());
+        stream.extend(key.segment(1, stream.len() as u64, &rekey));
+        let second = channel.send(b"after the boundary".to_vec());
+        stream.extend(rotated.segment(1, stream.len() as u64, &second));
+
+        // Receiver: decrypt up to the announcement, then switch keys
+        let boundary = first.len() + rekey.len();
+        let plain = key.segment(1, 0, &stream[..boundary]);
+        assert_eq!(&plain[1..first.len()], b"before the boundary");
+        let message = serde_json::from_slice(&plain[first.len() + 1..]).unwrap();
+        let ControlMessage::Rekey { epoch, offset, key: new_key, input_key } = message else {
+            panic!("expected a rekey");
+        };
+        assert_eq!(epoch, 1);
+        assert_eq!(offset, boundary as u64);
+        assert_ne!(new_key, input_key);
+        let new_key = Encrypt::from_key(new_key.try_into().unwrap());
+        let plain = new_key.segment(1, offset, &stream[boundary..]);
+        assert_eq!(&plain[1..], b"after the boundary");
+
+        // The next rotation's keys are fresh
+        let (_, next) = channel.rekey(0);
+        let ControlMessage::Rekey { key: next_key, .. } = serde_json::from_slice(&next[1..]).unwrap() else {
+            panic!("expected a rekey");
+        };
+        assert_ne!(Encrypt::from_key(next_key.try_into().unwrap()).zeros(), new_key.zeros());
+
+        // The client's input switches to the input key of the epoch right
+        // after its announcement; other epochs are not taken
+        let input_key = Encrypt::from_key(input_key.try_into().unwrap());
+        assert_eq!(channel.receive(control_frame(&ControlMessage::InputRekey { epoch: 1, offset: 40 })), Received::Ignored);
+        let announce = control_frame(&ControlMessage::InputRekey { epoch: 2, offset: 40 });
+        let mut schedule = KeySchedule::new(key.clone());
+        let Received::InputRekey(switch, next_input) = channel.receive(announce.clone()) else {
+            panic!("expected an input rekey");
+        };
+        assert_eq!(switch, 40 + announce.len() as u64);
+        let next_input = Encrypt::from_key(next_input);
+        assert_ne!(next_input.zeros(), input_key.zeros());
+        schedule.switch(switch, next_input.clone());
+        let input = next_input.segment(2, switch, b"typed");
+        assert_eq!(schedule.segment_bytes(2, switch, input.into()), &b"typed"[..]);
+        assert_eq!(channel.receive(announce), Received::Ignored);
+    }
+}
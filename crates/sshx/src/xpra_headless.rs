@@ -0,0 +1,517 @@
+//! Headless sessions for automation: desktops with no attached client,
+//! driven entirely through exec and screenshot requests on the admin API.
+//!
+//! Headless sessions have their own idle timeout, counted from the last
+//! request, and run with the `batch` session class priorities.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Mutex, Notify};
+use tokio::time;
+use tracing::{debug, info, warn};
+
+use crate::xpra::{DisplayOptions, XpraDisplay};
+use crate::xpra_backend::BackendConfig;
+use crate::xpra_capacity::SessionSlot;
+use crate::xpra_config::XpraConfig;
+use crate::xpra_context::XpraContext;
+use crate::xpra_crash;
+use crate::xpra_hooks::{HookPoint, HookSession};
+use crate::xpra_hosts::WorkerHost;
+use crate::xpra_labels::Labels;
+use crate::xpra_logger::TerminationReason;
+use crate::xpra_monitor::SessionGuard;
+use crate::xpra_policy::SessionTemplate;
+use crate::xpra_priority::SessionClass;
+use crate::xpra_runner::admit_session;
+use crate::xpra_screenshots;
+use crate::xpra_session_state::SessionState;
+
+const REAP_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Limits of headless sessions.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HeadlessConfig {
+    /// Seconds without requests before a headless session is stopped (0 = never)
+    #[serde(default = "default_idle_timeout")]
+    pub idle_timeout: u64,
+
+    /// Seconds an exec request may run before it is killed
+    #[serde(default = "default_exec_timeout")]
+    pub exec_timeout: u64,
+
+    /// Directory screenshots of headless sessions are saved in
+    #[serde(default = "default_screenshot_dir")]
+    pub screenshot_dir: PathBuf,
+}
+
+fn default_idle_timeout() -> u64 { 600 } // 10 minutes
+fn default_exec_timeout() -> u64 { 300 } // 5 minutes
+fn default_screenshot_dir() -> PathBuf { PathBuf::from("/var/lib/sshx/headless-screenshots") }
+
+impl Default for HeadlessConfig {
+    fn default() -> Self {
+        Self {
+            idle_timeout: default_idle_timeout(),
+            exec_timeout: default_exec_timeout(),
+            screenshot_dir: default_screenshot_dir(),
+        }
+    }
+}
+
+/// A launched headless session.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HeadlessSession {
+    /// Id of the session
+    pub session_id: String,
+    /// User the session runs as
+    pub user: String,
+    /// Host running the display
+    pub host: String,
+    /// Display number of the session
+    pub display: u16,
+}
+
+/// Screenshot saved of a headless display.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SavedScreenshot {
+    /// Where the PNG was written on the host
+    pub path: PathBuf,
+    /// Size of the PNG
+    pub bytes: u64,
+}
+
+/// Result of a command run on a headless display.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExecOutput {
+    /// Exit code, or `None` if the command was killed by a signal
+    pub exit_code: Option<i32>,
+    /// Standard output of the command
+    pub stdout: String,
+    /// Standard error of the command
+    pub stderr: String,
+}
+
+struct Running {
+    /// Owns the session's display
+    guard: SessionGuard,
+    /// Configuration the session was launched with
+    config: Arc<XpraConfig>,
+    user: String,
+    idle_timeout: Option<Duration>,
+    last_used: Instant,
+    /// Held for the session's lifetime, like a forwarder holds its slot
+    _slot: SessionSlot,
+}
+
+impl Running {
+    /// Stop the display and deregister the session, then run the post-stop
+    /// hooks. A stop still running when `abort` is notified is dropped,
+    /// which kills xpra and tells the monitor the session stopped.
+    async fn stop(self, session_id: &str, abort: Option<&Notify>) {
+        let Running { guard, config, user, _slot, .. } = self;
+        let host = guard.display().host().clone();
+        let hook_session = HookSession { session_id, user: &user, display: Some(guard.display().display()) };
+        match abort {
+            Some(abort) => tokio::select! {
+                _ = guard.finish() => {}
+                _ = abort.notified() => warn!(session_id, "Aborted stopping headless session"),
+            },
+            None => guard.finish().await,
+        }
+        config.hooks.run_post_stop(&host, &hook_session).await;
+    }
+}
+
+/// Headless sessions running on the workers of a context.
+#[derive(Clone)]
+pub struct HeadlessManager {
+    /// Context the manager belongs to, which holds it
+    context: Weak<XpraContext>,
+    sessions: Arc<Mutex<HashMap<String, Running>>>,
+}
+
+impl fmt::Debug for HeadlessManager {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HeadlessManager").finish_non_exhaustive()
+    }
+}
+
+impl HeadlessManager {
+    /// Create a manager of the headless sessions of `context`, with none
+    /// running
+    pub fn new(context: Weak<XpraContext>) -> Self {
+        Self {
+            context,
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Stop idle and exited sessions in the background.
+    pub fn start(&self) {
+        self.start_reaper();
+    }
+
+    fn context(&self) -> Result<Arc<XpraContext>> {
+        self.context.upgrade().context("the desktop host is shutting down")
+    }
+
+    /// Start a headless session running `command`, or the configured window
+    /// manager, and wait for its display to come up.
+    pub async fn launch(
+        &self,
+        user: String,
+        command: Option<String>,
+        idle_timeout_secs: Option<u64>,
+        labels: Labels,
+    ) -> Result<HeadlessSession> {
+        let context = self.context()?;
+        let config = context.config.clone();
+
+        // The id is chosen up front so the policy and pre-start hooks can be
+        // given it
+        let session_id = context.session_ids.headless();
+        let template = SessionTemplate {
+            window_manager: command.unwrap_or_else(|| config.window_manager.clone()),
+            class: SessionClass::Batch,
+            labels,
+            app: None,
+            resolution: None,
+            audio: false,
+            locale: None,
+            timezone: None,
+            keyboard_layout: config.keyboard_layout.clone(),
+            backend: BackendConfig::Xpra,
+        };
+        let template = admit_session(&context, &session_id, &user, template).await?;
+        let slot = context.capacity.acquire().await?;
+
+        let host = context.hosts.place(&context, &user).await?;
+        let hooks = &config.hooks;
+        let mut hook_session = HookSession { session_id: &session_id, user: &user, display: None };
+        hooks.run(HookPoint::PreStart, &host, &hook_session).await?;
+        let options = DisplayOptions {
+            app: template.app.clone(),
+            audio: template.audio,
+            locale: template.locale.clone(),
+            timezone: template.timezone.clone(),
+            keyboard_layout: template.keyboard_layout.clone(),
+            backend: template.backend.clone(),
+            ..DisplayOptions::default()
+        };
+        let mut display = match XpraDisplay::new_on_host(&config, host.clone(), &template.window_manager, template.class, &options).await {
+            Ok(display) => display,
+            Err(e) => {
+                hooks.run_post_stop(&host, &hook_session).await;
+                return Err(e);
+            }
+        };
+        hook_session.display = Some(display.display());
+        // Post-start hooks run once xpra listens and the display is bound
+        let started = async {
+            display.wait_ready(config.startup_duration()).await?;
+            display.bind()?;
+            hooks.run(HookPoint::PostStart, &host, &hook_session).await
+        }
+        .await;
+        if let Err(e) = started {
+            display.close().await;
+            hooks.run_post_stop(&host, &hook_session).await;
+            return Err(e);
+        }
+
+        let session = HeadlessSession {
+            session_id: session_id.clone(),
+            user: user.clone(),
+            host: host.name.clone(),
+            display: display.display(),
+        };
+        let guard = context
+            .monitor
+            .register_session(session_id, user.clone(), display, None, template.labels)
+            .await;
+        context.monitor.set_state(&session.session_id, SessionState::Active).await;
+        let (shutdown, abort) = (guard.info().shutdown.clone(), guard.info().abort.clone());
+
+        let idle_timeout = idle_timeout_secs.unwrap_or(config.headless.idle_timeout);
+        self.sessions.lock().await.insert(session.session_id.clone(), Running {
+            guard,
+            config,
+            user,
+            idle_timeout: (idle_timeout > 0).then(|| Duration::from_secs(idle_timeout)),
+            last_used: Instant::now(),
+            _slot: slot,
+        });
+
+        // Stop the display when the session is terminated from elsewhere,
+        // e.g. at the end of a drain, aborting it if it outlasts the
+        // monitor's stop timeout
+        let manager = self.clone();
+        let session_id = session.session_id.clone();
+        tokio::spawn(async move {
+            shutdown.notified().await;
+            let running = manager.sessions.lock().await.remove(&session_id);
+            if let Some(running) = running {
+                running.stop(&session_id, Some(&abort)).await;
+                debug!(session_id, "Stopped terminated headless session");
+            }
+        });
+
+        info!(
+            session_id = session.session_id,
+            host = session.host,
+            display = session.display,
+            "Launched headless Xpra session"
+        );
+        Ok(session)
+    }
+
+    /// Run a shell command on a session's display. Detached commands are
+    /// started by xpra and return immediately; others are waited for up to
+    /// the exec timeout.
+    pub async fn exec(&self, session_id: &str, command: &str, detach: bool) -> Result<ExecOutput> {
+        let (host, number, prefix, config) = self.touch(session_id).await?;
+        let mut cmd = if detach {
+            let args = [
+                "control".to_string(),
+                format!(":{}", number),
+                "start".to_string(),
+                command.to_string(),
+            ];
+            tokio::process::Command::from(host.command("xpra", &args))
+        } else {
+            // Commands run in the display's namespace and sandbox, like its programs
+            let mut args = prefix;
+            args.extend([
+                "env".to_string(),
+                format!("DISPLAY=:{}", number),
+                "sh".to_string(),
+                "-c".to_string(),
+                command.to_string(),
+            ]);
+            tokio::process::Command::from(host.command(&args[0], &args[1..]))
+        };
+        cmd.kill_on_drop(true);
+
+        let timeout = Duration::from_secs(config.headless.exec_timeout);
+        let output = time::timeout(timeout, cmd.output())
+            .await
+            .with_context(|| format!("command timed out after {}s", timeout.as_secs()))?
+            .context("failed to run command")?;
+        debug!(session_id, command, status = %output.status, "Ran command on headless session");
+        Ok(ExecOutput {
+            exit_code: output.status.code(),
+            stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        })
+    }
+
+    /// Save a PNG screenshot of a session's display as `name` in the
+    /// screenshot directory of this host. Names are plain file names, so
+    /// requests cannot write anywhere else.
+    pub async fn screenshot(&self, session_id: &str, name: &str) -> Result<SavedScreenshot> {
+        let (host, number, _, config) = self.touch(session_id).await?;
+        let dir = &config.headless.screenshot_dir;
+        let path = screenshot_path(dir, name)?;
+        let png = xpra_screenshots::capture(&host, number).await?;
+        tokio::fs::create_dir_all(dir)
+            .await
+            .with_context(|| format!("failed to create {}", dir.display()))?;
+        tokio::fs::write(&path, &png)
+            .await
+            .with_context(|| format!("failed to write {}", path.display()))?;
+        Ok(SavedScreenshot { path, bytes: png.len() as u64 })
+    }
+
+    /// Stop a headless session.
+    pub async fn stop(&self, session_id: &str) -> Result<()> {
+        self.end(session_id, TerminationReason::AdminKill).await
+    }
+
+    /// Stop a session ending for `reason`.
+    async fn end(&self, session_id: &str, reason: TerminationReason) -> Result<()> {
+        if !reason.is_failure() {
+            if !self.sessions.lock().await.contains_key(session_id) {
+                anyhow::bail!("no headless session {}", session_id);
+            }
+            // The session's shutdown task stops it while the monitor waits
+            self.context()?.monitor.terminate_session(session_id, reason).await;
+            return Ok(());
+        }
+        let running = self.sessions.lock().await.remove(session_id);
+        let Some(mut running) = running else {
+            anyhow::bail!("no headless session {}", session_id);
+        };
+        running.guard.set_reason(reason);
+        let config = running.config.clone();
+        if let Some(bundle) = xpra_crash::collect_for(&config.crash, running.guard.display_mut(), session_id, &running.user).await {
+            running.guard.set_crash_bundle(bundle);
+        }
+        running.guard.info().shutdown.notify_one();
+        running.stop(session_id, None).await;
+        Ok(())
+    }
+
+    /// Record a request on a session, returning where its display runs and
+    /// the configuration it was launched with.
+    async fn touch(&self, session_id: &str) -> Result<(Arc<WorkerHost>, u16, Vec<String>, Arc<XpraConfig>)> {
+        let mut sessions = self.sessions.lock().await;
+        let running = sessions
+            .get_mut(session_id)
+            .with_context(|| format!("no headless session {}", session_id))?;
+        running.last_used = Instant::now();
+        let display = running.guard.display();
+        let location = (display.host().clone(), display.display(), display.command_prefix(), running.config.clone());
+        drop(sessions);
+
+        if let Ok(context) = self.context() {
+            context.monitor.update_activity(session_id).await;
+        }
+        Ok(location)
+    }
+
+    /// Stop sessions idle past their timeout or whose xpra has exited.
+    fn start_reaper(&self) {
+        let manager = self.clone();
+        tokio::spawn(async move {
+            let mut interval = time::interval(REAP_INTERVAL);
+            loop {
+                interval.tick().await;
+                if manager.context.strong_count() == 0 {
+                    return;
+                }
+                let mut expired = Vec::new();
+                {
+                    let mut sessions = manager.sessions.lock().await;
+                    let now = Instant::now();
+                    for (session_id, running) in sessions.iter_mut() {
+                        let idle = running
+                            .idle_timeout
+                            .is_some_and(|timeout| now.duration_since(running.last_used) > timeout);
+                        let exited = !running.guard.display_mut().is_running();
+                        if idle || exited {
+                            expired.push((session_id.clone(), exited));
+                        }
+                    }
+                }
+                for (session_id, exited) in expired {
+                    warn!(session_id, exited, "Stopping idle or exited headless session");
+                    let reason = match exited {
+                        true => TerminationReason::ProcessCrash,
+                        false => TerminationReason::IdleTimeout,
+                    };
+                    let _ = manager.end(&session_id, reason).await;
+                }
+            }
+        });
+    }
+}
+
+/// Path of the screenshot `name` in `dir`, if `name` is a file name.
+fn screenshot_path(dir: &Path, name: &str) -> Result<PathBuf> {
+    let mut components = Path::new(name).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(file)), None) => Ok(dir.join(file)),
+        _ => anyhow::bail!("screenshot name {:?} is not a plain file name", name),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::xpra_capacity::SessionCapacity;
+    use crate::xpra_config::XpraConfig;
+    use crate::xpra_logger::XpraLogger;
+    use crate::xpra_metrics::XpraMetrics;
+    use crate::xpra_monitor::SessionMonitor;
+
+    /// Add a headless session of Alice whose display is a `sleep`, tracked
+    /// by a monitor of its own.
+    async fn add_session(manager: &HeadlessManager, session_id: &str) -> SessionMonitor {
+        let dir = std::env::temp_dir().join(format!("sshx-headless-{}-{}", session_id, std::process::id()));
+        let config = Arc::new(XpraConfig { log_dir: dir.clone(), instance: None, ..XpraConfig::default() });
+        let monitor = SessionMonitor::new(config.clone(), XpraLogger::new(dir), Arc::new(XpraMetrics::new()));
+        let (guard, _) = monitor.register_sleeping(session_id, None, Labels::new()).await;
+        manager.sessions.lock().await.insert(session_id.to_string(), Running {
+            guard,
+            config,
+            user: "alice".to_string(),
+            idle_timeout: None,
+            last_used: Instant::now(),
+            _slot: SessionCapacity::new(0, None, Arc::default()).acquire().await.unwrap(),
+        });
+        monitor
+    }
+
+    #[test]
+    fn test_config_defaults() {
+        let config: HeadlessConfig = serde_json::from_str(r#"{"exec_timeout": 30}"#).unwrap();
+        assert_eq!(config.idle_timeout, 600);
+        assert_eq!(config.exec_timeout, 30);
+    }
+
+    #[test]
+    fn test_screenshot_path() {
+        let dir = Path::new("/var/lib/sshx/headless-screenshots");
+        assert_eq!(screenshot_path(dir, "result.png").unwrap(), dir.join("result.png"));
+        for name in ["", "/etc/passwd", "../result.png", "ci/result.png", "..", "./result.png"] {
+            assert!(screenshot_path(dir, name).is_err(), "{name:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unknown_session() {
+        let manager = HeadlessManager::new(Weak::new());
+        let err = manager.exec("headless-x", "true", false).await.unwrap_err();
+        assert_eq!(err.to_string(), "no headless session headless-x");
+        assert!(manager.screenshot("headless-x", "x.png").await.is_err());
+        assert!(manager.stop("headless-x").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_launch_is_admitted() {
+        let dir = std::env::temp_dir().join(format!("sshx-headless-admit-{}", std::process::id()));
+        let context = XpraContext::new(XpraConfig { log_dir: dir.clone(), instance: None, ..XpraConfig::default() });
+
+        // Headless launches are refused like interactive starts
+        context.drain.start(Duration::from_secs(60), None).await;
+        let err = context.headless.launch("alice".to_string(), None, None, Labels::new()).await.unwrap_err();
+        assert!(err.to_string().contains("maintenance mode"), "{err}");
+        assert_eq!(context.monitor.get_user_session_count("alice").await, 0);
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn test_exec_touches_session() {
+        let manager = HeadlessManager::new(Weak::new());
+        let _monitor = add_session(&manager, "headless-exec").await;
+        let before = manager.sessions.lock().await["headless-exec"].last_used;
+
+        let output = manager.exec("headless-exec", "echo \"$DISPLAY\"; echo oops >&2; exit 3", false).await.unwrap();
+        let number = manager.sessions.lock().await["headless-exec"].guard.display().display();
+        assert_eq!(output.stdout, format!(":{number}\n"));
+        assert_eq!(output.stderr, "oops\n");
+        assert_eq!(output.exit_code, Some(3));
+        assert!(manager.sessions.lock().await["headless-exec"].last_used > before);
+
+        manager.end("headless-exec", TerminationReason::ProcessCrash).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_failed_session_is_stopped() {
+        let manager = HeadlessManager::new(Weak::new());
+        let monitor = add_session(&manager, "headless-crash").await;
+        assert_eq!(monitor.get_user_session_count("alice").await, 1);
+
+        manager.end("headless-crash", TerminationReason::ProcessCrash).await.unwrap();
+        assert!(manager.sessions.lock().await.is_empty());
+        assert_eq!(monitor.get_user_session_count("alice").await, 0);
+        assert!(manager.end("headless-crash", TerminationReason::ProcessCrash).await.is_err());
+    }
+}
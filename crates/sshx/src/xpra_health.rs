@@ -0,0 +1,282 @@
+//! Health, readiness, and liveness checks for a desktop host.
+//!
+//! `/healthz` answers as long as the process is serving requests, while
+//! `/readyz` only succeeds when new desktop sessions can actually be started,
+//! so load balancers and supervisors can take the host out of rotation.
+//! `/metrics` serves the session summary to Prometheus.
+
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+use tokio::time;
+use tracing::{debug, error, info};
+
+use crate::xpra_context::XpraContext;
+
+/// How long the outcome of `xpra --version` is reused by later probes.
+const XPRA_CHECK_TTL: Duration = Duration::from_secs(60);
+
+/// Time `xpra --version` has to answer before the check fails.
+const XPRA_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+lazy_static::lazy_static! {
+    /// Last outcome of the xpra check and when it was found, so frequent
+    /// probes do not each start xpra
+    static ref XPRA_CHECK: Mutex<Option<(Instant, CheckResult)>> = Mutex::new(None);
+}
+
+/// Outcome of a single readiness check.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CheckResult {
+    /// Name of the check
+    pub name: String,
+    /// Whether the check passed
+    pub ok: bool,
+    /// What the check found
+    pub detail: String,
+}
+
+impl CheckResult {
+    pub(crate) fn pass(name: &str, detail: impl Into<String>) -> Self {
+        Self { name: name.to_string(), ok: true, detail: detail.into() }
+    }
+
+    pub(crate) fn fail(name: &str, detail: impl Into<String>) -> Self {
+        Self { name: name.to_string(), ok: false, detail: detail.into() }
+    }
+}
+
+/// Result of the self-check; the host is ready when every check passes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HealthReport {
+    /// Whether every check passed
+    pub ready: bool,
+    /// Results of the checks
+    pub checks: Vec<CheckResult>,
+}
+
+impl HealthReport {
+    /// A report of `checks`, ready if all of them passed
+    pub fn new(checks: Vec<CheckResult>) -> Self {
+        Self {
+            ready: checks.iter().all(|c| c.ok),
+            checks,
+        }
+    }
+}
+
+/// Run all readiness checks against the state of `context`.
+pub async fn self_check(context: &XpraContext) -> HealthReport {
+    HealthReport::new(vec![
+        check_xpra_binary().await,
+        check_capacity(context).await,
+        check_log_dir(context.logger.log_dir()).await,
+        check_drain(context).await,
+    ])
+}
+
+async fn check_xpra_binary() -> CheckResult {
+    // Held while xpra runs, so probes arriving meanwhile wait for its answer
+    let mut cached = XPRA_CHECK.lock().await;
+    if let Some((checked_at, result)) = &*cached {
+        if checked_at.elapsed() < XPRA_CHECK_TTL {
+            return result.clone();
+        }
+    }
+    let mut command = tokio::process::Command::new("xpra");
+    command.arg("--version");
+    let result = check_version(command, XPRA_CHECK_TIMEOUT).await;
+    *cached = Some((Instant::now(), result.clone()));
+    result
+}
+
+/// Check that `command` prints a version within `timeout`.
+async fn check_version(mut command: tokio::process::Command, timeout: Duration) -> CheckResult {
+    command.kill_on_drop(true);
+    match time::timeout(timeout, command.output()).await {
+        Ok(Ok(output)) if output.status.success() => {
+            let version = String::from_utf8_lossy(&output.stdout);
+            CheckResult::pass("xpra", version.lines().next().unwrap_or_default().trim())
+        }
+        Ok(Ok(output)) => CheckResult::fail("xpra", format!("xpra --version exited with {}", output.status)),
+        Ok(Err(e)) => CheckResult::fail("xpra", format!("xpra not found: {e}")),
+        Err(_) => CheckResult::fail("xpra", format!("xpra --version did not answer within {}s", timeout.as_secs())),
+    }
+}
+
+async fn check_capacity(context: &XpraContext) -> CheckResult {
+    let hosts = context.hosts.status().await;
+    let free: usize = hosts
+        .iter()
+        .filter(|h| h.healthy)
+        .map(|h| h.capacity.saturating_sub(h.active_sessions))
+        .sum();
+    if free > 0 {
+        CheckResult::pass("display_pool", format!("{free} displays free"))
+    } else {
+        CheckResult::fail("display_pool", "no healthy host has a free display")
+    }
+}
+
+pub(crate) async fn check_log_dir(dir: &Path) -> CheckResult {
+    let probe = dir.join(".health-check");
+    match tokio::fs::write(&probe, b"ok").await {
+        Ok(()) => {
+            let _ = tokio::fs::remove_file(&probe).await;
+            CheckResult::pass("log_dir", dir.display().to_string())
+        }
+        Err(e) => CheckResult::fail("log_dir", format!("{} is not writable: {e}", dir.display())),
+    }
+}
+
+async fn check_drain(context: &XpraContext) -> CheckResult {
+    if context.drain.is_draining().await {
+        CheckResult::fail("drain", "host is draining for maintenance")
+    } else {
+        CheckResult::pass("drain", "accepting sessions")
+    }
+}
+
+/// Serves `/healthz`, `/readyz` and `/metrics` over plain HTTP.
+pub struct HealthServer {
+    context: Arc<XpraContext>,
+    addr: SocketAddr,
+}
+
+impl HealthServer {
+    /// Create a server of the host of `context` listening on `addr`
+    pub fn new(context: Arc<XpraContext>, addr: SocketAddr) -> Self {
+        Self { context, addr }
+    }
+
+    /// Bind the listener and serve probes in the background.
+    pub fn start(&self) -> Result<()> {
+        let listener = std::net::TcpListener::bind(self.addr)
+            .with_context(|| format!("failed to bind health endpoint on {}", self.addr))?;
+        listener.set_nonblocking(true)?;
+        let listener = TcpListener::from_std(listener)?;
+        info!(addr = %self.addr, "Health endpoints listening");
+
+        let context = self.context.clone();
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        let context = context.clone();
+                        tokio::spawn(async move {
+                            if let Err(e) = serve_probe(&context, stream).await {
+                                debug!("Health probe connection closed with error: {}", e);
+                            }
+                        });
+                    }
+                    Err(e) => error!("Failed to accept health probe: {}", e),
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+async fn serve_probe(context: &XpraContext, stream: TcpStream) -> Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+    let request_line = lines.next_line().await?.unwrap_or_default();
+    // Drain the headers so the client sees a clean close
+    while let Some(line) = lines.next_line().await? {
+        if line.is_empty() {
+            break;
+        }
+    }
+
+    let mut content_type = "application/json";
+    let (status, body) = match request_path(&request_line) {
+        Some("/healthz") => (200, r#"{"status":"ok"}"#.to_string()),
+        Some("/readyz") => {
+            let report = self_check(context).await;
+            let status = if report.ready { 200 } else { 503 };
+            (status, serde_json::to_string(&report)?)
+        }
+        Some("/metrics") => {
+            content_type = "text/plain; version=0.0.4";
+            (200, context.monitor.summary().prometheus())
+        }
+        Some(_) => (404, r#"{"error":"not found"}"#.to_string()),
+        None => (400, r#"{"error":"bad request"}"#.to_string()),
+    };
+
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        _ => "Service Unavailable",
+    };
+    let response = format!(
+        "HTTP/1.1 {status} {reason}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len(),
+    );
+    write.write_all(response.as_bytes()).await?;
+    write.shutdown().await?;
+    Ok(())
+}
+
+/// Path of a `GET` or `HEAD` request line, without any query string.
+fn request_path(request_line: &str) -> Option<&str> {
+    let mut parts = request_line.split_whitespace();
+    match parts.next()? {
+        "GET" | "HEAD" => {}
+        _ => return None,
+    }
+    let target = parts.next()?;
+    Some(target.split('?').next().unwrap_or(target))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_path() {
+        assert_eq!(request_path("GET /healthz HTTP/1.1"), Some("/healthz"));
+        assert_eq!(request_path("GET /readyz?verbose=1 HTTP/1.0"), Some("/readyz"));
+        assert_eq!(request_path("POST /readyz HTTP/1.1"), None);
+        assert_eq!(request_path(""), None);
+    }
+
+    #[test]
+    fn test_report_ready() {
+        let report = HealthReport::new(vec![
+            CheckResult::pass("xpra", "v6.0"),
+            CheckResult::fail("log_dir", "not writable"),
+        ]);
+        assert!(!report.ready);
+        assert!(HealthReport::new(vec![CheckResult::pass("xpra", "v6.0")]).ready);
+    }
+
+    #[tokio::test]
+    async fn test_check_version() {
+        let mut command = tokio::process::Command::new("echo");
+        command.arg("xpra v6.0");
+        let result = check_version(command, Duration::from_secs(5)).await;
+        assert!(result.ok);
+        assert_eq!(result.detail, "xpra v6.0");
+
+        let mut command = tokio::process::Command::new("sleep");
+        command.arg("30");
+        let started = Instant::now();
+        assert!(!check_version(command, Duration::from_millis(100)).await.ok);
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn test_check_log_dir() {
+        let dir = std::env::temp_dir();
+        assert!(check_log_dir(&dir).await.ok);
+        assert!(!check_log_dir(Path::new("/nonexistent/sshx-health")).await.ok);
+    }
+}
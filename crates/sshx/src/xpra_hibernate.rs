@@ -0,0 +1,155 @@
+//! Experimental hibernation of idle sessions with CRIU.
+//!
+//! Instead of terminating a long-idle session, its xpra process tree is
+//! checkpointed to disk, freeing the display and memory, and restored when the
+//! user sends input again. Requires the `criu` feature, a working `criu`
+//! binary with checkpoint/restore privileges, and a locally running display.
+
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+use tracing::{debug, warn};
+
+use crate::xpra_config::XpraConfig;
+
+/// What happens to sessions that stay idle past `idle_timeout`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IdlePolicy {
+    /// Terminate the session and free its display.
+    #[default]
+    Terminate,
+    /// Checkpoint the session to disk and restore it on the next input.
+    Hibernate,
+}
+
+// Options shared by dump and restore; they must match for a restore to work.
+const CRIU_OPTIONS: &[&str] = &["--shell-job", "--tcp-established", "--ext-unix-sk", "--file-locks"];
+
+lazy_static::lazy_static! {
+    // Whether CRIU is installed and usable, checked once on first use
+    static ref CRIU_AVAILABLE: bool = detect_criu();
+}
+
+fn detect_criu() -> bool {
+    match std::process::Command::new("criu").arg("check").output() {
+        Ok(output) if output.status.success() => true,
+        Ok(output) => {
+            warn!(
+                "CRIU check failed, idle sessions will be terminated instead of hibernated: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            false
+        }
+        Err(e) => {
+            warn!("CRIU not found, idle sessions will be terminated instead of hibernated: {}", e);
+            false
+        }
+    }
+}
+
+/// Whether `config` hibernates idle sessions rather than terminating them.
+pub fn enabled(config: &XpraConfig) -> bool {
+    cfg!(feature = "criu") && config.idle_policy == IdlePolicy::Hibernate && *CRIU_AVAILABLE
+}
+
+/// Directory `config` keeps the checkpoint images of a session in.
+pub fn image_dir(config: &XpraConfig, session_id: &str) -> PathBuf {
+    config.hibernate_dir.join(session_id)
+}
+
+/// Dump the process tree rooted at `pid` into `dir`. CRIU kills the tree once
+/// the dump succeeds.
+pub async fn checkpoint(pid: u32, dir: &Path) -> Result<()> {
+    tokio::fs::create_dir_all(dir).await?;
+    let output = Command::new("criu")
+        .arg("dump")
+        .args(["--tree", &pid.to_string()])
+        .arg("--images-dir")
+        .arg(dir)
+        .args(CRIU_OPTIONS)
+        .output()
+        .await
+        .context("failed to run criu dump");
+    let result = match output {
+        Ok(output) if output.status.success() => Ok(()),
+        Ok(output) => Err(anyhow::anyhow!("criu dump failed: {}", String::from_utf8_lossy(&output.stderr).trim())),
+        Err(e) => Err(e),
+    };
+    if result.is_err() {
+        // No partial images are left behind to be restored from
+        let _ = tokio::fs::remove_dir_all(dir).await;
+        return result;
+    }
+    debug!(pid, dir = %dir.display(), "Checkpointed Xpra process tree");
+    Ok(())
+}
+
+/// Restore a process tree from `dir`, returning the pid of its root.
+pub async fn restore(dir: &Path) -> Result<u32> {
+    let pidfile = dir.join("restored.pid");
+    let output = Command::new("criu")
+        .arg("restore")
+        .arg("--images-dir")
+        .arg(dir)
+        .args(CRIU_OPTIONS)
+        .arg("--restore-detached")
+        .arg("--pidfile")
+        .arg(&pidfile)
+        .output()
+        .await
+        .context("failed to run criu restore")?;
+    if !output.status.success() {
+        anyhow::bail!("criu restore failed: {}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+    let pid = tokio::fs::read_to_string(&pidfile)
+        .await?
+        .trim()
+        .parse()
+        .context("invalid pid written by criu restore")?;
+    debug!(pid, dir = %dir.display(), "Restored Xpra process tree");
+    Ok(pid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_idle_policy() {
+        assert_eq!(IdlePolicy::default(), IdlePolicy::Terminate);
+        assert_eq!(serde_json::to_string(&IdlePolicy::Hibernate).unwrap(), r#""hibernate""#);
+        let policy: IdlePolicy = serde_json::from_str(r#""terminate""#).unwrap();
+        assert_eq!(policy, IdlePolicy::Terminate);
+        assert!(serde_json::from_str::<IdlePolicy>(r#""suspend""#).is_err());
+
+        // Idle sessions are terminated unless hibernation is configured
+        assert!(!enabled(&XpraConfig::default()));
+    }
+
+    #[test]
+    fn test_image_dir() {
+        let config = XpraConfig { hibernate_dir: PathBuf::from("/var/lib/sshx"), ..XpraConfig::default() };
+        assert_eq!(image_dir(&config, "xpra-42"), PathBuf::from("/var/lib/sshx/xpra-42"));
+    }
+
+    #[tokio::test]
+    async fn test_failed_checkpoint_leaves_no_images() {
+        let dir = std::env::temp_dir().join(format!("sshx-hibernate-dump-{}", std::process::id()));
+
+        // No process has this pid, whether or not criu is installed
+        assert!(checkpoint(u32::MAX, &dir).await.is_err());
+        assert!(!dir.exists());
+    }
+
+    #[tokio::test]
+    async fn test_restore_without_images() {
+        let dir = std::env::temp_dir().join(format!("sshx-hibernate-restore-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        assert!(restore(&dir).await.is_err());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
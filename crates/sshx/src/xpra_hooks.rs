@@ -0,0 +1,214 @@
+//! Commands run at session lifecycle points, e.g. to mount network shares
+//! before a desktop starts and clean up scratch space after it stops.
+//!
+//! Hooks run through `sh -c` on the host running the session, with the
+//! session's metadata in `SSHX_*` environment variables.
+
+use std::time::Duration;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::time;
+use tracing::{debug, warn};
+
+use crate::xpra_hosts::WorkerHost;
+
+/// What a failing or timed out hook does to the session.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HookFailure {
+    /// Log the failure and carry on
+    #[default]
+    Warn,
+    /// Refuse to start the session; has no effect on `post_stop` hooks
+    Abort,
+}
+
+/// A command run at a lifecycle point.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HookCommand {
+    /// Shell command to run
+    pub command: String,
+
+    /// Seconds before the command is killed and counted as failed
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+
+    /// What a failure of the command does to the session
+    #[serde(default)]
+    pub on_failure: HookFailure,
+}
+
+fn default_timeout() -> u64 { 30 }
+
+/// Commands run at each lifecycle point, in order.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HooksConfig {
+    /// Run before the session's display is started
+    #[serde(default)]
+    pub pre_start: Vec<HookCommand>,
+
+    /// Run once the session's display is up
+    #[serde(default)]
+    pub post_start: Vec<HookCommand>,
+
+    /// Run after the session's display has stopped
+    #[serde(default)]
+    pub post_stop: Vec<HookCommand>,
+}
+
+/// Lifecycle point a hook runs at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookPoint {
+    /// Before the display is started
+    PreStart,
+    /// Once the display is up
+    PostStart,
+    /// After the display has stopped
+    PostStop,
+}
+
+impl HookPoint {
+    /// Name of the point, as in configuration
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HookPoint::PreStart => "pre_start",
+            HookPoint::PostStart => "post_start",
+            HookPoint::PostStop => "post_stop",
+        }
+    }
+}
+
+/// Session metadata passed to hooks.
+#[derive(Debug, Clone)]
+pub struct HookSession<'a> {
+    /// Id of the session
+    pub session_id: &'a str,
+    /// User owning the session
+    pub user: &'a str,
+    /// Not yet known before the display is started
+    pub display: Option<u16>,
+}
+
+impl HooksConfig {
+    fn commands(&self, point: HookPoint) -> &[HookCommand] {
+        match point {
+            HookPoint::PreStart => &self.pre_start,
+            HookPoint::PostStart => &self.post_start,
+            HookPoint::PostStop => &self.post_stop,
+        }
+    }
+
+    /// Run the hooks of `point` for a session on `host` in order, returning
+    /// an error when one with the abort policy fails. Post-stop hooks run
+    /// through [`HooksConfig::run_post_stop`] instead.
+    pub async fn run(&self, point: HookPoint, host: &WorkerHost, session: &HookSession<'_>) -> Result<()> {
+        for hook in self.commands(point) {
+            let Err(e) = run_hook(hook, point, host, session).await else {
+                continue;
+            };
+            if hook.on_failure == HookFailure::Abort && point != HookPoint::PostStop {
+                return Err(e.context(format!("{} hook failed", point.as_str())));
+            }
+            log_failure(hook, point, session, &e);
+        }
+        Ok(())
+    }
+
+    /// Run the post-stop hooks for a session on `host` in order. Failures
+    /// are only logged, as aborting makes no sense once the session is gone.
+    pub async fn run_post_stop(&self, host: &WorkerHost, session: &HookSession<'_>) {
+        for hook in &self.post_stop {
+            if let Err(e) = run_hook(hook, HookPoint::PostStop, host, session).await {
+                log_failure(hook, HookPoint::PostStop, session, &e);
+            }
+        }
+    }
+}
+
+fn log_failure(hook: &HookCommand, point: HookPoint, session: &HookSession<'_>, e: &anyhow::Error) {
+    warn!(
+        session_id = session.session_id,
+        hook = point.as_str(),
+        command = hook.command,
+        "Session hook failed: {:#}",
+        e
+    );
+}
+
+fn hook_env(point: HookPoint, host: &WorkerHost, session: &HookSession<'_>) -> Vec<String> {
+    let mut env = vec![
+        format!("SSHX_HOOK={}", point.as_str()),
+        format!("SSHX_SESSION_ID={}", session.session_id),
+        format!("SSHX_USER={}", session.user),
+        format!("SSHX_HOST={}", host.name),
+    ];
+    if let Some(display) = session.display {
+        env.push(format!("SSHX_DISPLAY=:{}", display));
+    }
+    env
+}
+
+async fn run_hook(hook: &HookCommand, point: HookPoint, host: &WorkerHost, session: &HookSession<'_>) -> Result<()> {
+    let mut args = hook_env(point, host, session);
+    args.extend(["sh".to_string(), "-c".to_string(), hook.command.clone()]);
+    let mut cmd = tokio::process::Command::from(host.command("env", &args));
+    cmd.kill_on_drop(true);
+
+    let timeout = Duration::from_secs(hook.timeout);
+    let output = time::timeout(timeout, cmd.output())
+        .await
+        .with_context(|| format!("timed out after {}s", hook.timeout))?
+        .context("failed to run hook")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    debug!(session_id = session.session_id, hook = point.as_str(), command = hook.command, "Ran session hook");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::xpra_config::XpraConfig;
+    use crate::xpra_pool::DisplayPool;
+
+    #[test]
+    fn test_hook_env() {
+        let host = WorkerHost::local(&XpraConfig::default(), DisplayPool::new());
+        let session = HookSession { session_id: "xpra-1", user: "alice", display: None };
+        let env = hook_env(HookPoint::PreStart, &host, &session);
+        assert!(env.contains(&"SSHX_HOOK=pre_start".to_string()));
+        assert!(env.contains(&"SSHX_SESSION_ID=xpra-1".to_string()));
+        assert!(!env.iter().any(|v| v.starts_with("SSHX_DISPLAY=")));
+
+        let session = HookSession { display: Some(100), ..session };
+        let env = hook_env(HookPoint::PostStop, &host, &session);
+        assert!(env.contains(&"SSHX_DISPLAY=:100".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_failure_policy() {
+        let host = WorkerHost::local(&XpraConfig::default(), DisplayPool::new());
+        let session = HookSession { session_id: "xpra-1", user: "alice", display: Some(100) };
+        let hook = |command: &str, on_failure| HookCommand {
+            command: command.to_string(),
+            timeout: 5,
+            on_failure,
+        };
+        let hooks = HooksConfig {
+            pre_start: vec![hook("exit 1", HookFailure::Warn), hook("true", HookFailure::Abort)],
+            post_start: vec![hook("exit 1", HookFailure::Abort)],
+            post_stop: vec![hook("exit 1", HookFailure::Abort)],
+        };
+
+        assert!(hooks.run(HookPoint::PreStart, &host, &session).await.is_ok());
+        assert!(hooks.run(HookPoint::PostStart, &host, &session).await.is_err());
+        // Aborting makes no sense once the session is gone
+        assert!(hooks.run(HookPoint::PostStop, &host, &session).await.is_ok());
+        hooks.run_post_stop(&host, &session).await;
+    }
+}
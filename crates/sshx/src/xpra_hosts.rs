@@ -0,0 +1,550 @@
+//! The hosts desktops run on, this one or workers reached over SSH.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::process::Command;
+use tokio::sync::Mutex;
+use tokio::time;
+use tracing::{debug, info, warn};
+
+use crate::xpra_affinity::CpuPool;
+use crate::xpra_capabilities::XpraCapabilities;
+use crate::xpra_config::XpraConfig;
+use crate::xpra_context::XpraContext;
+use crate::xpra_gpu::{GpuPool, GpuUsage};
+use crate::xpra_placement::{Candidate, PlacementStrategy};
+use crate::xpra_pool::DisplayPool;
+use crate::xpra_rejection::{RejectionCode, SessionRejection};
+use crate::xpra_tls::TlsConfig;
+
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long the status of the hosts is reused for admission decisions.
+const STATUS_CACHE_TTL: Duration = Duration::from_secs(2);
+
+/// Worker host that can run desktop sessions.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HostConfig {
+    /// Unique name of the host, used in status output and logs
+    pub name: String,
+
+    /// SSH destination (`user@host`) used to launch xpra on the worker
+    pub ssh_target: String,
+
+    /// Address the forwarder connects to for the worker's websockets; only
+    /// needed when `bind_address` is not a loopback address
+    #[serde(default)]
+    pub address: String,
+
+    /// Address xpra binds its websockets to on the worker. On a loopback
+    /// address they are reached through a forward of the SSH connection
+    /// xpra runs in; any other address needs `tls` or `socket_auth`.
+    #[serde(default = "default_bind_address")]
+    pub bind_address: String,
+
+    /// Maximum concurrent sessions on this host (0 = limited by display range)
+    #[serde(default)]
+    pub max_sessions: u32,
+
+    /// Minimum display number to allocate on this host
+    #[serde(default = "default_min_display")]
+    pub min_display: u16,
+
+    /// Maximum display number to allocate on this host
+    #[serde(default = "default_max_display")]
+    pub max_display: u16,
+
+    /// Base port for websocket connections on this host
+    #[serde(default = "default_base_port")]
+    pub base_port: u16,
+
+    /// GPU devices on this host; the `gpu` settings apply to them
+    #[serde(default)]
+    pub gpus: Vec<String>,
+
+    /// Certificates to reach the host's websockets over mutual TLS with;
+    /// plain websockets if unset
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+}
+
+fn default_bind_address() -> String { "127.0.0.1".to_string() }
+fn default_min_display() -> u16 { 100 }
+fn default_max_display() -> u16 { 599 }
+fn default_base_port() -> u16 { 14500 }
+
+/// How xpra is launched on a host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostKind {
+    /// Xpra runs as a child of this process.
+    Local,
+    /// Xpra runs on a worker reached over SSH.
+    Ssh {
+        /// Destination given to `ssh`, e.g. `user@worker1`
+        target: String,
+    },
+}
+
+/// A host in the pool, with its own display pool and health state.
+#[derive(Debug)]
+pub struct WorkerHost {
+    /// Name of the host, as in configuration
+    pub name: String,
+    /// How xpra is launched on the host
+    pub kind: HostKind,
+    /// Address the forwarder connects to, unless tunnelled
+    pub address: String,
+    /// Address xpra binds to on the host
+    pub bind_address: String,
+    /// Display numbers in use on the host
+    pub pool: DisplayPool,
+    /// GPUs of the host
+    pub gpus: GpuPool,
+    /// CPU sets of the host
+    pub cpus: CpuPool,
+    /// Certificates of the TLS to the host's websockets, if used
+    pub tls: Option<TlsConfig>,
+    max_sessions: u32,
+    min_display: u16,
+    max_display: u16,
+    base_port: u16,
+    healthy: AtomicBool,
+    /// Detected on first use, and again after the host recovers
+    capabilities: Mutex<Option<XpraCapabilities>>,
+}
+
+impl WorkerHost {
+    /// The host running this process with the settings of `config`, taking
+    /// display numbers from `pool`.
+    pub fn local(config: &XpraConfig, pool: DisplayPool) -> Self {
+        Self {
+            name: "local".to_string(),
+            kind: HostKind::Local,
+            address: "127.0.0.1".to_string(),
+            bind_address: "127.0.0.1".to_string(),
+            pool,
+            gpus: GpuPool::new(config.gpu.devices.clone(), config.gpu.sessions_per_gpu),
+            cpus: CpuPool::new(&config.cpu_affinity),
+            tls: None,
+            max_sessions: 0,
+            min_display: config.min_display,
+            max_display: config.max_display,
+            base_port: config.base_port,
+            healthy: AtomicBool::new(true),
+            capabilities: Mutex::new(None),
+        }
+    }
+
+    /// A worker reached over SSH, with the pool settings of `xpra`.
+    pub fn remote(config: &HostConfig, xpra: &XpraConfig) -> Self {
+        Self {
+            name: config.name.clone(),
+            kind: HostKind::Ssh { target: config.ssh_target.clone() },
+            address: config.address.clone(),
+            bind_address: config.bind_address.clone(),
+            pool: DisplayPool::with_range(config.min_display, config.max_display),
+            gpus: GpuPool::new(config.gpus.clone(), xpra.gpu.sessions_per_gpu),
+            cpus: CpuPool::new(&xpra.cpu_affinity),
+            tls: config.tls.clone(),
+            max_sessions: config.max_sessions,
+            min_display: config.min_display,
+            max_display: config.max_display,
+            base_port: config.base_port,
+            healthy: AtomicBool::new(true),
+            capabilities: Mutex::new(None),
+        }
+    }
+
+    /// WebSocket port of a display on this host
+    pub fn websocket_port(&self, display: u16) -> u16 {
+        self.base_port + (display - self.min_display)
+    }
+
+    /// Maximum number of concurrent sessions on this host.
+    pub fn capacity(&self) -> usize {
+        let displays = (self.max_display - self.min_display) as usize + 1;
+        match self.max_sessions {
+            0 => displays,
+            max => displays.min(max as usize),
+        }
+    }
+
+    /// Number of displays in use on this host
+    pub async fn active_sessions(&self) -> usize {
+        self.pool.allocated_count().await
+    }
+
+    /// Whether the last health check of the host passed
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    /// Features of the xpra installed on this host.
+    pub async fn capabilities(&self) -> XpraCapabilities {
+        let mut cached = self.capabilities.lock().await;
+        if let Some(capabilities) = &*cached {
+            return capabilities.clone();
+        }
+        match XpraCapabilities::detect(self).await {
+            Ok(capabilities) => {
+                info!(host = self.name, version = %capabilities.version, "Detected xpra");
+                *cached = Some(capabilities.clone());
+                capabilities
+            }
+            Err(e) => {
+                // Not cached, so the next session tries again
+                warn!(host = self.name, "Failed to detect xpra version, assuming a current release: {}", e);
+                XpraCapabilities::default()
+            }
+        }
+    }
+
+    /// Build a command that runs `program` on this host.
+    pub fn command(&self, program: &str, args: &[String]) -> Command {
+        self.command_forwarding(program, args, &[], true)
+    }
+
+    /// Whether the host's account runs one display at most: a worker reached
+    /// over SSH with room for a single session. No other desktop runs as
+    /// the account that could reach the files and sockets of its display.
+    pub fn is_dedicated(&self) -> bool {
+        matches!(self.kind, HostKind::Ssh { .. }) && self.capacity() == 1
+    }
+
+    /// `port` at the address xpra binds to, as a forward names it.
+    pub fn bind_target(&self, port: u16) -> String {
+        match self.bind_address.contains(':') {
+            true => format!("[{}]:{port}", self.bind_address),
+            false => format!("{}:{port}", self.bind_address),
+        }
+    }
+
+    /// Whether xpra on this host binds to loopback, so its websockets are
+    /// reached through the SSH connection it runs in.
+    pub fn is_tunnelled(&self) -> bool {
+        matches!(self.kind, HostKind::Ssh { .. })
+            && self.bind_address.parse::<IpAddr>().is_ok_and(|address| address.is_loopback())
+    }
+
+    /// Build a command that runs `program` on this host, forwarding each
+    /// `(local_port, target)` from this host's loopback to the worker's
+    /// `host:port` or Unix socket. The command fails if a forward cannot be
+    /// set up. Without `tty`, `program` must end itself once its standard
+    /// input closes.
+    pub fn command_forwarding(
+        &self,
+        program: &str,
+        args: &[String],
+        forwards: &[(u16, String)],
+        tty: bool,
+    ) -> Command {
+        match &self.kind {
+            HostKind::Local => {
+                let mut cmd = Command::new(program);
+                cmd.args(args);
+                cmd
+            }
+            HostKind::Ssh { target } => {
+                let mut cmd = Command::new("ssh");
+                // A forced tty makes the remote process receive SIGHUP when
+                // the local ssh client is killed.
+                cmd.args([if tty { "-tt" } else { "-T" }, "-o", "BatchMode=yes"]);
+                if !forwards.is_empty() {
+                    cmd.args(["-o", "ExitOnForwardFailure=yes"]);
+                }
+                for (local_port, target) in forwards {
+                    cmd.arg("-L").arg(format!("127.0.0.1:{local_port}:{target}"));
+                }
+                cmd.args(["--", target]);
+                cmd.arg(shell_quote(program));
+                cmd.args(args.iter().map(|a| shell_quote(a)));
+                cmd
+            }
+        }
+    }
+
+    /// Check that xpra can be run on this host.
+    pub async fn check_health(&self) -> bool {
+        let mut cmd = tokio::process::Command::from(self.command("xpra", &["--version".to_string()]));
+        cmd.kill_on_drop(true);
+        let healthy = match time::timeout(HEALTH_CHECK_TIMEOUT, cmd.output()).await {
+            Ok(Ok(output)) => output.status.success(),
+            Ok(Err(e)) => {
+                debug!(host = self.name, "Health check failed to run: {}", e);
+                false
+            }
+            Err(_) => false,
+        };
+
+        let was_healthy = self.healthy.swap(healthy, Ordering::Relaxed);
+        if was_healthy && !healthy {
+            warn!(host = self.name, "Worker host became unhealthy");
+        } else if !was_healthy && healthy {
+            info!(host = self.name, "Worker host recovered");
+            // xpra may have been upgraded while the host was down
+            *self.capabilities.lock().await = None;
+        }
+        healthy
+    }
+}
+
+/// Quote an argument for the remote shell that ssh runs commands through.
+pub(crate) fn shell_quote(arg: &str) -> String {
+    if !arg.is_empty()
+        && arg.chars().all(|c| c.is_ascii_alphanumeric() || "-_./:=,@%+".contains(c))
+    {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+/// Host-level view for status output.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HostStatus {
+    /// Name of the host
+    pub name: String,
+    /// Address the forwarder connects to
+    pub address: String,
+    /// Whether the last health check passed
+    pub healthy: bool,
+    /// Displays in use
+    pub active_sessions: usize,
+    /// Most sessions the host takes
+    pub capacity: usize,
+    /// Sessions using each GPU
+    pub gpus: Vec<GpuUsage>,
+}
+
+/// Set of hosts that desktop sessions can be placed on.
+#[derive(Debug, Clone, Default)]
+pub struct HostPool {
+    hosts: Vec<Arc<WorkerHost>>,
+    /// Last status of every host and when it was taken
+    status_cache: Arc<Mutex<Option<CachedStatus>>>,
+}
+
+/// Status of every host taken at some instant.
+type CachedStatus = (Instant, Vec<HostStatus>);
+
+impl HostPool {
+    /// Build the pool from the workers of `config`, falling back to the local
+    /// host, taking display numbers from `local_pool`, when none are
+    /// configured.
+    pub fn new(config: &XpraConfig, local_pool: DisplayPool) -> Self {
+        let hosts = if config.hosts.is_empty() {
+            vec![Arc::new(WorkerHost::local(config, local_pool))]
+        } else {
+            config.hosts.iter().map(|c| Arc::new(WorkerHost::remote(c, config))).collect()
+        };
+        Self { hosts, status_cache: Arc::default() }
+    }
+
+    /// The host or Mac `name`.
+    pub fn get(&self, name: &str) -> Option<Arc<WorkerHost>> {
+        self.hosts.iter().find(|h| h.name == name).cloned()
+    }
+
+    /// Every host of the pool, the local one first
+    pub fn hosts(&self) -> &[Arc<WorkerHost>] {
+        &self.hosts
+    }
+
+    /// Choose a healthy host with free capacity for a new session using the
+    /// placement strategy of `context`, counting the user's sessions there.
+    pub async fn place(&self, context: &XpraContext, user: &str) -> Result<Arc<WorkerHost>> {
+        let user_hosts = context.monitor.get_user_hosts(user).await;
+        self.place_with(context.config.placement_strategy, user, &user_hosts).await
+    }
+
+    async fn place_with(
+        &self,
+        strategy: PlacementStrategy,
+        user: &str,
+        user_hosts: &HashMap<String, usize>,
+    ) -> Result<Arc<WorkerHost>> {
+        let mut candidates = Vec::with_capacity(self.hosts.len());
+        for host in &self.hosts {
+            if !host.is_healthy() {
+                debug!(host = host.name, "Skipping unhealthy host for placement");
+                continue;
+            }
+            let active = host.active_sessions().await;
+            let capacity = host.capacity();
+            if active >= capacity {
+                debug!(host = host.name, active, capacity, "Skipping full host for placement");
+                continue;
+            }
+            candidates.push(Candidate {
+                host: host.clone(),
+                active,
+                capacity,
+                user_sessions: user_hosts.get(&host.name).copied().unwrap_or(0),
+            });
+        }
+
+        match strategy.choose(&candidates) {
+            Some(chosen) => {
+                debug!(
+                    user,
+                    ?strategy,
+                    host = chosen.host.name,
+                    active = chosen.active,
+                    capacity = chosen.capacity,
+                    user_sessions = chosen.user_sessions,
+                    candidates = candidates.len(),
+                    "Placed new Xpra session"
+                );
+                Ok(chosen.host.clone())
+            }
+            None => Err(SessionRejection::new(
+                RejectionCode::Capacity,
+                "No healthy desktop host has free capacity",
+            )
+            .into()),
+        }
+    }
+
+    /// Periodically check the health of remote hosts in the background.
+    pub fn start_health_checks(&self) {
+        let hosts: Vec<_> = self.hosts.iter()
+            .filter(|h| h.kind != HostKind::Local)
+            .cloned()
+            .collect();
+        if hosts.is_empty() {
+            return;
+        }
+        tokio::spawn(async move {
+            let mut interval = time::interval(HEALTH_CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                for host in &hosts {
+                    host.check_health().await;
+                }
+            }
+        });
+    }
+
+    /// Status of every host
+    pub async fn status(&self) -> Vec<HostStatus> {
+        let mut status = Vec::with_capacity(self.hosts.len());
+        for host in &self.hosts {
+            status.push(HostStatus {
+                name: host.name.clone(),
+                address: host.address.clone(),
+                healthy: host.is_healthy(),
+                active_sessions: host.active_sessions().await,
+                capacity: host.capacity(),
+                gpus: host.gpus.usage().await,
+            });
+        }
+        status
+    }
+
+    /// Status of every host, taken at most two seconds ago, for decisions
+    /// made on every session start.
+    pub async fn cached_status(&self) -> Vec<HostStatus> {
+        let mut cached = self.status_cache.lock().await;
+        if let Some((taken, status)) = &*cached {
+            if taken.elapsed() < STATUS_CACHE_TTL {
+                return status.clone();
+            }
+        }
+        let status = self.status().await;
+        *cached = Some((Instant::now(), status.clone()));
+        status
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote(":100"), ":100");
+        assert_eq!(shell_quote("--bind-ws=0.0.0.0:14500"), "--bind-ws=0.0.0.0:14500");
+        assert_eq!(shell_quote("xterm; rm -rf /"), "'xterm; rm -rf /'");
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+    }
+
+    #[tokio::test]
+    async fn test_cached_status() {
+        let pool = DisplayPool::new();
+        let hosts = HostPool::new(&XpraConfig::default(), pool.clone());
+        assert_eq!(hosts.cached_status().await[0].active_sessions, 0);
+
+        // Starts right after one another share the status
+        let _lease = pool.allocate().await.unwrap();
+        assert_eq!(hosts.cached_status().await[0].active_sessions, 0);
+        assert_eq!(hosts.status().await[0].active_sessions, 1);
+    }
+
+    #[tokio::test]
+    async fn test_place_least_loaded() {
+        let config = |name: &str, max_display| HostConfig {
+            name: name.to_string(),
+            ssh_target: name.to_string(),
+            address: name.to_string(),
+            bind_address: default_bind_address(),
+            max_sessions: 0,
+            min_display: 100,
+            max_display,
+            base_port: 14500,
+            gpus: Vec::new(),
+            tls: None,
+        };
+        let xpra = XpraConfig {
+            hosts: vec![config("a", 101), config("b", 103)],
+            ..XpraConfig::default()
+        };
+        let pool = HostPool::new(&xpra, DisplayPool::new());
+
+        let strategy = PlacementStrategy::LeastLoaded;
+        let no_sessions = HashMap::new();
+
+        let a = pool.get("a").unwrap();
+        let _first = a.pool.allocate().await.unwrap();
+
+        assert_eq!(pool.place_with(strategy, "alice", &no_sessions).await.unwrap().name, "b");
+        let _second = a.pool.allocate().await.unwrap();
+        assert_eq!(pool.place_with(strategy, "alice", &no_sessions).await.unwrap().name, "b");
+
+        // Full hosts are skipped even when the user has sessions there
+        let affinity = HashMap::from([("a".to_string(), 2)]);
+        let host = pool.place_with(PlacementStrategy::UserAffinity, "alice", &affinity).await;
+        assert_eq!(host.unwrap().name, "b");
+    }
+
+    #[test]
+    fn test_tunnel() {
+        let config: HostConfig = serde_json::from_str(r#"{"name": "a", "ssh_target": "xpra@a"}"#).unwrap();
+        let host = WorkerHost::remote(&config, &XpraConfig::default());
+        assert!(host.is_tunnelled());
+        let cmd = host.command_forwarding("xpra", &["start".to_string()], &[(40001, host.bind_target(14500))], true);
+        let args: Vec<_> = cmd.get_args().map(|a| a.to_str().unwrap()).collect();
+        assert_eq!(
+            args,
+            ["-tt", "-o", "BatchMode=yes", "-o", "ExitOnForwardFailure=yes", "-L", "127.0.0.1:40001:127.0.0.1:14500", "--", "xpra@a", "xpra", "start"]
+        );
+        assert!(XpraConfig { hosts: vec![config.clone()], ..XpraConfig::default() }.validate().is_ok());
+        // Only a host running a single display has an account to itself
+        assert!(!host.is_dedicated());
+        let single = HostConfig { max_sessions: 1, ..config.clone() };
+        assert!(WorkerHost::remote(&single, &XpraConfig::default()).is_dedicated());
+
+        // Other addresses are reached directly, and only with a credential
+        let exposed = HostConfig { address: "10.0.0.11".to_string(), bind_address: "0.0.0.0".to_string(), ..config };
+        assert!(!WorkerHost::remote(&exposed, &XpraConfig::default()).is_tunnelled());
+        let err = XpraConfig { hosts: vec![exposed.clone()], ..XpraConfig::default() }.validate().unwrap_err();
+        assert!(err.to_string().contains("without tls or socket_auth"));
+        let authenticated = XpraConfig { hosts: vec![exposed], socket_auth: true, ..XpraConfig::default() };
+        assert!(authenticated.validate().is_ok());
+    }
+}
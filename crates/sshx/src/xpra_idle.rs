@@ -0,0 +1,67 @@
+//! Signals deciding whether a session is idle.
+//!
+//! By default a session is idle when no input came through its channel for
+//! `idle_timeout`. Channel traffic is a poor proxy though: xpra clients keep
+//! acknowledging frames while a video plays, so such a session never idles
+//! out. With `idle_source` set to `x_input`, the monitor instead asks each
+//! display's X server how long ago it saw keyboard or pointer input, through
+//! the screensaver extension, and falls back to channel traffic for displays
+//! it cannot ask.
+
+use std::time::Duration;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::xpra_hosts::WorkerHost;
+
+/// Program printing the X server's idle time in milliseconds.
+pub const IDLE_PROGRAM: &str = "xprintidle";
+
+/// Signal driving the idle policy.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IdleSource {
+    /// Input forwarded through the session's channel
+    #[default]
+    Channel,
+    /// Keyboard and pointer input seen by the display's X server
+    XInput,
+}
+
+/// How long ago the X server of a display saw input.
+pub async fn input_idle_time(host: &WorkerHost, display: u16) -> Result<Duration> {
+    let args = [format!("DISPLAY=:{}", display), IDLE_PROGRAM.to_string()];
+    let output = tokio::process::Command::from(host.command("env", &args))
+        .kill_on_drop(true)
+        .output()
+        .await
+        .with_context(|| format!("failed to run {}", IDLE_PROGRAM))?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "{} exited with {}: {}",
+            IDLE_PROGRAM,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    parse_idle_time(&String::from_utf8_lossy(&output.stdout))
+}
+
+fn parse_idle_time(output: &str) -> Result<Duration> {
+    let millis = output
+        .trim()
+        .parse()
+        .with_context(|| format!("unexpected {} output {:?}", IDLE_PROGRAM, output.trim()))?;
+    Ok(Duration::from_millis(millis))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_idle_time() {
+        assert_eq!(parse_idle_time("61234\n").unwrap(), Duration::from_millis(61234));
+        assert!(parse_idle_time("couldn't open display\n").is_err());
+    }
+}
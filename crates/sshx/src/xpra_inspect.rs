@@ -0,0 +1,365 @@
+//! Full machine-readable view of a single session, assembled from the
+//! session monitor, its traffic counters, the event history and the xpra
+//! server running its display.
+
+use std::collections::BTreeMap;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::xpra::XpraDisplay;
+use crate::xpra_children::ChildStatus;
+use crate::xpra_config::XpraConfig;
+use crate::xpra_context::XpraContext;
+use crate::xpra_hibernate::IdlePolicy;
+use crate::xpra_hosts::WorkerHost;
+use crate::xpra_idle::IdleSource;
+use crate::xpra_labels::Labels;
+use crate::xpra_logger::SessionEvent;
+use crate::xpra_netns::SessionNetwork;
+use crate::xpra_priority::{ProcessPriority, SessionClass};
+use crate::xpra_sandbox::SandboxProfile;
+use crate::xpra_session_id::ParentShell;
+use crate::xpra_session_state::{SessionState, StateTransition};
+use crate::xpra_watchdog::DisplayHealth;
+use crate::xpra_xorg::XBackend;
+
+/// Number of history events included in an inspection.
+const RECENT_EVENTS: usize = 20;
+
+/// Settings a session was created with, as reported when inspecting it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConfigSnapshot {
+    /// Window manager or program the display was started with
+    pub window_manager: String,
+    /// Session class of the desktop
+    pub class: SessionClass,
+    /// CPU, IO and OOM priorities of the desktop's processes
+    pub priority: ProcessPriority,
+    /// Virtual X server of the display
+    pub x_backend: XBackend,
+    /// Largest resolution of the display, e.g. `3840x2160`
+    pub max_resolution: String,
+    /// Idle timeout in seconds, 0 if disabled
+    pub idle_timeout: u64,
+    /// What happens to the session once idle
+    pub idle_policy: IdlePolicy,
+    /// Signal deciding that the session is idle
+    #[serde(default)]
+    pub idle_source: IdleSource,
+    /// Network namespace and egress rules of an isolated desktop
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub network: Option<SessionNetwork>,
+    /// Sandbox profile of the xpra process tree
+    #[serde(default)]
+    pub sandbox: SandboxProfile,
+}
+
+impl ConfigSnapshot {
+    /// Capture the settings in effect for a newly started display.
+    pub fn capture(display: &XpraDisplay, config: &XpraConfig) -> Self {
+        Self {
+            window_manager: display.wm().to_string(),
+            class: display.class(),
+            priority: config.priority.for_class(display.class()).clone(),
+            x_backend: config.x_backend,
+            max_resolution: config.max_resolution.clone(),
+            idle_timeout: config.idle_timeout,
+            idle_policy: config.idle_policy,
+            idle_source: config.idle_source,
+            network: display.network().cloned(),
+            sandbox: config.sandbox.profile,
+        }
+    }
+}
+
+/// Bytes forwarded between the client and xpra.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrafficCounters {
+    /// Bytes from the client to xpra
+    pub bytes_in: u64,
+    /// Bytes from xpra to the client
+    pub bytes_out: u64,
+}
+
+/// Resources used by the xpra server and the processes it started.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ResourceUsage {
+    /// Number of processes
+    pub processes: usize,
+    /// Resident memory of the processes, in KiB
+    pub rss_kb: u64,
+    /// CPU use of the processes, 100 for one full core
+    pub cpu_percent: f64,
+}
+
+/// The xpra server behind the session's display.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct XpraProcessInfo {
+    /// Server pid on the session's host
+    pub pid: Option<u32>,
+    /// `server.*` entries of `xpra info`, without the prefix
+    pub server: BTreeMap<String, String>,
+}
+
+/// Everything known about one session.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SessionInspection {
+    /// Id of the session
+    pub session_id: String,
+    /// Name the user gave the session
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// What the user said the session is for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// User owning the session
+    pub user: String,
+    /// Host running the display
+    pub host: String,
+    /// Display number of the session
+    pub display: u16,
+    /// When the session was started
+    pub created_at: DateTime<Utc>,
+    /// State of the session
+    pub state: SessionState,
+    /// Every state the session entered, oldest first
+    pub transitions: Vec<StateTransition>,
+    /// Seconds since input or output was last seen
+    pub idle_seconds: u64,
+    /// Whether the session is hibernated
+    pub hibernated: bool,
+    /// Whether the session was launched for automation
+    pub headless: bool,
+    /// sshx session and shell the session was started from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent: Option<ParentShell>,
+    /// What the watchdog last found on the display
+    #[serde(default)]
+    pub health: DisplayHealth,
+    /// Times the watchdog restarted the window manager
+    #[serde(default)]
+    pub wm_restarts: u32,
+    /// Programs started on the desktop next to the window manager
+    #[serde(default)]
+    pub children: Vec<ChildStatus>,
+    /// GPU device assigned to the session
+    pub gpu: Option<String>,
+    /// CPUs the session is pinned to
+    pub cpus: Option<String>,
+    /// Labels attached to the session
+    pub labels: Labels,
+    /// Settings the session was created with
+    pub config: ConfigSnapshot,
+    /// Bytes forwarded for the session
+    pub traffic: TrafficCounters,
+    /// `None` while hibernated or when the host could not be queried
+    pub resources: Option<ResourceUsage>,
+    /// `client.N.*` entries of `xpra info`, one map per attached client
+    pub clients: Vec<BTreeMap<String, String>>,
+    /// The xpra server, for xpra displays
+    pub xpra: Option<XpraProcessInfo>,
+    /// Latest history events of the session, oldest first
+    pub recent_events: Vec<SessionEvent>,
+    /// Why the xpra server could not be queried, if it could not
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub xpra_error: Option<String>,
+}
+
+/// Inspect a session running on the host of `context`.
+pub async fn inspect(context: &XpraContext, session_id: &str) -> Result<SessionInspection> {
+    let session = context
+        .monitor
+        .get_session(session_id)
+        .await
+        .with_context(|| format!("no session {}", session_id))?;
+    let (bytes_in, bytes_out) = session.traffic.totals();
+
+    let mut inspection = SessionInspection {
+        session_id: session_id.to_string(),
+        name: session.name.clone(),
+        description: session.description.clone(),
+        user: session.user.clone(),
+        host: session.host.clone(),
+        display: session.display,
+        created_at: session.created_at,
+        state: session.lifecycle.state(),
+        transitions: session.lifecycle.transitions().to_vec(),
+        idle_seconds: session.last_activity.elapsed().as_secs(),
+        hibernated: session.hibernated,
+        headless: session.headless,
+        parent: session.parent.clone(),
+        health: session.health,
+        wm_restarts: session.wm_restarts,
+        children: session.children.statuses(),
+        gpu: session.gpu.clone(),
+        cpus: session.cpus.clone(),
+        labels: session.labels.clone(),
+        config: session.config.clone(),
+        traffic: TrafficCounters { bytes_in, bytes_out },
+        resources: None,
+        clients: Vec::new(),
+        xpra: None,
+        recent_events: recent_events(context, session_id).await,
+        xpra_error: None,
+    };
+
+    // A hibernated session has no xpra server to ask
+    if session.hibernated {
+        return Ok(inspection);
+    }
+    let Some(host) = context.hosts.get(&session.host) else {
+        inspection.xpra_error = Some(format!("unknown desktop host {}", session.host));
+        return Ok(inspection);
+    };
+    match xpra_info(&host, session.display).await {
+        Ok(info) => {
+            inspection.clients = parse_clients(&info);
+            let xpra = parse_server(&info);
+            if let Some(pid) = xpra.pid {
+                match process_table(&host).await {
+                    Ok(table) => inspection.resources = tree_usage(&table, pid),
+                    Err(e) => debug!(session_id, "Failed to read process table: {:#}", e),
+                }
+            }
+            inspection.xpra = Some(xpra);
+        }
+        Err(e) => inspection.xpra_error = Some(format!("{e:#}")),
+    }
+    Ok(inspection)
+}
+
+/// Latest events of a session from the history log.
+async fn recent_events(context: &XpraContext, session_id: &str) -> Vec<SessionEvent> {
+    match context.logger.recent_history(session_id, RECENT_EVENTS).await {
+        Ok(events) => events,
+        Err(e) => {
+            debug!(session_id, "Failed to read session history: {:#}", e);
+            Vec::new()
+        }
+    }
+}
+
+/// Run `xpra info` for a display, returning its `key=value` lines.
+pub(crate) async fn xpra_info(host: &WorkerHost, display: u16) -> Result<BTreeMap<String, String>> {
+    let args = ["info".to_string(), format!(":{}", display)];
+    let output = tokio::process::Command::from(host.command("xpra", &args))
+        .output()
+        .await
+        .context("failed to run xpra info")?;
+    if !output.status.success() {
+        anyhow::bail!("xpra info :{} exited with {}", display, output.status);
+    }
+    Ok(parse_info(&String::from_utf8_lossy(&output.stdout)))
+}
+
+fn parse_info(output: &str) -> BTreeMap<String, String> {
+    output
+        .lines()
+        .filter_map(|line| line.trim().split_once('='))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+pub(crate) fn parse_server(info: &BTreeMap<String, String>) -> XpraProcessInfo {
+    let server: BTreeMap<String, String> = info
+        .iter()
+        .filter_map(|(key, value)| Some((key.strip_prefix("server.")?.to_string(), value.clone())))
+        .collect();
+    XpraProcessInfo {
+        pid: server.get("pid").and_then(|pid| pid.parse().ok()),
+        server,
+    }
+}
+
+fn parse_clients(info: &BTreeMap<String, String>) -> Vec<BTreeMap<String, String>> {
+    let mut clients: BTreeMap<u32, BTreeMap<String, String>> = BTreeMap::new();
+    for (key, value) in info {
+        let Some((index, field)) = key.strip_prefix("client.").and_then(|rest| rest.split_once('.')) else {
+            continue;
+        };
+        if let Ok(index) = index.parse() {
+            clients.entry(index).or_default().insert(field.to_string(), value.clone());
+        }
+    }
+    clients.into_values().collect()
+}
+
+/// List `pid ppid rss pcpu` of every process on the host.
+async fn process_table(host: &WorkerHost) -> Result<String> {
+    let args = ["-eo".to_string(), "pid=,ppid=,rss=,pcpu=".to_string()];
+    let output = tokio::process::Command::from(host.command("ps", &args))
+        .output()
+        .await
+        .context("failed to run ps")?;
+    if !output.status.success() {
+        anyhow::bail!("ps exited with {}", output.status);
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Sum the usage of `root` and all its descendants in `ps` output.
+fn tree_usage(table: &str, root: u32) -> Option<ResourceUsage> {
+    let rows: Vec<(u32, u32, u64, f64)> = table
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            Some((
+                fields.next()?.parse().ok()?,
+                fields.next()?.parse().ok()?,
+                fields.next()?.parse().ok()?,
+                fields.next()?.parse().ok()?,
+            ))
+        })
+        .collect();
+    if !rows.iter().any(|(pid, ..)| *pid == root) {
+        return None;
+    }
+
+    let mut tree = vec![root];
+    let mut i = 0;
+    while i < tree.len() {
+        let parent = tree[i];
+        tree.extend(rows.iter().filter(|(_, ppid, ..)| *ppid == parent).map(|(pid, ..)| *pid));
+        i += 1;
+    }
+
+    let mut usage = ResourceUsage { processes: 0, rss_kb: 0, cpu_percent: 0.0 };
+    for (_, _, rss, cpu) in rows.iter().filter(|(pid, ..)| tree.contains(pid)) {
+        usage.processes += 1;
+        usage.rss_kb += rss;
+        usage.cpu_percent += cpu;
+    }
+    Some(usage)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tree_usage() {
+        let table = "    1     0  1000  0.1\n  200     1  5000  2.5\n  201   200  3000  1.0\n  202   201   500  0.5\n  300     1  9999  9.0\n";
+        assert_eq!(
+            tree_usage(table, 200),
+            Some(ResourceUsage { processes: 3, rss_kb: 8500, cpu_percent: 4.0 })
+        );
+        assert_eq!(tree_usage(table, 400), None);
+    }
+
+    #[test]
+    fn test_parse_info() {
+        let info = parse_info(
+            "server.pid=4242\nserver.version=6.1\nclient.0.hostname=laptop\nclient.0.platform=linux\nclient.1.hostname=tablet\nclients=2\n",
+        );
+        let server = parse_server(&info);
+        assert_eq!(server.pid, Some(4242));
+        assert_eq!(server.server.get("version").map(String::as_str), Some("6.1"));
+
+        let clients = parse_clients(&info);
+        assert_eq!(clients.len(), 2);
+        assert_eq!(clients[0].get("platform").map(String::as_str), Some("linux"));
+        assert_eq!(clients[1].get("hostname").map(String::as_str), Some("tablet"));
+    }
+}
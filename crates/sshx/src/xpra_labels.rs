@@ -0,0 +1,84 @@
+//! Free-form key/value labels attached to sessions at creation, e.g.
+//! `project=alpha`, used to filter status output and log analysis.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use anyhow::Result;
+
+/// Labels of a session, sorted by key.
+pub type Labels = BTreeMap<String, String>;
+
+/// A single `key=value` label, as given on the command line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Label {
+    /// Key of the label
+    pub key: String,
+    /// Value of the label
+    pub value: String,
+}
+
+impl FromStr for Label {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let Some((key, value)) = s.split_once('=') else {
+            anyhow::bail!("invalid label {s:?}, expected KEY=VALUE");
+        };
+        let key = key.trim();
+        if key.is_empty() {
+            anyhow::bail!("invalid label {s:?}, the key is empty");
+        }
+        Ok(Self { key: key.to_string(), value: value.trim().to_string() })
+    }
+}
+
+impl fmt::Display for Label {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.key, self.value)
+    }
+}
+
+/// Collect labels into a map; later labels override earlier ones with the
+/// same key.
+pub fn to_labels(labels: &[Label]) -> Labels {
+    labels.iter().map(|l| (l.key.clone(), l.value.clone())).collect()
+}
+
+/// Whether `labels` contains every label in `filter`.
+pub fn matches(labels: &Labels, filter: &[Label]) -> bool {
+    filter.iter().all(|l| labels.get(&l.key) == Some(&l.value))
+}
+
+/// Labels as `key=value` pairs separated by commas.
+pub fn format_labels(labels: &Labels) -> String {
+    labels
+        .iter()
+        .map(|(key, value)| format!("{key}={value}"))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_label() {
+        let label: Label = "project=alpha".parse().unwrap();
+        assert_eq!(label, Label { key: "project".into(), value: "alpha".into() });
+        assert_eq!("note=a=b".parse::<Label>().unwrap().value, "a=b");
+        assert!("project".parse::<Label>().is_err());
+        assert!("=alpha".parse::<Label>().is_err());
+    }
+
+    #[test]
+    fn test_matches() {
+        let labels = to_labels(&["project=alpha".parse().unwrap(), "purpose=demo".parse().unwrap()]);
+        assert!(matches(&labels, &[]));
+        assert!(matches(&labels, &["project=alpha".parse().unwrap()]));
+        assert!(!matches(&labels, &["project=beta".parse().unwrap()]));
+        assert!(!matches(&labels, &["owner=alice".parse().unwrap()]));
+        assert_eq!(format_labels(&labels), "project=alpha,purpose=demo");
+    }
+}
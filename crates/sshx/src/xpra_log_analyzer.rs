@@ -1,55 +1,412 @@
-use std::collections::HashMap;
-use std::path::PathBuf;
-use chrono::{DateTime, Duration, Utc};
+//! Statistics over a period of the session history log.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use chrono::{DateTime, Duration, DurationRound, Timelike, Utc};
 use serde::Serialize;
-use anyhow::Result;
+use sshx_core::Sid;
+use anyhow::{Context, Result};
+use tokio::task::JoinSet;
+use tracing::{debug, warn};
+
+use crate::xpra_archive::{self, ArchiveTarget};
+use crate::xpra_labels::{self as labels, Label};
+use crate::xpra_log_rotation::open_log_file;
+use crate::xpra_log_schema::LogSchema;
+use crate::xpra_logger::{SessionEvent, TerminationReason};
+use crate::xpra_server_log::{self as server_log, ServerLogEvent, ServerLogKind};
+use crate::xpra_session_id::ParentShell;
+
+/// How long after a session failed its xpra may still log why.
+const FAILURE_GRACE: Duration = Duration::minutes(1);
 
+/// How much of an uncompressed log [`visit_lines_rev`] reads at a time.
+const REV_CHUNK: u64 = 64 * 1024;
+
+/// Results of analyzing the history log over a period.
 #[derive(Debug, Serialize)]
 pub struct LogAnalysis {
+    /// Period that was analyzed.
     pub period: AnalysisPeriod,
+    /// Totals over all sessions.
     pub session_stats: SessionStats,
+    /// Totals per user.
     pub user_stats: HashMap<String, UserStats>,
+    /// Sessions started in each hour of the day.
     pub hourly_distribution: Vec<HourlyStats>,
+    /// Sessions started and running in each hour of the period, oldest first.
+    pub timeline: Vec<TimelinePoint>,
+    /// Number of problems of each kind in the xpra server logs.
+    pub server_issues: HashMap<ServerLogKind, u64>,
+    /// Sessions that failed, with what their xpra logged, in order.
+    pub failures: Vec<SessionFailure>,
+    /// Sessions started in the period from each sshx session, by its name.
+    pub spawned: BTreeMap<String, Vec<SpawnedDesktop>>,
 }
 
+/// Start and end of an analyzed period.
 #[derive(Debug, Serialize)]
 pub struct AnalysisPeriod {
+    /// Start of the period.
     pub start: DateTime<Utc>,
+    /// End of the period.
     pub end: DateTime<Utc>,
 }
 
+/// Session totals over a period.
 #[derive(Debug, Serialize)]
 pub struct SessionStats {
+    /// Number of sessions started.
     pub total_sessions: u64,
+    /// Average duration of the sessions that ended in the period, counting
+    /// sessions started before it from its start.
     pub avg_duration: Duration,
+    /// Largest number of sessions running at once, from the history log or
+    /// the metrics log, whichever saw more.
     pub max_concurrent: u32,
+    /// Number of sessions terminated for being idle.
     pub idle_terminations: u64,
+    /// Number of sessions that failed.
     pub failed_sessions: u64,
+    /// Number of sessions started before the period and still running at its
+    /// start.
+    pub carried_over: u64,
+    /// Number of sessions still running at the end of the period, left out
+    /// of the average duration.
+    pub open_at_end: u64,
+    /// Number of sessions that ended for each reason, for sessions whose
+    /// end was logged with one.
+    pub termination_reasons: HashMap<TerminationReason, u64>,
 }
 
+/// Session totals of a single user.
 #[derive(Debug, Serialize)]
 pub struct UserStats {
+    /// Number of sessions started.
     pub total_sessions: u32,
+    /// Sum of the durations of the sessions that ended.
     pub total_duration: Duration,
+    /// Average duration of the sessions that ended.
     pub avg_session_duration: Duration,
+    /// Number of sessions terminated for being idle.
     pub idle_terminations: u32,
 }
 
-#[derive(Debug, Serialize)]
+/// Sessions started in one hour of the day.
+#[derive(Debug, Clone, Serialize)]
 pub struct HourlyStats {
+    /// Hour of the day, from 0 to 23 in UTC.
     pub hour: u32,
+    /// Number of sessions started in that hour.
     pub session_count: u32,
 }
 
+/// A session that failed in an analyzed period.
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionFailure {
+    /// Session ID.
+    pub session_id: String,
+    /// User of the session.
+    pub user: String,
+    /// Display of the session.
+    pub display: u16,
+    /// Host the session ran on, if logged.
+    pub host: Option<String>,
+    /// When the session started, or the start of the period if before it.
+    pub started: DateTime<Utc>,
+    /// When the session failed.
+    pub failed_at: DateTime<Utc>,
+    /// Why the session failed, if logged.
+    pub reason: Option<TerminationReason>,
+    /// sshx session and shell the session was started from, if logged.
+    pub parent: Option<ParentShell>,
+    /// Crash bundle collected for its xpra, if any.
+    pub crash_bundle: Option<PathBuf>,
+    /// Problems logged by the xpra server of the display while the session
+    /// ran and shortly after it failed.
+    pub server_events: Vec<ServerLogEvent>,
+}
+
+/// A session running in an analyzed period, with when it started counting
+/// towards the period.
+struct OpenSession {
+    start: DateTime<Utc>,
+    user: String,
+    parent: Option<ParentShell>,
+    host: Option<String>,
+}
+
+impl OpenSession {
+    fn new(start: DateTime<Utc>, created: SessionEvent) -> Self {
+        Self { start, user: created.user, parent: created.parent, host: created.host }
+    }
+}
+
+/// A session started from a shell of an sshx session in an analyzed period.
+#[derive(Debug, Clone, Serialize)]
+pub struct SpawnedDesktop {
+    /// Session ID.
+    pub session_id: String,
+    /// Shell of the sshx session it was started from.
+    pub shell: Sid,
+    /// User of the session.
+    pub user: String,
+    /// When the session started.
+    pub started: DateTime<Utc>,
+}
+
+/// Sessions in one hour of an analyzed period.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TimelinePoint {
+    /// Start of the hour.
+    pub time: DateTime<Utc>,
+    /// Number of sessions started in that hour.
+    pub sessions_started: u32,
+    /// Largest number of sessions running at once in that hour.
+    pub max_concurrent: u32,
+}
+
+/// Sessions started at the `created` times and running at once over the
+/// `(start, end)` intervals, for each hour from the one containing `start`
+/// up to `end`.
+fn timeline(
+    created: &[DateTime<Utc>],
+    intervals: &[(DateTime<Utc>, DateTime<Utc>)],
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> Vec<TimelinePoint> {
+    let first = start.duration_trunc(Duration::hours(1)).unwrap_or(start);
+    let mut points = Vec::new();
+    let mut time = first;
+    while time < end {
+        points.push(TimelinePoint { time, sessions_started: 0, max_concurrent: 0 });
+        time += Duration::hours(1);
+    }
+    for created in created {
+        if let Some(point) = points.get_mut((*created - first).num_hours() as usize) {
+            point.sessions_started += 1;
+        }
+    }
+
+    let mut changes: Vec<_> = intervals
+        .iter()
+        .flat_map(|&(start, end)| [(start, 1), (end, -1)])
+        .collect();
+    changes.sort();
+    let mut changes = changes.into_iter().peekable();
+    let mut running = 0i64;
+    for point in &mut points {
+        // Sessions running as the hour starts, then the peak within it
+        while let Some((_, change)) = changes.next_if(|(time, _)| *time <= point.time) {
+            running += change;
+        }
+        let mut peak = running;
+        let next = point.time + Duration::hours(1);
+        while let Some((_, change)) = changes.next_if(|(time, _)| *time < next) {
+            running += change;
+            peak = peak.max(running);
+        }
+        point.max_concurrent = peak as u32;
+    }
+    points
+}
+
+/// Largest number of the `(start, end)` intervals that overlap. Intervals
+/// ending when another starts do not overlap.
+fn max_concurrent(intervals: &[(DateTime<Utc>, DateTime<Utc>)]) -> u32 {
+    let mut changes: Vec<_> = intervals
+        .iter()
+        .flat_map(|&(start, end)| [(start, 1), (end, -1)])
+        .collect();
+    // Ends sort before starts at the same time
+    changes.sort();
+    let (mut running, mut max) = (0i64, 0i64);
+    for (_, change) in changes {
+        running += change;
+        max = max.max(running);
+    }
+    max as u32
+}
+
+/// Log files named `name` in `log_dir` with their rotated, possibly gzipped
+/// siblings, oldest first: rotated files are named after the time they were
+/// rotated, and the current file comes last.
+pub(crate) fn log_files(log_dir: &Path, name: &str) -> Result<Vec<PathBuf>> {
+    let pattern = log_dir.join(format!("{name}*"));
+    let files: Vec<PathBuf> = glob::glob(&pattern.to_string_lossy())?.flatten().collect();
+    Ok(with_archived(files, &[], name))
+}
+
+/// `files` and the archived logs among `archived` named `name`, oldest first
+/// like [`log_files`].
+pub(crate) fn with_archived(mut files: Vec<PathBuf>, archived: &[PathBuf], name: &str) -> Vec<PathBuf> {
+    let file_name = |path: &PathBuf| path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    files.extend(archived.iter().filter(|path| file_name(path).starts_with(name)).cloned());
+    files.sort_by_key(|path| {
+        let file_name = file_name(path);
+        (file_name == name, file_name)
+    });
+    files
+}
+
+/// Read a log file, decompressing it if it was compressed when rotated.
+pub(crate) fn read_log_file(path: &Path) -> Result<String> {
+    let mut content = String::new();
+    open_log_file(path)?.read_to_string(&mut content)?;
+    Ok(content)
+}
+
+/// Visit the lines of the log file at `path` newest first, until `visit`
+/// returns false. Uncompressed files are read backwards from their end, so
+/// only about as much is read as is visited.
+pub(crate) fn visit_lines_rev(path: &Path, mut visit: impl FnMut(&str) -> bool) -> Result<()> {
+    if matches!(path.extension().and_then(|ext| ext.to_str()), Some("gz" | "zst")) {
+        for line in read_log_file(path)?.lines().rev() {
+            if !visit(line) {
+                break;
+            }
+        }
+        return Ok(());
+    }
+    let mut file = File::open(path)?;
+    let mut end = file.metadata()?.len();
+    // Start of the line the bytes after `end` begin with
+    let mut carry = Vec::new();
+    while end > 0 {
+        let size = REV_CHUNK.min(end);
+        end -= size;
+        let mut chunk = vec![0; size as usize];
+        file.seek(SeekFrom::Start(end))?;
+        file.read_exact(&mut chunk)?;
+        chunk.extend_from_slice(&carry);
+        // The first line may continue before the chunk
+        let first = match (end, chunk.iter().position(|&b| b == b'\n')) {
+            (0, _) => 0,
+            (_, Some(newline)) => newline + 1,
+            (_, None) => {
+                carry = chunk;
+                continue;
+            }
+        };
+        for line in chunk[first..].split(|&b| b == b'\n').rev().filter(|line| !line.is_empty()) {
+            if !visit(&String::from_utf8_lossy(line)) {
+                return Ok(());
+            }
+        }
+        chunk.truncate(first);
+        carry = chunk;
+    }
+    Ok(())
+}
+
+/// Lines of the log named `name` in `log_dir` and its rotated files, oldest
+/// first.
+pub(crate) fn read_log_lines(log_dir: &Path, name: &str) -> Result<Vec<String>> {
+    let mut lines = Vec::new();
+    for path in log_files(log_dir, name)? {
+        let content = read_log_file(&path).with_context(|| format!("failed to read {}", path.display()))?;
+        lines.extend(content.lines().map(String::from));
+    }
+    Ok(lines)
+}
+
+/// Reads the history logs in a directory and aggregates them.
 pub struct LogAnalyzer {
     log_dir: PathBuf,
+    xpra_log_dir: Option<PathBuf>,
+    archive: Option<ArchiveTarget>,
+    label_filter: Arc<Vec<Label>>,
+    jobs: usize,
 }
 
 impl LogAnalyzer {
+    /// Create an analyzer for the logs in `log_dir`, reading as many log
+    /// files at once as there are CPUs.
     pub fn new(log_dir: PathBuf) -> Self {
-        Self { log_dir }
+        let jobs = std::thread::available_parallelism().map_or(1, |n| n.get());
+        Self { log_dir, xpra_log_dir: None, archive: None, label_filter: Arc::new(Vec::new()), jobs }
     }
 
+    /// Only analyze sessions carrying all of the given labels.
+    pub fn with_label_filter(mut self, filter: Vec<Label>) -> Self {
+        self.label_filter = Arc::new(filter);
+        self
+    }
+
+    /// Also read xpra's own `:<display>.log` server logs in `dir`, besides
+    /// the output captured in the `sessions` directory of the log directory.
+    pub fn with_xpra_log_dir(mut self, dir: PathBuf) -> Self {
+        self.xpra_log_dir = Some(dir);
+        self
+    }
+
+    /// Also read the logs of the period archived to `target` after their
+    /// retention, downloading those in an object store for the analysis.
+    pub fn with_archive(mut self, target: ArchiveTarget) -> Self {
+        self.archive = Some(target);
+        self
+    }
+
+    /// Read at most `jobs` log files at once.
+    pub fn with_jobs(mut self, jobs: usize) -> Self {
+        self.jobs = jobs.max(1);
+        self
+    }
+
+    /// Run `read` on each of `files` in a blocking task, at most `self.jobs`
+    /// at once, returning the results in the order of `files`.
+    pub(crate) async fn read_files<T, F>(&self, files: Vec<PathBuf>, read: F) -> Result<Vec<T>>
+    where
+        T: Send + 'static,
+        F: Fn(&Path) -> Result<T> + Clone + Send + 'static,
+    {
+        let count = files.len();
+        let mut results: Vec<Option<T>> = (0..count).map(|_| None).collect();
+        let mut tasks = JoinSet::new();
+        let mut files = files.into_iter().enumerate();
+        loop {
+            while tasks.len() < self.jobs {
+                let Some((index, path)) = files.next() else {
+                    break;
+                };
+                let read = read.clone();
+                tasks.spawn_blocking(move || {
+                    debug!(path = %path.display(), "Reading log file for analysis");
+                    let result = read(&path).with_context(|| format!("failed to read {}", path.display()));
+                    (index, result)
+                });
+            }
+            let Some(joined) = tasks.join_next().await else {
+                break;
+            };
+            let (index, result) = joined?;
+            results[index] = Some(result?);
+        }
+        Ok(results.into_iter().flatten().collect())
+    }
+
+    /// Run `read` on each line of each of `files` with its path and line
+    /// number, reading the files as [`Self::read_files`] does. Returns what
+    /// it kept, in the order of the files.
+    async fn read_lines<T, F>(&self, files: Vec<PathBuf>, read: F) -> Result<Vec<T>>
+    where
+        T: Send + 'static,
+        F: Fn(&Path, usize, &str) -> Result<Option<T>> + Clone + Send + 'static,
+    {
+        let parsed = self.read_files(files, move |path| {
+            let mut kept = Vec::new();
+            for (number, line) in read_log_file(path)?.lines().enumerate() {
+                kept.extend(read(path, number + 1, line)?);
+            }
+            Ok(kept)
+        }).await?;
+        Ok(parsed.into_iter().flatten().collect())
+    }
+
+    /// Analyze the sessions between `start` and `end`.
     pub async fn analyze_period(
         &self,
         start: DateTime<Utc>,
@@ -63,106 +420,581 @@ impl LogAnalyzer {
                 max_concurrent: 0,
                 idle_terminations: 0,
                 failed_sessions: 0,
+                carried_over: 0,
+                open_at_end: 0,
+                termination_reasons: HashMap::new(),
             },
             user_stats: HashMap::new(),
             hourly_distribution: vec![HourlyStats { hour: 0, session_count: 0 }; 24],
+            timeline: Vec::new(),
+            server_issues: HashMap::new(),
+            failures: Vec::new(),
+            spawned: BTreeMap::new(),
         };
 
-        // Process history log
-        self.process_history_log(&mut analysis, start, end).await?;
-        
-        // Process metrics log for concurrent session data
-        self.process_metrics_log(&mut analysis, start, end).await?;
+        // Downloaded archives are only kept for the analysis
+        let scratch = tempfile::tempdir()?;
+        self.process_logs(&mut analysis, start, end, scratch.path()).await?;
 
         Ok(analysis)
     }
 
+    async fn process_logs(
+        &self,
+        analysis: &mut LogAnalysis,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        scratch: &Path,
+    ) -> Result<()> {
+        // Fetch archived logs of the period
+        let archived = match &self.archive {
+            Some(target) => xpra_archive::fetch(target, &self.log_dir, start, end, scratch).await?,
+            None => Vec::new(),
+        };
+
+        // Process history logs
+        self.process_history_log(analysis, &archived, start, end).await?;
+        
+        // Process metrics logs for concurrent session data
+        self.process_metrics_log(analysis, &archived, start, end).await?;
+
+        // Process xpra server logs for what went wrong on the displays
+        self.process_server_logs(analysis, start, end).await
+    }
+
+    /// Events of the session or user `key` in the history log and its
+    /// rotated files, oldest first, only from `since` on if given.
+    pub async fn history(&self, key: &str, since: Option<DateTime<Utc>>) -> Result<Vec<SessionEvent>> {
+        let files = log_files(&self.log_dir, "history.log")?;
+        let key = key.to_string();
+        let mut events = self.read_lines(files, move |path, number, line| {
+            // A damaged line only loses its own event from the answer
+            let event: SessionEvent = match LogSchema::History.parse(line) {
+                Ok(event) => event,
+                Err(e) => {
+                    warn!(path = %path.display(), line = number, "Skipping unreadable history event: {:#}", e);
+                    return Ok(None);
+                }
+            };
+            Ok((event.concerns(&key) && since.is_none_or(|since| event.timestamp >= since)).then_some(event))
+        }).await?;
+        // Files are read oldest first, so events logged at the same time
+        // keep their order
+        events.sort_by_key(|event| event.timestamp);
+        Ok(events)
+    }
+
     async fn process_history_log(
         &self,
         analysis: &mut LogAnalysis,
+        archived: &[PathBuf],
         start: DateTime<Utc>,
         end: DateTime<Utc>,
     ) -> Result<()> {
-        let history_path = self.log_dir.join("history.log");
-        let content = tokio::fs::read_to_string(history_path).await?;
+        let files = with_archived(log_files(&self.log_dir, "history.log")?, archived, "history.log");
+        if files.is_empty() {
+            anyhow::bail!("no history log in {}", self.log_dir.display());
+        }
+
+        // Parse the files concurrently, keeping the events that may count
+        // towards the period, then go through them in order
+        let filter = self.label_filter.clone();
+        let mut events = self.read_lines(files, move |_, _, line| {
+            let event: SessionEvent = LogSchema::History.parse(line)?;
+            Ok((event.timestamp <= end && labels::matches(&event.labels, &filter)).then_some(event))
+        }).await?;
+        events.sort_by_key(|event| event.timestamp);
 
-        let mut session_starts: HashMap<String, (DateTime<Utc>, String)> = HashMap::new();
+        // Running sessions by id, with when they started counting towards
+        // the period, their user, parent shell and host: sessions carried
+        // over from before it count from its start
+        let mut session_starts: HashMap<String, OpenSession> = HashMap::new();
+        let mut carried: HashSet<String> = HashSet::new();
+        // When each session in the period ran, clamped to the period
+        let mut intervals = Vec::new();
+        let mut created = Vec::new();
+        let mut ended = 0;
+        let mut total_duration = Duration::zero();
 
-        for line in content.lines() {
-            let event: crate::xpra_logger::SessionEvent = serde_json::from_str(line)?;
-            
-            if event.timestamp < start || event.timestamp > end {
+        for event in events {
+            // Before the period, only track which sessions are running at its start
+            if event.timestamp < start {
+                match event.event_type {
+                    crate::xpra_logger::SessionEventType::Created => {
+                        carried.insert(event.session_id.clone());
+                        session_starts.insert(event.session_id.clone(), OpenSession::new(start, event));
+                    }
+                    crate::xpra_logger::SessionEventType::Terminated |
+                    crate::xpra_logger::SessionEventType::IdleTimeout |
+                    crate::xpra_logger::SessionEventType::Failed => {
+                        carried.remove(&event.session_id);
+                        session_starts.remove(&event.session_id);
+                    }
+                    crate::xpra_logger::SessionEventType::RateLimited |
+                    crate::xpra_logger::SessionEventType::PolicyDenied |
+                    crate::xpra_logger::SessionEventType::Hibernated |
+                    crate::xpra_logger::SessionEventType::Resumed |
+                    crate::xpra_logger::SessionEventType::StateChanged |
+                    crate::xpra_logger::SessionEventType::Renamed |
+                    crate::xpra_logger::SessionEventType::DeviceAttached |
+                    crate::xpra_logger::SessionEventType::DeviceDetached |
+                    crate::xpra_logger::SessionEventType::RecordingAcknowledged => {}
+                }
                 continue;
             }
 
             match event.event_type {
                 crate::xpra_logger::SessionEventType::Created => {
-                    session_starts.insert(
-                        event.session_id,
-                        (event.timestamp, event.user)
-                    );
-                    
+                    analysis.session_stats.total_sessions += 1;
+                    created.push(event.timestamp);
+                    if let Some(parent) = &event.parent {
+                        analysis.spawned.entry(parent.session.clone()).or_default().push(SpawnedDesktop {
+                            session_id: event.session_id.clone(),
+                            shell: parent.shell,
+                            user: event.user.clone(),
+                            started: event.timestamp,
+                        });
+                    }
+
                     // Update hourly distribution
                     let hour = event.timestamp.hour() as usize;
                     analysis.hourly_distribution[hour].session_count += 1;
+                    session_starts.insert(event.session_id.clone(), OpenSession::new(event.timestamp, event));
                 }
                 crate::xpra_logger::SessionEventType::Terminated |
                 crate::xpra_logger::SessionEventType::IdleTimeout |
                 crate::xpra_logger::SessionEventType::Failed => {
-                    if let Some((start_time, user)) = session_starts.remove(&event.session_id) {
-                        let duration = event.timestamp - start_time;
-                        
-                        // Update user stats
-                        let user_stats = analysis.user_stats
-                            .entry(user)
-                            .or_insert_with(|| UserStats {
-                                total_sessions: 0,
-                                total_duration: Duration::zero(),
-                                avg_session_duration: Duration::zero(),
-                                idle_terminations: 0,
-                            });
-                        
-                        user_stats.total_sessions += 1;
-                        user_stats.total_duration = user_stats.total_duration + duration;
-                        user_stats.avg_session_duration = user_stats.total_duration / 
-                            user_stats.total_sessions as i32;
-                        
-                        if matches!(event.event_type, 
-                            crate::xpra_logger::SessionEventType::IdleTimeout) {
-                            user_stats.idle_terminations += 1;
-                            analysis.session_stats.idle_terminations += 1;
+                    if let Some(reason) = event.reason {
+                        *analysis.session_stats.termination_reasons.entry(reason).or_insert(0) += 1;
+                    }
+                    if matches!(event.event_type, crate::xpra_logger::SessionEventType::Failed) {
+                        analysis.session_stats.failed_sessions += 1;
+                    }
+
+                    // Sessions whose start was not logged ran since before the period
+                    let OpenSession { start: start_time, user, parent, host } = match session_starts.remove(&event.session_id) {
+                        Some(session) => session,
+                        None => {
+                            carried.insert(event.session_id.clone());
+                            OpenSession { start, user: event.user, parent: None, host: None }
                         }
+                    };
+                    if matches!(event.event_type, crate::xpra_logger::SessionEventType::Failed) {
+                        analysis.failures.push(SessionFailure {
+                            session_id: event.session_id.clone(),
+                            user: user.clone(),
+                            display: event.display,
+                            host,
+                            started: start_time,
+                            failed_at: event.timestamp,
+                            reason: event.reason,
+                            parent,
+                            crash_bundle: event.crash_bundle.clone(),
+                            server_events: Vec::new(),
+                        });
+                    }
+                    let duration = event.timestamp - start_time;
+                    intervals.push((start_time, event.timestamp));
+                    ended += 1;
+                    total_duration += duration;
+                    
+                    // Update user stats
+                    let user_stats = analysis.user_stats
+                        .entry(user)
+                        .or_insert_with(|| UserStats {
+                            total_sessions: 0,
+                            total_duration: Duration::zero(),
+                            avg_session_duration: Duration::zero(),
+                            idle_terminations: 0,
+                        });
+                    
+                    user_stats.total_sessions += 1;
+                    user_stats.total_duration += duration;
+                    user_stats.avg_session_duration = user_stats.total_duration / 
+                        user_stats.total_sessions as i32;
+                    
+                    if matches!(event.event_type, 
+                        crate::xpra_logger::SessionEventType::IdleTimeout) {
+                        user_stats.idle_terminations += 1;
+                        analysis.session_stats.idle_terminations += 1;
                     }
                 }
+                crate::xpra_logger::SessionEventType::RateLimited |
+                crate::xpra_logger::SessionEventType::PolicyDenied |
+                crate::xpra_logger::SessionEventType::Hibernated |
+                crate::xpra_logger::SessionEventType::Resumed |
+                crate::xpra_logger::SessionEventType::StateChanged |
+                crate::xpra_logger::SessionEventType::Renamed |
+                crate::xpra_logger::SessionEventType::DeviceAttached |
+                crate::xpra_logger::SessionEventType::DeviceDetached |
+                crate::xpra_logger::SessionEventType::RecordingAcknowledged => {}
             }
         }
 
+        // Sessions still running at the end of the period
+        for session in session_starts.values() {
+            intervals.push((session.start, end));
+        }
+
+        if ended > 0 {
+            analysis.session_stats.avg_duration = total_duration / ended;
+        }
+        analysis.session_stats.carried_over = carried.len() as u64;
+        analysis.session_stats.open_at_end = session_starts.len() as u64;
+        analysis.session_stats.max_concurrent = max_concurrent(&intervals);
+        analysis.timeline = timeline(&created, &intervals, start, end);
+
         Ok(())
     }
 
     async fn process_metrics_log(
         &self,
         analysis: &mut LogAnalysis,
+        archived: &[PathBuf],
         start: DateTime<Utc>,
         end: DateTime<Utc>,
     ) -> Result<()> {
-        let metrics_path = self.log_dir.join("metrics.log");
-        let content = tokio::fs::read_to_string(metrics_path).await?;
+        // Sessions running at each snapshot in the period
+        let files = with_archived(log_files(&self.log_dir, "metrics.log")?, archived, "metrics.log");
+        let active = self.read_lines(files, move |_, _, line| {
+            let entry: crate::xpra_logger::LogEntry = LogSchema::Metrics.parse(line)?;
+            let in_period = entry.timestamp >= start && entry.timestamp <= end;
+            Ok(in_period.then_some(entry.metrics.active_sessions as u32))
+        }).await?;
+        let max_concurrent = active.into_iter().max().unwrap_or(0);
 
-        let mut max_concurrent = 0;
+        analysis.session_stats.max_concurrent = analysis.session_stats.max_concurrent.max(max_concurrent);
 
-        for line in content.lines() {
-            let entry: crate::xpra_logger::LogEntry = serde_json::from_str(line)?;
-            
-            if entry.timestamp < start || entry.timestamp > end {
-                continue;
-            }
+        Ok(())
+    }
+
+    async fn process_server_logs(
+        &self,
+        analysis: &mut LogAnalysis,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<()> {
+        // Problems logged in the period, and shortly after it for sessions
+        // failing near its end
+        let files = server_log::log_files(&self.log_dir, self.xpra_log_dir.as_deref());
+        let parsed = self.read_files(files, move |path| {
+            let Some((host, display)) = server_log::file_display(path) else {
+                return Ok(Vec::new());
+            };
+            let content = read_log_file(path)?;
+            Ok(server_log::parse(&content, host.as_deref(), display, start, end + FAILURE_GRACE))
+        }).await?;
+        let events: Vec<ServerLogEvent> = parsed.into_iter().flatten().collect();
 
-            max_concurrent = max_concurrent.max(entry.metrics.active_sessions as u32);
+        for event in events.iter().filter(|event| event.timestamp <= end) {
+            *analysis.server_issues.entry(event.kind).or_insert(0) += 1;
         }
 
-        analysis.session_stats.max_concurrent = max_concurrent;
+        // Blame each failure on what its display logged while it ran
+        for failure in &mut analysis.failures {
+            failure.server_events = events
+                .iter()
+                .filter(|event| {
+                    event.is_from(failure.host.as_deref(), failure.display)
+                        && event.timestamp >= failure.started
+                        && event.timestamp <= failure.failed_at + FAILURE_GRACE
+                })
+                .cloned()
+                .collect();
+            failure.server_events.sort_by_key(|event| event.timestamp);
+        }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_visit_lines_rev() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.log");
+        let lines: Vec<String> = (0..20_000).map(|i| format!("line {i}")).collect();
+        std::fs::write(&path, lines.join("\n") + "\n").unwrap();
+        assert!(std::fs::metadata(&path).unwrap().len() > 2 * REV_CHUNK);
+
+        let mut visited = Vec::new();
+        visit_lines_rev(&path, |line| {
+            visited.push(line.to_string());
+            true
+        })
+        .unwrap();
+        visited.reverse();
+        assert_eq!(visited, lines);
+
+        let mut last = Vec::new();
+        visit_lines_rev(&path, |line| {
+            last.push(line.to_string());
+            last.len() < 3
+        })
+        .unwrap();
+        assert_eq!(last, ["line 19999", "line 19998", "line 19997"]);
+    }
+
+    #[tokio::test]
+    async fn test_termination_reasons() {
+        let dir = std::env::temp_dir().join(format!("sshx-analyzer-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let history = [
+            r#"{"timestamp":"2024-05-01T10:00:00Z","event_type":"Created","session_id":"a","user":"alice","display":100}"#,
+            r#"{"timestamp":"2024-05-01T11:00:00Z","event_type":"Terminated","session_id":"a","user":"alice","display":100,"reason":"client_disconnect"}"#,
+            r#"{"timestamp":"2024-05-01T10:00:00Z","event_type":"Created","session_id":"b","user":"bob","display":101}"#,
+            r#"{"timestamp":"2024-05-01T10:30:00Z","event_type":"Failed","session_id":"b","user":"bob","display":101,"reason":"process_crash"}"#,
+            // Logged before ends carried a reason
+            r#"{"timestamp":"2024-05-01T12:00:00Z","event_type":"Terminated","session_id":"c","user":"carol","display":102}"#,
+        ];
+        std::fs::write(dir.join("history.log"), history.join("\n")).unwrap();
+        std::fs::write(dir.join("metrics.log"), "").unwrap();
+
+        let start = "2024-05-01T00:00:00Z".parse().unwrap();
+        let end = "2024-05-02T00:00:00Z".parse().unwrap();
+        let analysis = LogAnalyzer::new(dir.clone()).analyze_period(start, end).await.unwrap();
+        let reasons = &analysis.session_stats.termination_reasons;
+        assert_eq!(reasons.len(), 2);
+        assert_eq!(reasons[&TerminationReason::ClientDisconnect], 1);
+        assert_eq!(reasons[&TerminationReason::ProcessCrash], 1);
+
+        let json = serde_json::to_value(&analysis.session_stats).unwrap();
+        assert_eq!(json["termination_reasons"]["process_crash"], 1);
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn test_spawned_desktops() {
+        let dir = std::env::temp_dir().join(format!("sshx-analyzer-spawned-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let history = [
+            r#"{"timestamp":"2024-05-01T10:00:00Z","event_type":"Created","session_id":"a","user":"alice","display":100,"parent":{"session":"k3xq9v","shell":1}}"#,
+            r#"{"timestamp":"2024-05-01T10:05:00Z","event_type":"Created","session_id":"b","user":"alice","display":101,"parent":{"session":"k3xq9v","shell":4}}"#,
+            r#"{"timestamp":"2024-05-01T10:10:00Z","event_type":"Created","session_id":"c","user":"bob","display":102,"parent":{"session":"p8wm2d","shell":1}}"#,
+            r#"{"timestamp":"2024-05-01T10:15:00Z","event_type":"Created","session_id":"headless-1","user":"ci","display":103}"#,
+            r#"{"timestamp":"2024-05-01T10:30:00Z","event_type":"Failed","session_id":"b","user":"alice","display":101,"reason":"process_crash"}"#,
+        ];
+        std::fs::write(dir.join("history.log"), history.join("\n")).unwrap();
+        std::fs::write(dir.join("metrics.log"), "").unwrap();
+
+        let start = "2024-05-01T00:00:00Z".parse().unwrap();
+        let end = "2024-05-02T00:00:00Z".parse().unwrap();
+        let analysis = LogAnalyzer::new(dir.clone()).analyze_period(start, end).await.unwrap();
+        let spawned: Vec<_> = analysis.spawned["k3xq9v"].iter().map(|d| (d.session_id.as_str(), d.shell)).collect();
+        assert_eq!(spawned, [("a", Sid(1)), ("b", Sid(4))]);
+        assert_eq!(analysis.spawned["p8wm2d"].len(), 1);
+        assert_eq!(analysis.spawned.len(), 2);
+
+        // Failures name the shell the desktop was started from
+        let parent = analysis.failures[0].parent.as_ref().unwrap();
+        assert_eq!(parent.to_string(), "k3xq9v/4");
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn test_session_totals() {
+        let dir = std::env::temp_dir().join(format!("sshx-analyzer-totals-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let history = [
+            r#"{"timestamp":"2024-05-01T10:00:00Z","event_type":"Created","session_id":"a","user":"alice","display":100}"#,
+            r#"{"timestamp":"2024-05-01T11:00:00Z","event_type":"Terminated","session_id":"a","user":"alice","display":100}"#,
+            r#"{"timestamp":"2024-05-01T10:00:00Z","event_type":"Created","session_id":"b","user":"bob","display":101}"#,
+            r#"{"timestamp":"2024-05-01T10:30:00Z","event_type":"Failed","session_id":"b","user":"bob","display":101}"#,
+            r#"{"timestamp":"2024-05-01T20:00:00Z","event_type":"Created","session_id":"c","user":"carol","display":102}"#,
+        ];
+        std::fs::write(dir.join("history.log"), history.join("\n")).unwrap();
+        std::fs::write(dir.join("metrics.log"), "").unwrap();
+
+        let start = "2024-05-01T00:00:00Z".parse().unwrap();
+        let end = "2024-05-02T00:00:00Z".parse().unwrap();
+        let analysis = LogAnalyzer::new(dir.clone()).analyze_period(start, end).await.unwrap();
+        let stats = &analysis.session_stats;
+        assert_eq!(stats.total_sessions, 3);
+        assert_eq!(stats.failed_sessions, 1);
+        assert_eq!(stats.open_at_end, 1);
+        assert_eq!(stats.avg_duration, Duration::minutes(45));
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn test_sessions_spanning_period() {
+        let dir = std::env::temp_dir().join(format!("sshx-analyzer-span-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let history = [
+            r#"{"timestamp":"2024-05-01T09:00:00Z","event_type":"Created","session_id":"b","user":"bob","display":101}"#,
+            r#"{"timestamp":"2024-05-01T10:00:00Z","event_type":"Created","session_id":"a","user":"alice","display":100}"#,
+            r#"{"timestamp":"2024-05-01T11:00:00Z","event_type":"Terminated","session_id":"b","user":"bob","display":101}"#,
+            r#"{"timestamp":"2024-05-01T11:00:00Z","event_type":"Created","session_id":"c","user":"carol","display":102}"#,
+            r#"{"timestamp":"2024-05-01T13:00:00Z","event_type":"Terminated","session_id":"a","user":"alice","display":100}"#,
+            r#"{"timestamp":"2024-05-01T14:00:00Z","event_type":"Created","session_id":"d","user":"dave","display":103}"#,
+            r#"{"timestamp":"2024-05-01T15:00:00Z","event_type":"Created","session_id":"e","user":"erin","display":104}"#,
+            r#"{"timestamp":"2024-05-01T16:00:00Z","event_type":"Terminated","session_id":"e","user":"erin","display":104}"#,
+            r#"{"timestamp":"2024-05-01T20:00:00Z","event_type":"Terminated","session_id":"d","user":"dave","display":103}"#,
+        ];
+        std::fs::write(dir.join("history.log"), history.join("\n")).unwrap();
+        std::fs::write(dir.join("metrics.log"), "").unwrap();
+
+        let start = "2024-05-01T12:00:00Z".parse().unwrap();
+        let end = "2024-05-01T18:00:00Z".parse().unwrap();
+        let analysis = LogAnalyzer::new(dir.clone()).analyze_period(start, end).await.unwrap();
+        let stats = &analysis.session_stats;
+        assert_eq!(stats.total_sessions, 2);
+        assert_eq!(stats.carried_over, 2);
+        assert_eq!(stats.open_at_end, 2);
+        // Alice's session counts from the start of the period
+        assert_eq!(stats.avg_duration, Duration::hours(1));
+        assert_eq!(analysis.user_stats["alice"].total_duration, Duration::hours(1));
+        assert!(!analysis.user_stats.contains_key("bob"));
+        // Carol's, dave's and erin's sessions overlap at 15:00
+        assert_eq!(stats.max_concurrent, 3);
+
+        let timeline: Vec<_> = analysis.timeline.iter().map(|p| (p.sessions_started, p.max_concurrent)).collect();
+        assert_eq!(timeline, [(0, 2), (0, 1), (1, 2), (1, 3), (0, 2), (0, 2)]);
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn test_rotated_files() {
+        use std::io::Write;
+
+        let dir = std::env::temp_dir().join(format!("sshx-analyzer-rotated-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let gzip = |lines: &[&str]| {
+            let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(lines.join("\n").as_bytes()).unwrap();
+            encoder.finish().unwrap()
+        };
+        std::fs::write(dir.join("history.log.20240501_110000.gz"), gzip(&[
+            r#"{"timestamp":"2024-05-01T10:00:00Z","event_type":"Created","session_id":"a","user":"alice","display":100}"#,
+        ])).unwrap();
+        std::fs::write(dir.join("history.log.20240501_120000"), [
+            r#"{"timestamp":"2024-05-01T11:00:00Z","event_type":"Created","session_id":"b","user":"bob","display":101}"#,
+            r#"{"timestamp":"2024-05-01T11:30:00Z","event_type":"Terminated","session_id":"b","user":"bob","display":101}"#,
+        ].join("\n")).unwrap();
+        // Alice's session ends in the current file
+        std::fs::write(dir.join("history.log"), [
+            r#"{"timestamp":"2024-05-01T12:30:00Z","event_type":"Terminated","session_id":"a","user":"alice","display":100}"#,
+        ].join("\n")).unwrap();
+        std::fs::write(dir.join("metrics.log.20240501_120000.gz"), gzip(&[
+            r#"{"timestamp":"2024-05-01T11:15:00Z","metrics":{"total_sessions":2,"active_sessions":2,"failed_sessions":0,"idle_terminations":0},"sessions":[]}"#,
+        ])).unwrap();
+        std::fs::write(dir.join("metrics.log"), "").unwrap();
+
+        let start = "2024-05-01T00:00:00Z".parse().unwrap();
+        let end = "2024-05-02T00:00:00Z".parse().unwrap();
+        let analysis = LogAnalyzer::new(dir.clone())
+            .with_jobs(2)
+            .analyze_period(start, end)
+            .await
+            .unwrap();
+        let stats = &analysis.session_stats;
+        assert_eq!(stats.total_sessions, 2);
+        assert_eq!(stats.open_at_end, 0);
+        assert_eq!(analysis.user_stats["alice"].total_duration, Duration::minutes(150));
+        assert_eq!(stats.max_concurrent, 2);
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn test_history() {
+        let dir = std::env::temp_dir().join(format!("sshx-analyzer-history-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("history.log.20240501_120000"), [
+            r#"{"timestamp":"2024-05-01T10:00:00Z","event_type":"Created","session_id":"a","user":"alice","display":100}"#,
+            r#"{"timestamp":"2024-05-01T11:00:00Z","event_type":"Created","session_id":"b","user":"bob","display":101}"#,
+        ].join("\n")).unwrap();
+        std::fs::write(dir.join("history.log"), [
+            r#"{"timestamp":"2024-05-01T12:30:00Z","event_type":"Terminated","session_id":"a","user":"alice","display":100}"#,
+            r#"{"timestamp":"2024-05-01T12:45:00Z","event_type":"Created","session_id":"a","us"#,
+            r#"{"timestamp":"2024-05-01T13:00:00Z","event_type":"Created","session_id":"c","user":"alice","display":100}"#,
+        ].join("\n")).unwrap();
+
+        let analyzer = LogAnalyzer::new(dir.clone());
+        let ids = |events: Vec<SessionEvent>| -> Vec<String> {
+            events.into_iter().map(|event| format!("{}@{}", event.session_id, event.timestamp.format("%H:%M"))).collect()
+        };
+        assert_eq!(ids(analyzer.history("a", None).await.unwrap()), ["a@10:00", "a@12:30"]);
+        assert_eq!(ids(analyzer.history("alice", None).await.unwrap()), ["a@10:00", "a@12:30", "c@13:00"]);
+        let since = "2024-05-01T12:00:00Z".parse().unwrap();
+        assert_eq!(ids(analyzer.history("alice", Some(since)).await.unwrap()), ["a@12:30", "c@13:00"]);
+        assert!(analyzer.history("carol", None).await.unwrap().is_empty());
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn test_failures_with_server_logs() {
+        let dir = std::env::temp_dir().join(format!("sshx-analyzer-server-{}", std::process::id()));
+        let xpra_dir = dir.join("xpra");
+        std::fs::create_dir_all(dir.join("sessions")).unwrap();
+        std::fs::create_dir_all(&xpra_dir).unwrap();
+        let history = [
+            r#"{"timestamp":"2024-05-14T10:00:00Z","event_type":"Created","session_id":"a","user":"alice","display":100,"host":"worker-1"}"#,
+            r#"{"timestamp":"2024-05-14T10:30:00Z","event_type":"Failed","session_id":"a","user":"alice","display":100,"reason":"process_crash"}"#,
+            r#"{"timestamp":"2024-05-14T11:00:00Z","event_type":"Created","session_id":"b","user":"bob","display":100}"#,
+            r#"{"timestamp":"2024-05-14T12:00:00Z","event_type":"Terminated","session_id":"b","user":"bob","display":100}"#,
+        ];
+        std::fs::write(dir.join("history.log"), history.join("\n")).unwrap();
+        std::fs::write(dir.join("metrics.log"), "").unwrap();
+        std::fs::write(dir.join("sessions").join("worker-1-100.log"), [
+            "2024-05-14T10:10:00.000Z Warning: vaapi encoder failed, falling back to x264",
+            "2024-05-14T10:30:05.000Z Traceback (most recent call last):",
+            // Logged during Bob's session, which did not fail
+            "2024-05-14T11:30:00.000Z connection lost: socket error",
+        ].join("\n")).unwrap();
+        // Another display's problems are not blamed on Alice's session, nor
+        // are those of the same display number on other hosts
+        std::fs::write(xpra_dir.join(":101.log"), "2024-05-14 10:20:00,000 Segmentation fault\n").unwrap();
+        std::fs::write(xpra_dir.join(":100.log"), "2024-05-14 12:00:00,000 Segmentation fault\n").unwrap();
+        std::fs::write(dir.join("sessions").join("worker-2-100.log"), "2024-05-14T10:25:00.000Z Segmentation fault\n").unwrap();
+
+        let start = "2024-05-14T00:00:00Z".parse().unwrap();
+        let end = "2024-05-15T00:00:00Z".parse().unwrap();
+        let analysis = LogAnalyzer::new(dir.clone())
+            .with_xpra_log_dir(xpra_dir)
+            .analyze_period(start, end)
+            .await
+            .unwrap();
+        assert_eq!(analysis.server_issues[&ServerLogKind::Crash], 4);
+        assert_eq!(analysis.server_issues[&ServerLogKind::EncoderFallback], 1);
+        assert_eq!(analysis.server_issues[&ServerLogKind::ConnectionError], 1);
+
+        assert_eq!(analysis.failures.len(), 1);
+        let failure = &analysis.failures[0];
+        assert_eq!(failure.session_id, "a");
+        assert_eq!(failure.reason, Some(TerminationReason::ProcessCrash));
+        let kinds: Vec<_> = failure.server_events.iter().map(|event| event.kind).collect();
+        assert_eq!(kinds, [ServerLogKind::EncoderFallback, ServerLogKind::Crash]);
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn test_schema_versions() {
+        // Logs kept from each schema version, as written by that version
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/log_schema");
+        let analyze = |version: &str, start: &str| {
+            let start: DateTime<Utc> = start.parse().unwrap();
+            let analyzer = LogAnalyzer::new(fixtures.join(version));
+            async move { analyzer.analyze_period(start, start + Duration::days(1)).await.unwrap() }
+        };
+
+        let v1 = analyze("v1", "2024-03-04T00:00:00Z").await;
+        assert_eq!(v1.session_stats.total_sessions, 2);
+        assert_eq!((v1.session_stats.idle_terminations, v1.session_stats.failed_sessions), (1, 1));
+        assert_eq!(v1.session_stats.max_concurrent, 1);
+
+        let v2 = analyze("v2", "2024-06-03T00:00:00Z").await;
+        assert_eq!(v2.session_stats.total_sessions, 1);
+        assert_eq!(v2.session_stats.termination_reasons[&TerminationReason::IdleTimeout], 1);
+        assert_eq!(v2.spawned["k3xq9v"].len(), 1);
+    }
+}
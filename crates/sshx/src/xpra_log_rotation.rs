@@ -1,22 +1,276 @@
+//! Rotation, compression, and pruning or archival of the Xpra log files.
+
 use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read};
 use std::path::{Path, PathBuf};
 use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
 use tokio::time::{self, Duration};
-use tracing::{error, info};
+use tracing::{error, info, warn};
 use glob::glob;
 
+use crate::xpra_archive::{self, ArchiveTarget};
+use crate::xpra_logger::{AlertEvent, AlertKind, XpraLogger};
+
 const MAX_LOG_AGE_DAYS: i64 = 30;
 const MAX_LOG_SIZE_BYTES: u64 = 10 * 1024 * 1024; // 10MB
 
+/// Current logs smaller than this are not rotated early under disk
+/// pressure, as compressing them frees next to nothing.
+const MIN_EARLY_ROTATION_BYTES: u64 = 1024 * 1024;
+
+/// Logs rotated by size and age, and pruned under disk pressure.
+const ROTATED_LOGS: [&str; 3] = ["metrics.log", "history.log", "alerts.log"];
+
+lazy_static::lazy_static! {
+    /// Held while rotating or pruning, so the hourly rotation and the disk
+    /// checks never work on the same files, like their `.partial` ones
+    static ref ROTATION: tokio::sync::Mutex<()> = tokio::sync::Mutex::new(());
+}
+
+/// Format rotated logs are compressed with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CompressionAlgorithm {
+    /// gzip, written as `.gz`
+    #[default]
+    Gzip,
+    /// Zstandard, written as `.zst`; smaller and faster than gzip
+    Zstd,
+}
+
+impl CompressionAlgorithm {
+    /// Extension of the files this format writes
+    pub fn extension(&self) -> &'static str {
+        match self {
+            CompressionAlgorithm::Gzip => "gz",
+            CompressionAlgorithm::Zstd => "zst",
+        }
+    }
+}
+
+/// How rotated logs are compressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct LogCompression {
+    /// Format of the compressed files
+    #[serde(default)]
+    pub algorithm: CompressionAlgorithm,
+    /// Compression level, 0-9 for gzip and 1-22 for zstd, the format's
+    /// default (6 and 3) if not given
+    #[serde(default)]
+    pub level: Option<i32>,
+}
+
+impl LogCompression {
+    /// Compress the file at `path` into `<path>.<extension>`, streaming so
+    /// large logs are not read into memory, and remove it.
+    pub fn compress(&self, path: &Path) -> anyhow::Result<PathBuf> {
+        let file_name = path.file_name().ok_or_else(|| anyhow::anyhow!("log file without a name"))?;
+        let compressed_path = path.with_file_name(format!(
+            "{}.{}",
+            file_name.to_string_lossy(),
+            self.algorithm.extension(),
+        ));
+        // Written under a hidden name first, so readers globbing for the
+        // log never see a partial file
+        let partial_path = path.with_file_name(format!(".{}.partial", file_name.to_string_lossy()));
+
+        let mut input = BufReader::new(File::open(path)?);
+        let output = BufWriter::new(File::create(&partial_path)?);
+        let output = match self.algorithm {
+            CompressionAlgorithm::Gzip => {
+                let level = self.level.map_or(6, |level| level.clamp(0, 9)) as u32;
+                let mut encoder = flate2::write::GzEncoder::new(output, flate2::Compression::new(level));
+                io::copy(&mut input, &mut encoder)?;
+                encoder.finish()?
+            }
+            CompressionAlgorithm::Zstd => {
+                let range = zstd::compression_level_range();
+                let level = self.level.map_or(3, |level| level.clamp(*range.start(), *range.end()));
+                let mut encoder = zstd::stream::write::Encoder::new(output, level)?;
+                io::copy(&mut input, &mut encoder)?;
+                encoder.finish()?
+            }
+        };
+        output.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+
+        fs::rename(&partial_path, &compressed_path)?;
+        fs::remove_file(path)?;
+        Ok(compressed_path)
+    }
+}
+
+/// Limits on the space taken by the logs, checked between rotations. None
+/// are set by default.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct DiskLimits {
+    /// Least free space on the file system of the log directory, in percent
+    /// (0 = no limit)
+    #[serde(default)]
+    pub min_free_percent: f64,
+
+    /// Most bytes the log directory may hold (0 = no limit)
+    #[serde(default)]
+    pub max_log_bytes: u64,
+
+    /// Seconds between checks
+    #[serde(default = "default_check_interval")]
+    pub check_interval: u64,
+}
+
+fn default_check_interval() -> u64 { 60 }
+
+impl Default for DiskLimits {
+    fn default() -> Self {
+        Self {
+            min_free_percent: 0.0,
+            max_log_bytes: 0,
+            check_interval: default_check_interval(),
+        }
+    }
+}
+
+impl DiskLimits {
+    fn enabled(&self) -> bool {
+        self.min_free_percent > 0.0 || self.max_log_bytes > 0
+    }
+
+    /// Why `usage` is past the limits, if it is.
+    fn exceeded(&self, usage: &DiskUsage) -> Option<String> {
+        let free_percent = usage.free_bytes as f64 * 100.0 / usage.total_bytes.max(1) as f64;
+        if self.min_free_percent > 0.0 && free_percent < self.min_free_percent {
+            Some(format!("{:.1}% free, below {}%", free_percent, self.min_free_percent))
+        } else if self.max_log_bytes > 0 && usage.log_bytes > self.max_log_bytes {
+            Some(format!("logs take {} bytes, above {}", usage.log_bytes, self.max_log_bytes))
+        } else {
+            None
+        }
+    }
+}
+
+/// Space on the file system of the log directory and taken by the logs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct DiskUsage {
+    free_bytes: u64,
+    total_bytes: u64,
+    log_bytes: u64,
+}
+
+impl DiskUsage {
+    fn of(log_dir: &Path) -> anyhow::Result<Self> {
+        let (free_bytes, total_bytes) = free_space(log_dir)?;
+        Ok(Self { free_bytes, total_bytes, log_bytes: dir_size(log_dir)? })
+    }
+}
+
+/// Free and total bytes of the file system of `dir`.
+fn free_space(dir: &Path) -> anyhow::Result<(u64, u64)> {
+    let stat = nix::sys::statvfs::statvfs(dir)?;
+    let block = stat.fragment_size() as u64;
+    Ok((stat.blocks_available() as u64 * block, stat.blocks() as u64 * block))
+}
+
+/// Logs that may be removed to free space, oldest first: the rotated logs,
+/// by when they were rotated, and the xpra output of sessions, by when it
+/// was last written, so that of running sessions comes last.
+fn prunable_logs(log_dir: &Path) -> anyhow::Result<Vec<(DateTime<Utc>, PathBuf, u64)>> {
+    let mut patterns: Vec<PathBuf> = ROTATED_LOGS.iter().map(|name| log_dir.join(format!("{name}.*"))).collect();
+    patterns.push(log_dir.join("sessions").join("*.log"));
+    let mut logs = Vec::new();
+    for pattern in patterns {
+        for path in glob(&pattern.to_string_lossy())?.flatten() {
+            let Ok(metadata) = fs::metadata(&path) else {
+                continue;
+            };
+            let modified = metadata.modified().map(DateTime::<Utc>::from).unwrap_or_default();
+            logs.push((rotated_at(&path).unwrap_or(modified), path, metadata.len()));
+        }
+    }
+    logs.sort();
+    Ok(logs)
+}
+
+/// Bytes taken by the files in `dir` and its subdirectories.
+fn dir_size(dir: &Path) -> io::Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        size += if metadata.is_dir() { dir_size(&entry.path())? } else { metadata.len() };
+    }
+    Ok(size)
+}
+
+/// When a rotated log named `<name>.log.<YYYYmmdd_HHMMSS>[.<ext>]` was
+/// rotated.
+fn rotated_at(path: &Path) -> Option<DateTime<Utc>> {
+    let timestamp = path.file_name()?.to_str()?.split('.').nth(2)?;
+    let timestamp = DateTime::parse_from_str(&format!("{}+0000", timestamp), "%Y%m%d_%H%M%S%z").ok()?;
+    Some(timestamp.with_timezone(&Utc))
+}
+
+/// Open a log file for reading, decompressing it by its extension if it was
+/// compressed when rotated.
+pub fn open_log_file(path: &Path) -> io::Result<Box<dyn Read>> {
+    let file = File::open(path)?;
+    Ok(match path.extension().and_then(|ext| ext.to_str()) {
+        Some("gz") => Box::new(flate2::read::GzDecoder::new(file)),
+        Some("zst") => Box::new(zstd::stream::read::Decoder::new(file)?),
+        _ => Box::new(file),
+    })
+}
+
+/// Rotates the log files in a directory once they grow too large.
+#[derive(Clone)]
 pub struct LogRotator {
     log_dir: PathBuf,
+    compression: LogCompression,
+    archive: Option<ArchiveTarget>,
+    logger: Option<XpraLogger>,
+    disk_limits: Option<DiskLimits>,
 }
 
 impl LogRotator {
+    /// Create a rotator for the logs in `log_dir`.
     pub fn new(log_dir: PathBuf) -> Self {
-        Self { log_dir }
+        Self {
+            log_dir,
+            compression: LogCompression::default(),
+            archive: None,
+            logger: None,
+            disk_limits: None,
+        }
+    }
+
+    /// Rotate and prune logs early when the disk usage passes `limits`,
+    /// instead of only by size and age.
+    pub fn with_disk_limits(mut self, limits: DiskLimits) -> Self {
+        self.disk_limits = Some(limits).filter(|limits| limits.enabled());
+        self
+    }
+
+    /// Hand the metrics and history logs over from `logger` when rotating
+    /// them, so no line is written to a rotated file.
+    pub fn with_logger(mut self, logger: XpraLogger) -> Self {
+        self.logger = Some(logger);
+        self
+    }
+
+    /// Compress rotated logs as set in `compression` instead of with gzip at
+    /// its default level.
+    pub fn with_compression(mut self, compression: LogCompression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Archive old rotated logs to `target` instead of deleting them.
+    pub fn with_archive(mut self, target: ArchiveTarget) -> Self {
+        self.archive = Some(target);
+        self
     }
 
+    /// Spawn a background task checking the logs every hour, and one
+    /// checking the disk usage if limits are set.
     pub fn start_rotation(&self) {
         let rotator = self.clone();
         tokio::spawn(async move {
@@ -28,79 +282,174 @@ impl LogRotator {
                 }
             }
         });
+
+        if let Some(limits) = self.disk_limits {
+            let rotator = self.clone();
+            tokio::spawn(async move {
+                let mut interval = time::interval(Duration::from_secs(limits.check_interval.max(1)));
+                loop {
+                    interval.tick().await;
+                    if let Err(e) = rotator.check_disk_usage().await {
+                        error!("Failed to check disk usage of logs: {}", e);
+                    }
+                }
+            });
+        }
+    }
+
+    /// When the disk usage is past the limits, remove rotated logs and the
+    /// xpra output of sessions, oldest first, until it is back within them.
+    /// Only if that is not enough are current logs of some size rotated
+    /// early, to be compressed. Logs an alert when it removed or rotated
+    /// anything.
+    pub async fn check_disk_usage(&self) -> anyhow::Result<()> {
+        let Some(limits) = self.disk_limits else {
+            return Ok(());
+        };
+        if !self.log_dir.exists() {
+            return Ok(());
+        }
+        let _rotation = ROTATION.lock().await;
+        let mut usage = DiskUsage::of(&self.log_dir)?;
+        let Some(reason) = limits.exceeded(&usage) else {
+            return Ok(());
+        };
+        warn!(log_dir = %self.log_dir.display(), "Logs are short of disk space: {}", reason);
+
+        let mut pruned = 0;
+        for (_, path, size) in prunable_logs(&self.log_dir)? {
+            if limits.exceeded(&usage).is_none() {
+                break;
+            }
+            if let Err(e) = fs::remove_file(&path) {
+                warn!(path = %path.display(), "Failed to remove log to free disk space: {}", e);
+                continue;
+            }
+            warn!(path = %path.display(), "Removed log to free disk space");
+            // Files still open, like the output of running sessions, only
+            // free their space once closed
+            (usage.free_bytes, usage.total_bytes) = free_space(&self.log_dir)?;
+            usage.log_bytes = usage.log_bytes.saturating_sub(size);
+            pruned += 1;
+        }
+
+        let mut rotated = 0;
+        if limits.exceeded(&usage).is_some() {
+            for name in ROTATED_LOGS {
+                rotated += self.rotate_file(&self.log_dir.join(name), MIN_EARLY_ROTATION_BYTES).await? as usize;
+            }
+        }
+        if pruned == 0 && rotated == 0 {
+            return Ok(());
+        }
+
+        let usage = DiskUsage::of(&self.log_dir)?;
+        let done = format!("removed {pruned} old logs and rotated {rotated} current ones early");
+        let message = match limits.exceeded(&usage) {
+            Some(still) => format!("{reason}; {done}, still {still}"),
+            None => format!("{reason}; {done}"),
+        };
+        if let Some(logger) = &self.logger {
+            let alert = AlertEvent { timestamp: Utc::now(), kind: AlertKind::DiskPressure, message };
+            logger.log_alert(alert).await?;
+        }
+        Ok(())
     }
 
-    async fn rotate_logs(&self) -> anyhow::Result<()> {
-        let metrics_path = self.log_dir.join("metrics.log");
-        let history_path = self.log_dir.join("history.log");
+    /// Rotate the logs that grew too large and remove or archive old rotated
+    /// ones.
+    pub(crate) async fn rotate_logs(&self) -> anyhow::Result<()> {
+        let _rotation = ROTATION.lock().await;
+
+        // Finish rotations interrupted by a crash
+        self.recover_rotated_logs().await?;
 
         // Check and rotate current log files
-        self.check_and_rotate_file(&metrics_path).await?;
-        self.check_and_rotate_file(&history_path).await?;
+        for name in ROTATED_LOGS {
+            self.rotate_file(&self.log_dir.join(name), MAX_LOG_SIZE_BYTES).await?;
+        }
 
-        // Clean up old rotated logs
+        // Clean up or archive old rotated logs
         self.cleanup_old_logs().await?;
 
         Ok(())
     }
 
-    async fn check_and_rotate_file(&self, path: &Path) -> anyhow::Result<()> {
+    /// Rotate the log at `path` if it grew larger than `max_size`. Returns
+    /// whether it did.
+    async fn rotate_file(&self, path: &Path, max_size: u64) -> anyhow::Result<bool> {
         if !path.exists() {
-            return Ok(());
+            return Ok(false);
         }
 
-        let metadata = fs::metadata(path)?;
-        if metadata.len() > MAX_LOG_SIZE_BYTES {
+        let size = fs::metadata(path)?.len();
+        let rotate = size > max_size;
+        if rotate {
             let timestamp = Utc::now().format("%Y%m%d_%H%M%S");
             let rotated_path = path.with_extension(format!("log.{}", timestamp));
             
-            // Rename current log file
-            fs::rename(path, &rotated_path)?;
-            
-            // Create new empty log file
-            File::create(path)?;
-            
-            // Compress rotated log
-            let rotator = self.clone();
-            let rotated_path_clone = rotated_path.clone();
-            tokio::spawn(async move {
-                if let Err(e) = rotator.compress_log(&rotated_path_clone).await {
-                    error!("Failed to compress rotated log: {}", e);
+            // Rename current log file, handing it over from the logger
+            // writing it so its next line goes to a new file
+            match &self.logger {
+                Some(logger) => logger.rotate_file(path, &rotated_path).await?,
+                None => {
+                    fs::rename(path, &rotated_path)?;
+                    File::create(path)?;
                 }
-            });
+            }
 
             info!(
-                path = path.display(),
-                rotated = rotated_path.display(),
+                path = %path.display(),
+                rotated = %rotated_path.display(),
                 "Rotated log file"
             );
+
+            // Compress rotated log
+            self.compress(rotated_path).await;
         }
 
-        Ok(())
+        Ok(rotate)
     }
 
-    async fn compress_log(&self, path: &Path) -> anyhow::Result<()> {
-        let input = fs::read(path)?;
-        let compressed_path = path.with_extension("log.gz");
-        
-        // Compress using gzip
-        let mut encoder = flate2::write::GzEncoder::new(
-            Vec::new(),
-            flate2::Compression::default()
-        );
-        std::io::copy(&mut &input[..], &mut encoder)?;
-        let compressed = encoder.finish()?;
-        
-        // Write compressed file and remove original
-        fs::write(&compressed_path, compressed)?;
-        fs::remove_file(path)?;
+    /// Compress a rotated log, leaving it uncompressed on failure to be
+    /// retried on the next run.
+    async fn compress(&self, path: PathBuf) {
+        let compression = self.compression;
+        let result = tokio::task::spawn_blocking(move || {
+            let compressed_path = compression.compress(&path)?;
+            info!(
+                original = %path.display(),
+                compressed = %compressed_path.display(),
+                "Compressed rotated log"
+            );
+            anyhow::Ok(())
+        })
+        .await;
+        match result {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => error!("Failed to compress rotated log: {}", e),
+            Err(e) => error!("Failed to compress rotated log: {}", e),
+        }
+    }
 
-        info!(
-            original = path.display(),
-            compressed = compressed_path.display(),
-            "Compressed rotated log"
-        );
+    /// Remove partial files of compressions that were cut short, and compress
+    /// rotated logs left uncompressed.
+    async fn recover_rotated_logs(&self) -> anyhow::Result<()> {
+        for name in ROTATED_LOGS {
+            let partial = self.log_dir.join(format!(".{name}.*.partial"));
+            for path in glob(&partial.to_string_lossy())?.flatten() {
+                fs::remove_file(&path)?;
+                info!(path = %path.display(), "Removed partially compressed log");
+            }
 
+            let rotated = self.log_dir.join(format!("{name}.*"));
+            for path in glob(&rotated.to_string_lossy())?.flatten() {
+                let compressed = path.extension().is_some_and(|ext| ext == "gz" || ext == "zst");
+                if !compressed {
+                    self.compress(path).await;
+                }
+            }
+        }
         Ok(())
     }
 
@@ -109,22 +458,24 @@ impl LogRotator {
         
         for pattern in &["*.log.*", "*.log.gz"] {
             let glob_pattern = self.log_dir.join(pattern);
-            for entry in glob(glob_pattern.to_str().unwrap())? {
-                if let Ok(path) = entry {
-                    if let Some(timestamp_str) = path.file_name()
-                        .and_then(|n| n.to_str())
-                        .and_then(|n| n.split('.').nth(2))
-                    {
-                        if let Ok(timestamp) = DateTime::parse_from_str(
-                            &format!("{}+0000", timestamp_str),
-                            "%Y%m%d_%H%M%S%z"
-                        ) {
-                            if timestamp < cutoff {
-                                fs::remove_file(&path)?;
-                                info!(path = path.display(), "Removed old log file");
-                            }
+            for path in glob(glob_pattern.to_str().unwrap())?.flatten() {
+                let Some(rotated) = rotated_at(&path) else {
+                    continue;
+                };
+                if rotated >= cutoff {
+                    continue;
+                }
+                match &self.archive {
+                    // Kept until the archive takes it, to retry on the next run
+                    Some(target) => {
+                        if let Err(e) = xpra_archive::archive(target, &self.log_dir, &path, rotated).await {
+                            error!(path = %path.display(), "Failed to archive old log file: {:#}", e);
                         }
                     }
+                    None => {
+                        fs::remove_file(&path)?;
+                        info!(path = %path.display(), "Removed old log file");
+                    }
                 }
             }
         }
@@ -132,3 +483,98 @@ impl LogRotator {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compress_and_read_back() {
+        let dir = std::env::temp_dir().join(format!("sshx-rotation-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let content = "{\"timestamp\":\"2024-05-01T10:00:00Z\"}\n".repeat(1000);
+
+        for (algorithm, level) in [(CompressionAlgorithm::Gzip, None), (CompressionAlgorithm::Zstd, Some(19))] {
+            let path = dir.join("history.log.20240501_120000");
+            fs::write(&path, &content).unwrap();
+            let compressed = LogCompression { algorithm, level }.compress(&path).unwrap();
+            assert_eq!(
+                compressed.file_name().unwrap().to_string_lossy(),
+                format!("history.log.20240501_120000.{}", algorithm.extension()),
+            );
+            assert!(!path.exists());
+
+            let mut read = String::new();
+            open_log_file(&compressed).unwrap().read_to_string(&mut read).unwrap();
+            assert_eq!(read, content);
+            fs::remove_file(compressed).unwrap();
+        }
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn test_recover_interrupted_rotation() {
+        let dir = std::env::temp_dir().join(format!("sshx-rotation-recover-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        // A crash after renaming the log and while compressing another
+        let rotated = Utc::now().format("%Y%m%d_%H%M%S");
+        fs::write(dir.join(format!("history.log.{rotated}")), "{}\n").unwrap();
+        fs::write(dir.join(format!(".metrics.log.{rotated}.partial")), "").unwrap();
+        fs::write(dir.join(format!("metrics.log.{rotated}")), "{}\n").unwrap();
+
+        LogRotator::new(dir.clone()).rotate_logs().await.unwrap();
+        let mut files: Vec<String> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        files.sort();
+        assert_eq!(files, [format!("history.log.{rotated}.gz"), format!("metrics.log.{rotated}.gz")]);
+
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn test_disk_pressure_prunes_oldest() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        fs::create_dir_all(dir.join("sessions")).unwrap();
+        fs::write(dir.join("history.log.20240501_120000.gz"), vec![0; 1000]).unwrap();
+        fs::write(dir.join("alerts.log.20240502_120000.gz"), vec![0; 1000]).unwrap();
+        fs::write(dir.join("metrics.log.20240503_120000.gz"), vec![0; 1000]).unwrap();
+        fs::write(dir.join("history.log.20240504_120000.gz"), vec![0; 1000]).unwrap();
+        fs::write(dir.join("metrics.log"), vec![b'\n'; 500]).unwrap();
+        // Output of a running session, written just now
+        fs::write(dir.join("sessions").join("local-100.log"), vec![b'\n'; 400]).unwrap();
+        assert!(DiskLimits::default().exceeded(&DiskUsage::of(dir).unwrap()).is_none());
+
+        let logger = XpraLogger::new(dir.to_path_buf());
+        let limits = DiskLimits { max_log_bytes: 2500, ..DiskLimits::default() };
+        let rotator = LogRotator::new(dir.to_path_buf()).with_logger(logger).with_disk_limits(limits);
+        rotator.check_disk_usage().await.unwrap();
+
+        // The oldest logs were removed until the rest fit, and the small
+        // current log left alone
+        assert!(!dir.join("history.log.20240501_120000.gz").exists());
+        assert!(!dir.join("alerts.log.20240502_120000.gz").exists());
+        assert!(!dir.join("metrics.log.20240503_120000.gz").exists());
+        assert!(dir.join("history.log.20240504_120000.gz").exists());
+        assert!(dir.join("sessions").join("local-100.log").exists());
+        assert!(dir.join("metrics.log").exists());
+        let alerts = fs::read_to_string(dir.join("alerts.log")).unwrap();
+        assert!(alerts.contains("disk_pressure") && alerts.contains("removed 3 old logs"), "{alerts}");
+
+        // Within the limits, the next check does nothing
+        rotator.check_disk_usage().await.unwrap();
+        assert_eq!(fs::read_to_string(dir.join("alerts.log")).unwrap(), alerts);
+
+        // Past them with nothing left to remove but session output, that
+        // goes before a small current log is rotated
+        let limits = DiskLimits { max_log_bytes: 100, ..DiskLimits::default() };
+        let rotator = rotator.with_disk_limits(limits);
+        rotator.check_disk_usage().await.unwrap();
+        assert!(!dir.join("sessions").join("local-100.log").exists());
+        assert!(dir.join("metrics.log").exists());
+    }
+}
@@ -0,0 +1,138 @@
+//! Schema versions of the metrics and history logs.
+//!
+//! Every line is written with the `schema_version` it follows. Lines of an
+//! older version are migrated to the current one when read, so changing a
+//! field does not break reading the logs retained from before the change:
+//!
+//! - 1: lines written before the logs were versioned, without the field.
+//!   Metrics sessions have no `bytes_in` and `bytes_out`.
+//! - 2: the current version.
+
+use anyhow::{bail, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+/// Version of the lines written to the metrics and history logs.
+pub const SCHEMA_VERSION: u32 = 2;
+
+/// Upgrades a line from the version it is listed with to the next.
+type Migration = fn(&mut Map<String, Value>);
+
+/// A line of a log, tagged with the schema version it follows.
+#[derive(Serialize)]
+struct Versioned<'a, T> {
+    schema_version: u32,
+    #[serde(flatten)]
+    entry: &'a T,
+}
+
+/// Serialize `entry` as a line of the current schema version.
+pub fn to_line<T: Serialize>(entry: &T) -> serde_json::Result<String> {
+    serde_json::to_string(&Versioned { schema_version: SCHEMA_VERSION, entry })
+}
+
+/// Log whose lines are read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogSchema {
+    /// `metrics.log`, the periodic metrics and sessions
+    Metrics,
+    /// `history.log`, the session events
+    History,
+}
+
+impl LogSchema {
+    /// Migrations of the lines of this log, by the version they upgrade.
+    fn migrations(&self) -> &'static [(u32, Migration)] {
+        match self {
+            LogSchema::Metrics => &[(1, metrics_v1_traffic)],
+            // Fields the history log gained before it was versioned all
+            // have defaults
+            LogSchema::History => &[],
+        }
+    }
+
+    /// Parse a line of this log written in any version up to the current
+    /// one, migrating it to the current version first.
+    pub fn parse<T: DeserializeOwned>(&self, line: &str) -> Result<T> {
+        let Value::Object(mut fields) = serde_json::from_str(line)? else {
+            bail!("log line is not a JSON object");
+        };
+        let version = match fields.get("schema_version") {
+            None => 1,
+            Some(version) => match version.as_u64().and_then(|version| u32::try_from(version).ok()) {
+                Some(version) => version,
+                None => bail!("invalid schema version {version}"),
+            },
+        };
+        if version > SCHEMA_VERSION {
+            bail!("log line of schema version {version}, newer than the {SCHEMA_VERSION} this version reads");
+        }
+        for (_, migrate) in self.migrations().iter().filter(|(from, _)| *from >= version) {
+            migrate(&mut fields);
+        }
+        Ok(serde_json::from_value(Value::Object(fields))?)
+    }
+}
+
+/// Version 1 metrics did not count the traffic of each session.
+fn metrics_v1_traffic(fields: &mut Map<String, Value>) {
+    let Some(Value::Array(sessions)) = fields.get_mut("sessions") else {
+        return;
+    };
+    for session in sessions.iter_mut().filter_map(Value::as_object_mut) {
+        session.entry("bytes_in").or_insert(Value::from(0));
+        session.entry("bytes_out").or_insert(Value::from(0));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::xpra_logger::{LogEntry, SessionEvent, SessionEventType};
+
+    /// Fixture logs written in schema `version`.
+    fn fixture(version: u32, name: &str) -> String {
+        let path = format!("{}/tests/fixtures/log_schema/v{version}/{name}", env!("CARGO_MANIFEST_DIR"));
+        std::fs::read_to_string(path).unwrap()
+    }
+
+    #[test]
+    fn test_reads_every_version() {
+        for version in 1..=SCHEMA_VERSION {
+            let events: Vec<SessionEvent> =
+                fixture(version, "history.log").lines().map(|line| LogSchema::History.parse(line).unwrap()).collect();
+            assert!(matches!(events[0].event_type, SessionEventType::Created), "v{version}");
+            assert_eq!(events[0].user, "alice", "v{version}");
+
+            let entries: Vec<LogEntry> =
+                fixture(version, "metrics.log").lines().map(|line| LogSchema::Metrics.parse(line).unwrap()).collect();
+            assert_eq!(entries[0].metrics.active_sessions, 1, "v{version}");
+        }
+    }
+
+    #[test]
+    fn test_migrates_metrics_traffic() {
+        let line = fixture(1, "metrics.log");
+        let entry: Value = LogSchema::Metrics.parse(line.lines().next().unwrap()).unwrap();
+        assert_eq!(entry["sessions"][0]["bytes_in"], 0);
+        assert_eq!(entry["sessions"][0]["user"], "alice");
+    }
+
+    #[test]
+    fn test_round_trip_and_newer_versions() {
+        let line = fixture(SCHEMA_VERSION, "history.log");
+        let event: SessionEvent = LogSchema::History.parse(line.lines().next().unwrap()).unwrap();
+        let written = to_line(&event).unwrap();
+        assert!(written.starts_with(&format!("{{\"schema_version\":{SCHEMA_VERSION},")));
+        let reread: SessionEvent = LogSchema::History.parse(&written).unwrap();
+        assert_eq!(reread.session_id, event.session_id);
+
+        let newer = written.replacen(
+            &format!("\"schema_version\":{SCHEMA_VERSION}"),
+            &format!("\"schema_version\":{}", SCHEMA_VERSION + 1),
+            1,
+        );
+        assert!(LogSchema::History.parse::<SessionEvent>(&newer).is_err());
+    }
+}
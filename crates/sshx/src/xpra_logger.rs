@@ -1,86 +1,203 @@
+//! Periodic metrics log and history log of session events.
+
+use std::collections::VecDeque;
 use std::fs::{File, OpenOptions};
-use std::io::Write;
-use std::path::PathBuf;
-use std::sync::Arc;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Weak};
 use chrono::{DateTime, Utc};
-use serde::Serialize;
-use tokio::sync::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, Mutex};
 use tokio::time::{self, Duration};
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
-use crate::xpra_metrics::METRICS;
-use crate::xpra_monitor::SESSION_MONITOR;
+use crate::xpra_archive::ArchiveTarget;
+use crate::xpra_audit::{self, RedactReport};
+use crate::xpra_context::XpraContext;
+use crate::xpra_devices::Device;
+use crate::xpra_inspect::TrafficCounters;
+use crate::xpra_labels::Labels;
+use crate::xpra_log_analyzer::{log_files, visit_lines_rev, LogAnalyzer};
+use crate::xpra_log_schema::{self, LogSchema};
+use crate::xpra_metrics::LoggingMetrics;
+use crate::xpra_quota::UsageAccounting;
+use crate::xpra_session_id::ParentShell;
+use crate::xpra_session_state::SessionState;
 
-#[derive(Debug, Serialize)]
-struct LogEntry {
-    timestamp: DateTime<Utc>,
-    metrics: MetricsLog,
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct LogEntry {
+    pub(crate) timestamp: DateTime<Utc>,
+    pub(crate) metrics: MetricsLog,
     sessions: Vec<SessionLog>,
 }
 
-#[derive(Debug, Serialize)]
-struct MetricsLog {
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct MetricsLog {
     total_sessions: u64,
-    active_sessions: u64,
+    pub(crate) active_sessions: u64,
     failed_sessions: u64,
     idle_terminations: u64,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Deserialize)]
 struct SessionLog {
     session_id: String,
     user: String,
     display: u16,
     idle_seconds: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+}
+
+/// Most lines kept in memory per log while its file cannot be written.
+const MAX_BUFFERED_LINES: usize = 10_000;
+
+/// Session events kept for subscribers that fall behind.
+const EVENT_CHANNEL_CAPACITY: usize = 1024;
+
+/// Append-only log file, opened on first write. While the file cannot be
+/// written, lines are kept in a bounded in-memory buffer and written out
+/// once it can be.
+#[derive(Debug)]
+struct LogSink {
+    path: PathBuf,
+    file: Option<File>,
+    buffer: VecDeque<String>,
+    /// Whether writing is currently failing, so it is only warned about once
+    degraded: bool,
+    /// Hash of the last line written, for logs chaining their lines, read
+    /// from the log on the first write
+    last_hash: Option<String>,
+    /// Number of the last event written, for logs numbering their events,
+    /// read from the log directory on the first write
+    last_seq: Option<u64>,
+    metrics: Arc<LoggingMetrics>,
+}
+
+impl LogSink {
+    fn new(path: PathBuf, metrics: Arc<LoggingMetrics>) -> Self {
+        Self { path, file: None, buffer: VecDeque::new(), degraded: false, last_hash: None, last_seq: None, metrics }
+    }
+
+    fn write_line(&mut self, line: String) {
+        if self.buffer.len() >= MAX_BUFFERED_LINES {
+            self.buffer.pop_front();
+            self.metrics.event_dropped();
+        }
+        self.buffer.push_back(line);
+
+        match self.flush() {
+            Ok(()) if self.degraded => {
+                info!(path = %self.path.display(), "Log is writable again, flushed buffered lines");
+                self.degraded = false;
+                self.metrics.log_recovered();
+            }
+            Ok(()) => {}
+            Err(e) => {
+                self.file = None;
+                self.metrics.write_failed();
+                if !self.degraded {
+                    warn!(path = %self.path.display(), "Cannot write log, buffering in memory: {}", e);
+                    self.degraded = true;
+                    self.metrics.log_failing();
+                }
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.file.is_none() {
+            if let Some(parent) = self.path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            self.file = Some(OpenOptions::new().create(true).append(true).open(&self.path)?);
+        }
+        let Some(file) = self.file.as_mut() else {
+            return Ok(());
+        };
+        while let Some(line) = self.buffer.front() {
+            writeln!(file, "{}", line)?;
+            self.metrics.event_written(line.len() as u64 + 1);
+            self.buffer.pop_front();
+        }
+        Ok(())
+    }
 }
 
+/// Writer of the metrics log and the history log.
 #[derive(Debug, Clone)]
 pub struct XpraLogger {
     log_dir: PathBuf,
-    metrics_file: Arc<Mutex<File>>,
-    history_file: Arc<Mutex<File>>,
+    metrics_log: Arc<Mutex<LogSink>>,
+    history_log: Arc<Mutex<LogSink>>,
+    alerts_log: Arc<Mutex<LogSink>>,
+    /// Session events as they are logged, for subscribers
+    events: broadcast::Sender<SessionEvent>,
+    /// Alerts as they are logged, for subscribers
+    alerts: broadcast::Sender<AlertEvent>,
+    metrics: Arc<LoggingMetrics>,
+    /// Accounting charged with the session events logged
+    usage: Option<UsageAccounting>,
 }
 
 impl XpraLogger {
-    pub fn new(log_dir: PathBuf) -> anyhow::Result<Self> {
-        std::fs::create_dir_all(&log_dir)?;
-        
-        let metrics_path = log_dir.join("metrics.log");
-        let history_path = log_dir.join("history.log");
-        
-        let metrics_file = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(&metrics_path)?;
-            
-        let history_file = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(&history_path)?;
-
-        Ok(Self {
+    /// Create a logger writing to `log_dir`. Nothing is created until the
+    /// first line is logged, and an unwritable directory only degrades
+    /// logging to memory.
+    pub fn new(log_dir: PathBuf) -> Self {
+        Self::with_metrics(log_dir, Arc::default())
+    }
+
+    /// Create a logger writing to `log_dir` and counting what it writes in
+    /// `metrics`.
+    pub fn with_metrics(log_dir: PathBuf, metrics: Arc<LoggingMetrics>) -> Self {
+        let sink = |name: &str| Arc::new(Mutex::new(LogSink::new(log_dir.join(name), metrics.clone())));
+        Self {
+            metrics_log: sink("metrics.log"),
+            history_log: sink("history.log"),
+            alerts_log: sink("alerts.log"),
+            events: broadcast::channel(EVENT_CHANNEL_CAPACITY).0,
+            alerts: broadcast::channel(EVENT_CHANNEL_CAPACITY).0,
             log_dir,
-            metrics_file: Arc::new(Mutex::new(metrics_file)),
-            history_file: Arc::new(Mutex::new(history_file)),
-        })
+            metrics,
+            usage: None,
+        }
+    }
+
+    /// Record the session events logged into `usage`.
+    pub fn with_usage(mut self, usage: UsageAccounting) -> Self {
+        self.usage = Some(usage);
+        self
     }
 
-    pub fn start_logging(&self) {
+    /// Directory holding the metrics and history logs
+    pub fn log_dir(&self) -> &Path {
+        &self.log_dir
+    }
+
+    /// Periodically log the metrics and sessions of `context`, until the
+    /// context is dropped.
+    pub fn start_logging(&self, context: &Arc<XpraContext>) {
         let logger = self.clone();
+        let context = Arc::downgrade(context);
         tokio::spawn(async move {
             let mut interval = time::interval(Duration::from_secs(300)); // Log every 5 minutes
             loop {
                 interval.tick().await;
-                if let Err(e) = logger.log_metrics().await {
+                let Some(context) = Weak::upgrade(&context) else {
+                    break;
+                };
+                if let Err(e) = logger.log_metrics(&context).await {
                     error!("Failed to log metrics: {}", e);
                 }
             }
         });
     }
 
-    async fn log_metrics(&self) -> anyhow::Result<()> {
-        let metrics = METRICS.get_metrics();
-        let sessions = SESSION_MONITOR.get_all_sessions().await;
+    /// Append the current metrics and sessions of `context` to the metrics
+    /// log.
+    pub(crate) async fn log_metrics(&self, context: &XpraContext) -> anyhow::Result<()> {
+        let metrics = context.metrics.get_metrics();
 
         let entry = LogEntry {
             timestamp: Utc::now(),
@@ -90,50 +207,472 @@ impl XpraLogger {
                 failed_sessions: metrics.failed_sessions,
                 idle_terminations: metrics.idle_terminations,
             },
-            sessions: sessions.iter().map(|(id, info)| SessionLog {
-                session_id: id.clone(),
-                user: info.user.clone(),
-                display: info.display,
-                idle_seconds: info.last_activity.elapsed().as_secs(),
-            }).collect(),
+            sessions: context.monitor.collect_sessions(|id, info| {
+                let (bytes_in, bytes_out) = info.traffic.totals();
+                Some(SessionLog {
+                    session_id: id.to_string(),
+                    user: info.user.clone(),
+                    display: info.display,
+                    idle_seconds: info.last_activity.elapsed().as_secs(),
+                    bytes_in,
+                    bytes_out,
+                })
+            }),
         };
 
         // Log to metrics file
-        let mut metrics_file = self.metrics_file.lock().await;
-        serde_json::to_writer(&mut *metrics_file, &entry)?;
-        writeln!(metrics_file)?;
+        let line = xpra_log_schema::to_line(&entry)?;
+        self.metrics_log.lock().await.write_line(line);
+
+        Ok(())
+    }
+
+    /// Append an event to the history log, numbered after the event before
+    /// it and chained to its line by its hash.
+    pub async fn log_session_event(&self, mut event: SessionEvent) -> anyhow::Result<()> {
+        if let Some(usage) = &self.usage {
+            usage.record(&event).await;
+        }
+
+        let mut sink = self.history_log.lock().await;
+        let seq = sink.last_seq.unwrap_or_else(|| xpra_audit::last_seq(&self.log_dir)) + 1;
+        // Saved before the event is written, so a crash in between leaves a
+        // gap in the numbers rather than a number used twice. The log stays
+        // locked meanwhile, keeping the numbers in order.
+        let log_dir = self.log_dir.clone();
+        let saved = tokio::task::spawn_blocking(move || xpra_audit::save_seq(&log_dir, seq))
+            .await
+            .unwrap_or_else(|e| Err(io::Error::other(e)));
+        if let Err(e) = saved {
+            if !sink.degraded {
+                warn!(log_dir = %self.log_dir.display(), "Cannot save the history event number: {}", e);
+            }
+        }
+        sink.last_seq = Some(seq);
+        event.seq = Some(seq);
+        let prev = match &sink.last_hash {
+            Some(hash) => hash.clone(),
+            None => xpra_audit::last_hash(&self.log_dir),
+        };
+        let (line, hash) = xpra_audit::seal(&prev, &event)?;
+        sink.last_hash = Some(hash);
+        sink.write_line(line);
+        // Sent while holding the log, so subscribers see events in the
+        // order they were logged
+        let _ = self.events.send(event);
+        Ok(())
+    }
+
+    /// Receive the session events logged from now on. A subscriber that
+    /// falls behind by more than 1024 events misses the oldest of them.
+    pub fn subscribe(&self) -> broadcast::Receiver<SessionEvent> {
+        self.events.subscribe()
+    }
+
+    /// Replace every event of `user` in the history log, its rotated files
+    /// and the ones archived to `archive` with a tombstone, for a data
+    /// deletion request. No event is logged and the log is not rotated
+    /// meanwhile.
+    pub async fn redact_history(&self, user: &str, archive: Option<&ArchiveTarget>) -> anyhow::Result<RedactReport> {
+        let mut sink = self.history_log.lock().await;
+        if let Err(e) = sink.flush() {
+            warn!(path = %sink.path.display(), "Redacting history with lines still buffered: {}", e);
+        }
+        let result = {
+            let (log_dir, user) = (self.log_dir.clone(), user.to_string());
+            tokio::task::spawn_blocking(move || xpra_audit::redact_files(&log_dir, &user)).await?
+        };
+        // The current file may have been replaced
+        sink.file = None;
+        let mut report = result?;
+        for line in sink.buffer.iter_mut() {
+            if let Some(tombstone) = xpra_audit::redact_line(line, user) {
+                *line = tombstone;
+                report.events += 1;
+            }
+        }
+        // Archived files are no longer written
+        drop(sink);
+        if let Some(target) = archive {
+            report.merge(xpra_audit::redact_archived(target, &self.log_dir, user).await?);
+        }
+        Ok(report)
+    }
 
+    /// Append an alert about the host to the alerts log.
+    pub async fn log_alert(&self, alert: AlertEvent) -> anyhow::Result<()> {
+        let line = serde_json::to_string(&alert)?;
+        self.alerts_log.lock().await.write_line(line);
+        let _ = self.alerts.send(alert);
         Ok(())
     }
 
-    pub async fn log_session_event(&self, event: SessionEvent) -> anyhow::Result<()> {
-        let mut history_file = self.history_file.lock().await;
-        serde_json::to_writer(&mut *history_file, &event)?;
-        writeln!(history_file)?;
+    /// Receive the alerts logged from now on, like [`Self::subscribe`].
+    pub fn subscribe_alerts(&self) -> broadcast::Receiver<AlertEvent> {
+        self.alerts.subscribe()
+    }
+
+    /// Rename the log at `path` to `rotated` for rotation. For the metrics
+    /// and history logs this happens while no line is being written, after
+    /// writing out buffered lines, and the next line starts a new file at
+    /// `path` instead of going to the renamed one.
+    pub async fn rotate_file(&self, path: &Path, rotated: &Path) -> io::Result<()> {
+        for sink in [&self.metrics_log, &self.history_log, &self.alerts_log] {
+            let mut sink = sink.lock().await;
+            if sink.path != path {
+                continue;
+            }
+            // Buffered lines are older than the rotation, so they belong to
+            // the rotated file
+            if let Err(e) = sink.flush() {
+                warn!(path = %path.display(), "Rotating log with lines still buffered: {}", e);
+            }
+            std::fs::rename(path, rotated)?;
+            sink.file = None;
+            self.metrics.rotated();
+            return Ok(());
+        }
+        std::fs::rename(path, rotated)?;
+        self.metrics.rotated();
         Ok(())
     }
+
+    /// Events of the session or user `key`, oldest first, only from `since`
+    /// on if given. Besides the history log and its rotated files, this
+    /// includes events still buffered while the log cannot be written.
+    pub async fn history(&self, key: &str, since: Option<DateTime<Utc>>) -> anyhow::Result<Vec<SessionEvent>> {
+        let mut events = LogAnalyzer::new(self.log_dir.clone()).history(key, since).await?;
+        let buffered: Vec<String> = self.history_log.lock().await.buffer.iter().cloned().collect();
+        events.extend(
+            buffered
+                .iter()
+                .filter_map(|line| LogSchema::History.parse::<SessionEvent>(line).ok())
+                .filter(|event| event.concerns(key) && since.is_none_or(|since| event.timestamp >= since)),
+        );
+        Ok(events)
+    }
+
+    /// The last `count` events of the session `session_id`, oldest first.
+    /// The history log is read backwards from its end, only as far back as
+    /// needed.
+    pub async fn recent_history(&self, session_id: &str, count: usize) -> anyhow::Result<Vec<SessionEvent>> {
+        let parse = |line: &str, session_id: &str| {
+            // Only lines naming the session are worth parsing
+            line.contains(session_id)
+                .then(|| LogSchema::History.parse::<SessionEvent>(line).ok())
+                .flatten()
+                .filter(|event| event.session_id == session_id)
+        };
+        // Lines still buffered are newer than those written
+        let mut events: Vec<SessionEvent> = {
+            let history_log = self.history_log.lock().await;
+            history_log.buffer.iter().rev().filter_map(|line| parse(line, session_id)).take(count).collect()
+        };
+        let (log_dir, session_id) = (self.log_dir.clone(), session_id.to_string());
+        tokio::task::spawn_blocking(move || {
+            for path in log_files(&log_dir, "history.log")?.iter().rev() {
+                if events.len() >= count {
+                    break;
+                }
+                visit_lines_rev(path, |line| {
+                    events.extend(parse(line, &session_id));
+                    events.len() < count
+                })?;
+            }
+            events.reverse();
+            Ok(events)
+        })
+        .await?
+    }
 }
 
-#[derive(Debug, Serialize)]
+/// An entry of the history log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SessionEvent {
+    /// When the event happened.
     pub timestamp: DateTime<Utc>,
+    /// Number of the event in the host's history log, one more than the
+    /// event logged before it. Set when the event is logged; none for
+    /// events logged before they were numbered
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+    /// What happened to the session.
     pub event_type: SessionEventType,
+    /// Id of the session.
     pub session_id: String,
+    /// User owning the session.
     pub user: String,
+    /// Display number of the session, or 0 if none was allocated.
     pub display: u16,
+    /// Labels of the session
+    #[serde(default, skip_serializing_if = "Labels::is_empty")]
+    pub labels: Labels,
+    /// State of the session after the event, for registered sessions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state: Option<SessionState>,
+    /// Why the session ended, for `Terminated` and `Failed` events
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<TerminationReason>,
+    /// Crash bundle collected for a `Failed` event whose xpra exited with an
+    /// error
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crash_bundle: Option<PathBuf>,
+    /// Name given to the session, for `Renamed` events
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Description given to the session, for `Renamed` events
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// sshx session and shell the session was started from, for `Created`
+    /// events
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent: Option<ParentShell>,
+    /// Device of the client, for `DeviceAttached` and `DeviceDetached`
+    /// events
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device: Option<Device>,
+    /// Bytes forwarded over the whole session, for `Terminated`,
+    /// `IdleTimeout` and `Failed` events
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub traffic: Option<TrafficCounters>,
+    /// Host running the display, for `Created` events
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+}
+
+impl SessionEvent {
+    /// Whether the event is about the session or the user named `key`.
+    pub fn concerns(&self, key: &str) -> bool {
+        self.session_id == key || self.user == key
+    }
+}
+
+/// An entry of the alerts log, about a problem of the host rather than of a
+/// session.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AlertEvent {
+    /// When the problem was found
+    pub timestamp: DateTime<Utc>,
+    /// What is wrong
+    pub kind: AlertKind,
+    /// What was found and done about it
+    pub message: String,
 }
 
-#[derive(Debug, Serialize)]
+/// Kind of an alert.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertKind {
+    /// The logs ran short of disk space, so they were rotated and pruned
+    DiskPressure,
+}
+
+/// Kind of a session event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum SessionEventType {
+    /// Session started.
     Created,
+    /// Session ended normally.
     Terminated,
+    /// Session failed to start or crashed.
     Failed,
+    /// Session terminated for being idle.
+    IdleTimeout,
+    /// Session start rejected by the rate limiter; no display is allocated.
+    RateLimited,
+    /// Session start refused by the admission policy; no display is allocated.
+    PolicyDenied,
+    /// Idle session checkpointed to disk; the session stays open.
+    Hibernated,
+    /// Hibernated session restored on user input.
+    Resumed,
+    /// Session moved to the state given in the event.
+    StateChanged,
+    /// Session given the name and description in the event.
+    Renamed,
+    /// Device of the client given in the event attached to the session.
+    DeviceAttached,
+    /// Device of the client given in the event detached from the session.
+    DeviceDetached,
+    /// User acknowledged the notice that the session is recorded.
+    RecordingAcknowledged,
+}
+
+/// Why a session ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TerminationReason {
+    /// The client went away or the user logged out of the desktop
+    ClientDisconnect,
+    /// Stopped by an administrator
+    AdminKill,
+    /// Terminated at the deadline of a drain
+    Drain,
+    /// Headless session idle past its timeout
     IdleTimeout,
+    /// The xpra process exited unexpectedly
+    ProcessCrash,
+    /// The forwarder ended with an error
+    Error,
+}
+
+impl TerminationReason {
+    /// Name of the reason, as written to the history log
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TerminationReason::ClientDisconnect => "client_disconnect",
+            TerminationReason::AdminKill => "admin_kill",
+            TerminationReason::Drain => "drain",
+            TerminationReason::IdleTimeout => "idle_timeout",
+            TerminationReason::ProcessCrash => "process_crash",
+            TerminationReason::Error => "error",
+        }
+    }
+
+    /// Whether sessions ending for this reason count as failed
+    pub fn is_failure(&self) -> bool {
+        matches!(self, TerminationReason::ProcessCrash | TerminationReason::Error)
+    }
+}
+
+impl std::fmt::Display for TerminationReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
 }
 
-// Global logger instance
-lazy_static::lazy_static! {
-    pub static ref LOGGER: XpraLogger = XpraLogger::new(
-        PathBuf::from("/var/log/sshx/xpra")
-    ).expect("Failed to initialize Xpra logger");
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_sink_buffers_until_writable() {
+        let dir = std::env::temp_dir().join(format!("sshx-logger-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        // A file where the log directory should be makes it unwritable
+        std::fs::write(&dir, b"").unwrap();
+
+        let metrics = Arc::new(LoggingMetrics::default());
+        let mut sink = LogSink::new(dir.join("history.log"), metrics.clone());
+        sink.write_line("first".to_string());
+        sink.write_line("second".to_string());
+        assert!(sink.degraded);
+        assert_eq!(sink.buffer.len(), 2);
+        assert_eq!(metrics.snapshot().write_errors, 2);
+        assert!(metrics.snapshot().is_failing());
+
+        std::fs::remove_file(&dir).unwrap();
+        sink.write_line("third".to_string());
+        assert!(!sink.degraded);
+        assert!(sink.buffer.is_empty());
+        assert_eq!(std::fs::read_to_string(dir.join("history.log")).unwrap(), "first\nsecond\nthird\n");
+        let snapshot = metrics.snapshot();
+        assert_eq!((snapshot.events_written, snapshot.bytes_written), (3, 19));
+        assert_eq!(snapshot.dropped_events, 0);
+        // Recovered failures stay counted but are no longer failing
+        assert_eq!(snapshot.write_errors, 2);
+        assert!(!snapshot.is_failing());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_rotate_file_hands_off_writer() {
+        let dir = std::env::temp_dir().join(format!("sshx-logger-rotate-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let logger = XpraLogger::new(dir.clone());
+        let (path, rotated) = (dir.join("history.log"), dir.join("history.log.20240501_120000"));
+
+        logger.history_log.lock().await.write_line("before".to_string());
+        logger.rotate_file(&path, &rotated).await.unwrap();
+        logger.history_log.lock().await.write_line("after".to_string());
+        assert_eq!(std::fs::read_to_string(&rotated).unwrap(), "before\n");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "after\n");
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_history_is_chained_across_restarts() {
+        let dir = std::env::temp_dir().join(format!("sshx-logger-chain-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let event = |session_id: &str| SessionEvent {
+            timestamp: Utc::now(),
+            seq: None,
+            event_type: SessionEventType::Created,
+            session_id: session_id.to_string(),
+            user: "alice".to_string(),
+            display: 10,
+            labels: Labels::new(),
+            state: None,
+            reason: None,
+            crash_bundle: None,
+            name: None,
+            description: None,
+            parent: None,
+            device: None,
+            traffic: None,
+            host: None,
+        };
+
+        let logger = XpraLogger::new(dir.clone());
+        logger.log_session_event(event("s1")).await.unwrap();
+        logger.rotate_file(&dir.join("history.log"), &dir.join("history.log.20240501_120000")).await.unwrap();
+        logger.log_session_event(event("s2")).await.unwrap();
+        // A new logger continues the chain of the log it finds
+        XpraLogger::new(dir.clone()).log_session_event(event("s3")).await.unwrap();
+
+        let report = crate::xpra_audit::HistoryAudit::new(dir.clone()).verify().await.unwrap();
+        assert!(report.is_intact(), "{:?}", report.issues);
+        assert_eq!((report.files, report.hashed), (2, 3));
+        let history = logger.history("alice", None).await.unwrap();
+        assert_eq!(history.iter().map(|event| event.seq).collect::<Vec<_>>(), [Some(1), Some(2), Some(3)]);
+
+        // Numbering goes on after the logs were pruned
+        for path in crate::xpra_log_analyzer::log_files(&dir, "history.log").unwrap() {
+            std::fs::remove_file(path).unwrap();
+        }
+        XpraLogger::new(dir.clone()).log_session_event(event("s4")).await.unwrap();
+        assert_eq!(logger.history("alice", None).await.unwrap()[0].seq, Some(4));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_recent_history_reads_back_across_rotations() {
+        let dir = tempfile::tempdir().unwrap();
+        let logger = XpraLogger::new(dir.path().to_path_buf());
+        let event = |session_id: &str, display| SessionEvent {
+            timestamp: Utc::now(),
+            seq: None,
+            event_type: SessionEventType::StateChanged,
+            session_id: session_id.to_string(),
+            user: "alice".to_string(),
+            display,
+            labels: Labels::new(),
+            state: None,
+            reason: None,
+            crash_bundle: None,
+            name: None,
+            description: None,
+            parent: None,
+            device: None,
+            traffic: None,
+            host: None,
+        };
+
+        for display in 0..3 {
+            logger.log_session_event(event("a", display)).await.unwrap();
+        }
+        let rotated = dir.path().join("history.log.20240501_120000");
+        logger.rotate_file(&dir.path().join("history.log"), &rotated).await.unwrap();
+        logger.log_session_event(event("b", 0)).await.unwrap();
+        logger.log_session_event(event("a", 3)).await.unwrap();
+
+        let displays = |events: Vec<SessionEvent>| events.iter().map(|event| event.display).collect::<Vec<_>>();
+        assert_eq!(displays(logger.recent_history("a", 2).await.unwrap()), [2, 3]);
+        assert_eq!(displays(logger.recent_history("a", 20).await.unwrap()), [0, 1, 2, 3]);
+        assert_eq!(displays(logger.recent_history("b", 20).await.unwrap()), [0]);
+        assert!(logger.recent_history("c", 20).await.unwrap().is_empty());
+    }
 }
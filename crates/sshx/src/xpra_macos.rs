@@ -0,0 +1,98 @@
+//! macOS desktops shown through the built-in screen sharing.
+//!
+//! Like the RDP desktops of Windows hosts, the display of a macOS desktop
+//! runs a VNC viewer in full screen, connected to the Screen Sharing of a
+//! Mac, and xpra streams it through the usual forwarder. A Mac has no
+//! displays to hand out: screen sharing with a VNC password shows its one
+//! console screen, whoever is logged in at it, to every viewer. So a Mac has
+//! a single desktop at a time, and starting another is refused until it
+//! ends, in favor of attaching to it for the user who owns it.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::xpra::DisplayOptions;
+use crate::xpra_backend::{self, DesktopBackend};
+use crate::xpra_command::ProgramAllowlist;
+
+/// Mac desktops are started for.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MacosConfig {
+    /// Address of the Mac, `host` or `host::port` for screen sharing on
+    /// another port than 5900
+    pub target: String,
+
+    /// VNC viewer started on the display
+    #[serde(default = "default_client")]
+    pub client: String,
+}
+
+fn default_client() -> String { "vncviewer".to_string() }
+
+/// Desktops of a Mac.
+#[derive(Debug, Clone)]
+pub struct MacosBackend {
+    config: MacosConfig,
+}
+
+impl MacosBackend {
+    /// Backend connecting to the Mac of `config`.
+    pub fn new(config: MacosConfig) -> Self {
+        Self { config }
+    }
+
+    /// Arguments of the VNC viewer.
+    fn args(&self) -> Vec<String> {
+        vec![
+            // The Mac's screen keeps its size; the viewer scales it to the display
+            "-FullScreen".to_string(),
+            "-RemoteResize=0".to_string(),
+            self.config.target.clone(),
+        ]
+    }
+}
+
+impl DesktopBackend for MacosBackend {
+    fn name(&self) -> &'static str {
+        "macos"
+    }
+
+    fn desktop_program(&self, _wm: &str, options: &DisplayOptions, _allowlist: &ProgramAllowlist) -> Result<String> {
+        if options.app.is_some() {
+            anyhow::bail!("macOS desktops cannot start apps");
+        }
+        xpra_backend::check_target(&self.config.target, "macOS")?;
+        Ok(xpra_backend::command_line(&self.config.client, &self.args()))
+    }
+
+    fn ends_with_program(&self) -> bool {
+        true
+    }
+
+    fn target(&self) -> Option<&str> {
+        Some(&self.config.target)
+    }
+
+    fn exclusive(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_desktop_program() {
+        let config = MacosConfig { target: "mac-1.corp::5901".to_string(), client: default_client() };
+        let (backend, allowlist) = (MacosBackend::new(config.clone()), ProgramAllowlist::default());
+        let program = backend.desktop_program("", &DisplayOptions::default(), &allowlist).unwrap();
+        assert_eq!(program, "vncviewer -FullScreen -RemoteResize=0 mac-1.corp::5901");
+        assert!(backend.exclusive());
+
+        let app = DisplayOptions { app: Some("firefox".to_string()), ..DisplayOptions::default() };
+        assert!(backend.desktop_program("", &app, &allowlist).is_err());
+        let backend = MacosBackend::new(MacosConfig { target: "-via".to_string(), ..config });
+        assert!(backend.desktop_program("", &DisplayOptions::default(), &allowlist).is_err());
+    }
+}
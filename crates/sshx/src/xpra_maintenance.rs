@@ -0,0 +1,380 @@
+//! Maintenance windows during which no sessions are started.
+
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Datelike, NaiveDate, Timelike, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tokio::time;
+use tracing::info;
+
+use crate::xpra_drain::DrainController;
+
+/// Recurring maintenance window, given as a cron schedule for its start.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MaintenanceWindow {
+    /// Start of the window as a 5-field cron expression in UTC
+    /// (`minute hour day-of-month month day-of-week`)
+    pub schedule: String,
+
+    /// Length of the window in minutes
+    pub duration_minutes: u64,
+
+    /// Minutes before the window to warn users in their sessions
+    #[serde(default = "default_warn_minutes")]
+    pub warn_minutes: u64,
+
+    /// Message shown to users, defaults to `drain_message`
+    #[serde(default)]
+    pub message: Option<String>,
+}
+
+fn default_warn_minutes() -> u64 { 15 }
+
+/// Parsed cron expression with minute resolution.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CronSchedule {
+    minutes: Vec<bool>,
+    hours: Vec<bool>,
+    days: Vec<bool>,
+    months: Vec<bool>,
+    weekdays: Vec<bool>,
+    days_restricted: bool,
+    weekdays_restricted: bool,
+}
+
+impl CronSchedule {
+    /// Parse a standard 5-field cron expression supporting `*`, lists,
+    /// ranges, and steps.
+    pub fn parse(expr: &str) -> Result<Self> {
+        let fields: Vec<&str> = expr.split_whitespace().collect();
+        if fields.len() != 5 {
+            anyhow::bail!("cron expression {expr:?} must have 5 fields");
+        }
+        let mut weekdays = parse_field(fields[4], 0, 7)?;
+        // Both 0 and 7 mean Sunday
+        if weekdays[7] {
+            weekdays[0] = true;
+        }
+        Ok(Self {
+            minutes: parse_field(fields[0], 0, 59)?,
+            hours: parse_field(fields[1], 0, 23)?,
+            days: parse_field(fields[2], 1, 31)?,
+            months: parse_field(fields[3], 1, 12)?,
+            weekdays,
+            days_restricted: fields[2] != "*",
+            weekdays_restricted: fields[4] != "*",
+        })
+    }
+
+    /// Whether the schedule fires in the minute containing `t`.
+    pub fn matches(&self, t: DateTime<Utc>) -> bool {
+        self.minutes[t.minute() as usize] && self.hours[t.hour() as usize] && self.matches_day(t.date_naive())
+    }
+
+    /// Whether the schedule fires on some minute of `date`.
+    fn matches_day(&self, date: NaiveDate) -> bool {
+        if !self.months[date.month() as usize] {
+            return false;
+        }
+        let day = self.days[date.day() as usize];
+        let weekday = self.weekdays[date.weekday().num_days_from_sunday() as usize];
+        // Like cron, a restricted day-of-month and day-of-week are OR'ed.
+        match (self.days_restricted, self.weekdays_restricted) {
+            (true, true) => day || weekday,
+            _ => day && weekday,
+        }
+    }
+
+    /// The first hour and minute the schedule fires at on a matching day,
+    /// at or after `hour`:`minute`.
+    fn first_time_from(&self, hour: u32, minute: u32) -> Option<(u32, u32)> {
+        (hour..24).filter(|&h| self.hours[h as usize]).find_map(|h| {
+            let from = if h == hour { minute } else { 0 };
+            (from..60).find(|&m| self.minutes[m as usize]).map(|m| (h, m))
+        })
+    }
+
+    /// The latest start at or before `t` within `lookback`, if any.
+    pub fn last_start(&self, t: DateTime<Utc>, lookback: chrono::Duration) -> Option<DateTime<Utc>> {
+        let t = truncate_minute(t);
+        (0..=lookback.num_minutes())
+            .map(|m| t - chrono::Duration::minutes(m))
+            .find(|&start| self.matches(start))
+    }
+
+    /// The next start strictly after `t`, searching up to `horizon` ahead.
+    /// Goes a day at a time, so far horizons are cheap.
+    pub fn next_start(&self, t: DateTime<Utc>, horizon: chrono::Duration) -> Option<DateTime<Utc>> {
+        let limit = truncate_minute(t) + horizon;
+        let after = truncate_minute(t) + chrono::Duration::minutes(1);
+        let (mut date, mut hour, mut minute) = (after.date_naive(), after.hour(), after.minute());
+        while date <= limit.date_naive() {
+            if self.matches_day(date) {
+                if let Some((hour, minute)) = self.first_time_from(hour, minute) {
+                    let start = date.and_hms_opt(hour, minute, 0)?.and_utc();
+                    return (start <= limit).then_some(start);
+                }
+            }
+            date = date.succ_opt()?;
+            (hour, minute) = (0, 0);
+        }
+        None
+    }
+}
+
+fn truncate_minute(t: DateTime<Utc>) -> DateTime<Utc> {
+    t.with_second(0).and_then(|t| t.with_nanosecond(0)).unwrap_or(t)
+}
+
+fn parse_field(field: &str, min: u32, max: u32) -> Result<Vec<bool>> {
+    let mut allowed = vec![false; max as usize + 1];
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().context("invalid cron step")?),
+            None => (part, 1),
+        };
+        if step == 0 {
+            anyhow::bail!("cron step must be positive in {field:?}");
+        }
+        let (lo, hi) = if range == "*" {
+            (min, max)
+        } else if let Some((lo, hi)) = range.split_once('-') {
+            (lo.parse().context("invalid cron range")?, hi.parse().context("invalid cron range")?)
+        } else {
+            let value: u32 = range.parse().with_context(|| format!("invalid cron value {range:?}"))?;
+            // `5/15` means "from 5 to the end, every 15"
+            (value, if part.contains('/') { max } else { value })
+        };
+        if lo < min || hi > max || lo > hi {
+            anyhow::bail!("cron field {field:?} out of range {min}-{max}");
+        }
+        for value in (lo..=hi).step_by(step as usize) {
+            allowed[value as usize] = true;
+        }
+    }
+    Ok(allowed)
+}
+
+/// Window the scheduler is draining the host for.
+#[derive(Debug, Clone, Copy)]
+struct ActiveWindow {
+    end: DateTime<Utc>,
+    /// When the drain was started, to tell it from drains started since
+    drain_started_at: DateTime<Utc>,
+}
+
+/// Drives the configured maintenance windows, draining the host while a
+/// window is open.
+#[derive(Debug, Clone)]
+pub struct MaintenanceScheduler {
+    windows: Vec<(CronSchedule, MaintenanceWindow)>,
+    drain: DrainController,
+    /// Window this scheduler is currently draining for.
+    active: Arc<Mutex<Option<ActiveWindow>>>,
+}
+
+impl MaintenanceScheduler {
+    /// Drive `windows`, draining the host with `drain` while one is open.
+    pub fn new(windows: &[MaintenanceWindow], drain: DrainController) -> Result<Self> {
+        let windows = windows
+            .iter()
+            .map(|w| Ok((CronSchedule::parse(&w.schedule)?, w.clone())))
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            windows,
+            drain,
+            active: Arc::new(Mutex::new(None)),
+        })
+    }
+
+    /// Evaluate windows once a minute in the background.
+    pub fn start(&self) {
+        if self.windows.is_empty() {
+            return;
+        }
+        let scheduler = self.clone();
+        tokio::spawn(async move {
+            let mut interval = time::interval(Duration::from_secs(60));
+            loop {
+                interval.tick().await;
+                scheduler.tick(Utc::now()).await;
+            }
+        });
+    }
+
+    /// Start of the next maintenance window, if one is scheduled in the next
+    /// year.
+    pub fn next_window(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.windows
+            .iter()
+            .filter_map(|(cron, _)| cron.next_start(now, chrono::Duration::days(366)))
+            .min()
+    }
+
+    async fn tick(&self, now: DateTime<Utc>) {
+        let mut active = self.active.lock().await;
+
+        if let Some(window) = *active {
+            if now >= window.end {
+                // A drain started by an operator since is theirs to stop
+                if self.drain.stop_started_at(window.drain_started_at).await.is_some() {
+                    info!("Maintenance window ended, resuming normal operation");
+                }
+                *active = None;
+            }
+        }
+
+        for (cron, window) in &self.windows {
+            let duration = chrono::Duration::minutes(window.duration_minutes as i64);
+            let message = window.message.clone().unwrap_or_else(|| self.drain.config().drain_message.clone());
+
+            if let Some(start) = cron.last_start(now, duration) {
+                let end = start + duration;
+                if now < end {
+                    // Leave a drain in progress alone, whether an operator
+                    // or an earlier window started it
+                    if active.is_none() && !self.drain.is_draining().await {
+                        info!(%start, %end, "Maintenance window started, draining host");
+                        let deadline = Duration::from_secs(self.drain.config().drain_deadline);
+                        let status = self.drain.start(deadline, Some(message)).await;
+                        *active = status.started_at.map(|drain_started_at| ActiveWindow { end, drain_started_at });
+                    }
+                    continue;
+                }
+            }
+
+            let warn_at = now + chrono::Duration::minutes(window.warn_minutes as i64);
+            if window.warn_minutes > 0 && cron.matches(warn_at) {
+                self.warn_sessions(&message, truncate_minute(warn_at)).await;
+            }
+        }
+    }
+
+    async fn warn_sessions(&self, message: &str, start: DateTime<Utc>) {
+        let body = format!(
+            "{} Scheduled maintenance starts at {}, open sessions will be closed.",
+            message,
+            start.format("%H:%M UTC"),
+        );
+        self.drain.notify_sessions("Upcoming maintenance", &body).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::xpra_context::XpraContext;
+
+    #[test]
+    fn test_cron_fields() {
+        // Sundays at 02:30
+        let cron = CronSchedule::parse("30 2 * * 0").unwrap();
+        assert!(cron.matches(Utc.with_ymd_and_hms(2024, 5, 12, 2, 30, 45).unwrap()));
+        assert!(!cron.matches(Utc.with_ymd_and_hms(2024, 5, 13, 2, 30, 0).unwrap()));
+
+        // Every 15 minutes during working hours on weekdays
+        let cron = CronSchedule::parse("*/15 9-17 * * 1-5").unwrap();
+        assert!(cron.matches(Utc.with_ymd_and_hms(2024, 5, 13, 9, 45, 0).unwrap()));
+        assert!(!cron.matches(Utc.with_ymd_and_hms(2024, 5, 13, 9, 50, 0).unwrap()));
+        assert!(!cron.matches(Utc.with_ymd_and_hms(2024, 5, 12, 9, 45, 0).unwrap()));
+
+        assert!(CronSchedule::parse("60 * * * *").is_err());
+        assert!(CronSchedule::parse("* * *").is_err());
+    }
+
+    #[test]
+    fn test_window_lookup() {
+        let cron = CronSchedule::parse("0 3 * * *").unwrap();
+        let now = Utc.with_ymd_and_hms(2024, 5, 12, 3, 20, 10).unwrap();
+        assert_eq!(
+            cron.last_start(now, chrono::Duration::minutes(60)),
+            Some(Utc.with_ymd_and_hms(2024, 5, 12, 3, 0, 0).unwrap()),
+        );
+        assert_eq!(cron.last_start(now, chrono::Duration::minutes(10)), None);
+        assert_eq!(
+            cron.next_start(now, chrono::Duration::days(2)),
+            Some(Utc.with_ymd_and_hms(2024, 5, 13, 3, 0, 0).unwrap()),
+        );
+        assert_eq!(cron.next_start(now, chrono::Duration::hours(23)), None);
+
+        // Only in leap years
+        let cron = CronSchedule::parse("30 4 29 2 *").unwrap();
+        assert_eq!(
+            cron.next_start(now, chrono::Duration::days(366 * 4)),
+            Some(Utc.with_ymd_and_hms(2028, 2, 29, 4, 30, 0).unwrap()),
+        );
+        assert_eq!(CronSchedule::parse("0 0 31 2 *").unwrap().next_start(now, chrono::Duration::days(366 * 8)), None);
+    }
+
+    #[test]
+    fn test_next_start_matches_every_minute() {
+        let schedules = ["0 3 * * *", "*/15 9-17 * * 1-5", "59 23 31 * *", "5/20 */6 1,15 * 0", "0 0 * 2 *"];
+        let times = [
+            Utc.with_ymd_and_hms(2024, 5, 12, 3, 20, 10).unwrap(),
+            Utc.with_ymd_and_hms(2024, 5, 13, 9, 45, 0).unwrap(),
+            Utc.with_ymd_and_hms(2024, 12, 31, 23, 59, 30).unwrap(),
+            Utc.with_ymd_and_hms(2024, 2, 29, 23, 30, 0).unwrap(),
+        ];
+        let horizon = chrono::Duration::days(40);
+        for schedule in schedules {
+            let cron = CronSchedule::parse(schedule).unwrap();
+            for now in times {
+                let expected = (1..=horizon.num_minutes())
+                    .map(|m| truncate_minute(now) + chrono::Duration::minutes(m))
+                    .find(|&start| cron.matches(start));
+                assert_eq!(cron.next_start(now, horizon), expected, "{schedule} after {now}");
+            }
+        }
+    }
+
+    fn nightly() -> MaintenanceWindow {
+        MaintenanceWindow {
+            schedule: "0 3 * * *".to_string(),
+            duration_minutes: 60,
+            warn_minutes: 0,
+            message: Some("Nightly patching".to_string()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_window_drains_with_configured_deadline() {
+        let context = XpraContext::for_test("maintenance-window");
+        let drain = context.drain.clone();
+        let scheduler = MaintenanceScheduler::new(&[nightly()], drain.clone()).unwrap();
+
+        scheduler.tick(Utc.with_ymd_and_hms(2024, 5, 12, 3, 10, 0).unwrap()).await;
+        let status = drain.status().await;
+        assert!(status.draining);
+        assert_eq!(status.message.as_deref(), Some("Nightly patching"));
+        let deadline = chrono::Duration::seconds(context.config.drain_deadline as i64);
+        assert_eq!(status.deadline, Some(status.started_at.unwrap() + deadline));
+
+        scheduler.tick(Utc.with_ymd_and_hms(2024, 5, 12, 4, 0, 0).unwrap()).await;
+        assert!(!drain.is_draining().await);
+    }
+
+    #[tokio::test]
+    async fn test_window_end_leaves_operator_drain() {
+        let context = XpraContext::for_test("maintenance-operator");
+        let drain = context.drain.clone();
+        let scheduler = MaintenanceScheduler::new(&[nightly()], drain.clone()).unwrap();
+
+        // Started by an operator before the window
+        drain.start(Duration::from_secs(3600), None).await;
+        scheduler.tick(Utc.with_ymd_and_hms(2024, 5, 12, 3, 10, 0).unwrap()).await;
+        scheduler.tick(Utc.with_ymd_and_hms(2024, 5, 12, 4, 0, 0).unwrap()).await;
+        assert!(drain.is_draining().await);
+        drain.stop().await;
+
+        // Started by an operator during the window, replacing its drain
+        scheduler.tick(Utc.with_ymd_and_hms(2024, 5, 13, 3, 10, 0).unwrap()).await;
+        time::sleep(Duration::from_millis(5)).await;
+        drain.start(Duration::from_secs(3600), Some("Disk replacement".to_string())).await;
+        scheduler.tick(Utc.with_ymd_and_hms(2024, 5, 13, 4, 0, 0).unwrap()).await;
+        assert_eq!(drain.status().await.message.as_deref(), Some("Disk replacement"));
+    }
+}
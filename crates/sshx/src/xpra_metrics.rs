@@ -1,66 +1,359 @@
+//! Counters of the Xpra sessions started on this host.
+
+use std::collections::HashSet;
 use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::Instant;
-use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
 
+/// Counters of sessions since the process started.
+///
+/// Each session moves from started to ended exactly once: the ids of running
+/// sessions are tracked, and an end of a session that is not running is
+/// counted as an invalid transition instead of touching the counters.
 #[derive(Debug)]
 pub struct XpraMetrics {
+    /// Ids of the sessions counted as active
+    running: Mutex<HashSet<String>>,
+    invalid_transitions: AtomicU64,
     total_sessions: AtomicU64,
     active_sessions: AtomicU64,
     failed_sessions: AtomicU64,
     idle_terminations: AtomicU64,
+    rate_limited: AtomicU64,
+    rejected_full: AtomicU64,
+    queued_sessions: AtomicU64,
+    clipboard_blocked: AtomicU64,
+    logging: Arc<LoggingMetrics>,
     start_time: Instant,
 }
 
+impl Default for XpraMetrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl XpraMetrics {
+    /// Create counters starting at zero.
     pub fn new() -> Self {
         Self {
+            running: Mutex::new(HashSet::new()),
+            invalid_transitions: AtomicU64::new(0),
             total_sessions: AtomicU64::new(0),
             active_sessions: AtomicU64::new(0),
             failed_sessions: AtomicU64::new(0),
             idle_terminations: AtomicU64::new(0),
+            rate_limited: AtomicU64::new(0),
+            rejected_full: AtomicU64::new(0),
+            queued_sessions: AtomicU64::new(0),
+            clipboard_blocked: AtomicU64::new(0),
+            logging: Arc::new(LoggingMetrics::default()),
             start_time: Instant::now(),
         }
     }
 
-    pub fn session_started(&self) {
+    /// Count a session start.
+    pub fn session_started(&self, session_id: &str) {
+        if !self.running.lock().unwrap().insert(session_id.to_string()) {
+            self.invalid_transition(session_id, "start of a running session");
+            return;
+        }
         self.total_sessions.fetch_add(1, Ordering::Relaxed);
         self.active_sessions.fetch_add(1, Ordering::Relaxed);
     }
 
-    pub fn session_ended(&self) {
-        self.active_sessions.fetch_sub(1, Ordering::Relaxed);
+    /// Count a session that ended.
+    pub fn session_ended(&self, session_id: &str) {
+        self.end_session(session_id, None);
     }
 
-    pub fn session_failed(&self) {
-        self.failed_sessions.fetch_add(1, Ordering::Relaxed);
-        self.active_sessions.fetch_sub(1, Ordering::Relaxed);
+    /// Count a session that failed.
+    pub fn session_failed(&self, session_id: &str) {
+        self.end_session(session_id, Some(&self.failed_sessions));
     }
 
-    pub fn idle_terminated(&self) {
-        self.idle_terminations.fetch_add(1, Ordering::Relaxed);
-        self.active_sessions.fetch_sub(1, Ordering::Relaxed);
+    /// Count a session terminated for being idle.
+    pub fn idle_terminated(&self, session_id: &str) {
+        self.end_session(session_id, Some(&self.idle_terminations));
+    }
+
+    fn end_session(&self, session_id: &str, outcome: Option<&AtomicU64>) {
+        if !self.running.lock().unwrap().remove(session_id) {
+            self.invalid_transition(session_id, "end of a session that is not running");
+            return;
+        }
+        if let Some(counter) = outcome {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+        if !saturating_decrement(&self.active_sessions) {
+            self.invalid_transition(session_id, "end with no active sessions");
+        }
     }
 
+    fn invalid_transition(&self, session_id: &str, transition: &str) {
+        self.invalid_transitions.fetch_add(1, Ordering::Relaxed);
+        warn!(session_id, "Ignoring {} in session metrics", transition);
+    }
+
+    /// Count a session start rejected by the rate limiter.
+    pub fn session_rate_limited(&self) {
+        self.rate_limited.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a session start rejected because the host was full
+    pub fn session_rejected_full(&self) {
+        self.rejected_full.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a session start waiting for a free slot
+    pub fn session_queued(&self) {
+        self.queued_sessions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a session start that stopped waiting
+    pub fn session_dequeued(&self) {
+        if !saturating_decrement(&self.queued_sessions) {
+            self.invalid_transitions.fetch_add(1, Ordering::Relaxed);
+            warn!("Ignoring dequeue with no queued sessions in session metrics");
+        }
+    }
+
+    /// Count a clipboard transfer blocked by a clipboard policy
+    pub fn clipboard_blocked(&self) {
+        self.clipboard_blocked.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counters of the logs, shared with the logger writing them.
+    pub fn logging(&self) -> &Arc<LoggingMetrics> {
+        &self.logging
+    }
+
+    /// Compare the active counter with the sessions tracked as running.
+    pub fn consistency(&self) -> MetricsConsistency {
+        let running = self.running.lock().unwrap().len() as u64;
+        MetricsConsistency {
+            active_sessions: self.active_sessions.load(Ordering::Relaxed),
+            running_sessions: running,
+            invalid_transitions: self.invalid_transitions.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Read the current values of the counters.
     pub fn get_metrics(&self) -> XpraMetricsSnapshot {
         XpraMetricsSnapshot {
             total_sessions: self.total_sessions.load(Ordering::Relaxed),
             active_sessions: self.active_sessions.load(Ordering::Relaxed),
             failed_sessions: self.failed_sessions.load(Ordering::Relaxed),
             idle_terminations: self.idle_terminations.load(Ordering::Relaxed),
+            rate_limited: self.rate_limited.load(Ordering::Relaxed),
+            rejected_full: self.rejected_full.load(Ordering::Relaxed),
+            queued_sessions: self.queued_sessions.load(Ordering::Relaxed),
+            clipboard_blocked: self.clipboard_blocked.load(Ordering::Relaxed),
+            invalid_transitions: self.invalid_transitions.load(Ordering::Relaxed),
+            logging: self.logging.snapshot(),
             uptime_secs: self.start_time.elapsed().as_secs(),
         }
     }
 }
 
-#[derive(Debug, Clone, serde::Serialize)]
+/// Counters of the metrics, history and alerts logs, to tell when the audit
+/// trail is failing.
+#[derive(Debug, Default)]
+pub struct LoggingMetrics {
+    events_written: AtomicU64,
+    bytes_written: AtomicU64,
+    write_errors: AtomicU64,
+    dropped_events: AtomicU64,
+    rotations: AtomicU64,
+    unwritable_logs: AtomicU64,
+}
+
+impl LoggingMetrics {
+    /// Count an entry of `bytes` bytes written to a log file.
+    pub fn event_written(&self, bytes: u64) {
+        self.events_written.fetch_add(1, Ordering::Relaxed);
+        self.bytes_written.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Count a failed attempt to write a log file.
+    pub fn write_failed(&self) {
+        self.write_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count an entry dropped from a full buffer of a log that cannot be
+    /// written.
+    pub fn event_dropped(&self) {
+        self.dropped_events.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a rotation of a log file.
+    pub fn rotated(&self) {
+        self.rotations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Note that a log became unwritable, until [`Self::log_recovered`].
+    pub fn log_failing(&self) {
+        self.unwritable_logs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Note that a log is writable again.
+    pub fn log_recovered(&self) {
+        saturating_decrement(&self.unwritable_logs);
+    }
+
+    /// Read the current values of the counters.
+    pub fn snapshot(&self) -> LoggingMetricsSnapshot {
+        LoggingMetricsSnapshot {
+            events_written: self.events_written.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            write_errors: self.write_errors.load(Ordering::Relaxed),
+            dropped_events: self.dropped_events.load(Ordering::Relaxed),
+            rotations: self.rotations.load(Ordering::Relaxed),
+            unwritable_logs: self.unwritable_logs.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Values of the log counters at one point in time.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LoggingMetricsSnapshot {
+    /// Entries written to the metrics, history and alerts logs
+    pub events_written: u64,
+    /// Bytes written to the logs, including line ends
+    pub bytes_written: u64,
+    /// Failed attempts to write a log; entries are buffered meanwhile
+    pub write_errors: u64,
+    /// Entries lost because the buffer of an unwritable log was full
+    pub dropped_events: u64,
+    /// Rotations of the metrics and history logs
+    pub rotations: u64,
+    /// Logs that cannot be written right now, their entries buffered
+    #[serde(default)]
+    pub unwritable_logs: u64,
+}
+
+impl LoggingMetricsSnapshot {
+    /// Whether a log cannot be written right now. Failures that were
+    /// recovered from stay counted, but no longer count as failing.
+    pub fn is_failing(&self) -> bool {
+        self.unwritable_logs > 0
+    }
+}
+
+/// Decrement a counter unless it is zero, returning whether it was
+/// decremented.
+fn saturating_decrement(counter: &AtomicU64) -> bool {
+    counter
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |value| value.checked_sub(1))
+        .is_ok()
+}
+
+/// Result of checking the session counters against the sessions tracked as
+/// running.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MetricsConsistency {
+    /// Value of the active sessions counter
+    pub active_sessions: u64,
+    /// Sessions tracked as running
+    pub running_sessions: u64,
+    /// Transitions ignored because the session was not in the right state
+    pub invalid_transitions: u64,
+}
+
+impl MetricsConsistency {
+    /// Whether the counter matches the tracked sessions and no transition
+    /// was ever ignored.
+    pub fn is_consistent(&self) -> bool {
+        self.active_sessions == self.running_sessions && self.invalid_transitions == 0
+    }
+}
+
+/// Values of the counters at one point in time.
+#[derive(Debug, Clone, Serialize)]
 pub struct XpraMetricsSnapshot {
+    /// Sessions started.
     pub total_sessions: u64,
+    /// Sessions currently running.
     pub active_sessions: u64,
+    /// Sessions that failed.
     pub failed_sessions: u64,
+    /// Sessions terminated for being idle.
     pub idle_terminations: u64,
+    /// Session starts rejected by the rate limiter.
+    pub rate_limited: u64,
+    /// Session starts rejected because the host was full
+    pub rejected_full: u64,
+    /// Session starts currently waiting for a free slot
+    pub queued_sessions: u64,
+    /// Clipboard transfers blocked by clipboard policies
+    pub clipboard_blocked: u64,
+    /// Session transitions ignored as invalid
+    pub invalid_transitions: u64,
+    /// Counters of the logs
+    pub logging: LoggingMetricsSnapshot,
+    /// Seconds since the counters were created.
     pub uptime_secs: u64,
 }
 
-lazy_static! {
-    pub static ref METRICS: XpraMetrics = XpraMetrics::new();
+/// Byte counters for a single session's forwarded traffic.
+#[derive(Debug, Clone, Default)]
+pub struct SessionTraffic {
+    bytes_in: Arc<AtomicU64>,
+    bytes_out: Arc<AtomicU64>,
+}
+
+impl SessionTraffic {
+    /// Record bytes received from the client and forwarded to Xpra.
+    pub fn add_in(&self, bytes: u64) {
+        self.bytes_in.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Record bytes received from Xpra and forwarded to the client.
+    pub fn add_out(&self, bytes: u64) {
+        self.bytes_out.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Total bytes as (in, out).
+    pub fn totals(&self) -> (u64, u64) {
+        (
+            self.bytes_in.load(Ordering::Relaxed),
+            self.bytes_out.load(Ordering::Relaxed),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_ends_once() {
+        let metrics = XpraMetrics::new();
+        metrics.session_started("a");
+        metrics.session_started("b");
+        metrics.session_failed("a");
+        metrics.session_ended("a");
+        metrics.idle_terminated("unknown");
+        metrics.session_started("b");
+
+        let snapshot = metrics.get_metrics();
+        assert_eq!(snapshot.total_sessions, 2);
+        assert_eq!(snapshot.active_sessions, 1);
+        assert_eq!(snapshot.failed_sessions, 1);
+        assert_eq!(snapshot.idle_terminations, 0);
+        let consistency = metrics.consistency();
+        assert_eq!(consistency.running_sessions, 1);
+        assert_eq!(consistency.invalid_transitions, 3);
+        assert!(!consistency.is_consistent());
+    }
+
+    #[test]
+    fn test_counters_saturate() {
+        let metrics = XpraMetrics::new();
+        metrics.session_dequeued();
+        assert_eq!(metrics.get_metrics().queued_sessions, 0);
+        assert_eq!(metrics.consistency().invalid_transitions, 1);
+    }
 }
@@ -1,141 +1,1219 @@
+//! Tracking of running Xpra sessions and termination of idle ones.
+
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::path::PathBuf;
+use std::sync::{Arc, OnceLock};
 use std::time::{Duration, Instant};
-use tokio::sync::Mutex;
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use futures_util::{stream, StreamExt};
+use sshx_core::Sid;
+use tokio::sync::{mpsc, watch, Notify};
 use tokio::time;
-use tracing::{debug, info, warn};
-use crate::xpra_config::CONFIG;
+use tracing::{debug, error, info, warn};
+use crate::xpra::XpraDisplay;
+use crate::xpra_children::SessionChildren;
+use crate::xpra_config::XpraConfig;
+use crate::xpra_devices::Device;
+use crate::xpra_hosts::{HostKind, HostPool};
+use crate::xpra_idle::{self, IdleSource};
+use crate::xpra_inspect::{ConfigSnapshot, TrafficCounters};
+use crate::xpra_labels::Labels;
+use crate::xpra_logger::{SessionEvent, SessionEventType, TerminationReason, XpraLogger};
+use crate::xpra_metrics::{SessionTraffic, XpraMetrics};
+use crate::xpra_paging::{SessionPage, SessionQuery};
+use crate::xpra_summary::SessionSummary;
+use crate::xpra_runner::Attachment;
+use crate::xpra_session_id::ParentShell;
+use crate::xpra_session_state::{SessionLifecycle, SessionState};
+use crate::xpra_termination::TerminationNotice;
+use crate::xpra_watchdog::DisplayHealth;
 
-#[derive(Debug)]
-struct SessionInfo {
-    user: String,
-    display: u16,
-    last_activity: Instant,
-}
+/// Idle sessions hibernated or terminated at the same time.
+const IDLE_CLEANUP_CONCURRENCY: usize = 8;
+
+/// Sessions whose guards were dropped that are closed at the same time.
+const DROPPED_CLOSE_CONCURRENCY: usize = 8;
 
+/// Running sessions by id, terminating idle ones in the background.
+///
+/// Sessions are kept in a map sharded by id, so updates to one session only
+/// wait on those to sessions of the same shard, and readers iterate over the
+/// sessions in place with [`Self::collect_sessions`] rather than cloning
+/// them all. No reference into the map is held across an `.await`.
 #[derive(Debug, Clone)]
 pub struct SessionMonitor {
-    sessions: Arc<Mutex<HashMap<String, SessionInfo>>>,
+    sessions: Arc<DashMap<String, SessionInfo>>,
+    /// Sessions of each user on each host, kept apart from `sessions` so
+    /// starting sessions can check a user's count without scanning them
+    user_sessions: Arc<std::sync::Mutex<HashMap<String, HashMap<String, usize>>>>,
+    config: Arc<XpraConfig>,
+    logger: XpraLogger,
+    metrics: Arc<XpraMetrics>,
+    /// Hosts the sessions' displays run on
+    hosts: HostPool,
+    /// Sessions whose guards were dropped without finishing, for the
+    /// monitor to close and deregister
+    dropped: mpsc::UnboundedSender<DroppedSession>,
+    /// Receiver of `dropped`, until the task closing them is started
+    dropped_rx: Arc<std::sync::Mutex<Option<mpsc::UnboundedReceiver<DroppedSession>>>>,
 }
 
+/// What a guard dropped without finishing leaves to the monitor.
+#[derive(Debug)]
+struct DroppedSession {
+    session_id: String,
+    reason: TerminationReason,
+    crash_bundle: Option<PathBuf>,
+    display: Option<XpraDisplay>,
+    stopped: Option<watch::Sender<bool>>,
+}
+
+/// A running session.
 #[derive(Debug, Clone)]
 pub struct SessionInfo {
+    /// User owning the session.
     pub user: String,
+    /// Name of the host running the session's display
+    pub host: String,
+    /// Display number of the session.
     pub display: u16,
+    /// GPU device assigned to the session's display
+    pub gpu: Option<String>,
+    /// CPUs or NUMA node the session's display is pinned to
+    pub cpus: Option<String>,
+    /// Last time the session was active, by the configured idle source
     pub last_activity: Instant,
+    /// Last time input came through the session's channel
+    pub channel_activity: Instant,
+    /// The display's X server could not be asked for its idle time, so
+    /// channel input counts as activity instead
+    pub idle_fallback: bool,
+    /// Bytes forwarded for the session
+    pub traffic: SessionTraffic,
+    /// Signalled to stop the session's forwarder from outside
+    pub shutdown: Arc<Notify>,
+    /// Why the host terminated the session, set before `shutdown` is signalled
+    pub termination: Arc<OnceLock<TerminationNotice>>,
+    /// Signalled to abort a forwarder that did not stop on `shutdown`
+    pub abort: Arc<Notify>,
+    /// Turns true once the session's display is closed
+    pub stopped: watch::Receiver<bool>,
+    /// Signalled to have the forwarder checkpoint the idle session
+    pub hibernate: Arc<Notify>,
+    /// Hands the forwarder the shell of a client attaching to the session
+    pub attach: mpsc::Sender<Attachment>,
+    /// Whether the session is currently checkpointed to disk
+    pub hibernated: bool,
+    /// Started for automation with no attached client; headless sessions
+    /// follow their own idle timeout
+    pub headless: bool,
+    /// sshx session and shell the session was started from, none for
+    /// headless sessions
+    pub parent: Option<ParentShell>,
+    /// Labels given when the session was created
+    pub labels: Labels,
+    /// Name the user gave the session, at first its `name` label
+    pub name: Option<String>,
+    /// What the user said the session is for
+    pub description: Option<String>,
+    /// When the session was registered
+    pub created_at: DateTime<Utc>,
+    /// Settings the session was created with
+    pub config: ConfigSnapshot,
+    /// Screen size last set for a client, if the display was resized
+    pub resolution: Option<String>,
+    /// State of the session and the states it went through
+    pub lifecycle: SessionLifecycle,
+    /// What the watchdog last found on the display
+    pub health: DisplayHealth,
+    /// Times the watchdog restarted the window manager
+    pub wm_restarts: u32,
+    /// Programs started on the desktop next to the window manager
+    pub children: SessionChildren,
+    /// Screenshots of the session are taken for compliance
+    pub recording: bool,
+    /// The user acknowledged the recording notice; screenshots are only
+    /// taken from then on
+    pub recording_acknowledged: bool,
 }
 
 impl SessionMonitor {
-    pub fn new() -> Self {
-        let monitor = Self {
-            sessions: Arc::new(Mutex::new(HashMap::new())),
-        };
-
-        // Start cleanup task if idle timeout is configured
-        if let Some(timeout) = CONFIG.idle_duration() {
-            monitor.start_cleanup_task(timeout);
+    /// Create a monitor recording session events to `logger` and `metrics`.
+    pub fn new(config: Arc<XpraConfig>, logger: XpraLogger, metrics: Arc<XpraMetrics>) -> Self {
+        let (dropped, dropped_rx) = mpsc::unbounded_channel();
+        Self {
+            sessions: Arc::new(DashMap::new()),
+            user_sessions: Arc::default(),
+            config,
+            logger,
+            metrics,
+            hosts: HostPool::default(),
+            dropped,
+            dropped_rx: Arc::new(std::sync::Mutex::new(Some(dropped_rx))),
         }
+    }
+
+    /// Look up the hosts of sessions in `hosts`, which without it are
+    /// neither sampled for input nor hibernated.
+    pub fn with_hosts(mut self, hosts: HostPool) -> Self {
+        self.hosts = hosts;
+        self
+    }
 
-        monitor
+    /// Hosts the sessions' displays run on
+    pub fn hosts(&self) -> &HostPool {
+        &self.hosts
+    }
+
+    /// Start cleaning up idle sessions, if an idle timeout is configured,
+    /// and sampling the displays' input if it decides when they are idle.
+    pub fn start(&self) {
+        let timeout = self.config.idle_duration();
+        if timeout.is_some() || self.config.idle_source == IdleSource::XInput {
+            self.start_cleanup_task(timeout);
+        }
     }
 
-    pub async fn register_session(&self, session_id: String, user: String, display: u16) {
-        let mut sessions = self.sessions.lock().await;
-        sessions.insert(session_id.clone(), SessionInfo {
+    /// Register a session started from `parent`, or headless if none, and
+    /// count its start, returning the guard that owns its display and
+    /// deregisters it again.
+    pub async fn register_session(
+        &self,
+        session_id: String,
+        user: String,
+        display: XpraDisplay,
+        parent: Option<ParentShell>,
+        labels: Labels,
+    ) -> SessionGuard {
+        self.start_closing_dropped();
+        let (attach, attachments) = mpsc::channel(1);
+        let (stopped_tx, stopped) = watch::channel(false);
+        let info = SessionInfo {
             user: user.clone(),
-            display,
+            host: display.host().name.clone(),
+            display: display.display(),
+            gpu: display.gpu().map(str::to_string),
+            cpus: display.cpus().map(|c| c.to_string()),
             last_activity: Instant::now(),
-        });
-        debug!(user, display, "Registered new Xpra session");
+            channel_activity: Instant::now(),
+            idle_fallback: false,
+            traffic: SessionTraffic::default(),
+            shutdown: Arc::new(Notify::new()),
+            termination: Arc::default(),
+            abort: Arc::new(Notify::new()),
+            stopped,
+            hibernate: Arc::new(Notify::new()),
+            attach,
+            hibernated: false,
+            headless: parent.is_none(),
+            parent,
+            name: labels.get("name").cloned(),
+            description: None,
+            labels,
+            created_at: Utc::now(),
+            config: ConfigSnapshot::capture(&display, &self.config),
+            resolution: None,
+            lifecycle: SessionLifecycle::new(),
+            health: DisplayHealth::default(),
+            wm_restarts: 0,
+            children: SessionChildren::default(),
+            recording: self.config.screenshots.enabled(),
+            recording_acknowledged: false,
+        };
+        self.sessions.insert(session_id.clone(), info.clone());
+        self.count_user_session(&info, true);
+        self.metrics.session_started(&session_id);
+        debug!(user, host = info.host, display = info.display, "Registered new Xpra session");
 
         // Log session creation
-        if let Err(e) = LOGGER.log_session_event(SessionEvent {
-            timestamp: Utc::now(),
+        if let Err(e) = self.logger.log_session_event(SessionEvent {
+            timestamp: info.created_at,
+            seq: None,
             event_type: SessionEventType::Created,
-            session_id,
+            session_id: session_id.clone(),
             user,
-            display,
+            display: info.display,
+            labels: info.labels.clone(),
+            state: Some(info.lifecycle.state()),
+            reason: None,
+            crash_bundle: None,
+            name: None,
+            description: None,
+            parent: info.parent.clone(),
+            device: None,
+            traffic: None,
+            host: Some(info.host.clone()),
         }).await {
             error!("Failed to log session creation: {}", e);
         }
+
+        SessionGuard {
+            monitor: self.clone(),
+            session_id,
+            info,
+            reason: TerminationReason::ClientDisconnect,
+            crash_bundle: None,
+            attachments: Some(attachments),
+            display: Some(display),
+            stopped: Some(stopped_tx),
+            finished: false,
+        }
     }
 
+    /// Start closing and deregistering the sessions of dropped guards, once
+    /// a session is registered and so a runtime runs.
+    fn start_closing_dropped(&self) {
+        let Some(dropped) = self.dropped_rx.lock().unwrap().take() else {
+            return;
+        };
+        let monitor = self.clone();
+        tokio::spawn(async move {
+            tokio_stream::wrappers::UnboundedReceiverStream::new(dropped)
+                .for_each_concurrent(DROPPED_CLOSE_CONCURRENCY, |dropped| {
+                    let monitor = monitor.clone();
+                    async move {
+                        if let Some(display) = dropped.display {
+                            display.close().await;
+                        }
+                        if let Some(stopped) = dropped.stopped {
+                            let _ = stopped.send(true);
+                        }
+                        monitor.deregister(&dropped.session_id, dropped.reason, dropped.crash_bundle).await;
+                    }
+                })
+                .await;
+        });
+    }
+
+    /// Record input through a session's channel now, which counts as
+    /// activity unless the X server's input decides.
     pub async fn update_activity(&self, session_id: &str) {
-        if let Some(session) = self.sessions.lock().await.get_mut(session_id) {
-            session.last_activity = Instant::now();
+        if let Some(mut session) = self.sessions.get_mut(session_id) {
+            session.channel_activity = Instant::now();
+            if self.config.idle_source == IdleSource::Channel || session.idle_fallback {
+                session.last_activity = session.channel_activity;
+            }
+        }
+    }
+
+    /// Record what the watchdog found on a session's display.
+    pub async fn set_health(&self, session_id: &str, health: DisplayHealth) {
+        if let Some(mut session) = self.sessions.get_mut(session_id) {
+            session.health = health;
+        }
+    }
+
+    /// Count a restart of a session's window manager, returning how many
+    /// there were.
+    pub async fn window_manager_restarted(&self, session_id: &str) -> u32 {
+        match self.sessions.get_mut(session_id) {
+            Some(mut session) => {
+                session.wm_restarts += 1;
+                session.wm_restarts
+            }
+            None => 0,
+        }
+    }
+
+    /// Record the screen size a session's display was resized to.
+    pub async fn set_resolution(&self, session_id: &str, resolution: String) {
+        if let Some(mut session) = self.sessions.get_mut(session_id) {
+            session.resolution = Some(resolution);
+        }
+    }
+
+    /// Give a session a name and description, logging the change. A name
+    /// of `None` goes back to the default one. Returns whether the session
+    /// is running.
+    pub async fn rename_session(
+        &self,
+        session_id: &str,
+        name: Option<String>,
+        description: Option<String>,
+    ) -> bool {
+        let Some(mut session) = self.sessions.get_mut(session_id) else {
+            return false;
+        };
+        session.name = name.clone();
+        session.description = description.clone();
+        debug!(session_id, ?name, "Session renamed");
+        let event = SessionEvent {
+            timestamp: Utc::now(),
+            seq: None,
+            event_type: SessionEventType::Renamed,
+            session_id: session_id.to_string(),
+            user: session.user.clone(),
+            display: session.display,
+            labels: session.labels.clone(),
+            state: Some(session.lifecycle.state()),
+            reason: None,
+            crash_bundle: None,
+            name,
+            description,
+            parent: None,
+            device: None,
+            traffic: None,
+            host: None,
+        };
+        drop(session);
+
+        if let Err(e) = self.logger.log_session_event(event).await {
+            error!("Failed to log session rename: {}", e);
         }
+        true
     }
 
-    pub async fn remove_session(&self, session_id: &str) {
-        let mut sessions = self.sessions.lock().await;
-        if let Some(session) = sessions.remove(session_id) {
-            debug!(
-                user = session.user,
-                display = session.display,
-                "Removed Xpra session"
-            );
+    /// Mark a hibernated session as running again.
+    pub async fn session_resumed(&self, session_id: &str) {
+        let Some(mut session) = self.sessions.get_mut(session_id) else {
+            return;
+        };
+        session.last_activity = Instant::now();
+        if !std::mem::replace(&mut session.hibernated, false) {
+            return;
+        }
+        let event = SessionEvent {
+            timestamp: Utc::now(),
+            seq: None,
+            event_type: SessionEventType::Resumed,
+            session_id: session_id.to_string(),
+            user: session.user.clone(),
+            display: session.display,
+            labels: session.labels.clone(),
+            state: Some(session.lifecycle.state()),
+            reason: None,
+            crash_bundle: None,
+            name: None,
+            description: None,
+            parent: None,
+            device: None,
+            traffic: None,
+            host: None,
+        };
+        drop(session);
+
+        if let Err(e) = self.logger.log_session_event(event).await {
+            error!("Failed to log session resume: {}", e);
         }
     }
 
+    /// Log that the client of a session attached one of its devices.
+    pub async fn device_attached(&self, session_id: &str, device: Device) {
+        info!(session_id, %device, "Client attached a device");
+        self.log_event(session_id, SessionEventType::DeviceAttached, Some(device)).await;
+    }
+
+    /// Log that a device of the client was detached from a session.
+    pub async fn device_detached(&self, session_id: &str, device: Device) {
+        info!(session_id, %device, "Client detached a device");
+        self.log_event(session_id, SessionEventType::DeviceDetached, Some(device)).await;
+    }
+
+    /// Log that the user of a session acknowledged the notice that it is
+    /// recorded.
+    pub async fn recording_acknowledged(&self, session_id: &str) {
+        info!(session_id, "User acknowledged the recording notice");
+        if let Some(mut session) = self.sessions.get_mut(session_id) {
+            session.recording_acknowledged = true;
+        }
+        self.log_event(session_id, SessionEventType::RecordingAcknowledged, None).await;
+    }
+
+    async fn log_event(&self, session_id: &str, event_type: SessionEventType, device: Option<Device>) {
+        let Some(session) = self.sessions.get(session_id) else {
+            return;
+        };
+        let event = SessionEvent {
+            timestamp: Utc::now(),
+            seq: None,
+            event_type,
+            session_id: session_id.to_string(),
+            user: session.user.clone(),
+            display: session.display,
+            labels: session.labels.clone(),
+            state: Some(session.lifecycle.state()),
+            reason: None,
+            crash_bundle: None,
+            name: None,
+            description: None,
+            parent: None,
+            device,
+            traffic: None,
+            host: None,
+        };
+        drop(session);
+
+        if let Err(e) = self.logger.log_session_event(event).await {
+            error!("Failed to log session event: {}", e);
+        }
+    }
+
+    /// Move a session to another state, logging the change. Returns whether
+    /// the session is running and the transition is allowed from its state.
+    pub async fn set_state(&self, session_id: &str, state: SessionState) -> bool {
+        let Some(mut session) = self.sessions.get_mut(session_id) else {
+            return false;
+        };
+        if let Err(e) = session.lifecycle.transition(state) {
+            warn!(session_id, "Ignoring session state change: {}", e);
+            return false;
+        }
+        debug!(session_id, %state, "Session changed state");
+        let event = SessionEvent {
+            timestamp: session.lifecycle.since(),
+            seq: None,
+            event_type: SessionEventType::StateChanged,
+            session_id: session_id.to_string(),
+            user: session.user.clone(),
+            display: session.display,
+            labels: session.labels.clone(),
+            state: Some(state),
+            reason: None,
+            crash_bundle: None,
+            name: None,
+            description: None,
+            parent: None,
+            device: None,
+            traffic: None,
+            host: None,
+        };
+        drop(session);
+
+        if let Err(e) = self.logger.log_session_event(event).await {
+            error!("Failed to log session state change: {}", e);
+        }
+        true
+    }
+
+    /// Stop a running session's forwarder and record its termination for
+    /// `reason`, returning once its xpra is gone and its display released.
+    /// Sessions terminated for being idle are counted and logged as idle
+    /// timeouts.
+    pub async fn terminate_session(&self, session_id: &str, reason: TerminationReason) -> bool {
+        self.terminate_session_if(session_id, reason, |_| true).await
+    }
+
+    /// Terminate a session like [`Self::terminate_session`], but only if
+    /// `check` holds for it. The check runs under the lock that removes the
+    /// session, so activity just before can not be missed.
+    async fn terminate_session_if(
+        &self,
+        session_id: &str,
+        reason: TerminationReason,
+        check: impl FnOnce(&SessionInfo) -> bool,
+    ) -> bool {
+        let removed = self.sessions.remove_if(session_id, |_, session| check(session));
+        let Some(mut session) = removed.map(|(_, session)| session) else {
+            return false;
+        };
+        self.count_user_session(&session, false);
+        if let Err(e) = session.lifecycle.transition(SessionState::Terminating) {
+            warn!(session_id, "Terminating session: {}", e);
+        }
+        // The forwarder tells the client why, stops and closes the display
+        let _ = session.termination.set(TerminationNotice::new(reason));
+        session.shutdown.notify_one();
+        let (stopped, abort) = (session.stopped.clone(), session.abort.clone());
+        let event_type = match reason {
+            TerminationReason::IdleTimeout => {
+                self.metrics.idle_terminated(session_id);
+                SessionEventType::IdleTimeout
+            }
+            _ => {
+                self.metrics.session_ended(session_id);
+                SessionEventType::Terminated
+            }
+        };
+        info!(
+            user = session.user,
+            display = session.display,
+            %reason,
+            "Terminated Xpra session"
+        );
+
+        // Final totals, so traffic after the last metrics snapshot is billed
+        let (bytes_in, bytes_out) = session.traffic.totals();
+        if let Err(e) = self.logger.log_session_event(SessionEvent {
+            timestamp: Utc::now(),
+            seq: None,
+            event_type,
+            session_id: session_id.to_string(),
+            user: session.user,
+            display: session.display,
+            labels: session.labels.clone(),
+            state: Some(session.lifecycle.state()),
+            reason: Some(reason),
+            crash_bundle: None,
+            name: None,
+            description: None,
+            parent: None,
+            device: None,
+            traffic: Some(TrafficCounters { bytes_in, bytes_out }),
+            host: None,
+        }).await {
+            error!("Failed to log session termination: {}", e);
+        }
+        self.wait_stopped(session_id, stopped, &abort).await;
+        true
+    }
+
+    /// Wait for a terminated session's display to be closed, aborting its
+    /// forwarder if it does not stop within the stop timeout. A guard
+    /// dropped without finishing killed its xpra as well.
+    async fn wait_stopped(&self, session_id: &str, mut stopped: watch::Receiver<bool>, abort: &Notify) {
+        let timeout = self.config.stop_duration();
+        if time::timeout(timeout, stopped.wait_for(|stopped| *stopped)).await.is_ok() {
+            return;
+        }
+        warn!(session_id, "Session did not stop within {}s, aborting its forwarder", timeout.as_secs());
+        abort.notify_one();
+        if time::timeout(timeout, stopped.wait_for(|stopped| *stopped)).await.is_err() {
+            error!(session_id, "Session did not stop after aborting its forwarder");
+        }
+    }
+
+    /// Terminate every session started from the sshx session `session`, or
+    /// only from its shell `shell`, for `reason`. Returns the ids of the
+    /// sessions terminated.
+    pub async fn terminate_spawned(&self, session: &str, shell: Option<Sid>, reason: TerminationReason) -> Vec<String> {
+        let ids = self.collect_sessions(|id, info| {
+            let parent = info.parent.as_ref()?;
+            (parent.session == session && shell.is_none_or(|shell| parent.shell == shell)).then(|| id.to_string())
+        });
+        let mut terminated = Vec::with_capacity(ids.len());
+        for id in ids {
+            if self.terminate_session(&id, reason).await {
+                terminated.push(id);
+            }
+        }
+        terminated
+    }
+
+    /// Remove a session whose forwarder or display ended for `reason`,
+    /// counting it as ended or failed and logging its end with the crash
+    /// bundle collected for it, if any. Sessions already terminated through
+    /// the monitor were counted then and are left alone.
+    async fn deregister(&self, session_id: &str, reason: TerminationReason, crash_bundle: Option<PathBuf>) {
+        let Some(mut session) = self.sessions.remove(session_id).map(|(_, session)| session) else {
+            return;
+        };
+        self.count_user_session(&session, false);
+        let failed = reason.is_failure();
+        let (state, event_type) = match failed {
+            true => (SessionState::Failed, SessionEventType::Failed),
+            false => (SessionState::Terminating, SessionEventType::Terminated),
+        };
+        if let Err(e) = session.lifecycle.transition(state) {
+            warn!(session_id, "Deregistering session: {}", e);
+        }
+        if failed {
+            self.metrics.session_failed(session_id);
+        } else {
+            self.metrics.session_ended(session_id);
+        }
+        info!(
+            user = session.user,
+            display = session.display,
+            %reason,
+            "Xpra session ended"
+        );
+
+        // Final totals, so traffic after the last metrics snapshot is billed
+        let (bytes_in, bytes_out) = session.traffic.totals();
+        if let Err(e) = self.logger.log_session_event(SessionEvent {
+            timestamp: Utc::now(),
+            seq: None,
+            event_type,
+            session_id: session_id.to_string(),
+            user: session.user,
+            display: session.display,
+            labels: session.labels,
+            state: Some(session.lifecycle.state()),
+            reason: Some(reason),
+            crash_bundle,
+            name: None,
+            description: None,
+            parent: None,
+            device: None,
+            traffic: Some(TrafficCounters { bytes_in, bytes_out }),
+            host: None,
+        }).await {
+            error!("Failed to log session end: {}", e);
+        }
+    }
+
+    /// Number of sessions the user is running.
     pub async fn get_user_session_count(&self, user: &str) -> usize {
-        self.sessions.lock().await
-            .values()
-            .filter(|s| s.user == user)
-            .count()
+        let user_sessions = self.user_sessions.lock().unwrap();
+        user_sessions.get(user).map_or(0, |hosts| hosts.values().sum())
     }
 
+    /// Number of sessions the user has on each host.
+    pub async fn get_user_hosts(&self, user: &str) -> HashMap<String, usize> {
+        self.user_sessions.lock().unwrap().get(user).cloned().unwrap_or_default()
+    }
+
+    /// Count a session towards its user's sessions on its host, or stop
+    /// counting it once it is removed.
+    fn count_user_session(&self, session: &SessionInfo, added: bool) {
+        let mut user_sessions = self.user_sessions.lock().unwrap();
+        if added {
+            let hosts = user_sessions.entry(session.user.clone()).or_default();
+            *hosts.entry(session.host.clone()).or_default() += 1;
+            return;
+        }
+        let Some(hosts) = user_sessions.get_mut(&session.user) else {
+            return;
+        };
+        if let Some(count) = hosts.get_mut(&session.host) {
+            *count -= 1;
+            if *count == 0 {
+                hosts.remove(&session.host);
+            }
+        }
+        if hosts.is_empty() {
+            user_sessions.remove(&session.user);
+        }
+    }
+
+    /// The session with the given id, if it is running
+    pub async fn get_session(&self, session_id: &str) -> Option<SessionInfo> {
+        self.sessions.get(session_id).map(|session| session.clone())
+    }
+
+    /// All tracked sessions, by id. Cloning every session is costly with
+    /// many of them; [`Self::collect_sessions`] takes only what is needed.
     pub async fn get_all_sessions(&self) -> HashMap<String, SessionInfo> {
-        self.sessions.lock().await.clone()
+        self.collect_sessions(|id, session| Some((id.to_string(), session.clone()))).into_iter().collect()
+    }
+
+    /// What `f` takes from each running session it returns something for,
+    /// read in place. `f` must not call back into the monitor, which could
+    /// wait on the shard being read.
+    pub fn collect_sessions<T>(&self, mut f: impl FnMut(&str, &SessionInfo) -> Option<T>) -> Vec<T> {
+        self.sessions.iter().filter_map(|entry| f(entry.key(), entry.value())).collect()
+    }
+
+    /// The page of running sessions `query` asks for, each made into what
+    /// `f` returns in place. Like with [`Self::collect_sessions`], `f` must
+    /// not call back into the monitor.
+    pub fn query_sessions<T>(&self, query: &SessionQuery, mut f: impl FnMut(&str, &SessionInfo) -> T) -> SessionPage<T> {
+        let ids = self.collect_sessions(|id, session| query.matches(session).then(|| id.to_string()));
+        let total = ids.len();
+        let (page, next) = query.page(ids);
+        // Sessions that ended since their ids were collected are left out
+        let sessions = page
+            .iter()
+            .filter_map(|id| self.sessions.get(id).map(|session| f(id, &session)))
+            .collect();
+        SessionPage { sessions, total, next }
+    }
+
+    /// Counts and idle and running times of the running sessions, in all,
+    /// by user, and by team if a team label is configured.
+    pub fn summary(&self) -> SessionSummary {
+        let now = Utc::now();
+        let mut summary = SessionSummary::new(self.config.team_label.clone());
+        for entry in self.sessions.iter() {
+            let session = entry.value();
+            let duration = (now - session.created_at).num_seconds().max(0) as u64;
+            let idle = session.last_activity.elapsed().as_secs();
+            summary.record(&session.user, &session.labels, idle, duration, self.config.idle_timeout);
+        }
+        summary
+    }
+
+    /// Ids of the running sessions.
+    pub fn session_ids(&self) -> Vec<String> {
+        self.collect_sessions(|id, _| Some(id.to_string()))
     }
 
-    fn start_cleanup_task(&self, timeout: Duration) {
+    /// Number of running sessions.
+    pub fn session_count(&self) -> usize {
+        self.sessions.len()
+    }
+
+    fn start_cleanup_task(&self, timeout: Option<Duration>) {
         let monitor = self.clone();
         tokio::spawn(async move {
             let mut interval = time::interval(Duration::from_secs(60));
             loop {
                 interval.tick().await;
-                monitor.cleanup_idle_sessions(timeout).await;
+                if monitor.config.idle_source == IdleSource::XInput {
+                    monitor.sample_input_idle().await;
+                }
+                if let Some(timeout) = timeout {
+                    monitor.cleanup_idle_sessions(timeout).await;
+                }
             }
         });
     }
 
+    /// Ask the X server of each running display how long ago it saw input,
+    /// taking that as the session's last activity. Displays that cannot be
+    /// asked fall back to input through the channel.
+    async fn sample_input_idle(&self) {
+        let displays = self.collect_sessions(|id, info| {
+            (!info.hibernated && !info.headless).then(|| (id.to_string(), info.host.clone(), info.display))
+        });
+
+        for (session_id, host, number) in displays {
+            let Some(host) = self.hosts.get(&host) else {
+                continue;
+            };
+            let idle = xpra_idle::input_idle_time(&host, number).await;
+            let Some(mut session) = self.sessions.get_mut(&session_id) else {
+                continue;
+            };
+            match idle {
+                Ok(idle) => {
+                    session.idle_fallback = false;
+                    if let Some(input) = Instant::now().checked_sub(idle) {
+                        session.last_activity = input;
+                    }
+                }
+                Err(e) => {
+                    if !std::mem::replace(&mut session.idle_fallback, true) {
+                        warn!(session_id, display = number, "Using channel input for the idle time of the display: {:#}", e);
+                    }
+                    session.last_activity = session.channel_activity;
+                }
+            }
+        }
+    }
+
+    /// Hibernate or terminate the sessions idle for longer than `timeout`.
+    /// Candidates are collected first, so no shard of the map stays locked
+    /// while sessions are torn down, a few at a time. Each is checked again
+    /// under the lock that hibernates or removes it, keeping sessions used
+    /// again since they were collected.
     async fn cleanup_idle_sessions(&self, timeout: Duration) {
-        let mut sessions = self.sessions.lock().await;
         let now = Instant::now();
-        
-        let idle_sessions: Vec<_> = sessions
-            .iter()
-            .filter(|(_, info)| now.duration_since(info.last_activity) > timeout)
-            .map(|(id, _)| id.clone())
-            .collect();
+        let idle_sessions = self.collect_sessions(|id, info| is_idle(info, now, timeout).then(|| id.to_string()));
 
-        for session_id in idle_sessions {
-            if let Some(session) = sessions.remove(&session_id) {
-                info!(
-                    user = session.user,
-                    display = session.display,
-                    "Terminated idle Xpra session"
-                );
-                
-                // Log session termination
-                if let Err(e) = LOGGER.log_session_event(SessionEvent {
-                    timestamp: Utc::now(),
-                    event_type: SessionEventType::IdleTimeout,
-                    session_id,
-                    user: session.user.clone(),
-                    display: session.display,
-                }).await {
-                    error!("Failed to log session termination: {}", e);
+        stream::iter(idle_sessions)
+            .for_each_concurrent(IDLE_CLEANUP_CONCURRENCY, |session_id| async move {
+                if crate::xpra_hibernate::enabled(&self.config) && self.hibernate_idle_session(session_id.clone(), now, timeout).await {
+                    return;
                 }
-                
-                // Release display number
-                crate::xpra_pool::DISPLAY_POOL.release(session.display).await;
-            }
+                let idle = |session: &SessionInfo| is_idle(session, now, timeout);
+                self.terminate_session_if(&session_id, TerminationReason::IdleTimeout, idle).await;
+            })
+            .await;
+    }
+
+    /// Hibernate a session still idle for longer than `timeout` at `now`,
+    /// unless it runs on a worker host or holds a GPU or a network
+    /// namespace, which cannot be checkpointed. Returns whether it was
+    /// hibernated.
+    async fn hibernate_idle_session(&self, session_id: String, now: Instant, timeout: Duration) -> bool {
+        let Some(mut session) = self.sessions.get_mut(&session_id) else {
+            return false;
+        };
+        let local = self.hosts.get(&session.host).is_some_and(|h| h.kind == HostKind::Local);
+        if !is_idle(&session, now, timeout) || !local || session.gpu.is_some() || session.config.network.is_some() {
+            return false;
+        }
+        session.hibernated = true;
+        session.hibernate.notify_one();
+        info!(
+            user = session.user,
+            display = session.display,
+            "Hibernating idle Xpra session"
+        );
+        let event = SessionEvent {
+            timestamp: Utc::now(),
+            seq: None,
+            event_type: SessionEventType::Hibernated,
+            session_id,
+            user: session.user.clone(),
+            display: session.display,
+            labels: session.labels.clone(),
+            state: Some(session.lifecycle.state()),
+            reason: None,
+            crash_bundle: None,
+            name: None,
+            description: None,
+            parent: None,
+            device: None,
+            traffic: None,
+            host: None,
+        };
+        drop(session);
+        if let Err(e) = self.logger.log_session_event(event).await {
+            error!("Failed to log session hibernation: {}", e);
+        }
+        true
+    }
+}
+
+/// Whether an interactive session has seen no activity for longer than
+/// `timeout` at `now`. Headless sessions have idle timeouts of their own,
+/// and hibernated ones are idle already.
+fn is_idle(info: &SessionInfo, now: Instant, timeout: Duration) -> bool {
+    !info.hibernated && !info.headless && now.saturating_duration_since(info.last_activity) > timeout
+}
+
+/// Handle of a registered session that deregisters it when finished or
+/// dropped: the session's [`XpraDisplay`] is closed, returning its number to
+/// the pool, the session leaves the monitor, is counted as ended or failed
+/// in the metrics and its end is logged, on every path out of the session
+/// including panics. A guard dropped without finishing hands the session to
+/// the monitor, which does the same in the background.
+#[derive(Debug)]
+#[must_use = "dropping the guard deregisters the session"]
+pub struct SessionGuard {
+    monitor: SessionMonitor,
+    session_id: String,
+    info: SessionInfo,
+    reason: TerminationReason,
+    crash_bundle: Option<PathBuf>,
+    attachments: Option<mpsc::Receiver<Attachment>>,
+    display: Option<XpraDisplay>,
+    /// Set once the display is closed
+    stopped: Option<watch::Sender<bool>>,
+    finished: bool,
+}
+
+impl SessionGuard {
+    /// Id of the session
+    pub fn session_id(&self) -> &str {
+        &self.session_id
+    }
+
+    /// The session as registered, with the handles used by its forwarder
+    pub fn info(&self) -> &SessionInfo {
+        &self.info
+    }
+
+    /// Display of the session
+    pub fn display(&self) -> &XpraDisplay {
+        self.display.as_ref().expect("the display is closed when finishing")
+    }
+
+    /// Display of the session, for its forwarder to drive
+    pub fn display_mut(&mut self) -> &mut XpraDisplay {
+        self.display.as_mut().expect("the display is closed when finishing")
+    }
+
+    /// Shells of clients attaching to the session, for its forwarder to
+    /// take over. Only returned once.
+    pub fn take_attachments(&mut self) -> Option<mpsc::Receiver<Attachment>> {
+        self.attachments.take()
+    }
+
+    /// Record why the session ended, logged when it is deregistered.
+    /// Sessions end for a client disconnect unless told otherwise.
+    pub fn set_reason(&mut self, reason: TerminationReason) {
+        self.reason = reason;
+    }
+
+    /// Reference the crash bundle collected for the session's xpra from the
+    /// `Failed` event logged when it is deregistered.
+    pub fn set_crash_bundle(&mut self, bundle: PathBuf) {
+        self.crash_bundle = Some(bundle);
+    }
+
+    /// Count the session as failed with an error when it is deregistered.
+    pub fn fail(&mut self) {
+        self.reason = TerminationReason::Error;
+    }
+
+    /// Close the session's display and deregister the session.
+    pub async fn finish(mut self) {
+        if let Some(display) = self.display.take() {
+            display.close().await;
+        }
+        if let Some(stopped) = self.stopped.take() {
+            let _ = stopped.send(true);
         }
+        self.finished = true;
+        self.monitor.deregister(&self.session_id, self.reason, self.crash_bundle.take()).await;
     }
 }
 
-// Global monitor instance
-lazy_static::lazy_static! {
-    pub static ref SESSION_MONITOR: SessionMonitor = SessionMonitor::new();
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        let reason = match std::thread::panicking() {
+            true => TerminationReason::Error,
+            false => self.reason,
+        };
+        let dropped = DroppedSession {
+            session_id: std::mem::take(&mut self.session_id),
+            reason,
+            crash_bundle: self.crash_bundle.take(),
+            display: self.display.take(),
+            stopped: self.stopped.take(),
+        };
+        // The display is closed as it drops if the monitor is gone
+        if let Err(mpsc::error::SendError(dropped)) = self.monitor.dropped.send(dropped) {
+            error!(session_id = dropped.session_id, "Monitor gone, dropped session is not deregistered");
+        }
+    }
+}
+
+#[cfg(test)]
+impl SessionMonitor {
+    /// Register a session of Alice whose display is a `sleep` on the first
+    /// host of the monitor, returning its guard and the pid of the `sleep`.
+    /// Shared by the tests of the modules that track sessions.
+    pub(crate) async fn register_sleeping(
+        &self,
+        id: &str,
+        parent: Option<ParentShell>,
+        labels: Labels,
+    ) -> (SessionGuard, u32) {
+        let host = match self.hosts.hosts().first() {
+            Some(host) => host.clone(),
+            None => Arc::new(crate::xpra_hosts::WorkerHost::local(&self.config, crate::xpra_pool::DisplayPool::new())),
+        };
+        let (display, pid) = XpraDisplay::sleeping(host).await;
+        let guard = self.register_session(id.to_string(), "alice".to_string(), display, parent, labels).await;
+        (guard, pid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parent(shell: u32) -> ParentShell {
+        ParentShell { session: "k3xq9v".to_string(), shell: Sid(shell) }
+    }
+
+    async fn register(monitor: &SessionMonitor, id: &str, parent: Option<ParentShell>) -> (SessionGuard, u32) {
+        monitor.register_sleeping(id, parent, Labels::new()).await
+    }
+
+    /// Stand in for the forwarder of a session, finishing its guard once
+    /// the session is terminated.
+    fn forward(guard: SessionGuard) {
+        let shutdown = guard.info().shutdown.clone();
+        tokio::spawn(async move {
+            shutdown.notified().await;
+            guard.finish().await;
+        });
+    }
+
+    fn is_running(pid: u32) -> bool {
+        std::path::Path::new(&format!("/proc/{pid}")).exists()
+    }
+
+    #[tokio::test]
+    async fn test_guard_deregisters_on_panic() {
+        let dir = std::env::temp_dir().join(format!("sshx-monitor-{}", std::process::id()));
+        let config = Arc::new(XpraConfig { log_dir: dir.clone(), instance: None, ..XpraConfig::default() });
+        let metrics = Arc::new(XpraMetrics::new());
+        let monitor = SessionMonitor::new(config, XpraLogger::new(dir.clone()), metrics.clone());
+        let (guard, pid) = register(&monitor, "xpra-1", Some(parent(1))).await;
+        let pool = guard.display().host().pool.clone();
+        assert_eq!(pool.allocated_count().await, 1);
+        assert_eq!(monitor.get_user_session_count("alice").await, 1);
+        assert_eq!(monitor.get_user_hosts("alice").await, HashMap::from([("local".to_string(), 1)]));
+        assert_eq!(monitor.session_ids(), ["xpra-1"]);
+        let users = monitor.collect_sessions(|_, info| (info.display > 0).then(|| info.user.clone()));
+        assert_eq!(users, ["alice"]);
+        let forwarder = tokio::spawn(async move {
+            let _guard = guard;
+            panic!("forwarder crashed");
+        });
+        assert!(forwarder.await.is_err());
+
+        // The monitor closes and deregisters the session of a dropped guard
+        let deregistered = async {
+            while monitor.get_session("xpra-1").await.is_some() {
+                tokio::task::yield_now().await;
+            }
+        };
+        time::timeout(Duration::from_secs(5), deregistered).await.unwrap();
+        assert_eq!(monitor.get_user_session_count("alice").await, 0);
+        assert_eq!(monitor.session_count(), 0);
+        // The display was closed first, returning its number
+        assert_eq!(pool.allocated_count().await, 0);
+        assert!(!is_running(pid));
+        let snapshot = metrics.get_metrics();
+        assert_eq!(snapshot.active_sessions, 0);
+        assert_eq!(snapshot.failed_sessions, 1);
+        assert!(metrics.consistency().is_consistent());
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn test_rename_session() {
+        let dir = std::env::temp_dir().join(format!("sshx-rename-{}", std::process::id()));
+        let config = Arc::new(XpraConfig { log_dir: dir.clone(), instance: None, ..XpraConfig::default() });
+        let logger = XpraLogger::new(dir.clone());
+        let monitor = SessionMonitor::new(config, logger.clone(), Arc::new(XpraMetrics::new()));
+        let labels = Labels::from([("name".to_string(), "xpra-42".to_string())]);
+        let (guard, _) = monitor.register_sleeping("xpra-2", Some(parent(2)), labels).await;
+        // The name label is the name until the user gives another
+        assert_eq!(guard.info().name.as_deref(), Some("xpra-42"));
+
+        let description = Some("Quarterly numbers".to_string());
+        assert!(monitor.rename_session("xpra-2", Some("Q3 reporting desktop".to_string()), description).await);
+        assert!(!monitor.rename_session("xpra-9", None, None).await);
+        let session = monitor.get_session("xpra-2").await.unwrap();
+        assert_eq!(session.name.as_deref(), Some("Q3 reporting desktop"));
+        assert_eq!(session.description.as_deref(), Some("Quarterly numbers"));
+
+        let events = logger.history("xpra-2", None).await.unwrap();
+        let renamed = events.iter().find(|e| matches!(e.event_type, SessionEventType::Renamed)).unwrap();
+        assert_eq!(renamed.name.as_deref(), Some("Q3 reporting desktop"));
+        assert_eq!(renamed.description.as_deref(), Some("Quarterly numbers"));
+
+        guard.finish().await;
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn test_terminate_spawned() {
+        let dir = std::env::temp_dir().join(format!("sshx-spawned-{}", std::process::id()));
+        let config = Arc::new(XpraConfig { log_dir: dir.clone(), instance: None, ..XpraConfig::default() });
+        let monitor = SessionMonitor::new(config, XpraLogger::new(dir.clone()), Arc::new(XpraMetrics::new()));
+        for (id, parent) in [("xpra-1", Some(parent(1))), ("xpra-2", Some(parent(2))), ("headless-1", None)] {
+            forward(register(&monitor, id, parent).await.0);
+        }
+
+        let reason = TerminationReason::AdminKill;
+        assert_eq!(monitor.terminate_spawned("k3xq9v", Some(Sid(2)), reason).await, ["xpra-2"]);
+        assert!(monitor.terminate_spawned("p8wm2d", None, reason).await.is_empty());
+        assert_eq!(monitor.terminate_spawned("k3xq9v", None, reason).await, ["xpra-1"]);
+        assert_eq!(monitor.session_ids(), ["headless-1"]);
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn test_cleanup_idle_sessions() {
+        let dir = std::env::temp_dir().join(format!("sshx-idle-cleanup-{}", std::process::id()));
+        let config = Arc::new(XpraConfig { log_dir: dir.clone(), instance: None, ..XpraConfig::default() });
+        let logger = XpraLogger::new(dir.clone());
+        let metrics = Arc::new(XpraMetrics::new());
+        let monitor = SessionMonitor::new(config, logger.clone(), metrics.clone());
+        let mut pids = Vec::new();
+        for shell in 0..=IDLE_CLEANUP_CONCURRENCY as u32 {
+            let (id, parent) = match shell {
+                0 => ("headless-1".to_string(), None),
+                _ => (format!("xpra-{shell}"), Some(parent(shell))),
+            };
+            let (guard, pid) = register(&monitor, &id, parent).await;
+            forward(guard);
+            pids.push(pid);
+        }
+        time::sleep(Duration::from_millis(10)).await;
+
+        monitor.cleanup_idle_sessions(Duration::from_millis(1)).await;
+        // Headless sessions time out on their own
+        assert_eq!(monitor.session_ids(), ["headless-1"]);
+        assert_eq!(monitor.get_user_session_count("alice").await, 1);
+        assert_eq!(metrics.get_metrics().idle_terminations, IDLE_CLEANUP_CONCURRENCY as u64);
+        // No xpra outlives its idle session
+        assert!(is_running(pids[0]));
+        assert!(!pids[1..].iter().any(|&pid| is_running(pid)));
+
+        let events = logger.history("xpra-3", None).await.unwrap();
+        let last = events.last().unwrap();
+        assert!(matches!(last.event_type, SessionEventType::IdleTimeout));
+        assert_eq!(last.reason, Some(TerminationReason::IdleTimeout));
+
+        // Activity between collecting a session and removing it keeps it
+        let (guard, pid) = register(&monitor, "xpra-9", Some(parent(9))).await;
+        forward(guard);
+        time::sleep(Duration::from_millis(10)).await;
+        let now = Instant::now();
+        assert!(monitor.sessions.get("xpra-9").is_some_and(|session| is_idle(&session, now, Duration::from_millis(1))));
+        monitor.update_activity("xpra-9").await;
+        let idle = |session: &SessionInfo| is_idle(session, now, Duration::from_millis(1));
+        assert!(!monitor.terminate_session_if("xpra-9", TerminationReason::IdleTimeout, idle).await);
+        assert!(is_running(pid));
+        let now = Instant::now() + Duration::from_secs(1);
+        let idle = |session: &SessionInfo| is_idle(session, now, Duration::from_millis(1));
+        assert!(monitor.terminate_session_if("xpra-9", TerminationReason::IdleTimeout, idle).await);
+        assert!(!is_running(pid));
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn test_terminate_kills_xpra() {
+        let dir = std::env::temp_dir().join(format!("sshx-terminate-{}", std::process::id()));
+        let config = Arc::new(XpraConfig { log_dir: dir.clone(), instance: None, stop_timeout: 1, ..XpraConfig::default() });
+        let monitor = SessionMonitor::new(config, XpraLogger::new(dir.clone()), Arc::new(XpraMetrics::new()));
+
+        let (guard, pid) = register(&monitor, "xpra-1", Some(parent(1))).await;
+        let termination = guard.info().termination.clone();
+        forward(guard);
+        assert!(monitor.terminate_session("xpra-1", TerminationReason::AdminKill).await);
+        assert!(!is_running(pid));
+        // The forwarder tells its client why
+        assert_eq!(termination.get().map(|notice| notice.reason), Some(TerminationReason::AdminKill));
+
+        // A forwarder that ignores the shutdown is aborted after the stop timeout
+        let (guard, pid) = register(&monitor, "xpra-2", Some(parent(2))).await;
+        let abort = guard.info().abort.clone();
+        tokio::spawn(async move {
+            abort.notified().await;
+            guard.finish().await;
+        });
+        let start = Instant::now();
+        assert!(monitor.terminate_session("xpra-2", TerminationReason::IdleTimeout).await);
+        assert!(start.elapsed() >= Duration::from_secs(1));
+        assert!(!is_running(pid));
+        assert!(!monitor.terminate_session("xpra-2", TerminationReason::AdminKill).await);
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn test_x_input_idle_source() {
+        let dir = std::env::temp_dir().join(format!("sshx-idle-{}", std::process::id()));
+        let config = Arc::new(XpraConfig {
+            log_dir: dir.clone(),
+            instance: None,
+            idle_source: IdleSource::XInput,
+            ..XpraConfig::default()
+        });
+        let monitor = SessionMonitor::new(config, XpraLogger::new(dir.clone()), Arc::new(XpraMetrics::new()));
+        let (guard, _) = register(&monitor, "xpra-3", Some(parent(1))).await;
+        let started = guard.info().last_activity;
+
+        // Channel traffic, such as a playing video, does not count
+        monitor.update_activity("xpra-3").await;
+        let session = monitor.get_session("xpra-3").await.unwrap();
+        assert_eq!(session.last_activity, started);
+        assert!(session.channel_activity > started);
+
+        // Unless the X server cannot be asked
+        monitor.sessions.get_mut("xpra-3").unwrap().idle_fallback = true;
+        monitor.update_activity("xpra-3").await;
+        assert!(monitor.get_session("xpra-3").await.unwrap().last_activity > started);
+
+        guard.finish().await;
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn test_recording_acknowledged() {
+        let dir = std::env::temp_dir().join(format!("sshx-recording-{}", std::process::id()));
+        let mut config = XpraConfig { log_dir: dir.clone(), instance: None, ..XpraConfig::default() };
+        config.screenshots.interval_minutes = 5;
+        let logger = XpraLogger::new(dir.clone());
+        let monitor = SessionMonitor::new(Arc::new(config), logger.clone(), Arc::new(XpraMetrics::new()));
+        let (guard, _) = register(&monitor, "xpra-4", Some(parent(1))).await;
+
+        // Recorded sessions are only captured once the user acknowledged it
+        assert!(guard.info().recording && !guard.info().recording_acknowledged);
+        monitor.recording_acknowledged("xpra-4").await;
+        assert!(monitor.get_session("xpra-4").await.unwrap().recording_acknowledged);
+        let events = logger.history("xpra-4", None).await.unwrap();
+        assert!(events.iter().any(|e| matches!(e.event_type, SessionEventType::RecordingAcknowledged)));
+
+        guard.finish().await;
+        let _ = std::fs::remove_dir_all(dir);
+    }
 }
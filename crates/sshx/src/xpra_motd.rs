@@ -0,0 +1,155 @@
+//! Message of the day shown on each new desktop.
+//!
+//! Deployments whose acceptable-use policy must be shown to users configure
+//! a `motd`: a text with `{user}`, `{host}`, `{display}`, `{idle_timeout}`
+//! and `{support}` placeholders, shown once when a desktop starts, either
+//! as a dialog the user dismisses (zenity, or xmessage where zenity is not
+//! installed) or rendered onto the desktop background with ImageMagick and
+//! feh. The message runs as one of the desktop's programs, never restarted.
+
+use serde::{Deserialize, Serialize};
+
+use crate::xpra_children::{ChildProgram, RestartPolicy};
+use crate::xpra_hosts::shell_quote;
+use crate::xpra_time_format::format_duration;
+
+/// How the message of the day is shown.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MotdStyle {
+    /// Not shown
+    #[default]
+    Off,
+    /// In a dialog the user dismisses
+    Dialog,
+    /// As text on the desktop background
+    Wallpaper,
+}
+
+/// Message shown on each new desktop.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MotdConfig {
+    /// How the message is shown
+    #[serde(default)]
+    pub style: MotdStyle,
+
+    /// Title of the dialog
+    #[serde(default = "default_title")]
+    pub title: String,
+
+    /// Text of the message, with placeholders
+    #[serde(default)]
+    pub text: String,
+
+    /// Who to contact for help, for the `{support}` placeholder
+    #[serde(default)]
+    pub support: String,
+}
+
+fn default_title() -> String { "Welcome".to_string() }
+
+impl Default for MotdConfig {
+    fn default() -> Self {
+        Self {
+            style: MotdStyle::default(),
+            title: default_title(),
+            text: String::new(),
+            support: String::new(),
+        }
+    }
+}
+
+/// Values of the placeholders for one desktop.
+#[derive(Debug, Clone)]
+pub struct MotdVars<'a> {
+    /// User owning the desktop
+    pub user: &'a str,
+    /// Host running the desktop
+    pub host: &'a str,
+    /// Display number of the desktop
+    pub display: u16,
+    /// Seconds without input before the desktop is ended, 0 for never
+    pub idle_timeout: u64,
+}
+
+impl MotdConfig {
+    /// The message with its placeholders filled in.
+    pub fn render(&self, vars: &MotdVars) -> String {
+        let idle_timeout = match vars.idle_timeout {
+            0 => "never".to_string(),
+            secs => format_duration(secs),
+        };
+        self.text
+            .replace("{user}", vars.user)
+            .replace("{host}", vars.host)
+            .replace("{display}", &vars.display.to_string())
+            .replace("{idle_timeout}", &idle_timeout)
+            .replace("{support}", &self.support)
+    }
+
+    /// The program showing the message on a desktop, if one is configured.
+    pub fn program(&self, vars: &MotdVars) -> Option<ChildProgram> {
+        if self.text.is_empty() {
+            return None;
+        }
+        let message = self.render(vars);
+        let command = match self.style {
+            MotdStyle::Off => return None,
+            MotdStyle::Dialog => dialog(&self.title, &message),
+            MotdStyle::Wallpaper => {
+                // The text goes in on stdin, so none of it is read as an
+                // ImageMagick option or file; the image lives in a private
+                // directory only until feh has set it
+                let text = shell_quote(&message);
+                format!(
+                    "dir=$(mktemp -d) || exit 1; trap 'rm -rf \"$dir\"' EXIT; \
+                     printf '%s' {text} | convert -background '#1e1e1e' -fill white -pointsize 22 \
+                     -gravity center label:@- \"$dir/motd.png\" && \
+                     feh --no-fehbg --bg-center \"$dir/motd.png\""
+                )
+            }
+        };
+        Some(ChildProgram { name: "motd".to_string(), command, restart: RestartPolicy::Never })
+    }
+}
+
+/// Command showing `text` in a dialog the user dismisses, which exits
+/// successfully once they click OK.
+pub(crate) fn dialog(title: &str, text: &str) -> String {
+    let (title, text) = (shell_quote(title), shell_quote(text));
+    format!(
+        "if command -v zenity >/dev/null; then \
+         zenity --info --no-markup --no-wrap --title {title} --text {text}; \
+         else xmessage -center -title {title} {text}; fi"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let motd = MotdConfig {
+            style: MotdStyle::Dialog,
+            text: "Hi {user}, idle desktops on {host} end after {idle_timeout}. Help: {support}".to_string(),
+            support: "it@example.com".to_string(),
+            ..MotdConfig::default()
+        };
+        let vars = MotdVars { user: "alice", host: "worker1", display: 100, idle_timeout: 7200 };
+        assert_eq!(motd.render(&vars), "Hi alice, idle desktops on worker1 end after 2h 0m. Help: it@example.com");
+
+        let program = motd.program(&vars).unwrap();
+        assert_eq!(program.restart, RestartPolicy::Never);
+        assert!(program.command.contains("--text 'Hi alice, idle desktops on worker1 end after 2h 0m. Help: it@example.com'"));
+
+        let wallpaper = MotdConfig { style: MotdStyle::Wallpaper, text: "@/etc/shadow".to_string(), ..motd.clone() };
+        let command = wallpaper.program(&vars).unwrap().command;
+        assert!(command.starts_with("dir=$(mktemp -d) || exit 1;"));
+        assert!(command.contains("printf '%s' @/etc/shadow | convert"));
+        assert!(command.contains("label:@- \"$dir/motd.png\""));
+        assert!(!command.contains("/tmp/"));
+
+        assert!(MotdConfig { style: MotdStyle::Off, ..motd }.program(&vars).is_none());
+    }
+}
@@ -0,0 +1,420 @@
+//! Network isolation of desktops in their own network namespace.
+//!
+//! A desktop can reach whatever its host can, so a compromised one could
+//! scan the internal network. With `network.isolate` set, each display's
+//! xpra server, and so its window manager and programs, runs in a network
+//! namespace `sshx-<N>` joined to the host by a veth pair with a /30 taken
+//! from `network.subnet`. Traffic leaving the namespace is masqueraded by
+//! the host, and nftables rules of the host on the veth drop all of it
+//! except what the `egress` rules allow, so nothing in the namespace can
+//! lift them. Only the xpra port is reachable from outside, forwarded from
+//! the host's loopback and the address xpra would otherwise bind. The host
+//! forwards while it has namespaces, and only their traffic if it did not
+//! before. Setting up namespaces and nftables rules takes root on the
+//! desktop's host.
+
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::xpra_hosts::WorkerHost;
+
+/// Directory whose per-namespace files `ip netns exec` mounts over `/etc`.
+const NETNS_ETC: &str = "/etc/netns";
+
+/// Directory of the lock on a host's namespaces, and of the forwarding
+/// setting from before the first.
+const STATE_DIR: &str = "/run/sshx-netns";
+
+/// Rules keeping a host that did not forward before from forwarding
+/// anything but the namespaces' traffic.
+const FORWARD_RULES: &str = r#"table ip sshx_forward {
+  chain forward {
+    type filter hook forward priority filter;
+    iifname != "sxh*" oifname != "sxh*" drop
+  }
+}"#;
+
+/// Transport protocol an egress rule applies to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EgressProtocol {
+    /// TCP only
+    #[default]
+    Tcp,
+    /// UDP only
+    Udp,
+    /// Any protocol, or TCP and UDP when ports are given
+    Any,
+}
+
+/// IPv4 network in CIDR notation, like `10.0.0.0/8`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    /// First address of the network
+    pub network: Ipv4Addr,
+    /// Length of the network prefix
+    pub prefix: u8,
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (address, prefix) = s.split_once('/').unwrap_or((s, "32"));
+        let address: Ipv4Addr = address.parse().with_context(|| format!("invalid IPv4 address in {s:?}"))?;
+        let prefix: u8 = prefix.parse().ok().filter(|p| *p <= 32).with_context(|| format!("invalid prefix in {s:?}"))?;
+        let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+        Ok(Self { network: Ipv4Addr::from(u32::from(address) & mask), prefix })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+impl Serialize for Cidr {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Cidr {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(|e: anyhow::Error| serde::de::Error::custom(format!("{e:#}")))
+    }
+}
+
+/// Destinations a desktop may connect to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EgressRule {
+    /// Destination network, e.g. `203.0.113.0/24`
+    pub cidr: Cidr,
+
+    /// Destination ports, any if empty
+    #[serde(default)]
+    pub ports: Vec<u16>,
+
+    /// Protocol the rule applies to
+    #[serde(default)]
+    pub protocol: EgressProtocol,
+}
+
+impl EgressRule {
+    /// nftables rule accepting the traffic.
+    fn nft_rule(&self) -> String {
+        let ports: Vec<String> = self.ports.iter().map(|p| p.to_string()).collect();
+        let ports = ports.join(", ");
+        let matches = match (self.protocol, self.ports.is_empty()) {
+            (EgressProtocol::Any, true) => String::new(),
+            (EgressProtocol::Tcp, true) => " meta l4proto tcp".to_string(),
+            (EgressProtocol::Udp, true) => " meta l4proto udp".to_string(),
+            (EgressProtocol::Tcp, false) => format!(" tcp dport {{ {ports} }}"),
+            (EgressProtocol::Udp, false) => format!(" udp dport {{ {ports} }}"),
+            (EgressProtocol::Any, false) => format!(" meta l4proto {{ tcp, udp }} th dport {{ {ports} }}"),
+        };
+        format!("ip daddr {}{} accept", self.cidr, matches)
+    }
+}
+
+/// Network isolation of the desktops.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NetworkConfig {
+    /// Run each display in its own network namespace
+    #[serde(default)]
+    pub isolate: bool,
+
+    /// Addresses the namespaces are joined to their host with, a /30 each
+    #[serde(default = "default_subnet")]
+    pub subnet: Cidr,
+
+    /// Connections desktops may make; everything else is dropped
+    #[serde(default)]
+    pub egress: Vec<EgressRule>,
+
+    /// Name servers of the desktops, which they may always query
+    #[serde(default)]
+    pub dns_servers: Vec<Ipv4Addr>,
+}
+
+fn default_subnet() -> Cidr {
+    Cidr { network: Ipv4Addr::new(10, 213, 0, 0), prefix: 16 }
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            isolate: false,
+            subnet: default_subnet(),
+            egress: Vec::new(),
+            dns_servers: Vec::new(),
+        }
+    }
+}
+
+impl NetworkConfig {
+    /// The namespace display `number` runs in, if displays are isolated.
+    pub fn for_display(&self, number: u16) -> Result<Option<SessionNetwork>> {
+        if !self.isolate {
+            return Ok(None);
+        }
+        if self.subnet.prefix > 30 {
+            anyhow::bail!("network subnet {} is too small for a /30 per display", self.subnet);
+        }
+        let slots = 1u32 << (30 - self.subnet.prefix);
+        if number as u32 >= slots {
+            anyhow::bail!("network subnet {} has no /30 for display {}", self.subnet, number);
+        }
+        let base = u32::from(self.subnet.network) + number as u32 * 4;
+        Ok(Some(SessionNetwork {
+            namespace: format!("sshx-{number}"),
+            gateway: Ipv4Addr::from(base + 1),
+            address: Ipv4Addr::from(base + 2),
+            egress: self.egress.clone(),
+            dns_servers: self.dns_servers.clone(),
+        }))
+    }
+}
+
+/// Network namespace a display runs in, and what it may reach.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionNetwork {
+    /// Name of the network namespace
+    pub namespace: String,
+    /// Address of the host's end of the veth pair
+    pub gateway: Ipv4Addr,
+    /// Address of the display inside the namespace
+    pub address: Ipv4Addr,
+    /// Connections the display may make
+    pub egress: Vec<EgressRule>,
+    /// Name servers the display may query
+    pub dns_servers: Vec<Ipv4Addr>,
+}
+
+impl SessionNetwork {
+    /// Command and arguments that run a program inside the namespace.
+    pub fn command_prefix(&self) -> Vec<String> {
+        vec!["ip".to_string(), "netns".to_string(), "exec".to_string(), self.namespace.clone()]
+    }
+
+    /// Create the namespace, replacing any left over from an earlier
+    /// display, and apply its rules, letting xpra be reached on `port`.
+    pub async fn setup(&self, host: &WorkerHost, port: u16) -> Result<()> {
+        let args = ["-c".to_string(), self.setup_script(port, &host.bind_address)];
+        let output = tokio::process::Command::from(host.command("sh", &args))
+            .output()
+            .await
+            .context("failed to run sh")?;
+        if !output.status.success() {
+            self.teardown(host).await;
+            anyhow::bail!(
+                "failed to set up network namespace {}: {}",
+                self.namespace,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        debug!(host = host.name, namespace = self.namespace, address = %self.address, "Set up network namespace");
+        Ok(())
+    }
+
+    /// Remove the namespace and its rules.
+    pub async fn teardown(&self, host: &WorkerHost) {
+        let args = ["-c".to_string(), self.teardown_script()];
+        match tokio::process::Command::from(host.command("sh", &args)).status().await {
+            Ok(status) if status.success() => {}
+            Ok(status) => warn!(host = host.name, namespace = self.namespace, "Network namespace teardown exited with {}", status),
+            Err(e) => warn!(host = host.name, namespace = self.namespace, "Failed to remove network namespace: {}", e),
+        }
+    }
+
+    /// Names of the host's end of the veth pair, the end in the namespace
+    /// and the host's nftables table.
+    fn names(&self) -> (String, String, String) {
+        let suffix = self.namespace.trim_start_matches("sshx-");
+        (format!("sxh{suffix}"), format!("sxn{suffix}"), format!("sshx_{suffix}"))
+    }
+
+    fn setup_script(&self, port: u16, bind: &str) -> String {
+        let (ns, gateway, address) = (&self.namespace, self.gateway, self.address);
+        let (host_if, ns_if, table) = self.names();
+        let mut resolv = String::new();
+        for server in &self.dns_servers {
+            resolv.push_str(&format!("nameserver {server}\n"));
+        }
+        format!(
+            r#"set -e
+{teardown}
+ip netns add {ns}
+ip link add {host_if} type veth peer name {ns_if} netns {ns}
+ip addr add {gateway}/30 dev {host_if}
+sysctl -qw net.ipv4.conf.{host_if}.route_localnet=1
+ip link set {host_if} up
+ip -n {ns} link set lo up
+ip -n {ns} addr add {address}/30 dev {ns_if}
+ip -n {ns} link set {ns_if} up
+ip -n {ns} route add default via {gateway}
+nft -f - <<'EOF'
+{host_rules}
+EOF
+if [ ! -e {STATE_DIR}/ip_forward ]; then
+  sysctl -n net.ipv4.ip_forward > {STATE_DIR}/ip_forward
+  if [ "$(cat {STATE_DIR}/ip_forward)" = 0 ]; then nft -f - <<'EOF'
+{FORWARD_RULES}
+EOF
+  fi
+  sysctl -qw net.ipv4.ip_forward=1
+fi
+if [ -n '{resolv}' ]; then mkdir -p {NETNS_ETC}/{ns} && printf '{resolv}' > {NETNS_ETC}/{ns}/resolv.conf; fi
+"#,
+            teardown = self.teardown_script(),
+            host_rules = self.host_rules(&table, &host_if, port, bind),
+            resolv = resolv.replace('\n', "\\n"),
+        )
+    }
+
+    /// Removes the namespace, and turns forwarding back off once the last
+    /// namespace is gone if it was off before the first. Holds the lock on
+    /// the host's namespaces until the shell running it exits.
+    fn teardown_script(&self) -> String {
+        let ns = &self.namespace;
+        let (host_if, _, table) = self.names();
+        format!(
+            r#"mkdir -p {STATE_DIR} && exec 9>{STATE_DIR}/lock && flock 9
+ip netns del {ns} 2>/dev/null || true
+ip link del {host_if} 2>/dev/null || true
+nft delete table ip {table} 2>/dev/null || true
+rm -rf {NETNS_ETC}/{ns}
+if [ -e {STATE_DIR}/ip_forward ] && ! ip -o link show type veth | grep -q ': sxh'; then
+  sysctl -qw net.ipv4.ip_forward="$(cat {STATE_DIR}/ip_forward)"
+  nft delete table ip sshx_forward 2>/dev/null || true
+  rm -f {STATE_DIR}/ip_forward
+fi"#
+        )
+    }
+
+    /// Rules on the host, out of reach of the namespace: masquerading its
+    /// traffic, dropping all of it except replies, the name servers and the
+    /// egress rules, and forwarding xpra's port to it from the host's
+    /// loopback and from `bind`, where xpra would listen without one.
+    fn host_rules(&self, table: &str, host_if: &str, port: u16, bind: &str) -> String {
+        let (gateway, address) = (self.gateway, self.address);
+        let mut allowed = Vec::new();
+        if !self.dns_servers.is_empty() {
+            let servers: Vec<String> = self.dns_servers.iter().map(|s| s.to_string()).collect();
+            allowed.push(format!("ip daddr {{ {} }} meta l4proto {{ tcp, udp }} th dport 53 accept", servers.join(", ")));
+        }
+        allowed.extend(self.egress.iter().map(EgressRule::nft_rule));
+        let allowed: String = allowed.iter().map(|rule| format!("\n    iifname \"{host_if}\" {rule}")).collect();
+        // The loopback is always forwarded, for the forwarder and ssh tunnels
+        let exposed = match bind {
+            "0.0.0.0" | "::" => format!("\n    fib daddr type local tcp dport {port} dnat to {address}"),
+            bind => match bind.parse::<Ipv4Addr>() {
+                Ok(bind) if !bind.is_loopback() => format!("\n    ip daddr {bind} tcp dport {port} dnat to {address}"),
+                _ => String::new(),
+            },
+        };
+        format!(
+            r#"table ip {table} {{
+  chain prerouting {{
+    type nat hook prerouting priority dstnat;{exposed}
+  }}
+  chain output {{
+    type nat hook output priority dstnat;
+    ip daddr 127.0.0.1 tcp dport {port} dnat to {address}
+  }}
+  chain postrouting {{
+    type nat hook postrouting priority srcnat;
+    oifname "{host_if}" ip saddr 127.0.0.0/8 snat to {gateway}
+    ip saddr {address} oifname != "{host_if}" masquerade
+  }}
+  chain input {{
+    type filter hook input priority filter;
+    iifname "{host_if}" ct state established,related accept{allowed}
+    iifname "{host_if}" drop
+  }}
+  chain forward {{
+    type filter hook forward priority filter;
+    iifname "{host_if}" ct state established,related accept{allowed}
+    iifname "{host_if}" drop
+    oifname "{host_if}" ct state established,related accept
+    oifname "{host_if}" tcp dport {port} accept
+    oifname "{host_if}" drop
+  }}
+}}"#
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cidr() {
+        let cidr: Cidr = "10.1.2.3/8".parse().unwrap();
+        assert_eq!(cidr.to_string(), "10.0.0.0/8");
+        assert_eq!("203.0.113.7".parse::<Cidr>().unwrap().to_string(), "203.0.113.7/32");
+        assert_eq!("0.0.0.0/0".parse::<Cidr>().unwrap().to_string(), "0.0.0.0/0");
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/8; flush ruleset".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn test_for_display() {
+        assert_eq!(NetworkConfig::default().for_display(100).unwrap(), None);
+
+        let config = NetworkConfig { isolate: true, ..NetworkConfig::default() };
+        let network = config.for_display(100).unwrap().unwrap();
+        assert_eq!(network.namespace, "sshx-100");
+        assert_eq!(network.gateway, Ipv4Addr::new(10, 213, 1, 145));
+        assert_eq!(network.address, Ipv4Addr::new(10, 213, 1, 146));
+        assert_eq!(network.command_prefix(), ["ip", "netns", "exec", "sshx-100"]);
+
+        // Displays never share a /30
+        let config = NetworkConfig { subnet: "10.213.0.0/24".parse().unwrap(), ..config };
+        assert_eq!(config.for_display(63).unwrap().unwrap().address, Ipv4Addr::new(10, 213, 0, 254));
+        assert!(config.for_display(64).is_err());
+        let config = NetworkConfig { subnet: "10.0.0.0/31".parse().unwrap(), ..config };
+        assert!(config.for_display(0).is_err());
+    }
+
+    #[test]
+    fn test_host_rules() {
+        let network = SessionNetwork {
+            namespace: "sshx-100".to_string(),
+            gateway: Ipv4Addr::new(10, 213, 1, 145),
+            address: Ipv4Addr::new(10, 213, 1, 146),
+            egress: vec![
+                EgressRule { cidr: "0.0.0.0/0".parse().unwrap(), ports: vec![80, 443], protocol: EgressProtocol::Tcp },
+                EgressRule { cidr: "10.20.0.5/32".parse().unwrap(), ports: vec![], protocol: EgressProtocol::Any },
+            ],
+            dns_servers: vec![Ipv4Addr::new(10, 0, 0, 53)],
+        };
+        let rules = network.host_rules("sshx_100", "sxh100", 14500, "127.0.0.1");
+        assert!(rules.contains(r#"oifname "sxh100" tcp dport 14500 accept"#));
+        assert!(rules.contains(r#"iifname "sxh100" ip daddr { 10.0.0.53 } meta l4proto { tcp, udp } th dport 53 accept"#));
+        assert!(rules.contains(r#"iifname "sxh100" ip daddr 0.0.0.0/0 tcp dport { 80, 443 } accept"#));
+        assert!(rules.contains(r#"iifname "sxh100" ip daddr 10.20.0.5/32 accept"#));
+        // Only loopback reaches xpra on a host binding it
+        assert!(rules.contains("ip daddr 127.0.0.1 tcp dport 14500 dnat to 10.213.1.146"));
+        assert!(!rules.contains("fib daddr"));
+        let rules = network.host_rules("sshx_100", "sxh100", 14500, "192.0.2.10");
+        assert!(rules.contains("ip daddr 192.0.2.10 tcp dport 14500 dnat to 10.213.1.146"));
+        assert!(!rules.contains("fib daddr"));
+        assert!(network.host_rules("sshx_100", "sxh100", 14500, "0.0.0.0").contains("fib daddr type local"));
+
+        let script = network.setup_script(14500, "127.0.0.1");
+        assert!(script.contains("printf 'nameserver 10.0.0.53\\n' > /etc/netns/sshx-100/resolv.conf"));
+        // Nothing is set up inside the namespace that it could undo
+        assert!(!script.contains("ip netns exec"));
+        assert!(script.contains("sysctl -qw net.ipv4.ip_forward=\"$(cat /run/sshx-netns/ip_forward)\""));
+        let syntax = std::process::Command::new("sh").args(["-n", "-c", &script]).status().unwrap();
+        assert!(syntax.success());
+    }
+}
@@ -0,0 +1,110 @@
+//! Bridge of the desktop notifications raised inside a session to its web
+//! client.
+//!
+//! xpra forwards freedesktop notifications to its client as `notify_show`
+//! and `notify_close` packets. The forwarder picks these out of the xpra
+//! output passing through it with [`crate::xpra_protocol::PacketWatcher`],
+//! so negotiated clients can get them as control messages and show them as
+//! browser notifications, even while the desktop is minimized.
+
+use serde::{Deserialize, Serialize};
+
+/// Notification raised inside a desktop session.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Notification {
+    /// Id of the notification within the session
+    pub id: u64,
+    /// Id of the notification this one replaces, 0 if none
+    pub replaces: u64,
+    /// Application that raised it
+    pub app_name: String,
+    /// One line summary
+    pub summary: String,
+    /// Body text, possibly with simple markup
+    pub body: String,
+    /// Milliseconds until it expires, -1 for the desktop's default and 0
+    /// to never expire
+    pub expire_timeout: i64,
+}
+
+/// Change of the notifications shown by a desktop.
+#[derive(Debug, Clone, PartialEq)]
+pub enum NotificationEvent {
+    /// A notification was raised or updated
+    Show(Notification),
+    /// The notification with this id was closed
+    Close(u64),
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use serde_json::json;
+
+    use super::*;
+    use crate::xpra_handshake::{ClientHello, ControlMessage, DesktopChannel, RotationPolicy, FRAME_CONTROL};
+    use crate::xpra_protocol::XpraPacket;
+
+    fn channel(notifications: bool) -> DesktopChannel {
+        let mut channel = DesktopChannel::new(RotationPolicy::default(), &[]);
+        let hello = ControlMessage::Hello(ClientHello { version: 1, notifications, ..Default::default() });
+        let mut frame = vec![FRAME_CONTROL];
+        frame.extend(serde_json::to_vec(&hello).unwrap());
+        channel.receive(Bytes::from(frame));
+        channel
+    }
+
+    fn packets() -> Vec<XpraPacket> {
+        let shown = Notification {
+            id: 7,
+            replaces: 0,
+            app_name: "chat".to_string(),
+            summary: "Ping".to_string(),
+            body: "Are you there?".to_string(),
+            expire_timeout: 5000,
+        };
+        vec![
+            XpraPacket::Notification(NotificationEvent::Show(shown)),
+            XpraPacket::Notification(NotificationEvent::Close(7)),
+        ]
+    }
+
+    #[test]
+    fn test_control_messages() {
+        let frames = channel(true).notifications(&packets());
+        let messages: Vec<serde_json::Value> = frames
+            .iter()
+            .map(|frame| {
+                assert_eq!(frame[0], FRAME_CONTROL);
+                serde_json::from_slice(&frame[1..]).unwrap()
+            })
+            .collect();
+        assert_eq!(messages, vec![
+            json!({
+                "type": "notification",
+                "id": 7,
+                "replaces": 0,
+                "app_name": "chat",
+                "summary": "Ping",
+                "body": "Are you there?",
+                "expire_timeout": 5000,
+            }),
+            json!({"type": "notification_closed", "id": 7}),
+        ]);
+    }
+
+    #[test]
+    fn test_not_sent_unless_asked_for() {
+        assert!(channel(false).notifications(&packets()).is_empty());
+        // Legacy clients never said hello
+        let legacy = DesktopChannel::new(RotationPolicy::default(), &[]);
+        assert!(legacy.notifications(&packets()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_notify_unknown_host() {
+        let hosts = crate::xpra_hosts::HostPool::new(&Default::default(), Default::default());
+        let err = crate::xpra::notify_display(&hosts, "nowhere", 100, "Maintenance", "Soon").await.unwrap_err();
+        assert_eq!(err.to_string(), "unknown desktop host nowhere");
+    }
+}
@@ -0,0 +1,344 @@
+//! Per-display capture of xpra's stdout and stderr, and reading it back so
+//! operators can follow a session's xpra output from the CLI.
+//!
+//! Output goes to `sessions/<host>-<display>.log` under the log directory,
+//! one line per output line prefixed with the time it was read. The file is
+//! truncated when the display number is reused, so it only ever holds the
+//! output of the current session on that display. Once it reaches
+//! [`MAX_LOG_BYTES`] it is moved to `<host>-<display>.log.1`, replacing the
+//! previous one, and a new file is started.
+
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::Child;
+use std::sync::{Arc, Mutex};
+use anyhow::{Context, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tracing::warn;
+
+use crate::xpra_config::XpraConfig;
+use crate::xpra_context::XpraContext;
+use crate::xpra_time_format::format_time;
+
+/// Size at which a session's xpra output log is rotated.
+pub const MAX_LOG_BYTES: u64 = 16 * 1024 * 1024;
+
+/// How much of a log [`read_tail`] reads at a time, going back from its end.
+const TAIL_CHUNK: u64 = 64 * 1024;
+
+/// Lines of a session's xpra output.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LogLines {
+    /// Lines read, oldest first
+    pub lines: Vec<String>,
+}
+
+/// File holding the xpra output of a display on a host, in the log
+/// directory of `config`.
+pub fn log_path(config: &XpraConfig, host: &str, display: u16) -> PathBuf {
+    config.instance_log_dir().join("sessions").join(format!("{}-{}.log", host, display))
+}
+
+/// File holding the xpra output of a running session of `context`.
+pub async fn session_log_path(context: &XpraContext, session_id: &str) -> Result<PathBuf> {
+    let session = context
+        .monitor
+        .get_session(session_id)
+        .await
+        .with_context(|| format!("no session {}", session_id))?;
+    Ok(log_path(&context.config, &session.host, session.display))
+}
+
+/// Copy the piped stdout and stderr of a spawned xpra process into `path`.
+///
+/// The pipes are drained even if the file cannot be written, so xpra never
+/// blocks on a full pipe.
+pub fn capture(process: &mut Child, path: &Path) {
+    let file = Arc::new(Mutex::new(OutputLog::open(path, MAX_LOG_BYTES)));
+
+    if let Some(stdout) = process.stdout.take() {
+        copy_lines(stdout, file.clone());
+    }
+    if let Some(stderr) = process.stderr.take() {
+        copy_lines(stderr, file);
+    }
+}
+
+/// The output log of one display, rotated once it reaches `max` bytes.
+struct OutputLog {
+    path: PathBuf,
+    /// `None` if the log could not be opened; output is then dropped
+    file: Option<File>,
+    written: u64,
+    max: u64,
+}
+
+impl OutputLog {
+    fn open(path: &Path, max: u64) -> Self {
+        let file = match open_log(path) {
+            Ok(file) => Some(file),
+            Err(e) => {
+                warn!(path = %path.display(), "Failed to open xpra output log: {:#}", e);
+                None
+            }
+        };
+        Self { path: path.to_path_buf(), file, written: 0, max }
+    }
+
+    fn write_line(&mut self, line: &str) {
+        let len = line.len() as u64 + 1;
+        if self.file.is_some() && self.written > 0 && self.written + len > self.max {
+            self.rotate();
+        }
+        if let Some(file) = &mut self.file {
+            if writeln!(file, "{}", line).is_ok() {
+                self.written += len;
+            }
+        }
+    }
+
+    fn rotate(&mut self) {
+        self.file = None;
+        self.written = 0;
+        if let Err(e) = std::fs::rename(&self.path, rotated_path(&self.path)) {
+            warn!(path = %self.path.display(), "Failed to rotate xpra output log: {}", e);
+        }
+        match File::create(&self.path) {
+            Ok(file) => self.file = Some(file),
+            Err(e) => warn!(path = %self.path.display(), "Failed to open xpra output log: {}", e),
+        }
+    }
+}
+
+/// Where the older part of a rotated log is kept.
+fn rotated_path(path: &Path) -> PathBuf {
+    let mut rotated = OsString::from(path.as_os_str());
+    rotated.push(".1");
+    PathBuf::from(rotated)
+}
+
+fn open_log(path: &Path) -> Result<File> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    // Output rotated out by the display's previous session
+    match std::fs::remove_file(rotated_path(path)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    Ok(File::create(path)?)
+}
+
+fn copy_lines(pipe: impl Read + Send + 'static, file: Arc<Mutex<OutputLog>>) {
+    std::thread::spawn(move || {
+        let mut reader = BufReader::new(pipe);
+        let mut buf = Vec::new();
+        loop {
+            buf.clear();
+            match reader.read_until(b'\n', &mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {
+                    // Output of worker hosts comes through a pty, with \r\n endings
+                    let line = String::from_utf8_lossy(&buf);
+                    let line = line.trim_end_matches(['\r', '\n']);
+                    let timestamp = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+                    let mut file = file.lock().unwrap_or_else(|e| e.into_inner());
+                    file.write_line(&format!("{} {}", timestamp, line));
+                }
+            }
+        }
+    });
+}
+
+/// Read the end of a log: lines written at or after `since`, limited to the
+/// last `lines`. Returns the lines and the offset up to which the file was
+/// read, for following it with [`read_new`].
+///
+/// The file is read backwards from its end, only as far as needed.
+pub async fn read_tail(
+    path: &Path,
+    lines: Option<usize>,
+    since: Option<DateTime<Utc>>,
+) -> Result<(Vec<String>, u64)> {
+    if lines.is_none() && since.is_none() {
+        return read_new(path, 0).await;
+    }
+    let mut file = tokio::fs::File::open(path)
+        .await
+        .with_context(|| format!("failed to open {}", path.display()))?;
+    let mut start = file.metadata().await?.len();
+    let mut buf = Vec::new();
+    while start > 0 && !read_enough(&buf, lines, since) {
+        let size = TAIL_CHUNK.min(start);
+        start -= size;
+        let mut chunk = vec![0; size as usize];
+        file.seek(io::SeekFrom::Start(start)).await?;
+        file.read_exact(&mut chunk).await?;
+        chunk.extend_from_slice(&buf);
+        buf = chunk;
+    }
+
+    // Leave a partially written last line for the next read, and drop the
+    // first line if it was cut off
+    let complete = buf.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+    let first = match start {
+        0 => 0,
+        _ => buf.iter().position(|&b| b == b'\n').map_or(0, |i| i + 1),
+    };
+    let all = parse_lines(&buf[first.min(complete)..complete]);
+    Ok((tail(all, lines, since), start + complete as u64))
+}
+
+/// Whether the end of a log in `buf`, which may start mid-line, holds every
+/// line [`tail`] would keep.
+fn read_enough(buf: &[u8], count: Option<usize>, since: Option<DateTime<Utc>>) -> bool {
+    let Some(first) = buf.iter().position(|&b| b == b'\n') else {
+        return false;
+    };
+    let rest = &buf[first + 1..];
+    let complete = rest.iter().filter(|&&b| b == b'\n').count();
+    if count.is_some_and(|count| complete >= count) {
+        return true;
+    }
+    // Lines are written in order, so everything before an older line is older
+    let older = |since| {
+        let line = rest.split(|&b| b == b'\n').next().unwrap_or_default();
+        line_time(&String::from_utf8_lossy(line)).is_some_and(|time| time < since)
+    };
+    complete > 0 && since.is_some_and(older)
+}
+
+/// Read complete lines added to a log after `offset`, returning them and
+/// the new offset.
+pub async fn read_new(path: &Path, offset: u64) -> Result<(Vec<String>, u64)> {
+    let mut file = tokio::fs::File::open(path)
+        .await
+        .with_context(|| format!("failed to open {}", path.display()))?;
+    // The display was reused and the log truncated
+    let offset = if file.metadata().await?.len() < offset { 0 } else { offset };
+    file.seek(io::SeekFrom::Start(offset)).await?;
+    let mut buf = Vec::new();
+    file.read_to_end(&mut buf).await?;
+
+    // Leave a partially written last line for the next read
+    let complete = buf.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+    Ok((parse_lines(&buf[..complete]), offset + complete as u64))
+}
+
+fn parse_lines(buf: &[u8]) -> Vec<String> {
+    String::from_utf8_lossy(buf).lines().map(String::from).collect()
+}
+
+fn tail(lines: Vec<String>, count: Option<usize>, since: Option<DateTime<Utc>>) -> Vec<String> {
+    let mut lines: Vec<String> = match since {
+        Some(since) => lines
+            .into_iter()
+            .filter(|line| line_time(line).is_none_or(|time| time >= since))
+            .collect(),
+        None => lines,
+    };
+    if let Some(count) = count {
+        let skip = lines.len().saturating_sub(count);
+        lines.drain(..skip);
+    }
+    lines
+}
+
+/// A line of xpra output with its time shown as set for reports.
+pub fn display_line(line: &str) -> String {
+    match (line_time(line), line.split_once(' ')) {
+        (Some(time), Some((_, rest))) => format!("{} {}", format_time(time), rest),
+        _ => line.to_string(),
+    }
+}
+
+fn line_time(line: &str) -> Option<DateTime<Utc>> {
+    let timestamp = line.split(' ').next()?;
+    DateTime::parse_from_rfc3339(timestamp).ok().map(|t| t.with_timezone(&Utc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tail() {
+        let lines: Vec<String> = [
+            "2024-05-15T10:00:00.000Z starting xpra",
+            "2024-05-15T10:05:00.000Z client connected",
+            "2024-05-15T10:10:00.000Z client disconnected",
+        ]
+        .into_iter()
+        .map(String::from)
+        .collect();
+
+        assert_eq!(tail(lines.clone(), None, None).len(), 3);
+        assert_eq!(tail(lines.clone(), Some(1), None), vec![lines[2].clone()]);
+
+        let since = "2024-05-15T10:05:00Z".parse().unwrap();
+        assert_eq!(tail(lines.clone(), None, Some(since)), lines[1..].to_vec());
+        assert_eq!(tail(lines.clone(), Some(5), Some(since)).len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_read_tail() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("0-100.log");
+        let start: DateTime<Utc> = "2024-05-15T10:00:00Z".parse().unwrap();
+        let mut content = String::new();
+        for i in 0..10_000 {
+            let time = start + chrono::Duration::seconds(i);
+            let timestamp = time.to_rfc3339_opts(SecondsFormat::Millis, true);
+            content.push_str(&format!("{} line {}\n", timestamp, i));
+        }
+        let complete = content.len() as u64;
+        content.push_str("2024-05-15T12:46:40.000Z partial");
+        std::fs::write(&path, &content).unwrap();
+        assert!(complete > 4 * TAIL_CHUNK);
+
+        let (lines, offset) = read_tail(&path, Some(2), None).await.unwrap();
+        assert_eq!(offset, complete);
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with(" line 9998"));
+        assert!(lines[1].ends_with(" line 9999"));
+
+        // Reaches back past several chunks
+        let since = start + chrono::Duration::seconds(5_000);
+        let (lines, offset) = read_tail(&path, None, Some(since)).await.unwrap();
+        assert_eq!(offset, complete);
+        assert_eq!(lines.len(), 5_000);
+        assert!(lines[0].ends_with(" line 5000"));
+
+        let (lines, _) = read_tail(&path, Some(20_000), None).await.unwrap();
+        assert_eq!(lines.len(), 10_000);
+        assert!(lines[0].ends_with(" line 0"));
+
+        let (lines, offset) = read_tail(&path, Some(0), None).await.unwrap();
+        assert!(lines.is_empty());
+        assert_eq!(offset, complete);
+    }
+
+    #[test]
+    fn test_output_log_rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sessions").join("0-100.log");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(rotated_path(&path), "previous session\n").unwrap();
+
+        let mut log = OutputLog::open(&path, 20);
+        assert!(!rotated_path(&path).exists());
+        log.write_line("first line");
+        log.write_line("second line");
+        log.write_line("third");
+        assert_eq!(std::fs::read_to_string(rotated_path(&path)).unwrap(), "first line\n");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "second line\nthird\n");
+
+        // A line longer than the cap still gets written
+        log.write_line("a line longer than the cap");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "a line longer than the cap\n");
+    }
+}
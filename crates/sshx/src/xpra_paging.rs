@@ -0,0 +1,107 @@
+//! Filtering and paging of session listings.
+//!
+//! Hosts with hundreds of sessions should not send them all on every
+//! refresh. A [`SessionQuery`] picks sessions by user, host, state and
+//! labels on the host, and returns them a page at a time in the order of
+//! their ids. Each page names the id to continue after, which stays valid
+//! while sessions start and end, unlike an offset.
+
+use serde::{Deserialize, Serialize};
+
+use crate::xpra_labels::Labels;
+use crate::xpra_monitor::SessionInfo;
+use crate::xpra_session_state::SessionState;
+
+/// Most sessions on a page when no limit is given.
+pub const DEFAULT_PAGE_SIZE: usize = 100;
+
+/// Most sessions on a page, whatever the limit asked for.
+pub const MAX_PAGE_SIZE: usize = 1000;
+
+/// Which sessions a listing returns, and which page of them.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SessionQuery {
+    /// Only sessions of this user
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// Only sessions on this host
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    /// Only sessions in this state
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state: Option<SessionState>,
+    /// Only sessions carrying all of these labels
+    #[serde(default, skip_serializing_if = "Labels::is_empty")]
+    pub labels: Labels,
+    /// Only sessions with ids after this one, the `next` of the last page
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub after: Option<String>,
+    /// Most sessions on the page, [`DEFAULT_PAGE_SIZE`] if not given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+}
+
+impl SessionQuery {
+    /// Whether `session` passes the filters, whichever page it is on.
+    pub fn matches(&self, session: &SessionInfo) -> bool {
+        self.user.as_ref().is_none_or(|user| &session.user == user)
+            && self.host.as_ref().is_none_or(|host| &session.host == host)
+            && self.state.is_none_or(|state| session.lifecycle.state() == state)
+            && self.labels.iter().all(|(key, value)| session.labels.get(key) == Some(value))
+    }
+
+    /// Most sessions on the page.
+    pub fn page_size(&self) -> usize {
+        self.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE)
+    }
+
+    /// The page of `ids`, all of sessions that match, with the id to
+    /// continue after if more follow.
+    pub fn page(&self, mut ids: Vec<String>) -> (Vec<String>, Option<String>) {
+        ids.sort_unstable();
+        let start = match &self.after {
+            Some(after) => ids.partition_point(|id| id <= after),
+            None => 0,
+        };
+        let mut page = ids.split_off(start);
+        let more = page.len() > self.page_size();
+        page.truncate(self.page_size());
+        let next = more.then(|| page.last().cloned()).flatten();
+        (page, next)
+    }
+}
+
+/// A page of a session listing.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionPage<T> {
+    /// Sessions on the page, by id
+    pub sessions: Vec<T>,
+    /// Sessions matching the query on every page
+    pub total: usize,
+    /// Id to ask for the sessions after, if there are more
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_page() {
+        let ids: Vec<String> = ["xpra-c", "xpra-a", "xpra-d", "xpra-b", "xpra-e"].map(String::from).to_vec();
+        let mut query = SessionQuery { limit: Some(2), ..SessionQuery::default() };
+        assert_eq!(query.page(ids.clone()), (vec!["xpra-a".into(), "xpra-b".into()], Some("xpra-b".into())));
+
+        // The cursor holds when the session it names has ended
+        query.after = Some("xpra-bb".to_string());
+        assert_eq!(query.page(ids.clone()), (vec!["xpra-c".into(), "xpra-d".into()], Some("xpra-d".into())));
+        query.after = Some("xpra-d".to_string());
+        assert_eq!(query.page(ids.clone()), (vec!["xpra-e".into()], None));
+
+        query.limit = Some(0);
+        assert_eq!(query.page_size(), 1);
+        query.limit = Some(1_000_000);
+        assert_eq!(query.page_size(), MAX_PAGE_SIZE);
+    }
+}
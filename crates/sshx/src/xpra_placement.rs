@@ -0,0 +1,116 @@
+//! Choice of the host a new session runs on.
+
+use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+
+use crate::xpra_hosts::WorkerHost;
+
+/// How new sessions are spread over worker hosts.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PlacementStrategy {
+    /// Prefer the host with the lowest fraction of its capacity in use.
+    #[default]
+    LeastLoaded,
+    /// Fill the busiest host first, keeping idle hosts free to scale down.
+    BinPacking,
+    /// Keep a user's sessions on the host they already use, falling back to
+    /// the least loaded host for their first session.
+    UserAffinity,
+}
+
+/// A healthy host with free capacity that a session could be placed on.
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    /// The host
+    pub host: Arc<WorkerHost>,
+    /// Sessions running on the host
+    pub active: usize,
+    /// Most sessions the host takes
+    pub capacity: usize,
+    /// Sessions the requesting user already has on this host
+    pub user_sessions: usize,
+}
+
+impl Candidate {
+    /// Share of the host's capacity in use, from 0 to 1
+    pub fn load(&self) -> f64 {
+        self.active as f64 / self.capacity as f64
+    }
+}
+
+impl PlacementStrategy {
+    /// Pick one of the candidates. Ties go to the candidate listed first, so
+    /// placement follows the configured host order.
+    pub fn choose<'a>(&self, candidates: &'a [Candidate]) -> Option<&'a Candidate> {
+        match self {
+            Self::LeastLoaded => least_loaded(candidates),
+            Self::BinPacking => candidates
+                .iter()
+                .fold(None, |best: Option<&Candidate>, c| match best {
+                    Some(b) if b.load() >= c.load() => Some(b),
+                    _ => Some(c),
+                }),
+            Self::UserAffinity => candidates
+                .iter()
+                .filter(|c| c.user_sessions > 0)
+                .fold(None, |best: Option<&Candidate>, c| match best {
+                    Some(b) if b.user_sessions >= c.user_sessions => Some(b),
+                    _ => Some(c),
+                })
+                .or_else(|| least_loaded(candidates)),
+        }
+    }
+}
+
+fn least_loaded(candidates: &[Candidate]) -> Option<&Candidate> {
+    candidates.iter().fold(None, |best: Option<&Candidate>, c| match best {
+        Some(b) if b.load() <= c.load() => Some(b),
+        _ => Some(c),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::xpra_config::XpraConfig;
+    use crate::xpra_hosts::HostConfig;
+
+    fn candidate(name: &str, active: usize, capacity: usize, user_sessions: usize) -> Candidate {
+        let config: HostConfig = serde_json::from_value(serde_json::json!({
+            "name": name,
+            "ssh_target": name,
+            "address": name,
+        }))
+        .unwrap();
+        Candidate {
+            host: Arc::new(WorkerHost::remote(&config, &XpraConfig::default())),
+            active,
+            capacity,
+            user_sessions,
+        }
+    }
+
+    fn chosen(strategy: PlacementStrategy, candidates: &[Candidate]) -> String {
+        strategy.choose(candidates).unwrap().host.name.clone()
+    }
+
+    #[test]
+    fn test_strategies() {
+        let candidates = [
+            candidate("a", 5, 10, 0),
+            candidate("b", 2, 10, 1),
+            candidate("c", 8, 20, 0),
+        ];
+        assert_eq!(chosen(PlacementStrategy::LeastLoaded, &candidates), "b");
+        assert_eq!(chosen(PlacementStrategy::BinPacking, &candidates), "a");
+        assert_eq!(chosen(PlacementStrategy::UserAffinity, &candidates), "b");
+    }
+
+    #[test]
+    fn test_affinity_falls_back_to_least_loaded() {
+        let candidates = [candidate("a", 5, 10, 0), candidate("b", 1, 10, 0)];
+        assert_eq!(chosen(PlacementStrategy::UserAffinity, &candidates), "b");
+        assert!(PlacementStrategy::LeastLoaded.choose(&[]).is_none());
+    }
+}
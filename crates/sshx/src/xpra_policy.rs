@@ -0,0 +1,277 @@
+//! Admission policy: an external command consulted before a desktop
+//! session starts, for site-specific rules beyond the static limits.
+//!
+//! The command runs through `sh -c` on this host. It is given an
+//! [`AdmissionRequest`] as JSON on stdin and answers with an
+//! [`AdmissionDecision`] as JSON on stdout, e.g.
+//! `{"decision": "deny", "reason": "Desktops are closed on weekends"}`.
+
+use std::process::Stdio;
+use std::time::Duration;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local, Timelike};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::time;
+use tracing::warn;
+
+use crate::xpra_backend::BackendConfig;
+use crate::xpra_labels::Labels;
+use crate::xpra_priority::SessionClass;
+
+/// What happens to a session when the policy command fails.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PolicyFailure {
+    /// Start the session unchanged
+    Allow,
+    /// Refuse the session
+    #[default]
+    Deny,
+}
+
+/// External admission policy, none by default.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AdmissionPolicyConfig {
+    /// Shell command deciding on session starts (disabled if unset)
+    #[serde(default)]
+    pub command: Option<String>,
+
+    /// Seconds before the command is killed and counted as failed
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+
+    /// Decision when the command fails, times out or answers garbage
+    #[serde(default)]
+    pub on_failure: PolicyFailure,
+}
+
+fn default_timeout() -> u64 { 5 }
+
+/// Settings a session is started with, which a policy may change.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionTemplate {
+    /// Window manager started on the display
+    pub window_manager: String,
+    /// Session class the desktop runs with
+    pub class: SessionClass,
+    /// Labels attached to the session
+    pub labels: Labels,
+    /// Program started on the desktop besides the window manager
+    #[serde(default)]
+    pub app: Option<String>,
+    /// Screen size the display starts with, instead of the largest
+    #[serde(default)]
+    pub resolution: Option<String>,
+    /// Whether the desktop's audio is forwarded
+    #[serde(default)]
+    pub audio: bool,
+    /// POSIX locale of the desktop, e.g. `de_DE.UTF-8`
+    #[serde(default)]
+    pub locale: Option<String>,
+    /// Time zone of the desktop, e.g. `Europe/Berlin`
+    #[serde(default)]
+    pub timezone: Option<String>,
+    /// XKB keyboard layout of the desktop, e.g. `de`
+    #[serde(default)]
+    pub keyboard_layout: Option<String>,
+    /// Backend showing the desktop
+    #[serde(default)]
+    pub backend: BackendConfig,
+}
+
+/// Load of the desktop hosts at the time of the request.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdmissionLoad {
+    /// Sessions running on all hosts
+    pub active_sessions: usize,
+    /// Sessions all healthy hosts can run together
+    pub capacity: usize,
+    /// Sessions of the requesting user
+    pub user_sessions: usize,
+    /// One minute load average of this host
+    pub load_average: Option<f64>,
+}
+
+/// Context a policy decides on.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdmissionRequest {
+    /// Id the session would get
+    pub session_id: String,
+    /// User requesting the session
+    pub user: String,
+    /// Local time of this host
+    pub time: DateTime<Local>,
+    /// Hour of `time`, from 0 to 23
+    pub hour: u32,
+    /// Abbreviated day of the week, e.g. `Mon`
+    pub weekday: String,
+    /// Sessions running on this host and in the fleet
+    pub load: AdmissionLoad,
+    /// Settings the session would be started with
+    pub template: SessionTemplate,
+    /// Whether a hibernated session asks to resume, rather than a new one
+    /// to start; the template of a resuming session cannot be changed
+    pub resume: bool,
+}
+
+impl AdmissionRequest {
+    /// A request of `user` made now
+    pub fn new(session_id: &str, user: &str, load: AdmissionLoad, template: SessionTemplate) -> Self {
+        let time = Local::now();
+        Self {
+            session_id: session_id.to_string(),
+            user: user.to_string(),
+            hour: time.hour(),
+            weekday: time.format("%a").to_string(),
+            time,
+            load,
+            template,
+            resume: false,
+        }
+    }
+
+    /// The request as made by a hibernated session to resume
+    pub fn resuming(self) -> Self {
+        Self { resume: true, ..self }
+    }
+}
+
+/// Answer of a policy to a session request.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "decision", rename_all = "snake_case")]
+pub enum AdmissionDecision {
+    /// Start the session as requested
+    Allow,
+    /// Refuse to start the session
+    Deny {
+        /// Why, as shown to the user
+        reason: String,
+    },
+    /// Start the session with a changed template
+    Modify {
+        /// Settings the session is started with instead
+        template: Box<SessionTemplate>,
+    },
+}
+
+impl AdmissionPolicyConfig {
+    /// Decide on a session request, allowing it if no policy is configured.
+    pub async fn evaluate(&self, request: &AdmissionRequest) -> AdmissionDecision {
+        let Some(command) = &self.command else {
+            return AdmissionDecision::Allow;
+        };
+        match run_policy(command, self.timeout, request).await {
+            Ok(decision) => decision,
+            Err(e) => {
+                warn!(
+                    session_id = request.session_id,
+                    user = request.user,
+                    "Admission policy failed: {:#}",
+                    e
+                );
+                match self.on_failure {
+                    PolicyFailure::Allow => AdmissionDecision::Allow,
+                    PolicyFailure::Deny => AdmissionDecision::Deny {
+                        reason: "Desktop sessions are temporarily unavailable, please try again later"
+                            .to_string(),
+                    },
+                }
+            }
+        }
+    }
+}
+
+async fn run_policy(command: &str, timeout: u64, request: &AdmissionRequest) -> Result<AdmissionDecision> {
+    let mut child = tokio::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .context("failed to run policy command")?;
+
+    let input = serde_json::to_vec(request)?;
+    let mut stdin = child.stdin.take().context("policy command has no stdin")?;
+    let run = async move {
+        // A policy may decide without reading its input
+        let _ = stdin.write_all(&input).await;
+        drop(stdin);
+        child.wait_with_output().await
+    };
+    let output = time::timeout(Duration::from_secs(timeout), run)
+        .await
+        .with_context(|| format!("timed out after {}s", timeout))?
+        .context("failed to run policy command")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    serde_json::from_slice(&output.stdout).context("invalid policy decision")
+}
+
+/// One minute load average of this host, where the OS exposes it.
+pub fn load_average() -> Option<f64> {
+    let loadavg = std::fs::read_to_string("/proc/loadavg").ok()?;
+    loadavg.split_whitespace().next()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request() -> AdmissionRequest {
+        let load = AdmissionLoad { active_sessions: 3, capacity: 10, user_sessions: 1, load_average: None };
+        let template = SessionTemplate {
+            window_manager: "xfce4-session".to_string(),
+            class: SessionClass::Interactive,
+            labels: Labels::new(),
+            app: None,
+            resolution: None,
+            audio: false,
+            locale: None,
+            timezone: None,
+            keyboard_layout: None,
+            backend: BackendConfig::Xpra,
+        };
+        AdmissionRequest::new("xpra-1", "alice", load, template)
+    }
+
+    #[tokio::test]
+    async fn test_evaluate() {
+        let policy = |command: &str, on_failure| AdmissionPolicyConfig {
+            command: Some(command.to_string()),
+            timeout: 5,
+            on_failure,
+        };
+        let request = request();
+
+        assert_eq!(AdmissionPolicyConfig::default().evaluate(&request).await, AdmissionDecision::Allow);
+        assert_eq!(
+            policy(r#"echo '{"decision": "deny", "reason": "closed"}'"#, PolicyFailure::Allow)
+                .evaluate(&request)
+                .await,
+            AdmissionDecision::Deny { reason: "closed".to_string() }
+        );
+
+        // The policy sees the request on stdin
+        let modify = r#"grep -q '"user":"alice"' && echo '{"decision": "modify", "template": {"window_manager": "twm", "class": "batch", "labels": {"team": "qa"}}}'"#;
+        let AdmissionDecision::Modify { template } = policy(modify, PolicyFailure::Deny).evaluate(&request).await else {
+            panic!("expected a modified template");
+        };
+        assert_eq!(template.window_manager, "twm");
+        assert_eq!(template.class, SessionClass::Batch);
+        assert_eq!(template.labels.get("team").map(String::as_str), Some("qa"));
+
+        assert_eq!(policy("exit 1", PolicyFailure::Allow).evaluate(&request).await, AdmissionDecision::Allow);
+        assert!(matches!(
+            policy("echo maybe", PolicyFailure::Deny).evaluate(&request).await,
+            AdmissionDecision::Deny { .. }
+        ));
+    }
+}
@@ -1,53 +1,237 @@
-use std::collections::HashSet;
-use std::sync::Arc;
-use tokio::sync::Mutex;
+//! Display numbers handed out to sessions.
+//!
+//! Numbers are handed out as [`DisplayLease`]s, which return their number to
+//! the pool when dropped. A lease can be given an expiry: if it is not bound
+//! to a running session in time, e.g. because the session's start hangs
+//! half-way, its number returns to the pool even while the lease is held,
+//! once the cleanup its holder registered stopped whatever runs on it.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use anyhow::Result;
 use tracing::{debug, warn};
 
 const MIN_DISPLAY: u16 = 100;  // Start at :100 to avoid conflicts
 const MAX_DISPLAY: u16 = 599;  // Allow up to 500 displays
 
+/// Display numbers in use on this host.
+///
+/// The numbers are bits of a bitset that is changed with atomic operations,
+/// so sessions starting at once take numbers without waiting on each other.
+/// The lowest free number is handed out first.
 #[derive(Debug, Clone)]
 pub struct DisplayPool {
-    used_displays: Arc<Mutex<HashSet<u16>>>,
+    /// Bit `n` is set while display `min_display + n` is in use
+    used: Arc<[AtomicU64]>,
+    allocated: Arc<AtomicUsize>,
+    min_display: u16,
+    max_display: u16,
 }
 
 impl DisplayPool {
+    /// Create a pool with every display number free.
     pub fn new() -> Self {
+        Self::with_range(MIN_DISPLAY, MAX_DISPLAY)
+    }
+
+    /// Create a pool handing out displays in `min_display..=max_display`.
+    /// A range with `min_display` past `max_display` hands out nothing;
+    /// [`XpraConfig::validate`](crate::xpra_config::XpraConfig::validate)
+    /// rejects such configs.
+    pub fn with_range(min_display: u16, max_display: u16) -> Self {
+        let len = range_len(min_display, max_display).div_ceil(64);
         Self {
-            used_displays: Arc::new(Mutex::new(HashSet::new())),
+            used: (0..len).map(|_| AtomicU64::new(0)).collect(),
+            allocated: Arc::new(AtomicUsize::new(0)),
+            min_display,
+            max_display,
         }
     }
 
-    /// Allocate a new display number
-    pub async fn allocate(&self) -> Result<u16> {
-        let mut displays = self.used_displays.lock().await;
-        
-        // Find first available display number
-        for display in MIN_DISPLAY..=MAX_DISPLAY {
-            if !displays.contains(&display) {
-                displays.insert(display);
-                debug!(display, "Allocated new display number");
-                return Ok(display);
+    /// Lease the lowest free display number
+    pub async fn allocate(&self) -> Result<DisplayLease> {
+        let count = range_len(self.min_display, self.max_display);
+        for (i, word) in self.used.iter().enumerate() {
+            let mut bits = word.load(Ordering::Acquire);
+            loop {
+                // Bits past the end of the range are never handed out
+                let free = !bits & range_mask(count.saturating_sub(i * 64));
+                if free == 0 {
+                    break;
+                }
+                let bit = 1 << free.trailing_zeros();
+                match word.compare_exchange_weak(bits, bits | bit, Ordering::AcqRel, Ordering::Acquire) {
+                    Ok(_) => {
+                        self.allocated.fetch_add(1, Ordering::Relaxed);
+                        let number = self.min_display + (i * 64) as u16 + free.trailing_zeros() as u16;
+                        debug!(display = number, "Allocated new display number");
+                        return Ok(DisplayLease::new(self.clone(), number));
+                    }
+                    Err(current) => bits = current,
+                }
             }
         }
-        
+
         anyhow::bail!("No available display numbers")
     }
 
-    /// Release a display number back to the pool
-    pub async fn release(&self, display: u16) {
-        let mut displays = self.used_displays.lock().await;
-        if displays.remove(&display) {
-            debug!(display, "Released display number");
+    /// Lease a specific display number if it is free, e.g. to bring back a
+    /// hibernated session on its original display
+    pub async fn reserve(&self, number: u16) -> Option<DisplayLease> {
+        let (word, bit) = self.slot(number)?;
+        if word.fetch_or(bit, Ordering::AcqRel) & bit != 0 {
+            return None;
+        }
+        self.allocated.fetch_add(1, Ordering::Relaxed);
+        debug!(display = number, "Reserved display number");
+        Some(DisplayLease::new(self.clone(), number))
+    }
+
+    /// Return a display number to the pool, once its lease ends
+    fn release(&self, number: u16) {
+        let released = match self.slot(number) {
+            Some((word, bit)) => word.fetch_and(!bit, Ordering::AcqRel) & bit != 0,
+            None => false,
+        };
+        if released {
+            self.allocated.fetch_sub(1, Ordering::Relaxed);
+            debug!(display = number, "Released display number");
         } else {
-            warn!(display, "Attempted to release unallocated display");
+            warn!(display = number, "Attempted to release unallocated display");
         }
     }
 
     /// Get number of currently allocated displays
     pub async fn allocated_count(&self) -> usize {
-        self.used_displays.lock().await.len()
+        self.allocated.load(Ordering::Relaxed)
+    }
+
+    /// Word and bit of a display number, if it is in the pool's range.
+    fn slot(&self, number: u16) -> Option<(&AtomicU64, u64)> {
+        if number < self.min_display || number > self.max_display {
+            return None;
+        }
+        let index = (number - self.min_display) as usize;
+        Some((&self.used[index / 64], 1 << (index % 64)))
+    }
+}
+
+/// Count of the numbers in `min_display..=max_display`.
+fn range_len(min_display: u16, max_display: u16) -> usize {
+    (max_display as usize + 1).saturating_sub(min_display as usize)
+}
+
+/// Mask of the lowest `bits` bits of a word.
+fn range_mask(bits: usize) -> u64 {
+    match bits {
+        0 => 0,
+        1..=63 => (1 << bits) - 1,
+        _ => u64::MAX,
+    }
+}
+
+/// Lease states: held and not yet bound to a session, bound, expired and
+/// being cleaned up, or over with the number returned to the pool.
+const UNBOUND: u8 = 0;
+const BOUND: u8 = 1;
+const EXPIRING: u8 = 2;
+const RELEASED: u8 = 3;
+
+/// Cleanup run when a lease expires, before its number returns to the pool.
+pub type ExpiryCleanup = Box<dyn FnOnce() -> Pin<Box<dyn Future<Output = ()> + Send>> + Send>;
+
+/// A display number taken from a [`DisplayPool`], and with it the
+/// websocket port derived from it, until the lease is dropped.
+pub struct DisplayLease {
+    pool: DisplayPool,
+    number: u16,
+    state: Arc<AtomicU8>,
+    cleanup: Arc<Mutex<Option<ExpiryCleanup>>>,
+}
+
+impl std::fmt::Debug for DisplayLease {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DisplayLease")
+            .field("number", &self.number)
+            .field("state", &self.state.load(Ordering::Acquire))
+            .finish_non_exhaustive()
+    }
+}
+
+impl DisplayLease {
+    fn new(pool: DisplayPool, number: u16) -> Self {
+        Self { pool, number, state: Arc::new(AtomicU8::new(UNBOUND)), cleanup: Arc::default() }
+    }
+
+    /// The leased display number
+    pub fn number(&self) -> u16 {
+        self.number
+    }
+
+    /// Expire the lease after `timeout` unless it is bound to a session by
+    /// then, running the cleanup registered with [`on_expiry`] before the
+    /// number returns to the pool. Must be called within a Tokio runtime.
+    ///
+    /// [`on_expiry`]: Self::on_expiry
+    pub fn expire_after(&self, timeout: Duration) {
+        let (pool, number, state, cleanup) = (self.pool.clone(), self.number, self.state.clone(), self.cleanup.clone());
+        tokio::spawn(async move {
+            tokio::time::sleep(timeout).await;
+            if state.compare_exchange(UNBOUND, EXPIRING, Ordering::AcqRel, Ordering::Acquire).is_err() {
+                return;
+            }
+            warn!(display = number, "Display lease expired before a session was bound to it");
+            let cleanup = cleanup.lock().unwrap().take();
+            if let Some(cleanup) = cleanup {
+                cleanup().await;
+            }
+            state.store(RELEASED, Ordering::Release);
+            pool.release(number);
+        });
+    }
+
+    /// Register what stops and cleans up after the server on the number
+    /// should the lease expire. Fails, handing the cleanup back, if the
+    /// lease already expired.
+    pub fn on_expiry(&self, cleanup: ExpiryCleanup) -> std::result::Result<(), ExpiryCleanup> {
+        // The expiry takes the cleanup under the same lock, so it sees one
+        // registered before the lease expired
+        let mut slot = self.cleanup.lock().unwrap();
+        if self.is_expired() {
+            return Err(cleanup);
+        }
+        *slot = Some(cleanup);
+        Ok(())
+    }
+
+    /// Bind the lease to a running session, so it no longer expires. Fails
+    /// if it already expired, when the number may belong to another session.
+    pub fn bind(&self) -> Result<()> {
+        match self.state.compare_exchange(UNBOUND, BOUND, Ordering::AcqRel, Ordering::Acquire) {
+            Ok(_) | Err(BOUND) => Ok(()),
+            Err(_) => anyhow::bail!("the lease of display :{} expired before the session started", self.number),
+        }
+    }
+
+    /// Whether the lease expired, with its number back in the pool or
+    /// about to be once cleaned up
+    pub fn is_expired(&self) -> bool {
+        self.state.load(Ordering::Acquire) >= EXPIRING
+    }
+}
+
+impl Drop for DisplayLease {
+    fn drop(&mut self) {
+        // An expiring lease's number is returned once its cleanup is done
+        let held = self
+            .state
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |state| (state < EXPIRING).then_some(RELEASED));
+        if held.is_ok() {
+            self.pool.release(self.number);
+        }
     }
 }
 
@@ -57,13 +241,9 @@ impl Default for DisplayPool {
     }
 }
 
-// Make DisplayPool available globally
-lazy_static::lazy_static! {
-    pub static ref DISPLAY_POOL: DisplayPool = DisplayPool::new();
-}
-
 #[cfg(test)]
 mod tests {
+    use std::collections::HashSet;
     use super::*;
 
     #[tokio::test]
@@ -71,45 +251,147 @@ mod tests {
         let pool = DisplayPool::new();
         
         // Allocate display
-        let display = pool.allocate().await.unwrap();
+        let lease = pool.allocate().await.unwrap();
+        let display = lease.number();
         assert!(display >= MIN_DISPLAY);
         assert!(display <= MAX_DISPLAY);
         
         // Verify it's marked as used
         assert_eq!(pool.allocated_count().await, 1);
         
-        // Release display
-        pool.release(display).await;
+        // Dropping the lease releases the display
+        drop(lease);
         assert_eq!(pool.allocated_count().await, 0);
         
         // Should be able to allocate same number again
         let new_display = pool.allocate().await.unwrap();
-        assert_eq!(display, new_display);
+        assert_eq!(display, new_display.number());
     }
 
     #[tokio::test]
     async fn test_multiple_allocations() {
         let pool = DisplayPool::new();
-        let mut displays = Vec::new();
+        let mut leases = Vec::new();
         
         // Allocate 10 displays
         for _ in 0..10 {
-            displays.push(pool.allocate().await.unwrap());
+            leases.push(pool.allocate().await.unwrap());
         }
         
         assert_eq!(pool.allocated_count().await, 10);
         
         // All numbers should be unique
         let mut unique = HashSet::new();
-        displays.iter().for_each(|d| {
-            assert!(unique.insert(d));
+        leases.iter().for_each(|d| {
+            assert!(unique.insert(d.number()));
         });
         
         // Release all
-        for display in displays {
-            pool.release(display).await;
-        }
+        leases.clear();
         
         assert_eq!(pool.allocated_count().await, 0);
     }
+
+    #[tokio::test]
+    async fn test_reserve_display() {
+        let pool = DisplayPool::with_range(100, 102);
+        let lease = pool.allocate().await.unwrap();
+
+        assert!(pool.reserve(lease.number()).await.is_none());
+        assert!(pool.reserve(99).await.is_none());
+        let _reserved = pool.reserve(102).await.unwrap();
+        let next = pool.allocate().await.unwrap();
+        assert_eq!(next.number(), 101);
+        assert!(pool.allocate().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_empty_range() {
+        let pool = DisplayPool::with_range(200, 100);
+        assert!(pool.allocate().await.is_err());
+        assert!(pool.reserve(100).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_allocations() {
+        // The range spans several words of the bitset and ends inside one
+        let pool = DisplayPool::with_range(100, 229);
+        let tasks: Vec<_> = (0..130)
+            .map(|_| {
+                let pool = pool.clone();
+                tokio::spawn(async move { pool.allocate().await.unwrap() })
+            })
+            .collect();
+        let mut leases = Vec::new();
+        for task in tasks {
+            leases.push(task.await.unwrap());
+        }
+        let displays: HashSet<u16> = leases.iter().map(DisplayLease::number).collect();
+        assert_eq!(displays, (100..=229).collect());
+        assert_eq!(pool.allocated_count().await, 130);
+        assert!(pool.allocate().await.is_err());
+
+        leases.retain(|lease| lease.number() != 164);
+        assert_eq!(pool.allocated_count().await, 129);
+        assert_eq!(pool.allocate().await.unwrap().number(), 164);
+    }
+
+    #[tokio::test]
+    async fn test_lease_expiry() {
+        let pool = DisplayPool::with_range(100, 101);
+        let stuck = pool.allocate().await.unwrap();
+        stuck.expire_after(Duration::from_millis(50));
+        let started = pool.allocate().await.unwrap();
+        started.expire_after(Duration::from_millis(50));
+        started.bind().unwrap();
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(stuck.is_expired());
+        assert!(stuck.bind().is_err());
+        assert!(!started.is_expired());
+        assert_eq!(pool.allocated_count().await, 1);
+
+        // The expired number is handed out again, and dropping the expired
+        // lease does not release it a second time
+        let reused = pool.allocate().await.unwrap();
+        assert_eq!(reused.number(), stuck.number());
+        drop(stuck);
+        assert_eq!(pool.allocated_count().await, 2);
+    }
+
+    #[tokio::test]
+    async fn test_lease_expiry_cleans_up_first() {
+        let pool = DisplayPool::with_range(100, 100);
+        let lease = pool.allocate().await.unwrap();
+        let (started, finish) = (Arc::new(tokio::sync::Notify::new()), Arc::new(tokio::sync::Notify::new()));
+        let (on_start, on_finish) = (started.clone(), finish.clone());
+        lease
+            .on_expiry(Box::new(move || {
+                Box::pin(async move {
+                    on_start.notify_one();
+                    on_finish.notified().await;
+                })
+            }))
+            .unwrap_or_else(|_| panic!("lease expired early"));
+        lease.expire_after(Duration::from_millis(10));
+
+        // While the cleanup runs the lease counts as expired but the number
+        // stays taken, even once the lease is dropped
+        started.notified().await;
+        assert!(lease.is_expired());
+        assert!(lease.bind().is_err());
+        drop(lease);
+        assert_eq!(pool.allocated_count().await, 1);
+        assert!(pool.allocate().await.is_err());
+
+        finish.notify_one();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(pool.allocated_count().await, 0);
+
+        // A cleanup registered too late is handed back
+        let lease = pool.allocate().await.unwrap();
+        lease.expire_after(Duration::ZERO);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(lease.on_expiry(Box::new(|| Box::pin(async {}))).is_err());
+    }
 }
@@ -0,0 +1,139 @@
+//! CPU, IO and OOM priorities for xpra processes by session class, so
+//! desktops stay responsive under load and the kernel picks batch sessions
+//! first under memory pressure.
+
+use serde::{Deserialize, Serialize};
+
+/// Kind of workload a session runs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionClass {
+    /// A desktop someone is working in.
+    #[default]
+    Interactive,
+    /// Unattended work such as automation jobs.
+    Batch,
+}
+
+/// IO scheduling class, as understood by `ionice -c`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IoClass {
+    /// Served before all other IO
+    Realtime,
+    /// Served by priority, the default
+    BestEffort,
+    /// Served only when no other process does IO
+    Idle,
+}
+
+impl IoClass {
+    fn number(self) -> u8 {
+        match self {
+            IoClass::Realtime => 1,
+            IoClass::BestEffort => 2,
+            IoClass::Idle => 3,
+        }
+    }
+}
+
+/// Priorities for the xpra process of a session; unset values are inherited.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProcessPriority {
+    /// Niceness from -20 (highest priority) to 19
+    #[serde(default)]
+    pub nice: Option<i32>,
+
+    /// IO scheduling class of the desktop's processes
+    #[serde(default)]
+    pub io_class: Option<IoClass>,
+
+    /// Priority within the realtime and best-effort IO classes, 0 (highest) to 7
+    #[serde(default)]
+    pub io_level: Option<u8>,
+
+    /// Adjustment to the OOM killer's score, from -1000 (never kill) to 1000
+    #[serde(default)]
+    pub oom_score_adj: Option<i32>,
+}
+
+impl ProcessPriority {
+    /// Command and arguments that run a program with these priorities.
+    pub fn command_prefix(&self) -> Vec<String> {
+        let mut prefix = Vec::new();
+        if let Some(score) = self.oom_score_adj {
+            prefix.extend(["choom".to_string(), "-n".to_string(), score.clamp(-1000, 1000).to_string(), "--".to_string()]);
+        }
+        if let Some(nice) = self.nice {
+            prefix.extend(["nice".to_string(), "-n".to_string(), nice.clamp(-20, 19).to_string()]);
+        }
+        if self.io_class.is_some() || self.io_level.is_some() {
+            prefix.push("ionice".to_string());
+            let class = self.io_class.unwrap_or(IoClass::BestEffort);
+            prefix.extend(["-c".to_string(), class.number().to_string()]);
+            // The idle class has no levels
+            if let (Some(level), false) = (self.io_level, class == IoClass::Idle) {
+                prefix.extend(["-n".to_string(), level.min(7).to_string()]);
+            }
+        }
+        prefix
+    }
+}
+
+/// CPU and IO priority of the desktops, by session class.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PriorityConfig {
+    /// Priority of interactive sessions
+    #[serde(default)]
+    pub interactive: ProcessPriority,
+
+    /// Priority of batch sessions, lowered by default
+    #[serde(default = "default_batch")]
+    pub batch: ProcessPriority,
+}
+
+fn default_batch() -> ProcessPriority {
+    ProcessPriority {
+        nice: Some(10),
+        io_class: Some(IoClass::BestEffort),
+        io_level: Some(7),
+        oom_score_adj: Some(500),
+    }
+}
+
+impl Default for PriorityConfig {
+    fn default() -> Self {
+        Self {
+            interactive: ProcessPriority::default(),
+            batch: default_batch(),
+        }
+    }
+}
+
+impl PriorityConfig {
+    /// The priority of sessions of class `class`
+    pub fn for_class(&self, class: SessionClass) -> &ProcessPriority {
+        match class {
+            SessionClass::Interactive => &self.interactive,
+            SessionClass::Batch => &self.batch,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_prefix() {
+        let config = PriorityConfig::default();
+        assert!(config.for_class(SessionClass::Interactive).command_prefix().is_empty());
+        assert_eq!(
+            config.for_class(SessionClass::Batch).command_prefix(),
+            vec!["choom", "-n", "500", "--", "nice", "-n", "10", "ionice", "-c", "2", "-n", "7"],
+        );
+
+        let idle = ProcessPriority { io_class: Some(IoClass::Idle), io_level: Some(3), ..Default::default() };
+        assert_eq!(idle.command_prefix(), vec!["ionice", "-c", "3"]);
+    }
+}
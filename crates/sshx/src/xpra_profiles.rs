@@ -0,0 +1,335 @@
+//! Browser profiles of app sessions, kept apart per user.
+//!
+//! The desktops of all users run as the service's account, so a browser
+//! started as the app of a session would find the cookies and logins of
+//! whoever ran it before. Instead each app session gets a home directory of
+//! its own under `profiles.dir`, where the browser keeps its profile: a
+//! fresh one per session that is wiped when the session ends, or one per
+//! user and app that is kept, for good or until it goes unused for a number
+//! of days. Profiles are wiped by overwriting their files before removing
+//! them.
+//!
+//! Every desktop runs with `profiles.dir` hidden by the sandbox, apart from
+//! the profile of its own session, so programs of one user cannot read the
+//! profiles of another. A session holds a lock on its profile for as long
+//! as its xpra server runs, and profiles locked by any process on the host
+//! are neither started again nor wiped.
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use tokio::time;
+use tracing::{info, warn};
+
+use crate::xpra_hosts::{shell_quote, WorkerHost};
+use crate::xpra_sandbox::HiddenPaths;
+
+/// How often profiles past their time to live are looked for.
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Overwrites the files of the profiles given as arguments, where `shred`
+/// is installed, and removes them. Profiles a running session holds the
+/// lock of are skipped.
+const WIPE_SCRIPT: &str = r#"for dir; do
+  exec 9<"$dir" 2>/dev/null || continue
+  flock -n 9 || continue
+  if command -v shred >/dev/null; then find "$dir" -type f -exec shred -z -u -- {} + 2>/dev/null; fi
+  rm -rf -- "$dir"
+  exec 9<&-
+done"#;
+
+/// Exit code of xpra's wrapper when another session holds the profile.
+const IN_USE_EXIT: u8 = 75;
+
+lazy_static::lazy_static! {
+    /// Profiles of running sessions, by host, never expired under them
+    static ref IN_USE: Mutex<HashSet<(String, PathBuf)>> = Mutex::new(HashSet::new());
+}
+
+/// How long the profiles of app sessions are kept.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum ProfilePersistence {
+    /// A fresh profile for every session, wiped when it ends
+    #[default]
+    Ephemeral,
+    /// One profile per user and app, kept across sessions
+    Persistent,
+    /// One profile per user and app, wiped once unused for `ttl_days`
+    PersistentWithTtl {
+        /// Days a profile is kept after its last session ended
+        ttl_days: u64,
+    },
+}
+
+/// Where and for how long the profiles of app sessions are kept.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProfileConfig {
+    /// Directory of the profiles, on the host running the desktop
+    #[serde(default = "default_dir")]
+    pub dir: PathBuf,
+
+    /// How long profiles are kept
+    #[serde(default)]
+    pub persistence: ProfilePersistence,
+}
+
+fn default_dir() -> PathBuf { PathBuf::from("/var/lib/sshx/profiles") }
+
+impl Default for ProfileConfig {
+    fn default() -> Self {
+        Self {
+            dir: default_dir(),
+            persistence: ProfilePersistence::default(),
+        }
+    }
+}
+
+impl ProfileConfig {
+    /// Profile a session of `user` starts `app` with.
+    pub fn profile(&self, user: &str, app: &str, session_id: &str) -> AppProfile {
+        let app = app.split_whitespace().next().and_then(|program| Path::new(program).file_name());
+        let app = path_component(&app.map(|name| name.to_string_lossy()).unwrap_or_default());
+        let dir = self.dir.join(path_component(user));
+        let ephemeral = self.persistence == ProfilePersistence::Ephemeral;
+        let path = match ephemeral {
+            true => dir.join(format!("{}.{}", app, path_component(session_id))),
+            false => dir.join(app),
+        };
+        AppProfile { path, ephemeral }
+    }
+
+    /// Paths the sandbox hides from a desktop running `profile`, if any:
+    /// all profiles but that one.
+    pub fn hidden(&self, profile: Option<&AppProfile>) -> HiddenPaths {
+        HiddenPaths {
+            hide: vec![self.dir.clone()],
+            keep: profile.map(|profile| profile.path.clone()).into_iter().collect(),
+        }
+    }
+
+    /// Time after which a profile no session uses is wiped, none if kept
+    /// for good, as with a time to live too long to count. Ephemeral
+    /// profiles left over by a host that went down with their sessions are
+    /// wiped right away.
+    fn max_age(&self) -> Option<Duration> {
+        match self.persistence {
+            ProfilePersistence::Ephemeral => Some(Duration::ZERO),
+            ProfilePersistence::Persistent => None,
+            ProfilePersistence::PersistentWithTtl { ttl_days } => ttl_days.checked_mul(86400).map(Duration::from_secs),
+        }
+    }
+
+    /// Wipe the profiles on `hosts` past their time to live, hourly in the
+    /// background.
+    pub fn start_expiry(&self, hosts: Vec<Arc<WorkerHost>>) {
+        let Some(max_age) = self.max_age() else {
+            return;
+        };
+        let dir = self.dir.clone();
+        tokio::spawn(async move {
+            let mut interval = time::interval(EXPIRY_INTERVAL);
+            loop {
+                interval.tick().await;
+                for host in &hosts {
+                    if let Err(e) = expire(host, &dir, max_age).await {
+                        warn!(host = host.name, "Failed to expire browser profiles: {:#}", e);
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// Home directory of the programs of an app session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AppProfile {
+    /// Directory of the profile on the host
+    pub path: PathBuf,
+    ephemeral: bool,
+}
+
+impl AppProfile {
+    /// Take the profile for a session on `host`, none if a running session
+    /// already uses it.
+    pub fn claim(&self, host: &WorkerHost) -> Option<ProfileClaim> {
+        let key = (host.name.clone(), self.path.clone());
+        let claimed = IN_USE.lock().unwrap().insert(key.clone());
+        claimed.then(|| ProfileClaim { key })
+    }
+
+    /// Environment making the desktop's programs keep their data in the
+    /// profile.
+    pub fn env(&self) -> Vec<String> {
+        let path = self.path.display();
+        vec![
+            format!("HOME={path}"),
+            format!("XDG_CONFIG_HOME={path}/.config"),
+            format!("XDG_CACHE_HOME={path}/.cache"),
+            format!("XDG_DATA_HOME={path}/.local/share"),
+        ]
+    }
+
+    /// Command and arguments that create the profile, only accessible to
+    /// the service's account, mark it as used and lock it before running a
+    /// program. The program inherits the lock, held until it exits.
+    pub fn command_prefix(&self) -> Vec<String> {
+        let path = shell_quote(&self.path.to_string_lossy());
+        let script = format!(
+            r#"umask 077 && mkdir -p -- {path} && touch -- {path} && exec 9<{path} || exit 1
+flock -n 9 || {{ echo "browser profile in use by another session" >&2; exit {IN_USE_EXIT}; }}
+exec "$@""#
+        );
+        vec!["sh".to_string(), "-c".to_string(), script, "sh".to_string()]
+    }
+
+    /// Wipe the profile once its session on `host` ended if ephemeral, and
+    /// mark it as used otherwise.
+    pub async fn release(&self, host: &WorkerHost) {
+        let (program, mut args) = match self.ephemeral {
+            true => ("sh", vec!["-c".to_string(), WIPE_SCRIPT.to_string(), "sh".to_string()]),
+            false => ("touch", vec!["-c".to_string(), "--".to_string()]),
+        };
+        args.push(self.path.to_string_lossy().into_owned());
+        let status = tokio::process::Command::from(host.command(program, &args)).status().await;
+        if !status.is_ok_and(|status| status.success()) {
+            warn!(host = host.name, path = %self.path.display(), "Failed to release browser profile");
+        }
+    }
+}
+
+/// A profile taken by a running session, free again once dropped.
+#[derive(Debug)]
+pub struct ProfileClaim {
+    key: (String, PathBuf),
+}
+
+impl Drop for ProfileClaim {
+    fn drop(&mut self) {
+        IN_USE.lock().unwrap().remove(&self.key);
+    }
+}
+
+/// Wipe the profiles in `dir` on `host` that no running session uses and
+/// that were last used longer than `max_age` ago.
+async fn expire(host: &WorkerHost, dir: &Path, max_age: Duration) -> anyhow::Result<()> {
+    let mut args: Vec<String> = [&*dir.to_string_lossy(), "-mindepth", "2", "-maxdepth", "2", "-type", "d"]
+        .map(str::to_string)
+        .into();
+    if !max_age.is_zero() {
+        args.extend(["-mmin".to_string(), format!("+{}", max_age.as_secs() / 60)]);
+    }
+    // Finds nothing while the directory does not exist yet
+    let output = tokio::process::Command::from(host.command("find", &args)).output().await?;
+    let expired: Vec<String> = {
+        let in_use = IN_USE.lock().unwrap();
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter(|path| !in_use.contains(&(host.name.clone(), PathBuf::from(path))))
+            .map(str::to_string)
+            .collect()
+    };
+    if expired.is_empty() {
+        return Ok(());
+    }
+    let mut args = vec!["-c".to_string(), WIPE_SCRIPT.to_string(), "sh".to_string()];
+    args.extend(expired.iter().cloned());
+    let status = tokio::process::Command::from(host.command("sh", &args)).status().await?;
+    if !status.success() {
+        anyhow::bail!("wiping exited with {}", status);
+    }
+    info!(host = host.name, profiles = expired.len(), "Wiped expired browser profiles");
+    Ok(())
+}
+
+/// A user, app or session id as a single path component.
+fn path_component(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || "-_.".contains(c) { c } else { '_' })
+        .collect();
+    match name.as_str() {
+        "" | "." | ".." => "_".to_string(),
+        _ => name,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::xpra_config::XpraConfig;
+    use crate::xpra_pool::DisplayPool;
+
+    #[test]
+    fn test_profile_paths() {
+        let config = ProfileConfig { dir: PathBuf::from("/profiles"), persistence: ProfilePersistence::Ephemeral };
+        let profile = config.profile("alice", "/usr/bin/firefox --kiosk", "xpra-7");
+        assert_eq!(profile.path, Path::new("/profiles/alice/firefox.xpra-7"));
+        assert!(profile.env().contains(&"HOME=/profiles/alice/firefox.xpra-7".to_string()));
+
+        let config = ProfileConfig { persistence: ProfilePersistence::PersistentWithTtl { ttl_days: 30 }, ..config };
+        assert_eq!(config.profile("alice", "chromium", "xpra-7").path, Path::new("/profiles/alice/chromium"));
+        assert_eq!(config.max_age(), Some(Duration::from_secs(30 * 86400)));
+        let forever = ProfileConfig { persistence: ProfilePersistence::PersistentWithTtl { ttl_days: u64::MAX }, ..config.clone() };
+        assert_eq!(forever.max_age(), None);
+        let hidden = config.hidden(Some(&config.profile("alice", "chromium", "xpra-7")));
+        assert_eq!(hidden.hide, [Path::new("/profiles")]);
+        assert_eq!(hidden.keep, [Path::new("/profiles/alice/chromium")]);
+        assert!(config.hidden(None).keep.is_empty());
+        // Names cannot leave the directory of the profiles
+        assert_eq!(config.profile("../bob", "..", "xpra-7").path, Path::new("/profiles/.._bob/_"));
+    }
+
+    #[tokio::test]
+    async fn test_release_and_expire() {
+        let dir = std::env::temp_dir().join(format!("sshx-profiles-{}", std::process::id()));
+        let host = WorkerHost::local(&XpraConfig::default(), DisplayPool::new());
+        let config = ProfileConfig { dir: dir.clone(), persistence: ProfilePersistence::Ephemeral };
+        let profile = config.profile("alice", "firefox", "xpra-1");
+        let claim = profile.claim(&host).unwrap();
+        assert!(profile.claim(&host).is_none());
+        std::fs::create_dir_all(profile.path.join(".mozilla")).unwrap();
+        std::fs::write(profile.path.join(".mozilla/cookies.sqlite"), "secret").unwrap();
+
+        // Profiles of running sessions are not expired
+        let leftover = config.profile("bob", "firefox", "xpra-0").path;
+        std::fs::create_dir_all(&leftover).unwrap();
+        expire(&host, &dir, Duration::ZERO).await.unwrap();
+        assert!(profile.path.exists());
+        assert!(!leftover.exists());
+
+        // Nor those another process holds the lock of
+        let locked = config.profile("carol", "firefox", "xpra-2");
+        let mut command = std::process::Command::new(&locked.command_prefix()[0]);
+        let mut holder = command.args(&locked.command_prefix()[1..]).args(["sleep", "30"]).spawn().unwrap();
+        // A second session cannot start with it, once the first locked it
+        let mut second = None;
+        for _ in 0..100 {
+            let status = std::process::Command::new(&locked.command_prefix()[0])
+                .args(&locked.command_prefix()[1..])
+                .arg("true")
+                .status()
+                .unwrap();
+            second = status.code();
+            if second == Some(IN_USE_EXIT.into()) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(second, Some(IN_USE_EXIT.into()));
+        expire(&host, &dir, Duration::ZERO).await.unwrap();
+        assert!(locked.path.exists());
+        holder.kill().unwrap();
+        holder.wait().unwrap();
+        expire(&host, &dir, Duration::ZERO).await.unwrap();
+        assert!(!locked.path.exists());
+
+        profile.release(&host).await;
+        assert!(!profile.path.exists());
+        drop(claim);
+        assert!(profile.claim(&host).is_some());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,870 @@
+//! Minimal reading of the xpra protocol passing through the forwarder.
+//!
+//! The web client and xpra speak the xpra protocol with each other, and the
+//! forwarder passes it through. [`PacketWatcher`] still reads the packets
+//! from xpra that matter to the forwarder: the `hello` greeting the client,
+//! which ends the handshake, `disconnect` packets with the reasons xpra
+//! gives, such as a failed authentication, the `challenge` of a display
+//! requiring a token, and the `notify_show` and `notify_close` packets of
+//! desktop notifications. Output that does not start with xpra packets means
+//! something other than xpra answered.
+//!
+//! An xpra packet is an 8 byte header, `P`, protocol flags, compression
+//! level, chunk index and a big-endian payload size, followed by the payload:
+//! a rencoded or bencoded list starting with the packet type. The type is
+//! read from the start of the payload, and only packets of the types above
+//! are inflated, if compressed with zlib or lz4, and decoded, at most
+//! [`MAX_DEPTH`] levels deep. Packets compressed otherwise, encrypted, or too
+//! large to be of interest are skipped unread.
+//!
+//! Large items of a packet, such as clipboard contents, may come before it
+//! as raw chunks: packets with a chunk index, whose payload is the item at
+//! that index of the next packet, compressed at the chunk's level.
+
+use std::borrow::Cow;
+use std::io::Read;
+use flate2::read::ZlibDecoder;
+use tracing::debug;
+
+use crate::xpra_notify::{Notification, NotificationEvent};
+
+/// Length of an xpra packet header.
+const HEADER_LEN: usize = 8;
+
+/// Largest payload inspected; bigger packets are screen updates.
+const MAX_PAYLOAD: usize = 64 * 1024;
+
+/// Most of a compressed payload inflated to read the packet's type.
+const TYPE_PREFIX: usize = 256;
+
+/// Largest clipboard contents inflated, as xpra's own limit.
+const MAX_CLIPBOARD: usize = 16 * 1024 * 1024;
+
+/// Packet types read; packets of other types are skipped undecoded.
+const WATCHED: [&str; 5] = ["hello", "challenge", "disconnect", "notify_show", "notify_close"];
+
+/// Deepest nesting of lists and dictionaries decoded. xpra's packets nest a
+/// few levels; deeper ones are refused rather than exhausting the stack.
+const MAX_DEPTH: usize = 32;
+
+/// Protocol flag of rencoded payloads.
+const FLAGS_RENCODE: u8 = 0x01;
+/// Protocol flag of encrypted payloads.
+const FLAGS_CIPHER: u8 = 0x02;
+/// Protocol flag of YAML payloads.
+const FLAGS_YAML: u8 = 0x04;
+/// Protocol flag of rencodeplus payloads, rencode with distinct bytes.
+const FLAGS_RENCODEPLUS: u8 = 0x10;
+
+/// rencode type codes of lists and dictionaries of any length, and of
+/// their end.
+const RENCODE_LIST: u8 = 59;
+const RENCODE_DICT: u8 = 60;
+const RENCODE_TERM: u8 = 127;
+
+/// Compression level flag of lz4 payloads.
+const LZ4_FLAG: u8 = 0x10;
+/// Compression level flag of brotli payloads.
+const BROTLI_FLAG: u8 = 0x40;
+
+/// Packet of xpra that matters to the forwarder.
+#[derive(Debug, Clone, PartialEq)]
+pub enum XpraPacket {
+    /// xpra greeted the client, ending the handshake
+    Hello,
+    /// xpra turned the client away before greeting it, e.g. because it
+    /// failed to authenticate, for the given reasons
+    Refused(String),
+    /// xpra closed the connection of a greeted client for the given reasons
+    Disconnect(String),
+    /// xpra asked the client to authenticate
+    Challenge(Challenge),
+    /// A notification was raised, updated or closed
+    Notification(NotificationEvent),
+}
+
+/// Clipboard contents carried by an xpra packet, from either side.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClipboardTransfer {
+    /// Type of the contents, e.g. `UTF8_STRING` or `image/png`
+    pub target: String,
+    /// Size of the contents in bytes, at least
+    pub size: usize,
+    /// The contents, unless they could not be read
+    pub data: Option<Vec<u8>>,
+}
+
+/// What an xpra packet is to the clipboard filter.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClipboardPacket {
+    /// A packet of another type
+    Other,
+    /// A clipboard packet carrying contents
+    Transfer(ClipboardTransfer),
+    /// A packet that cannot be read far enough to tell, e.g. an encrypted
+    /// one or a clipboard packet inflating past xpra's limit
+    Unreadable,
+}
+
+/// Authentication xpra asks of a client before greeting it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Challenge {
+    /// Salt of the server
+    pub salt: Vec<u8>,
+    /// Digest the response is made with, e.g. `hmac+sha256`
+    pub digest: String,
+    /// Digest the server and client salts are combined with
+    pub salt_digest: String,
+}
+
+/// Finds the packets of interest in the xpra output of one connection.
+#[derive(Debug, Default)]
+pub struct PacketWatcher {
+    /// Bytes of a header or payload being inspected
+    buffer: Vec<u8>,
+    /// Header of the packet whose payload is being read
+    header: Option<[u8; HEADER_LEN]>,
+    /// Bytes left of a payload that is skipped
+    skip: usize,
+    /// Set when the output stopped looking like xpra packets
+    lost: bool,
+    /// Set once xpra greeted the client
+    greeted: bool,
+}
+
+impl PacketWatcher {
+    /// Start watching a new connection to xpra.
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Whether xpra's output stopped looking like xpra packets before it
+    /// greeted the client, so what answers is not xpra.
+    pub fn invalid(&self) -> bool {
+        self.lost && !self.greeted
+    }
+
+    /// Inspect the next xpra output, returning the packets of interest it
+    /// completes.
+    pub fn push(&mut self, mut data: &[u8]) -> Vec<XpraPacket> {
+        let mut packets = Vec::new();
+        while !data.is_empty() && !self.lost {
+            if self.skip > 0 {
+                let n = self.skip.min(data.len());
+                self.skip -= n;
+                data = &data[n..];
+                continue;
+            }
+            let wanted = match self.header {
+                Some(header) => payload_len(&header),
+                None => HEADER_LEN,
+            };
+            let n = (wanted - self.buffer.len()).min(data.len());
+            self.buffer.extend_from_slice(&data[..n]);
+            data = &data[n..];
+            if self.buffer.len() < wanted {
+                break;
+            }
+            let buffer = std::mem::take(&mut self.buffer);
+            match self.header.take() {
+                None => self.read_header(&buffer),
+                Some(header) => match parse_packet(&header, &buffer) {
+                    Some(XpraPacket::Hello) => {
+                        self.greeted = true;
+                        packets.push(XpraPacket::Hello);
+                    }
+                    Some(XpraPacket::Refused(reason)) if self.greeted => packets.push(XpraPacket::Disconnect(reason)),
+                    Some(packet) => packets.push(packet),
+                    None => {}
+                },
+            }
+        }
+        packets
+    }
+
+    fn read_header(&mut self, buffer: &[u8]) {
+        let header: [u8; HEADER_LEN] = buffer.try_into().expect("a whole header is buffered");
+        if header[0] != b'P' {
+            debug!("Desktop output is not xpra packets, no longer watching it");
+            self.lost = true;
+            return;
+        }
+        let (flags, level, index) = (header[1], header[2], header[3]);
+        let len = payload_len(&header);
+        let readable = flags & (FLAGS_CIPHER | FLAGS_YAML) == 0 && level & BROTLI_FLAG == 0;
+        // Raw chunks (index > 0) carry pictures and icons, never a packet type.
+        // Neither they nor large or unreadable packets come before the hello
+        if index > 0 || !readable || len > MAX_PAYLOAD {
+            self.greeted = true;
+            self.skip = len;
+        } else if len > 0 {
+            self.header = Some(header);
+        }
+    }
+}
+
+fn payload_len(header: &[u8; HEADER_LEN]) -> usize {
+    u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize
+}
+
+/// The packet of interest, if it is one. Disconnects are read as refusals,
+/// which only the watcher can tell apart.
+fn parse_packet(header: &[u8; HEADER_LEN], payload: &[u8]) -> Option<XpraPacket> {
+    let (flags, level) = (header[1], header[2]);
+    // The many packets of other types are told by the start of their
+    // payload, without inflating or decoding all of it
+    let kind = read_type(flags, level, payload)?;
+    if !WATCHED.contains(&kind.as_str()) {
+        return None;
+    }
+    let payload = inflate(level, payload, MAX_PAYLOAD * 4)?;
+    let (packet, _) = match flags & (FLAGS_RENCODE | FLAGS_RENCODEPLUS) {
+        0 => decode_bencode(&payload, 0)?,
+        _ => decode_rencode(&payload, 0)?,
+    };
+    let Value::List(items) = packet else {
+        return None;
+    };
+    let text = |i: usize| items.get(i).and_then(Value::text).unwrap_or_default();
+    let int = |i: usize| items.get(i).and_then(Value::int);
+    match items.first()?.text()?.as_str() {
+        "hello" => Some(XpraPacket::Hello),
+        // reason, extra details, ...
+        "disconnect" => {
+            let reasons: Vec<String> = items[1..].iter().filter_map(Value::text).filter(|r| !r.is_empty()).collect();
+            Some(XpraPacket::Refused(reasons.join(": ")))
+        }
+        // salt, auth capabilities, digest, salt digest, prompt
+        "challenge" => Some(XpraPacket::Challenge(Challenge {
+            salt: items.get(1)?.bytes()?.to_vec(),
+            digest: text(3),
+            salt_digest: items.get(4).and_then(Value::text).unwrap_or_else(|| "xor".to_string()),
+        })),
+        // dbus_id, nid, app_name, replaces_nid, app_icon, summary, body, expire_timeout, ...
+        "notify_show" => Some(XpraPacket::Notification(NotificationEvent::Show(Notification {
+            id: int(2)? as u64,
+            replaces: int(4).unwrap_or(0) as u64,
+            app_name: text(3),
+            summary: text(6),
+            body: text(7),
+            expire_timeout: int(8).unwrap_or(-1),
+        }))),
+        "notify_close" => Some(XpraPacket::Notification(NotificationEvent::Close(int(1)? as u64))),
+        _ => None,
+    }
+}
+
+/// Decoded value of a packet payload.
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Int(i64),
+    Bytes(Vec<u8>),
+    List(Vec<Value>),
+    Other,
+}
+
+/// Length of the packet `data` starts with, header included, if it starts
+/// with a header.
+pub fn packet_len(data: &[u8]) -> Option<usize> {
+    let header: &[u8; HEADER_LEN] = data.get(..HEADER_LEN)?.try_into().ok()?;
+    (header[0] == b'P').then(|| HEADER_LEN + payload_len(header))
+}
+
+/// Types of the packets `data` holds, if it holds whole packets only, each
+/// readable far enough to tell its type. Raw chunks belong to the packet
+/// after them, so `data` may not end with one.
+pub fn packet_types(mut data: &[u8]) -> Option<Vec<String>> {
+    let mut types = Vec::new();
+    let mut chunks_pending = false;
+    while !data.is_empty() {
+        let packet = data.get(..packet_len(data)?)?;
+        data = &data[packet.len()..];
+        let (flags, level, index) = (packet[1], packet[2], packet[3]);
+        chunks_pending = index > 0;
+        if !chunks_pending {
+            types.push(read_type(flags, level, &packet[HEADER_LEN..])?);
+        }
+    }
+    (!chunks_pending).then_some(types)
+}
+
+/// Type of a packet, read from as little of its payload as names it.
+fn read_type(flags: u8, level: u8, payload: &[u8]) -> Option<String> {
+    if flags & (FLAGS_CIPHER | FLAGS_YAML) != 0 || level & BROTLI_FLAG != 0 {
+        return None;
+    }
+    let start = match level {
+        0 => Cow::Borrowed(payload),
+        _ if level & LZ4_FLAG != 0 => Cow::Borrowed(lz4_literals(payload)?),
+        _ => {
+            let mut buf = Vec::new();
+            ZlibDecoder::new(payload).take(TYPE_PREFIX as u64).read_to_end(&mut buf).ok()?;
+            Cow::Owned(buf)
+        }
+    };
+    let rencoded = flags & (FLAGS_RENCODE | FLAGS_RENCODEPLUS) != 0;
+    let (kind, _) = match (rencoded, *start.first()?) {
+        (true, 192..=255 | RENCODE_LIST) => decode_rencode(&start, 1)?,
+        (false, b'l') => decode_bencode(&start, 1)?,
+        _ => return None,
+    };
+    kind.text()
+}
+
+/// Literals an lz4 block with its size prepended starts with: the start of
+/// the data, as the first sequence has nothing before it to copy from.
+fn lz4_literals(payload: &[u8]) -> Option<&[u8]> {
+    let block = payload.get(4..)?;
+    let mut len = (*block.first()? >> 4) as usize;
+    let mut pos = 1;
+    if len == 15 {
+        loop {
+            let byte = *block.get(pos)?;
+            pos += 1;
+            len += byte as usize;
+            if byte != 255 {
+                break;
+            }
+        }
+    }
+    block.get(pos..(pos + len).min(block.len()))
+}
+
+/// What the packet `data` holds is to the clipboard filter, with the raw
+/// `chunks` sent before it. Only packets whose type names the clipboard are
+/// decoded in full.
+pub fn clipboard_packet(data: &[u8], chunks: &[Vec<u8>]) -> ClipboardPacket {
+    let Some(payload) = packet_len(data).and_then(|len| data.get(HEADER_LEN..len)) else {
+        return ClipboardPacket::Unreadable;
+    };
+    let (flags, level, index) = (data[1], data[2], data[3]);
+    if index > 0 {
+        return ClipboardPacket::Unreadable;
+    }
+    let contents = match read_type(flags, level, payload).as_deref() {
+        // selection, targets, target, dtype, dformat, wire encoding, wire data, ...
+        Some("clipboard-token") => 7,
+        // request id, selection, dtype, dformat, wire encoding, wire data
+        Some("clipboard-contents") => 6,
+        Some(_) => return ClipboardPacket::Other,
+        None => return ClipboardPacket::Unreadable,
+    };
+    match clipboard_transfer(flags, level, payload, contents, chunks) {
+        Some(Some(transfer)) => ClipboardPacket::Transfer(transfer),
+        // Tokens only announcing the targets carry no contents
+        Some(None) => ClipboardPacket::Other,
+        None => ClipboardPacket::Unreadable,
+    }
+}
+
+/// Contents of a clipboard packet whose item `contents` holds them, if it
+/// carries some; `None` if it cannot be read.
+fn clipboard_transfer(
+    flags: u8,
+    level: u8,
+    payload: &[u8],
+    contents: usize,
+    chunks: &[Vec<u8>],
+) -> Option<Option<ClipboardTransfer>> {
+    let payload = inflate(level, payload, MAX_CLIPBOARD)?;
+    let (packet, _) = match flags & (FLAGS_RENCODE | FLAGS_RENCODEPLUS) {
+        0 => decode_bencode(&payload, 0)?,
+        _ => decode_rencode(&payload, 0)?,
+    };
+    let Value::List(items) = packet else {
+        return None;
+    };
+    let target = items.get(3).and_then(Value::text).unwrap_or_default();
+    let Some(item) = items.get(contents) else {
+        return Some(None);
+    };
+    let chunk = chunks.iter().rev().find(|chunk| chunk.get(3) == Some(&(contents as u8)));
+    let data = match (chunk, item) {
+        (Some(chunk), _) => {
+            let body = chunk.get(HEADER_LEN..)?;
+            let data = inflate(chunk[2], body, MAX_CLIPBOARD);
+            let size = data.as_ref().map_or(body.len(), |data| data.len());
+            return Some(Some(ClipboardTransfer { target, size, data: data.map(Cow::into_owned) }));
+        }
+        (None, Value::Bytes(bytes)) => Some(bytes.clone()),
+        // Contents sent as integers
+        (None, Value::List(ints)) => ints.iter().map(|n| n.int().and_then(|n| u8::try_from(n).ok())).collect(),
+        (None, _) => None,
+    };
+    let size = data.as_ref().map_or(0, Vec::len);
+    Some(Some(ClipboardTransfer { target, size, data }))
+}
+
+/// The `hello` packet with string `entries` added to its capabilities, sent
+/// uncompressed. `None` if the packet is no hello this can read.
+pub fn add_to_hello(packet: &[u8], entries: &[(&str, &[u8])]) -> Option<Vec<u8>> {
+    let header: &[u8; HEADER_LEN] = packet.get(..HEADER_LEN)?.try_into().ok()?;
+    let (flags, level, index) = (header[1], header[2], header[3]);
+    if index > 0 || flags & (FLAGS_CIPHER | FLAGS_YAML) != 0 || level & BROTLI_FLAG != 0 {
+        return None;
+    }
+    let payload = inflate(level, packet.get(HEADER_LEN..HEADER_LEN + payload_len(header))?, MAX_PAYLOAD * 4)?;
+    let rencoded = flags & (FLAGS_RENCODE | FLAGS_RENCODEPLUS) != 0;
+    let decode = if rencoded { decode_rencode } else { decode_bencode };
+    // The packet is a list of the type and the capabilities
+    let start = match (rencoded, *payload.first()?) {
+        (true, 192..=255 | RENCODE_LIST) | (false, b'l') => 1,
+        _ => return None,
+    };
+    let (kind, caps) = decode(&payload, start)?;
+    if kind.text()? != "hello" {
+        return None;
+    }
+    let (_, end) = decode(&payload, caps)?;
+    let mut added = Vec::new();
+    for (key, value) in entries {
+        for s in [key.as_bytes(), value] {
+            match rencoded && s.len() < 64 {
+                true => added.push(128 + s.len() as u8),
+                false => added.extend_from_slice(format!("{}:", s.len()).as_bytes()),
+            }
+            added.extend_from_slice(s);
+        }
+    }
+    let dict = &payload[caps..end];
+    let mut new_dict = Vec::with_capacity(dict.len() + added.len() + 1);
+    match dict[0] {
+        // Dictionaries of a fixed number of pairs
+        code @ 102..=126 if rencoded => {
+            let count = (code - 102) as usize + entries.len();
+            if count < 25 {
+                new_dict.push(102 + count as u8);
+                new_dict.extend_from_slice(&dict[1..]);
+                new_dict.extend(added);
+            } else {
+                new_dict.push(RENCODE_DICT);
+                new_dict.extend_from_slice(&dict[1..]);
+                new_dict.extend(added);
+                new_dict.push(RENCODE_TERM);
+            }
+        }
+        RENCODE_DICT if rencoded => {
+            new_dict.extend_from_slice(&dict[..dict.len() - 1]);
+            new_dict.extend(added);
+            new_dict.push(RENCODE_TERM);
+        }
+        b'd' if !rencoded => {
+            new_dict.extend_from_slice(&dict[..dict.len() - 1]);
+            new_dict.extend(added);
+            new_dict.push(b'e');
+        }
+        _ => return None,
+    }
+    let mut new_payload = payload[..caps].to_vec();
+    new_payload.extend(new_dict);
+    new_payload.extend_from_slice(&payload[end..]);
+    let mut hello = vec![b'P', flags, 0, 0];
+    hello.extend_from_slice(&(new_payload.len() as u32).to_be_bytes());
+    hello.extend(new_payload);
+    Some(hello)
+}
+
+/// The payload of a packet compressed at `level`, inflated, unless it
+/// inflates to more than `limit` bytes.
+fn inflate(level: u8, payload: &[u8], limit: usize) -> Option<Cow<'_, [u8]>> {
+    match level {
+        0 => Some(Cow::Borrowed(payload)),
+        _ if level & LZ4_FLAG != 0 => {
+            let size = u32::from_le_bytes(payload.get(..4)?.try_into().ok()?) as usize;
+            if size > limit {
+                return None;
+            }
+            lz4_flex::block::decompress_size_prepended(payload).ok().map(Cow::Owned)
+        }
+        _ => {
+            let mut buf = Vec::new();
+            ZlibDecoder::new(payload).take(limit as u64 + 1).read_to_end(&mut buf).ok()?;
+            (buf.len() <= limit).then_some(Cow::Owned(buf))
+        }
+    }
+}
+
+impl Value {
+    fn bytes(&self) -> Option<&[u8]> {
+        match self {
+            Value::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    fn text(&self) -> Option<String> {
+        match self {
+            Value::Bytes(bytes) => Some(String::from_utf8_lossy(bytes).into_owned()),
+            _ => None,
+        }
+    }
+
+    fn int(&self) -> Option<i64> {
+        match self {
+            Value::Int(n) => Some(*n),
+            _ => None,
+        }
+    }
+}
+
+/// Decode the bencoded value at `pos`, with the position after it.
+fn decode_bencode(data: &[u8], pos: usize) -> Option<(Value, usize)> {
+    bencode_value(data, pos, MAX_DEPTH)
+}
+
+/// Decode the bencoded value at `pos`, nested at most `depth` levels deep.
+fn bencode_value(data: &[u8], pos: usize, depth: usize) -> Option<(Value, usize)> {
+    match *data.get(pos)? {
+        b'i' => {
+            let end = pos + data[pos..].iter().position(|&b| b == b'e')?;
+            let n = std::str::from_utf8(&data[pos + 1..end]).ok()?.parse().ok()?;
+            Some((Value::Int(n), end + 1))
+        }
+        b'l' | b'd' => {
+            // Dictionaries are read as lists of keys and values
+            let depth = depth.checked_sub(1)?;
+            let mut items = Vec::new();
+            let mut pos = pos + 1;
+            while *data.get(pos)? != b'e' {
+                let (item, next) = bencode_value(data, pos, depth)?;
+                items.push(item);
+                pos = next;
+            }
+            Some((Value::List(items), pos + 1))
+        }
+        b'0'..=b'9' => decode_string(data, pos),
+        _ => None,
+    }
+}
+
+/// Decode a `<length>:<bytes>` string at `pos`.
+fn decode_string(data: &[u8], pos: usize) -> Option<(Value, usize)> {
+    let colon = pos + data[pos..].iter().position(|&b| b == b':')?;
+    let len: usize = std::str::from_utf8(&data[pos..colon]).ok()?.parse().ok()?;
+    let bytes = data.get(colon + 1..colon + 1 + len)?;
+    Some((Value::Bytes(bytes.to_vec()), colon + 1 + len))
+}
+
+/// Decode the rencoded value at `pos`, with the position after it.
+fn decode_rencode(data: &[u8], pos: usize) -> Option<(Value, usize)> {
+    rencode_value(data, pos, MAX_DEPTH)
+}
+
+/// Decode the rencoded value at `pos`, nested at most `depth` levels deep.
+fn rencode_value(data: &[u8], pos: usize, depth: usize) -> Option<(Value, usize)> {
+    const CHR_INT: u8 = 61;
+    const CHR_INT1: u8 = 62;
+    const CHR_INT2: u8 = 63;
+    const CHR_INT4: u8 = 64;
+    const CHR_INT8: u8 = 65;
+    const CHR_FLOAT32: u8 = 66;
+    const CHR_FLOAT64: u8 = 44;
+
+    let fixed = |len: usize| data.get(pos + 1..pos + 1 + len);
+    let code = *data.get(pos)?;
+    match code {
+        0..=43 => Some((Value::Int(code as i64), pos + 1)),
+        70..=101 => Some((Value::Int(69 - code as i64), pos + 1)),
+        CHR_INT1 => Some((Value::Int(i8::from_be_bytes(fixed(1)?.try_into().ok()?) as i64), pos + 2)),
+        CHR_INT2 => Some((Value::Int(i16::from_be_bytes(fixed(2)?.try_into().ok()?) as i64), pos + 3)),
+        CHR_INT4 => Some((Value::Int(i32::from_be_bytes(fixed(4)?.try_into().ok()?) as i64), pos + 5)),
+        CHR_INT8 => Some((Value::Int(i64::from_be_bytes(fixed(8)?.try_into().ok()?)), pos + 9)),
+        CHR_INT => {
+            let end = pos + data[pos..].iter().position(|&b| b == RENCODE_TERM)?;
+            let n = std::str::from_utf8(&data[pos + 1..end]).ok()?.parse().ok()?;
+            Some((Value::Int(n), end + 1))
+        }
+        CHR_FLOAT32 => Some((Value::Other, pos + 5)),
+        CHR_FLOAT64 => Some((Value::Other, pos + 9)),
+        67..=69 => Some((Value::Other, pos + 1)),
+        b'0'..=b'9' => decode_string(data, pos),
+        128..=191 => {
+            let len = (code - 128) as usize;
+            Some((Value::Bytes(fixed(len)?.to_vec()), pos + 1 + len))
+        }
+        RENCODE_LIST | RENCODE_DICT => {
+            let depth = depth.checked_sub(1)?;
+            let mut items = Vec::new();
+            let mut pos = pos + 1;
+            while *data.get(pos)? != RENCODE_TERM {
+                let (item, next) = rencode_value(data, pos, depth)?;
+                items.push(item);
+                pos = next;
+            }
+            Some((Value::List(items), pos + 1))
+        }
+        192..=255 | 102..=126 => {
+            // Fixed size lists, or dictionaries of key and value pairs
+            let count = match code {
+                192.. => (code - 192) as usize,
+                _ => (code - 102) as usize * 2,
+            };
+            let depth = depth.checked_sub(1)?;
+            let mut items = Vec::with_capacity(count);
+            let mut pos = pos + 1;
+            for _ in 0..count {
+                let (item, next) = rencode_value(data, pos, depth)?;
+                items.push(item);
+                pos = next;
+            }
+            Some((Value::List(items), pos))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use flate2::write::ZlibEncoder;
+    use flate2::Compression;
+
+    use super::*;
+
+    fn packet(flags: u8, level: u8, index: u8, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![b'P', flags, level, index];
+        packet.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    /// Rencoded `["notify_show", 0, 7, "chat", 0, "", "Ping", "Are you there?", 5000, "", [], {}]`.
+    fn rencoded_show() -> Vec<u8> {
+        let mut payload = vec![192 + 12, 128 + 11];
+        payload.extend_from_slice(b"notify_show");
+        payload.extend([0, 7, 128 + 4]);
+        payload.extend_from_slice(b"chat");
+        payload.extend([0, 128, 128 + 4]);
+        payload.extend_from_slice(b"Ping");
+        payload.push(128 + 14);
+        payload.extend_from_slice(b"Are you there?");
+        payload.push(63);
+        payload.extend(5000i16.to_be_bytes());
+        payload.extend([128, 192, 102]);
+        payload
+    }
+
+    #[test]
+    fn test_rencoded_notification() {
+        let shown = XpraPacket::Notification(NotificationEvent::Show(Notification {
+            id: 7,
+            replaces: 0,
+            app_name: "chat".to_string(),
+            summary: "Ping".to_string(),
+            body: "Are you there?".to_string(),
+            expire_timeout: 5000,
+        }));
+
+        let mut watcher = PacketWatcher::default();
+        let mut stream = packet(FLAGS_RENCODEPLUS, 0, 0, &[192 + 1, 128 + 4, b'p', b'i', b'n', b'g']);
+        stream.extend(packet(FLAGS_RENCODEPLUS, 0, 1, &[0; 100]));
+        stream.extend(packet(FLAGS_RENCODEPLUS, 0, 0, &rencoded_show()));
+        // Packets may be split anywhere between frames
+        let (first, second) = stream.split_at(20);
+        assert_eq!(watcher.push(first), vec![]);
+        assert_eq!(watcher.push(second), vec![shown.clone()]);
+
+        let compressed = lz4_flex::block::compress_prepend_size(&rencoded_show());
+        assert_eq!(watcher.push(&packet(FLAGS_RENCODEPLUS, LZ4_FLAG | 1, 0, &compressed)), vec![shown.clone()]);
+
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::fast());
+        encoder.write_all(&rencoded_show()).unwrap();
+        let compressed = encoder.finish().unwrap();
+        assert_eq!(watcher.push(&packet(FLAGS_RENCODE, 3, 0, &compressed)), vec![shown]);
+
+        // Anything else ends the watching
+        assert_eq!(watcher.push(b"GET / HTTP/1.1\r\n"), vec![]);
+        assert_eq!(watcher.push(&packet(FLAGS_RENCODEPLUS, 0, 0, &rencoded_show())), vec![]);
+        watcher.reset();
+        assert_eq!(watcher.push(&packet(FLAGS_RENCODEPLUS, 0, 0, &rencoded_show())).len(), 1);
+    }
+
+    #[test]
+    fn test_bencoded_notification() {
+        let mut watcher = PacketWatcher::default();
+        let payload = b"l12:notify_closei7ee";
+        assert_eq!(watcher.push(&packet(0, 0, 0, payload)), vec![XpraPacket::Notification(NotificationEvent::Close(7))]);
+        let payload = b"l11:notify_showi0ei9e4:maili0e0:3:New5:Helloi-1e0:ledee";
+        let events = watcher.push(&packet(0, 0, 0, payload));
+        let [XpraPacket::Notification(NotificationEvent::Show(notification))] = &events[..] else {
+            panic!("expected a notification, got {events:?}");
+        };
+        assert_eq!(notification.id, 9);
+        assert_eq!(notification.app_name, "mail");
+        assert_eq!(notification.summary, "New");
+        assert_eq!(notification.body, "Hello");
+    }
+
+    #[test]
+    fn test_depth_limit() {
+        let nested = |depth: usize| {
+            let mut payload = b"l12:notify_close".to_vec();
+            payload.extend(b"l".repeat(depth));
+            payload.extend(b"e".repeat(depth));
+            payload.extend(b"i7ee");
+            payload
+        };
+        assert!(decode_bencode(&nested(MAX_DEPTH - 1), 0).is_some());
+        assert!(decode_bencode(&nested(MAX_DEPTH), 0).is_none());
+
+        // A packet nested too deeply is skipped, even one that would
+        // overflow the stack
+        let mut watcher = PacketWatcher::default();
+        assert_eq!(watcher.push(&packet(0, 0, 0, &nested(30_000))), vec![]);
+        let mut payload = vec![RENCODE_LIST, 128 + 12];
+        payload.extend_from_slice(b"notify_close");
+        payload.extend([RENCODE_LIST].repeat(30_000));
+        payload.extend([RENCODE_TERM].repeat(30_001));
+        assert_eq!(watcher.push(&packet(FLAGS_RENCODE, 0, 0, &payload)), vec![]);
+        assert!(!watcher.invalid());
+    }
+
+    #[test]
+    fn test_handshake() {
+        // A refusal before the hello, as when authentication fails
+        let mut watcher = PacketWatcher::default();
+        let payload = b"l10:disconnect21:authentication failed14:wrong passworde";
+        assert_eq!(
+            watcher.push(&packet(0, 0, 0, payload)),
+            vec![XpraPacket::Refused("authentication failed: wrong password".to_string())]
+        );
+        assert!(!watcher.invalid());
+
+        // A disconnect once greeted
+        let mut watcher = PacketWatcher::default();
+        let mut stream = packet(0, 0, 0, b"l5:hellod7:versioni6eee");
+        stream.extend(packet(0, 0, 0, b"l10:disconnect12:ping timeoute"));
+        assert_eq!(
+            watcher.push(&stream),
+            vec![XpraPacket::Hello, XpraPacket::Disconnect("ping timeout".to_string())]
+        );
+
+        // Something other than xpra answering
+        let mut watcher = PacketWatcher::default();
+        assert_eq!(watcher.push(b"HTTP/1.1 404 Not Found\r\n"), vec![]);
+        assert!(watcher.invalid());
+        watcher.reset();
+        assert!(!watcher.invalid());
+    }
+
+    #[test]
+    fn test_challenge() {
+        // Rencoded `["challenge", "salt", "", "hmac+sha256", "xor", ""]`
+        let mut payload = vec![192 + 6, 128 + 9];
+        payload.extend_from_slice(b"challenge");
+        payload.extend([128 + 4, b's', b'a', b'l', b't', 128, 128 + 11]);
+        payload.extend_from_slice(b"hmac+sha256");
+        payload.extend([128 + 3, b'x', b'o', b'r', 128]);
+        let mut watcher = PacketWatcher::default();
+        assert_eq!(
+            watcher.push(&packet(FLAGS_RENCODEPLUS, 0, 0, &payload)),
+            vec![XpraPacket::Challenge(Challenge {
+                salt: b"salt".to_vec(),
+                digest: "hmac+sha256".to_string(),
+                salt_digest: "xor".to_string(),
+            })]
+        );
+    }
+
+    #[test]
+    fn test_add_to_hello() {
+        // Rencoded `["hello", {"version": 6}]`, with a fixed size dictionary
+        let mut payload = vec![192 + 2, 128 + 5];
+        payload.extend_from_slice(b"hello");
+        payload.extend([103, 128 + 7]);
+        payload.extend_from_slice(b"version");
+        payload.push(6);
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::fast());
+        encoder.write_all(&payload).unwrap();
+        let hello = packet(FLAGS_RENCODEPLUS, 1, 0, &encoder.finish().unwrap());
+
+        let added = add_to_hello(&hello, &[("challenge_response", b"abc")]).unwrap();
+        assert_eq!(&added[..4], [b'P', FLAGS_RENCODEPLUS, 0, 0]);
+        assert_eq!(packet_len(&added), Some(added.len()));
+        let (Value::List(items), _) = decode_rencode(&added[HEADER_LEN..], 0).unwrap() else {
+            panic!("expected a list");
+        };
+        let Value::List(caps) = &items[1] else {
+            panic!("expected the capabilities");
+        };
+        assert_eq!(caps.len(), 4);
+        assert_eq!(caps[2].text().unwrap(), "challenge_response");
+        assert_eq!(caps[3].text().unwrap(), "abc");
+
+        let notify = packet(FLAGS_RENCODEPLUS, 0, 0, &rencoded_show());
+        assert_eq!(add_to_hello(&notify, &[("challenge_response", b"abc")]), None);
+    }
+
+    #[test]
+    fn test_packet_types() {
+        let notify = packet(FLAGS_RENCODEPLUS, 0, 0, &rencoded_show());
+        let webcam = packet(0, 0, 0, b"l12:webcam-starti0ee");
+        assert_eq!(packet_types(&notify).unwrap(), ["notify_show"]);
+        // Every packet of a frame is read, with the chunks before them
+        let chunk = packet(FLAGS_RENCODEPLUS, 0, 1, &[0; 100]);
+        let frame = [notify.clone(), chunk.clone(), webcam.clone()].concat();
+        assert_eq!(packet_types(&frame).unwrap(), ["notify_show", "webcam-start"]);
+        assert_eq!(packet_types(&chunk), None);
+        assert_eq!(packet_types(&notify[..20]), None);
+        assert_eq!(packet_types(b"GET / HTTP/1.1\r\n"), None);
+
+        // Compressed packets are read from their start
+        let compressed = lz4_flex::block::compress_prepend_size(&rencoded_show());
+        assert_eq!(packet_types(&packet(FLAGS_RENCODEPLUS, LZ4_FLAG | 1, 0, &compressed)).unwrap(), ["notify_show"]);
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::fast());
+        encoder.write_all(b"l12:webcam-starti0e").unwrap();
+        encoder.write_all(&[b'x'; 100_000]).unwrap();
+        let compressed = encoder.finish().unwrap();
+        assert_eq!(packet_types(&packet(0, 3, 0, &compressed)).unwrap(), ["webcam-start"]);
+        // Packets that cannot be read have no type
+        assert_eq!(packet_types(&packet(FLAGS_CIPHER, 0, 0, b"l12:webcam-starti0ee")), None);
+        assert_eq!(packet_types(&packet(0, BROTLI_FLAG | 1, 0, b"l12:webcam-starti0ee")), None);
+        assert_eq!(packet_types(&packet(FLAGS_YAML, 0, 0, b"- webcam-start")), None);
+    }
+
+    #[test]
+    fn test_clipboard_packet() {
+        let transfer = |packet: ClipboardPacket| match packet {
+            ClipboardPacket::Transfer(transfer) => transfer,
+            other => panic!("no transfer: {other:?}"),
+        };
+        let token = packet(0, 0, 0, b"l15:clipboard-token9:CLIPBOARDl11:UTF8_STRINGe11:UTF8_STRING11:UTF8_STRINGi8e5:bytes5:helloi1ei0ei0ee");
+        let token_transfer = transfer(clipboard_packet(&token, &[]));
+        assert_eq!(token_transfer.target, "UTF8_STRING");
+        assert_eq!((token_transfer.size, token_transfer.data.as_deref()), (5, Some(&b"hello"[..])));
+        // Tokens only announcing the targets carry no contents
+        let announce = packet(0, 0, 0, b"l15:clipboard-token9:CLIPBOARDl11:UTF8_STRINGee");
+        assert_eq!(clipboard_packet(&announce, &[]), ClipboardPacket::Other);
+        assert_eq!(clipboard_packet(&packet(0, 0, 0, b"l12:webcam-starti0ee"), &[]), ClipboardPacket::Other);
+
+        // Packets that cannot be read are not let through unchecked
+        let hidden = [
+            packet(FLAGS_CIPHER, 0, 0, &token[HEADER_LEN..]),
+            packet(FLAGS_YAML, 0, 0, b"- clipboard-token"),
+            packet(0, BROTLI_FLAG | 1, 0, &token[HEADER_LEN..]),
+        ];
+        for hidden in hidden {
+            assert_eq!(clipboard_packet(&hidden, &[]), ClipboardPacket::Unreadable);
+        }
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::fast());
+        encoder.write_all(b"l15:clipboard-token9:CLIPBOARDl11:UTF8_STRINGe11:UTF8_STRING11:UTF8_STRINGi8e5:bytes").unwrap();
+        encoder.write_all(format!("{}:", MAX_CLIPBOARD + 1).as_bytes()).unwrap();
+        encoder.write_all(&vec![b'x'; MAX_CLIPBOARD + 1]).unwrap();
+        encoder.write_all(b"i1ei0ei0ee").unwrap();
+        let oversized = packet(0, 1, 0, &encoder.finish().unwrap());
+        assert_eq!(clipboard_packet(&oversized, &[]), ClipboardPacket::Unreadable);
+
+        // Large contents come compressed in a chunk before the packet
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&[b'x'; 100_000]).unwrap();
+        let chunk = packet(0, 1, 6, &encoder.finish().unwrap());
+        let contents = packet(0, 0, 0, b"l18:clipboard-contentsi3e9:CLIPBOARD9:image/pngi8e5:bytesi0ee");
+        let chunked = transfer(clipboard_packet(&contents, std::slice::from_ref(&chunk)));
+        assert_eq!((chunked.target.as_str(), chunked.size), ("image/png", 100_000));
+        // Contents that do not inflate are unread, sized by the chunk
+        let mut broken = chunk.clone();
+        broken[HEADER_LEN] ^= 0xff;
+        let unread = transfer(clipboard_packet(&contents, &[broken]));
+        assert_eq!((unread.size, unread.data), (chunk.len() - HEADER_LEN, None));
+    }
+}
@@ -0,0 +1,143 @@
+//! Proxy settings and extra CA certificates for the desktops.
+//!
+//! Networks that only reach the internet through a proxy, often one
+//! intercepting TLS with its own CA, need both in every desktop. The proxy
+//! is passed to xpra, and so to each program on the desktop, through the
+//! usual `http_proxy` variables. Extra CA certificates are added when the
+//! desktop starts, before xpra: to a bundle in the user's home with the
+//! system's CAs, which `SSL_CERT_FILE` and friends point at, and to the NSS
+//! databases Chromium and Firefox read, if certutil is installed.
+
+use std::path::PathBuf;
+use serde::{Deserialize, Serialize};
+
+use crate::xpra_hosts::shell_quote;
+
+/// System CA bundles, of Debian and of Red Hat derived hosts.
+const SYSTEM_BUNDLES: [&str; 2] = ["/etc/ssl/certs/ca-certificates.crt", "/etc/pki/tls/certs/ca-bundle.crt"];
+
+/// Variables pointing TLS libraries at the bundle with the extra CAs.
+const BUNDLE_VARS: [&str; 4] = ["SSL_CERT_FILE", "REQUESTS_CA_BUNDLE", "CURL_CA_BUNDLE", "NODE_EXTRA_CA_CERTS"];
+
+/// Proxy and CA certificates used by every desktop.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ProxyConfig {
+    /// Proxy for HTTP requests, e.g. `http://proxy.example.com:3128`
+    #[serde(default)]
+    pub http_proxy: Option<String>,
+
+    /// Proxy for HTTPS requests
+    #[serde(default)]
+    pub https_proxy: Option<String>,
+
+    /// Hosts and domains reached without the proxy
+    #[serde(default)]
+    pub no_proxy: Vec<String>,
+
+    /// PEM files of CA certificates to trust, on the desktop's host
+    #[serde(default)]
+    pub ca_certificates: Vec<PathBuf>,
+}
+
+impl ProxyConfig {
+    /// Environment of the xpra server, inherited by the desktop's programs.
+    /// Both spellings are set, as programs disagree on which one they read.
+    pub fn env(&self) -> Vec<String> {
+        let mut env = Vec::new();
+        let no_proxy = (!self.no_proxy.is_empty()).then(|| self.no_proxy.join(","));
+        let vars = [("http_proxy", &self.http_proxy), ("https_proxy", &self.https_proxy), ("no_proxy", &no_proxy)];
+        for (name, value) in vars {
+            if let Some(value) = value {
+                env.push(format!("{}={}", name, value));
+                env.push(format!("{}={}", name.to_ascii_uppercase(), value));
+            }
+        }
+        env
+    }
+
+    /// Command and arguments that add the CA certificates to the user's
+    /// trust stores before running a program.
+    pub fn command_prefix(&self) -> Vec<String> {
+        if self.ca_certificates.is_empty() {
+            return Vec::new();
+        }
+        vec!["sh".to_string(), "-c".to_string(), self.ca_script(), "sh".to_string()]
+    }
+
+    fn ca_script(&self) -> String {
+        let certs: Vec<String> =
+            self.ca_certificates.iter().map(|cert| shell_quote(&cert.to_string_lossy())).collect();
+        let certs = certs.join(" ");
+        let systems = SYSTEM_BUNDLES.join(" ");
+        let exports: Vec<String> = BUNDLE_VARS.iter().map(|var| format!("{var}=\"$bundle\"")).collect();
+        let exports = exports.join(" ");
+        // Failures leave the desktop without the CAs, but still start it
+        format!(
+            r#"dir="$HOME/.local/share/sshx"
+bundle="$dir/ca-certificates.pem"
+system=
+for file in {systems}; do [ -f "$file" ] && system=$file && break; done
+mkdir -p "$dir" && tmp=$(mktemp "$bundle.XXXXXX") &&
+  {{ cat $system {certs} > "$tmp" && mv "$tmp" "$bundle" || rm -f "$tmp"; }}
+export {exports}
+if command -v certutil >/dev/null; then
+  mkdir -p "$HOME/.pki/nssdb"
+  [ -f "$HOME/.pki/nssdb/cert9.db" ] || certutil -N -d "sql:$HOME/.pki/nssdb" --empty-password
+  for db in "$HOME/.pki/nssdb" "$HOME"/.mozilla/firefox/*; do
+    [ -f "$db/cert9.db" ] || continue
+    for cert in {certs}; do
+      certutil -A -d "sql:$db" -t C,, -n "sshx $(basename "$cert")" -i "$cert"
+    done
+  done
+fi 2>/dev/null
+exec "$@"
+"#
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_env() {
+        assert!(ProxyConfig::default().env().is_empty());
+        let proxy = ProxyConfig {
+            https_proxy: Some("http://proxy:3128".to_string()),
+            no_proxy: vec!["localhost".to_string(), ".internal".to_string()],
+            ..ProxyConfig::default()
+        };
+        assert_eq!(
+            proxy.env(),
+            [
+                "https_proxy=http://proxy:3128",
+                "HTTPS_PROXY=http://proxy:3128",
+                "no_proxy=localhost,.internal",
+                "NO_PROXY=localhost,.internal",
+            ]
+        );
+        assert!(proxy.command_prefix().is_empty());
+    }
+
+    #[test]
+    fn test_ca_script() {
+        let home = std::env::temp_dir().join(format!("sshx-proxy-{}", std::process::id()));
+        std::fs::create_dir_all(&home).unwrap();
+        let cert = home.join("corp ca.pem");
+        std::fs::write(&cert, "-----BEGIN CERTIFICATE-----\ncorp\n").unwrap();
+
+        let proxy = ProxyConfig { ca_certificates: vec![cert], ..ProxyConfig::default() };
+        let output = std::process::Command::new(&proxy.command_prefix()[0])
+            .args(&proxy.command_prefix()[1..])
+            .args(["sh", "-c", "echo $SSL_CERT_FILE"])
+            .env("HOME", &home)
+            .output()
+            .unwrap();
+        let bundle = home.join(".local/share/sshx/ca-certificates.pem");
+        assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), bundle.to_str().unwrap());
+        assert!(std::fs::read_to_string(&bundle).unwrap().ends_with("corp\n"));
+
+        std::fs::remove_dir_all(&home).unwrap();
+    }
+}
@@ -0,0 +1,685 @@
+//! Publisher of the host's events to a message broker.
+//!
+//! Fleet-wide dashboards would otherwise have to scrape every host. With a
+//! `publisher` configured, the host forwards its session events as they are
+//! logged, its alerts, and a snapshot of its metrics at an interval, to NATS
+//! subjects or Kafka topics named after the `topic` template. Messages are
+//! JSON: session events as exported by `audit export --format records`,
+//! keyed by their `event_id` so consumers can drop duplicates, metrics and
+//! alerts with the `source` host and instance.
+//!
+//! Messages are sent in batches, once `batch_size` are waiting or every
+//! `batch_interval_ms`, by a task of their own so a slow broker never holds
+//! up the collection of events. While the broker cannot be reached, up to
+//! 10000 are kept and retried in order; the oldest are dropped past that.
+//! Events the publisher falls too far behind on are only in the history log.
+//!
+//! NATS is spoken to in its text protocol: a batch is confirmed by the
+//! server answering the `PING` that follows it. Credentials are read from
+//! `NATS_TOKEN`, or `NATS_USER` and `NATS_PASSWORD`. Kafka is reached through
+//! a REST proxy with the Confluent v2 API, with a bearer token from
+//! `KAFKA_REST_TOKEN`, or basic auth from `KAFKA_REST_USER` and
+//! `KAFKA_REST_PASSWORD`. With `tls` configured, both are spoken to over TLS,
+//! trusting its CA and presenting its client certificate if any.
+
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use anyhow::{Context, Result};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader, ReadHalf, WriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{oneshot, Notify};
+use tokio::time;
+use tracing::{info, warn};
+
+use crate::xpra_audit;
+use crate::xpra_context::XpraContext;
+use crate::xpra_log_schema;
+use crate::xpra_logger::SessionEvent;
+use crate::xpra_tls::{self, XpraIo};
+
+/// Most messages kept while the broker cannot be reached.
+const MAX_PENDING: usize = 10_000;
+
+/// Most messages sent to the broker in one request.
+const MAX_BATCH: usize = 1000;
+
+/// Time a batch may take to be accepted before it is retried.
+const PUBLISH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Port of NATS servers given without one.
+const NATS_PORT: u16 = 4222;
+
+/// Broker the events are published to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PublisherBroker {
+    /// A NATS server
+    Nats {
+        /// Address of the server, like `nats://nats.internal:4222`
+        url: String,
+    },
+    /// A Kafka cluster, through a REST proxy
+    Kafka {
+        /// Base URL of the REST proxy, like `http://kafka-rest.internal:8082`
+        rest_url: String,
+    },
+}
+
+/// Publishing of the host's events to a message broker.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PublisherConfig {
+    /// Broker the events are published to
+    pub broker: PublisherBroker,
+
+    /// Subject or topic of the messages, with a `{source}` placeholder for
+    /// the host and instance and `{kind}` for `session_events`, `metrics`
+    /// or `alerts`
+    #[serde(default = "default_topic")]
+    pub topic: String,
+
+    /// Messages sent at once
+    #[serde(default = "default_batch_size")]
+    pub batch_size: usize,
+
+    /// Longest time in milliseconds a message waits for its batch to fill
+    #[serde(default = "default_batch_interval_ms")]
+    pub batch_interval_ms: u64,
+
+    /// Seconds between snapshots of the metrics (0 = none)
+    #[serde(default = "default_metrics_interval")]
+    pub metrics_interval: u64,
+
+    /// TLS to the broker
+    #[serde(default)]
+    pub tls: Option<PublisherTls>,
+}
+
+/// TLS to the broker.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PublisherTls {
+    /// PEM file of the CA the broker's certificate is signed by
+    pub ca_cert: PathBuf,
+
+    /// PEM file of the certificate presented to brokers requiring one
+    #[serde(default)]
+    pub client_cert: Option<PathBuf>,
+
+    /// PEM file of the key of `client_cert`
+    #[serde(default)]
+    pub client_key: Option<PathBuf>,
+}
+
+impl PublisherTls {
+    /// The client certificate and key, if both are set.
+    fn identity(&self) -> Result<Option<(&PathBuf, &PathBuf)>> {
+        match (&self.client_cert, &self.client_key) {
+            (Some(cert), Some(key)) => Ok(Some((cert, key))),
+            (None, None) => Ok(None),
+            _ => anyhow::bail!("publisher.tls needs both client_cert and client_key, or neither"),
+        }
+    }
+}
+
+fn default_topic() -> String { "sshx.{source}.{kind}".to_string() }
+
+fn default_batch_size() -> usize { 100 }
+
+fn default_batch_interval_ms() -> u64 { 1000 }
+
+fn default_metrics_interval() -> u64 { 60 }
+
+/// What a message is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageKind {
+    /// An event of the history log
+    SessionEvent,
+    /// A snapshot of the metrics
+    Metrics,
+    /// An alert about the host
+    Alert,
+}
+
+impl MessageKind {
+    /// Name of the kind, for the `{kind}` placeholder
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MessageKind::SessionEvent => "session_events",
+            MessageKind::Metrics => "metrics",
+            MessageKind::Alert => "alerts",
+        }
+    }
+}
+
+/// A message waiting to be published.
+#[derive(Debug, Clone, PartialEq)]
+struct Message {
+    topic: String,
+    /// Key of the message, for Kafka partitioning and deduplication
+    key: String,
+    payload: Value,
+}
+
+impl PublisherConfig {
+    /// Subject or topic of the messages of `kind` from `source`. Characters
+    /// of the source that may not appear in a subject or topic name are
+    /// replaced with `_`.
+    pub fn topic(&self, source: &str, kind: MessageKind) -> String {
+        let source: String = source
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+            .collect();
+        self.topic.replace("{source}", &source).replace("{kind}", kind.as_str())
+    }
+
+    /// Publish the events of `context` in the background, until the context
+    /// is dropped. Fails if the TLS certificates cannot be loaded.
+    pub fn start(&self, context: &Arc<XpraContext>, source: String) -> Result<()> {
+        let config = self.clone();
+        let queue = Arc::new(Mutex::new(Queue::default()));
+        let publisher = Publisher::new(&config, queue.clone())?;
+        let ready = Arc::new(Notify::new());
+        let (stop, stopped) = oneshot::channel();
+        let interval = Duration::from_millis(config.batch_interval_ms.max(1));
+        tokio::spawn(publisher.run(interval, ready.clone(), stopped));
+
+        let mut events = context.logger.subscribe();
+        let mut alerts = context.logger.subscribe_alerts();
+        let context = Arc::downgrade(context);
+        info!(broker = ?config.broker, "Publishing events as {}", config.topic(&source, MessageKind::SessionEvent));
+        tokio::spawn(async move {
+            // Publishes what is left once this loop ends
+            let _stop = stop;
+            let mut metrics = time::interval(Duration::from_secs(config.metrics_interval.max(1)));
+            loop {
+                let message = tokio::select! {
+                    event = events.recv() => match event {
+                        Ok(event) => config.event_message(&event, &source),
+                        Err(RecvError::Lagged(missed)) => {
+                            warn!(missed, "Publisher fell behind, session events were not published");
+                            continue;
+                        }
+                        Err(RecvError::Closed) => break,
+                    },
+                    alert = alerts.recv() => match alert {
+                        Ok(alert) => serde_json::to_value(&alert)
+                            .map(|alert| config.message(MessageKind::Alert, &source, with_source(alert, &source)))
+                            .map_err(Into::into),
+                        Err(RecvError::Lagged(missed)) => {
+                            warn!(missed, "Publisher fell behind, alerts were not published");
+                            continue;
+                        }
+                        Err(RecvError::Closed) => break,
+                    },
+                    _ = metrics.tick(), if config.metrics_interval > 0 => {
+                        let Some(context) = Weak::upgrade(&context) else {
+                            break;
+                        };
+                        let snapshot = json!({ "timestamp": Utc::now(), "metrics": context.metrics.get_metrics() });
+                        Ok(config.message(MessageKind::Metrics, &source, with_source(snapshot, &source)))
+                    }
+                };
+                match message {
+                    Ok(message) => {
+                        if queue.lock().unwrap().push(message) >= config.batch_size.max(1) {
+                            ready.notify_one();
+                        }
+                    }
+                    Err(e) => warn!("Failed to serialize a message to publish: {:#}", e),
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// Message of `kind` from `source`, keyed by the source.
+    fn message(&self, kind: MessageKind, source: &str, payload: Value) -> Message {
+        Message { topic: self.topic(source, kind), key: source.to_string(), payload }
+    }
+
+    /// Message of a session event, as exported for billing systems.
+    fn event_message(&self, event: &SessionEvent, source: &str) -> Result<Message> {
+        let record = xpra_audit::export_record(&xpra_log_schema::to_line(event)?, source)?;
+        let payload: Value = serde_json::from_str(&record)?;
+        let key = payload["event_id"].as_str().unwrap_or(source).to_string();
+        Ok(Message { key, ..self.message(MessageKind::SessionEvent, source, payload) })
+    }
+}
+
+/// `payload` with the `source` it comes from.
+fn with_source(mut payload: Value, source: &str) -> Value {
+    if let Value::Object(fields) = &mut payload {
+        fields.insert("source".to_string(), Value::from(source));
+    }
+    payload
+}
+
+/// Messages waiting for the broker, filled by the loop collecting them and
+/// emptied by the publisher.
+#[derive(Debug, Default)]
+struct Queue {
+    messages: VecDeque<Message>,
+    /// Messages dropped or published so far, the position of the first
+    /// waiting one
+    front: u64,
+}
+
+impl Queue {
+    /// Add `message`, dropping the oldest if the queue is full, and return
+    /// how many are waiting.
+    fn push(&mut self, message: Message) -> usize {
+        if self.messages.len() >= MAX_PENDING {
+            self.messages.pop_front();
+            self.front += 1;
+            warn!("Publisher queue is full, dropped the oldest message");
+        }
+        self.messages.push_back(message);
+        self.messages.len()
+    }
+
+    /// Position and copy of the next batch to publish.
+    fn batch(&self) -> (u64, Vec<Message>) {
+        (self.front, self.messages.iter().take(MAX_BATCH).cloned().collect())
+    }
+
+    /// Remove the `count` messages from position `start` on, once published,
+    /// except those dropped meanwhile.
+    fn remove(&mut self, start: u64, count: usize) {
+        let end = start + count as u64;
+        while self.front < end && self.messages.pop_front().is_some() {
+            self.front += 1;
+        }
+    }
+}
+
+/// Publisher of the queued messages, with the connection to the broker.
+struct Publisher {
+    broker: PublisherBroker,
+    tls: Option<PublisherTls>,
+    queue: Arc<Mutex<Queue>>,
+    nats: Option<NatsConnection>,
+    http: reqwest::Client,
+    /// Whether publishing is currently failing, so it is only warned about
+    /// once
+    degraded: bool,
+}
+
+impl Publisher {
+    fn new(config: &PublisherConfig, queue: Arc<Mutex<Queue>>) -> Result<Self> {
+        let mut http = reqwest::Client::builder();
+        if let Some(tls) = &config.tls {
+            let ca = std::fs::read(&tls.ca_cert).with_context(|| format!("failed to read {}", tls.ca_cert.display()))?;
+            for cert in reqwest::Certificate::from_pem_bundle(&ca)
+                .with_context(|| format!("invalid CA certificate in {}", tls.ca_cert.display()))?
+            {
+                http = http.add_root_certificate(cert);
+            }
+            if let Some((cert, key)) = tls.identity()? {
+                let mut pem = std::fs::read(cert).with_context(|| format!("failed to read {}", cert.display()))?;
+                pem.extend(std::fs::read(key).with_context(|| format!("failed to read {}", key.display()))?);
+                http = http.identity(reqwest::Identity::from_pem(&pem).context("invalid client certificate or key")?);
+            }
+            if let PublisherBroker::Nats { .. } = config.broker {
+                // Fail at start rather than at the first connection
+                tls_connector(tls)?;
+            }
+        }
+        Ok(Self {
+            broker: config.broker.clone(),
+            tls: config.tls.clone(),
+            queue,
+            nats: None,
+            http: http.build()?,
+            degraded: false,
+        })
+    }
+
+    /// Publish the queued messages every `interval`, and as soon as
+    /// `ready` is notified of a full batch while the broker is reachable,
+    /// until `stop` is sent or dropped.
+    async fn run(mut self, interval: Duration, ready: Arc<Notify>, mut stop: oneshot::Receiver<()>) {
+        let mut tick = time::interval(interval);
+        loop {
+            tokio::select! {
+                _ = tick.tick() => {}
+                _ = ready.notified(), if !self.degraded => {}
+                _ = &mut stop => {
+                    self.flush().await;
+                    return;
+                }
+            }
+            self.flush().await;
+        }
+    }
+
+    /// Publish the waiting messages, a batch at a time, until they are all
+    /// accepted or the broker fails.
+    async fn flush(&mut self) {
+        loop {
+            let (start, batch) = self.queue.lock().unwrap().batch();
+            if batch.is_empty() {
+                return;
+            }
+            let result = match time::timeout(PUBLISH_TIMEOUT, self.publish(&batch)).await {
+                Ok(result) => result,
+                Err(_) => Err(anyhow::anyhow!("broker did not answer in {}s", PUBLISH_TIMEOUT.as_secs())),
+            };
+            match result {
+                Ok(()) => {
+                    self.queue.lock().unwrap().remove(start, batch.len());
+                    if self.degraded {
+                        info!("Publishing events again");
+                        self.degraded = false;
+                    }
+                }
+                Err(e) => {
+                    // Whatever the connection was in the middle of is lost
+                    self.nats = None;
+                    if !self.degraded {
+                        let pending = self.queue.lock().unwrap().messages.len();
+                        warn!(pending, "Cannot publish events, keeping them to retry: {:#}", e);
+                        self.degraded = true;
+                    }
+                    return;
+                }
+            }
+        }
+    }
+
+    async fn publish(&mut self, batch: &[Message]) -> Result<()> {
+        match &self.broker {
+            PublisherBroker::Nats { url } => {
+                if self.nats.is_none() {
+                    self.nats = Some(NatsConnection::connect(url, self.tls.as_ref()).await?);
+                }
+                self.nats.as_mut().unwrap().publish(batch).await
+            }
+            PublisherBroker::Kafka { rest_url } => {
+                for (topic, records) in kafka_batches(batch) {
+                    let url = format!("{}/topics/{}", rest_url.trim_end_matches('/'), topic);
+                    let request = self
+                        .http
+                        .post(&url)
+                        .header(reqwest::header::CONTENT_TYPE, "application/vnd.kafka.json.v2+json")
+                        .body(serde_json::to_vec(&records)?);
+                    let response = kafka_auth(request).send().await?;
+                    let status = response.status();
+                    let answer: Value = serde_json::from_str(&response.text().await.unwrap_or_default()).unwrap_or_default();
+                    if !status.is_success() {
+                        anyhow::bail!("POST {} failed with {}: {}", url, status, answer);
+                    }
+                    // The proxy answers 200 with an error for each record
+                    // the cluster refused
+                    let refused = answer["offsets"].as_array().into_iter().flatten().find(|offset| !offset["error"].is_null());
+                    if let Some(offset) = refused {
+                        anyhow::bail!("Kafka refused records of {}: {}", topic, offset["error"]);
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// `request` to the Kafka REST proxy with the credentials from the
+/// environment, if any.
+fn kafka_auth(request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+    if let Ok(token) = std::env::var("KAFKA_REST_TOKEN") {
+        request.bearer_auth(token)
+    } else if let (Ok(user), Ok(password)) = (std::env::var("KAFKA_REST_USER"), std::env::var("KAFKA_REST_PASSWORD")) {
+        request.basic_auth(user, Some(password))
+    } else {
+        request
+    }
+}
+
+/// Connector trusting the broker's CA.
+fn tls_connector(tls: &PublisherTls) -> Result<tokio_rustls::TlsConnector> {
+    let identity = tls.identity()?;
+    xpra_tls::connector(&tls.ca_cert, identity.map(|(cert, key)| (cert.as_path(), key.as_path())))
+}
+
+/// Request bodies of the Kafka REST proxy for `batch`, by topic, keeping
+/// the order of each topic's messages.
+fn kafka_batches(batch: &[Message]) -> Vec<(&str, Value)> {
+    let mut topics: Vec<(&str, Vec<Value>)> = Vec::new();
+    for message in batch {
+        let record = json!({ "key": message.key, "value": message.payload });
+        match topics.iter_mut().find(|(topic, _)| *topic == message.topic) {
+            Some((_, records)) => records.push(record),
+            None => topics.push((&message.topic, vec![record])),
+        }
+    }
+    topics.into_iter().map(|(topic, records)| (topic, json!({ "records": records }))).collect()
+}
+
+/// Connection to a NATS server.
+struct NatsConnection {
+    reader: BufReader<ReadHalf<Box<dyn XpraIo>>>,
+    writer: WriteHalf<Box<dyn XpraIo>>,
+}
+
+impl NatsConnection {
+    /// Connect to the server at `url` and log in, over TLS if `tls` is
+    /// given or the server requires it.
+    async fn connect(url: &str, tls: Option<&PublisherTls>) -> Result<Self> {
+        let (tls_url, address) = match url.strip_prefix("tls://") {
+            Some(address) => (true, address),
+            None => (false, url.strip_prefix("nats://").unwrap_or(url)),
+        };
+        let address = address.trim_end_matches('/');
+        let (host, address) = match address.rsplit_once(':') {
+            Some((host, port)) if port.parse::<u16>().is_ok() => (host, address.to_string()),
+            _ => (address, format!("{address}:{NATS_PORT}")),
+        };
+        let stream = TcpStream::connect(&address)
+            .await
+            .with_context(|| format!("failed to connect to the NATS server at {address}"))?;
+        // The server introduces itself in plain text, TLS starting after
+        let mut plain = BufReader::new(stream);
+        let info = read_line(&mut plain).await?;
+        let Some(info) = info.strip_prefix("INFO") else {
+            anyhow::bail!("{address} is no NATS server: {info}");
+        };
+        let info: Value = serde_json::from_str(info.trim()).unwrap_or_default();
+        let io: Box<dyn XpraIo> = match tls {
+            Some(tls) => {
+                if !plain.buffer().is_empty() {
+                    anyhow::bail!("NATS server at {address} sent more than INFO before TLS");
+                }
+                let tls = tls_connector(tls)?.connect(xpra_tls::server_name(host)?, plain.into_inner()).await;
+                Box::new(tls.with_context(|| format!("TLS to the NATS server at {address} failed"))?)
+            }
+            None if tls_url || info["tls_required"] == true => {
+                anyhow::bail!("NATS server at {address} requires TLS, which needs publisher.tls")
+            }
+            None => Box::new(plain),
+        };
+        let (read, writer) = tokio::io::split(io);
+        let mut connection = Self { reader: BufReader::new(read), writer };
+
+        let mut options = json!({ "verbose": false, "pedantic": false, "name": "sshx", "lang": "rust" });
+        if let Ok(token) = std::env::var("NATS_TOKEN") {
+            options["auth_token"] = Value::from(token);
+        } else if let (Ok(user), Ok(password)) = (std::env::var("NATS_USER"), std::env::var("NATS_PASSWORD")) {
+            options["user"] = Value::from(user);
+            options["pass"] = Value::from(password);
+        }
+        connection.writer.write_all(format!("CONNECT {options}\r\n").as_bytes()).await?;
+        // Login errors are answered to the first PING
+        connection.publish(&[]).await.context("NATS server refused the connection")?;
+        Ok(connection)
+    }
+
+    /// Publish `batch` and wait for the server to have processed it.
+    async fn publish(&mut self, batch: &[Message]) -> Result<()> {
+        let mut data = Vec::new();
+        for message in batch {
+            let payload = serde_json::to_vec(&message.payload)?;
+            data.extend_from_slice(format!("PUB {} {}\r\n", message.topic, payload.len()).as_bytes());
+            data.extend_from_slice(&payload);
+            data.extend_from_slice(b"\r\n");
+        }
+        data.extend_from_slice(b"PING\r\n");
+        self.writer.write_all(&data).await?;
+        loop {
+            let line = self.read_line().await?;
+            match line.as_str() {
+                "PONG" => return Ok(()),
+                "PING" => self.writer.write_all(b"PONG\r\n").await?,
+                line if line.starts_with("-ERR") => anyhow::bail!("NATS server answered {line}"),
+                // INFO updates and +OK
+                _ => {}
+            }
+        }
+    }
+
+    async fn read_line(&mut self) -> Result<String> {
+        read_line(&mut self.reader).await
+    }
+}
+
+async fn read_line<R: AsyncRead + Unpin>(reader: &mut BufReader<R>) -> Result<String> {
+    let mut line = String::new();
+    if reader.read_line(&mut line).await? == 0 {
+        anyhow::bail!("NATS server closed the connection");
+    }
+    Ok(line.trim_end().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::xpra_labels::Labels;
+    use crate::xpra_logger::SessionEventType;
+
+    fn message(topic: &str, key: &str) -> Message {
+        Message { topic: topic.to_string(), key: key.to_string(), payload: json!({ "key": key }) }
+    }
+
+    fn nats_config(url: String) -> PublisherConfig {
+        serde_json::from_value(json!({ "broker": { "type": "nats", "url": url } })).unwrap()
+    }
+
+    #[test]
+    fn test_queue_keeps_messages_dropped_while_publishing() {
+        let mut queue = Queue::default();
+        for key in 0..MAX_PENDING {
+            queue.push(message("a", &key.to_string()));
+        }
+        let (start, batch) = queue.batch();
+        assert_eq!((start, batch.len()), (0, MAX_BATCH));
+        // Three messages are dropped for new ones while the batch is out
+        for key in 0..3 {
+            assert_eq!(queue.push(message("b", &key.to_string())), MAX_PENDING);
+        }
+        queue.remove(start, batch.len());
+        assert_eq!(queue.front, MAX_BATCH as u64);
+        assert_eq!(queue.messages.len(), MAX_PENDING - MAX_BATCH + 3);
+        assert_eq!(queue.messages[0].key, MAX_BATCH.to_string());
+    }
+
+    #[test]
+    fn test_topics_and_batches() {
+        let config: PublisherConfig =
+            serde_json::from_str(r#"{"broker": {"type": "kafka", "rest_url": "http://kafka-rest:8082"}}"#).unwrap();
+        assert_eq!(config.topic("worker1.example.com/ml", MessageKind::Alert), "sshx.worker1_example_com_ml.alerts");
+        assert_eq!((config.batch_size, config.metrics_interval), (100, 60));
+
+        let event = SessionEvent {
+            timestamp: Utc::now(),
+            seq: Some(5),
+            event_type: SessionEventType::Created,
+            session_id: "s1".to_string(),
+            user: "alice".to_string(),
+            display: 10,
+            labels: Labels::new(),
+            state: None,
+            reason: None,
+            crash_bundle: None,
+            name: None,
+            description: None,
+            parent: None,
+            device: None,
+            traffic: None,
+            host: None,
+        };
+        let published = config.event_message(&event, "w1").unwrap();
+        assert_eq!((published.topic.as_str(), published.key.as_str()), ("sshx.w1.session_events", "w1:5"));
+        assert_eq!(published.payload["user"], "alice");
+
+        let batch = [message("a", "1"), message("b", "2"), message("a", "3")];
+        let batches = kafka_batches(&batch);
+        assert_eq!(batches.len(), 2);
+        assert_eq!(batches[0].0, "a");
+        assert_eq!(batches[0].1["records"][1]["key"], "3");
+        assert_eq!(batches[1].1["records"][0]["value"]["key"], "2");
+    }
+
+    #[tokio::test]
+    async fn test_nats_publish() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("nats://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (read, mut write) = stream.into_split();
+            let mut read = BufReader::new(read);
+            write.write_all(b"INFO {\"server_id\":\"test\"}\r\n").await.unwrap();
+            let mut received = Vec::new();
+            let mut line = String::new();
+            while read.read_line(&mut line).await.unwrap() > 0 {
+                if line == "PING\r\n" {
+                    write.write_all(b"PONG\r\n").await.unwrap();
+                }
+                received.push(std::mem::take(&mut line));
+            }
+            received
+        });
+
+        let queue = Arc::new(Mutex::new(Queue::default()));
+        let mut publisher = Publisher::new(&nats_config(url), queue.clone()).unwrap();
+        queue.lock().unwrap().push(message("sshx.w1.session_events", "w1:1"));
+        queue.lock().unwrap().push(message("sshx.w1.alerts", "w1"));
+        publisher.flush().await;
+        assert!(queue.lock().unwrap().messages.is_empty());
+        drop(publisher);
+
+        let received = server.await.unwrap();
+        assert!(received[0].starts_with("CONNECT {"));
+        assert_eq!(
+            received[1..],
+            [
+                "PING\r\n",
+                "PUB sshx.w1.session_events 14\r\n",
+                "{\"key\":\"w1:1\"}\r\n",
+                "PUB sshx.w1.alerts 12\r\n",
+                "{\"key\":\"w1\"}\r\n",
+                "PING\r\n",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_nats_requires_tls() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream.write_all(b"INFO {\"server_id\":\"test\",\"tls_required\":true}\r\n").await.unwrap();
+            let _ = stream.read(&mut [0; 1]).await;
+        });
+        let error = NatsConnection::connect(&format!("nats://{address}"), None).await.err().unwrap();
+        assert!(error.to_string().contains("requires TLS"), "{error}");
+
+        let tls = PublisherTls { ca_cert: "/etc/sshx/broker-ca.pem".into(), client_cert: Some("/x.pem".into()), client_key: None };
+        assert!(tls.identity().is_err());
+    }
+}
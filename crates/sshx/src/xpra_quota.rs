@@ -0,0 +1,425 @@
+//! Time budgets limiting how long users keep desktops running.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use anyhow::Result;
+use chrono::{DateTime, Datelike, Duration, NaiveTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+use tokio::sync::Mutex;
+use tracing::{debug, warn};
+
+use crate::xpra_log_analyzer::read_log_lines;
+use crate::xpra_log_schema::LogSchema;
+use crate::xpra_logger::{SessionEvent, SessionEventType};
+
+/// Desktop hour budget for a user or group.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TimeBudget {
+    /// Maximum desktop hours per UTC day
+    #[serde(default)]
+    pub daily_hours: Option<f64>,
+
+    /// Maximum desktop hours per ISO week (starting Monday, UTC)
+    #[serde(default)]
+    pub weekly_hours: Option<f64>,
+}
+
+/// Desktop time used by one user in the current day and week.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserUsage {
+    /// User name
+    pub user: String,
+    /// Hours used today
+    pub daily_hours: f64,
+    /// Hours used this week
+    pub weekly_hours: f64,
+    /// Budget applying to the user, if any
+    pub budget: Option<TimeBudget>,
+}
+
+/// Outcome of checking a user's budget before starting a session.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BudgetCheck {
+    /// No budget applies or the user is within it.
+    Within,
+    /// The budget is exhausted, with a message for the user.
+    Exhausted(String),
+}
+
+#[derive(Debug, Default)]
+struct UserAccount {
+    /// Completed sessions as (start, end), pruned to the current week.
+    completed: Vec<(DateTime<Utc>, DateTime<Utc>)>,
+}
+
+/// The sessions of a line of the metrics log, for when they were last seen.
+#[derive(Debug, Deserialize)]
+struct MetricsLine {
+    timestamp: DateTime<Utc>,
+    #[serde(default)]
+    sessions: Vec<MetricsSession>,
+}
+
+#[derive(Debug, Deserialize)]
+struct MetricsSession {
+    session_id: String,
+}
+
+#[derive(Debug, Default)]
+struct Ledger {
+    accounts: HashMap<String, UserAccount>,
+    open: HashMap<String, (String, DateTime<Utc>)>,
+}
+
+/// Per-user accounting of desktop time, fed by the session event stream.
+#[derive(Debug, Clone, Default)]
+pub struct UsageAccounting {
+    ledger: Arc<Mutex<Ledger>>,
+    budgets: Arc<HashMap<String, TimeBudget>>,
+}
+
+impl UsageAccounting {
+    /// Accounting with no usage and no budgets.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Check usage against `budgets`, keyed by user, `@group` or `*`.
+    pub fn with_budgets(mut self, budgets: HashMap<String, TimeBudget>) -> Self {
+        self.budgets = Arc::new(budgets);
+        self
+    }
+
+    /// Create an accounting layer seeded from this week's history, in the
+    /// current history log and the rotated ones.
+    ///
+    /// Sessions left open in the history belong to a previous process and
+    /// are no longer running. They are charged until the metrics log last
+    /// listed them, or else until the last entry of either log, the last
+    /// time that process was known to run.
+    pub fn from_history(log_dir: &Path) -> Self {
+        let mut ledger = Ledger::default();
+        let mut last_alive: Option<DateTime<Utc>> = None;
+        // Oldest first, so sessions spanning a rotation are closed in order
+        read_logs(log_dir, "history.log", |line| match LogSchema::History.parse::<SessionEvent>(line) {
+            Ok(event) => {
+                last_alive = last_alive.max(Some(event.timestamp));
+                apply_event(&mut ledger, &event);
+            }
+            Err(e) => debug!("Skipping unparseable history entry: {}", e),
+        });
+
+        if !ledger.open.is_empty() {
+            let mut last_seen: HashMap<String, DateTime<Utc>> = HashMap::new();
+            read_logs(log_dir, "metrics.log", |line| {
+                let Ok(entry) = LogSchema::Metrics.parse::<MetricsLine>(line) else {
+                    return;
+                };
+                last_alive = last_alive.max(Some(entry.timestamp));
+                for session in entry.sessions.into_iter().filter(|s| ledger.open.contains_key(&s.session_id)) {
+                    last_seen.insert(session.session_id, entry.timestamp);
+                }
+            });
+            for (session_id, (user, start)) in ledger.open.drain() {
+                let end = last_seen.get(&session_id).copied().or(last_alive).unwrap_or(start).max(start);
+                ledger.accounts.entry(user).or_default().completed.push((start, end));
+            }
+        }
+
+        let week_start = week_start(Utc::now());
+        for account in ledger.accounts.values_mut() {
+            account.completed.retain(|(_, end)| *end >= week_start);
+        }
+
+        Self {
+            ledger: Arc::new(Mutex::new(ledger)),
+            budgets: Arc::default(),
+        }
+    }
+
+    /// Record a session event.
+    pub async fn record(&self, event: &SessionEvent) {
+        let mut ledger = self.ledger.lock().await;
+        apply_event(&mut ledger, event);
+    }
+
+    /// Desktop hours used by `user` in the current day and week.
+    pub async fn usage(&self, user: &str) -> (f64, f64) {
+        let now = Utc::now();
+        let ledger = self.ledger.lock().await;
+        usage_of(&ledger, user, now)
+    }
+
+    /// Usage of every user with a running session or desktop time this
+    /// week, by user name.
+    pub async fn all_usage(&self) -> Vec<UserUsage> {
+        let now = Utc::now();
+        let users: Vec<(String, (f64, f64))> = {
+            let ledger = self.ledger.lock().await;
+            let running: Vec<&String> = ledger.open.values().map(|(user, _)| user).collect();
+            let mut users: Vec<&String> = ledger.accounts.keys().chain(running.iter().copied()).collect();
+            users.sort();
+            users.dedup();
+            users
+                .into_iter()
+                .map(|user| (user.clone(), usage_of(&ledger, user, now)))
+                .filter(|(user, (_, weekly))| *weekly > 0.0 || running.contains(&user))
+                .collect()
+        };
+
+        let mut usage = Vec::with_capacity(users.len());
+        for (user, (daily_hours, weekly_hours)) in users {
+            let budget = entry_for(&self.budgets, &user).await.cloned();
+            usage.push(UserUsage { user, daily_hours, weekly_hours, budget });
+        }
+        usage
+    }
+
+    /// Check whether `user` still has budget for a new session.
+    pub async fn check(&self, user: &str) -> BudgetCheck {
+        let Some(budget) = entry_for(&self.budgets, user).await.cloned() else {
+            return BudgetCheck::Within;
+        };
+        let (daily, weekly) = self.usage(user).await;
+
+        if let Some(limit) = budget.daily_hours {
+            if daily >= limit {
+                return BudgetCheck::Exhausted(format!(
+                    "Daily desktop budget of {limit:.1}h exhausted ({daily:.1}h used)"
+                ));
+            }
+        }
+        if let Some(limit) = budget.weekly_hours {
+            if weekly >= limit {
+                return BudgetCheck::Exhausted(format!(
+                    "Weekly desktop budget of {limit:.1}h exhausted ({weekly:.1}h used)"
+                ));
+            }
+        }
+        BudgetCheck::Within
+    }
+}
+
+/// Pass each line of the logs named `name` in `log_dir` to `f`, oldest first.
+fn read_logs(log_dir: &Path, name: &str, f: impl FnMut(&str)) {
+    match read_log_lines(log_dir, name) {
+        Ok(lines) => lines.iter().map(String::as_str).for_each(f),
+        Err(e) => warn!("Failed to read {} for usage accounting: {:#}", name, e),
+    }
+}
+
+fn apply_event(ledger: &mut Ledger, event: &SessionEvent) {
+    match event.event_type {
+        SessionEventType::Created => {
+            ledger.open.insert(event.session_id.clone(), (event.user.clone(), event.timestamp));
+        }
+        SessionEventType::Terminated | SessionEventType::IdleTimeout | SessionEventType::Failed => {
+            if let Some((user, start)) = ledger.open.remove(&event.session_id) {
+                let account = ledger.accounts.entry(user).or_default();
+                let week_start = week_start(event.timestamp);
+                account.completed.retain(|(_, end)| *end >= week_start);
+                account.completed.push((start, event.timestamp));
+            }
+        }
+        SessionEventType::RateLimited
+        | SessionEventType::PolicyDenied
+        | SessionEventType::Hibernated
+        | SessionEventType::Resumed
+        | SessionEventType::StateChanged
+        | SessionEventType::Renamed
+        | SessionEventType::DeviceAttached
+        | SessionEventType::DeviceDetached
+        | SessionEventType::RecordingAcknowledged => {}
+    }
+}
+
+fn usage_of(ledger: &Ledger, user: &str, now: DateTime<Utc>) -> (f64, f64) {
+    let day_start = day_start(now);
+    let week_start = week_start(now);
+    let open = ledger.open.values()
+        .filter(|(u, _)| u == user)
+        .map(|(_, start)| (*start, now));
+    let intervals: Vec<_> = ledger.accounts.get(user)
+        .map(|a| a.completed.clone())
+        .unwrap_or_default()
+        .into_iter()
+        .chain(open)
+        .collect();
+
+    (overlap_hours(&intervals, day_start, now), overlap_hours(&intervals, week_start, now))
+}
+
+fn overlap_hours(
+    intervals: &[(DateTime<Utc>, DateTime<Utc>)],
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> f64 {
+    let total: Duration = intervals.iter()
+        .map(|(start, end)| {
+            let start = (*start).max(from);
+            let end = (*end).min(to);
+            if end > start { end - start } else { Duration::zero() }
+        })
+        .fold(Duration::zero(), |acc, d| acc + d);
+    total.num_seconds() as f64 / 3600.0
+}
+
+fn day_start(now: DateTime<Utc>) -> DateTime<Utc> {
+    now.date_naive().and_time(NaiveTime::MIN).and_utc()
+}
+
+fn week_start(now: DateTime<Utc>) -> DateTime<Utc> {
+    day_start(now) - Duration::days(now.weekday().num_days_from_monday() as i64)
+}
+
+/// Keys a per-user config entry may apply to `user` under, most specific
+/// first: the user name, `@group` for each of the user's groups, then `*`.
+/// Groups are only looked up if `groups` is set, i.e. some entry names one.
+pub(crate) async fn user_keys(user: &str, groups: bool) -> Vec<String> {
+    let mut keys = vec![user.to_string()];
+    if groups {
+        match user_groups(user).await {
+            Ok(groups) => keys.extend(groups.into_iter().map(|group| format!("@{group}"))),
+            Err(e) => warn!(user, "Failed to resolve groups of user: {}", e),
+        }
+    }
+    keys.push("*".to_string());
+    keys
+}
+
+/// Entry of `entries`, keyed by user name, `@group` or `*`, that applies to
+/// `user`.
+pub(crate) async fn entry_for<'a, T>(entries: &'a HashMap<String, T>, user: &str) -> Option<&'a T> {
+    if entries.is_empty() {
+        return None;
+    }
+    let groups = entries.keys().any(|key| key.starts_with('@'));
+    user_keys(user, groups).await.iter().find_map(|key| entries.get(key))
+}
+
+/// Names of the groups `user` is in.
+pub(crate) async fn user_groups(user: &str) -> Result<Vec<String>> {
+    let output = Command::new("id").args(["-Gn", "--", user]).output().await?;
+    if !output.status.success() {
+        anyhow::bail!("id exited with {}", output.status);
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .map(String::from)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::xpra_labels::Labels;
+
+    fn event(event_type: SessionEventType, id: &str, at: DateTime<Utc>) -> SessionEvent {
+        SessionEvent {
+            timestamp: at,
+            seq: None,
+            event_type,
+            session_id: id.to_string(),
+            user: "alice".to_string(),
+            display: 100,
+            labels: Labels::new(),
+            state: None,
+            reason: None,
+            crash_bundle: None,
+            name: None,
+            description: None,
+            parent: None,
+            device: None,
+            traffic: None,
+            host: None,
+        }
+    }
+
+    #[test]
+    fn test_usage_clamped_to_day_and_week() {
+        // Wednesday noon
+        let now = Utc.with_ymd_and_hms(2024, 5, 15, 12, 0, 0).unwrap();
+        let mut ledger = Ledger::default();
+
+        // Two hours on Monday
+        apply_event(&mut ledger, &event(SessionEventType::Created, "a",
+            Utc.with_ymd_and_hms(2024, 5, 13, 9, 0, 0).unwrap()));
+        apply_event(&mut ledger, &event(SessionEventType::Terminated, "a",
+            Utc.with_ymd_and_hms(2024, 5, 13, 11, 0, 0).unwrap()));
+
+        // Still running since 10:00 today
+        apply_event(&mut ledger, &event(SessionEventType::Created, "b",
+            Utc.with_ymd_and_hms(2024, 5, 15, 10, 0, 0).unwrap()));
+
+        let (daily, weekly) = usage_of(&ledger, "alice", now);
+        assert_eq!(daily, 2.0);
+        assert_eq!(weekly, 4.0);
+        assert_eq!(usage_of(&ledger, "bob", now), (0.0, 0.0));
+    }
+
+    #[tokio::test]
+    async fn test_from_history_reads_rotated_logs() {
+        let dir = std::env::temp_dir().join(format!("sshx-quota-history-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let line = |event: &SessionEvent| serde_json::to_string(event).unwrap();
+        let end = Utc::now() - Duration::seconds(1);
+        let start = end - Duration::seconds(36);
+
+        // A session started before the last rotation and ended after it
+        std::fs::write(
+            dir.join("history.log.20240501_120000"),
+            line(&event(SessionEventType::Created, "a", start)),
+        ).unwrap();
+        std::fs::write(
+            dir.join("history.log"),
+            line(&event(SessionEventType::Terminated, "a", end)),
+        ).unwrap();
+
+        let usage = UsageAccounting::from_history(&dir);
+        let (_, weekly) = usage.usage("alice").await;
+        assert_eq!(weekly, 0.01);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_from_history_charges_sessions_open_at_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let line = |event: &SessionEvent| serde_json::to_string(event).unwrap() + "\n";
+        let start = Utc::now() - Duration::seconds(600);
+        let seen = |at: DateTime<Utc>, ids: &[&str]| {
+            let sessions: Vec<_> = ids.iter().map(|id| serde_json::json!({"session_id": id, "user": "alice"})).collect();
+            serde_json::json!({"timestamp": at, "sessions": sessions}).to_string() + "\n"
+        };
+
+        // "a" was last listed 36s in and "b" 72s in; "c" never was, and the
+        // last sign of the previous process is the metrics line 72s in
+        let created = ["a", "b", "c"].map(|id| line(&event(SessionEventType::Created, id, start)));
+        std::fs::write(dir.path().join("history.log"), created.concat()).unwrap();
+        std::fs::write(
+            dir.path().join("metrics.log"),
+            seen(start + Duration::seconds(36), &["a", "b"]) + &seen(start + Duration::seconds(72), &["b"]),
+        ).unwrap();
+
+        let usage = UsageAccounting::from_history(dir.path());
+        let (_, weekly) = usage.usage("alice").await;
+        assert_eq!(weekly, 0.05);
+        let all = usage.all_usage().await;
+        assert_eq!(all.len(), 1);
+        assert_eq!(all[0].weekly_hours, 0.05);
+    }
+
+    #[tokio::test]
+    async fn test_check_uses_own_budgets() {
+        let exhausted = TimeBudget { daily_hours: Some(0.0), weekly_hours: None };
+        let usage = UsageAccounting::new()
+            .with_budgets(HashMap::from([("alice".to_string(), exhausted)]));
+        assert!(matches!(usage.check("alice").await, BudgetCheck::Exhausted(_)));
+        assert_eq!(usage.check("bob").await, BudgetCheck::Within);
+        assert_eq!(UsageAccounting::new().check("alice").await, BudgetCheck::Within);
+    }
+}
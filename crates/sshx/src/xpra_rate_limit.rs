@@ -0,0 +1,185 @@
+//! Token bucket rate limits on starting sessions, per user and host-wide.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tracing::debug;
+
+
+/// Classic token bucket refilled continuously at a fixed rate.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    refill_per_sec: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Create a full bucket holding `burst` tokens, refilled at `per_minute`.
+    pub fn new(per_minute: u32, burst: u32) -> Self {
+        let capacity = burst.max(1) as f64;
+        Self {
+            capacity,
+            tokens: capacity,
+            refill_per_sec: per_minute as f64 / 60.0,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Take one token if available.
+    pub fn try_acquire(&mut self) -> bool {
+        self.try_acquire_at(Instant::now())
+    }
+
+    fn try_acquire_at(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        self.last_refill = now;
+    }
+
+    /// Time until the next token becomes available.
+    pub fn retry_after(&self) -> Duration {
+        if self.tokens >= 1.0 || self.refill_per_sec <= 0.0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64((1.0 - self.tokens) / self.refill_per_sec)
+    }
+
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.capacity
+    }
+}
+
+/// Which limit rejected a session start.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimitScope {
+    /// The per-user bucket was empty.
+    User,
+    /// The host-wide bucket was empty.
+    Global,
+}
+
+/// Per-user and global rate limiting for session creation.
+#[derive(Debug, Clone)]
+pub struct SessionRateLimiter {
+    global: Option<Arc<Mutex<TokenBucket>>>,
+    users: Arc<Mutex<HashMap<String, TokenBucket>>>,
+    user_rate: u32,
+    user_burst: u32,
+}
+
+impl SessionRateLimiter {
+    /// Create a limiter, where a rate of 0 disables the corresponding limit.
+    pub fn new(user_rate: u32, user_burst: u32, global_rate: u32, global_burst: u32) -> Self {
+        let global = (global_rate > 0)
+            .then(|| Arc::new(Mutex::new(TokenBucket::new(global_rate, global_burst))));
+        Self {
+            global,
+            users: Arc::new(Mutex::new(HashMap::new())),
+            user_rate,
+            user_burst,
+        }
+    }
+
+    /// Check whether `user` may start a new session right now.
+    ///
+    /// The per-user bucket is checked first so that a single noisy user does
+    /// not drain the global bucket for everybody else.
+    pub async fn check(&self, user: &str) -> Result<(), RateLimitScope> {
+        if self.user_rate > 0 {
+            let mut users = self.users.lock().await;
+            let now = Instant::now();
+            // Buckets that have refilled completely carry no state worth keeping.
+            users.retain(|name, bucket| name == user || !bucket.is_full(now));
+            let bucket = users
+                .entry(user.to_string())
+                .or_insert_with(|| TokenBucket::new(self.user_rate, self.user_burst));
+            if !bucket.try_acquire_at(now) {
+                debug!(user, retry_after = ?bucket.retry_after(), "User session rate limited");
+                return Err(RateLimitScope::User);
+            }
+        }
+
+        if let Some(global) = &self.global {
+            let mut bucket = global.lock().await;
+            if !bucket.try_acquire() {
+                debug!(retry_after = ?bucket.retry_after(), "Global session rate limited");
+                return Err(RateLimitScope::Global);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Time until a session start refused for `scope` may succeed.
+    pub async fn retry_after(&self, user: &str, scope: RateLimitScope) -> Duration {
+        match scope {
+            RateLimitScope::User => self
+                .users
+                .lock()
+                .await
+                .get(user)
+                .map_or(Duration::ZERO, TokenBucket::retry_after),
+            RateLimitScope::Global => match &self.global {
+                Some(global) => global.lock().await.retry_after(),
+                None => Duration::ZERO,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_burst_then_refill() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(60, 3);
+        bucket.last_refill = start;
+
+        assert!(bucket.try_acquire_at(start));
+        assert!(bucket.try_acquire_at(start));
+        assert!(bucket.try_acquire_at(start));
+        assert!(!bucket.try_acquire_at(start));
+
+        // One token per second at 60/min
+        assert!(bucket.try_acquire_at(start + Duration::from_secs(1)));
+        assert!(!bucket.try_acquire_at(start + Duration::from_secs(1)));
+    }
+
+    #[tokio::test]
+    async fn test_user_limit_is_per_user() {
+        let limiter = SessionRateLimiter::new(1, 2, 0, 0);
+
+        assert!(limiter.check("alice").await.is_ok());
+        assert!(limiter.check("alice").await.is_ok());
+        assert_eq!(limiter.check("alice").await, Err(RateLimitScope::User));
+        assert!(limiter.check("bob").await.is_ok());
+
+        let retry_after = limiter.retry_after("alice", RateLimitScope::User).await;
+        assert!(retry_after > Duration::from_secs(50) && retry_after <= Duration::from_secs(60));
+    }
+
+    #[tokio::test]
+    async fn test_global_limit() {
+        let limiter = SessionRateLimiter::new(0, 0, 1, 2);
+
+        assert!(limiter.check("alice").await.is_ok());
+        assert!(limiter.check("bob").await.is_ok());
+        assert_eq!(limiter.check("carol").await, Err(RateLimitScope::Global));
+    }
+}
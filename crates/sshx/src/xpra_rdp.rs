@@ -0,0 +1,135 @@
+//! Windows desktops shown through an RDP client, a prototype for mixed
+//! fleets.
+//!
+//! The display of an RDP desktop runs xfreerdp in full screen, connected to
+//! a Windows host, instead of a window manager, and xpra streams it like any
+//! other desktop. Windows asks for the password on its own logon screen, so
+//! no credentials pass through the host; the Windows host has to accept
+//! connections without Network Level Authentication for that.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::xpra::DisplayOptions;
+use crate::xpra_backend::{self, DesktopBackend};
+use crate::xpra_command::ProgramAllowlist;
+
+/// Windows host desktops are started for.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RdpConfig {
+    /// Address of the Windows host, `host` or `host:port`
+    pub target: String,
+
+    /// Domain filled in on the logon screen
+    #[serde(default)]
+    pub domain: Option<String>,
+
+    /// Accept the host's certificate without verifying it, e.g. a
+    /// self-signed one
+    #[serde(default)]
+    pub ignore_certificate: bool,
+
+    /// RDP client started on the display
+    #[serde(default = "default_client")]
+    pub client: String,
+}
+
+fn default_client() -> String { "xfreerdp".to_string() }
+
+/// Desktops of a Windows host.
+#[derive(Debug, Clone)]
+pub struct RdpBackend {
+    config: RdpConfig,
+}
+
+impl RdpBackend {
+    /// Backend connecting to the host of `config`.
+    pub fn new(config: RdpConfig) -> Self {
+        Self { config }
+    }
+
+    /// Arguments of the RDP client.
+    fn args(&self, options: &DisplayOptions) -> Vec<String> {
+        let mut args = vec![
+            format!("/v:{}", self.config.target),
+            // Fill the display and follow its size as clients resize it
+            "/f".to_string(),
+            "/dynamic-resolution".to_string(),
+            // Leave the login to the Windows logon screen
+            "/sec:tls".to_string(),
+            "-authentication".to_string(),
+            "+clipboard".to_string(),
+        ];
+        if let Some(domain) = &self.config.domain {
+            args.push(format!("/d:{}", domain));
+        }
+        if self.config.ignore_certificate {
+            args.push("/cert:ignore".to_string());
+        }
+        // Played on the display's PulseAudio, which xpra forwards
+        if options.audio {
+            args.push("/sound:sys:pulse".to_string());
+        }
+        args
+    }
+}
+
+impl DesktopBackend for RdpBackend {
+    fn name(&self) -> &'static str {
+        "rdp"
+    }
+
+    fn desktop_program(&self, _wm: &str, options: &DisplayOptions, _allowlist: &ProgramAllowlist) -> Result<String> {
+        if options.app.is_some() {
+            anyhow::bail!("RDP desktops cannot start apps");
+        }
+        xpra_backend::check_target(&self.config.target, "RDP")?;
+        let valid_domain = |domain: &str| {
+            !domain.is_empty() && domain.chars().all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+        };
+        if let Some(domain) = self.config.domain.as_deref().filter(|domain| !valid_domain(domain)) {
+            anyhow::bail!("RDP domain {:?} is not a domain name", domain);
+        }
+        Ok(xpra_backend::command_line(&self.config.client, &self.args(options)))
+    }
+
+    fn ends_with_program(&self) -> bool {
+        true
+    }
+
+    fn target(&self) -> Option<&str> {
+        Some(&self.config.target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_desktop_program() {
+        let config = RdpConfig {
+            target: "win-1.corp:3389".to_string(),
+            domain: Some("CORP".to_string()),
+            ignore_certificate: true,
+            client: default_client(),
+        };
+        let allowlist = ProgramAllowlist::default();
+        let backend = RdpBackend::new(config.clone());
+        let options = DisplayOptions { audio: true, ..DisplayOptions::default() };
+        let program = backend.desktop_program("gnome-flashback", &options, &allowlist).unwrap();
+        assert!(program.starts_with("xfreerdp /v:win-1.corp:3389 /f "));
+        assert!(program.ends_with(" /d:CORP /cert:ignore /sound:sys:pulse"));
+        assert!(backend.ends_with_program());
+
+        let refused = |config: RdpConfig, options: &DisplayOptions| {
+            RdpBackend::new(config).desktop_program("", options, &allowlist).unwrap_err().to_string()
+        };
+        let app = DisplayOptions { app: Some("firefox".to_string()), ..DisplayOptions::default() };
+        assert!(refused(config.clone(), &app).contains("cannot start apps"));
+        let target = RdpConfig { target: "win-1; rm -rf ~".to_string(), ..config.clone() };
+        assert!(refused(target, &options).contains("not a host"));
+        let domain = RdpConfig { domain: Some("CORP /p:x".to_string()), ..config };
+        assert!(refused(domain, &options).contains("not a domain"));
+    }
+}
@@ -0,0 +1,118 @@
+//! Structured reasons for refusing to start a desktop session, sent to the
+//! server in place of a plain error so the web client can tell the user what
+//! happened and when to try again.
+
+use std::fmt;
+use std::time::Duration;
+use sshx_core::proto::ShellRejected;
+use sshx_core::Sid;
+
+/// Machine-readable reason a session was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectionCode {
+    /// The host is in maintenance mode
+    Maintenance,
+    /// Too many sessions were started recently
+    RateLimited,
+    /// The user's desktop hour budget is used up
+    TimeBudget,
+    /// The user has as many sessions as allowed
+    MaxSessions,
+    /// No host has a free session slot
+    Capacity,
+    /// The admission policy refused the session
+    Policy,
+    /// The desktop to attach to is not running or not the user's
+    UnknownSession,
+    /// The client asked for parameters the host does not allow
+    InvalidParams,
+    /// The machine the desktop would show already has a desktop
+    TargetInUse,
+}
+
+impl RejectionCode {
+    /// Name of the code, as sent to clients
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RejectionCode::Maintenance => "maintenance",
+            RejectionCode::RateLimited => "rate_limited",
+            RejectionCode::TimeBudget => "time_budget",
+            RejectionCode::MaxSessions => "max_sessions",
+            RejectionCode::Capacity => "capacity",
+            RejectionCode::Policy => "policy",
+            RejectionCode::UnknownSession => "unknown_session",
+            RejectionCode::InvalidParams => "invalid_params",
+            RejectionCode::TargetInUse => "target_in_use",
+        }
+    }
+}
+
+/// Error for a session start refused by a limit or policy, as opposed to
+/// one that failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionRejection {
+    /// Why the session was refused
+    pub code: RejectionCode,
+    /// Explanation shown to the user
+    pub message: String,
+    /// When a retry may succeed, if known
+    pub retry_after: Option<Duration>,
+}
+
+impl SessionRejection {
+    /// A rejection with no retry time
+    pub fn new(code: RejectionCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            retry_after: None,
+        }
+    }
+
+    /// Sets when a retry may succeed
+    pub fn retry_after(mut self, retry_after: Duration) -> Self {
+        self.retry_after = Some(retry_after);
+        self
+    }
+
+    /// Protocol message refusing shell `id`.
+    pub fn to_proto(&self, id: Sid) -> ShellRejected {
+        ShellRejected {
+            id: id.0,
+            code: self.code.as_str().to_string(),
+            message: self.message.clone(),
+            // Round up so clients never retry too early
+            retry_after: self
+                .retry_after
+                .map(|d| d.as_secs() as u32 + (d.subsec_nanos() > 0) as u32),
+        }
+    }
+}
+
+impl fmt::Display for SessionRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for SessionRejection {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_proto() {
+        let rejection = SessionRejection::new(RejectionCode::RateLimited, "Slow down")
+            .retry_after(Duration::from_millis(2500));
+        let error = anyhow::Error::new(rejection);
+        let rejection = error.downcast_ref::<SessionRejection>().unwrap();
+
+        let proto = rejection.to_proto(Sid(3));
+        assert_eq!(proto.id, 3);
+        assert_eq!(proto.code, "rate_limited");
+        assert_eq!(proto.message, "Slow down");
+        assert_eq!(proto.retry_after, Some(3));
+        assert_eq!(error.to_string(), "Slow down");
+    }
+}
@@ -1,131 +1,1419 @@
-use std::pin::Pin;
+//! Forwarding between a session's encrypted channel and xpra's WebSocket.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use anyhow::Result;
+use bytes::Bytes;
+use chrono::Utc;
 use futures_util::{SinkExt, StreamExt};
-use tokio::net::TcpStream;
-use tokio::sync::mpsc;
-use tokio_tungstenite::{connect_async, WebSocketStream};
-use tracing::{debug, error, info};
-
-use crate::encrypt::Encrypt;
-use crate::xpra::XpraDisplay;
-use sshx_core::proto::{client_update::ClientMessage, TerminalData};
+use tokio::sync::{mpsc, oneshot, Notify};
+use tokio::task::JoinHandle;
+use tokio::time;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{debug, error, info, warn};
+
+use crate::encrypt::{Encrypt, KeySchedule};
+use crate::runner::ShellData;
+use crate::xpra::{DisplayOptions, XpraDisplay};
+use crate::xpra_backend::BackendConfig;
+use crate::xpra_auth::XpraLogin;
+use crate::xpra_children::{self, ChildProgram, ChildSupervisor};
+use crate::xpra_clipboard::{ClipboardDirection, ClipboardFilter};
+use crate::xpra_config::XpraConfig;
+use crate::xpra_context::XpraContext;
+use crate::xpra_devices::Device;
+use crate::xpra_crash;
+use crate::xpra_handshake::{DesktopChannel, Received};
+use crate::xpra_hosts::WorkerHost;
+use crate::xpra_labels::Labels;
+use crate::xpra_logger::TerminationReason;
+use crate::xpra_monitor::SessionInfo;
+use crate::xpra_motd::MotdVars;
+use crate::xpra_policy::{load_average, AdmissionDecision, AdmissionLoad, AdmissionRequest, SessionTemplate};
+use crate::xpra_protocol::{PacketWatcher, XpraPacket};
+use crate::xpra_quota::entry_for;
+use crate::xpra_session_id::ParentShell;
+use crate::xpra_session_state::SessionState;
+use crate::xpra_smartcard::{SmartcardInput, SmartcardRelay};
+use crate::xpra_tls::{TlsClient, XpraStream};
+use sshx_core::proto::{client_update::ClientMessage, DesktopSession, SessionParams, TerminalData};
 use sshx_core::Sid;
 
+/// How often user input refreshes the session's idle timer
+const ACTIVITY_UPDATE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Time between WebSocket pings to xpra, so a quiet but healthy server still
+/// answers within the read timeout.
+const XPRA_PING_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Time between attempts to connect to xpra's WebSocket.
+const CONNECT_RETRY_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Quiet time after a client's last resize message before the display is
+/// resized, so dragging a window edge runs xrandr once.
+const RESIZE_DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// Longest name users may give a desktop session, in characters
+const MAX_NAME_LENGTH: usize = 80;
+
+/// Longest description users may give a desktop session, in characters
+const MAX_DESCRIPTION_LENGTH: usize = 500;
+
+/// Shell of a client taking over the forwarding of a running session.
+pub struct Attachment {
+    /// ID of the shell
+    pub id: Sid,
+    /// Key of the session the shell belongs to
+    pub encrypt: Encrypt,
+    /// Input to the shell
+    pub shell_rx: mpsc::Receiver<ShellData>,
+    /// Output of the shell
+    pub output_tx: mpsc::Sender<ClientMessage>,
+    /// Dropped once the session lets go of the shell
+    pub released: oneshot::Sender<()>,
+}
+
+/// Shell a session is currently forwarded to.
+struct ClientShell {
+    id: Sid,
+    /// Key of the current epoch of the stream to the client
+    key: Encrypt,
+    /// Keys of the client's input, switched as the client rotates them
+    input_keys: KeySchedule,
+    channel: DesktopChannel,
+    seq: u64,
+    shell_rx: mpsc::Receiver<ShellData>,
+    output_tx: mpsc::Sender<ClientMessage>,
+    /// Held until the session lets go of an attached shell
+    _released: Option<oneshot::Sender<()>>,
+}
+
+impl ClientShell {
+    fn new(
+        context: &XpraContext,
+        devices: &[Device],
+        id: Sid,
+        encrypt: Encrypt,
+        shell_rx: mpsc::Receiver<ShellData>,
+        output_tx: mpsc::Sender<ClientMessage>,
+    ) -> Self {
+        Self {
+            id,
+            input_keys: KeySchedule::new(encrypt.clone()),
+            key: encrypt,
+            channel: DesktopChannel::new(context.config.key_rotation(), devices),
+            seq: 0,
+            shell_rx,
+            output_tx,
+            _released: None,
+        }
+    }
+
+    fn attach(context: &XpraContext, devices: &[Device], attachment: Attachment) -> Self {
+        let mut shell = Self::new(context, devices, attachment.id, attachment.encrypt, attachment.shell_rx, attachment.output_tx);
+        shell._released = Some(attachment.released);
+        shell
+    }
+
+    /// Encrypt a frame and send it to the client, returning whether it was sent.
+    async fn send(&mut self, frame: Bytes) -> bool {
+        send_frame(&self.output_tx, &self.key, self.id, &mut self.seq, frame).await
+    }
+
+    /// Decrypt input of the client still encrypted.
+    fn decrypt(&self, msg: ShellData) -> ShellData {
+        match msg {
+            ShellData::Encrypted(offset, data) => ShellData::Data(self.input_keys.segment_bytes(0x200000000, offset, data)),
+            msg => msg,
+        }
+    }
+
+    /// Send frames in order, returning whether all were sent.
+    async fn send_all(&mut self, frames: Vec<Bytes>) -> bool {
+        for frame in frames {
+            if !self.send(frame).await {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Input of the attached shell, pending while no client is attached.
+async fn recv_input(client: &mut Option<ClientShell>) -> Option<ShellData> {
+    match client {
+        Some(client) => client.shell_rx.recv().await.map(|msg| client.decrypt(msg)),
+        None => std::future::pending().await,
+    }
+}
+
+/// Whether the user acknowledged the recording notice, once it closes.
+async fn notice_closed(notice: &mut Option<JoinHandle<bool>>) -> bool {
+    match notice {
+        Some(notice) => notice.await.unwrap_or(false),
+        None => std::future::pending().await,
+    }
+}
+
+/// Forward a session's channel to and from the xpra display until either side closes,
+/// returning why the session ended and the crash bundle collected if xpra exited
+/// with an error. When the client closes its shell the session stays running
+/// detached, until the shell of another client arrives on `attachments`.
+#[allow(clippy::too_many_arguments)]
 pub async fn xpra_task(
+    context: Arc<XpraContext>,
     id: Sid,
+    session_id: String,
     encrypt: Encrypt,
-    display: XpraDisplay,
-    mut shell_rx: mpsc::Receiver<ShellData>,
+    display: &mut XpraDisplay,
+    session: SessionInfo,
+    shell_rx: mpsc::Receiver<ShellData>,
     output_tx: mpsc::Sender<ClientMessage>,
-) -> Result<()> {
+    mut attachments: mpsc::Receiver<Attachment>,
+) -> Result<(TerminationReason, Option<PathBuf>)> {
+    let (host, number, port) = (display.host().clone(), display.display(), display.connect_port());
     info!(
-        display = display.display(),
-        port = display.websocket_port(),
+        host = host.name,
+        display = number,
+        port,
         "Starting Xpra WebSocket forwarder"
     );
 
-    // Connect to Xpra's WebSocket server
-    let ws_url = format!("ws://127.0.0.1:{}/xpra", display.websocket_port());
-    let (ws_stream, _) = connect_async(ws_url).await?;
+    // Connect to Xpra's WebSocket server on the host running the display
+    let tls = host.tls.as_ref().map(|tls| tls.client(&host.address)).transpose()?;
+    let scheme = if tls.is_some() { "wss" } else { "ws" };
+    let ws_url = format!("{}://{}:{}/xpra", scheme, display.connect_address(), port);
+    let ws_stream = connect_xpra(&ws_url, tls.as_ref(), &context.config).await?;
     
     let (mut ws_write, mut ws_read) = ws_stream.split();
-    let mut seq = 0u64;
+    // Reset whenever xpra sends something or is connected to again
+    let read_timeout = context.config.read_duration();
+    let read_deadline = || time::Instant::now() + read_timeout.unwrap_or_default();
+    let mut xpra_deadline = read_deadline();
+    // Reset whenever xpra is connected to again, as a connection begins at a packet
+    let mut packets = PacketWatcher::default();
+    // Answers xpra's challenges for the client on displays requiring a token
+    let mut login = display.token().cloned().map(XpraLogin::new);
+    // Holds the clipboard contents copied either way to the user's policy
+    let mut clipboard = entry_for(&context.config.clipboard, &session.user).await.cloned().map(ClipboardFilter::new);
+
+    context.monitor.set_state(&session_id, SessionState::Active).await;
+    // Screen size the client asked for, set once xpra greeted the client,
+    // as xpra sizes the display to the client's hello
+    let mut requested_size = session.resolution.as_deref().and_then(|resolution| {
+        crate::xpra_xorg::parse_resolution(resolution)
+            .inspect_err(|e| warn!(display = number, "Failed to set requested Xpra resolution: {:#}", e))
+            .ok()
+    });
+    // Programs next to the window manager run as long as the display does;
+    // the branding and message of the day are only applied when it starts
+    let start_children = |display: &XpraDisplay, programs: &[ChildProgram]| {
+        ChildSupervisor::start(display, programs, session.children.clone())
+    };
+    let vars = MotdVars {
+        user: &session.user,
+        host: &host.name,
+        display: number,
+        idle_timeout: context.config.idle_timeout,
+    };
+    // Programs running as long as the display does, started again when it
+    // resumes from hibernation
+    let mut resident = context.config.children.clone();
+    resident.extend(context.config.devices.smartcard.program(number, &host));
+    resident.extend(context.config.watermark.program(&vars, &session_id).await);
+    let mut programs = context.config.branding.programs(&context.config.max_resolution);
+    programs.extend(resident.iter().cloned());
+    programs.extend(context.config.motd.program(&vars));
+    let mut children = Some(start_children(display, &programs));
+    // Recorded users are told so until they acknowledge it by dismissing the notice
+    let notice = session.recording.then(|| context.config.screenshots.notice_program()).flatten();
+    let mut recording_notice = notice.as_ref().map(|program| xpra_children::run_once(display, program));
+    let traffic = session.traffic;
+    let mut last_activity_update = Instant::now();
+    let mut client = Some(ClientShell::new(&context, display.devices(), id, encrypt, shell_rx, output_tx));
+    disable_devices(display).await;
+    // Reader of the display connected to the client's smartcard, if attached
+    let mut smartcard: Option<SmartcardRelay> = None;
+    // Only ticks once a client negotiated heartbeats
+    let mut heartbeat = time::interval(ACTIVITY_UPDATE_INTERVAL);
+    // Keeps xpra answering while the desktop and its client are quiet
+    let mut keepalive = time::interval(XPRA_PING_INTERVAL);
+    // Size the client last reported, applied once it stops resizing
+    let mut pending_size: Option<(u32, u32)> = None;
+    let mut resize_at = time::Instant::now();
+    // Sessions end with the client unless xpra goes away first
+    let mut reason = TerminationReason::ClientDisconnect;
 
     loop {
+        let attached = client.is_some();
+        let heartbeat_due = client.as_ref().is_some_and(|c| c.channel.heartbeat_interval().is_some());
         tokio::select! {
             // Handle incoming messages from client
-            Some(msg) = shell_rx.recv() => {
+            msg = recv_input(&mut client), if attached => {
+                let shell = client.as_mut().unwrap();
+                let Some(msg) = msg else {
+                    // Keep the desktop running for another client to attach
+                    info!(session_id, "Client detached from Xpra session");
+                    client = None;
+                    detach_smartcard(&context, &session_id, &mut smartcard).await;
+                    let _ = ws_write.close().await;
+                    let state = context.monitor.get_session(&session_id).await.map(|s| s.lifecycle.state());
+                    if state == Some(SessionState::Active) {
+                        context.monitor.set_state(&session_id, SessionState::Detached).await;
+                    }
+                    continue;
+                };
                 match msg {
                     ShellData::Data(data) => {
+                        traffic.add_in(data.len() as u64);
+                        if last_activity_update.elapsed() >= ACTIVITY_UPDATE_INTERVAL {
+                            context.monitor.update_activity(&session_id).await;
+                            last_activity_update = Instant::now();
+                        }
+                        let data = match shell.channel.receive(data) {
+                            Received::Data(data) => data,
+                            Received::Attach(device, data) => {
+                                context.monitor.device_attached(&session_id, device).await;
+                                data
+                            }
+                            Received::Smartcard(input) => {
+                                smartcard_input(&context, &session_id, display, &mut smartcard, input).await;
+                                continue;
+                            }
+                            Received::Consent(device) => {
+                                set_device(display, device, true).await;
+                                continue;
+                            }
+                            Received::InputRekey(offset, key) => {
+                                shell.input_keys.switch(offset, Encrypt::from_key(key));
+                                debug!(session_id, offset, "Rotated desktop input key");
+                                continue;
+                            }
+                            Received::Reply(frame) => {
+                                if let Some(interval) = shell.channel.heartbeat_interval() {
+                                    heartbeat = time::interval_at(time::Instant::now() + interval, interval);
+                                }
+                                let consent = shell.channel.consent_request(&context.config.devices);
+                                if !shell.send_all([frame].into_iter().chain(consent).collect()).await {
+                                    break;
+                                }
+                                continue;
+                            }
+                            Received::Ignored => continue,
+                        };
+                        let data = filter_clipboard(&context, &session_id, &session.user, &mut clipboard, ClipboardDirection::ToDesktop, data.into());
+                        if data.is_empty() {
+                            continue;
+                        }
                         // Forward decrypted data to Xpra
-                        if let Err(e) = ws_write.send(data.into()).await {
+                        if let Some(login) = login.as_mut() {
+                            login.client_data(&data);
+                        }
+                        if let Err(e) = ws_write.send(Message::Binary(data)).await {
                             error!("Failed to forward data to Xpra: {}", e);
+                            reason = xpra_exit_reason(display).await;
                             break;
                         }
                     }
                     ShellData::Size(rows, cols) => {
-                        // Handle resize events if needed
+                        // Clients that negotiated resizing send their size in pixels
                         debug!(rows, cols, "Resize event received");
+                        if shell.channel.resize() {
+                            pending_size = Some((cols, rows));
+                            resize_at = time::Instant::now() + RESIZE_DEBOUNCE;
+                        }
                     }
                     ShellData::Sync(server_seq) => {
                         // Update our sequence number if server is ahead
-                        if server_seq > seq {
-                            seq = server_seq;
+                        if server_seq > shell.seq {
+                            shell.seq = server_seq;
                         }
                     }
+                    ShellData::Encrypted(..) => unreachable!("input is decrypted as it is received"),
                 }
             }
 
             // Handle messages from Xpra
-            Some(msg) = ws_read.next() => {
+            Some(msg) = ws_read.next(), if attached => {
+                let shell = client.as_mut().unwrap();
                 match msg {
                     Ok(msg) => {
-                        // Encrypt data before sending to client
-                        let data = encrypt.segment(
-                            0x100000000 | id.0 as u64,
-                            seq,
-                            &msg.into_data()
-                        );
-
-                        let term_data = TerminalData {
-                            id: id.0,
-                            data: data.into(),
-                            seq,
+                        xpra_deadline = read_deadline();
+                        // WebSocket control frames are between the forwarder and xpra
+                        match &msg {
+                            Message::Ping(data) => {
+                                if let Err(e) = ws_write.send(Message::Pong(data.clone())).await {
+                                    error!("Failed to answer the ping of Xpra: {}", e);
+                                    reason = xpra_exit_reason(display).await;
+                                    break;
+                                }
+                                continue;
+                            }
+                            Message::Pong(_) | Message::Frame(_) => continue,
+                            Message::Close(frame) => {
+                                debug!(session_id, ?frame, "Xpra closed the WebSocket");
+                                continue;
+                            }
+                            Message::Text(_) | Message::Binary(_) => {}
+                        }
+                        let seen = match &msg {
+                            Message::Binary(data) => packets.push(data),
+                            _ => Vec::new(),
                         };
+                        if packets.invalid() {
+                            anyhow::bail!("{} does not answer with xpra packets", ws_url);
+                        }
+                        let challenge = seen.iter().find_map(|packet| match packet {
+                            XpraPacket::Challenge(challenge) => Some(challenge),
+                            _ => None,
+                        });
+                        if let (Some(login), Some(challenge)) = (login.as_mut(), challenge) {
+                            let hello = login.answer(challenge)?;
+                            if let Err(e) = ws_write.send(Message::Binary(hello)).await {
+                                error!("Failed to answer the challenge of Xpra: {}", e);
+                                reason = xpra_exit_reason(display).await;
+                                break;
+                            }
+                            continue;
+                        }
+                        let payload = filter_clipboard(&context, &session_id, &session.user, &mut clipboard, ClipboardDirection::ToClient, msg.into_data());
+                        if shell.channel.rekey_due() {
+                            let (key, frame) = shell.channel.rekey(shell.seq);
+                            if !shell.send(frame).await {
+                                break;
+                            }
+                            shell.key = key;
+                            debug!(session_id, "Rotated desktop stream key");
+                        }
+                        let len = payload.len() as u64;
+                        let notifications = shell.channel.notifications(&seen);
+                        if !payload.is_empty() {
+                            let frame = shell.channel.send(payload);
+                            if !shell.send(frame).await {
+                                break;
+                            }
+                        }
+                        traffic.add_out(len);
+                        if !shell.send_all(notifications).await {
+                            break;
+                        }
+                        // The client was sent the packet, so it can show the reason too
+                        for packet in seen {
+                            match packet {
+                                XpraPacket::Refused(reason) => anyhow::bail!("xpra refused the client: {}", reason),
+                                XpraPacket::Disconnect(reason) => info!(session_id, reason, "Xpra disconnected the client"),
+                                XpraPacket::Hello => {
+                                    debug!(session_id, "Xpra greeted the client");
+                                    if let Some(size) = requested_size.take() {
+                                        pending_size = Some(size);
+                                        resize_at = time::Instant::now();
+                                    }
+                                }
+                                XpraPacket::Challenge(_) | XpraPacket::Notification(_) => {}
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        error!("WebSocket error: {}", e);
+                        reason = xpra_exit_reason(display).await;
+                        break;
+                    }
+                }
+            }
+
+            // Show the session in the shell of a client attaching to it
+            Some(attachment) = attachments.recv() => {
+                // xpra greets each client on a connection of its own
+                let stream = match connect_xpra(&ws_url, tls.as_ref(), &context.config).await {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        // Only the attaching shell fails; the desktop stays with its client
+                        warn!(session_id, shell = %attachment.id, "Failed to attach client to Xpra session: {:#}", e);
+                        let message = format!("Failed to attach to the desktop: {:#}", e);
+                        attachment.output_tx.send(ClientMessage::Error(message)).await.ok();
+                        continue;
+                    }
+                };
+                let _ = ws_write.close().await;
+                (ws_write, ws_read) = stream.split();
+                xpra_deadline = read_deadline();
+                packets.reset();
+                if let Some(login) = login.as_mut() {
+                    login.reset();
+                }
+                if let Some(clipboard) = clipboard.as_mut() {
+                    clipboard.reset();
+                }
+                detach_smartcard(&context, &session_id, &mut smartcard).await;
+                take_over(&context, &session_id, display, &mut client, attachment).await;
+            }
 
-                        if let Err(e) = output_tx.send(ClientMessage::Data(term_data)).await {
-                            error!("Failed to send data to client: {}", e);
+            // Pass the commands of the display's reader to the client's card
+            command = recv_smartcard(&mut smartcard), if attached && smartcard.is_some() => {
+                let shell = client.as_mut().unwrap();
+                match command {
+                    Some(data) => {
+                        let frame = shell.channel.smartcard(data);
+                        if !shell.send(frame).await {
                             break;
                         }
+                    }
+                    None => detach_smartcard(&context, &session_id, &mut smartcard).await,
+                }
+            }
+
+            // Resize the display once the client settled on a size
+            _ = time::sleep_until(resize_at), if pending_size.is_some() => {
+                let (width, height) = pending_size.take().unwrap();
+                match resize_display(&host, number, width, height, &context.config.max_resolution).await {
+                    Ok(size) => context.monitor.set_resolution(&session_id, size).await,
+                    Err(e) => warn!(display = number, "Failed to resize Xpra display: {:#}", e),
+                }
+            }
+
+            // Give up on an xpra that stopped talking to its client
+            _ = time::sleep_until(xpra_deadline), if attached && read_timeout.is_some() => {
+                if display.is_running() {
+                    error!(session_id, "Xpra sent nothing for {}s, giving up on it", context.config.read_timeout);
+                    reason = TerminationReason::Error;
+                } else {
+                    reason = xpra_exit_reason(display).await;
+                }
+                break;
+            }
+
+            // Stop when the host terminates the session
+            _ = session.shutdown.notified() => {
+                info!("Xpra session terminated by host");
+                break;
+            }
+
+            // Log the user's acknowledgement of the recording notice
+            acknowledged = notice_closed(&mut recording_notice), if recording_notice.is_some() => {
+                recording_notice = None;
+                if acknowledged {
+                    context.monitor.recording_acknowledged(&session_id).await;
+                }
+            }
 
-                        seq += msg.len() as u64;
+            // Checkpoint the display when the monitor finds the session idle
+            _ = session.hibernate.notified() => {
+                let _ = ws_write.close().await;
+                drop(children.take());
+                let notice_shown = recording_notice.take().inspect(JoinHandle::abort).is_some();
+                let wake = match hibernate_until_input(
+                    &context,
+                    &session_id,
+                    display,
+                    &mut client,
+                    &mut attachments,
+                    &session.shutdown,
+                ).await {
+                    Ok(Hibernation::Woken(wake)) => Some(wake),
+                    Ok(Hibernation::Ended) => break,
+                    Ok(Hibernation::Expired) => {
+                        info!(session_id, "Hibernated Xpra session expired");
+                        reason = TerminationReason::IdleTimeout;
+                        break;
+                    }
+                    Err(e) if display.is_hibernated() => {
+                        error!("Failed to resume hibernated Xpra session: {}", e);
+                        reason = TerminationReason::Error;
+                        break;
                     }
                     Err(e) => {
-                        error!("WebSocket error: {}", e);
+                        warn!("Failed to hibernate Xpra session, keeping it running: {}", e);
+                        context.monitor.session_resumed(&session_id).await;
+                        None
+                    }
+                };
+
+                (ws_write, ws_read) = connect_xpra(&ws_url, tls.as_ref(), &context.config).await?.split();
+                xpra_deadline = read_deadline();
+                packets.reset();
+                if let Some(login) = login.as_mut() {
+                    login.reset();
+                }
+                if let Some(clipboard) = clipboard.as_mut() {
+                    clipboard.reset();
+                }
+                children.get_or_insert_with(|| start_children(display, &resident));
+                if notice_shown {
+                    recording_notice = notice.as_ref().map(|program| xpra_children::run_once(display, program));
+                }
+                let pending = match wake {
+                    Some(Wake::Input(data)) => client.as_mut().map(|shell| match shell.channel.receive(data) {
+                        Received::InputRekey(offset, key) => {
+                            shell.input_keys.switch(offset, Encrypt::from_key(key));
+                            Received::Ignored
+                        }
+                        received => received,
+                    }),
+                    Some(Wake::Attached(attachment)) => {
+                        detach_smartcard(&context, &session_id, &mut smartcard).await;
+                        take_over(&context, &session_id, display, &mut client, attachment).await;
+                        None
+                    }
+                    None => None,
+                };
+                match &pending {
+                    Some(Received::Attach(device, _)) => context.monitor.device_attached(&session_id, *device).await,
+                    Some(Received::Consent(device)) => set_device(display, *device, true).await,
+                    _ => {}
+                }
+                if let Some(Received::Data(data) | Received::Attach(_, data)) = pending {
+                    traffic.add_in(data.len() as u64);
+                    let data = filter_clipboard(&context, &session_id, &session.user, &mut clipboard, ClipboardDirection::ToDesktop, data.into());
+                    if data.is_empty() {
+                        continue;
+                    }
+                    if let Err(e) = ws_write.send(Message::Binary(data)).await {
+                        error!("Failed to forward data to Xpra: {}", e);
+                        reason = xpra_exit_reason(display).await;
+                        break;
+                    }
+                }
+            }
+
+            // Ping xpra, whose pong counts as it talking for the read timeout
+            _ = keepalive.tick(), if attached => {
+                if let Err(e) = ws_write.send(Message::Ping(Vec::new())).await {
+                    error!("Failed to ping Xpra: {}", e);
+                    reason = xpra_exit_reason(display).await;
+                    break;
+                }
+            }
+
+            // Let negotiated clients tell a stalled channel from an idle desktop
+            _ = heartbeat.tick(), if heartbeat_due => {
+                let shell = client.as_mut().unwrap();
+                if let Some(frame) = shell.channel.heartbeat() {
+                    if !shell.send(frame).await {
                         break;
                     }
                 }
             }
 
+            // Notice xpra going away while no client is attached
+            _ = time::sleep(ACTIVITY_UPDATE_INTERVAL), if !attached => {
+                if !display.is_hibernated() && !display.is_running() {
+                    info!("Xpra process terminated");
+                    reason = xpra_exit_reason(display).await;
+                    break;
+                }
+            }
+
             // Check if Xpra is still running
             else => {
                 if !display.is_running() {
                     info!("Xpra process terminated");
+                    reason = xpra_exit_reason(display).await;
                     break;
                 }
             }
         }
     }
 
-    info!("Xpra WebSocket forwarder terminated");
-    Ok(())
+    detach_smartcard(&context, &session_id, &mut smartcard).await;
+    if let Some(notice) = recording_notice {
+        notice.abort();
+    }
+    // Tell the client why the host ended the session before its shell closes
+    if let (Some(shell), Some(notice)) = (&client, session.termination.get()) {
+        shell.output_tx.send(ClientMessage::TerminatedShell(notice.to_proto(shell.id))).await.ok();
+    }
+
+    info!(%reason, "Xpra WebSocket forwarder terminated");
+    let crash_bundle = match reason {
+        TerminationReason::ProcessCrash => {
+            xpra_crash::collect_for(&context.config.crash, display, &session_id, &session.user).await
+        }
+        _ => None,
+    };
+    Ok((reason, crash_bundle))
+}
+
+/// `data` sent `direction` as passed by the session's clipboard filter, if
+/// any; the transfers it blocks are logged and counted.
+fn filter_clipboard(
+    context: &XpraContext,
+    session_id: &str,
+    user: &str,
+    filter: &mut Option<ClipboardFilter>,
+    direction: ClipboardDirection,
+    data: Vec<u8>,
+) -> Vec<u8> {
+    let Some(filter) = filter.as_mut() else {
+        return data;
+    };
+    let (data, blocked) = filter.push(direction, data);
+    for block in blocked {
+        context.metrics.clipboard_blocked();
+        warn!(
+            session_id,
+            user,
+            direction = %block.direction,
+            target = block.target,
+            size = block.size,
+            "Blocked clipboard transfer: {}",
+            block.reason
+        );
+    }
+    data
+}
+
+/// Command of the reader connected to the client's smartcard, pending while
+/// none is.
+async fn recv_smartcard(relay: &mut Option<SmartcardRelay>) -> Option<Vec<u8>> {
+    match relay {
+        Some(relay) => relay.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Act on the client's input for the reader of the session's display.
+async fn smartcard_input(
+    context: &XpraContext,
+    session_id: &str,
+    display: &XpraDisplay,
+    relay: &mut Option<SmartcardRelay>,
+    input: SmartcardInput,
+) {
+    match input {
+        SmartcardInput::Attach if relay.is_none() => {
+            let Some(port) = display.smartcard_port() else {
+                warn!(session_id, "Refused the client's smartcard: the display has no reader");
+                return;
+            };
+            match SmartcardRelay::connect("127.0.0.1", port).await {
+                Ok(connected) => {
+                    *relay = Some(connected);
+                    context.monitor.device_attached(session_id, Device::Smartcard).await;
+                }
+                Err(e) => warn!(session_id, "Failed to attach the client's smartcard: {:#}", e),
+            }
+        }
+        SmartcardInput::Attach => {}
+        SmartcardInput::Data(data) => {
+            let Some(connected) = relay.as_mut() else {
+                return;
+            };
+            if let Err(e) = connected.send(&data).await {
+                warn!(session_id, "Failed to pass the client's smartcard to its reader: {:#}", e);
+                detach_smartcard(context, session_id, relay).await;
+            }
+        }
+        SmartcardInput::Detach => detach_smartcard(context, session_id, relay).await,
+    }
+}
+
+/// Let xpra attach a device of the client or not.
+async fn set_device(display: &XpraDisplay, device: Device, enabled: bool) {
+    let number = display.display();
+    if let Err(e) = device.set_enabled(display.host(), number, enabled).await {
+        warn!(display = number, %device, "Failed to switch forwarding of a device: {:#}", e);
+    }
+}
+
+/// Stop xpra from attaching any device until the client's user consents.
+async fn disable_devices(display: &XpraDisplay) {
+    for device in display.devices() {
+        set_device(display, *device, false).await;
+    }
+}
+
+/// Disconnect the client's smartcard from the reader, if attached.
+async fn detach_smartcard(context: &XpraContext, session_id: &str, relay: &mut Option<SmartcardRelay>) {
+    if relay.take().is_some() {
+        context.monitor.device_detached(session_id, Device::Smartcard).await;
+    }
+}
+
+/// Forward a session to the shell of an attaching client, closing the shell
+/// of the client it was forwarded to so far, if any.
+async fn take_over(
+    context: &XpraContext,
+    session_id: &str,
+    display: &XpraDisplay,
+    client: &mut Option<ClientShell>,
+    attachment: Attachment,
+) {
+    if let Some(previous) = client.take() {
+        previous.output_tx.send(ClientMessage::ClosedShell(previous.id.0)).await.ok();
+    }
+    info!(session_id, shell = %attachment.id, "Client attached to Xpra session");
+    // The consent of the previous client does not carry over
+    disable_devices(display).await;
+    *client = Some(ClientShell::attach(context, display.devices(), attachment));
+    context.monitor.update_activity(session_id).await;
+    let state = context.monitor.get_session(session_id).await.map(|s| s.lifecycle.state());
+    if state == Some(SessionState::Detached) {
+        context.monitor.set_state(session_id, SessionState::Active).await;
+    }
+}
+
+/// Why a session ended when xpra went away under its forwarder: a logout if
+/// xpra exited cleanly, for instance with the session's window manager, and
+/// a crash otherwise. Waits briefly for the process to exit, since the
+/// WebSocket usually closes first.
+async fn xpra_exit_reason(display: &mut XpraDisplay) -> TerminationReason {
+    for _ in 0..10 {
+        if let Some(status) = display.exit_status() {
+            return match status.success() {
+                true => TerminationReason::ClientDisconnect,
+                false => TerminationReason::ProcessCrash,
+            };
+        }
+        time::sleep(Duration::from_millis(50)).await;
+    }
+    TerminationReason::ProcessCrash
+}
+
+/// Encrypt a frame in place and send it to the client, returning whether it
+/// was sent.
+async fn send_frame(
+    output_tx: &mpsc::Sender<ClientMessage>,
+    encrypt: &Encrypt,
+    id: Sid,
+    seq: &mut u64,
+    frame: Bytes,
+) -> bool {
+    let len = frame.len() as u64;
+    let data = encrypt.segment_bytes(0x100000000 | id.0 as u64, *seq, frame);
+    let term_data = TerminalData {
+        id: id.0,
+        data,
+        seq: *seq,
+    };
+    if let Err(e) = output_tx.send(ClientMessage::Data(term_data)).await {
+        error!("Failed to send data to client: {}", e);
+        return false;
+    }
+    *seq += len;
+    true
+}
+
+/// Resize a display to the size a client reported, within the configured
+/// maximum resolution, returning the size set.
+async fn resize_display(
+    host: &WorkerHost,
+    display: u16,
+    width: u32,
+    height: u32,
+    max_resolution: &str,
+) -> Result<String> {
+    let (max_width, max_height) = crate::xpra_xorg::parse_resolution(max_resolution)?;
+    let size = format!("{}x{}", width.clamp(1, max_width), height.clamp(1, max_height));
+    let args = [format!("DISPLAY=:{}", display), "xrandr".to_string(), "--fb".to_string(), size.clone()];
+    let status = tokio::process::Command::from(host.command("env", &args))
+        .status()
+        .await?;
+    if !status.success() {
+        anyhow::bail!("xrandr exited with {}", status);
+    }
+    Ok(size)
+}
+
+/// Connect to Xpra's WebSocket, retrying briefly while a freshly started or
+/// restored server comes up. Each attempt is bounded by `connect_timeout`,
+/// and the session fails after `connect_attempts`. Workers with `tls` set
+/// are connected to over TLS.
+async fn connect_xpra(url: &str, tls: Option<&TlsClient>, config: &XpraConfig) -> Result<XpraStream> {
+    let timeout = config.connect_duration();
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        let error = match time::timeout(timeout, crate::xpra_tls::connect(url, tls)).await {
+            Ok(Ok(stream)) => return Ok(stream),
+            Ok(Err(e)) => format!("{:#}", e),
+            Err(_) => format!("no answer within {}s", timeout.as_secs()),
+        };
+        if attempts >= config.connect_attempts.max(1) {
+            anyhow::bail!("failed to connect to xpra at {} after {} attempts: {}", url, attempts, error);
+        }
+        debug!(attempts, "Xpra WebSocket not ready: {}", error);
+        time::sleep(CONNECT_RETRY_INTERVAL).await;
+    }
+}
+
+/// What ended the hibernation of a session.
+enum Wake {
+    /// First input of the attached client
+    Input(Bytes),
+    /// Shell of a client attaching to the session
+    Attached(Attachment),
+}
+
+/// How the hibernation of a session ended.
+enum Hibernation {
+    /// The session was woken and resumed
+    Woken(Wake),
+    /// The session was terminated while hibernated
+    Ended,
+    /// The session stayed hibernated past `hibernate_ttl`
+    Expired,
+}
+
+/// Checkpoint an idle display and wait for the user to return, restoring it
+/// on their first input or when a client attaches, if the admission policy
+/// lets it resume. A session not resumed within `hibernate_ttl` expires and
+/// stays hibernated, for closing the display to remove its images.
+async fn hibernate_until_input(
+    context: &XpraContext,
+    session_id: &str,
+    display: &mut XpraDisplay,
+    client: &mut Option<ClientShell>,
+    attachments: &mut mpsc::Receiver<Attachment>,
+    shutdown: &Notify,
+) -> Result<Hibernation> {
+    display.hibernate(crate::xpra_hibernate::image_dir(&context.config, session_id)).await?;
+    info!(session_id, "Hibernated idle Xpra session");
+
+    let deadline = context.config.hibernate_ttl_duration().map(|ttl| time::Instant::now() + ttl);
+    loop {
+        let hibernation = wait_for_wake(client, attachments, shutdown, deadline).await;
+        if let Hibernation::Woken(_) = hibernation {
+            // What woke a session refused is dropped, releasing a new client
+            if let Err(reason) = admit_resume(context, session_id).await {
+                warn!(session_id, reason, "Admission policy kept Xpra session hibernated");
+                continue;
+            }
+            // Fails if the display number went to another session meanwhile
+            display.resume().await?;
+            context.monitor.session_resumed(session_id).await;
+            info!(session_id, "Resumed hibernated Xpra session");
+        }
+        return Ok(hibernation);
+    }
+}
+
+/// Wait for the first input of the attached client or a client attaching to
+/// a hibernated session, until `deadline` if there is one.
+async fn wait_for_wake(
+    client: &mut Option<ClientShell>,
+    attachments: &mut mpsc::Receiver<Attachment>,
+    shutdown: &Notify,
+    deadline: Option<time::Instant>,
+) -> Hibernation {
+    let expiry = async {
+        match deadline {
+            Some(deadline) => time::sleep_until(deadline).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::pin!(expiry);
+    loop {
+        let attached = client.is_some();
+        tokio::select! {
+            msg = recv_input(client), if attached => match msg {
+                Some(ShellData::Data(data)) => return Hibernation::Woken(Wake::Input(data)),
+                Some(_) => continue,
+                // Stay hibernated for another client to attach
+                None => *client = None,
+            },
+            Some(attachment) = attachments.recv() => return Hibernation::Woken(Wake::Attached(attachment)),
+            _ = shutdown.notified() => return Hibernation::Ended,
+            _ = &mut expiry => return Hibernation::Expired,
+        }
+    }
+}
+
+/// Decide whether `user` may start the session `session_id` with
+/// `template`, returning the template to start it with. Every way of
+/// starting a desktop goes through here: maintenance mode, the rate
+/// limiter, time budgets, the session limit and the admission policy.
+pub(crate) async fn admit_session(
+    context: &XpraContext,
+    session_id: &str,
+    user: &str,
+    mut template: SessionTemplate,
+) -> Result<SessionTemplate> {
+    use crate::xpra_logger::{SessionEvent, SessionEventType};
+    use crate::xpra_quota::BudgetCheck;
+    use crate::xpra_rate_limit::RateLimitScope;
+    use crate::xpra_rejection::{RejectionCode, SessionRejection};
+
+    // Refuse new sessions while the host is in maintenance mode
+    if context.drain.is_draining().await {
+        return Err(SessionRejection::new(
+            RejectionCode::Maintenance,
+            "This host is in maintenance mode and not accepting new desktops, please try again later",
+        )
+        .into());
+    }
+
+    // Reject bursts of session starts before touching the display pool
+    if let Err(scope) = context.rate_limiter.check(user).await {
+        context.metrics.session_rate_limited();
+        warn!(user, ?scope, "Rejected Xpra session start due to rate limit");
+        let retry_after = context.rate_limiter.retry_after(user, scope).await;
+        if let Err(e) = context.logger.log_session_event(SessionEvent {
+            timestamp: Utc::now(),
+            seq: None,
+            event_type: SessionEventType::RateLimited,
+            session_id: session_id.to_string(),
+            user: user.to_string(),
+            display: 0,
+            labels: template.labels.clone(),
+            state: None,
+            reason: None,
+            crash_bundle: None,
+            name: None,
+            description: None,
+            parent: None,
+            device: None,
+            traffic: None,
+            host: None,
+        }).await {
+            error!("Failed to log rate limited session: {}", e);
+        }
+        let message = match scope {
+            RateLimitScope::User => "You have started too many desktops recently, try again later",
+            RateLimitScope::Global => "Too many desktops are being started on this host, try again later",
+        };
+        return Err(SessionRejection::new(RejectionCode::RateLimited, message)
+            .retry_after(retry_after)
+            .into());
+    }
+
+    // Check desktop hour budgets
+    if let BudgetCheck::Exhausted(reason) = context.usage.check(user).await {
+        if context.config.enforce_time_budgets {
+            warn!(user, reason, "Rejected Xpra session start due to time budget");
+            return Err(SessionRejection::new(RejectionCode::TimeBudget, reason).into());
+        }
+        warn!(user, reason, "User is over their desktop time budget");
+    }
+
+    // Check session limit
+    let session_count = context.monitor.get_user_session_count(user).await;
+    if context.config.max_sessions > 0 && session_count >= context.config.max_sessions as usize {
+        return Err(SessionRejection::new(
+            RejectionCode::MaxSessions,
+            format!("You already have {} desktops open, close one or wait", session_count),
+        )
+        .into());
+    }
+
+    // Ask the admission policy, which may refuse or change the session
+    let request = admission_request(context, session_id, user, session_count, template.clone()).await;
+    match context.config.admission_policy.evaluate(&request).await {
+        AdmissionDecision::Allow => {}
+        AdmissionDecision::Modify { template: modified } => {
+            debug!(session_id, ?modified, "Admission policy modified session template");
+            template = *modified;
+        }
+        AdmissionDecision::Deny { reason } => {
+            warn!(user, reason, "Rejected Xpra session start by admission policy");
+            if let Err(e) = context.logger.log_session_event(SessionEvent {
+                timestamp: Utc::now(),
+                seq: None,
+                event_type: SessionEventType::PolicyDenied,
+                session_id: session_id.to_string(),
+                user: user.to_string(),
+                display: 0,
+                labels: template.labels.clone(),
+                state: None,
+                reason: None,
+                crash_bundle: None,
+                name: None,
+                description: None,
+                parent: None,
+                device: None,
+                traffic: None,
+                host: None,
+            }).await {
+                error!("Failed to log denied session: {}", e);
+            }
+            return Err(SessionRejection::new(RejectionCode::Policy, reason).into());
+        }
+    }
+
+    Ok(template)
+}
+
+/// The admission policy's view of `user` asking for the session
+/// `session_id` with `template` while running `user_sessions` sessions.
+async fn admission_request(
+    context: &XpraContext,
+    session_id: &str,
+    user: &str,
+    user_sessions: usize,
+    template: SessionTemplate,
+) -> AdmissionRequest {
+    // Taken for every start, so the hosts are only asked every few seconds
+    let hosts = context.hosts.cached_status().await;
+    let load = AdmissionLoad {
+        active_sessions: hosts.iter().map(|host| host.active_sessions).sum(),
+        capacity: hosts.iter().filter(|host| host.healthy).map(|host| host.capacity).sum(),
+        user_sessions,
+        load_average: load_average(),
+    };
+    AdmissionRequest::new(session_id, user, load, template)
+}
+
+/// Ask the admission policy whether the hibernated session `session_id`
+/// may resume, returning why not if it may not. The session keeps the
+/// settings it was started with; a policy changing them allows it as is.
+async fn admit_resume(context: &XpraContext, session_id: &str) -> Result<(), String> {
+    let Some(session) = context.monitor.get_session(session_id).await else {
+        return Ok(());
+    };
+    let template = SessionTemplate {
+        window_manager: session.config.window_manager.clone(),
+        class: session.config.class,
+        labels: session.labels.clone(),
+        app: None,
+        resolution: session.resolution.clone(),
+        audio: false,
+        locale: None,
+        timezone: None,
+        keyboard_layout: context.config.keyboard_layout.clone(),
+        backend: BackendConfig::Xpra,
+    };
+    let user_sessions = context.monitor.get_user_session_count(&session.user).await;
+    let request = admission_request(context, session_id, &session.user, user_sessions, template).await.resuming();
+    match context.config.admission_policy.evaluate(&request).await {
+        AdmissionDecision::Deny { reason } => Err(reason),
+        AdmissionDecision::Allow | AdmissionDecision::Modify { .. } => Ok(()),
+    }
 }
 
 // Helper function to start a new Xpra session
+/// Start a new xpra display for the user with the parameters the client asked
+/// for, and forward the session's channel to it.
+#[allow(clippy::too_many_arguments)]
 pub async fn start_xpra_session(
+    context: Arc<XpraContext>,
+    parent: ParentShell,
+    user: String,
+    labels: Labels,
+    params: SessionParams,
+    encrypt: Encrypt,
+    shell_rx: mpsc::Receiver<ShellData>,
+    output_tx: mpsc::Sender<ClientMessage>,
+) -> Result<()> {
+    use crate::xpra_hooks::{HookPoint, HookSession};
+    use crate::xpra_priority::SessionClass;
+    use crate::xpra_rejection::{RejectionCode, SessionRejection};
+
+    let id = parent.shell;
+    let session_id = context.session_ids.interactive(id);
+
+    // Only start what the host allows clients to ask for
+    let mut template = SessionTemplate {
+        window_manager: context.config.window_manager.clone(),
+        class: SessionClass::Interactive,
+        labels,
+        app: None,
+        resolution: None,
+        audio: false,
+        locale: None,
+        timezone: None,
+        keyboard_layout: context.config.keyboard_layout.clone(),
+        backend: BackendConfig::Xpra,
+    };
+    if let Err(rejection) = context.config.session_params.apply(&params, &mut template, &context.config.max_resolution) {
+        warn!(user, ?params, "Rejected Xpra session start with invalid parameters");
+        return Err(rejection.into());
+    }
+
+    let mut template = admit_session(&context, &session_id, &user, template).await?;
+
+    // Tell the sessions of other backends apart in the logs and analyzer
+    let backend = template.backend.backend();
+    if let Some(target) = backend.target() {
+        template.labels.insert("backend".to_string(), backend.name().to_string());
+        template.labels.insert("target".to_string(), target.to_string());
+    }
+    // Every desktop of a machine with one screen would show the same screen,
+    // so it has one desktop at a time, held for the session's lifetime
+    let _target = match backend.target().filter(|_| backend.exclusive()) {
+        Some(target) => Some(crate::xpra_backend::claim_target(target, &user).map_err(|holder| {
+            let message = match holder == user {
+                true => {
+                    let running = context.monitor.collect_sessions(|id, s| {
+                        let same = |key: &str| s.labels.get(key) == template.labels.get(key);
+                        (s.user == user && same("backend") && same("target")).then(|| id.to_string())
+                    });
+                    match running.first() {
+                        Some(running) => format!("You already have desktop {running} of {target} open, attach to it instead"),
+                        None => format!("Your desktop of {target} is still starting"),
+                    }
+                }
+                false => format!("{target} is in use by another user's desktop, try again later"),
+            };
+            SessionRejection::new(RejectionCode::TargetInUse, message)
+        })?),
+        None => None,
+    };
+
+    // Wait for (or fail on) a slot under the host-wide session cap. The slot
+    // is held until this function returns, i.e. for the session's lifetime.
+    let _slot = context.capacity.acquire().await?;
+
+    // Create new display on a healthy host chosen by the placement strategy
+    let host = context.hosts.place(&context, &user).await?;
+    // Apps get a home of their own, so users do not share browser profiles
+    let profile = template.app.as_deref().map(|app| context.config.profiles.profile(&user, app, &session_id));
+    let _claim = match &profile {
+        Some(profile) => match profile.claim(&host) {
+            Some(claim) => Some(claim),
+            None => {
+                return Err(SessionRejection::new(
+                    RejectionCode::MaxSessions,
+                    "Another of your desktops is using this app's profile, close it first",
+                )
+                .into());
+            }
+        },
+        None => None,
+    };
+    let hooks = context.config.hooks.clone();
+    let mut hook_session = HookSession { session_id: &session_id, user: &user, display: None };
+    hooks.run(HookPoint::PreStart, &host, &hook_session).await?;
+    let options = DisplayOptions {
+        app: template.app.clone(),
+        audio: template.audio,
+        locale: template.locale.clone(),
+        timezone: template.timezone.clone(),
+        keyboard_layout: template.keyboard_layout.clone(),
+        backend: template.backend.clone(),
+        profile: profile.clone(),
+        devices: context.config.devices.allowed(&user, &host).await,
+    };
+    let mut display = match XpraDisplay::new_on_host(&context.config, host.clone(), &template.window_manager, template.class, &options).await {
+        Ok(display) => display,
+        Err(e) => {
+            hooks.run_post_stop(&host, &hook_session).await;
+            return Err(e);
+        }
+    };
+    hook_session.display = Some(display.display());
+    // The session is only registered and forwarded once xpra listens, and
+    // post-start hooks run once the display is bound to it
+    let started = async {
+        display.wait_ready(context.config.startup_duration()).await?;
+        display.bind()?;
+        hooks.run(HookPoint::PostStart, &host, &hook_session).await
+    }
+    .await;
+    if let Err(e) = started {
+        display.close().await;
+        if let Some(profile) = &profile {
+            profile.release(&host).await;
+        }
+        hooks.run_post_stop(&host, &hook_session).await;
+        return Err(e);
+    }
+
+    // Register session; the guard owns the display and deregisters the
+    // session however the task ends
+    let mut guard = context
+        .monitor
+        .register_session(session_id.clone(), user.clone(), display, Some(parent), template.labels)
+        .await;
+
+    // Run the Xpra task; finishing the guard closes the display
+    let mut info = guard.info().clone();
+    info.resolution = template.resolution;
+    let attachments = guard.take_attachments().expect("attachments are taken once");
+    let abort = info.abort.clone();
+    let display = guard.display_mut();
+    let result = tokio::select! {
+        result = xpra_task(context, id, session_id.clone(), encrypt, display, info, shell_rx, output_tx, attachments) => result,
+        // A forwarder stuck past the stop timeout is dropped, and finishing
+        // the guard kills its xpra
+        _ = abort.notified() => Err(anyhow::anyhow!("forwarder did not stop when the session was terminated")),
+    };
+    match &result {
+        Ok((reason, crash_bundle)) => {
+            guard.set_reason(*reason);
+            if let Some(bundle) = crash_bundle {
+                guard.set_crash_bundle(bundle.clone());
+            }
+        }
+        Err(e) => {
+            error!(session_id, "Xpra session failed: {:#}", e);
+            guard.fail();
+        }
+    }
+    guard.finish().await;
+    if let Some(profile) = &profile {
+        profile.release(&host).await;
+    }
+    hooks.run_post_stop(&host, &hook_session).await;
+    result.map(|_| ())
+}
+
+/// Show a running session of the user in a new shell, returning once the
+/// session lets go of the shell: when it ends or another client takes over.
+pub async fn attach_xpra_session(
+    context: Arc<XpraContext>,
     id: Sid,
     user: String,
+    session_id: String,
     encrypt: Encrypt,
     shell_rx: mpsc::Receiver<ShellData>,
     output_tx: mpsc::Sender<ClientMessage>,
 ) -> Result<()> {
-    use crate::xpra_config::CONFIG;
-    use crate::xpra_monitor::SESSION_MONITOR;
+    use crate::xpra_rejection::{RejectionCode, SessionRejection};
 
-    // Check session limit
-    let session_count = SESSION_MONITOR.get_user_session_count(&user).await;
-    if CONFIG.max_sessions > 0 && session_count >= CONFIG.max_sessions as usize {
-        anyhow::bail!("Maximum number of Xpra sessions reached for user");
+    let unknown = || SessionRejection::new(
+        RejectionCode::UnknownSession,
+        format!("You have no desktop {} running to attach to", session_id),
+    );
+    let session = context.monitor.get_session(&session_id).await;
+    let Some(session) = session.filter(|s| s.user == user && !s.headless) else {
+        return Err(unknown().into());
+    };
+    let (released, done) = oneshot::channel();
+    let attachment = Attachment { id, encrypt, shell_rx, output_tx, released };
+    if session.attach.send(attachment).await.is_err() {
+        // The session ended in the meantime
+        return Err(unknown().into());
     }
+    let _ = done.await;
+    Ok(())
+}
 
-    // Create new display
-    let display = XpraDisplay::new(&CONFIG.window_manager).await?;
-    
-    // Register session
-    let session_id = format!("xpra-{}", id.0);
-    SESSION_MONITOR.register_session(session_id.clone(), user, display.display()).await;
-    METRICS.session_started();
+/// Give one of the user's sessions a name and description. An empty name
+/// goes back to the default one and an empty description clears it.
+pub async fn rename_desktop(
+    context: &XpraContext,
+    user: &str,
+    session_id: &str,
+    name: &str,
+    description: Option<&str>,
+) -> Result<()> {
+    use crate::xpra_rejection::{RejectionCode, SessionRejection};
+
+    let session = context.monitor.get_session(session_id).await;
+    if !session.is_some_and(|s| s.user == user && !s.headless) {
+        return Err(SessionRejection::new(
+            RejectionCode::UnknownSession,
+            format!("You have no desktop {} running to rename", session_id),
+        ).into());
+    }
+    let name = name.trim();
+    let description = description.map(str::trim).unwrap_or_default();
+    if name.chars().count() > MAX_NAME_LENGTH || description.chars().count() > MAX_DESCRIPTION_LENGTH {
+        return Err(SessionRejection::new(
+            RejectionCode::InvalidParams,
+            format!(
+                "Desktop names are limited to {} and descriptions to {} characters",
+                MAX_NAME_LENGTH, MAX_DESCRIPTION_LENGTH,
+            ),
+        ).into());
+    }
+    // Names are printed by the status and listings, where control
+    // characters would pass terminal escapes through
+    if name.chars().chain(description.chars()).any(char::is_control) {
+        return Err(SessionRejection::new(
+            RejectionCode::InvalidParams,
+            "Desktop names and descriptions cannot contain control characters",
+        ).into());
+    }
+    let some = |text: &str| (!text.is_empty()).then(|| text.to_string());
+    context.monitor.rename_session(session_id, some(name), some(description)).await;
+    Ok(())
+}
+
+/// The user's sessions that a shell can attach to, oldest first.
+pub async fn list_desktops(context: &XpraContext, user: &str) -> Vec<DesktopSession> {
+    let mut sessions = context.monitor.collect_sessions(|id, s| {
+        (s.user == user && !s.headless).then(|| DesktopSession {
+            name: s
+                .name
+                .clone()
+                .unwrap_or_else(|| format!("{} (:{})", s.config.window_manager, s.display)),
+            description: s.description.clone(),
+            created_at: s.created_at.timestamp_millis().max(0) as u64,
+            idle: s.last_activity.elapsed().as_secs(),
+            resolution: s.resolution.clone().unwrap_or_else(|| s.config.max_resolution.clone()),
+            attached: s.lifecycle.state() != SessionState::Detached,
+            id: id.to_string(),
+        })
+    });
+    sessions.sort_by_key(|s| s.created_at);
+    sessions
+}
 
-    // Run the Xpra task
-    xpra_task(id, encrypt, display, shell_rx, output_tx).await
+#[cfg(test)]
+mod tests {
+    use sshx_core::Sid;
+
+    use super::*;
+    use crate::xpra_labels::Labels;
+    use crate::xpra_policy::{AdmissionPolicyConfig, PolicyFailure};
+    use crate::xpra_session_id::ParentShell;
+
+    #[tokio::test]
+    async fn test_rename_desktop() {
+        let dir = std::env::temp_dir().join(format!("sshx-runner-rename-{}", std::process::id()));
+        let context = XpraContext::new(XpraConfig { log_dir: dir.clone(), instance: None, ..XpraConfig::default() });
+        let parent = ParentShell { session: "k3xq9v".to_string(), shell: Sid(1) };
+        let (guard, _) = context.monitor.register_sleeping("xpra-1", Some(parent), Labels::new()).await;
+
+        rename_desktop(&context, "alice", "xpra-1", " Reports ", Some("Q3")).await.unwrap();
+        let session = context.monitor.get_session("xpra-1").await.unwrap();
+        assert_eq!((session.name.as_deref(), session.description.as_deref()), (Some("Reports"), Some("Q3")));
+
+        // Only the user's own desktops, without terminal escapes
+        assert!(rename_desktop(&context, "bob", "xpra-1", "Mine", None).await.is_err());
+        assert!(rename_desktop(&context, "alice", "xpra-1", "\u{1b}[2JReports", None).await.is_err());
+        assert!(rename_desktop(&context, "alice", "xpra-1", "Reports", Some("line\u{7}")).await.is_err());
+        assert_eq!(context.monitor.get_session("xpra-1").await.unwrap().name.as_deref(), Some("Reports"));
+
+        guard.finish().await;
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn test_admit_resume() {
+        let dir = std::env::temp_dir().join(format!("sshx-runner-resume-{}", std::process::id()));
+        let policy = AdmissionPolicyConfig {
+            command: Some(r#"grep -q '"resume":true' && echo '{"decision": "deny", "reason": "Closed"}'"#.to_string()),
+            timeout: 5,
+            on_failure: PolicyFailure::Allow,
+        };
+        let context = XpraContext::new(XpraConfig {
+            log_dir: dir.clone(),
+            instance: None,
+            admission_policy: policy,
+            ..XpraConfig::default()
+        });
+        let parent = ParentShell { session: "k3xq9v".to_string(), shell: Sid(1) };
+        let (guard, _) = context.monitor.register_sleeping("xpra-1", Some(parent), Labels::new()).await;
+
+        assert_eq!(admit_resume(&context, "xpra-1").await, Err("Closed".to_string()));
+
+        guard.finish().await;
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn test_hibernation_expires() {
+        let (attach_tx, mut attachments) = mpsc::channel(1);
+        let shutdown = Notify::new();
+        let deadline = Some(time::Instant::now() + Duration::from_millis(50));
+
+        let hibernation = wait_for_wake(&mut None, &mut attachments, &shutdown, deadline).await;
+        assert!(matches!(hibernation, Hibernation::Expired));
+
+        // A client attaching in time wakes the session
+        let (_, shell_rx) = mpsc::channel(1);
+        let (output_tx, _output_rx) = mpsc::channel(1);
+        let (released, _) = oneshot::channel();
+        let encrypt = Encrypt::new("test");
+        attach_tx.send(Attachment { id: Sid(2), encrypt, shell_rx, output_tx, released }).await.unwrap();
+        let deadline = Some(time::Instant::now() + Duration::from_secs(5));
+        let hibernation = wait_for_wake(&mut None, &mut attachments, &shutdown, deadline).await;
+        assert!(matches!(hibernation, Hibernation::Woken(Wake::Attached(attachment)) if attachment.id == Sid(2)));
+
+        shutdown.notify_one();
+        let hibernation = wait_for_wake(&mut None, &mut attachments, &shutdown, None).await;
+        assert!(matches!(hibernation, Hibernation::Ended));
+    }
 }
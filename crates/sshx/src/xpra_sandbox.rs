@@ -0,0 +1,708 @@
+//! Seccomp and Landlock sandboxing of the xpra process tree.
+//!
+//! Desktop applications are the most likely way into a host. With a
+//! `sandbox.profile` set, xpra is started through `ssh-desktop sandbox`,
+//! which sets `no_new_privs`, installs a seccomp filter refusing syscalls
+//! desktops have no use for and restricts the filesystem with Landlock
+//! before running xpra. Both are inherited by every process xpra starts and
+//! cannot be lifted. Landlock is best effort: on kernels without it only
+//! the seccomp filter applies.
+//!
+//! The sandbox also hides directories of other users' data, like browser
+//! profiles, from the desktop: it mounts an empty directory over them in a
+//! mount namespace of xpra's own, with the paths of the desktop's own data
+//! mounted back beneath. Accounts other than root enter a user namespace
+//! for it, mapping their own ids only, and lose the capabilities they have
+//! there when xpra starts.
+
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use serde::{Deserialize, Serialize};
+
+use crate::xpra_hosts::{HostKind, WorkerHost};
+
+/// How tightly the xpra process tree is sandboxed, from least to most.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SandboxProfile {
+    /// Not sandboxed
+    #[default]
+    Off,
+    /// Refuse syscalls changing the kernel or the host's clock
+    Permissive,
+    /// Also refuse debugging other processes, eBPF and kernel keyrings,
+    /// and only allow writes to the home, temporary and device directories
+    Standard,
+    /// Also refuse mounts and namespaces, and only allow reading system
+    /// directories
+    Strict,
+}
+
+impl SandboxProfile {
+    /// Syscalls refused with `EPERM`.
+    fn denied_syscalls(self) -> Vec<&'static str> {
+        let mut denied = Vec::new();
+        if self >= SandboxProfile::Permissive {
+            denied.extend([
+                "kexec_load", "kexec_file_load", "init_module", "finit_module", "delete_module", "reboot",
+                "swapon", "swapoff", "acct", "settimeofday", "clock_settime", "clock_adjtime", "adjtimex",
+                "syslog", "quotactl",
+            ]);
+        }
+        if self >= SandboxProfile::Standard {
+            denied.extend([
+                "ptrace", "process_vm_readv", "process_vm_writev", "bpf", "perf_event_open", "userfaultfd",
+                "open_by_handle_at", "keyctl", "add_key", "request_key",
+            ]);
+        }
+        if self >= SandboxProfile::Strict {
+            denied.extend([
+                "mount", "umount2", "pivot_root", "chroot", "unshare", "setns", "fsopen", "fsconfig", "fsmount",
+                "fspick", "move_mount", "open_tree", "mount_setattr",
+            ]);
+        }
+        denied
+    }
+
+    /// Whether new namespaces are refused, which `clone` asks for with its
+    /// flags. `clone3` passes them in memory seccomp cannot read, so it is
+    /// refused with `ENOSYS` and callers fall back to `clone`.
+    fn denies_namespaces(self) -> bool {
+        self >= SandboxProfile::Strict
+    }
+
+    /// Directories readable, and directories writable, under Landlock,
+    /// given the user's home. `None` leaves the filesystem unrestricted.
+    fn filesystem(self, home: Option<PathBuf>) -> Option<(Vec<PathBuf>, Vec<PathBuf>)> {
+        let paths = |paths: &[&str]| paths.iter().map(PathBuf::from).collect::<Vec<_>>();
+        let mut write = paths(&["/tmp", "/var/tmp", "/run/user", "/dev/shm"]);
+        write.extend(home);
+        match self {
+            SandboxProfile::Off | SandboxProfile::Permissive => None,
+            SandboxProfile::Standard => {
+                write.extend(paths(&["/dev", "/proc"]));
+                Some((paths(&["/"]), write))
+            }
+            SandboxProfile::Strict => {
+                write.extend(paths(&["/dev/null", "/dev/pts", "/dev/dri", "/dev/snd"]));
+                let read = paths(&[
+                    "/usr", "/etc", "/bin", "/sbin", "/lib", "/lib32", "/lib64", "/opt", "/proc", "/sys",
+                    "/dev", "/run", "/var/lib", "/var/cache",
+                ]);
+                Some((read, write))
+            }
+        }
+    }
+}
+
+impl fmt::Display for SandboxProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SandboxProfile::Off => "off",
+            SandboxProfile::Permissive => "permissive",
+            SandboxProfile::Standard => "standard",
+            SandboxProfile::Strict => "strict",
+        })
+    }
+}
+
+impl FromStr for SandboxProfile {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "off" => Ok(Self::Off),
+            "permissive" => Ok(Self::Permissive),
+            "standard" => Ok(Self::Standard),
+            "strict" => Ok(Self::Strict),
+            _ => anyhow::bail!("unknown sandbox profile {s:?}, expected off, permissive, standard or strict"),
+        }
+    }
+}
+
+/// Directories hidden from the desktop's programs, and paths beneath them
+/// they still see.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HiddenPaths {
+    /// Directories that look empty
+    pub hide: Vec<PathBuf>,
+    /// Paths beneath them that stay in place
+    pub keep: Vec<PathBuf>,
+}
+
+/// Sandbox of the desktops.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SandboxConfig {
+    /// Profile the xpra process tree runs under
+    #[serde(default)]
+    pub profile: SandboxProfile,
+
+    /// Further paths readable under the standard and strict profiles
+    #[serde(default)]
+    pub allow_read: Vec<PathBuf>,
+
+    /// Further paths writable under the standard and strict profiles
+    #[serde(default)]
+    pub allow_write: Vec<PathBuf>,
+}
+
+impl SandboxConfig {
+    /// Command and arguments that run a program in the sandbox on `host`,
+    /// with `hidden` out of sight.
+    pub fn command_prefix(&self, host: &WorkerHost, hidden: &HiddenPaths) -> Vec<String> {
+        if self.profile == SandboxProfile::Off && hidden.hide.is_empty() {
+            return Vec::new();
+        }
+        // Worker hosts run the ssh-desktop installed there
+        let program = match host.kind {
+            HostKind::Local => std::env::current_exe().ok().map(|exe| exe.to_string_lossy().into_owned()),
+            HostKind::Ssh { .. } => None,
+        };
+        let mut prefix = vec![
+            program.unwrap_or_else(|| "ssh-desktop".to_string()),
+            "sandbox".to_string(),
+            "--profile".to_string(),
+            self.profile.to_string(),
+        ];
+        for path in &self.allow_read {
+            prefix.extend(["--read".to_string(), path.to_string_lossy().into_owned()]);
+        }
+        for path in &self.allow_write {
+            prefix.extend(["--write".to_string(), path.to_string_lossy().into_owned()]);
+        }
+        for path in &hidden.hide {
+            prefix.extend(["--hide".to_string(), path.to_string_lossy().into_owned()]);
+        }
+        for path in &hidden.keep {
+            prefix.extend(["--keep".to_string(), path.to_string_lossy().into_owned()]);
+        }
+        prefix.push("--".to_string());
+        prefix
+    }
+}
+
+/// Sandbox this process with `profile` and the further paths, hiding
+/// `hidden`, then run `command` in its place. Only returns on failure.
+pub fn exec(
+    profile: SandboxProfile,
+    allow_read: &[PathBuf],
+    allow_write: &[PathBuf],
+    hidden: &HiddenPaths,
+    command: &[String],
+) -> anyhow::Error {
+    use std::os::unix::process::CommandExt;
+
+    let Some((program, args)) = command.split_first() else {
+        return anyhow::anyhow!("no command to run in the sandbox");
+    };
+    // Hidden first, as the strict profile refuses mounts
+    if let Err(e) = hide(hidden) {
+        return e.context("failed to hide paths from the sandbox");
+    }
+    if let Err(e) = apply(profile, allow_read, allow_write) {
+        return e.context(format!("failed to apply the {profile} sandbox"));
+    }
+    let e = std::process::Command::new(program).args(args).exec();
+    anyhow::Error::new(e).context(format!("failed to run {program}"))
+}
+
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn apply(profile: SandboxProfile, allow_read: &[PathBuf], allow_write: &[PathBuf]) -> anyhow::Result<()> {
+    if profile == SandboxProfile::Off {
+        return Ok(());
+    }
+    linux::no_new_privs()?;
+    let home = std::env::var_os("HOME").map(PathBuf::from);
+    if let Some((mut read, mut write)) = profile.filesystem(home) {
+        read.extend(allow_read.iter().cloned());
+        write.extend(allow_write.iter().cloned());
+        if !linux::restrict_filesystem(&read, &write)? {
+            tracing::warn!("Landlock is not supported by this kernel, only filtering syscalls");
+        }
+    }
+    let denied: Vec<i64> = profile.denied_syscalls().into_iter().filter_map(linux::syscall_number).collect();
+    linux::install_filter(&linux::filter_program(&denied, profile.denies_namespaces()))
+}
+
+#[cfg(target_os = "linux")]
+fn hide(hidden: &HiddenPaths) -> anyhow::Result<()> {
+    mounts::hide_paths(&hidden.hide, &hidden.keep)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn hide(hidden: &HiddenPaths) -> anyhow::Result<()> {
+    match hidden.hide.is_empty() {
+        true => Ok(()),
+        false => anyhow::bail!("hiding paths is only supported on Linux"),
+    }
+}
+
+#[cfg(not(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64"))))]
+fn apply(profile: SandboxProfile, _allow_read: &[PathBuf], _allow_write: &[PathBuf]) -> anyhow::Result<()> {
+    match profile {
+        SandboxProfile::Off => Ok(()),
+        _ => anyhow::bail!("sandboxing is only supported on x86_64 and aarch64 Linux"),
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[allow(unsafe_code)]
+mod mounts {
+    //! The raw namespace and mount interfaces.
+
+    use std::ffi::CString;
+    use std::fs::File;
+    use std::os::fd::AsRawFd;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::path::{Path, PathBuf};
+    use anyhow::{Context, Result};
+    use nix::libc;
+
+    /// Mount an empty directory over each of `hide` in a new mount
+    /// namespace, and each of `keep` back in place. Directories missing on
+    /// this host hide nothing.
+    pub fn hide_paths(hide: &[PathBuf], keep: &[PathBuf]) -> Result<()> {
+        let hide: Vec<&PathBuf> = hide.iter().filter(|path| path.is_dir()).collect();
+        if hide.is_empty() {
+            return Ok(());
+        }
+        // Safety: getuid and getgid cannot fail
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        let flags = match uid {
+            0 => libc::CLONE_NEWNS,
+            _ => libc::CLONE_NEWUSER | libc::CLONE_NEWNS,
+        };
+        // Safety: unshare takes no pointers
+        if unsafe { libc::unshare(flags) } != 0 {
+            return Err(std::io::Error::last_os_error()).context("failed to enter a mount namespace");
+        }
+        if uid != 0 {
+            std::fs::write("/proc/self/uid_map", format!("{uid} {uid} 1")).context("failed to map the user id")?;
+            std::fs::write("/proc/self/setgroups", "deny").context("failed to deny setgroups")?;
+            std::fs::write("/proc/self/gid_map", format!("{gid} {gid} 1")).context("failed to map the group id")?;
+        }
+        // Nothing mounted here shows outside
+        mount(None, Path::new("/"), None, libc::MS_REC | libc::MS_PRIVATE, None)?;
+        // Kept paths are opened before they are hidden, to mount them back,
+        // and in the namespace, as only its own mounts can be bound
+        let kept: Vec<(&PathBuf, File)> = keep
+            .iter()
+            .map(|path| {
+                let file = File::options().read(true).custom_flags(libc::O_PATH | libc::O_CLOEXEC).open(path);
+                Ok((path, file.with_context(|| format!("failed to open {}", path.display()))?))
+            })
+            .collect::<Result<_>>()?;
+        for dir in hide {
+            let flags = libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC;
+            mount(Some(Path::new("tmpfs")), dir, Some("tmpfs"), flags, Some("mode=0700"))?;
+        }
+        for (path, file) in kept {
+            match file.metadata()?.is_dir() {
+                true => std::fs::create_dir_all(path)?,
+                false => {
+                    std::fs::create_dir_all(path.parent().unwrap_or(Path::new("/")))?;
+                    File::create(path)?;
+                }
+            }
+            let source = PathBuf::from(format!("/proc/self/fd/{}", file.as_raw_fd()));
+            mount(Some(&source), path, None, libc::MS_BIND | libc::MS_REC, None)?;
+        }
+        Ok(())
+    }
+
+    fn mount(source: Option<&Path>, target: &Path, fstype: Option<&str>, flags: libc::c_ulong, data: Option<&str>) -> Result<()> {
+        let cstr = |bytes: &[u8]| CString::new(bytes).context("path with a NUL byte");
+        let source = source.map(|source| cstr(source.as_os_str().as_bytes())).transpose()?;
+        let target_c = cstr(target.as_os_str().as_bytes())?;
+        let fstype = fstype.map(|fstype| cstr(fstype.as_bytes())).transpose()?;
+        let data = data.map(|data| cstr(data.as_bytes())).transpose()?;
+        let ptr = |s: &Option<CString>| s.as_ref().map_or(std::ptr::null(), |s| s.as_ptr());
+        // Safety: the strings outlive the call, and null pointers are
+        // allowed for the unused arguments
+        let result = unsafe {
+            libc::mount(ptr(&source), target_c.as_ptr(), ptr(&fstype), flags, ptr(&data) as *const libc::c_void)
+        };
+        if result != 0 {
+            return Err(std::io::Error::last_os_error()).with_context(|| format!("failed to mount {}", target.display()));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+#[allow(unsafe_code)]
+mod linux {
+    //! The raw seccomp and Landlock interfaces.
+
+    use std::fs::File;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::path::{Path, PathBuf};
+    use anyhow::{Context, Result};
+    use nix::libc;
+
+    #[cfg(target_arch = "x86_64")]
+    const AUDIT_ARCH: u32 = 0xc000_003e;
+    #[cfg(target_arch = "aarch64")]
+    const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+    /// Syscall numbers at or above this are of the x32 ABI.
+    #[cfg(target_arch = "x86_64")]
+    const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+    const BPF_LD_W_ABS: u16 = 0x20;
+    const BPF_JEQ_K: u16 = 0x15;
+    const BPF_JGE_K: u16 = 0x35;
+    const BPF_JSET_K: u16 = 0x45;
+    const BPF_RET_K: u16 = 0x06;
+    const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+    const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+
+    /// Flags of `clone` creating namespaces. `CLONE_NEWTIME` is only taken
+    /// by `clone3` and `unshare`, and its bit is part of the exit signal to
+    /// `clone`.
+    const CLONE_NEW_FLAGS: libc::c_int = libc::CLONE_NEWNS
+        | libc::CLONE_NEWCGROUP
+        | libc::CLONE_NEWUTS
+        | libc::CLONE_NEWIPC
+        | libc::CLONE_NEWUSER
+        | libc::CLONE_NEWPID
+        | libc::CLONE_NEWNET;
+
+    // Landlock filesystem rights, by ABI version
+    const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+    const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+    const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+    const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+    const ACCESS_FS_V1: u64 = (1 << 13) - 1;
+    const ACCESS_FS_REFER: u64 = 1 << 13;
+    const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+    const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1;
+    const LANDLOCK_RULE_PATH_BENEATH: u32 = 1;
+
+    #[repr(C)]
+    struct RulesetAttr {
+        handled_access_fs: u64,
+    }
+
+    #[repr(C, packed)]
+    struct PathBeneathAttr {
+        allowed_access: u64,
+        parent_fd: i32,
+    }
+
+    pub fn no_new_privs() -> Result<()> {
+        // Safety: prctl with integer arguments has no memory effects
+        if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+            return Err(std::io::Error::last_os_error()).context("failed to set no_new_privs");
+        }
+        Ok(())
+    }
+
+    /// Number of a syscall by name, if this architecture has it.
+    pub fn syscall_number(name: &str) -> Option<i64> {
+        let number = match name {
+            "kexec_load" => libc::SYS_kexec_load,
+            "kexec_file_load" => libc::SYS_kexec_file_load,
+            "init_module" => libc::SYS_init_module,
+            "finit_module" => libc::SYS_finit_module,
+            "delete_module" => libc::SYS_delete_module,
+            "reboot" => libc::SYS_reboot,
+            "swapon" => libc::SYS_swapon,
+            "swapoff" => libc::SYS_swapoff,
+            "acct" => libc::SYS_acct,
+            "settimeofday" => libc::SYS_settimeofday,
+            "clock_settime" => libc::SYS_clock_settime,
+            "clock_adjtime" => libc::SYS_clock_adjtime,
+            "adjtimex" => libc::SYS_adjtimex,
+            "syslog" => libc::SYS_syslog,
+            "quotactl" => libc::SYS_quotactl,
+            "ptrace" => libc::SYS_ptrace,
+            "process_vm_readv" => libc::SYS_process_vm_readv,
+            "process_vm_writev" => libc::SYS_process_vm_writev,
+            "bpf" => libc::SYS_bpf,
+            "perf_event_open" => libc::SYS_perf_event_open,
+            "userfaultfd" => libc::SYS_userfaultfd,
+            "open_by_handle_at" => libc::SYS_open_by_handle_at,
+            "keyctl" => libc::SYS_keyctl,
+            "add_key" => libc::SYS_add_key,
+            "request_key" => libc::SYS_request_key,
+            "mount" => libc::SYS_mount,
+            "umount2" => libc::SYS_umount2,
+            "pivot_root" => libc::SYS_pivot_root,
+            "chroot" => libc::SYS_chroot,
+            "unshare" => libc::SYS_unshare,
+            "setns" => libc::SYS_setns,
+            "fsopen" => libc::SYS_fsopen,
+            "fsconfig" => libc::SYS_fsconfig,
+            "fsmount" => libc::SYS_fsmount,
+            "fspick" => libc::SYS_fspick,
+            "move_mount" => libc::SYS_move_mount,
+            "open_tree" => libc::SYS_open_tree,
+            "mount_setattr" => libc::SYS_mount_setattr,
+            _ => return None,
+        };
+        Some(number)
+    }
+
+    /// BPF program refusing the `denied` syscalls with `EPERM`, and with
+    /// `namespaces` `clone` into new namespaces too, and killing processes
+    /// calling in with another architecture's numbers.
+    pub fn filter_program(denied: &[i64], namespaces: bool) -> Vec<libc::sock_filter> {
+        let statement = |code, k| libc::sock_filter { code, jt: 0, jf: 0, k };
+        let jump = |k, jt, jf| libc::sock_filter { code: BPF_JEQ_K, jt, jf, k };
+        let mut program = vec![
+            statement(BPF_LD_W_ABS, 4),
+            jump(AUDIT_ARCH, 1, 0),
+            statement(BPF_RET_K, SECCOMP_RET_KILL_PROCESS),
+            statement(BPF_LD_W_ABS, 0),
+        ];
+        #[cfg(target_arch = "x86_64")]
+        program.extend([
+            libc::sock_filter { code: BPF_JGE_K, jt: 0, jf: 1, k: X32_SYSCALL_BIT },
+            statement(BPF_RET_K, libc::SECCOMP_RET_ERRNO | libc::EPERM as u32),
+        ]);
+        if namespaces {
+            program.extend([
+                jump(libc::SYS_clone3 as u32, 0, 1),
+                statement(BPF_RET_K, libc::SECCOMP_RET_ERRNO | libc::ENOSYS as u32),
+                // The flags are the low word of clone's first argument
+                jump(libc::SYS_clone as u32, 0, 4),
+                statement(BPF_LD_W_ABS, 16),
+                libc::sock_filter { code: BPF_JSET_K, jt: 0, jf: 1, k: CLONE_NEW_FLAGS as u32 },
+                statement(BPF_RET_K, libc::SECCOMP_RET_ERRNO | libc::EPERM as u32),
+                statement(BPF_RET_K, SECCOMP_RET_ALLOW),
+            ]);
+        }
+        for number in denied {
+            program.push(jump(*number as u32, 0, 1));
+            program.push(statement(BPF_RET_K, libc::SECCOMP_RET_ERRNO | libc::EPERM as u32));
+        }
+        program.push(statement(BPF_RET_K, SECCOMP_RET_ALLOW));
+        program
+    }
+
+    /// Install `program` as this thread's seccomp filter, inherited by
+    /// every process it starts. Allocates nothing, so a forked child can
+    /// call it.
+    pub fn install_filter(program: &[libc::sock_filter]) -> Result<()> {
+        let prog = libc::sock_fprog { len: program.len() as u16, filter: program.as_ptr() as *mut libc::sock_filter };
+        // Safety: the kernel copies the program, which outlives the call
+        let result = unsafe {
+            libc::prctl(libc::PR_SET_SECCOMP, libc::SECCOMP_MODE_FILTER, &prog as *const libc::sock_fprog, 0, 0)
+        };
+        if result != 0 {
+            return Err(std::io::Error::last_os_error()).context("failed to install the seccomp filter");
+        }
+        Ok(())
+    }
+
+    /// Allow reading and executing beneath `read` and everything beneath
+    /// `write`, and nothing elsewhere. Returns false if the kernel has no
+    /// Landlock.
+    pub fn restrict_filesystem(read: &[PathBuf], write: &[PathBuf]) -> Result<bool> {
+        // Safety: asking for the ABI version takes no pointers
+        let abi = unsafe {
+            libc::syscall(libc::SYS_landlock_create_ruleset, std::ptr::null::<RulesetAttr>(), 0, LANDLOCK_CREATE_RULESET_VERSION)
+        };
+        if abi < 1 {
+            return Ok(false);
+        }
+        let mut handled = ACCESS_FS_V1;
+        if abi >= 2 {
+            handled |= ACCESS_FS_REFER;
+        }
+        if abi >= 3 {
+            handled |= ACCESS_FS_TRUNCATE;
+        }
+        let attr = RulesetAttr { handled_access_fs: handled };
+        // Safety: attr is a valid ruleset attribute of the given size
+        let fd = unsafe {
+            libc::syscall(libc::SYS_landlock_create_ruleset, &attr as *const RulesetAttr, std::mem::size_of::<RulesetAttr>(), 0)
+        };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error()).context("failed to create the landlock ruleset");
+        }
+        // Safety: the kernel just returned this fd to us
+        let ruleset = unsafe { OwnedFd::from_raw_fd(fd as i32) };
+
+        let read_access = ACCESS_FS_EXECUTE | ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR;
+        for (paths, access) in [(read, read_access), (write, handled)] {
+            for path in paths {
+                add_rule(&ruleset, path, access)?;
+            }
+        }
+        // Safety: the ruleset fd is valid and no_new_privs is set
+        if unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0) } != 0 {
+            return Err(std::io::Error::last_os_error()).context("failed to restrict the filesystem");
+        }
+        Ok(true)
+    }
+
+    fn add_rule(ruleset: &OwnedFd, path: &Path, access: u64) -> Result<()> {
+        // Paths missing on this host are skipped
+        let Ok(file) = File::options().read(true).custom_flags(libc::O_PATH | libc::O_CLOEXEC).open(path) else {
+            return Ok(());
+        };
+        let is_dir = file.metadata().map(|m| m.is_dir()).unwrap_or(false);
+        // Rights on directory contents cannot be given on a file
+        let file_access = ACCESS_FS_EXECUTE | ACCESS_FS_WRITE_FILE | ACCESS_FS_READ_FILE | ACCESS_FS_TRUNCATE;
+        let allowed_access = if is_dir { access } else { access & file_access };
+        let attr = PathBeneathAttr { allowed_access, parent_fd: file.as_raw_fd() };
+        // Safety: attr is a valid rule of the given type and its fd stays
+        // open for the call
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_add_rule,
+                ruleset.as_raw_fd(),
+                LANDLOCK_RULE_PATH_BENEATH,
+                &attr as *const PathBeneathAttr,
+                0,
+            )
+        };
+        if result != 0 {
+            return Err(std::io::Error::last_os_error()).with_context(|| format!("failed to allow {}", path.display()));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::xpra_config::XpraConfig;
+    use crate::xpra_pool::DisplayPool;
+
+    #[test]
+    fn test_profiles() {
+        assert!(SandboxProfile::Off.denied_syscalls().is_empty());
+        let standard = SandboxProfile::Standard.denied_syscalls();
+        assert!(standard.contains(&"kexec_load") && standard.contains(&"ptrace"));
+        assert!(!standard.contains(&"unshare"));
+        assert!(SandboxProfile::Strict.denied_syscalls().contains(&"unshare"));
+
+        assert_eq!(SandboxProfile::Permissive.filesystem(None), None);
+        let (read, write) = SandboxProfile::Strict.filesystem(Some(PathBuf::from("/home/alice"))).unwrap();
+        assert!(read.contains(&PathBuf::from("/usr")) && !read.contains(&PathBuf::from("/")));
+        assert!(write.contains(&PathBuf::from("/home/alice")));
+        assert_eq!("strict".parse::<SandboxProfile>().unwrap(), SandboxProfile::Strict);
+        assert!("paranoid".parse::<SandboxProfile>().is_err());
+    }
+
+    #[test]
+    fn test_command_prefix() {
+        let host = WorkerHost::local(&XpraConfig::default(), DisplayPool::new());
+        assert!(SandboxConfig::default().command_prefix(&host, &HiddenPaths::default()).is_empty());
+        let config = SandboxConfig {
+            profile: SandboxProfile::Strict,
+            allow_read: vec![PathBuf::from("/srv/data")],
+            ..SandboxConfig::default()
+        };
+        let prefix = config.command_prefix(&host, &HiddenPaths::default());
+        assert_eq!(prefix[1..], ["sandbox", "--profile", "strict", "--read", "/srv/data", "--"]);
+
+        // Hidden paths need the sandbox even without a profile
+        let hidden = HiddenPaths { hide: vec![PathBuf::from("/p")], keep: vec![PathBuf::from("/p/alice")] };
+        let prefix = SandboxConfig::default().command_prefix(&host, &hidden);
+        assert_eq!(prefix[1..], ["sandbox", "--profile", "off", "--hide", "/p", "--keep", "/p/alice", "--"]);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[allow(unsafe_code)]
+    #[test]
+    fn test_hide_paths() {
+        use std::os::unix::process::CommandExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let profiles = dir.path().join("profiles");
+        for user in ["alice", "bob"] {
+            std::fs::create_dir_all(profiles.join(user)).unwrap();
+            std::fs::write(profiles.join(user).join("cookies"), user).unwrap();
+        }
+        let (hide, keep) = (vec![profiles.clone()], vec![profiles.join("alice")]);
+        let mut command = std::process::Command::new("sh");
+        command.arg("-c").arg("ls \"$0\"; cat \"$0/alice/cookies\"").arg(&profiles);
+        // Safety: the child is single-threaded between fork and exec
+        unsafe {
+            command.pre_exec(move || mounts::hide_paths(&hide, &keep).map_err(std::io::Error::other));
+        }
+        // Namespaces are not available everywhere tests run
+        let output = match command.output() {
+            Ok(output) => output,
+            Err(e) => return eprintln!("skipped: {e}"),
+        };
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "alice\nalice");
+        // Nothing changed outside
+        assert!(profiles.join("bob/cookies").exists());
+    }
+
+    #[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+    #[test]
+    fn test_filter_program() {
+        let denied: Vec<i64> = SandboxProfile::Strict
+            .denied_syscalls()
+            .into_iter()
+            .filter_map(linux::syscall_number)
+            .collect();
+        assert_eq!(denied.len(), SandboxProfile::Strict.denied_syscalls().len());
+        let program = linux::filter_program(&denied, true);
+        // Each denied syscall is a comparison and a return
+        assert!(program.len() >= 2 * denied.len() + 12);
+        assert_eq!(program.last().unwrap().k, 0x7fff_0000);
+        assert_eq!(linux::filter_program(&denied, false).len(), program.len() - 7);
+    }
+
+    #[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+    #[allow(unsafe_code)]
+    #[test]
+    fn test_strict_filter_denies_namespaces_and_mounts() {
+        use nix::libc;
+        use nix::sys::wait::{waitpid, WaitStatus};
+        use nix::unistd::{fork, ForkResult};
+
+        let profile = SandboxProfile::Strict;
+        let denied: Vec<i64> = profile.denied_syscalls().into_iter().filter_map(linux::syscall_number).collect();
+        let program = linux::filter_program(&denied, profile.denies_namespaces());
+        let fsname = c"tmpfs";
+        let root = c"/";
+        // Safety: the child only makes raw syscalls before exiting
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Child => unsafe {
+                let errno = || *libc::__errno_location();
+                let refused = |result: libc::c_long, expected: libc::c_int| result == -1 && errno() == expected;
+                if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 || linux::install_filter(&program).is_err() {
+                    libc::_exit(100);
+                }
+                let checks = [
+                    refused(libc::syscall(libc::SYS_clone, libc::CLONE_NEWUSER | libc::SIGCHLD, 0, 0, 0, 0), libc::EPERM),
+                    refused(libc::syscall(libc::SYS_clone, libc::CLONE_NEWNET | libc::SIGCHLD, 0, 0, 0, 0), libc::EPERM),
+                    refused(libc::syscall(libc::SYS_clone3, std::ptr::null::<u8>(), 0), libc::ENOSYS),
+                    refused(libc::syscall(libc::SYS_unshare, libc::CLONE_NEWNS), libc::EPERM),
+                    refused(libc::syscall(libc::SYS_fsopen, fsname.as_ptr(), 0), libc::EPERM),
+                    refused(libc::syscall(libc::SYS_fsmount, -1, 0, 0), libc::EPERM),
+                    refused(libc::syscall(libc::SYS_move_mount, -1, root.as_ptr(), -1, root.as_ptr(), 0), libc::EPERM),
+                    refused(libc::syscall(libc::SYS_open_tree, libc::AT_FDCWD, root.as_ptr(), 0), libc::EPERM),
+                ];
+                if let Some(failed) = checks.iter().position(|refused| !refused) {
+                    libc::_exit(1 + failed as i32);
+                }
+                // Plain clones, as fork, still work
+                match libc::syscall(libc::SYS_clone, libc::SIGCHLD, 0, 0, 0, 0) {
+                    0 => libc::_exit(0),
+                    -1 => libc::_exit(99),
+                    child => {
+                        let mut status = 0;
+                        libc::waitpid(child as libc::pid_t, &mut status, 0);
+                        libc::_exit(0);
+                    }
+                }
+            },
+            ForkResult::Parent { child } => {
+                // Exits with the position of the first syscall allowed
+                assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 0));
+            }
+        }
+    }
+}
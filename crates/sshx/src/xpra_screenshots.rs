@@ -0,0 +1,384 @@
+//! Compliance recording of desktops as screenshots taken at an interval.
+//!
+//! Recording the whole stream of every desktop is costly to keep; many
+//! audits only need to see what a desktop showed now and then. With
+//! `screenshots.interval_minutes` set, each active session's display is
+//! captured that often into an archive on this host, a directory per day,
+//! and indexed in `index.jsonl` by session, user and time. Screenshots past
+//! `retention_days` are removed with their index entries, hourly.
+//! `ssh-desktop audit screenshots` lists them.
+//!
+//! Users are told: sessions started while screenshots are on are marked
+//! `recording` in the status, and their desktop shows `notice` in a dialog
+//! when it starts. Dismissing it is logged to the history log as the
+//! user's acknowledgement, and no screenshot of a session is taken before
+//! then; sessions whose user never acknowledged it, like headless ones,
+//! are not captured.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use futures_util::{stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::time;
+use tracing::{debug, info, warn};
+
+use crate::xpra_children::{ChildProgram, RestartPolicy};
+use crate::xpra_context::XpraContext;
+use crate::xpra_hosts::WorkerHost;
+use crate::xpra_motd::dialog;
+use crate::xpra_session_state::SessionState;
+
+/// Index of the screenshots, in the archive
+pub const INDEX_FILE: &str = "index.jsonl";
+
+/// How often screenshots past their retention are looked for.
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Time a screenshot may take before it is given up on.
+const CAPTURE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Screenshots taken at the same time.
+const CONCURRENT_CAPTURES: usize = 8;
+
+/// Screenshots of the active sessions, taken at an interval.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScreenshotConfig {
+    /// Minutes between screenshots of each active session (0 = off)
+    #[serde(default)]
+    pub interval_minutes: u64,
+
+    /// Directory of the archive on this host
+    #[serde(default = "default_dir")]
+    pub dir: PathBuf,
+
+    /// Days screenshots are kept (0 = forever)
+    #[serde(default = "default_retention_days")]
+    pub retention_days: u64,
+
+    /// Title of the notice shown on recorded desktops
+    #[serde(default = "default_notice_title")]
+    pub notice_title: String,
+
+    /// Text of the notice shown on recorded desktops, with an `{interval}`
+    /// placeholder for the minutes between screenshots
+    #[serde(default = "default_notice")]
+    pub notice: String,
+}
+
+fn default_dir() -> PathBuf { PathBuf::from("/var/lib/sshx/screenshots") }
+
+fn default_retention_days() -> u64 { 90 }
+
+fn default_notice_title() -> String { "Recording".to_string() }
+
+fn default_notice() -> String {
+    "This desktop is recorded: a screenshot of it is taken every {interval} minutes and kept for auditing."
+        .to_string()
+}
+
+impl Default for ScreenshotConfig {
+    fn default() -> Self {
+        Self {
+            interval_minutes: 0,
+            dir: default_dir(),
+            retention_days: default_retention_days(),
+            notice_title: default_notice_title(),
+            notice: default_notice(),
+        }
+    }
+}
+
+impl ScreenshotConfig {
+    /// Whether screenshots of the sessions are taken.
+    pub fn enabled(&self) -> bool {
+        self.interval_minutes > 0
+    }
+
+    /// The program showing recorded desktops the notice, if screenshots are
+    /// on. It exits successfully once the user acknowledged the notice.
+    pub fn notice_program(&self) -> Option<ChildProgram> {
+        if !self.enabled() {
+            return None;
+        }
+        let text = self.notice.replace("{interval}", &self.interval_minutes.to_string());
+        let command = dialog(&self.notice_title, &text);
+        Some(ChildProgram { name: "recording-notice".to_string(), command, restart: RestartPolicy::Never })
+    }
+
+    /// Capture the active sessions of `context` and expire old screenshots
+    /// in the background, if screenshots are on.
+    pub fn start(&self, context: &Arc<XpraContext>) {
+        if !self.enabled() {
+            return;
+        }
+        let interval = Duration::from_secs(self.interval_minutes.saturating_mul(60));
+        // Retentions longer than chrono can represent keep screenshots forever
+        let retention = (self.retention_days > 0)
+            .then(|| i64::try_from(self.retention_days).ok().and_then(chrono::Duration::try_days))
+            .flatten();
+        let archive = ScreenshotArchive::new(self.dir.clone());
+        info!(dir = %archive.dir.display(), "Taking screenshots of active sessions every {}m", self.interval_minutes);
+        let context = context.clone();
+        tokio::spawn(async move {
+            let mut ticks = time::interval(interval);
+            ticks.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+            let mut expired_at: Option<Instant> = None;
+            loop {
+                ticks.tick().await;
+                capture_sessions(&context, &archive).await;
+                let Some(retention) = retention else {
+                    continue;
+                };
+                if expired_at.is_some_and(|at| at.elapsed() < EXPIRY_INTERVAL) {
+                    continue;
+                }
+                expired_at = Some(Instant::now());
+                match archive.expire(Utc::now() - retention).await {
+                    Ok(0) => {}
+                    Ok(removed) => info!(removed, "Removed screenshots past their retention"),
+                    Err(e) => warn!("Failed to expire screenshots: {:#}", e),
+                }
+            }
+        });
+    }
+}
+
+/// Take a screenshot of every active recorded session into `archive`.
+async fn capture_sessions(context: &XpraContext, archive: &ScreenshotArchive) {
+    let sessions = context.monitor.collect_sessions(|session_id, info| {
+        let active = info.recording && info.recording_acknowledged && info.lifecycle.state() == SessionState::Active;
+        active.then(|| (session_id.to_string(), info.user.clone(), info.host.clone(), info.display))
+    });
+    // Sessions are captured a few at a time, so one slow host does not
+    // hold up the others; the index is written one entry at a time
+    let mut captures = stream::iter(sessions)
+        .map(|(session_id, user, host_name, display)| async move {
+            let host = context.hosts.get(&host_name)?;
+            match time::timeout(CAPTURE_TIMEOUT, capture(&host, display)).await {
+                Ok(Ok(png)) => {
+                    let entry = ScreenshotEntry::new(Utc::now(), &session_id, &user, &host_name, display, png.len() as u64);
+                    Some((entry, png))
+                }
+                Ok(Err(e)) => {
+                    warn!(session_id, "Failed to take a screenshot: {:#}", e);
+                    None
+                }
+                Err(_) => {
+                    warn!(session_id, "Screenshot took longer than {}s", CAPTURE_TIMEOUT.as_secs());
+                    None
+                }
+            }
+        })
+        .buffer_unordered(CONCURRENT_CAPTURES);
+    while let Some(capture) = captures.next().await {
+        let Some((entry, png)) = capture else {
+            continue;
+        };
+        let session_id = entry.session_id.clone();
+        match archive.record(entry, &png).await {
+            Ok(entry) => debug!(session_id, path = %entry.path.display(), "Archived screenshot"),
+            Err(e) => warn!(session_id, "Failed to archive a screenshot: {:#}", e),
+        }
+    }
+}
+
+/// PNG screenshot of display `number` on `host`.
+pub(crate) async fn capture(host: &WorkerHost, number: u16) -> Result<Vec<u8>> {
+    // The screenshot is taken on the session's host and streamed back.
+    // Commands on worker hosts run on a pty, so turn off output
+    // processing to keep the PNG intact.
+    let script = format!(
+        r#"stty raw -echo 2>/dev/null
+f=$(mktemp) || exit 1
+xpra screenshot "$f.png" :{number} >/dev/null 2>&1 && cat "$f.png"
+status=$?
+rm -f "$f" "$f.png"
+exit $status"#
+    );
+    let output = tokio::process::Command::from(host.command("sh", &["-c".to_string(), script]))
+        .kill_on_drop(true)
+        .output()
+        .await
+        .context("failed to run xpra screenshot")?;
+    if !output.status.success() || output.stdout.is_empty() {
+        anyhow::bail!("xpra screenshot of :{} failed", number);
+    }
+    Ok(output.stdout)
+}
+
+/// A screenshot in the archive.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScreenshotEntry {
+    /// When the screenshot was taken
+    pub timestamp: DateTime<Utc>,
+    /// Session shown
+    pub session_id: String,
+    /// User owning the session
+    pub user: String,
+    /// Host running the session's display
+    pub host: String,
+    /// Display number of the session
+    pub display: u16,
+    /// The PNG, relative to the archive
+    pub path: PathBuf,
+    /// Size of the PNG
+    pub bytes: u64,
+}
+
+impl ScreenshotEntry {
+    /// Entry of a screenshot of `session_id` taken at `timestamp`.
+    fn new(timestamp: DateTime<Utc>, session_id: &str, user: &str, host: &str, display: u16, bytes: u64) -> Self {
+        let name = format!("{}-{}.png", session_id.replace(['/', '\\'], "_"), timestamp.format("%H%M%S"));
+        Self {
+            timestamp,
+            session_id: session_id.to_string(),
+            user: user.to_string(),
+            host: host.to_string(),
+            display,
+            path: Path::new(&timestamp.format("%Y-%m-%d").to_string()).join(name),
+            bytes,
+        }
+    }
+}
+
+/// Directory of screenshots with their index.
+#[derive(Debug, Clone)]
+pub struct ScreenshotArchive {
+    dir: PathBuf,
+}
+
+impl ScreenshotArchive {
+    /// The archive in `dir`.
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    /// Save `png` and add `entry` to the index.
+    async fn record(&self, entry: ScreenshotEntry, png: &[u8]) -> Result<ScreenshotEntry> {
+        let path = self.dir.join(&entry.path);
+        if let Some(parent) = path.parent() {
+            // Screenshots are only for the auditors
+            tokio::fs::DirBuilder::new().recursive(true).mode(0o700).create(parent).await?;
+        }
+        tokio::fs::write(&path, png).await.with_context(|| format!("failed to write {}", path.display()))?;
+        let mut line = serde_json::to_string(&entry)?;
+        line.push('\n');
+        let mut index = tokio::fs::OpenOptions::new().create(true).append(true).open(self.dir.join(INDEX_FILE)).await?;
+        index.write_all(line.as_bytes()).await?;
+        Ok(entry)
+    }
+
+    /// Screenshots taken from `since` until `until`, of `user` and of
+    /// `session_id` if given, oldest first.
+    pub async fn entries(
+        &self,
+        since: Option<DateTime<Utc>>,
+        until: Option<DateTime<Utc>>,
+        user: Option<&str>,
+        session_id: Option<&str>,
+    ) -> Result<Vec<ScreenshotEntry>> {
+        let mut entries: Vec<ScreenshotEntry> = self
+            .read_index()
+            .await?
+            .into_iter()
+            .filter(|entry| since.is_none_or(|since| entry.timestamp >= since))
+            .filter(|entry| until.is_none_or(|until| entry.timestamp < until))
+            .filter(|entry| user.is_none_or(|user| entry.user == user))
+            .filter(|entry| session_id.is_none_or(|session_id| entry.session_id == session_id))
+            .collect();
+        entries.sort_by_key(|entry| entry.timestamp);
+        Ok(entries)
+    }
+
+    /// Path of the PNG of `entry`.
+    pub fn path(&self, entry: &ScreenshotEntry) -> PathBuf {
+        self.dir.join(&entry.path)
+    }
+
+    /// Remove the screenshots taken before `cutoff`, returning how many.
+    async fn expire(&self, cutoff: DateTime<Utc>) -> Result<usize> {
+        let (expired, kept): (Vec<_>, Vec<_>) =
+            self.read_index().await?.into_iter().partition(|entry| entry.timestamp < cutoff);
+        if expired.is_empty() {
+            return Ok(0);
+        }
+        for entry in &expired {
+            let path = self.path(entry);
+            if let Err(e) = tokio::fs::remove_file(&path).await {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    warn!(path = %path.display(), "Failed to remove screenshot: {}", e);
+                }
+            }
+            // Days are removed once their last screenshot is
+            if let Some(day) = path.parent() {
+                let _ = tokio::fs::remove_dir(day).await;
+            }
+        }
+        let mut lines = String::new();
+        for entry in &kept {
+            lines.push_str(&serde_json::to_string(entry)?);
+            lines.push('\n');
+        }
+        let tmp = self.dir.join(format!("{INDEX_FILE}.tmp"));
+        tokio::fs::write(&tmp, lines).await?;
+        tokio::fs::rename(&tmp, self.dir.join(INDEX_FILE)).await?;
+        Ok(expired.len())
+    }
+
+    /// Every entry of the index; lines that are no entry are skipped.
+    async fn read_index(&self) -> Result<Vec<ScreenshotEntry>> {
+        let path = self.dir.join(INDEX_FILE);
+        let content = match tokio::fs::read_to_string(&path).await {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).with_context(|| format!("failed to read {}", path.display())),
+        };
+        Ok(content.lines().filter_map(|line| serde_json::from_str(line).ok()).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn test_notice_program() {
+        assert!(ScreenshotConfig::default().notice_program().is_none());
+        let config = ScreenshotConfig { interval_minutes: 5, ..ScreenshotConfig::default() };
+        let program = config.notice_program().unwrap();
+        assert_eq!(program.restart, RestartPolicy::Never);
+        assert!(program.command.contains("--title Recording --text 'This desktop is recorded: a screenshot of it is taken every 5 minutes"));
+    }
+
+    #[tokio::test]
+    async fn test_archive() {
+        let dir = std::env::temp_dir().join(format!("sshx-screenshots-{}", std::process::id()));
+        let archive = ScreenshotArchive::new(dir.clone());
+        let at = |day: u32, hour: u32| Utc.with_ymd_and_hms(2024, 5, day, hour, 0, 0).unwrap();
+
+        let old = ScreenshotEntry::new(at(1, 9), "s1", "alice", "local", 100, 3);
+        assert_eq!(old.path, Path::new("2024-05-01/s1-090000.png"));
+        archive.record(old.clone(), b"png").await.unwrap();
+        archive.record(ScreenshotEntry::new(at(2, 9), "s2", "bob", "local", 101, 3), b"png").await.unwrap();
+        let new = archive.record(ScreenshotEntry::new(at(2, 10), "s1", "alice", "local", 100, 3), b"png").await.unwrap();
+
+        assert_eq!(archive.entries(None, None, None, None).await.unwrap().len(), 3);
+        assert_eq!(archive.entries(None, None, Some("alice"), None).await.unwrap(), [old.clone(), new.clone()]);
+        assert_eq!(archive.entries(Some(at(2, 0)), None, None, Some("s1")).await.unwrap(), [new.clone()].as_slice());
+
+        assert_eq!(archive.expire(at(2, 0)).await.unwrap(), 1);
+        assert!(!archive.path(&old).exists());
+        assert!(!dir.join("2024-05-01").exists());
+        assert!(archive.path(&new).exists());
+        assert_eq!(archive.entries(None, None, None, None).await.unwrap().len(), 2);
+        assert_eq!(archive.expire(at(2, 0)).await.unwrap(), 0);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
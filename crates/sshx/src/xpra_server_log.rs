@@ -0,0 +1,209 @@
+//! Problems found in xpra's server logs, for correlating with the sessions
+//! that ran on a display at the time.
+//!
+//! Two kinds of files are read: the output captured for each display in
+//! `sessions/<host>-<display>.log` under the log directory, whose lines start
+//! with an RFC 3339 time, and xpra's own `:<display>.log` files of the local
+//! host, whose lines start with xpra's `2024-05-01 10:00:00,123` time in the
+//! host's time zone.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone, Utc};
+use serde::Serialize;
+
+/// Kind of problem reported by xpra.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ServerLogKind {
+    /// A client connection failed or was lost
+    ConnectionError,
+    /// An encoder failed and xpra fell back to another one
+    EncoderFallback,
+    /// xpra or its X server crashed
+    Crash,
+}
+
+impl ServerLogKind {
+    /// Name of the kind, as in JSON output
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ServerLogKind::ConnectionError => "connection_error",
+            ServerLogKind::EncoderFallback => "encoder_fallback",
+            ServerLogKind::Crash => "crash",
+        }
+    }
+
+    /// The kind of problem a log line reports, if any.
+    pub fn classify(message: &str) -> Option<Self> {
+        let message = message.to_lowercase();
+        let any = |needles: &[&str]| needles.iter().any(|needle| message.contains(needle));
+        if any(&["traceback", "segmentation fault", "core dumped", "fatal", "server died"]) {
+            Some(ServerLogKind::Crash)
+        } else if any(&["falling back", "fallback"]) && any(&["encod", "codec", "video"]) {
+            Some(ServerLogKind::EncoderFallback)
+        } else if any(&["connection lost", "connection failed", "connection error", "connection refused", "socket error"]) {
+            Some(ServerLogKind::ConnectionError)
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for ServerLogKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A problem reported in an xpra server log.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ServerLogEvent {
+    /// When it was logged
+    pub timestamp: DateTime<Utc>,
+    /// Host running the display, none for xpra's own logs of the local host
+    pub host: Option<String>,
+    /// Display whose xpra logged it
+    pub display: u16,
+    /// Kind of problem
+    pub kind: ServerLogKind,
+    /// The logged line, without its time
+    pub message: String,
+}
+
+/// Server log files in the `sessions` directory of `log_dir` and, if given,
+/// in `xpra_log_dir`.
+pub fn log_files(log_dir: &Path, xpra_log_dir: Option<&Path>) -> Vec<PathBuf> {
+    let mut patterns = vec![log_dir.join("sessions").join("*.log")];
+    if let Some(dir) = xpra_log_dir {
+        patterns.push(dir.join(":*.log"));
+    }
+    patterns
+        .iter()
+        .filter_map(|pattern| glob::glob(&pattern.to_string_lossy()).ok())
+        .flat_map(|paths| paths.flatten())
+        .collect()
+}
+
+/// Host and display of a server log file, from its name: `<host>-<display>.log`
+/// or `:<display>.log`.
+pub fn file_display(path: &Path) -> Option<(Option<String>, u16)> {
+    let stem = path.file_stem()?.to_str()?;
+    if let Some(display) = stem.strip_prefix(':') {
+        return Some((None, display.parse().ok()?));
+    }
+    let (host, display) = stem.rsplit_once('-')?;
+    Some((Some(host.to_string()), display.parse().ok()?))
+}
+
+/// Host name of the sessions whose displays run on this host.
+pub const LOCAL_HOST: &str = "local";
+
+impl ServerLogEvent {
+    /// Whether the problem was logged by the display `display` of `host`, or
+    /// by a display numbered `display` on any host if `host` is unknown.
+    pub fn is_from(&self, host: Option<&str>, display: u16) -> bool {
+        let logged_on = self.host.as_deref().unwrap_or(LOCAL_HOST);
+        self.display == display && host.is_none_or(|host| host == logged_on)
+    }
+}
+
+/// Time and message of a server log line in either format, with xpra's own
+/// times in the time zone `tz`.
+fn parse_line<'a, Tz: TimeZone>(line: &'a str, tz: &Tz) -> Option<(DateTime<Utc>, &'a str)> {
+    let (first, rest) = line.split_once(' ')?;
+    if let Ok(time) = DateTime::parse_from_rfc3339(first) {
+        return Some((time.with_timezone(&Utc), rest));
+    }
+    let (time, message) = rest.split_once(' ').unwrap_or((rest, ""));
+    let time = NaiveDateTime::parse_from_str(&format!("{first} {time}"), "%Y-%m-%d %H:%M:%S,%3f").ok()?;
+    // Repeated times when clocks go back are taken as the earlier one
+    let time = tz.from_local_datetime(&time).earliest()?;
+    Some((time.with_timezone(&Utc), message))
+}
+
+/// Problems reported in the server log `content` of a display between
+/// `start` and `end`.
+pub fn parse(
+    content: &str,
+    host: Option<&str>,
+    display: u16,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> Vec<ServerLogEvent> {
+    content
+        .lines()
+        .filter_map(|line| parse_line(line, &Local))
+        .filter(|(timestamp, _)| *timestamp >= start && *timestamp <= end)
+        .filter_map(|(timestamp, message)| {
+            let kind = ServerLogKind::classify(message)?;
+            Some(ServerLogEvent {
+                timestamp,
+                host: host.map(str::to_string),
+                display,
+                kind,
+                message: message.to_string(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_both_formats() {
+        let start = "2024-05-14T00:00:00Z".parse().unwrap();
+        let end = "2024-05-15T00:00:00Z".parse().unwrap();
+        let captured = "\
+2024-05-14T10:00:00.000Z 2024-05-14 10:00:00,000 xpra is ready.
+2024-05-14T10:05:00.000Z 2024-05-14 10:05:00,120 Warning: nvenc encoder failed, falling back to x264
+2024-05-13T23:00:00.000Z connection lost before the period";
+        let events = parse(captured, Some("worker-1"), 100, start, end);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, ServerLogKind::EncoderFallback);
+        assert_eq!(events[0].host.as_deref(), Some("worker-1"));
+
+        let own = "\
+2024-05-14 11:00:00,500 Error: connection lost: socket error
+2024-05-14 11:00:01,000 Traceback (most recent call last):";
+        let kinds: Vec<_> = parse(own, None, 100, start, end).into_iter().map(|e| e.kind).collect();
+        assert_eq!(kinds, [ServerLogKind::ConnectionError, ServerLogKind::Crash]);
+    }
+
+    #[test]
+    fn test_local_times() {
+        let tz = chrono::FixedOffset::east_opt(2 * 3600).unwrap();
+        let (time, message) = parse_line("2024-05-14 12:00:00,500 Error: connection lost", &tz).unwrap();
+        assert_eq!(time, "2024-05-14T10:00:00.500Z".parse::<DateTime<Utc>>().unwrap());
+        assert_eq!(message, "Error: connection lost");
+        // Captured output carries its own offset
+        let (time, _) = parse_line("2024-05-14T12:00:00+02:00 2024-05-14 12:00:00,000 ready", &tz).unwrap();
+        assert_eq!(time, "2024-05-14T10:00:00Z".parse::<DateTime<Utc>>().unwrap());
+    }
+
+    #[test]
+    fn test_is_from() {
+        let event = |host: Option<&str>| ServerLogEvent {
+            timestamp: Utc::now(),
+            host: host.map(str::to_string),
+            display: 100,
+            kind: ServerLogKind::Crash,
+            message: String::new(),
+        };
+        assert!(event(Some("worker-1")).is_from(Some("worker-1"), 100));
+        assert!(!event(Some("worker-1")).is_from(Some("worker-2"), 100));
+        assert!(!event(Some("worker-1")).is_from(Some("worker-1"), 101));
+        assert!(event(None).is_from(Some(LOCAL_HOST), 100));
+        assert!(!event(None).is_from(Some("worker-1"), 100));
+        assert!(event(Some("worker-2")).is_from(None, 100));
+    }
+
+    #[test]
+    fn test_file_display() {
+        assert_eq!(file_display(Path::new("sessions/worker-1-104.log")), Some((Some("worker-1".to_string()), 104)));
+        assert_eq!(file_display(Path::new("/run/xpra/:100.log")), Some((None, 100)));
+        assert_eq!(file_display(Path::new("sessions/notes.log")), None);
+    }
+}
@@ -0,0 +1,157 @@
+//! Ids of desktop sessions.
+//!
+//! Ids used to be derived from the id of the shell that started the session,
+//! which restarts at 1 with every sshx session, so the history log ended up
+//! with many sessions of the same id, and told everyone how many shells came
+//! before. Ids are now random and collision-resistant by default, as ULIDs
+//! or UUIDv7s. Both begin with the time of creation, so ids sort in the order
+//! sessions started. The sshx session and shell a desktop was started from
+//! are recorded next to its id instead, as its [`ParentShell`].
+
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use chrono::Utc;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sshx_core::Sid;
+
+/// Prefix of the ids of sessions started from a shell.
+const INTERACTIVE_PREFIX: &str = "xpra";
+
+/// Prefix of the ids of sessions started through the admin API.
+const HEADLESS_PREFIX: &str = "headless";
+
+/// Alphabet of ULIDs, Crockford's base 32.
+const CROCKFORD: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+/// How session ids are made.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionIdScheme {
+    /// `xpra-01J0Z9XK8M5V3QJ6B2F4T7W9YC`: a ULID, 26 characters
+    #[default]
+    Ulid,
+    /// `xpra-0190a3c2-5b1e-7d4a-9f2c-3e8b6d1a0f47`: a UUID of version 7
+    Uuid7,
+    /// `xpra-3`: the id of the shell, as before; not unique across restarts
+    Sequential,
+}
+
+/// The terminal session a desktop was started from: a shell of an sshx
+/// session.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct ParentShell {
+    /// Name of the sshx session
+    pub session: String,
+    /// Id of the shell within the sshx session
+    pub shell: Sid,
+}
+
+impl fmt::Display for ParentShell {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.session, self.shell)
+    }
+}
+
+/// Makes the ids of new sessions by the configured scheme.
+#[derive(Debug, Default)]
+pub struct SessionIdFactory {
+    scheme: SessionIdScheme,
+    /// Headless sessions started, for sequential ids
+    headless: AtomicU64,
+}
+
+impl SessionIdFactory {
+    /// Create a factory making ids by `scheme`.
+    pub fn new(scheme: SessionIdScheme) -> Self {
+        Self { scheme, headless: AtomicU64::new(0) }
+    }
+
+    /// Id of a new session started from the shell `shell`.
+    pub fn interactive(&self, shell: Sid) -> String {
+        match self.scheme {
+            SessionIdScheme::Sequential => format!("{INTERACTIVE_PREFIX}-{}", shell.0),
+            _ => format!("{INTERACTIVE_PREFIX}-{}", self.unique()),
+        }
+    }
+
+    /// Id of a new headless session.
+    pub fn headless(&self) -> String {
+        match self.scheme {
+            SessionIdScheme::Sequential => format!(
+                "{HEADLESS_PREFIX}-{}-{}",
+                Utc::now().timestamp(),
+                self.headless.fetch_add(1, Ordering::Relaxed)
+            ),
+            _ => format!("{HEADLESS_PREFIX}-{}", self.unique()),
+        }
+    }
+
+    /// A new unique id by the scheme, without prefix.
+    fn unique(&self) -> String {
+        let millis = Utc::now().timestamp_millis().max(0) as u64;
+        let random: u128 = rand::thread_rng().gen();
+        match self.scheme {
+            SessionIdScheme::Uuid7 => uuid7(millis, random),
+            _ => ulid(millis, random),
+        }
+    }
+}
+
+/// A ULID of the 48 bits of `millis` and 80 bits of `random`.
+fn ulid(millis: u64, random: u128) -> String {
+    let value = (u128::from(millis & 0xffff_ffff_ffff) << 80) | (random & ((1 << 80) - 1));
+    // 26 characters of 5 bits hold 130 bits, the first two always zero
+    (0..26).rev().map(|i| CROCKFORD[(value >> (i * 5)) as usize & 0x1f] as char).collect()
+}
+
+/// A UUID of version 7 of the 48 bits of `millis` and 74 bits of `random`.
+fn uuid7(millis: u64, random: u128) -> String {
+    let rand_a = (random >> 62) as u16 & 0x0fff;
+    let rand_b = random as u64 & 0x3fff_ffff_ffff_ffff;
+    let value = (u128::from(millis & 0xffff_ffff_ffff) << 80)
+        | (0x7 << 76)
+        | (u128::from(rand_a) << 64)
+        | (0b10 << 62)
+        | u128::from(rand_b);
+    let hex = format!("{value:032x}");
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ulid() {
+        assert_eq!(ulid(0, 0), "00000000000000000000000000");
+        assert_eq!(ulid(1_469_922_850_259, 0), "01ARZ3NDEK0000000000000000");
+        assert_eq!(ulid(0, u128::MAX), "0000000000ZZZZZZZZZZZZZZZZ");
+        // Later ids sort after earlier ones
+        assert!(ulid(1_700_000_000_001, 0) > ulid(1_700_000_000_000, u128::MAX));
+    }
+
+    #[test]
+    fn test_uuid7() {
+        let id = uuid7(0x0190_a3c2_5b1e, u128::MAX);
+        assert_eq!(id, "0190a3c2-5b1e-7fff-bfff-ffffffffffff");
+        assert_eq!(uuid7(0x0190_a3c2_5b1e, 0), "0190a3c2-5b1e-7000-8000-000000000000");
+    }
+
+    #[test]
+    fn test_factory() {
+        let ids = SessionIdFactory::new(SessionIdScheme::Ulid);
+        let (a, b) = (ids.interactive(Sid(1)), ids.interactive(Sid(1)));
+        assert!(a.starts_with("xpra-") && a.len() == 31);
+        assert_ne!(a, b);
+        assert!(ids.headless().starts_with("headless-"));
+
+        let ids = SessionIdFactory::new(SessionIdScheme::Uuid7);
+        assert_eq!(ids.interactive(Sid(1)).len(), 41);
+
+        let ids = SessionIdFactory::new(SessionIdScheme::Sequential);
+        assert_eq!(ids.interactive(Sid(3)), "xpra-3");
+        assert!(ids.headless().ends_with("-0"));
+        assert!(ids.headless().ends_with("-1"));
+    }
+}
@@ -0,0 +1,236 @@
+//! Parameters a web client asks a new desktop session to start with, and the
+//! allowlists they are checked against. Clients can only pick from what the
+//! host configured; a session started without parameters gets the defaults.
+//! The client's locale and time zone are taken as long as they are well
+//! formed, so the desktop's language and clock match the user's.
+
+use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize};
+use sshx_core::proto::SessionParams;
+
+use crate::xpra_backend::BackendConfig;
+use crate::xpra_macos::MacosConfig;
+use crate::xpra_policy::SessionTemplate;
+use crate::xpra_rdp::RdpConfig;
+use crate::xpra_rejection::{RejectionCode, SessionRejection};
+use crate::xpra_xorg::parse_resolution;
+
+/// What clients may ask new sessions to start with. Nothing is allowed
+/// beyond the defaults unless configured.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SessionParamsConfig {
+    /// Templates clients may pick by name, with the window manager or
+    /// desktop command each starts
+    #[serde(default)]
+    pub templates: BTreeMap<String, String>,
+
+    /// Windows hosts clients may pick by name like templates, shown through
+    /// an RDP client
+    #[serde(default)]
+    pub rdp: BTreeMap<String, RdpConfig>,
+
+    /// Macs clients may pick by name like templates, shown through their
+    /// screen sharing
+    #[serde(default)]
+    pub macos: BTreeMap<String, MacosConfig>,
+
+    /// Programs clients may have started on their desktop
+    #[serde(default)]
+    pub apps: Vec<String>,
+
+    /// Screen sizes clients may start with, e.g. `1920x1080`, up to
+    /// `max_resolution`
+    #[serde(default)]
+    pub resolutions: Vec<String>,
+
+    /// Whether clients may turn on audio forwarding
+    #[serde(default)]
+    pub allow_audio: bool,
+}
+
+impl SessionParamsConfig {
+    /// Check the parameters a client asked for against the allowlists and
+    /// apply them to the template the session starts with.
+    pub fn apply(
+        &self,
+        params: &SessionParams,
+        template: &mut SessionTemplate,
+        max_resolution: &str,
+    ) -> Result<(), SessionRejection> {
+        let refuse = |message: String| Err(SessionRejection::new(RejectionCode::InvalidParams, message));
+        if let Some(name) = &params.template {
+            if let Some(window_manager) = self.templates.get(name) {
+                template.window_manager = window_manager.clone();
+            } else if let Some(rdp) = self.rdp.get(name) {
+                template.window_manager = rdp.client.clone();
+                template.backend = BackendConfig::Rdp(rdp.clone());
+            } else if let Some(macos) = self.macos.get(name) {
+                template.window_manager = macos.client.clone();
+                template.backend = BackendConfig::Macos(macos.clone());
+            } else {
+                return refuse(format!("There is no desktop template {name:?} on this host"));
+            }
+        }
+        if let Some(app) = &params.app {
+            if !self.apps.contains(app) {
+                return refuse(format!("The application {app:?} is not available on this host"));
+            }
+            template.app = Some(app.clone());
+        }
+        if let Some(resolution) = &params.resolution {
+            if !self.resolutions.contains(resolution) || !fits(resolution, max_resolution) {
+                return refuse(format!("The screen size {resolution:?} is not available on this host"));
+            }
+            template.resolution = Some(resolution.clone());
+        }
+        if params.audio {
+            if !self.allow_audio {
+                return refuse("Audio is not available on this host".to_string());
+            }
+            template.audio = true;
+        }
+        if let Some(locale) = &params.locale {
+            if !valid_language_tag(locale) {
+                return refuse(format!("The locale {locale:?} is not valid"));
+            }
+            // Languages without a region have no locale to set; keep the default
+            template.locale = posix_locale(locale);
+        }
+        if let Some(timezone) = &params.timezone {
+            if !valid_timezone(timezone) {
+                return refuse(format!("The time zone {timezone:?} is not valid"));
+            }
+            template.timezone = Some(timezone.clone());
+        }
+        Ok(())
+    }
+}
+
+/// Whether a locale is shaped like a BCP 47 language tag such as `de-DE`.
+fn valid_language_tag(tag: &str) -> bool {
+    !tag.is_empty() && tag.len() <= 35 && tag.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// The POSIX locale of a BCP 47 language tag like `de-DE` or `pt-BR`, e.g.
+/// `de_DE.UTF-8`, if the tag is well formed.
+fn posix_locale(tag: &str) -> Option<String> {
+    let mut parts = tag.split(['-', '_']);
+    let language = parts.next()?;
+    if !(2..=3).contains(&language.len()) || !language.chars().all(|c| c.is_ascii_alphabetic()) {
+        return None;
+    }
+    // Skip a script subtag such as `Hant`; only country regions have locales
+    let region = parts.find(|part| part.len() != 4)?;
+    if region.len() != 2 || !region.chars().all(|c| c.is_ascii_alphabetic()) {
+        return None;
+    }
+    Some(format!("{}_{}.UTF-8", language.to_ascii_lowercase(), region.to_ascii_uppercase()))
+}
+
+/// Whether a time zone is a well formed IANA name like `Europe/Berlin` or
+/// `UTC`, which rules out paths outside the zoneinfo database.
+fn valid_timezone(timezone: &str) -> bool {
+    !timezone.is_empty()
+        && timezone.len() <= 64
+        && timezone.split('/').all(|part| {
+            !part.is_empty()
+                && part != "."
+                && part != ".."
+                && part.chars().all(|c| c.is_ascii_alphanumeric() || "_+-".contains(c))
+        })
+}
+
+/// Whether a resolution is valid and no larger than the maximum.
+fn fits(resolution: &str, max_resolution: &str) -> bool {
+    match (parse_resolution(resolution), parse_resolution(max_resolution)) {
+        (Ok((width, height)), Ok((max_width, max_height))) => width <= max_width && height <= max_height,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::xpra_labels::Labels;
+    use crate::xpra_priority::SessionClass;
+
+    #[test]
+    fn test_apply() {
+        let config = SessionParamsConfig {
+            templates: BTreeMap::from([("xfce".to_string(), "xfce4-session".to_string())]),
+            rdp: BTreeMap::from([("windows".to_string(), serde_json::from_str(r#"{"target": "win-1"}"#).unwrap())]),
+            macos: BTreeMap::from([("mac".to_string(), serde_json::from_str(r#"{"target": "mac-1"}"#).unwrap())]),
+            apps: vec!["firefox".to_string()],
+            resolutions: vec!["1920x1080".to_string(), "5120x2880".to_string()],
+            allow_audio: false,
+        };
+        let mut template = SessionTemplate {
+            window_manager: "gnome-flashback".to_string(),
+            class: SessionClass::Interactive,
+            labels: Labels::new(),
+            app: None,
+            resolution: None,
+            audio: false,
+            locale: None,
+            timezone: None,
+            keyboard_layout: None,
+            backend: BackendConfig::Xpra,
+        };
+
+        // No parameters keep the defaults
+        config.apply(&SessionParams::default(), &mut template, "3840x2160").unwrap();
+        assert_eq!(template.window_manager, "gnome-flashback");
+
+        let params = SessionParams {
+            template: Some("xfce".to_string()),
+            app: Some("firefox".to_string()),
+            resolution: Some("1920x1080".to_string()),
+            audio: false,
+            locale: Some("de-DE".to_string()),
+            timezone: Some("Europe/Berlin".to_string()),
+        };
+        config.apply(&params, &mut template, "3840x2160").unwrap();
+        assert_eq!(template.window_manager, "xfce4-session");
+        assert_eq!(template.app.as_deref(), Some("firefox"));
+        assert_eq!(template.resolution.as_deref(), Some("1920x1080"));
+        assert_eq!(template.locale.as_deref(), Some("de_DE.UTF-8"));
+        assert_eq!(template.timezone.as_deref(), Some("Europe/Berlin"));
+        assert_eq!(template.backend, BackendConfig::Xpra);
+
+        let params = SessionParams { template: Some("windows".to_string()), ..Default::default() };
+        config.apply(&params, &mut template, "3840x2160").unwrap();
+        assert_eq!(template.window_manager, "xfreerdp");
+        assert!(matches!(&template.backend, BackendConfig::Rdp(rdp) if rdp.target == "win-1"));
+        let params = SessionParams { template: Some("mac".to_string()), ..Default::default() };
+        config.apply(&params, &mut template, "3840x2160").unwrap();
+        assert_eq!(template.window_manager, "vncviewer");
+        assert!(matches!(&template.backend, BackendConfig::Macos(macos) if macos.target == "mac-1"));
+
+        let refused = |params: SessionParams| {
+            let error = config.apply(&params, &mut template.clone(), "3840x2160").unwrap_err();
+            assert_eq!(error.code, RejectionCode::InvalidParams);
+        };
+        refused(SessionParams { template: Some("kde".to_string()), ..Default::default() });
+        refused(SessionParams { app: Some("rm -rf /".to_string()), ..Default::default() });
+        // Allowed sizes still have to fit the display
+        refused(SessionParams { resolution: Some("5120x2880".to_string()), ..Default::default() });
+        refused(SessionParams { audio: true, ..Default::default() });
+        refused(SessionParams { locale: Some("de_DE; rm -rf /".to_string()), ..Default::default() });
+        refused(SessionParams { timezone: Some("../../etc/passwd".to_string()), ..Default::default() });
+    }
+
+    #[test]
+    fn test_posix_locale() {
+        assert_eq!(posix_locale("en-US").as_deref(), Some("en_US.UTF-8"));
+        assert_eq!(posix_locale("zh-Hant-TW").as_deref(), Some("zh_TW.UTF-8"));
+        // Without a region there is no locale to pick
+        assert_eq!(posix_locale("fr"), None);
+        assert_eq!(posix_locale("es-419"), None);
+        assert_eq!(posix_locale("english-US"), None);
+        assert!(valid_language_tag("es-419"));
+        assert!(!valid_language_tag("en US"));
+        assert!(valid_timezone("America/Argentina/Buenos_Aires"));
+        assert!(valid_timezone("Etc/GMT+5"));
+        assert!(!valid_timezone("Europe/../../etc/shadow"));
+    }
+}
@@ -0,0 +1,181 @@
+//! Lifecycle states of a desktop session and the transitions allowed
+//! between them.
+//!
+//! A session is registered as [`SessionState::Starting`] and becomes
+//! [`SessionState::Active`] once its forwarder is connected to xpra. It ends
+//! in [`SessionState::Terminating`] or [`SessionState::Failed`], after which
+//! no transition is allowed.
+
+use std::fmt;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// State of a session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionState {
+    /// Registered, with the forwarder not yet connected to xpra
+    Starting,
+    /// Running with its forwarder connected
+    Active,
+    /// Running with no client attached
+    Detached,
+    /// Running on a host that is draining, to be terminated at the deadline
+    Draining,
+    /// Being shut down
+    Terminating,
+    /// Ended by an error
+    Failed,
+}
+
+impl SessionState {
+    /// Name of the state, as shown in status output
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SessionState::Starting => "starting",
+            SessionState::Active => "active",
+            SessionState::Detached => "detached",
+            SessionState::Draining => "draining",
+            SessionState::Terminating => "terminating",
+            SessionState::Failed => "failed",
+        }
+    }
+
+    /// Whether the session has ended, so no transition is allowed
+    pub fn is_final(&self) -> bool {
+        matches!(self, SessionState::Terminating | SessionState::Failed)
+    }
+
+    /// Whether a session may move from this state to `next`.
+    pub fn can_transition_to(&self, next: SessionState) -> bool {
+        use SessionState::*;
+        match (self, next) {
+            (Terminating | Failed, _) => false,
+            (_, Terminating | Failed) => true,
+            (Starting, Active | Draining) => true,
+            (Active, Detached | Draining) => true,
+            (Detached, Active | Draining) => true,
+            // A cancelled drain leaves sessions running
+            (Draining, Active) => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for SessionState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A state a session entered, and when.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StateTransition {
+    /// State entered
+    pub state: SessionState,
+    /// When it was entered
+    pub at: DateTime<Utc>,
+}
+
+/// Error for a transition not allowed from the session's current state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidTransition {
+    /// State the session is in
+    pub from: SessionState,
+    /// State it was asked to move to
+    pub to: SessionState,
+}
+
+impl fmt::Display for InvalidTransition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "session cannot move from {} to {}", self.from, self.to)
+    }
+}
+
+impl std::error::Error for InvalidTransition {}
+
+/// Current state of a session, with every state it went through.
+#[derive(Debug, Clone)]
+pub struct SessionLifecycle {
+    transitions: Vec<StateTransition>,
+}
+
+impl Default for SessionLifecycle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SessionLifecycle {
+    /// A lifecycle starting now
+    pub fn new() -> Self {
+        Self {
+            transitions: vec![StateTransition { state: SessionState::Starting, at: Utc::now() }],
+        }
+    }
+
+    /// Current state
+    pub fn state(&self) -> SessionState {
+        self.current().state
+    }
+
+    /// When the current state was entered
+    pub fn since(&self) -> DateTime<Utc> {
+        self.current().at
+    }
+
+    /// Every state entered, oldest first
+    pub fn transitions(&self) -> &[StateTransition] {
+        &self.transitions
+    }
+
+    /// Move to the `next` state, if allowed from the current one.
+    pub fn transition(&mut self, next: SessionState) -> Result<(), InvalidTransition> {
+        let from = self.state();
+        if !from.can_transition_to(next) {
+            return Err(InvalidTransition { from, to: next });
+        }
+        self.transitions.push(StateTransition { state: next, at: Utc::now() });
+        Ok(())
+    }
+
+    fn current(&self) -> &StateTransition {
+        self.transitions.last().expect("lifecycle starts with a state")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lifecycle_transitions() {
+        let mut lifecycle = SessionLifecycle::new();
+        assert_eq!(lifecycle.state(), SessionState::Starting);
+        lifecycle.transition(SessionState::Active).unwrap();
+        lifecycle.transition(SessionState::Draining).unwrap();
+        lifecycle.transition(SessionState::Active).unwrap();
+        assert_eq!(
+            lifecycle.transition(SessionState::Starting),
+            Err(InvalidTransition { from: SessionState::Active, to: SessionState::Starting }),
+        );
+        lifecycle.transition(SessionState::Terminating).unwrap();
+        assert!(lifecycle.transition(SessionState::Failed).is_err());
+
+        let states: Vec<_> = lifecycle.transitions().iter().map(|t| t.state).collect();
+        assert_eq!(states, [
+            SessionState::Starting,
+            SessionState::Active,
+            SessionState::Draining,
+            SessionState::Active,
+            SessionState::Terminating,
+        ]);
+        assert_eq!(lifecycle.since(), lifecycle.transitions()[4].at);
+    }
+
+    #[test]
+    fn test_state_names() {
+        assert_eq!(serde_json::to_string(&SessionState::Detached).unwrap(), "\"detached\"");
+        assert_eq!(SessionState::Draining.to_string(), "draining");
+    }
+}
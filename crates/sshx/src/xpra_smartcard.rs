@@ -0,0 +1,272 @@
+//! Passthrough of the client's smartcards and security keys to a desktop.
+//!
+//! Every display gets its own PC/SC daemon, started next to the window
+//! manager, with a single virtual reader of vsmartcard's `vpcd` driver.
+//! The desktop's programs find that pcscd through `PCSCLITE_CSOCK_NAME`, so
+//! they only see the reader of their own display. Its sockets are in a
+//! directory only the account running the display can open, so readers
+//! are only set up on hosts whose account runs no other desktop: a worker
+//! with room for a single session. Elsewhere the card is not forwarded.
+//!
+//! `vpcd` listens on a port for the card on every address it has, so
+//! pcscd runs in a network namespace of its own where only `vpcd` has
+//! them, and `socat` bridges a Unix socket in the display's directory to
+//! the port. When the client attaches its card, the forwarder connects to
+//! that socket through the SSH connection of the display and relays the
+//! reader's commands to the client and the card's answers back, as
+//! `smartcard` control messages.
+//!
+//! Both directions of the `vpcd` protocol are frames of a big-endian 16 bit
+//! length and the data: a one byte command to power the card off (0), on
+//! (1), reset it (2) or read its ATR (4), or an APDU, from the reader; the
+//! ATR or the response APDU from the card.
+
+use std::path::PathBuf;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::debug;
+
+use crate::xpra_children::{ChildProgram, RestartPolicy};
+use crate::xpra_hosts::{shell_quote, WorkerHost};
+
+/// Commands of the reader buffered for a client that is slow to take them.
+const COMMAND_BUFFER: usize = 16;
+
+/// Virtual smartcard readers of the displays.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SmartcardConfig {
+    /// Give every display a virtual reader the client's card can be
+    /// attached to
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// Port of the reader of display 0 in its network namespace; display N
+    /// listens on `port_base + N`
+    #[serde(default = "default_port_base")]
+    pub port_base: u16,
+
+    /// Directory of the per-display pcscd sockets and configuration, on the
+    /// host running the display. It must belong to the account running the
+    /// display.
+    #[serde(default = "default_socket_dir")]
+    pub socket_dir: PathBuf,
+
+    /// The `vpcd` driver of vsmartcard
+    #[serde(default = "default_driver")]
+    pub driver: PathBuf,
+}
+
+fn default_port_base() -> u16 { 35963 }
+
+fn default_socket_dir() -> PathBuf { PathBuf::from("/run/sshx/pcsc") }
+
+fn default_driver() -> PathBuf { PathBuf::from("/usr/lib/pcsc/drivers/serial/libifdvpcd.so") }
+
+impl Default for SmartcardConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            port_base: default_port_base(),
+            socket_dir: default_socket_dir(),
+            driver: default_driver(),
+        }
+    }
+}
+
+impl SmartcardConfig {
+    /// Port the reader of `display` listens on for the card.
+    pub fn port(&self, display: u16) -> u16 {
+        self.port_base.saturating_add(display)
+    }
+
+    /// Whether displays on `host` get a reader: readers are enabled and no
+    /// other desktop runs as the host's account.
+    pub fn available(&self, host: &WorkerHost) -> bool {
+        self.enabled && host.is_dedicated()
+    }
+
+    /// Directory of the pcscd of `display`.
+    fn dir(&self, display: u16) -> PathBuf {
+        self.socket_dir.join(display.to_string())
+    }
+
+    /// Unix socket the reader of `display` takes the card on.
+    pub fn socket(&self, display: u16) -> PathBuf {
+        self.dir(display).join("vpcd.sock")
+    }
+
+    /// Environment pointing the desktop's programs to the pcscd of
+    /// `display` on `host`, if it has a reader.
+    pub fn env(&self, display: u16, host: &WorkerHost) -> Vec<String> {
+        if !self.available(host) {
+            return Vec::new();
+        }
+        vec![format!("PCSCLITE_CSOCK_NAME={}", self.dir(display).join("pcscd.comm").display())]
+    }
+
+    /// Program running the pcscd of `display` on `host`, if it has a
+    /// reader. The display's directory is made anew, and the program fails
+    /// if the directory holding it belongs to another account.
+    pub fn program(&self, display: u16, host: &WorkerHost) -> Option<ChildProgram> {
+        if !self.available(host) {
+            return None;
+        }
+        let dir = self.dir(display);
+        let port = self.port(display);
+        let reader_conf = format!(
+            "FRIENDLYNAME \"Client smartcard\"\nDEVICENAME /dev/null:0x{port:X}\nLIBPATH {driver}\nCHANNELID 0x{port:X}\n",
+            driver = self.driver.display(),
+        );
+        let quote = |path: PathBuf| shell_quote(&path.to_string_lossy());
+        let (parent, conf, socket) = (quote(self.socket_dir.clone()), quote(dir.join("reader.conf")), quote(self.socket(display)));
+        let dir = quote(dir);
+        // Only the reader has addresses in the namespace, loopback to take
+        // the bridged card on
+        let reader = format!(
+            "ip link set lo up && {{ socat UNIX-LISTEN:{socket},fork TCP:127.0.0.1:{port} & }} && \
+             PCSCLITE_CSOCK_NAME={dir}/pcscd.comm exec pcscd --foreground --config {conf}"
+        );
+        let command = format!(
+            "umask 077 && mkdir -p {parent} && [ -O {parent} ] && rm -rf {dir} && mkdir {dir} && \
+             printf '%s' {reader_conf} > {conf} && exec unshare --user --map-root-user --net sh -c {reader}",
+            reader_conf = shell_quote(&reader_conf),
+            reader = shell_quote(&reader),
+        );
+        Some(ChildProgram { name: "pcscd".to_string(), command, restart: RestartPolicy::OnFailure })
+    }
+}
+
+/// What the client asks of the reader of its desktop.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SmartcardInput {
+    /// A card was attached to the client
+    Attach,
+    /// Frame of the card for the reader
+    Data(Vec<u8>),
+    /// The card was removed from the client
+    Detach,
+}
+
+/// Connection to the reader of a display, standing in for the client's
+/// card.
+#[derive(Debug)]
+pub struct SmartcardRelay {
+    writer: OwnedWriteHalf,
+    commands: mpsc::Receiver<Vec<u8>>,
+    reader: JoinHandle<()>,
+}
+
+impl SmartcardRelay {
+    /// Connect to the reader reached at `address` and `port`.
+    pub async fn connect(address: &str, port: u16) -> Result<Self> {
+        let stream = TcpStream::connect((address, port))
+            .await
+            .with_context(|| format!("failed to connect to the smartcard reader at {address}:{port}"))?;
+        stream.set_nodelay(true)?;
+        let (mut read, writer) = stream.into_split();
+        let (tx, commands) = mpsc::channel(COMMAND_BUFFER);
+        // Frames are read in a task of their own, so none is lost half read
+        // when the forwarder turns to something else
+        let reader = tokio::spawn(async move {
+            loop {
+                let frame = async {
+                    let len = read.read_u16().await?;
+                    let mut frame = vec![0; len as usize];
+                    read.read_exact(&mut frame).await?;
+                    std::io::Result::Ok(frame)
+                };
+                match frame.await {
+                    Ok(frame) => {
+                        if tx.send(frame).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        debug!("Smartcard reader closed: {}", e);
+                        break;
+                    }
+                }
+            }
+        });
+        Ok(Self { writer, commands, reader })
+    }
+
+    /// Next command of the reader for the card, none once it disconnected.
+    pub async fn recv(&mut self) -> Option<Vec<u8>> {
+        self.commands.recv().await
+    }
+
+    /// Send a frame of the card to the reader.
+    pub async fn send(&mut self, data: &[u8]) -> Result<()> {
+        let len = u16::try_from(data.len()).context("smartcard frame too long")?;
+        let mut frame = len.to_be_bytes().to_vec();
+        frame.extend_from_slice(data);
+        self.writer.write_all(&frame).await?;
+        Ok(())
+    }
+}
+
+impl Drop for SmartcardRelay {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::xpra_config::XpraConfig;
+    use crate::xpra_hosts::HostConfig;
+    use crate::xpra_pool::DisplayPool;
+
+    #[test]
+    fn test_program() {
+        let xpra = XpraConfig::default();
+        let host: HostConfig = serde_json::from_str(r#"{"name": "a", "ssh_target": "desk@a", "max_sessions": 1}"#).unwrap();
+        let dedicated = WorkerHost::remote(&host, &xpra);
+        assert!(SmartcardConfig::default().program(100, &dedicated).is_none());
+        let config = SmartcardConfig { enabled: true, ..SmartcardConfig::default() };
+        assert_eq!(config.port(100), 36063);
+        assert_eq!(config.env(100, &dedicated), ["PCSCLITE_CSOCK_NAME=/run/sshx/pcsc/100/pcscd.comm"]);
+        let program = config.program(100, &dedicated).unwrap();
+        assert!(program.command.contains("DEVICENAME /dev/null:0x8CDF"));
+        assert!(program.command.contains("[ -O /run/sshx/pcsc ] && rm -rf /run/sshx/pcsc/100 && mkdir /run/sshx/pcsc/100"));
+        assert!(program.command.contains("exec unshare --user --map-root-user --net sh -c"));
+        assert!(program.command.contains("UNIX-LISTEN:/run/sshx/pcsc/100/vpcd.sock,fork TCP:127.0.0.1:36063"));
+        assert!(program.command.contains("exec pcscd --foreground --config /run/sshx/pcsc/100/reader.conf"));
+
+        // Hosts whose account runs other desktops get no reader
+        let shared = WorkerHost::remote(&HostConfig { max_sessions: 2, ..host }, &xpra);
+        let local = WorkerHost::local(&xpra, DisplayPool::new());
+        for host in [shared, local] {
+            assert!(config.program(100, &host).is_none());
+            assert!(config.env(100, &host).is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_relay() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut relay = SmartcardRelay::connect("127.0.0.1", port).await.unwrap();
+        let (mut vpcd, _) = listener.accept().await.unwrap();
+
+        // The reader asks for the ATR, the card answers
+        vpcd.write_all(&[0, 1, 4]).await.unwrap();
+        assert_eq!(relay.recv().await, Some(vec![4]));
+        relay.send(&[0x3b, 0x80]).await.unwrap();
+        let mut answer = [0; 4];
+        vpcd.read_exact(&mut answer).await.unwrap();
+        assert_eq!(answer, [0, 2, 0x3b, 0x80]);
+
+        drop(vpcd);
+        assert_eq!(relay.recv().await, None);
+    }
+}
@@ -0,0 +1,177 @@
+//! Soak test running many fake desktop sessions against the session
+//! monitor, metrics, display pool, logger and log rotation at once.
+//!
+//! Each session stands in a `sleep` process for xpra. The test is ignored
+//! by a plain `cargo test`; run it briefly, or raise the duration and number
+//! of sessions for a real soak:
+//!
+//! ```bash
+//! SSHX_SOAK_SECS=14400 SSHX_SOAK_SESSIONS=400 \
+//!     cargo test --release -p sshx --lib xpra_soak -- --ignored --nocapture
+//! ```
+
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{ensure, Result};
+use sshx_core::Sid;
+use tokio::task::JoinSet;
+use tokio::time;
+
+use crate::xpra::XpraDisplay;
+use crate::xpra_config::XpraConfig;
+use crate::xpra_context::XpraContext;
+use crate::xpra_hosts::WorkerHost;
+use crate::xpra_labels::Labels;
+use crate::xpra_log_rotation::LogRotator;
+use crate::xpra_logger::TerminationReason;
+use crate::xpra_session_id::ParentShell;
+
+fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    std::env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+}
+
+/// Run one session from start to end.
+async fn run_session(context: &XpraContext, host: &Arc<WorkerHost>, worker: usize, round: usize) -> Result<()> {
+    let process = Command::new("sleep")
+        .arg("3600")
+        .stdin(Stdio::null())
+        .spawn()?;
+    let display = XpraDisplay::from_process(host.clone(), process, "soak").await?;
+
+    let session_id = format!("soak-{worker}-{round}");
+    let mut guard = context
+        .monitor
+        .register_session(session_id.clone(), format!("user{}", worker % 16), display, Some(ParentShell { session: "soak".to_string(), shell: Sid(worker as u32) }), Labels::new())
+        .await;
+    let info = guard.info().clone();
+
+    // Hold the session for a while with some traffic, so hundreds overlap
+    for i in 0..(worker + round) % 8 {
+        info.traffic.add_in(64 * i as u64);
+        info.traffic.add_out(4096 * i as u64);
+        context.monitor.update_activity(&session_id).await;
+        time::sleep(Duration::from_millis(((worker + i) % 5) as u64)).await;
+    }
+
+    // Sessions end on their own, fail, or are terminated, some while they
+    // end on their own like an admin terminating a session its user closes.
+    // Finishing the guard closes the display.
+    let kill = TerminationReason::AdminKill;
+    match round % 4 {
+        0 => {
+            tokio::join!(context.monitor.terminate_session(&session_id, kill), guard.finish());
+        }
+        1 => guard.finish().await,
+        2 => {
+            guard.fail();
+            guard.finish().await;
+        }
+        _ => {
+            // Terminating returns once the forwarder finished the guard
+            let forwarder = async {
+                info.shutdown.notified().await;
+                guard.finish().await;
+            };
+            let (terminated, ()) = tokio::join!(context.monitor.terminate_session(&session_id, kill), forwarder);
+            ensure!(terminated, "{session_id} was not running");
+        }
+    }
+    Ok(())
+}
+
+/// Check the invariants that hold at any time while `sessions` workers run.
+async fn check_running(context: &XpraContext, host: &WorkerHost, sessions: usize) -> Result<()> {
+    let metrics = context.metrics.get_metrics();
+    ensure!(
+        metrics.active_sessions <= metrics.total_sessions.min(sessions as u64),
+        "active_sessions is {} with {} started by {sessions} workers",
+        metrics.active_sessions,
+        metrics.total_sessions,
+    );
+    let consistency = context.metrics.consistency();
+    ensure!(consistency.invalid_transitions == 0, "invalid session transitions: {consistency:?}");
+    let tracked = context.monitor.session_count();
+    ensure!(tracked <= sessions, "{tracked} sessions tracked by {sessions} workers");
+    // Displays are released before their session finishes, so at most one
+    // per worker
+    let allocated = host.pool.allocated_count().await;
+    ensure!(allocated <= sessions, "{allocated} displays allocated by {sessions} workers");
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+#[ignore = "soak test, run with --ignored"]
+async fn soak_sessions() -> Result<()> {
+    let duration = Duration::from_secs(env_or("SSHX_SOAK_SECS", 2));
+    let sessions = env_or("SSHX_SOAK_SESSIONS", 200);
+
+    let log_dir = std::env::temp_dir().join(format!("sshx-soak-{}", std::process::id()));
+    let context = XpraContext::new(XpraConfig {
+        log_dir: log_dir.clone(),
+        instance: None,
+        ..XpraConfig::default()
+    });
+    let host = Arc::new(WorkerHost::local(&context.config, context.display_pool.clone()));
+    let rotator = LogRotator::new(log_dir.clone()).with_logger(context.logger.clone());
+    let deadline = Instant::now() + duration;
+
+    let mut workers = JoinSet::new();
+    for worker in 0..sessions {
+        let (context, host) = (context.clone(), host.clone());
+        workers.spawn(async move {
+            let mut round = 0;
+            while Instant::now() < deadline {
+                run_session(&context, &host, worker, round).await?;
+                round += 1;
+            }
+            anyhow::Ok(round)
+        });
+    }
+
+    // Log metrics, rotate logs and check invariants while sessions churn
+    let mut background = JoinSet::new();
+    background.spawn({
+        let context = context.clone();
+        async move {
+            while Instant::now() < deadline {
+                context.logger.log_metrics(&context).await?;
+                rotator.rotate_logs().await?;
+                time::sleep(Duration::from_millis(50)).await;
+            }
+            anyhow::Ok(())
+        }
+    });
+    background.spawn({
+        let (context, host) = (context.clone(), host.clone());
+        async move {
+            while Instant::now() < deadline {
+                check_running(&context, &host, sessions).await?;
+                time::sleep(Duration::from_millis(10)).await;
+            }
+            anyhow::Ok(())
+        }
+    });
+
+    let mut started = 0;
+    while let Some(rounds) = workers.join_next().await {
+        started += rounds??;
+    }
+    while let Some(result) = background.join_next().await {
+        result??;
+    }
+
+    // Once every session ended, nothing may be left behind
+    let metrics = context.metrics.get_metrics();
+    println!("soak: {started} sessions by {sessions} workers in {duration:?}");
+    assert_eq!(metrics.total_sessions, started as u64);
+    assert_eq!(metrics.active_sessions, 0);
+    assert!(context.metrics.consistency().is_consistent());
+    assert_eq!(context.monitor.session_count(), 0);
+    assert_eq!(host.pool.allocated_count().await, 0, "displays leaked");
+
+    let _ = std::fs::remove_dir_all(log_dir);
+    Ok(())
+}
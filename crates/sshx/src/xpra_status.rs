@@ -1,86 +1,213 @@
-use std::collections::HashMap;
-use serde::Serialize;
-use tokio::time::{Duration, Instant};
+//! Snapshot of the host's configuration, sessions, and metrics.
 
-use crate::xpra_metrics::METRICS;
-use crate::xpra_monitor::SESSION_MONITOR;
-use crate::xpra_config::CONFIG;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize)]
+use crate::xpra_context::XpraContext;
+use crate::xpra_drain::DrainStatus;
+use crate::xpra_hosts::HostStatus;
+use crate::xpra_labels::Labels;
+use crate::xpra_metrics::{LoggingMetricsSnapshot, MetricsConsistency};
+use crate::xpra_monitor::SessionInfo;
+use crate::xpra_paging::SessionQuery;
+use crate::xpra_placement::PlacementStrategy;
+use crate::xpra_quota::UserUsage;
+use crate::xpra_session_state::SessionState;
+use crate::xpra_watchdog::DisplayHealth;
+use crate::xpra_xorg::XBackend;
+
+/// Status of a running session.
+#[derive(Debug, Serialize, Deserialize)]
 pub struct SessionStatus {
+    /// Id of the session.
     pub session_id: String,
+    /// Name the user gave the session, if any.
+    pub name: Option<String>,
+    /// What the user said the session is for, if anything.
+    pub description: Option<String>,
+    /// User owning the session.
     pub user: String,
+    /// Host running the display.
+    pub host: String,
+    /// Display number of the session.
     pub display: u16,
+    /// State of the session.
+    pub state: SessionState,
+    /// When the session entered its state.
+    pub state_since: DateTime<Utc>,
+    /// GPU assigned to the session, if any.
+    pub gpu: Option<String>,
+    /// CPUs the session is pinned to, if any.
+    pub cpus: Option<String>,
+    /// Seconds since input or output was last seen.
     pub idle_time: u64,
+    /// WebSocket port of the display.
     pub websocket_port: u16,
+    /// Whether the session is hibernated.
+    pub hibernated: bool,
+    /// Whether the session has no client.
+    pub headless: bool,
+    /// Whether screenshots of the session are taken. Hosts before
+    /// screenshots do not send it.
+    #[serde(default)]
+    pub recording: bool,
+    /// What the watchdog last found on the display.
+    pub health: DisplayHealth,
+    /// Labels of the session.
+    pub labels: Labels,
 }
 
-#[derive(Debug, Serialize)]
+/// Status of the host.
+#[derive(Debug, Serialize, Deserialize)]
 pub struct XpraStatus {
+    /// Configuration in effect.
     pub config: ConfigStatus,
+    /// Running sessions, the page of those the query asked for.
     pub sessions: Vec<SessionStatus>,
+    /// Sessions matching the query, on every page.
+    pub matched_sessions: usize,
+    /// Id to ask for the sessions after, if there are more.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_session: Option<String>,
+    /// Hosts of the fleet.
+    pub hosts: Vec<HostStatus>,
+    /// Session counters.
     pub metrics: MetricsStatus,
+    /// Time used by the users with a budget.
+    pub usage: Vec<UserUsage>,
+    /// Whether the host is draining.
+    pub drain: DrainStatus,
+    /// Start of the next maintenance window, if any.
+    pub next_maintenance: Option<DateTime<Utc>>,
 }
 
-#[derive(Debug, Serialize)]
+/// Configuration of the host, as shown in its status.
+#[derive(Debug, Serialize, Deserialize)]
 pub struct ConfigStatus {
+    /// Minimum display number allocated.
     pub min_display: u16,
+    /// Maximum display number allocated.
     pub max_display: u16,
+    /// Base port for WebSocket connections.
     pub base_port: u16,
+    /// Window manager started in new sessions.
     pub window_manager: String,
+    /// X server the displays run on.
+    pub x_backend: XBackend,
+    /// Idle timeout in seconds, 0 if disabled.
     pub idle_timeout: u64,
+    /// Maximum sessions per user, 0 if unlimited.
     pub max_sessions: u32,
+    /// Most sessions running at once, 0 if unlimited.
+    pub max_total_sessions: u32,
+    /// Seconds a start waits for a free slot.
+    pub queue_timeout: u64,
+    /// How hosts are picked for new sessions.
+    pub placement_strategy: PlacementStrategy,
 }
 
-#[derive(Debug, Serialize)]
+/// Session counters, as shown in the host's status.
+#[derive(Debug, Serialize, Deserialize)]
 pub struct MetricsStatus {
+    /// Sessions started.
     pub total_sessions: u64,
+    /// Sessions currently running.
     pub active_sessions: u64,
+    /// Sessions that failed.
     pub failed_sessions: u64,
+    /// Sessions terminated for being idle.
     pub idle_terminations: u64,
+    /// Session starts rejected by the rate limiter.
+    pub rate_limited: u64,
+    /// Sessions refused because the host was full.
+    pub rejected_full: u64,
+    /// Session starts currently waiting for a free slot.
+    pub queued_sessions: u64,
+    /// Clipboard transfers blocked by clipboard policies.
+    pub clipboard_blocked: u64,
+    /// Check of the counters against the sessions tracked as running.
+    pub consistency: MetricsConsistency,
+    /// Entries, bytes, errors and rotations of the logs.
+    pub logging: LoggingMetricsSnapshot,
+    /// Time since the counters were created, like `1d 2h 3m 4s`.
     pub uptime: String,
+    /// When the counters were created.
+    pub started_at: DateTime<Utc>,
 }
 
-pub async fn get_status() -> XpraStatus {
-    let metrics = METRICS.get_metrics();
-    
+/// Collect the status of the host of `context`, with the page of sessions
+/// `query` asks for.
+pub async fn get_status(context: &XpraContext, query: &SessionQuery) -> XpraStatus {
+    let metrics = context.metrics.get_metrics();
+    let page = context.monitor.query_sessions(query, |id, info| session_status(context, id, info));
+
     XpraStatus {
         config: ConfigStatus {
-            min_display: CONFIG.min_display,
-            max_display: CONFIG.max_display,
-            base_port: CONFIG.base_port,
-            window_manager: CONFIG.window_manager.clone(),
-            idle_timeout: CONFIG.idle_timeout,
-            max_sessions: CONFIG.max_sessions,
+            min_display: context.config.min_display,
+            max_display: context.config.max_display,
+            base_port: context.config.base_port,
+            window_manager: context.config.window_manager.clone(),
+            x_backend: context.config.x_backend,
+            idle_timeout: context.config.idle_timeout,
+            max_sessions: context.config.max_sessions,
+            max_total_sessions: context.config.max_total_sessions,
+            queue_timeout: context.config.queue_timeout,
+            placement_strategy: context.config.placement_strategy,
         },
-        sessions: get_session_status().await,
+        sessions: page.sessions,
+        matched_sessions: page.total,
+        next_session: page.next,
+        hosts: context.hosts.status().await,
         metrics: MetricsStatus {
             total_sessions: metrics.total_sessions,
             active_sessions: metrics.active_sessions,
             failed_sessions: metrics.failed_sessions,
             idle_terminations: metrics.idle_terminations,
-            uptime: format_duration(Duration::from_secs(metrics.uptime_secs)),
+            rate_limited: metrics.rate_limited,
+            rejected_full: metrics.rejected_full,
+            queued_sessions: metrics.queued_sessions,
+            clipboard_blocked: metrics.clipboard_blocked,
+            consistency: context.metrics.consistency(),
+            logging: metrics.logging,
+            uptime: format_uptime(metrics.uptime_secs),
+            started_at: Utc::now() - chrono::Duration::seconds(metrics.uptime_secs as i64),
         },
+        usage: context.usage.all_usage().await,
+        drain: context.drain.status().await,
+        next_maintenance: context.maintenance.next_window(Utc::now()),
     }
 }
 
-async fn get_session_status() -> Vec<SessionStatus> {
-    let monitor = SESSION_MONITOR.clone();
-    let sessions = monitor.get_all_sessions().await;
-    
-    sessions.into_iter()
-        .map(|(id, info)| SessionStatus {
-            session_id: id,
-            user: info.user,
-            display: info.display,
-            idle_time: info.last_activity.elapsed().as_secs(),
-            websocket_port: CONFIG.websocket_port(info.display),
-        })
-        .collect()
+/// Status of the running session `id` of `context`.
+pub fn session_status(context: &XpraContext, id: &str, info: &SessionInfo) -> SessionStatus {
+    let websocket_port = match context.hosts.get(&info.host) {
+        Some(host) => host.websocket_port(info.display),
+        None => context.config.websocket_port(info.display),
+    };
+    SessionStatus {
+        session_id: id.to_string(),
+        name: info.name.clone(),
+        description: info.description.clone(),
+        user: info.user.clone(),
+        host: info.host.clone(),
+        display: info.display,
+        state: info.lifecycle.state(),
+        state_since: info.lifecycle.since(),
+        gpu: info.gpu.clone(),
+        cpus: info.cpus.clone(),
+        idle_time: info.last_activity.elapsed().as_secs(),
+        websocket_port,
+        hibernated: info.hibernated,
+        headless: info.headless,
+        recording: info.recording,
+        health: info.health,
+        labels: info.labels.clone(),
+    }
 }
 
-fn format_duration(duration: Duration) -> String {
-    let secs = duration.as_secs();
+/// Uptime in every unit down to seconds. Unlike the shorter durations of
+/// text reports, this is kept as is for scripts reading the JSON status.
+fn format_uptime(secs: u64) -> String {
     let days = secs / 86400;
     let hours = (secs % 86400) / 3600;
     let minutes = (secs % 3600) / 60;
@@ -96,3 +223,15 @@ fn format_duration(duration: Duration) -> String {
         format!("{}s", seconds)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_uptime() {
+        assert_eq!(format_uptime(42), "42s");
+        assert_eq!(format_uptime(3 * 3600 + 5 * 60 + 7), "3h 5m 7s");
+        assert_eq!(format_uptime(86400 + 60), "1d 0h 1m 0s");
+    }
+}
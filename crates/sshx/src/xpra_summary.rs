@@ -0,0 +1,163 @@
+//! Session counts grouped by user and by team.
+//!
+//! Capacity dashboards need how many sessions each user and team runs, and
+//! how long those sessions have been running and sitting idle, not the
+//! session table itself. A [`SessionSummary`] adds this up in one pass over
+//! the running sessions, for `status --summary` and the `/metrics` endpoint.
+//! Teams are the values of one session label, `team` unless configured
+//! otherwise.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use serde::{Deserialize, Serialize};
+
+use crate::xpra_labels::Labels;
+
+/// A gauge of each group on `/metrics`: name, help, and the value of a group.
+type Gauge = (&'static str, &'static str, fn(&GroupSummary) -> u64);
+
+const METRICS: [Gauge; 5] = [
+    ("sessions", "Running desktop sessions", |g| g.sessions as u64),
+    ("idle_sessions", "Desktop sessions idle past the idle timeout", |g| g.idle_sessions as u64),
+    ("session_idle_seconds_sum", "Seconds desktop sessions have been idle, added up", |g| g.idle_secs),
+    ("session_idle_seconds_max", "Seconds the longest idle desktop session has been idle", |g| g.max_idle_secs),
+    ("session_duration_seconds_sum", "Seconds desktop sessions have been running, added up", |g| g.duration_secs),
+];
+
+/// Counts and times of a group of sessions.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GroupSummary {
+    /// Running sessions
+    pub sessions: usize,
+    /// Sessions idle past the idle timeout
+    pub idle_sessions: usize,
+    /// Seconds the sessions have been idle, added up
+    pub idle_secs: u64,
+    /// Seconds the longest idle session has been idle
+    pub max_idle_secs: u64,
+    /// Seconds the sessions have been running, added up
+    pub duration_secs: u64,
+}
+
+impl GroupSummary {
+    fn record(&mut self, idle_secs: u64, duration_secs: u64, idle_timeout: u64) {
+        self.sessions += 1;
+        if idle_timeout > 0 && idle_secs >= idle_timeout {
+            self.idle_sessions += 1;
+        }
+        self.idle_secs += idle_secs;
+        self.max_idle_secs = self.max_idle_secs.max(idle_secs);
+        self.duration_secs += duration_secs;
+    }
+
+    /// Seconds a session of the group has been idle on average.
+    pub fn mean_idle_secs(&self) -> u64 {
+        self.idle_secs.checked_div(self.sessions as u64).unwrap_or(0)
+    }
+
+    /// Seconds a session of the group has been running on average.
+    pub fn mean_duration_secs(&self) -> u64 {
+        self.duration_secs.checked_div(self.sessions as u64).unwrap_or(0)
+    }
+}
+
+/// Running sessions of a host, in all and grouped by user and by team.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SessionSummary {
+    /// All running sessions
+    pub total: GroupSummary,
+    /// Sessions of each user
+    pub by_user: BTreeMap<String, GroupSummary>,
+    /// Label whose values the sessions are grouped by in `by_team`
+    pub team_label: Option<String>,
+    /// Sessions by the value of their team label, those without it under
+    /// an empty name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub by_team: BTreeMap<String, GroupSummary>,
+}
+
+impl SessionSummary {
+    /// An empty summary grouping sessions into teams by `team_label`.
+    pub fn new(team_label: Option<String>) -> Self {
+        Self { team_label, ..Self::default() }
+    }
+
+    /// Count a session of `user` carrying `labels`, idle for `idle_secs` of
+    /// the `duration_secs` it has been running. Sessions count as idle from
+    /// `idle_timeout` seconds on, never if it is 0.
+    pub fn record(&mut self, user: &str, labels: &Labels, idle_secs: u64, duration_secs: u64, idle_timeout: u64) {
+        self.total.record(idle_secs, duration_secs, idle_timeout);
+        self.by_user.entry(user.to_string()).or_default().record(idle_secs, duration_secs, idle_timeout);
+        if let Some(label) = &self.team_label {
+            let team = labels.get(label).cloned().unwrap_or_default();
+            self.by_team.entry(team).or_default().record(idle_secs, duration_secs, idle_timeout);
+        }
+    }
+
+    /// The summary in the Prometheus text format. Totals, users and teams
+    /// are separate metric families, so summing one never counts a session
+    /// twice.
+    pub fn prometheus(&self) -> String {
+        let mut out = String::new();
+        for (name, help, value) in METRICS {
+            let _ = writeln!(out, "# HELP sshx_{name} {help}");
+            let _ = writeln!(out, "# TYPE sshx_{name} gauge");
+            let _ = writeln!(out, "sshx_{name} {}", value(&self.total));
+            let _ = writeln!(out, "# HELP sshx_user_{name} {help}, by user");
+            let _ = writeln!(out, "# TYPE sshx_user_{name} gauge");
+            for (user, group) in &self.by_user {
+                let _ = writeln!(out, "sshx_user_{name}{{user=\"{}\"}} {}", escape(user), value(group));
+            }
+            if self.team_label.is_some() {
+                let _ = writeln!(out, "# HELP sshx_team_{name} {help}, by team");
+                let _ = writeln!(out, "# TYPE sshx_team_{name} gauge");
+                for (team, group) in &self.by_team {
+                    let _ = writeln!(out, "sshx_team_{name}{{team=\"{}\"}} {}", escape(team), value(group));
+                }
+            }
+        }
+        out
+    }
+}
+
+/// `value` escaped for a label value of the Prometheus text format.
+fn escape(value: &str) -> String {
+    value.replace('\\', r"\\").replace('"', "\\\"").replace('\n', r"\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(team: Option<&str>) -> Labels {
+        team.map(|team| ("team".to_string(), team.to_string())).into_iter().collect()
+    }
+
+    #[test]
+    fn test_record() {
+        let mut summary = SessionSummary::new(Some("team".to_string()));
+        summary.record("alice", &labels(Some("ml")), 120, 600, 3600);
+        summary.record("alice", &labels(Some("ml")), 4000, 9000, 3600);
+        summary.record("bob", &labels(None), 0, 60, 3600);
+
+        assert_eq!(summary.total.sessions, 3);
+        assert_eq!(summary.total.idle_sessions, 1);
+        assert_eq!(summary.total.max_idle_secs, 4000);
+        let alice = summary.by_user["alice"];
+        assert_eq!((alice.sessions, alice.mean_idle_secs(), alice.mean_duration_secs()), (2, 2060, 4800));
+        assert_eq!(summary.by_team["ml"].sessions, 2);
+        assert_eq!(summary.by_team[""].sessions, 1);
+        assert_eq!(GroupSummary::default().mean_idle_secs(), 0);
+    }
+
+    #[test]
+    fn test_prometheus() {
+        let mut summary = SessionSummary::new(None);
+        summary.record("al\"ice", &labels(Some("ml")), 10, 20, 0);
+        let text = summary.prometheus();
+        assert!(text.contains("# TYPE sshx_sessions gauge\nsshx_sessions 1\n"));
+        assert!(text.contains("sshx_user_sessions{user=\"al\\\"ice\"} 1\n"));
+        assert!(text.contains("sshx_user_idle_sessions{user=\"al\\\"ice\"} 0\n"));
+        assert!(!text.contains("sshx_team_"));
+    }
+}
@@ -0,0 +1,227 @@
+//! Optional systemd integration: readiness notifications, the service
+//! watchdog, and socket activation.
+//!
+//! Everything here is a no-op when the process is not started by systemd,
+//! i.e. when `NOTIFY_SOCKET`, `WATCHDOG_USEC`, and `LISTEN_FDS` are unset.
+
+use std::collections::HashMap;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::{UnixDatagram, UnixListener};
+use std::sync::Mutex;
+use std::time::Duration;
+use nix::sys::socket::{getsockopt, sockopt, SockType};
+use tracing::{debug, warn};
+
+/// First file descriptor passed by systemd socket activation.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Most sockets taken from `LISTEN_FDS`, far more than the service uses.
+const MAX_LISTEN_FDS: RawFd = 64;
+
+/// Send a state update such as `READY=1` to the service manager. Returns
+/// whether a notification socket was configured.
+pub fn notify(state: &str) -> bool {
+    let Ok(path) = std::env::var("NOTIFY_SOCKET") else {
+        return false;
+    };
+    if let Err(e) = send_notification(&path, state) {
+        warn!(socket = path, "Failed to notify systemd: {}", e);
+    }
+    true
+}
+
+fn send_notification(path: &str, state: &str) -> std::io::Result<()> {
+    let socket = UnixDatagram::unbound()?;
+    match path.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        _ => {
+            socket.send_to(state.as_bytes(), path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Tell systemd the service finished starting up.
+pub fn notify_ready() {
+    if notify("READY=1") {
+        debug!("Notified systemd of readiness");
+    }
+}
+
+/// Tell systemd the service is shutting down.
+pub fn notify_stopping() {
+    notify("STOPPING=1");
+}
+
+/// Pet the watchdog, if one is configured for this process.
+pub fn notify_watchdog() {
+    if watchdog_interval().is_some() {
+        notify("WATCHDOG=1");
+    }
+}
+
+/// How often to pet the watchdog: half the configured timeout, as systemd
+/// recommends, or `None` when no watchdog applies to this process.
+pub fn watchdog_interval() -> Option<Duration> {
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok()? != std::process::id() {
+            return None;
+        }
+    }
+    Some(Duration::from_micros(usec / 2))
+}
+
+/// Sockets passed by systemd, keyed by FileDescriptorName, not yet taken.
+static LISTEN_FDS: Mutex<Option<HashMap<String, RawFd>>> = Mutex::new(None);
+
+/// Read and clear the socket activation variables, so children don't inherit
+/// them. Call first thing in `main`, before any thread or runtime exists, as
+/// changing the environment is only sound while single-threaded.
+pub fn init() {
+    let var = |name| std::env::var(name).ok();
+    let fds = parse_listen_fds(
+        var("LISTEN_PID").as_deref(),
+        var("LISTEN_FDS").as_deref(),
+        var("LISTEN_FDNAMES").as_deref(),
+        std::process::id(),
+    );
+    for var in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(var);
+    }
+    *LISTEN_FDS.lock().unwrap() = Some(fds);
+}
+
+/// Sockets passed to the process `own_pid` by the socket activation
+/// variables, keyed by name. Sockets without a name are `unknown`, and of
+/// sockets sharing a name only the first is kept.
+fn parse_listen_fds(
+    pid: Option<&str>,
+    count: Option<&str>,
+    names: Option<&str>,
+    own_pid: u32,
+) -> HashMap<String, RawFd> {
+    let pid = pid.and_then(|p| p.parse::<u32>().ok());
+    let count = count.and_then(|n| n.parse::<RawFd>().ok());
+    let (Some(pid), Some(count)) = (pid, count) else {
+        return HashMap::new();
+    };
+    if pid != own_pid || count <= 0 {
+        return HashMap::new();
+    }
+    if count > MAX_LISTEN_FDS {
+        warn!(count, "Ignoring sockets passed by systemd past the first {}", MAX_LISTEN_FDS);
+    }
+    let names: Vec<&str> = names.unwrap_or_default().split(':').collect();
+    let mut fds = HashMap::new();
+    for i in 0..count.min(MAX_LISTEN_FDS) {
+        let name = names.get(i as usize).copied().filter(|n| !n.is_empty()).unwrap_or("unknown");
+        fds.entry(name.to_string()).or_insert(LISTEN_FDS_START + i);
+    }
+    fds
+}
+
+/// Take the Unix listener systemd passed with the given `FileDescriptorName=`,
+/// if the service was socket activated. Each socket can only be taken once,
+/// and one that is not a listening Unix stream socket is refused.
+#[allow(unsafe_code)]
+pub fn take_unix_listener(name: &str) -> Option<UnixListener> {
+    let fd = LISTEN_FDS.lock().unwrap().as_mut()?.remove(name)?;
+    // Safety: systemd passed us this fd, and removing it from the map above
+    // guarantees it is only ever wrapped once.
+    let listener = unsafe { UnixListener::from_raw_fd(fd) };
+    if let Err(e) = check_unix_listener(&listener) {
+        warn!(name, fd, "Ignoring socket passed by systemd: {}", e);
+        return None;
+    }
+    debug!(name, fd, "Using socket-activated listener");
+    if let Err(e) = listener.set_nonblocking(true) {
+        warn!(name, "Failed to make activated socket non-blocking: {}", e);
+        return None;
+    }
+    Some(listener)
+}
+
+/// Check that a socket is a listening Unix stream socket, as configured with
+/// `ListenStream=` and a path.
+fn check_unix_listener(listener: &UnixListener) -> Result<(), String> {
+    // Fails for sockets of other families
+    listener.local_addr().map_err(|e| format!("not a Unix socket: {}", e))?;
+    match getsockopt(listener, sockopt::SockType) {
+        Ok(SockType::Stream) => {}
+        Ok(other) => return Err(format!("a {:?} socket rather than a stream", other)),
+        Err(e) => return Err(format!("cannot read the socket type: {}", e)),
+    }
+    match getsockopt(listener, sockopt::AcceptConn) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err("not listening".to_string()),
+        Err(e) => Err(format!("cannot tell if it listens: {}", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_notify_datagram() {
+        let dir = std::env::temp_dir().join(format!("sshx-notify-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("notify.sock");
+        let _ = std::fs::remove_file(&path);
+        let server = UnixDatagram::bind(&path).unwrap();
+
+        send_notification(path.to_str().unwrap(), "READY=1").unwrap();
+        let mut buf = [0; 64];
+        let n = server.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_parse_listen_fds() {
+        let fds = parse_listen_fds(Some("42"), Some("3"), Some("admin:health"), 42);
+        assert_eq!(fds.len(), 3);
+        assert_eq!(fds["admin"], 3);
+        assert_eq!(fds["health"], 4);
+        assert_eq!(fds["unknown"], 5);
+
+        // Sockets meant for another process, or no sockets at all
+        assert!(parse_listen_fds(Some("41"), Some("1"), Some("admin"), 42).is_empty());
+        assert!(parse_listen_fds(None, Some("1"), Some("admin"), 42).is_empty());
+        assert!(parse_listen_fds(Some("42"), None, None, 42).is_empty());
+        assert!(parse_listen_fds(Some("42"), Some("0"), None, 42).is_empty());
+        assert!(parse_listen_fds(Some("42"), Some("-1"), None, 42).is_empty());
+        assert!(parse_listen_fds(Some("x"), Some("1"), None, 42).is_empty());
+
+        // The first of sockets sharing a name wins, and the count is capped
+        let fds = parse_listen_fds(Some("42"), Some("2"), Some("admin:admin"), 42);
+        assert_eq!(fds.len(), 1);
+        assert_eq!(fds["admin"], 3);
+        let fds = parse_listen_fds(Some("42"), Some("100000"), Some(""), 42);
+        assert_eq!(fds.len(), 1);
+        assert_eq!(fds["unknown"], 3);
+    }
+
+    #[test]
+    fn test_check_unix_listener() {
+        let dir = tempfile::tempdir().unwrap();
+        let listener = UnixListener::bind(dir.path().join("admin.sock")).unwrap();
+        assert!(check_unix_listener(&listener).is_ok());
+
+        // Passed fds are taken for listeners whatever they are
+        let datagram = UnixDatagram::bind(dir.path().join("dgram.sock")).unwrap();
+        let listener = UnixListener::from(std::os::fd::OwnedFd::from(datagram));
+        assert!(check_unix_listener(&listener).unwrap_err().contains("rather than a stream"));
+
+        let stream = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let listener = UnixListener::from(std::os::fd::OwnedFd::from(stream));
+        assert!(check_unix_listener(&listener).unwrap_err().contains("not a Unix socket"));
+    }
+}
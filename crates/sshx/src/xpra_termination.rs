@@ -0,0 +1,68 @@
+//! Notice sent to the client of a desktop session the host ended.
+//!
+//! A session terminated by the host, for being idle, at the end of a drain
+//! or by an administrator, used to just have its shell closed, leaving the
+//! web client with a frozen canvas and no idea why. The forwarder now sends
+//! the reason and time first, so the web client can say "Disconnected: idle
+//! timeout".
+
+use chrono::{DateTime, Utc};
+use sshx_core::proto::ShellTerminated;
+use sshx_core::Sid;
+
+use crate::xpra_logger::TerminationReason;
+
+/// Why and when the host terminated a session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TerminationNotice {
+    /// Why the session was terminated
+    pub reason: TerminationReason,
+    /// When the session was terminated
+    pub time: DateTime<Utc>,
+}
+
+impl TerminationNotice {
+    /// A notice of a session terminated now for `reason`
+    pub fn new(reason: TerminationReason) -> Self {
+        Self { reason, time: Utc::now() }
+    }
+
+    /// Explanation shown to the user
+    pub fn message(&self) -> &'static str {
+        match self.reason {
+            TerminationReason::ClientDisconnect => "Disconnected",
+            TerminationReason::AdminKill => "Disconnected: stopped by an administrator",
+            TerminationReason::Drain => "Disconnected: the host is going down for maintenance",
+            TerminationReason::IdleTimeout => "Disconnected: idle timeout",
+            TerminationReason::ProcessCrash => "Disconnected: the desktop crashed",
+            TerminationReason::Error => "Disconnected: the desktop failed",
+        }
+    }
+
+    /// Protocol message telling the client of shell `id` why its desktop
+    /// ended.
+    pub fn to_proto(&self, id: Sid) -> ShellTerminated {
+        ShellTerminated {
+            id: id.0,
+            reason: self.reason.as_str().to_string(),
+            message: self.message().to_string(),
+            time: self.time.timestamp_millis().max(0) as u64,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_proto() {
+        let time = "2024-05-01T10:00:00Z".parse().unwrap();
+        let notice = TerminationNotice { reason: TerminationReason::IdleTimeout, time };
+        let proto = notice.to_proto(Sid(4));
+        assert_eq!(proto.id, 4);
+        assert_eq!(proto.reason, "idle_timeout");
+        assert_eq!(proto.message, "Disconnected: idle timeout");
+        assert_eq!(proto.time, 1_714_557_600_000);
+    }
+}
@@ -0,0 +1,156 @@
+//! How times and durations are shown in text reports: in UTC or local time,
+//! as dates and times or relative to now ("3h 5m ago").
+//!
+//! The setting is made once per process, from the `time_display` config and
+//! the `--time-zone` and `--time-format` flags, and applies to every report.
+
+use std::str::FromStr;
+use std::sync::OnceLock;
+use anyhow::Result;
+use chrono::{DateTime, Local, Utc};
+use serde::{Deserialize, Serialize};
+
+static TIME_DISPLAY: OnceLock<TimeDisplay> = OnceLock::new();
+
+/// Time zone times are shown in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TimeZoneMode {
+    /// Coordinated universal time
+    #[default]
+    Utc,
+    /// The time zone of the machine running the command
+    Local,
+}
+
+impl FromStr for TimeZoneMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "utc" => Ok(Self::Utc),
+            "local" => Ok(Self::Local),
+            _ => anyhow::bail!("unknown time zone {s:?}, expected utc or local"),
+        }
+    }
+}
+
+/// Notation of times.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TimeNotation {
+    /// Date and time, like `2024-05-01 10:00:00 UTC`
+    #[default]
+    Iso,
+    /// Time from now, like `3h 5m ago` or `in 12m`
+    Relative,
+}
+
+impl FromStr for TimeNotation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "iso" => Ok(Self::Iso),
+            "relative" => Ok(Self::Relative),
+            _ => anyhow::bail!("unknown time format {s:?}, expected iso or relative"),
+        }
+    }
+}
+
+/// How times are shown in text reports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct TimeDisplay {
+    /// Time zone of dates and times
+    #[serde(default)]
+    pub zone: TimeZoneMode,
+    /// Dates and times, or times relative to now
+    #[serde(default)]
+    pub notation: TimeNotation,
+}
+
+impl TimeDisplay {
+    /// Show times this way in every report for the rest of the process.
+    /// Only the first call has an effect.
+    pub fn apply(self) {
+        let _ = TIME_DISPLAY.set(self);
+    }
+
+    /// The setting in effect, UTC dates and times unless applied.
+    pub fn current() -> Self {
+        TIME_DISPLAY.get().copied().unwrap_or_default()
+    }
+
+    /// `time` as set, relative to `now` for the relative notation.
+    pub fn format_at(&self, time: DateTime<Utc>, now: DateTime<Utc>) -> String {
+        match (self.notation, self.zone) {
+            (TimeNotation::Relative, _) => {
+                let secs = (now - time).num_seconds();
+                match secs {
+                    0 => "now".to_string(),
+                    1.. => format!("{} ago", format_duration(secs as u64)),
+                    _ => format!("in {}", format_duration(secs.unsigned_abs())),
+                }
+            }
+            (TimeNotation::Iso, TimeZoneMode::Utc) => time.format("%Y-%m-%d %H:%M:%S UTC").to_string(),
+            (TimeNotation::Iso, TimeZoneMode::Local) => {
+                time.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S %:z").to_string()
+            }
+        }
+    }
+}
+
+/// `time` as set for the process.
+pub fn format_time(time: DateTime<Utc>) -> String {
+    TimeDisplay::current().format_at(time, Utc::now())
+}
+
+/// A duration of `secs` seconds in its two largest units, like `45s`, `12m`,
+/// `3h 5m` or `2d 4h`.
+pub fn format_duration(secs: u64) -> String {
+    let (days, hours, minutes) = (secs / 86400, secs % 86400 / 3600, secs % 3600 / 60);
+    if days > 0 {
+        format!("{}d {}h", days, hours)
+    } else if hours > 0 {
+        format!("{}h {}m", hours, minutes)
+    } else if minutes > 0 {
+        format!("{}m", minutes)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone};
+
+    use super::*;
+
+    #[test]
+    fn test_format_at() {
+        let now = Utc.with_ymd_and_hms(2024, 5, 15, 10, 30, 0).unwrap();
+        let iso = TimeDisplay::default();
+        assert_eq!(iso.format_at(now, now), "2024-05-15 10:30:00 UTC");
+
+        let relative = TimeDisplay { notation: TimeNotation::Relative, ..TimeDisplay::default() };
+        assert_eq!(relative.format_at(now - Duration::minutes(185), now), "3h 5m ago");
+        assert_eq!(relative.format_at(now + Duration::minutes(12), now), "in 12m");
+        assert_eq!(relative.format_at(now, now), "now");
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(45), "45s");
+        assert_eq!(format_duration(720), "12m");
+        assert_eq!(format_duration(3 * 3600 + 300), "3h 5m");
+        assert_eq!(format_duration(2 * 86400 + 4 * 3600 + 59), "2d 4h");
+    }
+
+    #[test]
+    fn test_config() {
+        let display: TimeDisplay = serde_json::from_str(r#"{"zone": "local"}"#).unwrap();
+        assert_eq!(display.zone, TimeZoneMode::Local);
+        assert_eq!(display.notation, TimeNotation::Iso);
+        assert_eq!("relative".parse::<TimeNotation>().unwrap(), TimeNotation::Relative);
+    }
+}
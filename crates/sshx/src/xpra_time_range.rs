@@ -0,0 +1,206 @@
+//! Points in time and periods given relative to now, as accepted by the
+//! analysis and log commands and the admin API.
+//!
+//! A [`TimeSpec`] is written as an RFC 3339 time (`2024-05-01T08:00:00Z`), a
+//! date (`2024-05-01`, midnight UTC), a duration before now (`90s`, `30m`,
+//! `1h`, `7d`), `now`, `today`, `yesterday`, or `last monday` through
+//! `last sunday`. Days start at midnight UTC.
+
+use std::fmt;
+use std::str::FromStr;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveTime, Utc, Weekday};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::status_display::parse_duration;
+use crate::xpra_billing::BillingPeriod;
+
+const WEEKDAYS: [(Weekday, &str); 7] = [
+    (Weekday::Mon, "monday"),
+    (Weekday::Tue, "tuesday"),
+    (Weekday::Wed, "wednesday"),
+    (Weekday::Thu, "thursday"),
+    (Weekday::Fri, "friday"),
+    (Weekday::Sat, "saturday"),
+    (Weekday::Sun, "sunday"),
+];
+
+/// A point in time, given absolutely or relative to now.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeSpec {
+    /// A fixed time
+    At(DateTime<Utc>),
+    /// This many seconds before now
+    Ago(u64),
+    /// Start of today
+    Today,
+    /// Start of yesterday
+    Yesterday,
+    /// Start of the latest such weekday before today
+    Last(Weekday),
+}
+
+impl TimeSpec {
+    /// The time this stands for, with `now` as the current time.
+    pub fn resolve(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        let midnight = |date: NaiveDate| date.and_time(NaiveTime::MIN).and_utc();
+        let today = now.date_naive();
+        match *self {
+            TimeSpec::At(time) => time,
+            // Spans older than chrono can represent mean the start of time
+            TimeSpec::Ago(secs) => i64::try_from(secs)
+                .ok()
+                .and_then(Duration::try_seconds)
+                .and_then(|ago| now.checked_sub_signed(ago))
+                .unwrap_or(DateTime::<Utc>::MIN_UTC),
+            TimeSpec::Today => midnight(today),
+            TimeSpec::Yesterday => midnight(today - Duration::days(1)),
+            TimeSpec::Last(weekday) => {
+                let back = (today.weekday().num_days_from_monday() + 7 - weekday.num_days_from_monday()) % 7;
+                let back = if back == 0 { 7 } else { back };
+                midnight(today - Duration::days(back as i64))
+            }
+        }
+    }
+}
+
+impl FromStr for TimeSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let lower = s.to_lowercase();
+        match lower.as_str() {
+            "now" => return Ok(TimeSpec::Ago(0)),
+            "today" => return Ok(TimeSpec::Today),
+            "yesterday" => return Ok(TimeSpec::Yesterday),
+            _ => {}
+        }
+        if let Some(day) = lower.strip_prefix("last ") {
+            let day = day.trim();
+            return WEEKDAYS
+                .iter()
+                .find(|(_, name)| *name == day || name[..3] == *day)
+                .map(|(weekday, _)| TimeSpec::Last(*weekday))
+                .with_context(|| format!("invalid weekday in {s:?}, expected e.g. last monday"));
+        }
+        if let Ok(time) = DateTime::parse_from_rfc3339(s) {
+            return Ok(TimeSpec::At(time.with_timezone(&Utc)));
+        }
+        if let Ok(date) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+            return Ok(TimeSpec::At(date.and_time(NaiveTime::MIN).and_utc()));
+        }
+        parse_duration(s).map(TimeSpec::Ago).map_err(|_| {
+            anyhow::anyhow!(
+                "invalid time {s:?}, expected e.g. 7d, 30m, yesterday, last monday, 2024-05-01 or an RFC 3339 time"
+            )
+        })
+    }
+}
+
+impl fmt::Display for TimeSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TimeSpec::At(time) => write!(f, "{}", time.to_rfc3339()),
+            TimeSpec::Ago(0) => f.write_str("now"),
+            TimeSpec::Ago(secs) => write!(f, "{secs}s"),
+            TimeSpec::Today => f.write_str("today"),
+            TimeSpec::Yesterday => f.write_str("yesterday"),
+            TimeSpec::Last(weekday) => {
+                let (_, name) = WEEKDAYS.iter().find(|(day, _)| day == weekday).unwrap();
+                write!(f, "last {name}")
+            }
+        }
+    }
+}
+
+// Sent over the admin API as written, so relative times are resolved by the
+// host against its own clock
+impl Serialize for TimeSpec {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for TimeSpec {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// A period from `start` to `end`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeRange {
+    /// Start of the period
+    pub start: DateTime<Utc>,
+    /// End of the period
+    pub end: DateTime<Utc>,
+}
+
+impl TimeRange {
+    /// The period from `since` until `until`, or until now if not given.
+    pub fn between(since: TimeSpec, until: Option<TimeSpec>, now: DateTime<Utc>) -> Result<Self> {
+        let start = since.resolve(now);
+        let end = until.map_or(now, |until| until.resolve(now));
+        if start > end {
+            anyhow::bail!("time range starts at {start} after it ends at {end}");
+        }
+        Ok(Self { start, end })
+    }
+
+    /// The calendar month before the one containing `now`.
+    pub fn last_month(now: DateTime<Utc>) -> Self {
+        let month = BillingPeriod::previous(now);
+        Self { start: month.start(), end: month.end() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_and_resolve() {
+        // A Wednesday
+        let now = Utc.with_ymd_and_hms(2024, 5, 15, 10, 30, 0).unwrap();
+        let resolve = |s: &str| s.parse::<TimeSpec>().unwrap().resolve(now);
+        assert_eq!(resolve("7d"), Utc.with_ymd_and_hms(2024, 5, 8, 10, 30, 0).unwrap());
+        assert_eq!(resolve("30m"), Utc.with_ymd_and_hms(2024, 5, 15, 10, 0, 0).unwrap());
+        assert_eq!(resolve("now"), now);
+        assert_eq!(resolve("today"), Utc.with_ymd_and_hms(2024, 5, 15, 0, 0, 0).unwrap());
+        assert_eq!(resolve("Yesterday"), Utc.with_ymd_and_hms(2024, 5, 14, 0, 0, 0).unwrap());
+        assert_eq!(resolve("last monday"), Utc.with_ymd_and_hms(2024, 5, 13, 0, 0, 0).unwrap());
+        assert_eq!(resolve("last wed"), Utc.with_ymd_and_hms(2024, 5, 8, 0, 0, 0).unwrap());
+        assert_eq!(resolve("2024-05-01"), Utc.with_ymd_and_hms(2024, 5, 1, 0, 0, 0).unwrap());
+        assert_eq!(resolve("2024-05-01T08:00:00+02:00"), Utc.with_ymd_and_hms(2024, 5, 1, 6, 0, 0).unwrap());
+        assert!("last funday".parse::<TimeSpec>().is_err());
+        assert!("soon".parse::<TimeSpec>().is_err());
+        assert_eq!(TimeSpec::Ago(u64::MAX).resolve(now), DateTime::<Utc>::MIN_UTC);
+        assert_eq!(resolve("9999999999d"), DateTime::<Utc>::MIN_UTC);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for s in ["90s", "now", "today", "yesterday", "last sunday", "2024-05-01T08:00:00+00:00"] {
+            let spec: TimeSpec = s.parse().unwrap();
+            assert_eq!(spec.to_string(), s);
+            let json = serde_json::to_string(&spec).unwrap();
+            assert_eq!(serde_json::from_str::<TimeSpec>(&json).unwrap(), spec);
+        }
+    }
+
+    #[test]
+    fn test_ranges() {
+        let now = Utc.with_ymd_and_hms(2024, 1, 10, 12, 0, 0).unwrap();
+        let month = TimeRange::last_month(now);
+        assert_eq!(month.start, Utc.with_ymd_and_hms(2023, 12, 1, 0, 0, 0).unwrap());
+        assert_eq!(month.end, Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap());
+
+        let range = TimeRange::between(TimeSpec::Yesterday, Some(TimeSpec::Today), now).unwrap();
+        assert_eq!(range.end - range.start, Duration::days(1));
+        assert!(TimeRange::between(TimeSpec::Today, Some(TimeSpec::Yesterday), now).is_err());
+    }
+}
@@ -0,0 +1,202 @@
+//! TLS on the link between the forwarder and xpra on worker hosts.
+//!
+//! Desktops on workers are reached over the network, where xpra's plain
+//! websocket would carry every frame of the desktop unencrypted. With `tls`
+//! set on a host, xpra serves `wss://` with its own certificate and only
+//! accepts clients presenting a certificate signed by the configured CA. The
+//! forwarder presents its certificate in turn, and checks that the worker's
+//! certificate is signed by its CA and names the host's address, or the
+//! configured `server_name`, among its subject alternative names.
+
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::{client_async, WebSocketStream};
+
+/// Certificates of the mutual TLS between the forwarder and a worker's xpra.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TlsConfig {
+    /// PEM file of the CA worker certificates are signed by, on this host
+    pub ca_cert: PathBuf,
+
+    /// PEM file of the certificate the forwarder presents, on this host
+    pub client_cert: PathBuf,
+
+    /// PEM file of the key of `client_cert`, on this host
+    pub client_key: PathBuf,
+
+    /// PEM file of the certificate xpra presents, on the worker
+    pub server_cert: PathBuf,
+
+    /// PEM file of the key of `server_cert`, on the worker
+    pub server_key: PathBuf,
+
+    /// PEM file of the CA the forwarder's certificate is signed by, on the
+    /// worker
+    pub client_ca: PathBuf,
+
+    /// Name the worker's certificate must hold; the host's address if unset
+    #[serde(default)]
+    pub server_name: Option<String>,
+}
+
+impl TlsConfig {
+    /// Arguments for `xpra` to serve TLS with the worker's certificate and
+    /// to require clients to present one signed by `client_ca`.
+    pub fn xpra_args(&self) -> Vec<String> {
+        vec![
+            format!("--ssl-cert={}", self.server_cert.display()),
+            format!("--ssl-key={}", self.server_key.display()),
+            format!("--ssl-ca-certs={}", self.client_ca.display()),
+            "--ssl-client-verify-mode=required".to_string(),
+        ]
+    }
+
+    /// Paths passed to xpra, to be checked as its arguments.
+    pub fn worker_paths(&self) -> [&Path; 3] {
+        [&self.server_cert, &self.server_key, &self.client_ca]
+    }
+
+    /// The client side of the link to a worker at `address`, with the
+    /// certificates loaded.
+    pub fn client(&self, address: &str) -> Result<TlsClient> {
+        let connector = connector(&self.ca_cert, Some((&self.client_cert, &self.client_key)))?;
+        let server_name = server_name(self.server_name.as_deref().unwrap_or(address))?;
+        Ok(TlsClient { connector, server_name })
+    }
+}
+
+/// Connector trusting the CAs in `ca_cert`, and presenting the certificate
+/// and key of `identity` if given.
+pub fn connector(ca_cert: &Path, identity: Option<(&Path, &Path)>) -> Result<TlsConnector> {
+    let mut roots = RootCertStore::empty();
+    for cert in read_certs(ca_cert)? {
+        roots.add(cert).with_context(|| format!("invalid CA certificate in {}", ca_cert.display()))?;
+    }
+    let builder = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots);
+    let config = match identity {
+        Some((cert, key)) => builder
+            .with_client_auth_cert(read_certs(cert)?, read_key(key)?)
+            .context("invalid client certificate or key")?,
+        None => builder.with_no_client_auth(),
+    };
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+/// Name a server certificate must hold, from a host name or IP address.
+pub fn server_name(name: &str) -> Result<ServerName<'static>> {
+    // Addresses of IPv6 hosts may be bracketed
+    let name = name.trim_start_matches('[').trim_end_matches(']');
+    ServerName::try_from(name.to_string()).with_context(|| format!("{name:?} is no valid name for a server certificate"))
+}
+
+/// Connects to the xpra of one worker over TLS.
+#[derive(Clone)]
+pub struct TlsClient {
+    connector: TlsConnector,
+    server_name: ServerName<'static>,
+}
+
+impl fmt::Debug for TlsClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsClient").field("server_name", &self.server_name).finish_non_exhaustive()
+    }
+}
+
+/// A connection to xpra, over TLS or not.
+pub trait XpraIo: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> XpraIo for T {}
+
+/// A websocket to xpra.
+pub type XpraStream = WebSocketStream<Box<dyn XpraIo>>;
+
+/// Open the websocket at `url`, over TLS if `tls` is given.
+pub async fn connect(url: &str, tls: Option<&TlsClient>) -> Result<XpraStream> {
+    let request = url.into_client_request()?;
+    let uri = request.uri();
+    let host = uri.host().context("no host in the websocket URL")?;
+    let port = uri.port_u16().context("no port in the websocket URL")?;
+    // Addresses of IPv6 hosts are bracketed in URLs
+    let tcp = TcpStream::connect((host.trim_start_matches('[').trim_end_matches(']'), port)).await?;
+    let io: Box<dyn XpraIo> = match tls {
+        Some(tls) => Box::new(tls.connector.connect(tls.server_name.clone(), tcp).await?),
+        None => Box::new(tcp),
+    };
+    let (stream, _) = client_async(request, io).await?;
+    Ok(stream)
+}
+
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let file = File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("failed to read certificates from {}", path.display()))?;
+    if certs.is_empty() {
+        anyhow::bail!("no certificates in {}", path.display());
+    }
+    Ok(certs)
+}
+
+fn read_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    let file = File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .with_context(|| format!("failed to read the key from {}", path.display()))?
+        .with_context(|| format!("no private key in {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> TlsConfig {
+        TlsConfig {
+            ca_cert: "/etc/sshx/worker-ca.pem".into(),
+            client_cert: "/etc/sshx/forwarder.pem".into(),
+            client_key: "/etc/sshx/forwarder.key".into(),
+            server_cert: "/etc/xpra/worker.pem".into(),
+            server_key: "/etc/xpra/worker.key".into(),
+            client_ca: "/etc/xpra/forwarder-ca.pem".into(),
+            server_name: None,
+        }
+    }
+
+    #[test]
+    fn test_xpra_args() {
+        assert_eq!(
+            config().xpra_args(),
+            [
+                "--ssl-cert=/etc/xpra/worker.pem",
+                "--ssl-key=/etc/xpra/worker.key",
+                "--ssl-ca-certs=/etc/xpra/forwarder-ca.pem",
+                "--ssl-client-verify-mode=required",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_client_without_certificates() {
+        let dir = std::env::temp_dir().join(format!("sshx-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let ca = dir.join("ca.pem");
+        std::fs::write(&ca, "not a certificate\n").unwrap();
+        let mut config = config();
+        assert!(config.client("worker1").unwrap_err().to_string().contains("worker-ca.pem"));
+        config.ca_cert = ca;
+        assert!(config.client("worker1").unwrap_err().to_string().contains("no certificates"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,9 +1,13 @@
+//! Terminal and JSON reports of a log analysis.
+
 use std::io::Write;
-use chrono::{DateTime, Duration, Utc};
+use chrono::Duration;
 use colored::*;
 use tabled::{Table, Tabled};
-use terminal_charts::{Chart, ChartBuilder, TimeSeries};
-use crate::xpra_log_analyzer::{LogAnalysis, UserStats};
+use crate::xpra_chart::ChartStyle;
+use crate::xpra_export;
+use crate::xpra_log_analyzer::{HourlyStats, LogAnalysis, TimelinePoint};
+use crate::xpra_time_format::{self, format_time};
 
 #[derive(Tabled)]
 struct UserRow {
@@ -17,10 +21,21 @@ struct UserRow {
     idle_terms: String,
 }
 
-pub fn display_analysis(analysis: &LogAnalysis, format: &str) -> anyhow::Result<()> {
+/// Print a log analysis as a text report (`text`), as JSON (`json`), as
+/// InfluxDB line protocol (`influx`) or as a Grafana simple JSON datasource
+/// response (`grafana`), drawing the charts of the text report in `chart`.
+pub fn display_analysis(analysis: &LogAnalysis, format: &str, chart: &ChartStyle) -> anyhow::Result<()> {
     match format {
         "json" => display_json(analysis),
-        "text" => display_text(analysis),
+        "text" => display_text(analysis, chart),
+        "influx" => {
+            print!("{}", xpra_export::influx_lines(analysis));
+            Ok(())
+        }
+        "grafana" => {
+            println!("{}", serde_json::to_string_pretty(&xpra_export::grafana_json(analysis))?);
+            Ok(())
+        }
         _ => anyhow::bail!("Unsupported format: {}", format),
     }
 }
@@ -30,23 +45,93 @@ fn display_json(analysis: &LogAnalysis) -> anyhow::Result<()> {
     Ok(())
 }
 
-fn display_text(analysis: &LogAnalysis) -> anyhow::Result<()> {
+fn display_text(analysis: &LogAnalysis, chart: &ChartStyle) -> anyhow::Result<()> {
     let stdout = std::io::stdout();
     let mut out = stdout.lock();
 
     // Period header
     writeln!(out, "\n{}", "Analysis Period:".bold())?;
-    writeln!(out, "  From: {}", analysis.period.start.format("%Y-%m-%d %H:%M:%S UTC"))?;
-    writeln!(out, "  To:   {}", analysis.period.end.format("%Y-%m-%d %H:%M:%S UTC"))?;
+    writeln!(out, "  From: {}", format_time(analysis.period.start))?;
+    writeln!(out, "  To:   {}", format_time(analysis.period.end))?;
 
     // Overall statistics
     writeln!(out, "\n{}", "Session Statistics:".bold())?;
     writeln!(out, "  Total Sessions:     {}", analysis.session_stats.total_sessions)?;
+    writeln!(out, "  Carried Over:       {}", analysis.session_stats.carried_over)?;
+    writeln!(out, "  Open at End:        {}", analysis.session_stats.open_at_end)?;
     writeln!(out, "  Average Duration:   {}", format_duration(analysis.session_stats.avg_duration))?;
     writeln!(out, "  Max Concurrent:     {}", analysis.session_stats.max_concurrent)?;
     writeln!(out, "  Idle Terminations:  {}", analysis.session_stats.idle_terminations)?;
     writeln!(out, "  Failed Sessions:    {}", analysis.session_stats.failed_sessions.to_string().red())?;
 
+    // Why sessions ended, most common first
+    let mut reasons: Vec<_> = analysis.session_stats.termination_reasons.iter().collect();
+    reasons.sort_by(|a, b| b.1.cmp(a.1).then(a.0.as_str().cmp(b.0.as_str())));
+    if !reasons.is_empty() {
+        writeln!(out, "\n{}", "Termination Reasons:".bold())?;
+        for (reason, count) in reasons {
+            let line = format!("  {:<19} {}", format!("{}:", reason), count);
+            match reason.is_failure() {
+                true => writeln!(out, "{}", line.red())?,
+                false => writeln!(out, "{}", line)?,
+            }
+        }
+    }
+
+    // Problems in the xpra server logs, most common first
+    let mut issues: Vec<_> = analysis.server_issues.iter().collect();
+    issues.sort_by(|a, b| b.1.cmp(a.1).then(a.0.as_str().cmp(b.0.as_str())));
+    if !issues.is_empty() {
+        writeln!(out, "\n{}", "Server Log Issues:".bold())?;
+        for (kind, count) in issues {
+            writeln!(out, "  {:<19} {}", format!("{}:", kind), count)?;
+        }
+    }
+
+    // Failed sessions with what their xpra logged
+    if !analysis.failures.is_empty() {
+        writeln!(out, "\n{}", "Failed Sessions:".bold())?;
+        for failure in &analysis.failures {
+            let reason = failure.reason.map_or("unknown".to_string(), |reason| reason.to_string());
+            writeln!(
+                out,
+                "  {} {} ({}, display :{}): {}",
+                format_time(failure.failed_at),
+                failure.session_id,
+                failure.user,
+                failure.display,
+                reason.red(),
+            )?;
+            if let Some(parent) = &failure.parent {
+                writeln!(out, "    started from: {}", parent)?;
+            }
+            if let Some(bundle) = &failure.crash_bundle {
+                writeln!(out, "    crash bundle: {}", bundle.display())?;
+            }
+            for event in &failure.server_events {
+                writeln!(out, "    {} [{}] {}", format_time(event.timestamp), event.kind, event.message)?;
+            }
+        }
+    }
+
+    // Desktops started from each sshx session
+    if !analysis.spawned.is_empty() {
+        writeln!(out, "\n{}", "Desktops by sshx Session:".bold())?;
+        for (session, desktops) in &analysis.spawned {
+            writeln!(out, "  {}:", session)?;
+            for desktop in desktops {
+                writeln!(
+                    out,
+                    "    shell {} {} ({}, started {})",
+                    desktop.shell,
+                    desktop.session_id,
+                    desktop.user,
+                    format_time(desktop.started),
+                )?;
+            }
+        }
+    }
+
     // User statistics table
     let user_rows: Vec<UserRow> = analysis.user_stats
         .iter()
@@ -66,35 +151,46 @@ fn display_text(analysis: &LogAnalysis) -> anyhow::Result<()> {
 
     // Hourly distribution chart
     writeln!(out, "\n{}", "Hourly Distribution:".bold())?;
-    display_hourly_chart(&mut out, &analysis.hourly_distribution)?;
+    display_hourly_chart(&mut out, &analysis.hourly_distribution, chart)?;
+
+    // Concurrency over the period
+    if !analysis.timeline.is_empty() {
+        writeln!(out, "\n{}", "Concurrent Sessions:".bold())?;
+        display_concurrency(&mut out, &analysis.timeline, chart)?;
+    }
 
     Ok(())
 }
 
-fn display_hourly_chart(out: &mut impl Write, distribution: &[HourlyStats]) -> anyhow::Result<()> {
-    let data: Vec<(f64, f64)> = distribution.iter()
-        .map(|stat| (stat.hour as f64, stat.session_count as f64))
+fn display_hourly_chart(out: &mut impl Write, distribution: &[HourlyStats], chart: &ChartStyle) -> anyhow::Result<()> {
+    // Bars scaled to the busiest hour
+    let rows: Vec<(String, u64)> = distribution
+        .iter()
+        .map(|stat| (format!("{:02}:00", stat.hour), stat.session_count as u64))
         .collect();
+    for line in chart.bar_chart(&rows) {
+        writeln!(out, "  {}", line)?;
+    }
+    Ok(())
+}
 
-    let chart = ChartBuilder::new()
-        .width(60)
-        .height(15)
-        .caption("Sessions by Hour")
-        .series(TimeSeries::new(data))
-        .build()?;
-
-    writeln!(out, "{}", chart)?;
+fn display_concurrency(out: &mut impl Write, timeline: &[TimelinePoint], chart: &ChartStyle) -> anyhow::Result<()> {
+    // Peak of each hour, merged into wider columns for long periods
+    let peaks: Vec<u64> = timeline.iter().map(|point| point.max_concurrent as u64).collect();
+    let peak = peaks.iter().max().copied().unwrap_or(0);
+    writeln!(out, "  {}", chart.sparkline(&peaks))?;
+    if let (Some(first), Some(last)) = (timeline.first(), timeline.last()) {
+        writeln!(
+            out,
+            "  {} to {}, peak {}",
+            format_time(first.time),
+            format_time(last.time + Duration::hours(1)),
+            peak,
+        )?;
+    }
     Ok(())
 }
 
 fn format_duration(duration: Duration) -> String {
-    let total_seconds = duration.num_seconds();
-    let hours = total_seconds / 3600;
-    let minutes = (total_seconds % 3600) / 60;
-    
-    if hours > 0 {
-        format!("{}h {}m", hours, minutes)
-    } else {
-        format!("{}m", minutes)
-    }
+    xpra_time_format::format_duration(duration.num_seconds().max(0) as u64)
 }
@@ -0,0 +1,282 @@
+//! Watchdog of the window manager of each desktop display.
+//!
+//! A window manager can die while xpra keeps running, leaving users a gray
+//! screen. The watchdog checks each running display in turn: that its root
+//! window still answers, and that the window manager is still a process
+//! under the display's xpra server. A dead window manager is started again
+//! as one of the desktop's programs, like the display's other programs, up
+//! to `max_restarts` times per session; past that, or while the root window
+//! does not answer, the session is flagged unhealthy in status output.
+
+use std::fmt;
+use std::path::Path;
+use std::time::Duration;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::time;
+use tracing::{debug, info, warn};
+
+use crate::xpra_children::{ChildProgram, RestartPolicy, SessionChildren, WINDOW_MANAGER};
+use crate::xpra_hosts::WorkerHost;
+use crate::xpra_inspect::{parse_server, xpra_info};
+use crate::xpra_monitor::SessionMonitor;
+use crate::xpra_session_state::SessionState;
+
+/// When and how the window managers of displays are checked.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WatchdogConfig {
+    /// Seconds between checks of the displays, 0 to disable the watchdog
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+
+    /// Seconds the root window of a display has to answer, 0 to skip this
+    /// check
+    #[serde(default = "default_root_window_timeout")]
+    pub root_window_timeout: u64,
+
+    /// Times a dead window manager is restarted in one session before the
+    /// session is only flagged, 0 to never restart it
+    #[serde(default = "default_max_restarts")]
+    pub max_restarts: u32,
+}
+
+fn default_interval() -> u64 { 30 }
+fn default_root_window_timeout() -> u64 { 5 }
+fn default_max_restarts() -> u32 { 3 }
+
+impl Default for WatchdogConfig {
+    fn default() -> Self {
+        Self {
+            interval: default_interval(),
+            root_window_timeout: default_root_window_timeout(),
+            max_restarts: default_max_restarts(),
+        }
+    }
+}
+
+/// What the watchdog last found on a display.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DisplayHealth {
+    /// The window manager runs and the root window answers, or the display
+    /// was not checked yet
+    #[default]
+    Healthy,
+    /// The window manager is gone and was not restarted
+    WindowManagerDown,
+    /// The root window did not answer in time
+    Unresponsive,
+}
+
+impl DisplayHealth {
+    /// Name of the health, as shown in status output
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DisplayHealth::Healthy => "healthy",
+            DisplayHealth::WindowManagerDown => "window_manager_down",
+            DisplayHealth::Unresponsive => "unresponsive",
+        }
+    }
+
+    /// Whether the display works as far as the watchdog can tell
+    pub fn is_healthy(&self) -> bool {
+        *self == DisplayHealth::Healthy
+    }
+}
+
+impl fmt::Display for DisplayHealth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Check the displays of running sessions in the background, if enabled.
+pub fn start(monitor: SessionMonitor, config: WatchdogConfig) {
+    if config.interval == 0 {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut interval = time::interval(Duration::from_secs(config.interval));
+        loop {
+            interval.tick().await;
+            check_sessions(&monitor, &config).await;
+        }
+    });
+}
+
+/// What the watchdog needs of a session whose display it checks.
+struct Watched {
+    session_id: String,
+    host: String,
+    display: u16,
+    wm: String,
+    wm_restarts: u32,
+    health: DisplayHealth,
+    children: SessionChildren,
+}
+
+async fn check_sessions(monitor: &SessionMonitor, config: &WatchdogConfig) {
+    let sessions = monitor.collect_sessions(|id, session| {
+        // Displays without a window manager, or not up (any more)
+        let state = session.lifecycle.state();
+        if session.hibernated || session.headless || state == SessionState::Starting || state.is_final() {
+            return None;
+        }
+        Some(Watched {
+            session_id: id.to_string(),
+            host: session.host.clone(),
+            display: session.display,
+            wm: session.config.window_manager.clone(),
+            wm_restarts: session.wm_restarts,
+            health: session.health,
+            children: session.children.clone(),
+        })
+    });
+    for session in sessions {
+        let session_id = &session.session_id;
+        let Some(host) = monitor.hosts().get(&session.host) else {
+            continue;
+        };
+        let wm = &session.wm;
+        let Some(mut health) = check_display(&host, session.display, wm, config).await else {
+            continue;
+        };
+        // A window manager started again runs next to xpra, not under it
+        if health == DisplayHealth::WindowManagerDown && session.children.is_running(WINDOW_MANAGER) {
+            health = DisplayHealth::Healthy;
+        }
+        if health == DisplayHealth::WindowManagerDown && session.wm_restarts < config.max_restarts {
+            match restart_window_manager(&session.children, wm) {
+                Ok(()) => {
+                    let restarts = monitor.window_manager_restarted(session_id).await;
+                    info!(session_id, display = session.display, restarts, "Restarted dead window manager {}", wm);
+                    health = DisplayHealth::Healthy;
+                }
+                Err(e) => warn!(session_id, display = session.display, "Failed to restart window manager: {:#}", e),
+            }
+        }
+        if health != session.health {
+            if !health.is_healthy() {
+                warn!(session_id, display = session.display, %health, "Desktop display is unhealthy");
+            }
+            monitor.set_health(session_id, health).await;
+        }
+    }
+}
+
+/// Check the root window and the window manager `wm` of a display, or
+/// `None` if the window manager could not be looked for.
+pub async fn check_display(
+    host: &WorkerHost,
+    number: u16,
+    wm: &str,
+    config: &WatchdogConfig,
+) -> Option<DisplayHealth> {
+    if config.root_window_timeout > 0 {
+        let timeout = Duration::from_secs(config.root_window_timeout);
+        if !root_window_responds(host, number, timeout).await {
+            return Some(DisplayHealth::Unresponsive);
+        }
+    }
+    match window_manager_running(host, number, wm).await {
+        Ok(false) => Some(DisplayHealth::WindowManagerDown),
+        Ok(true) => Some(DisplayHealth::Healthy),
+        Err(e) => {
+            debug!(display = number, "Failed to look for the window manager: {:#}", e);
+            None
+        }
+    }
+}
+
+/// Whether the X server of a display answers a query of its root window in
+/// time.
+async fn root_window_responds(host: &WorkerHost, display: u16, timeout: Duration) -> bool {
+    let args = [format!("DISPLAY=:{}", display), "xprop".to_string(), "-root".to_string(), "-len".to_string(), "0".to_string()];
+    let mut command = tokio::process::Command::from(host.command("env", &args));
+    command.kill_on_drop(true);
+    matches!(time::timeout(timeout, command.output()).await, Ok(Ok(output)) if output.status.success())
+}
+
+/// Whether a process running `wm` is under the xpra server of a display.
+async fn window_manager_running(host: &WorkerHost, display: u16, wm: &str) -> Result<bool> {
+    let pid = parse_server(&xpra_info(host, display).await?)
+        .pid
+        .context("xpra info has no server pid")?;
+    let args = ["-eo".to_string(), "pid=,ppid=,args=".to_string()];
+    let output = tokio::process::Command::from(host.command("ps", &args))
+        .output()
+        .await
+        .context("failed to run ps")?;
+    if !output.status.success() {
+        anyhow::bail!("ps exited with {}", output.status);
+    }
+    Ok(runs_program(&String::from_utf8_lossy(&output.stdout), pid, wm))
+}
+
+/// Whether a descendant of `root` in `ps -eo pid=,ppid=,args=` output runs
+/// the program of the command line `wm`, directly or through a shell.
+fn runs_program(table: &str, root: u32, wm: &str) -> bool {
+    let Some(program) = wm.split_whitespace().next().map(basename) else {
+        return false;
+    };
+    let rows: Vec<(u32, u32, &str)> = table
+        .lines()
+        .filter_map(|line| {
+            let (pid, rest) = line.trim_start().split_once(char::is_whitespace)?;
+            let rest = rest.trim_start();
+            let (ppid, args) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+            Some((pid.parse().ok()?, ppid.parse().ok()?, args.trim_start()))
+        })
+        .collect();
+
+    let mut tree = vec![root];
+    let mut i = 0;
+    while i < tree.len() {
+        let parent = tree[i];
+        for (pid, _, args) in rows.iter().filter(|(_, ppid, _)| *ppid == parent) {
+            if args.split_whitespace().take(3).any(|word| basename(word) == program) {
+                return true;
+            }
+            tree.push(*pid);
+        }
+        i += 1;
+    }
+    false
+}
+
+fn basename(path: &str) -> &str {
+    Path::new(path).file_name().and_then(|name| name.to_str()).unwrap_or(path)
+}
+
+/// Start the window manager `wm` on a display again, as one of its
+/// programs. The watchdog decides on further restarts, so the program
+/// itself is never restarted.
+fn restart_window_manager(children: &SessionChildren, wm: &str) -> Result<()> {
+    children.start(ChildProgram {
+        name: WINDOW_MANAGER.to_string(),
+        command: wm.to_string(),
+        restart: RestartPolicy::Never,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_runs_program() {
+        let table = "\
+    1     0 /sbin/init
+  200     1 /usr/bin/python3 /usr/bin/xpra start :100
+  201   200 /usr/lib/xorg/Xorg -noreset :100
+  202   200 /bin/sh -c gnome-flashback --replace
+  203   202 /usr/bin/gnome-flashback --replace
+  300     1 /usr/bin/gnome-flashback
+";
+        assert!(runs_program(table, 200, "gnome-flashback"));
+        assert!(runs_program(table, 200, "/usr/bin/gnome-flashback --replace"));
+        assert!(!runs_program(table, 200, "startxfce4"));
+        // Only processes under the display's xpra count
+        assert!(!runs_program(table, 201, "gnome-flashback"));
+    }
+}
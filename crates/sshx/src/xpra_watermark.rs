@@ -0,0 +1,148 @@
+//! Watermark naming the user across their desktop.
+//!
+//! Screenshots and photos of a desktop leaked by its user are only
+//! attributable if the desktop says whose it is. For the users and groups
+//! `watermark` lists, a program started next to the window manager draws
+//! the user's name and the time across the middle of the screen with
+//! libaosd's `aosd_cat`, blended over the desktop by a compositor, so the
+//! desktop stays readable under it. `xcompmgr` is started for that, and
+//! exits again if the desktop has a compositor already.
+//!
+//! The user must not be able to get rid of the watermark. Its drawing loop
+//! ignores the signals a plain `kill` sends, and every redraw maps a new
+//! window, so one killed through X or unmapped comes back at the next
+//! redraw. A loop killed outright is restarted, like whenever it exits, and
+//! again when a hibernated desktop resumes.
+
+use serde::{Deserialize, Serialize};
+
+use crate::xpra_children::{ChildProgram, RestartPolicy};
+use crate::xpra_devices::listed;
+use crate::xpra_hosts::shell_quote;
+use crate::xpra_motd::MotdVars;
+
+/// Watermark drawn on the desktops of some users.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WatermarkConfig {
+    /// Users whose desktops are watermarked: user names, `@group`, or `*`
+    /// for everyone. Nobody's by default.
+    #[serde(default)]
+    pub users: Vec<String>,
+
+    /// Text drawn, with `{user}`, `{host}`, `{display}` and `{session}`
+    /// placeholders and the `%` fields of `date` for the time
+    #[serde(default = "default_format")]
+    pub format: String,
+
+    /// Pango font of the text
+    #[serde(default = "default_font")]
+    pub font: String,
+
+    /// Color of the text
+    #[serde(default = "default_color")]
+    pub color: String,
+
+    /// Opacity of the text in percent; a low one keeps the desktop readable
+    #[serde(default = "default_opacity")]
+    pub opacity: u8,
+
+    /// Seconds between redraws of the watermark, at most 60
+    #[serde(default = "default_refresh")]
+    pub refresh: u64,
+}
+
+fn default_format() -> String { "{user}@{host} %Y-%m-%d %H:%M".to_string() }
+
+fn default_font() -> String { "Sans Bold 28".to_string() }
+
+fn default_color() -> String { "#d0d0d0".to_string() }
+
+fn default_opacity() -> u8 { 30 }
+
+fn default_refresh() -> u64 { 10 }
+
+/// Longest time between redraws, and so the longest a watermark unmapped
+/// by the user stays away.
+const MAX_REFRESH: u64 = 60;
+
+impl Default for WatermarkConfig {
+    fn default() -> Self {
+        Self {
+            users: Vec::new(),
+            format: default_format(),
+            font: default_font(),
+            color: default_color(),
+            opacity: default_opacity(),
+            refresh: default_refresh(),
+        }
+    }
+}
+
+impl WatermarkConfig {
+    /// The `date` format of the text, with its placeholders filled in.
+    pub fn render(&self, vars: &MotdVars, session_id: &str) -> String {
+        // Values are text for date, not fields
+        let value = |value: &str| value.replace('%', "%%");
+        self.format
+            .replace("{user}", &value(vars.user))
+            .replace("{host}", &value(vars.host))
+            .replace("{display}", &vars.display.to_string())
+            .replace("{session}", &value(session_id))
+    }
+
+    /// The program drawing the watermark on the desktop of `vars.user`, if
+    /// their desktops are watermarked.
+    pub async fn program(&self, vars: &MotdVars<'_>, session_id: &str) -> Option<ChildProgram> {
+        if !listed(&self.users, vars.user).await {
+            return None;
+        }
+        let format = self.render(vars, session_id);
+        // Each window is shown until the next redraw; one that fails to
+        // draw is retried shortly rather than ending the loop
+        let command = format!(
+            "trap '' HUP INT TERM; xcompmgr -n >/dev/null 2>&1 & \
+             while :; do date {format} | aosd_cat --position=4 --transparency=2 --font={font} \
+             --fore-color={color} --fore-opacity={opacity} --back-opacity=0 --shadow-opacity=0 \
+             --fade-in=0 --fade-out=0 --fade-full={duration} || sleep 1; done",
+            format = shell_quote(&format!("+{format}")),
+            font = shell_quote(&self.font),
+            color = shell_quote(&self.color),
+            opacity = u32::from(self.opacity.min(100)) * 255 / 100,
+            duration = self.refresh.clamp(1, MAX_REFRESH) * 1000,
+        );
+        Some(ChildProgram { name: "watermark".to_string(), command, restart: RestartPolicy::Always })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_program() {
+        let vars = MotdVars { user: "alice", host: "worker1", display: 100, idle_timeout: 0 };
+        assert!(WatermarkConfig::default().program(&vars, "s1").await.is_none());
+
+        let watermark = WatermarkConfig {
+            users: vec!["alice".to_string()],
+            format: "{user} {session} %H:%M".to_string(),
+            ..WatermarkConfig::default()
+        };
+        assert_eq!(watermark.render(&vars, "s1"), "alice s1 %H:%M");
+        let program = watermark.program(&vars, "s1").await.unwrap();
+        assert_eq!(program.restart, RestartPolicy::Always);
+        assert!(program.command.starts_with("trap '' HUP INT TERM; xcompmgr -n"));
+        assert!(program.command.contains("while :; do date '+alice s1 %H:%M' | aosd_cat --position=4 --transparency=2"));
+        assert!(program.command.contains("--fore-color='#d0d0d0' --fore-opacity=76 --back-opacity=0"));
+        assert!(program.command.contains("--fade-full=10000 || sleep 1; done"));
+
+        let slow = WatermarkConfig { refresh: 3600, opacity: 250, ..watermark.clone() };
+        let program = slow.program(&vars, "s1").await.unwrap();
+        assert!(program.command.contains("--fore-opacity=255 "));
+        assert!(program.command.contains("--fade-full=60000 "));
+
+        let vars = MotdVars { user: "bob%d", ..vars };
+        assert_eq!(watermark.render(&vars, "s1"), "bob%%d s1 %H:%M");
+        assert!(watermark.program(&vars, "s1").await.is_none());
+    }
+}
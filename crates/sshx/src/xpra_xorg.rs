@@ -0,0 +1,208 @@
+//! Choice of virtual X server for xpra displays, and management of the
+//! xorg.conf used with the Xorg dummy driver.
+
+use std::fmt;
+use std::path::Path;
+use std::process::Stdio;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tracing::debug;
+
+use crate::xpra_capabilities::XpraCapabilities;
+use crate::xpra_config::XpraConfig;
+use crate::xpra_hosts::{HostKind, WorkerHost};
+
+/// Where distributions install the Xorg dummy video driver.
+pub const DUMMY_DRIVER_PATTERNS: &[&str] = &[
+    "/usr/lib/xorg/modules/drivers/dummy_drv.so",
+    "/usr/lib64/xorg/modules/drivers/dummy_drv.so",
+    "/usr/lib/*/xorg/modules/drivers/dummy_drv.so",
+];
+
+/// Generated xorg.conf, written on each host that runs Xdummy displays.
+const MANAGED_XORG_CONF: &str = "/var/lib/sshx/xorg-dummy.conf";
+
+/// Virtual X server that xpra starts for each display.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum XBackend {
+    /// Xdummy when the dummy driver is installed, Xvfb otherwise.
+    #[default]
+    Auto,
+    /// Xorg with the dummy video driver; supports resizing via RandR.
+    Xdummy,
+    /// Xvfb with a fixed framebuffer; works everywhere.
+    Xvfb,
+}
+
+impl fmt::Display for XBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            XBackend::Auto => "auto",
+            XBackend::Xdummy => "xdummy",
+            XBackend::Xvfb => "xvfb",
+        })
+    }
+}
+
+/// Parse a `WIDTHxHEIGHT` resolution.
+pub fn parse_resolution(resolution: &str) -> Result<(u32, u32)> {
+    let (width, height) = resolution
+        .split_once('x')
+        .with_context(|| format!("invalid resolution {resolution:?}, expected WIDTHxHEIGHT"))?;
+    let width = width.trim().parse().context("invalid resolution width")?;
+    let height = height.trim().parse().context("invalid resolution height")?;
+    Ok((width, height))
+}
+
+/// xorg.conf for the dummy driver allowing screens up to the given size.
+pub fn dummy_xorg_conf(width: u32, height: u32) -> String {
+    // 32 bits per pixel, with room for a second buffer
+    let video_ram_kb = (width as u64 * height as u64 * 4 * 2).div_ceil(1024);
+    format!(
+        r#"# Managed by sshx, changes will be overwritten.
+Section "Device"
+    Identifier "dummy_videocard"
+    Driver "dummy"
+    VideoRam {video_ram_kb}
+EndSection
+
+Section "Monitor"
+    Identifier "dummy_monitor"
+    HorizSync 5.0 - 1000.0
+    VertRefresh 5.0 - 200.0
+EndSection
+
+Section "Screen"
+    Identifier "dummy_screen"
+    Device "dummy_videocard"
+    Monitor "dummy_monitor"
+    DefaultDepth 24
+    SubSection "Display"
+        Viewport 0 0
+        Depth 24
+        Virtual {width} {height}
+    EndSubSection
+EndSection
+
+Section "ServerLayout"
+    Identifier "dummy_layout"
+    Screen "dummy_screen"
+EndSection
+"#
+    )
+}
+
+/// The `--xvfb` command for Xvfb with a framebuffer of the given size.
+pub fn xvfb_command(width: u32, height: u32) -> String {
+    format!(
+        "Xvfb +extension GLX +extension Composite +extension RANDR +extension RENDER \
+         -screen 0 {width}x{height}x24+32 -nolisten tcp -noreset -auth $XAUTHORITY"
+    )
+}
+
+/// The `--xvfb` command for Xorg with the dummy driver and the given config.
+pub fn xdummy_command(xorg_conf: &str) -> String {
+    format!(
+        "Xorg -noreset -novtswitch -nolisten tcp +extension GLX +extension RANDR +extension RENDER \
+         -auth $XAUTHORITY -logfile ${{XPRA_SESSION_DIR}}/Xorg.log -config {xorg_conf}"
+    )
+}
+
+/// Resolve the X server command `config` sets for a display on `host`,
+/// making sure the managed xorg.conf is in place when Xdummy is used.
+pub async fn x_server_command(config: &XpraConfig, host: &WorkerHost, capabilities: &XpraCapabilities) -> Result<String> {
+    let (width, height) = parse_resolution(&config.max_resolution)?;
+    let backend = match config.x_backend {
+        XBackend::Auto if capabilities.dummy_driver => XBackend::Xdummy,
+        XBackend::Auto => XBackend::Xvfb,
+        backend => backend,
+    };
+
+    match backend {
+        XBackend::Xdummy => {
+            let conf = match &config.xorg_conf {
+                Some(conf) => conf.display().to_string(),
+                None => {
+                    write_managed_conf(host, &dummy_xorg_conf(width, height)).await?;
+                    MANAGED_XORG_CONF.to_string()
+                }
+            };
+            Ok(xdummy_command(&conf))
+        }
+        _ => Ok(xvfb_command(width, height)),
+    }
+}
+
+/// Atomically replace the managed xorg.conf on a host, so a display starting
+/// concurrently never reads a partial file.
+async fn write_managed_conf(host: &WorkerHost, contents: &str) -> Result<()> {
+    let path = Path::new(MANAGED_XORG_CONF);
+    if host.kind == HostKind::Local {
+        if tokio::fs::read_to_string(path).await.ok().as_deref() == Some(contents) {
+            return Ok(());
+        }
+        let parent = path.parent().unwrap_or(Path::new("/"));
+        tokio::fs::create_dir_all(parent).await?;
+        // A file of its own for each start, even concurrent ones of this
+        // process, renamed over the conf once complete
+        let contents = contents.to_string();
+        let parent = parent.to_path_buf();
+        tokio::task::spawn_blocking(move || -> Result<()> {
+            let mut tmp = tempfile::NamedTempFile::new_in(&parent)?;
+            std::io::Write::write_all(&mut tmp, contents.as_bytes())?;
+            tmp.persist(MANAGED_XORG_CONF)?;
+            Ok(())
+        })
+        .await??;
+    } else {
+        let script = format!(
+            "mkdir -p {dir} && tmp=$(mktemp {path}.XXXXXX) && cat > \"$tmp\" && mv \"$tmp\" {path}",
+            dir = path.parent().unwrap_or(Path::new("/")).display(),
+            path = path.display(),
+        );
+        let mut child = tokio::process::Command::from(host.command("sh", &["-c".to_string(), script]))
+            .stdin(Stdio::piped())
+            .spawn()?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(contents.as_bytes()).await?;
+        }
+        let status = child.wait().await?;
+        if !status.success() {
+            anyhow::bail!("failed to write {} on {}: {}", path.display(), host.name, status);
+        }
+    }
+    debug!(host = host.name, path = %path.display(), "Updated managed xorg.conf");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_resolution() {
+        assert_eq!(parse_resolution("3840x2160").unwrap(), (3840, 2160));
+        assert!(parse_resolution("3840").is_err());
+        assert!(parse_resolution("widex2160").is_err());
+    }
+
+    #[test]
+    fn test_x_server_commands() {
+        let conf = dummy_xorg_conf(1920, 1080);
+        assert!(conf.contains("Virtual 1920 1080"));
+        assert!(conf.contains("VideoRam 16200"));
+
+        assert!(xvfb_command(1920, 1080).contains("-screen 0 1920x1080x24+32"));
+        assert!(xdummy_command("/etc/x.conf").ends_with("-config /etc/x.conf"));
+    }
+
+    #[test]
+    fn test_backend_names() {
+        // Shown as written in the config
+        for backend in [XBackend::Auto, XBackend::Xdummy, XBackend::Xvfb] {
+            assert_eq!(serde_json::to_string(&backend).unwrap(), format!("\"{backend}\""));
+        }
+    }
+}
@@ -0,0 +1,256 @@
+/**
+ * @file Client end of the desktop channel of a shell showing a desktop.
+ *
+ * The first frame a client sends is a hello with what it supports, answered
+ * in the shell's output by a welcome with the agreed settings. From then on,
+ * frames in both directions start with a type byte. From version 2, a rekey
+ * switches the output to a new key and gives one for the input, which the
+ * client switches to right after a frame saying so. Keep this file
+ * consistent with `xpra_handshake.rs`.
+ */
+
+import { Encrypt } from "./encrypt";
+import { WebSmartcard, type SmartcardLink } from "./smartcard";
+
+/** Frame type byte of xpra traffic. */
+export const FRAME_DATA = 0;
+/** Frame type byte of control messages. */
+export const FRAME_CONTROL = 1;
+
+/** Newest channel protocol version the client speaks. */
+const PROTOCOL_VERSION = 2;
+
+/** Seconds between heartbeats the client asks for. */
+const HEARTBEAT_INTERVAL = 15;
+
+/** Id of the notification asking to forward devices, `u64::MAX` as read from JSON. */
+export const CONSENT_NOTIFICATION_ID = 2 ** 64;
+
+/** Device of the client forwarded to a desktop, see the Rust version. */
+export type Device = "webcam" | "smartcard";
+
+/** Features announced by the client, see the Rust version. */
+export type ClientHello = {
+  version: number;
+  encodings: string[];
+  compression: string[];
+  resize: boolean;
+  heartbeat_interval: number | null;
+  notifications: boolean;
+  devices: Device[];
+};
+
+/** Settings agreed for a channel, see the Rust version. */
+export type ChannelParams = {
+  version: number;
+  encodings: string[];
+  compression: string | null;
+  resize: boolean;
+  heartbeat_interval: number | null;
+  notifications: boolean;
+  devices: Device[];
+};
+
+/** Notification raised inside a desktop, see the Rust version. */
+export type DesktopNotification = {
+  id: number;
+  replaces: number;
+  app_name: string;
+  summary: string;
+  body: string;
+  expire_timeout: number;
+};
+
+/** Message on the control stream of a desktop channel, see the Rust version. */
+export type ControlMessage =
+  | ({ type: "hello" } & ClientHello)
+  | ({ type: "welcome" } & ChannelParams)
+  | { type: "heartbeat"; time: number }
+  | {
+      type: "rekey";
+      epoch: number;
+      offset: number;
+      key: number[];
+      input_key: number[];
+    }
+  | ({ type: "notification" } & DesktopNotification)
+  | { type: "notification_closed"; id: number }
+  | { type: "device_consent"; device: Device }
+  | { type: "smartcard_attach" }
+  | { type: "smartcard"; data: number[] }
+  | { type: "smartcard_detach" };
+
+/** Framing state of the desktop channel of one shell. */
+export class DesktopChannel {
+  /** Called with the control messages read after the welcome. */
+  constructor(
+    private readonly onMessage: (message: ControlMessage) => void = () => {},
+  ) {}
+
+  /** Agreed settings, or null until the welcome was read. */
+  params: ChannelParams | null = null;
+
+  /** Whether this client sent a hello, so its input is framed. */
+  private greeted = false;
+
+  /** When the last frame was read, in milliseconds since the epoch. */
+  private lastFrame = Date.now();
+
+  /** Card attached to the desktop's reader, if any. */
+  private card: SmartcardLink | null = null;
+
+  /** Key of the output after a rekey, or null for the session key. */
+  outputKey: Encrypt | null = null;
+
+  /** Key of the input after a rekey, or null for the session key. */
+  inputKey: Encrypt | null = null;
+
+  /** Input key of the latest rekey, until the input switches to it. */
+  private nextInput: { epoch: number; key: Encrypt } | null = null;
+
+  /** Hello frame, to send before any other input to the shell. */
+  hello(): Uint8Array {
+    this.greeted = true;
+    return controlFrame({
+      type: "hello",
+      version: PROTOCOL_VERSION,
+      encodings: [],
+      compression:
+        typeof DecompressionStream !== "undefined" ? ["deflate"] : [],
+      resize: false,
+      heartbeat_interval: HEARTBEAT_INTERVAL,
+      notifications: typeof Notification !== "undefined",
+      devices: [
+        ...(navigator.mediaDevices?.getUserMedia ? ["webcam" as const] : []),
+        ...(WebSmartcard.available() ? ["smartcard" as const] : []),
+      ],
+    });
+  }
+
+  /** Frames telling the desktop the user agreed to forward its devices. */
+  consent(): Uint8Array[] {
+    return (this.params?.devices ?? []).map((device) =>
+      controlFrame({ type: "device_consent", device }),
+    );
+  }
+
+  /**
+   * Read a frame of the shell's output, returning the xpra traffic in it.
+   *
+   * Output before the welcome is the plain xpra stream of a channel nobody
+   * negotiated, and is returned as it is.
+   */
+  async receive(frame: Uint8Array): Promise<Uint8Array | null> {
+    this.lastFrame = Date.now();
+    if (this.params === null) {
+      const message = parseControl(frame);
+      if (message?.type === "welcome") {
+        this.params = message;
+        return null;
+      }
+      return frame;
+    }
+    if (frame[0] === FRAME_DATA) {
+      const data = frame.subarray(1);
+      return this.params.compression === "deflate" ? inflate(data) : data;
+    }
+    const message = parseControl(frame);
+    if (message?.type === "rekey") {
+      // The frames after this one are under the new key
+      this.outputKey = await Encrypt.fromKey(Uint8Array.from(message.key));
+      this.nextInput = {
+        epoch: message.epoch,
+        key: await Encrypt.fromKey(Uint8Array.from(message.input_key)),
+      };
+    }
+    if (message) {
+      this.onMessage(message);
+    } else {
+      console.warn("Dropping unreadable desktop frame of type", frame[0]);
+    }
+    return null;
+  }
+
+  /** Frame input for xpra, once the channel is or is being negotiated. */
+  send(data: Uint8Array): Uint8Array {
+    if (this.params === null && !this.greeted) return data;
+    const frame = new Uint8Array(data.length + 1);
+    frame[0] = FRAME_DATA;
+    frame.set(data, 1);
+    return frame;
+  }
+
+  /**
+   * Frame switching the input to the key of the latest rekey right after it,
+   * to send at input `offset` under the current key, if there is a new key.
+   */
+  inputRekey(offset: bigint): Uint8Array | null {
+    // Only the client that negotiated the channel writes to it
+    if (!this.greeted || !this.nextInput) return null;
+    const { epoch, key } = this.nextInput;
+    this.nextInput = null;
+    this.inputKey = key;
+    // Written out, as offsets do not fit in the numbers of `JSON.stringify`
+    const json = `{"type":"input_rekey","epoch":${epoch},"offset":${offset}}`;
+    const data = new TextEncoder().encode(json);
+    const frame = new Uint8Array(data.length + 1);
+    frame[0] = FRAME_CONTROL;
+    frame.set(data, 1);
+    return frame;
+  }
+
+  /** Frame attaching `card` to the desktop's reader, once consented to. */
+  attachSmartcard(card: SmartcardLink): Uint8Array {
+    this.card = card;
+    return controlFrame({ type: "smartcard_attach" });
+  }
+
+  /** Frame with the card's answer to a frame of the reader, if it has one. */
+  async smartcard(data: number[]): Promise<Uint8Array | null> {
+    const answer = await this.card?.transmit(Uint8Array.from(data));
+    if (!answer) return null;
+    return controlFrame({ type: "smartcard", data: Array.from(answer) });
+  }
+
+  /** Frame detaching the card from the desktop's reader, if one is attached. */
+  async detachSmartcard(): Promise<Uint8Array | null> {
+    if (!this.card) return null;
+    const card = this.card;
+    this.card = null;
+    await card.close();
+    return controlFrame({ type: "smartcard_detach" });
+  }
+
+  /** Whether heartbeats were agreed but none came for two intervals. */
+  stalled(now = Date.now()): boolean {
+    const interval = this.params?.heartbeat_interval;
+    return !!interval && now - this.lastFrame > 2000 * interval;
+  }
+}
+
+/** Frame of a control message. */
+export function controlFrame(message: ControlMessage): Uint8Array {
+  const json = new TextEncoder().encode(JSON.stringify(message));
+  const frame = new Uint8Array(json.length + 1);
+  frame[0] = FRAME_CONTROL;
+  frame.set(json, 1);
+  return frame;
+}
+
+/** The control message of a frame, or null if it holds none. */
+function parseControl(frame: Uint8Array): ControlMessage | null {
+  if (frame[0] !== FRAME_CONTROL) return null;
+  try {
+    return JSON.parse(new TextDecoder().decode(frame.subarray(1)));
+  } catch {
+    return null;
+  }
+}
+
+/** Inflate raw deflate data, as written by flate2's `DeflateEncoder`. */
+async function inflate(data: Uint8Array): Promise<Uint8Array> {
+  const stream = new Blob([data])
+    .stream()
+    .pipeThrough(new DecompressionStream("deflate-raw"));
+  return new Uint8Array(await new Response(stream).arrayBuffer());
+}
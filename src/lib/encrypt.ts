@@ -48,6 +48,18 @@ export class Encrypt {
     return new Uint8Array(cipher);
   }
 
+  /** Use a raw AES key sent by a stream that switches keys. */
+  static async fromKey(key: Uint8Array): Promise<Encrypt> {
+    const aesKey = await crypto.subtle.importKey(
+      "raw",
+      key,
+      { name: "AES-CTR" },
+      false,
+      ["encrypt"],
+    );
+    return new Encrypt(aesKey);
+  }
+
   async segment(
     streamNum: bigint,
     offset: bigint,
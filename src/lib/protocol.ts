@@ -17,15 +17,53 @@ export type WsUser = {
   canWrite: boolean;
 };
 
+/** Reason a shell could not be started, see the Rust version. */
+export type WsRejection = {
+  code: string;
+  message: string;
+  retryAfter: number | null;
+};
+
+/** Why the backend ended a shell's desktop, see the Rust version. */
+export type WsTermination = {
+  reason: string;
+  message: string;
+  time: number | bigint;
+};
+
+/** Desktop session a new shell can attach to, see the Rust version. */
+export type WsDesktop = {
+  id: string;
+  name: string;
+  description: string | null;
+  createdAt: number | bigint;
+  idle: number | bigint;
+  resolution: string;
+  attached: boolean;
+};
+
+/** Parameters of a new desktop session, see the Rust version. */
+export type WsSessionParams = {
+  template: string | null;
+  app: string | null;
+  resolution: string | null;
+  audio: boolean;
+  locale?: string | null;
+  timezone?: string | null;
+};
+
 /** Server message type, see the Rust version. */
 export type WsServer = {
-  hello?: [Uid, string];
+  hello?: [Uid, string, boolean];
   invalidAuth?: [];
   users?: [Uid, WsUser][];
   userDiff?: [Uid, WsUser | null];
   shells?: [Sid, WsWinsize][];
   chunks?: [Sid, number, Uint8Array[]];
   hear?: [Uid, string, string];
+  shellRejected?: [Sid, WsRejection];
+  shellTerminated?: [Sid, WsTermination];
+  desktops?: WsDesktop[];
   shellLatency?: number | bigint;
   pong?: number | bigint;
   error?: string;
@@ -38,6 +76,10 @@ export type WsClient = {
   setCursor?: [number, number] | null;
   setFocus?: number | null;
   create?: [number, number];
+  createWith?: [number, number, WsSessionParams];
+  attach?: [number, number, string];
+  listDesktops?: [];
+  renameDesktop?: [string, string, string | null];
   close?: Sid;
   move?: [Sid, WsWinsize | null];
   data?: [Sid, Uint8Array, bigint];
@@ -0,0 +1,81 @@
+/**
+ * @file Cards of the client presented to the reader of a desktop.
+ *
+ * The reader speaks vsmartcard's vpcd protocol, relayed in `smartcard`
+ * control messages: a frame of one byte is a command to power the card off
+ * (0), on (1), reset it (2) or read its ATR (4), any longer one an APDU.
+ * The card answers the ATR and APDUs. See `xpra_smartcard.rs`.
+ */
+
+const VPCD_CTRL_OFF = 0;
+const VPCD_CTRL_ON = 1;
+const VPCD_CTRL_RESET = 2;
+const VPCD_CTRL_ATR = 4;
+
+/** A card of the client, answering the commands of a desktop's reader. */
+export interface SmartcardLink {
+  /** Answer a frame of the reader, or return null for commands without one. */
+  transmit(frame: Uint8Array): Promise<Uint8Array | null>;
+  /** Let go of the card. */
+  close(): Promise<void>;
+}
+
+/**
+ * Card in the first reader of the client, through the browser's Web Smart
+ * Card API. Browsers without it cannot forward cards.
+ */
+export class WebSmartcard implements SmartcardLink {
+  private constructor(
+    private readonly context: any,
+    private readonly reader: string,
+    private connection: any,
+  ) {}
+
+  /** Whether the browser can reach the client's readers. */
+  static available(): boolean {
+    return typeof navigator !== "undefined" && "smartCard" in navigator;
+  }
+
+  /** Connect to the card in the first reader that has one. */
+  static async connect(): Promise<WebSmartcard> {
+    const context = await (navigator as any).smartCard.establishContext();
+    const readers: string[] = await context.listReaders();
+    if (readers.length === 0) throw new Error("no smartcard reader found");
+    const connection = await WebSmartcard.open(context, readers[0]);
+    return new WebSmartcard(context, readers[0], connection);
+  }
+
+  private static async open(context: any, reader: string): Promise<any> {
+    const result = await context.connect(reader, "shared", {
+      preferredProtocols: ["t0", "t1"],
+    });
+    return result.connection;
+  }
+
+  async transmit(frame: Uint8Array): Promise<Uint8Array | null> {
+    if (frame.length > 1) {
+      return new Uint8Array(await this.connection.transmit(frame));
+    }
+    switch (frame[0]) {
+      case VPCD_CTRL_ATR: {
+        const status = await this.connection.status();
+        return new Uint8Array(status.answerToReset ?? []);
+      }
+      case VPCD_CTRL_RESET:
+        await this.connection.disconnect("reset");
+        this.connection = await WebSmartcard.open(this.context, this.reader);
+        return null;
+      case VPCD_CTRL_OFF:
+      case VPCD_CTRL_ON:
+        // The card stays powered while connected
+        return null;
+      default:
+        console.warn("Ignoring unknown smartcard reader command", frame[0]);
+        return null;
+    }
+  }
+
+  async close(): Promise<void> {
+    await this.connection.disconnect("leave");
+  }
+}